@@ -3,12 +3,13 @@ extern crate mqtt;
 use std::io::Cursor;
 
 use mqtt::{Encodable, Decodable};
+use mqtt::control::variable_header::PacketIdentifier;
 use mqtt::packet::{VariablePacket, PublishPacket, QoSWithPacketIdentifier};
 
 fn main() {
     // Create a new Publish packet
     let packet = PublishPacket::new("mqtt/learning".to_owned(),
-                                    QoSWithPacketIdentifier::Level2(10),
+                                    QoSWithPacketIdentifier::Level2(PacketIdentifier::new(10).unwrap()),
                                     b"Hello MQTT!".to_vec());
 
     // Encode
@@ -13,9 +13,9 @@ use clap::{App, Arg};
 
 use uuid::Uuid;
 
-use mqtt::{Encodable, Decodable, QualityOfService};
+use mqtt::{Encodable, Decodable, QualityOfService, TopicFilter};
 use mqtt::packet::*;
-use mqtt::control::variable_header::ConnectReturnCode;
+use mqtt::control::variable_header::{ConnectReturnCode, PacketIdentifier};
 
 fn generate_client_id() -> String {
     format!("/MQTT/rust/{}", Uuid::new_v4().to_simple_string())
@@ -42,10 +42,10 @@ fn main() {
     let client_id = matches.value_of("CLIENT_ID")
         .map(|x| x.to_owned())
         .unwrap_or_else(generate_client_id);
-    let channel_filters: Vec<(String, QualityOfService)>
+    let channel_filters: Vec<(TopicFilter, QualityOfService)>
         = matches.values_of("SUBSCRIBE").unwrap()
                  .iter()
-                 .map(|c| (c.to_string(), QualityOfService::Level0))
+                 .map(|c| (TopicFilter::new(c.to_string()), QualityOfService::Level0))
                  .collect();
 
     print!("Connecting to {:?} ... ", server_addr);
@@ -53,8 +53,10 @@ fn main() {
     println!("Connected!");
 
     println!("Client identifier {:?}", client_id);
-    let mut conn = ConnectPacket::new(client_id.to_owned());
-    conn.set_clean_session(true);
+    let conn = ConnectPacket::builder(&client_id[..])
+                    .clean_session(true)
+                    .build()
+                    .unwrap();
     let mut buf = Vec::new();
     conn.encode(&mut buf).unwrap();
     stream.write_all(&buf[..]).unwrap();
@@ -68,7 +70,7 @@ fn main() {
 
     // const CHANNEL_FILTER: &'static str = "typing-speed-test.aoeu.eu";
     println!("Applying channel filters {:?} ...", channel_filters);
-    let sub = SubscribePacket::new(10, channel_filters);
+    let sub = SubscribePacket::new(PacketIdentifier::new(10).unwrap(), channel_filters);
     let mut buf = Vec::new();
     sub.encode(&mut buf).unwrap();
     stream.write_all(&buf[..]).unwrap();
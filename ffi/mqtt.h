@@ -0,0 +1,152 @@
+#ifndef MQTT_H
+#define MQTT_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+typedef enum mqtt_status_t {
+  MQTT_OK = 0,
+  /* The buffer ends before the end of the packet */
+  MQTT_INCOMPLETE = 1,
+  MQTT_MALFORMED_PACKET = 2,
+  /* A null pointer or a value that does not make a valid packet */
+  MQTT_INVALID_ARGUMENT = 3,
+  MQTT_BUFFER_TOO_SMALL = 4,
+  /* The packet does not have the requested field */
+  MQTT_NO_SUCH_FIELD = 5,
+  MQTT_ENCODE_ERROR = 6,
+} mqtt_status_t;
+
+/* Opaque packet handle */
+typedef struct mqtt_packet_t mqtt_packet_t;
+
+/*
+ * Decodes the packet at the start of `data` into a new handle stored in `out`
+ *
+ * `out` is set to NULL on failure. Use `mqtt_packet_encoded_length` to find where the next
+ * packet starts.
+ *
+ * # Safety
+ *
+ * `data` must point to `len` readable bytes, or be NULL if `len` is 0. `out` must be NULL or
+ * point to writable memory for a handle.
+ */
+mqtt_status_t mqtt_decode(const uint8_t *data, size_t len, mqtt_packet_t **out);
+
+/*
+ * Control packet type of the fixed header, e.g. 3 for PUBLISH, 0 for a NULL handle
+ *
+ * # Safety
+ *
+ * `packet` must be NULL or a handle that has not been freed.
+ */
+uint8_t mqtt_packet_type(const mqtt_packet_t *packet);
+
+/*
+ * Topic name of a PUBLISH, valid until the packet is freed
+ *
+ * # Safety
+ *
+ * `packet` must be NULL or a handle that has not been freed. `topic` and `len` must be NULL or
+ * point to writable memory.
+ */
+mqtt_status_t mqtt_publish_topic(const mqtt_packet_t *packet, const uint8_t **topic, size_t *len);
+
+/*
+ * Application message of a PUBLISH, valid until the packet is freed
+ *
+ * # Safety
+ *
+ * `packet` must be NULL or a handle that has not been freed. `payload` and `len` must be NULL
+ * or point to writable memory.
+ */
+mqtt_status_t mqtt_publish_payload(const mqtt_packet_t *packet, const uint8_t **payload, size_t *len);
+
+/*
+ * Packet Identifier of a QoS 1 or 2 PUBLISH, an acknowledgement, SUBSCRIBE or UNSUBSCRIBE
+ *
+ * # Safety
+ *
+ * `packet` must be NULL or a handle that has not been freed. `packet_id` must be NULL or point
+ * to writable memory.
+ */
+mqtt_status_t mqtt_packet_id(const mqtt_packet_t *packet, uint16_t *packet_id);
+
+/*
+ * Number of bytes written by `mqtt_encode`, 0 for a NULL handle
+ *
+ * # Safety
+ *
+ * `packet` must be NULL or a handle that has not been freed.
+ */
+size_t mqtt_packet_encoded_length(const mqtt_packet_t *packet);
+
+/*
+ * Encodes the packet into `buf`
+ *
+ * `written` receives the length of the packet, also when `MQTT_BUFFER_TOO_SMALL` is returned.
+ *
+ * # Safety
+ *
+ * `packet` must be NULL or a handle that has not been freed. `buf` must point to `cap` writable
+ * bytes, or be NULL if `cap` is 0. `written` must be NULL or point to writable memory.
+ */
+mqtt_status_t mqtt_encode(const mqtt_packet_t *packet, uint8_t *buf, size_t cap, size_t *written);
+
+/*
+ * Creates a PUBLISH, `packet_id` is ignored for QoS 0
+ *
+ * # Safety
+ *
+ * `topic` and `payload` must point to `topic_len` and `payload_len` readable bytes, or be NULL
+ * if their length is 0. `out` must be NULL or point to writable memory for a handle.
+ */
+mqtt_status_t mqtt_publish_new(const uint8_t *topic,
+                               size_t topic_len,
+                               const uint8_t *payload,
+                               size_t payload_len,
+                               uint8_t qos,
+                               uint16_t packet_id,
+                               bool retain,
+                               mqtt_packet_t **out);
+
+/*
+ * Creates a PINGREQ
+ *
+ * # Safety
+ *
+ * `out` must be NULL or point to writable memory for a handle.
+ */
+mqtt_status_t mqtt_pingreq_new(mqtt_packet_t **out);
+
+/*
+ * Creates a PUBACK
+ *
+ * # Safety
+ *
+ * `out` must be NULL or point to writable memory for a handle.
+ */
+mqtt_status_t mqtt_puback_new(uint16_t packet_id, mqtt_packet_t **out);
+
+/*
+ * Releases a handle, NULL is ignored
+ *
+ * # Safety
+ *
+ * `packet` must be NULL or a handle that has not been freed yet, it must not be used afterwards.
+ */
+void mqtt_packet_free(mqtt_packet_t *packet);
+
+/*
+ * Message of the last error on the calling thread, valid until the next failing call on it
+ *
+ * # Safety
+ *
+ * `len` must be NULL or point to writable memory.
+ */
+const uint8_t *mqtt_last_error_message(size_t *len);
+
+#endif /* MQTT_H */
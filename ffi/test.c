@@ -0,0 +1,94 @@
+/* Exercises the C interface through ffi/mqtt.h, run by the ffi tests of the crate */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "mqtt.h"
+
+static int failures = 0;
+
+#define CHECK(cond) do { \
+        if (!(cond)) { \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #cond); \
+            failures++; \
+        } \
+    } while (0)
+
+static void test_decode_publish(void) {
+    /* QoS 1 PUBLISH of "hello" to "a/b" with Packet Identifier 7 */
+    static const uint8_t data[] = {0x32, 0x0c, 0x00, 0x03, 'a', '/', 'b', 0x00, 0x07, 'h', 'e', 'l', 'l', 'o'};
+    mqtt_packet_t *packet = NULL;
+    const uint8_t *ptr = NULL;
+    size_t len = 0;
+    uint16_t packet_id = 0;
+    uint8_t buf[32];
+    size_t written = 0;
+
+    CHECK(mqtt_decode(data, sizeof(data), &packet) == MQTT_OK);
+    CHECK(mqtt_packet_type(packet) == 3);
+    CHECK(mqtt_publish_topic(packet, &ptr, &len) == MQTT_OK);
+    CHECK(len == 3 && memcmp(ptr, "a/b", len) == 0);
+    CHECK(mqtt_publish_payload(packet, &ptr, &len) == MQTT_OK);
+    CHECK(len == 5 && memcmp(ptr, "hello", len) == 0);
+    CHECK(mqtt_packet_id(packet, &packet_id) == MQTT_OK);
+    CHECK(packet_id == 7);
+
+    CHECK(mqtt_encode(packet, buf, sizeof(buf), &written) == MQTT_OK);
+    CHECK(written == sizeof(data) && memcmp(buf, data, written) == 0);
+    CHECK(mqtt_encode(packet, buf, 4, &written) == MQTT_BUFFER_TOO_SMALL);
+    CHECK(written == sizeof(data));
+
+    mqtt_packet_free(packet);
+}
+
+static void test_decode_errors(void) {
+    static const uint8_t truncated[] = {0x32, 0x0c, 0x00, 0x03, 'a'};
+    static const uint8_t malformed[] = {0x30, 0x02, 0x00, 0x05};
+    mqtt_packet_t *packet = NULL;
+    size_t len = 0;
+
+    CHECK(mqtt_decode(truncated, sizeof(truncated), &packet) == MQTT_INCOMPLETE);
+    CHECK(packet == NULL);
+    CHECK(mqtt_decode(malformed, sizeof(malformed), &packet) == MQTT_MALFORMED_PACKET);
+    CHECK(packet == NULL);
+    CHECK(mqtt_last_error_message(&len) != NULL && len > 0);
+}
+
+static void test_constructors(void) {
+    static const uint8_t publish[] = {0x30, 0x07, 0x00, 0x03, 'x', '/', 'y', 'h', 'i'};
+    static const uint8_t puback[] = {0x40, 0x02, 0x00, 0x09};
+    static const uint8_t pingreq[] = {0xc0, 0x00};
+    mqtt_packet_t *packet = NULL;
+    uint16_t packet_id = 0;
+    uint8_t buf[16];
+    size_t written = 0;
+
+    CHECK(mqtt_publish_new((const uint8_t *) "x/y", 3, (const uint8_t *) "hi", 2, 0, 0, false, &packet) == MQTT_OK);
+    CHECK(mqtt_encode(packet, buf, sizeof(buf), &written) == MQTT_OK);
+    CHECK(written == sizeof(publish) && memcmp(buf, publish, written) == 0);
+    CHECK(mqtt_packet_id(packet, &packet_id) == MQTT_NO_SUCH_FIELD);
+    mqtt_packet_free(packet);
+
+    CHECK(mqtt_publish_new((const uint8_t *) "x/y", 3, NULL, 0, 3, 1, false, &packet) == MQTT_INVALID_ARGUMENT);
+    CHECK(packet == NULL);
+
+    CHECK(mqtt_puback_new(9, &packet) == MQTT_OK);
+    CHECK(mqtt_encode(packet, buf, sizeof(buf), &written) == MQTT_OK);
+    CHECK(written == sizeof(puback) && memcmp(buf, puback, written) == 0);
+    CHECK(mqtt_packet_id(packet, &packet_id) == MQTT_OK && packet_id == 9);
+    mqtt_packet_free(packet);
+
+    CHECK(mqtt_pingreq_new(&packet) == MQTT_OK);
+    CHECK(mqtt_packet_encoded_length(packet) == sizeof(pingreq));
+    CHECK(mqtt_encode(packet, buf, sizeof(buf), &written) == MQTT_OK);
+    CHECK(written == sizeof(pingreq) && memcmp(buf, pingreq, written) == 0);
+    mqtt_packet_free(packet);
+    mqtt_packet_free(NULL);
+}
+
+int main(void) {
+    test_decode_publish();
+    test_decode_errors();
+    test_constructors();
+    return failures == 0 ? 0 : 1;
+}
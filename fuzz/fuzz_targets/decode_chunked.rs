@@ -0,0 +1,8 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate mqtt;
+
+fuzz_target!(|data: &[u8]| {
+    mqtt::testing::fuzz::decode_chunked(data);
+});
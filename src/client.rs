@@ -0,0 +1,600 @@
+//! Blocking client built on the packet types and the session helpers
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use control::ControlType;
+use control::variable_header::{ConnectReturnCode, PacketIdentifier, TopicName};
+use packet::*;
+use packet::suback::SubscribeReturnCode;
+use stats::{self, PacketStats};
+use session::{IncomingPublish, KeepAlive, PacketIdAllocator, Qos1Tracker, Qos2StateMachine, Role};
+use session::qos1::Qos1TrackerError;
+use session::qos2::Qos2Error;
+use {Encodable, Decodable, QualityOfService, TopicFilter};
+
+/// Byte stream the client runs on
+pub trait Transport: Read + Write {
+    /// `None` blocks reads until data arrives
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Blocking MQTT client
+///
+/// Every call waits for the acknowledgements it needs. PUBLISH packets received in the
+/// meantime are acknowledged right away and queued for `poll_message`.
+///
+/// ```rust,no_run
+/// use std::net::TcpStream;
+/// use std::time::Duration;
+///
+/// use mqtt::client::MqttClient;
+/// use mqtt::control::variable_header::TopicName;
+/// use mqtt::packet::ConnectPacket;
+/// use mqtt::{QualityOfService, TopicFilter};
+///
+/// let mut client = MqttClient::new(TcpStream::connect("127.0.0.1:1883").unwrap());
+/// client.connect(ConnectPacket::builder("client").keep_alive(30).build().unwrap()).unwrap();
+/// client.subscribe(&[(TopicFilter::new("a/#"), QualityOfService::Level1)]).unwrap();
+/// client.publish(&TopicName("a/b".to_owned()), QualityOfService::Level1, b"hello").unwrap();
+///
+/// while let Some(message) = client.poll_message(Duration::from_secs(1)).unwrap() {
+///     println!("{}", message);
+/// }
+/// client.disconnect().unwrap();
+/// ```
+pub struct MqttClient<T: Transport> {
+    transport: T,
+    keep_alive: KeepAlive,
+    packet_ids: PacketIdAllocator,
+    qos1: Qos1Tracker,
+    qos2: Qos2StateMachine,
+    messages: VecDeque<PublishPacket>,
+    stats: Option<Arc<PacketStats>>,
+    // Bytes of a packet whose reading timed out, read again by the next attempt
+    partial: Vec<u8>,
+}
+
+impl<T: Transport> MqttClient<T> {
+    pub fn new(transport: T) -> MqttClient<T> {
+        MqttClient {
+            transport: transport,
+            keep_alive: KeepAlive::new(0, Instant::now()),
+            packet_ids: PacketIdAllocator::new(),
+            qos1: Qos1Tracker::new(),
+            qos2: Qos2StateMachine::new(),
+            messages: VecDeque::new(),
+            stats: None,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Records the packets sent and received from now on
+    pub fn set_stats(&mut self, stats: Option<Arc<PacketStats>>) {
+        self.stats = stats;
+    }
+
+    /// Sends the CONNECT and waits for the CONNACK, a refused connection is an error
+    pub fn connect<'a>(&mut self, packet: ConnectPacket) -> Result<ConnackPacket, ClientError<'a>> {
+        self.keep_alive = KeepAlive::new(packet.keep_alive(), Instant::now());
+        try!(self.send(packet));
+
+        let packet = try!(self.read_packet());
+        self.keep_alive.record_recv(Instant::now());
+        let connack = try!(packet.expect::<ConnackPacket>().map_err(ClientError::UnexpectedPacket));
+        self.keep_alive.apply_connack(&connack);
+        if !connack.connect_return_code().is_accepted() {
+            return Err(ClientError::ConnectionRefused(connack.connect_return_code()));
+        }
+        Ok(connack)
+    }
+
+    /// Publishes a message and waits for the end of its QoS 1 or QoS 2 handshake
+    pub fn publish<'a>(&mut self, topic: &TopicName, qos: QualityOfService, payload: &[u8])
+            -> Result<(), ClientError<'a>> {
+        let pkid = match qos {
+            QualityOfService::Level0 => None,
+            _ => Some(try!(self.packet_ids.allocate().ok_or(ClientError::NoPacketIdentifier))),
+        };
+        let qos_pkid = match (qos, pkid) {
+            (QualityOfService::Level1, Some(pkid)) => QoSWithPacketIdentifier::Level1(pkid),
+            (QualityOfService::Level2, Some(pkid)) => QoSWithPacketIdentifier::Level2(pkid),
+            _ => QoSWithPacketIdentifier::Level0,
+        };
+        let packet = PublishPacket::new(topic.0.clone(), qos_pkid, payload.to_vec());
+        try!(self.send(packet.clone()));
+
+        let pkid = match pkid {
+            Some(pkid) => pkid,
+            None => return Ok(()),
+        };
+
+        let result = self.complete_publish(packet, pkid);
+        if result.is_err() {
+            self.qos1.remove(pkid);
+            self.qos2.remove_outgoing(pkid);
+        }
+        self.packet_ids.release(pkid);
+        result
+    }
+
+    fn complete_publish<'a>(&mut self, packet: PublishPacket, pkid: PacketIdentifier) -> Result<(), ClientError<'a>> {
+        let now = Instant::now();
+        match packet.qos() {
+            QoSWithPacketIdentifier::Level1(..) => {
+                try!(self.qos1.register(packet, now));
+                try!(self.wait_for(|p| match p {
+                    &VariablePacket::PubackPacket(ref ack) => ack.packet_identifier() == pkid.get(),
+                    _ => false,
+                }));
+                let _ = self.qos1.acknowledge(pkid.get());
+            },
+            QoSWithPacketIdentifier::Level2(..) => {
+                try!(self.qos2.on_outgoing_publish(packet));
+                try!(self.wait_for(|p| match p {
+                    &VariablePacket::PubrecPacket(ref ack) => ack.packet_identifier() == pkid.get(),
+                    _ => false,
+                }));
+                let pubrel = try!(self.qos2.on_pubrec(pkid.get()));
+                try!(self.send(pubrel));
+                try!(self.wait_for(|p| match p {
+                    &VariablePacket::PubcompPacket(ref ack) => ack.packet_identifier() == pkid.get(),
+                    _ => false,
+                }));
+                let _ = self.qos2.on_pubcomp(pkid.get());
+            },
+            QoSWithPacketIdentifier::Level0 => {},
+        }
+        Ok(())
+    }
+
+    /// Subscribes and returns the return codes of the SUBACK with the same packet identifier
+    pub fn subscribe<'a>(&mut self, subscriptions: &[(TopicFilter, QualityOfService)])
+            -> Result<Vec<SubscribeReturnCode>, ClientError<'a>> {
+        let pkid = try!(self.packet_ids.allocate().ok_or(ClientError::NoPacketIdentifier));
+        let result = self.send(SubscribePacket::new(pkid, subscriptions.to_vec()))
+            .and_then(|_| self.wait_for(|p| match p {
+                &VariablePacket::SubackPacket(ref ack) => ack.packet_identifier() == pkid.get(),
+                _ => false,
+            }));
+        self.packet_ids.release(pkid);
+
+        match try!(result) {
+            VariablePacket::SubackPacket(suback) => Ok(suback.return_codes().to_vec()),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Next received message, `None` if nothing arrived within `timeout`
+    ///
+    /// Sends a PINGREQ when the keep alive is due while waiting.
+    pub fn poll_message<'a>(&mut self, timeout: Duration) -> Result<Option<PublishPacket>, ClientError<'a>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if let Some(message) = self.messages.pop_front() {
+                return Ok(Some(message));
+            }
+
+            let now = Instant::now();
+            if self.keep_alive.is_expired(now, Role::Client) {
+                return Err(ClientError::KeepAliveTimeout);
+            }
+            if self.keep_alive.should_ping(now) {
+                try!(self.send(PingreqPacket::new()));
+            }
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            let mut wait = deadline - now;
+            if let Some(ping) = self.keep_alive.next_ping_deadline() {
+                if ping > now && ping - now < wait {
+                    wait = ping - now;
+                }
+            }
+            try!(self.transport.set_read_timeout(Some(wait.max(Duration::from_millis(1)))));
+            let result = self.receive();
+            try!(self.transport.set_read_timeout(None));
+
+            match result {
+                Ok(..) => {},
+                // Bytes of a packet read so far are kept in `partial`
+                Err(ClientError::ReadTimeout) => {},
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Sends a PINGREQ and waits for the PINGRESP
+    pub fn ping<'a>(&mut self) -> Result<(), ClientError<'a>> {
+        try!(self.send(PingreqPacket::new()));
+        try!(self.wait_for(|p| p.control_type() == ControlType::PingResponse));
+        Ok(())
+    }
+
+    /// Sends a DISCONNECT and gives the transport back
+    pub fn disconnect<'a>(mut self) -> Result<T, ClientError<'a>> {
+        try!(self.send(DisconnectPacket::new()));
+        try!(self.transport.flush());
+        Ok(self.transport)
+    }
+
+    fn send<'a, P>(&mut self, packet: P) -> Result<(), ClientError<'a>>
+        where VariablePacket: From<P>
+    {
+        let packet = VariablePacket::new(packet);
+        try!(packet.encode(&mut self.transport));
+        stats::record_encoded(self.stats.as_ref().map(|s| &**s), &packet);
+        self.keep_alive.record_send(Instant::now());
+        Ok(())
+    }
+
+    fn read_packet<'a>(&mut self) -> Result<VariablePacket, ClientError<'a>> {
+        let (result, timed_out) = {
+            let mut reader = FrameReader {
+                transport: &mut self.transport,
+                partial: &mut self.partial,
+                replayed: 0,
+                timed_out: false,
+            };
+            let result = VariablePacket::decode(&mut reader);
+            (result, reader.timed_out)
+        };
+
+        if timed_out {
+            return Err(ClientError::ReadTimeout);
+        }
+        self.partial.clear();
+        stats::record_decoded(self.stats.as_ref().map(|s| &**s), &result);
+        result.map_err(From::from)
+    }
+
+    /// Reads packets until one matches `expected`, handling the others
+    fn wait_for<'a, F>(&mut self, mut expected: F) -> Result<VariablePacket, ClientError<'a>>
+        where F: FnMut(&VariablePacket) -> bool
+    {
+        loop {
+            if let Some(packet) = try!(self.receive()) {
+                if expected(&packet) {
+                    return Ok(packet);
+                }
+            }
+        }
+    }
+
+    /// Reads a packet, answers it if needed and returns it unless it is a message
+    fn receive<'a>(&mut self) -> Result<Option<VariablePacket>, ClientError<'a>> {
+        let packet = try!(self.read_packet());
+        self.keep_alive.record_recv(Instant::now());
+
+        match packet {
+            VariablePacket::PublishPacket(publish) => {
+                match publish.qos() {
+                    QoSWithPacketIdentifier::Level0 => self.messages.push_back(publish),
+                    QoSWithPacketIdentifier::Level1(pkid) => {
+                        try!(self.send(PubackPacket::new(pkid.get())));
+                        self.messages.push_back(publish);
+                    },
+                    QoSWithPacketIdentifier::Level2(pkid) => {
+                        let deliver = self.qos2.on_incoming_publish(&publish) == Ok(IncomingPublish::Deliver);
+                        try!(self.send(PubrecPacket::new(pkid.get())));
+                        if deliver {
+                            self.messages.push_back(publish);
+                        }
+                    },
+                }
+                Ok(None)
+            },
+            VariablePacket::PubrelPacket(pubrel) => {
+                let pubcomp = self.qos2.on_pubrel(pubrel.packet_identifier());
+                try!(self.send(pubcomp));
+                Ok(None)
+            },
+            packet => Ok(Some(packet)),
+        }
+    }
+}
+
+/// Reads from the transport after replaying the bytes kept from a timed out read, keeping
+/// everything read until the packet is complete
+struct FrameReader<'t, T: 't> {
+    transport: &'t mut T,
+    partial: &'t mut Vec<u8>,
+    replayed: usize,
+    timed_out: bool,
+}
+
+impl<'t, T: Read> Read for FrameReader<'t, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.replayed < self.partial.len() {
+            let len = (self.partial.len() - self.replayed).min(buf.len());
+            buf[..len].copy_from_slice(&self.partial[self.replayed..self.replayed + len]);
+            self.replayed += len;
+            return Ok(len);
+        }
+
+        match self.transport.read(buf) {
+            Ok(len) => {
+                self.partial.extend_from_slice(&buf[..len]);
+                self.replayed += len;
+                Ok(len)
+            },
+            Err(err) => {
+                if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut {
+                    self.timed_out = true;
+                }
+                Err(err)
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError<'a> {
+    IoError(io::Error),
+    VariablePacketError(VariablePacketError<'a>),
+    UnexpectedPacket(VariablePacket),
+    ConnectionRefused(ConnectReturnCode),
+    NoPacketIdentifier,
+    KeepAliveTimeout,
+    /// Nothing or only part of a packet arrived within the read timeout
+    ReadTimeout,
+    Qos1TrackerError(Qos1TrackerError),
+    Qos2Error(Qos2Error),
+}
+
+impl<'a> From<io::Error> for ClientError<'a> {
+    fn from(err: io::Error) -> ClientError<'a> {
+        ClientError::IoError(err)
+    }
+}
+
+impl<'a> From<VariablePacketError<'a>> for ClientError<'a> {
+    fn from(err: VariablePacketError<'a>) -> ClientError<'a> {
+        ClientError::VariablePacketError(err)
+    }
+}
+
+impl<'a> From<Qos1TrackerError> for ClientError<'a> {
+    fn from(err: Qos1TrackerError) -> ClientError<'a> {
+        ClientError::Qos1TrackerError(err)
+    }
+}
+
+impl<'a> From<Qos2Error> for ClientError<'a> {
+    fn from(err: Qos2Error) -> ClientError<'a> {
+        ClientError::Qos2Error(err)
+    }
+}
+
+impl<'a> fmt::Display for ClientError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ClientError::IoError(ref err) => err.fmt(f),
+            &ClientError::VariablePacketError(ref err) => err.fmt(f),
+            &ClientError::UnexpectedPacket(ref packet) => write!(f, "Unexpected packet {}", packet),
+            &ClientError::ConnectionRefused(code) => write!(f, "Connection refused ({})", code.to_u8()),
+            &ClientError::NoPacketIdentifier => write!(f, "No packet identifier available"),
+            &ClientError::KeepAliveTimeout => write!(f, "Nothing received within the keep alive"),
+            &ClientError::ReadTimeout => write!(f, "Read timed out"),
+            &ClientError::Qos1TrackerError(ref err) => err.fmt(f),
+            &ClientError::Qos2Error(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl<'a> Error for ClientError<'a> {
+    fn description(&self) -> &str {
+        match self {
+            &ClientError::IoError(ref err) => err.description(),
+            &ClientError::VariablePacketError(ref err) => err.description(),
+            &ClientError::UnexpectedPacket(..) => "Unexpected packet",
+            &ClientError::ConnectionRefused(..) => "Connection refused",
+            &ClientError::NoPacketIdentifier => "No packet identifier available",
+            &ClientError::KeepAliveTimeout => "Nothing received within the keep alive",
+            &ClientError::ReadTimeout => "Read timed out",
+            &ClientError::Qos1TrackerError(ref err) => err.description(),
+            &ClientError::Qos2Error(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match self {
+            &ClientError::IoError(ref err) => Some(err),
+            &ClientError::VariablePacketError(ref err) => Some(err),
+            &ClientError::Qos1TrackerError(ref err) => Some(err),
+            &ClientError::Qos2Error(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::io::{self, Read, Write};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use control::variable_header::{ConnectReturnCode, TopicName};
+    use packet::suback::SubscribeReturnCode;
+    use stats::PacketStats;
+    use testing::MockBroker;
+    use {QualityOfService, TopicFilter};
+
+    #[test]
+    fn test_client_session() {
+        let (stream, broker) = MockBroker::spawn(|broker| {
+            broker.grant(|filter, qos| if filter.as_str() == "denied" { SubscribeReturnCode::Failure } else { qos.into() })
+                  .retain("a/b", QualityOfService::Level2, b"hello")
+                  // Retransmission that must not be delivered twice
+                  .duplicate_deliveries(true)
+        }).unwrap();
+        let mut client = MqttClient::new(stream);
+
+        let connack = client.connect(ConnectPacket::builder("client").keep_alive(30).build().unwrap()).unwrap();
+        assert!(!connack.session_present());
+
+        let codes = client.subscribe(&[(TopicFilter::new("a/#"), QualityOfService::Level2),
+                                       (TopicFilter::new("denied"), QualityOfService::Level1)]).unwrap();
+        assert_eq!(vec![SubscribeReturnCode::MaximumQoSLevel2, SubscribeReturnCode::Failure], codes);
+
+        let topic = TopicName("c/d".to_owned());
+        client.publish(&topic, QualityOfService::Level0, b"0").unwrap();
+        client.publish(&topic, QualityOfService::Level1, b"1").unwrap();
+        client.publish(&topic, QualityOfService::Level2, b"2").unwrap();
+        client.ping().unwrap();
+
+        let message = client.poll_message(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!("a/b", message.topic_name());
+        assert_eq!(&b"hello"[..], &message.payload()[..]);
+        assert_eq!(None, client.poll_message(Duration::from_millis(50)).unwrap());
+
+        client.disconnect().unwrap();
+
+        let received: Vec<String> = broker.join().unwrap().iter().map(|p| format!("{}", p.control_type())).collect();
+        assert_eq!(vec!["CONNECT", "SUBSCRIBE", "PUBLISH", "PUBLISH", "PUBREC", "PUBREC", "PUBLISH", "PUBCOMP",
+                        "PUBCOMP", "PUBREL", "PINGREQ", "DISCONNECT"],
+                   received);
+    }
+
+    #[test]
+    fn test_client_connection_refused() {
+        let (stream, broker) = MockBroker::spawn(|broker| {
+            broker.connack(ConnackPacket::rejected(ConnectReturnCode::NotAuthorized))
+        }).unwrap();
+        let stats = Arc::new(PacketStats::new());
+        let mut client = MqttClient::new(stream);
+        client.set_stats(Some(stats.clone()));
+
+        match client.connect(ConnectPacket::new("client".to_owned())) {
+            Err(ClientError::ConnectionRefused(ConnectReturnCode::NotAuthorized)) => {},
+            Err(err) => panic!("Unexpected error {:?}", err),
+            Ok(..) => panic!("Connection accepted"),
+        }
+
+        drop(client);
+        assert_eq!(1, broker.join().unwrap().len());
+
+        let snapshot = stats.snapshot();
+        assert_eq!(1, snapshot.get(ControlType::Connect).packets_out);
+        assert_eq!(1, snapshot.get(ControlType::ConnectAcknowledgement).packets_in);
+        assert_eq!(4, snapshot.largest_in);
+    }
+
+    // Answers reads from a script, an empty script times out
+    struct ScriptedTransport {
+        reads: VecDeque<io::Result<Vec<u8>>>,
+        written: Vec<u8>,
+    }
+
+    impl ScriptedTransport {
+        fn new(reads: Vec<io::Result<Vec<u8>>>) -> ScriptedTransport {
+            ScriptedTransport {
+                reads: reads.into_iter().collect(),
+                written: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for ScriptedTransport {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.reads.pop_front() {
+                Some(Ok(mut data)) => {
+                    let len = data.len().min(buf.len());
+                    buf[..len].copy_from_slice(&data[..len]);
+                    if len < data.len() {
+                        self.reads.push_front(Ok(data.split_off(len)));
+                    }
+                    Ok(len)
+                },
+                Some(Err(err)) => Err(err),
+                None => Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out")),
+            }
+        }
+    }
+
+    impl Write for ScriptedTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for ScriptedTransport {
+        fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn timed_out() -> io::Result<Vec<u8>> {
+        Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out"))
+    }
+
+    #[test]
+    fn test_client_poll_message_partial_packet() {
+        // A QoS 0 PUBLISH of "a/b" with "hello", interrupted by read timeouts
+        let transport = ScriptedTransport::new(vec![Ok(b"\x30".to_vec()), timed_out(),
+                                                    Ok(b"\x0a\x00\x03a".to_vec()), timed_out(),
+                                                    Ok(b"/bhello".to_vec())]);
+        let mut client = MqttClient::new(transport);
+
+        let message = client.poll_message(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!("a/b", message.topic_name());
+        assert_eq!(&b"hello"[..], &message.payload()[..]);
+        assert!(client.partial.is_empty());
+        assert_eq!(None, client.poll_message(Duration::from_millis(10)).unwrap());
+    }
+
+    #[test]
+    fn test_client_failed_publish_releases_tracking() {
+        let reset = || Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+        let transport = ScriptedTransport::new(vec![reset(), reset(), Ok(b"\x40\x02\x00\x01".to_vec()),
+                                                    Ok(b"\x50\x02\x00\x01".to_vec()), Ok(b"\x70\x02\x00\x01".to_vec())]);
+        let mut client = MqttClient::new(transport);
+        let topic = TopicName("a/b".to_owned());
+
+        for &qos in [QualityOfService::Level1, QualityOfService::Level2].iter() {
+            match client.publish(&topic, qos, b"lost") {
+                Err(ClientError::VariablePacketError(..)) => {},
+                err => panic!("Unexpected result {:?}", err),
+            }
+            assert!(client.qos1.is_empty());
+            assert_eq!(0, client.qos2.outgoing_len());
+            // Packet identifier 1 again
+            client.packet_ids = PacketIdAllocator::new();
+        }
+
+        client.publish(&topic, QualityOfService::Level1, b"1").unwrap();
+        client.packet_ids = PacketIdAllocator::new();
+        client.publish(&topic, QualityOfService::Level2, b"2").unwrap();
+    }
+
+    #[test]
+    fn test_client_tracker_errors() {
+        let mut client = MqttClient::new(ScriptedTransport::new(Vec::new()));
+        client.qos1 = Qos1Tracker::with_max_in_flight(0);
+        match client.publish(&TopicName("a/b".to_owned()), QualityOfService::Level1, b"1") {
+            Err(ClientError::Qos1TrackerError(Qos1TrackerError::WindowFull)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+}
@@ -7,6 +7,7 @@ use std::fmt;
 use byteorder::{self, ReadBytesExt, WriteBytesExt};
 
 use control::packet_type::{PacketType, PacketTypeError};
+use encodable::{VarInt, VarIntError};
 use {Encodable, Decodable};
 
 /// Fixed header for each MQTT control packet
@@ -40,6 +41,36 @@ impl FixedHeader {
             remaining_length: remaining_length,
         }
     }
+
+    /// Length of the first packet in `buf` according to its fixed header, `None` if the fixed
+    /// header is incomplete
+    ///
+    /// A Remaining Length longer than 4 bytes gives the length of the bytes read so far, decoding
+    /// them reports the error.
+    pub fn packet_length(buf: &[u8]) -> Option<usize> {
+        let mut remaining_len = 0usize;
+        for i in 0..4 {
+            let byte = match buf.get(i + 1) {
+                Some(&byte) => byte,
+                None => return None,
+            };
+            remaining_len |= ((byte & 0x7F) as usize) << (7 * i);
+
+            if byte & 0x80 == 0 {
+                return Some(i + 2 + remaining_len);
+            }
+        }
+        Some(5)
+    }
+
+    /// Reads the first byte and the Remaining Length without interpreting the packet type.
+    ///
+    /// With `require_minimal`, a Remaining Length encoded with more bytes than needed is rejected.
+    pub fn decode_raw<R: Read>(rdr: &mut R, require_minimal: bool) -> Result<(u8, u32), FixedHeaderError> {
+        let type_val = try!(rdr.read_u8());
+        let remaining_len = try!(VarInt::decode_with(rdr, Some(require_minimal)));
+        Ok((type_val, remaining_len.0))
+    }
 }
 
 impl<'a> Encodable<'a> for FixedHeader {
@@ -47,38 +78,12 @@ impl<'a> Encodable<'a> for FixedHeader {
 
     fn encode<W: Write>(&self, wr: &mut W) -> Result<(), FixedHeaderError> {
         try!(wr.write_u8(self.packet_type.to_u8()));
-
-        let mut cur_len = self.remaining_length;
-        loop {
-            let mut byte = (cur_len & 0x7F) as u8;
-            cur_len >>= 7;
-
-            if cur_len > 0 {
-                byte |= 0x80;
-            }
-
-            try!(wr.write_u8(byte));
-
-            if cur_len == 0 {
-                break;
-            }
-        }
-
+        try!(VarInt(self.remaining_length).encode(wr));
         Ok(())
     }
 
     fn encoded_length(&self) -> u32 {
-        let rem_size =
-            if self.remaining_length >= 2_097_152 {
-                4
-            } else if self.remaining_length >= 16_384 {
-                3
-            } else if self.remaining_length >= 128 {
-                2
-            } else {
-                1
-            };
-        1 + rem_size
+        1 + VarInt(self.remaining_length).encoded_length()
     }
 }
 
@@ -87,25 +92,9 @@ impl<'a> Decodable<'a> for FixedHeader {
     type Cond = ();
 
     fn decode_with<R: Read>(rdr: &mut R, _rest: Option<()>) -> Result<FixedHeader, FixedHeaderError> {
-        let type_val = try!(rdr.read_u8());
-        let packet_type = try!(PacketType::from_u8(type_val));
-        let remaining_len = {
-            let mut cur = 0u32;
-            for i in 0.. {
-                let byte = try!(rdr.read_u8());
-                cur |= ((byte as u32) & 0x7F) << (7 * i);
-
-                if i >= 4 {
-                    return Err(FixedHeaderError::MalformedRemainingLength);
-                }
-
-                if byte & 0x80 == 0 {
-                    break;
-                }
-            }
-
-            cur
-        };
+        let (type_val, remaining_len) = try!(FixedHeader::decode_raw(rdr, false));
+        // Flags are validated against the control type when the packet is decoded
+        let packet_type = try!(PacketType::from_u8_unchecked_flags(type_val));
 
         Ok(FixedHeader::new(packet_type, remaining_len))
     }
@@ -113,7 +102,8 @@ impl<'a> Decodable<'a> for FixedHeader {
 
 #[derive(Debug)]
 pub enum FixedHeaderError {
-    MalformedRemainingLength,
+    RemainingLengthTooLong,
+    NonMinimalRemainingLength,
     PacketTypeError(PacketTypeError),
     IoError(io::Error),
 }
@@ -130,6 +120,17 @@ impl From<PacketTypeError> for FixedHeaderError {
     }
 }
 
+impl From<VarIntError> for FixedHeaderError {
+    fn from(err: VarIntError) -> FixedHeaderError {
+        match err {
+            VarIntError::IoError(err) => FixedHeaderError::IoError(err),
+            // At most 4 bytes, so the value cannot exceed 268,435,455
+            VarIntError::TooLong => FixedHeaderError::RemainingLengthTooLong,
+            VarIntError::NonMinimal => FixedHeaderError::NonMinimalRemainingLength,
+        }
+    }
+}
+
 impl From<byteorder::Error> for FixedHeaderError {
     fn from(err: byteorder::Error) -> FixedHeaderError {
         FixedHeaderError::IoError(From::from(err))
@@ -139,7 +140,8 @@ impl From<byteorder::Error> for FixedHeaderError {
 impl fmt::Display for FixedHeaderError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            &FixedHeaderError::MalformedRemainingLength => write!(f, "Malformed remaining length"),
+            &FixedHeaderError::RemainingLengthTooLong => write!(f, "Remaining length is longer than 4 bytes"),
+            &FixedHeaderError::NonMinimalRemainingLength => write!(f, "Remaining length is not minimally encoded"),
             &FixedHeaderError::PacketTypeError(ref err) => write!(f, "{}", err),
             &FixedHeaderError::IoError(ref err) => write!(f, "{}", err),
         }
@@ -149,7 +151,8 @@ impl fmt::Display for FixedHeaderError {
 impl Error for FixedHeaderError {
     fn description(&self) -> &str {
         match self {
-            &FixedHeaderError::MalformedRemainingLength => "Malformed remaining length",
+            &FixedHeaderError::RemainingLengthTooLong => "Remaining length is longer than 4 bytes",
+            &FixedHeaderError::NonMinimalRemainingLength => "Remaining length is not minimally encoded",
             &FixedHeaderError::PacketTypeError(ref err) => err.description(),
             &FixedHeaderError::IoError(ref err) => err.description(),
         }
@@ -157,7 +160,8 @@ impl Error for FixedHeaderError {
 
     fn cause(&self) -> Option<&Error> {
         match self {
-            &FixedHeaderError::MalformedRemainingLength => None,
+            &FixedHeaderError::RemainingLengthTooLong => None,
+            &FixedHeaderError::NonMinimalRemainingLength => None,
             &FixedHeaderError::PacketTypeError(ref err) => Some(err),
             &FixedHeaderError::IoError(ref err) => Some(err),
         }
@@ -168,6 +172,8 @@ impl Error for FixedHeaderError {
 mod test {
     use super::*;
 
+    use std::prelude::v1::*;
+    use std::cmp;
     use std::io::Cursor;
     use control::packet_type::{PacketType, ControlType};
     use {Encodable, Decodable};
@@ -198,4 +204,60 @@ mod test {
         let mut cursor = Cursor::new(&stream[..]);
         FixedHeader::decode(&mut cursor).unwrap();
     }
+
+    #[test]
+    fn test_decode_remaining_length_too_long() {
+        let stream = b"\x10\xff\xff\xff\xff\x7f";
+        let mut cursor = Cursor::new(&stream[..]);
+        match FixedHeader::decode(&mut cursor) {
+            Err(FixedHeaderError::RemainingLengthTooLong) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+        // The fifth byte is left unread
+        assert_eq!(5, cursor.position());
+
+        let stream = b"\x10\xff\xff\xff\x7f";
+        let header = FixedHeader::decode(&mut Cursor::new(&stream[..])).unwrap();
+        assert_eq!(0x0FFFFFFF, header.remaining_length);
+    }
+
+    #[test]
+    fn test_decode_non_minimal_remaining_length() {
+        let stream = b"\x10\x81\x00";
+        assert_eq!((0x10, 1), FixedHeader::decode_raw(&mut Cursor::new(&stream[..]), false).unwrap());
+        match FixedHeader::decode_raw(&mut Cursor::new(&stream[..]), true) {
+            Err(FixedHeaderError::NonMinimalRemainingLength) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let stream = b"\x10\x00";
+        assert_eq!((0x10, 0), FixedHeader::decode_raw(&mut Cursor::new(&stream[..]), true).unwrap());
+    }
+
+    #[test]
+    fn test_remaining_length_round_trip() {
+        fn round_trip(remaining_length: u32) {
+            let header = FixedHeader::new(PacketType::with_default(ControlType::Publish), remaining_length);
+            let mut buf = Vec::new();
+            header.encode(&mut buf).unwrap();
+            assert_eq!(header.encoded_length() as usize, buf.len());
+
+            let decoded = FixedHeader::decode_raw(&mut Cursor::new(&buf[..]), true).unwrap();
+            assert_eq!((0x30, remaining_length), decoded);
+        }
+
+        // Every value around the boundaries of the encoded sizes
+        for &boundary in [0u32, 128, 16_384, 2_097_152, 268_435_455].iter() {
+            for value in boundary.saturating_sub(300)..cmp::min(boundary + 300, 268_435_456) {
+                round_trip(value);
+            }
+        }
+
+        // And a sweep over the whole range
+        let mut value = 0u32;
+        while value <= 268_435_455 {
+            round_trip(value);
+            value += 4099;
+        }
+    }
 }
@@ -1,6 +1,9 @@
+use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt;
 
+use control::variable_header::ProtocolVersion;
+
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub struct PacketType {
     pub control_type: ControlType,
@@ -51,6 +54,120 @@ pub enum ControlType {
 
     /// Client is disconnecting
     Disconnect                      = value::DISCONNECT,
+
+    /// Authentication exchange, MQTT 5 only
+    Auth                            = value::AUTH,
+}
+
+impl ControlType {
+    /// Parses a control type of MQTT 3.1.1, in which type 15 is still reserved
+    pub fn from_u8(val: u8) -> Result<ControlType, PacketTypeError> {
+        match val {
+            value::CONNECT      => Ok(ControlType::Connect),
+            value::CONNACK      => Ok(ControlType::ConnectAcknowledgement),
+
+            value::PUBLISH      => Ok(ControlType::Publish),
+            value::PUBACK       => Ok(ControlType::PublishAcknowledgement),
+            value::PUBREC       => Ok(ControlType::PublishReceived),
+            value::PUBREL       => Ok(ControlType::PublishRelease),
+            value::PUBCOMP      => Ok(ControlType::PublishComplete),
+
+            value::SUBSCRIBE    => Ok(ControlType::Subscribe),
+            value::SUBACK       => Ok(ControlType::SubscribeAcknowledgement),
+
+            value::UNSUBSCRIBE  => Ok(ControlType::Unsubscribe),
+            value::UNSUBACK     => Ok(ControlType::UnsubscribeAcknowledgement),
+
+            value::PINGREQ      => Ok(ControlType::PingRequest),
+            value::PINGRESP     => Ok(ControlType::PingResponse),
+
+            value::DISCONNECT   => Ok(ControlType::Disconnect),
+
+            0 | 15              => Err(PacketTypeError::ReservedType(val)),
+            _                   => Err(PacketTypeError::UndefinedType(val)),
+        }
+    }
+
+    /// Parses a control type of the protocol `version`, type 15 is AUTH in MQTT 5
+    pub fn from_u8_with_version(val: u8, version: ProtocolVersion) -> Result<ControlType, PacketTypeError> {
+        match val {
+            value::AUTH if version == ProtocolVersion::V5 => Ok(ControlType::Auth),
+            _ => ControlType::from_u8(val),
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+
+    /// Name of the packet type in the spec, e.g. `PUBACK`
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ControlType::Connect => "CONNECT",
+            ControlType::ConnectAcknowledgement => "CONNACK",
+
+            ControlType::Publish => "PUBLISH",
+            ControlType::PublishAcknowledgement => "PUBACK",
+            ControlType::PublishReceived => "PUBREC",
+            ControlType::PublishRelease => "PUBREL",
+            ControlType::PublishComplete => "PUBCOMP",
+
+            ControlType::Subscribe => "SUBSCRIBE",
+            ControlType::SubscribeAcknowledgement => "SUBACK",
+
+            ControlType::Unsubscribe => "UNSUBSCRIBE",
+            ControlType::UnsubscribeAcknowledgement => "UNSUBACK",
+
+            ControlType::PingRequest => "PINGREQ",
+            ControlType::PingResponse => "PINGRESP",
+
+            ControlType::Disconnect => "DISCONNECT",
+
+            ControlType::Auth => "AUTH",
+        }
+    }
+
+    /// Flags required in the fixed header (MQTT-2.2.2-1), `None` for PUBLISH whose flags
+    /// carry DUP, QoS and RETAIN
+    pub fn reserved_flags(&self) -> Option<u8> {
+        match *self {
+            ControlType::Connect                    => Some(0b0000),
+            ControlType::ConnectAcknowledgement     => Some(0b0000),
+
+            ControlType::Publish                    => None,
+            ControlType::PublishAcknowledgement     => Some(0b0000),
+            ControlType::PublishReceived            => Some(0b0000),
+            ControlType::PublishRelease             => Some(0b0010),
+            ControlType::PublishComplete            => Some(0b0000),
+
+            ControlType::Subscribe                  => Some(0b0010),
+            ControlType::SubscribeAcknowledgement   => Some(0b0000),
+
+            ControlType::Unsubscribe                => Some(0b0010),
+            ControlType::UnsubscribeAcknowledgement => Some(0b0000),
+
+            ControlType::PingRequest                => Some(0b0000),
+            ControlType::PingResponse               => Some(0b0000),
+
+            ControlType::Disconnect                 => Some(0b0000),
+
+            ControlType::Auth                       => Some(0b0000),
+        }
+    }
+}
+
+impl TryFrom<u8> for ControlType {
+    type Error = PacketTypeError;
+
+    fn try_from(val: u8) -> Result<ControlType, PacketTypeError> {
+        ControlType::from_u8(val)
+    }
+}
+
+impl fmt::Display for ControlType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
 }
 
 impl PacketType {
@@ -64,83 +181,56 @@ impl PacketType {
 
     #[inline]
     pub fn with_default(t: ControlType) -> PacketType {
-        match t {
-            ControlType::Connect => PacketType::new(t, 0),
-            ControlType::ConnectAcknowledgement => PacketType::new(t, 0),
-
-            ControlType::Publish => PacketType::new(t, 0),
-            ControlType::PublishAcknowledgement => PacketType::new(t, 0),
-            ControlType::PublishReceived => PacketType::new(t, 0),
-            ControlType::PublishRelease => PacketType::new(t, 0x02),
-            ControlType::PublishComplete => PacketType::new(t, 0),
+        PacketType::new(t, t.reserved_flags().unwrap_or(0))
+    }
 
-            ControlType::Subscribe => PacketType::new(t, 0x02),
-            ControlType::SubscribeAcknowledgement => PacketType::new(t, 0),
+    pub fn to_u8(&self) -> u8 {
+        (self.control_type as u8) << 4
+            | (self.flags & 0x0F)
+    }
 
-            ControlType::Unsubscribe => PacketType::new(t, 0x02),
-            ControlType::UnsubscribeAcknowledgement => PacketType::new(t, 0),
+    /// Parses the first byte of the fixed header, the flags are kept as they are without
+    /// being checked against the ones required by the control type
+    pub fn from_u8_unchecked_flags(val: u8) -> Result<PacketType, PacketTypeError> {
+        ControlType::try_from(val >> 4).map(|t| PacketType::new(t, val & 0x0F))
+    }
 
-            ControlType::PingRequest => PacketType::new(t, 0),
-            ControlType::PingResponse => PacketType::new(t, 0),
+    /// Like `from_u8_unchecked_flags`, with the control types of the protocol `version`
+    pub fn from_u8_unchecked_flags_with_version(val: u8, version: ProtocolVersion) -> Result<PacketType, PacketTypeError> {
+        ControlType::from_u8_with_version(val >> 4, version).map(|t| PacketType::new(t, val & 0x0F))
+    }
 
-            ControlType::Disconnect => PacketType::new(t, 0),
+    /// Checks the flags against the control type. Flags of PUBLISH are always accepted, the
+    /// others are reserved and must be exactly the ones defined by the spec (MQTT-2.2.2-2)
+    pub fn has_valid_flags(&self) -> bool {
+        match self.control_type.reserved_flags() {
+            Some(flags) => self.flags == flags,
+            None => true,
         }
     }
 
-    pub fn to_u8(&self) -> u8 {
-        (self.control_type as u8) << 4
-            | (self.flags & 0x0F)
+    /// Parses the first byte of the fixed header into the control type and flags, rejecting
+    /// reserved flags that do not match the control type
+    pub fn try_from_u8(val: u8) -> Result<PacketType, PacketTypeError> {
+        let packet_type = try!(PacketType::from_u8_unchecked_flags(val));
+
+        if packet_type.has_valid_flags() {
+            Ok(packet_type)
+        } else {
+            Err(PacketTypeError::InvalidFlag)
+        }
     }
 
     pub fn from_u8(val: u8) -> Result<PacketType, PacketTypeError> {
+        PacketType::try_from_u8(val)
+    }
+}
 
-        let type_val = val >> 4;
-        let flag = val & 0x0F;
-
-        macro_rules! vconst {
-            ($flag:expr, $ret:expr) => (
-                if flag != $flag {
-                    Err(PacketTypeError::InvalidFlag)
-                } else {
-                    Ok($ret)
-                }
-            )
-        }
+impl TryFrom<u8> for PacketType {
+    type Error = PacketTypeError;
 
-        match type_val {
-            value::CONNECT      => vconst!(0x00, PacketType::new(ControlType::Connect,
-                                           0)),
-            value::CONNACK      => vconst!(0x00, PacketType::new(ControlType::ConnectAcknowledgement,
-                                           0)),
-
-            value::PUBLISH      =>
-                Ok(PacketType::new(ControlType::Publish, flag & 0x0F)),
-            value::PUBACK       => vconst!(0x00, PacketType::new(ControlType::PublishAcknowledgement,
-                                                                 0)),
-            value::PUBREC       => vconst!(0x00, PacketType::new(ControlType::PublishReceived,
-                                                                 0)),
-            value::PUBREL       => vconst!(0x02, PacketType::new(ControlType::PublishRelease,
-                                                                 0x02)),
-            value::PUBCOMP      => vconst!(0x00, PacketType::new(ControlType::PublishComplete,
-                                                                 0)),
-
-            value::SUBSCRIBE    => vconst!(0x02, PacketType::new(ControlType::Subscribe, 0x02)),
-            value::SUBACK       => vconst!(0x00, PacketType::new(ControlType::SubscribeAcknowledgement,
-                                                                 0)),
-
-            value::UNSUBSCRIBE  => vconst!(0x02, PacketType::new(ControlType::Unsubscribe, 0x02)),
-            value::UNSUBACK     => vconst!(0x00, PacketType::new(ControlType::UnsubscribeAcknowledgement,
-                                                                 0)),
-
-            value::PINGREQ      => vconst!(0x00, PacketType::new(ControlType::PingRequest, 0)),
-            value::PINGRESP     => vconst!(0x00, PacketType::new(ControlType::PingResponse,
-                                                                 0)),
-
-            value::DISCONNECT   => vconst!(0x00, PacketType::new(ControlType::Disconnect, 0)),
-
-            0 | 15              => Err(PacketTypeError::ReservedType(type_val)),
-            _                   => Err(PacketTypeError::UndefinedType(type_val)),
-        }
+    fn try_from(val: u8) -> Result<PacketType, PacketTypeError> {
+        PacketType::try_from_u8(val)
     }
 }
 
@@ -186,4 +276,59 @@ mod value {
     pub const PINGREQ: u8 = 12;
     pub const PINGRESP: u8 = 13;
     pub const DISCONNECT: u8 = 14;
+    pub const AUTH: u8 = 15;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_control_type_try_from() {
+        for val in 0..16u8 {
+            match ControlType::try_from(val) {
+                Ok(t) => {
+                    assert!(val >= 1 && val <= 14);
+                    assert_eq!(val, t.to_u8());
+                },
+                Err(PacketTypeError::ReservedType(v)) => assert!(v == val && (val == 0 || val == 15)),
+                Err(err) => panic!("Unexpected error {:?} for {}", err, val),
+            }
+        }
+
+        assert_eq!(ControlType::Auth, ControlType::from_u8_with_version(15, ProtocolVersion::V5).unwrap());
+        assert_eq!(15, ControlType::Auth.to_u8());
+        match ControlType::from_u8_with_version(15, ProtocolVersion::V3_1_1) {
+            Err(PacketTypeError::ReservedType(15)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_packet_type_try_from_all_bytes() {
+        for byte in 0..256u32 {
+            let byte = byte as u8;
+            let (type_val, flags) = (byte >> 4, byte & 0x0F);
+
+            let valid_flags = match type_val {
+                value::PUBLISH => true,
+                value::PUBREL | value::SUBSCRIBE | value::UNSUBSCRIBE => flags == 0x02,
+                _ => flags == 0x00,
+            };
+
+            match PacketType::try_from(byte) {
+                Ok(packet_type) => {
+                    assert!(valid_flags);
+                    assert_eq!(type_val, packet_type.control_type.to_u8());
+                    assert_eq!(flags, packet_type.flags);
+                    assert_eq!(byte, packet_type.to_u8());
+                },
+                Err(PacketTypeError::ReservedType(..)) => assert!(type_val == 0 || type_val == 15),
+                Err(PacketTypeError::InvalidFlag) => assert!(!valid_flags),
+                Err(err) => panic!("Unexpected error {:?} for {:#04x}", err, byte),
+            }
+        }
+    }
 }
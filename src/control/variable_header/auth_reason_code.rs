@@ -0,0 +1,17 @@
+use std::io::{Read, Write};
+use std::convert::From;
+use std::fmt;
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+
+use control::variable_header::VariableHeaderError;
+use {Encodable, Decodable};
+
+impl_reason_code! {
+    /// Reason Code of an MQTT 5 AUTH
+    pub enum AuthReasonCode {
+        Success                = 0x00 => "Success",
+        ContinueAuthentication = 0x18 => "Continue authentication",
+        ReAuthenticate         = 0x19 => "Re-authenticate",
+    } Unspecified
+}
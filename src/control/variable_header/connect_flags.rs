@@ -1,10 +1,10 @@
 use std::io::{Read, Write};
-use std::convert::From;
+use std::convert::{From, TryFrom};
 
 use byteorder::{ReadBytesExt, WriteBytesExt};
 
 use control::variable_header::VariableHeaderError;
-use {Encodable, Decodable};
+use {Encodable, Decodable, QualityOfService};
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub struct ConnectFlags {
@@ -33,10 +33,11 @@ impl<'a> Encodable<'a> for ConnectFlags {
     type Err = VariableHeaderError;
 
     fn encode<W: Write>(&self, writer: &mut W) -> Result<(), VariableHeaderError> {
+        // Bit 0 is reserved and always left unset, so is the will QoS masked to its own two bits
         let code = ((self.user_name as u8) << 7)
             | ((self.password as u8) << 6)
             | ((self.will_retain as u8) << 5)
-            | ((self.will_qos) << 3)
+            | ((self.will_qos & 0b11) << 3)
             | ((self.will_flag as u8) << 2)
             | ((self.clean_session as u8) << 1);
 
@@ -54,17 +55,55 @@ impl<'a> Decodable<'a> for ConnectFlags {
 
     fn decode_with<R: Read>(reader: &mut R, _rest: Option<()>) -> Result<ConnectFlags, VariableHeaderError> {
         let code = try!(reader.read_u8());
+
+        // MQTT-3.1.2-3: The Server MUST disconnect the Client if the reserved flag is not 0
         if code & 1 != 0 {
-            return Err(VariableHeaderError::InvalidReservedFlag);
+            return Err(VariableHeaderError::ConnectFlagsReservedBitSet);
+        }
+
+        let will_flag = (code & 0b0000_0100) != 0;
+        let will_qos = (code & 0b0001_1000) >> 3;
+        let will_retain = (code & 0b0010_0000) != 0;
+
+        // MQTT-3.1.2-14: Will QoS MUST NOT be 3
+        try!(QualityOfService::try_from(will_qos).map_err(|err| VariableHeaderError::InvalidWillQoS(err.0)));
+
+        // MQTT-3.1.2-13: If the Will Flag is set to 0, the Will QoS MUST be set to 0
+        if !will_flag && will_qos != 0 {
+            return Err(VariableHeaderError::WillQoSWithoutWillFlag);
+        }
+
+        // MQTT-3.1.2-15: If the Will Flag is set to 0, then the Will Retain Flag MUST be set to 0
+        if !will_flag && will_retain {
+            return Err(VariableHeaderError::WillRetainWithoutWillFlag);
         }
 
         Ok(ConnectFlags {
             user_name: (code & 0b1000_0000) != 0,
             password: (code & 0b0100_0000) != 0,
-            will_retain: (code & 0b0010_0000) != 0,
-            will_qos: (code & 0b0001_1000) >> 3,
-            will_flag: (code & 0b0000_0100) != 0,
+            will_retain: will_retain,
+            will_qos: will_qos,
+            will_flag: will_flag,
             clean_session: (code & 0b0000_0010) != 0,
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::prelude::v1::*;
+    use Encodable;
+
+    #[test]
+    fn test_connect_flags_encode_never_sets_reserved_bit() {
+        let mut flags = ConnectFlags::empty();
+        flags.will_flag = true;
+        flags.will_qos = 0xff;
+
+        let mut buf = Vec::new();
+        flags.encode(&mut buf).unwrap();
+        assert_eq!(&[0b0001_1100][..], &buf[..]);
+    }
+}
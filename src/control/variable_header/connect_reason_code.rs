@@ -0,0 +1,91 @@
+use std::io::{Read, Write};
+use std::convert::From;
+use std::fmt;
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+
+use control::variable_header::{ConnectReturnCode, VariableHeaderError};
+use {Encodable, Decodable};
+
+impl_reason_code! {
+    /// Reason Code of an MQTT 5 CONNACK, it replaces the Connect Return Code of MQTT 3.1.1
+    pub enum ConnectReasonCode {
+        Success                     = 0x00 => "Success",
+        UnspecifiedError            = 0x80 => "Unspecified error",
+        MalformedPacket             = 0x81 => "Malformed Packet",
+        ProtocolError               = 0x82 => "Protocol Error",
+        ImplementationSpecificError = 0x83 => "Implementation specific error",
+        UnsupportedProtocolVersion  = 0x84 => "Unsupported Protocol Version",
+        ClientIdentifierNotValid    = 0x85 => "Client Identifier not valid",
+        BadUserNameOrPassword       = 0x86 => "Bad User Name or Password",
+        NotAuthorized               = 0x87 => "Not authorized",
+        ServerUnavailable           = 0x88 => "Server unavailable",
+        ServerBusy                  = 0x89 => "Server busy",
+        Banned                      = 0x8A => "Banned",
+        BadAuthenticationMethod     = 0x8C => "Bad authentication method",
+        TopicNameInvalid            = 0x90 => "Topic Name invalid",
+        PacketTooLarge              = 0x95 => "Packet too large",
+        QuotaExceeded               = 0x97 => "Quota exceeded",
+        PayloadFormatInvalid        = 0x99 => "Payload format invalid",
+        RetainNotSupported          = 0x9A => "Retain not supported",
+        QoSNotSupported             = 0x9B => "QoS not supported",
+        UseAnotherServer            = 0x9C => "Use another server",
+        ServerMoved                 = 0x9D => "Server moved",
+        ConnectionRateExceeded      = 0x9F => "Connection rate exceeded",
+    } Unspecified
+}
+
+impl ConnectReasonCode {
+    /// The MQTT 3.1.1 return code with the same meaning, if there is one
+    pub fn to_return_code(&self) -> Option<ConnectReturnCode> {
+        match self {
+            &ConnectReasonCode::Success => Some(ConnectReturnCode::ConnectionAccepted),
+            &ConnectReasonCode::UnsupportedProtocolVersion => Some(ConnectReturnCode::UnacceptableProtocolVersion),
+            &ConnectReasonCode::ClientIdentifierNotValid => Some(ConnectReturnCode::IdentifierRejected),
+            &ConnectReasonCode::ServerUnavailable => Some(ConnectReturnCode::ServiceUnavailable),
+            &ConnectReasonCode::BadUserNameOrPassword => Some(ConnectReturnCode::BadUserNameOrPassword),
+            &ConnectReasonCode::NotAuthorized => Some(ConnectReturnCode::NotAuthorized),
+            _ => None,
+        }
+    }
+}
+
+impl From<ConnectReturnCode> for ConnectReasonCode {
+    fn from(code: ConnectReturnCode) -> ConnectReasonCode {
+        match code {
+            ConnectReturnCode::ConnectionAccepted => ConnectReasonCode::Success,
+            ConnectReturnCode::UnacceptableProtocolVersion => ConnectReasonCode::UnsupportedProtocolVersion,
+            ConnectReturnCode::IdentifierRejected => ConnectReasonCode::ClientIdentifierNotValid,
+            ConnectReturnCode::ServiceUnavailable => ConnectReasonCode::ServerUnavailable,
+            ConnectReturnCode::BadUserNameOrPassword => ConnectReasonCode::BadUserNameOrPassword,
+            ConnectReturnCode::NotAuthorized => ConnectReasonCode::NotAuthorized,
+            ConnectReturnCode::Reserved(code) => ConnectReasonCode::Unspecified(code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::prelude::v1::*;
+
+    #[test]
+    fn test_connect_reason_code() {
+        for code in 0..256 {
+            assert_eq!(code as u8, ConnectReasonCode::from_u8(code as u8).to_u8());
+        }
+
+        assert!(ConnectReasonCode::Success.is_success());
+        assert!(!ConnectReasonCode::Banned.is_success());
+        assert!(ConnectReasonCode::ConnectionRateExceeded.is_defined());
+        assert_eq!(ConnectReasonCode::Unspecified(0x8B), ConnectReasonCode::from(0x8B));
+        assert!(!ConnectReasonCode::from(0x8B).is_defined());
+        assert_eq!("Server busy", ConnectReasonCode::ServerBusy.to_string());
+
+        assert_eq!(ConnectReasonCode::NotAuthorized, ConnectReasonCode::from(ConnectReturnCode::NotAuthorized));
+        assert_eq!(Some(ConnectReturnCode::IdentifierRejected),
+                   ConnectReasonCode::ClientIdentifierNotValid.to_return_code());
+        assert_eq!(None, ConnectReasonCode::Banned.to_return_code());
+    }
+}
@@ -1,5 +1,6 @@
 use std::io::{Read, Write};
 use std::convert::From;
+use std::fmt;
 
 use byteorder::{ReadBytesExt, WriteBytesExt};
 
@@ -48,6 +49,32 @@ impl ConnectReturnCode {
             _ => ConnectReturnCode::Reserved(code),
         }
     }
+
+    pub fn is_accepted(&self) -> bool {
+        *self == ConnectReturnCode::ConnectionAccepted
+    }
+}
+
+impl From<u8> for ConnectReturnCode {
+    fn from(code: u8) -> ConnectReturnCode {
+        ConnectReturnCode::from_u8(code)
+    }
+}
+
+impl fmt::Display for ConnectReturnCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConnectReturnCode::ConnectionAccepted => write!(f, "Connection accepted"),
+            ConnectReturnCode::UnacceptableProtocolVersion =>
+                write!(f, "Connection refused, unacceptable protocol version"),
+            ConnectReturnCode::IdentifierRejected => write!(f, "Connection refused, identifier rejected"),
+            ConnectReturnCode::ServiceUnavailable => write!(f, "Connection refused, server unavailable"),
+            ConnectReturnCode::BadUserNameOrPassword =>
+                write!(f, "Connection refused, bad user name or password"),
+            ConnectReturnCode::NotAuthorized => write!(f, "Connection refused, not authorized"),
+            ConnectReturnCode::Reserved(code) => write!(f, "Reserved return code ({})", code),
+        }
+    }
 }
 
 impl<'a> Encodable<'a> for ConnectReturnCode {
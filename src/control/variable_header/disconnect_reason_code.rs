@@ -0,0 +1,43 @@
+use std::io::{Read, Write};
+use std::convert::From;
+use std::fmt;
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+
+use control::variable_header::VariableHeaderError;
+use {Encodable, Decodable};
+
+impl_reason_code! {
+    /// Reason Code of an MQTT 5 DISCONNECT
+    pub enum DisconnectReasonCode {
+        NormalDisconnection                 = 0x00 => "Normal disconnection",
+        DisconnectWithWillMessage           = 0x04 => "Disconnect with Will Message",
+        UnspecifiedError                    = 0x80 => "Unspecified error",
+        MalformedPacket                     = 0x81 => "Malformed Packet",
+        ProtocolError                       = 0x82 => "Protocol Error",
+        ImplementationSpecificError         = 0x83 => "Implementation specific error",
+        NotAuthorized                       = 0x87 => "Not authorized",
+        ServerBusy                          = 0x89 => "Server busy",
+        ServerShuttingDown                  = 0x8B => "Server shutting down",
+        KeepAliveTimeout                    = 0x8D => "Keep Alive timeout",
+        SessionTakenOver                    = 0x8E => "Session taken over",
+        TopicFilterInvalid                  = 0x8F => "Topic Filter invalid",
+        TopicNameInvalid                    = 0x90 => "Topic Name invalid",
+        ReceiveMaximumExceeded              = 0x93 => "Receive Maximum exceeded",
+        TopicAliasInvalid                   = 0x94 => "Topic Alias invalid",
+        PacketTooLarge                      = 0x95 => "Packet too large",
+        MessageRateTooHigh                  = 0x96 => "Message rate too high",
+        QuotaExceeded                       = 0x97 => "Quota exceeded",
+        AdministrativeAction                = 0x98 => "Administrative action",
+        PayloadFormatInvalid                = 0x99 => "Payload format invalid",
+        RetainNotSupported                  = 0x9A => "Retain not supported",
+        QoSNotSupported                     = 0x9B => "QoS not supported",
+        UseAnotherServer                    = 0x9C => "Use another server",
+        ServerMoved                         = 0x9D => "Server moved",
+        SharedSubscriptionsNotSupported     = 0x9E => "Shared Subscriptions not supported",
+        ConnectionRateExceeded              = 0x9F => "Connection rate exceeded",
+        MaximumConnectTime                  = 0xA0 => "Maximum connect time",
+        SubscriptionIdentifiersNotSupported = 0xA1 => "Subscription Identifiers not supported",
+        WildcardSubscriptionsNotSupported   = 0xA2 => "Wildcard Subscriptions not supported",
+    } Unspecified
+}
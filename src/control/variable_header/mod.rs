@@ -7,16 +7,29 @@ use std::string::FromUtf8Error;
 use byteorder;
 
 use Encodable;
-use encodable::StringEncodeError;
+use encodable::{StringEncodeError, VarIntError};
 
 pub use self::packet_identifier::PacketIdentifier;
 pub use self::protocol_name::ProtocolName;
-pub use self::protocol_level::ProtocolLevel;
+pub use self::protocol_level::{ProtocolLevel, ProtocolVersion};
 pub use self::connect_flags::ConnectFlags;
 pub use self::keep_alive::KeepAlive;
 pub use self::connect_ack_flags::ConnackFlags;
 pub use self::connect_ret_code::ConnectReturnCode;
+pub use self::connect_reason_code::ConnectReasonCode;
+pub use self::auth_reason_code::AuthReasonCode;
+pub use self::disconnect_reason_code::DisconnectReasonCode;
+pub use self::puback_reason_code::PubackReasonCode;
+pub use self::pubrec_reason_code::PubrecReasonCode;
+pub use self::pubrel_reason_code::PubrelReasonCode;
+pub use self::pubcomp_reason_code::PubcompReasonCode;
+pub use self::suback_reason_code::SubackReasonCode;
+pub use self::unsuback_reason_code::UnsubackReasonCode;
 pub use self::topic_name::TopicName;
+pub use self::properties::{fit_within, Properties, Property, PropertyType, UserProperty};
+
+#[macro_use]
+mod reason_code;
 
 pub mod packet_identifier;
 pub mod protocol_name;
@@ -25,7 +38,17 @@ pub mod connect_flags;
 pub mod keep_alive;
 pub mod connect_ack_flags;
 pub mod connect_ret_code;
+pub mod connect_reason_code;
+pub mod auth_reason_code;
+pub mod disconnect_reason_code;
+pub mod puback_reason_code;
+pub mod pubrec_reason_code;
+pub mod pubrel_reason_code;
+pub mod pubcomp_reason_code;
+pub mod suback_reason_code;
+pub mod unsuback_reason_code;
 pub mod topic_name;
+pub mod properties;
 
 macro_rules! impl_variable_headers {
     ($($name:ident => $repr:ty,)*) => {
@@ -105,7 +128,34 @@ pub enum VariableHeaderError {
     IoError(io::Error),
     StringEncodeError(StringEncodeError),
     InvalidReservedFlag,
+    ConnectFlagsReservedBitSet,
     FromUtf8Error(FromUtf8Error),
+    InvalidWillQoS(u8),
+    WillQoSWithoutWillFlag,
+    WillRetainWithoutWillFlag,
+    PasswordWithoutUserName,
+    ZeroPacketIdentifier,
+    SessionPresentWithRejection,
+    MalformedVariableByteInteger,
+    InvalidPropertyIdentifier(u32),
+    DuplicateProperty(PropertyType),
+    StringTooLong(usize),
+    NullCharacterInString,
+}
+
+impl VariableHeaderError {
+    /// Normative statement of the spec violated by the decoded data, if any
+    pub fn spec_rule(&self) -> Option<&'static str> {
+        match self {
+            &VariableHeaderError::ConnectFlagsReservedBitSet => Some("MQTT-3.1.2-3"),
+            &VariableHeaderError::WillQoSWithoutWillFlag => Some("MQTT-3.1.2-13"),
+            &VariableHeaderError::InvalidWillQoS(..) => Some("MQTT-3.1.2-14"),
+            &VariableHeaderError::WillRetainWithoutWillFlag => Some("MQTT-3.1.2-15"),
+            &VariableHeaderError::PasswordWithoutUserName => Some("MQTT-3.1.2-22"),
+            &VariableHeaderError::SessionPresentWithRejection => Some("MQTT-3.2.2-4"),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for VariableHeaderError {
@@ -120,6 +170,15 @@ impl From<byteorder::Error> for VariableHeaderError {
     }
 }
 
+impl From<VarIntError> for VariableHeaderError {
+    fn from(err: VarIntError) -> VariableHeaderError {
+        match err {
+            VarIntError::IoError(err) => VariableHeaderError::IoError(err),
+            VarIntError::TooLong | VarIntError::NonMinimal => VariableHeaderError::MalformedVariableByteInteger,
+        }
+    }
+}
+
 impl From<FromUtf8Error> for VariableHeaderError {
     fn from(err: FromUtf8Error) -> VariableHeaderError {
         VariableHeaderError::FromUtf8Error(err)
@@ -138,7 +197,26 @@ impl fmt::Display for VariableHeaderError {
             &VariableHeaderError::IoError(ref err) => write!(f, "{}", err),
             &VariableHeaderError::StringEncodeError(ref err) => write!(f, "{}", err),
             &VariableHeaderError::InvalidReservedFlag => write!(f, "Invalid reserved flags"),
+            &VariableHeaderError::ConnectFlagsReservedBitSet =>
+                write!(f, "Reserved bit of the connect flags is set (MQTT-3.1.2-3)"),
             &VariableHeaderError::FromUtf8Error(ref err) => write!(f, "{}", err),
+            &VariableHeaderError::InvalidWillQoS(qos) => write!(f, "Invalid Will QoS {} (MQTT-3.1.2-14)", qos),
+            &VariableHeaderError::WillQoSWithoutWillFlag =>
+                write!(f, "Will QoS is set without the Will Flag (MQTT-3.1.2-13)"),
+            &VariableHeaderError::WillRetainWithoutWillFlag =>
+                write!(f, "Will Retain is set without the Will Flag (MQTT-3.1.2-15)"),
+            &VariableHeaderError::PasswordWithoutUserName =>
+                write!(f, "Password Flag is set without the User Name Flag (MQTT-3.1.2-22)"),
+            &VariableHeaderError::ZeroPacketIdentifier => write!(f, "Packet Identifier is zero"),
+            &VariableHeaderError::SessionPresentWithRejection =>
+                write!(f, "Session Present is set with a non-zero return code (MQTT-3.2.2-4)"),
+            &VariableHeaderError::MalformedVariableByteInteger => write!(f, "Malformed variable byte integer"),
+            &VariableHeaderError::InvalidPropertyIdentifier(id) => write!(f, "Invalid property identifier 0x{:02x}", id),
+            &VariableHeaderError::DuplicateProperty(property_type) =>
+                write!(f, "{:?} property (0x{:02x}) included more than once", property_type, property_type.to_u8()),
+            &VariableHeaderError::StringTooLong(len) => write!(f, "String of {} bytes is longer than 65535 bytes", len),
+            &VariableHeaderError::NullCharacterInString =>
+                write!(f, "String contains the null character U+0000 (MQTT-1.5.4-2)"),
         }
     }
 }
@@ -149,7 +227,19 @@ impl Error for VariableHeaderError {
             &VariableHeaderError::IoError(ref err) => err.description(),
             &VariableHeaderError::StringEncodeError(ref err) => err.description(),
             &VariableHeaderError::InvalidReservedFlag => "Invalid reserved flags",
+            &VariableHeaderError::ConnectFlagsReservedBitSet => "Reserved bit of the connect flags is set",
             &VariableHeaderError::FromUtf8Error(ref err) => err.description(),
+            &VariableHeaderError::InvalidWillQoS(..) => "Invalid Will QoS",
+            &VariableHeaderError::WillQoSWithoutWillFlag => "Will QoS is set without the Will Flag",
+            &VariableHeaderError::WillRetainWithoutWillFlag => "Will Retain is set without the Will Flag",
+            &VariableHeaderError::PasswordWithoutUserName => "Password Flag is set without the User Name Flag",
+            &VariableHeaderError::ZeroPacketIdentifier => "Packet Identifier is zero",
+            &VariableHeaderError::SessionPresentWithRejection => "Session Present is set with a non-zero return code",
+            &VariableHeaderError::MalformedVariableByteInteger => "Malformed variable byte integer",
+            &VariableHeaderError::InvalidPropertyIdentifier(..) => "Invalid property identifier",
+            &VariableHeaderError::DuplicateProperty(..) => "Property included more than once",
+            &VariableHeaderError::StringTooLong(..) => "String is longer than 65535 bytes",
+            &VariableHeaderError::NullCharacterInString => "String contains the null character",
         }
     }
 
@@ -158,7 +248,19 @@ impl Error for VariableHeaderError {
             &VariableHeaderError::IoError(ref err) => Some(err),
             &VariableHeaderError::StringEncodeError(ref err) => Some(err),
             &VariableHeaderError::InvalidReservedFlag => None,
+            &VariableHeaderError::ConnectFlagsReservedBitSet => None,
             &VariableHeaderError::FromUtf8Error(ref err) => Some(err),
+            &VariableHeaderError::InvalidWillQoS(..) => None,
+            &VariableHeaderError::WillQoSWithoutWillFlag => None,
+            &VariableHeaderError::WillRetainWithoutWillFlag => None,
+            &VariableHeaderError::PasswordWithoutUserName => None,
+            &VariableHeaderError::ZeroPacketIdentifier => None,
+            &VariableHeaderError::SessionPresentWithRejection => None,
+            &VariableHeaderError::MalformedVariableByteInteger => None,
+            &VariableHeaderError::InvalidPropertyIdentifier(..) => None,
+            &VariableHeaderError::DuplicateProperty(..) => None,
+            &VariableHeaderError::StringTooLong(..) => None,
+            &VariableHeaderError::NullCharacterInString => None,
         }
     }
 }
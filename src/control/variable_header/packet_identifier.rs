@@ -1,19 +1,51 @@
 use std::io::{Read, Write};
 use std::convert::From;
+use std::fmt;
+use std::num::NonZeroU16;
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 use control::variable_header::VariableHeaderError;
 use {Encodable, Decodable};
 
-#[derive(Debug, Eq, PartialEq, Copy, Clone)]
-pub struct PacketIdentifier(pub u16);
+/// Packet Identifier of PUBLISH (QoS > 0), SUBSCRIBE and UNSUBSCRIBE, which must be non-zero
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Copy, Clone)]
+pub struct PacketIdentifier(NonZeroU16);
+
+impl PacketIdentifier {
+    /// Returns `None` for 0
+    pub fn new(pkid: u16) -> Option<PacketIdentifier> {
+        NonZeroU16::new(pkid).map(PacketIdentifier)
+    }
+
+    pub fn get(&self) -> u16 {
+        self.0.get()
+    }
+}
+
+impl From<NonZeroU16> for PacketIdentifier {
+    fn from(pkid: NonZeroU16) -> PacketIdentifier {
+        PacketIdentifier(pkid)
+    }
+}
+
+impl From<PacketIdentifier> for u16 {
+    fn from(pkid: PacketIdentifier) -> u16 {
+        pkid.get()
+    }
+}
+
+impl fmt::Display for PacketIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
 
 impl<'a> Encodable<'a> for PacketIdentifier {
     type Err = VariableHeaderError;
 
     fn encode<W: Write>(&self, writer: &mut W) -> Result<(), VariableHeaderError> {
-        writer.write_u16::<BigEndian>(self.0)
+        writer.write_u16::<BigEndian>(self.get())
             .map_err(From::from)
     }
 
@@ -27,8 +59,7 @@ impl<'a> Decodable<'a> for PacketIdentifier {
     type Cond = ();
 
     fn decode_with<R: Read>(reader: &mut R, _rest: Option<()>) -> Result<PacketIdentifier, VariableHeaderError> {
-        reader.read_u16::<BigEndian>()
-            .map(PacketIdentifier)
-            .map_err(From::from)
+        let pkid = try!(reader.read_u16::<BigEndian>());
+        PacketIdentifier::new(pkid).ok_or(VariableHeaderError::ZeroPacketIdentifier)
     }
 }
@@ -0,0 +1,794 @@
+//! MQTT 5 properties
+//!
+//! Most MQTT 5 packets carry a property block in their variable header: a Variable Byte Integer
+//! with the length of the block, followed by the properties, each an identifier and a value of
+//! the data type fixed by the identifier (MQTT 5 section 2.2.2).
+
+use std::prelude::v1::*;
+use std::io::{Read, Write};
+use std::slice;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use control::variable_header::VariableHeaderError;
+use encodable::{VarBytes, VarInt};
+use {Encodable, Decodable};
+
+/// Data types of property values (MQTT 5 section 1.5)
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum PropertyDataType {
+    Byte,
+    TwoByteInteger,
+    FourByteInteger,
+    VariableByteInteger,
+    Utf8String,
+    Utf8StringPair,
+    BinaryData,
+}
+
+// Codecs of the data types, named after `PropertyDataType` so that `impl_properties!` can pick
+// them by the same identifier. Values are passed as tuples because a string pair has two.
+
+struct Byte;
+
+impl Byte {
+    fn encode<W: Write>((value,): (&u8,), writer: &mut W) -> Result<(), VariableHeaderError> {
+        writer.write_u8(*value).map_err(From::from)
+    }
+
+    fn encoded_length(_: (&u8,)) -> u32 {
+        1
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> Result<(u8,), VariableHeaderError> {
+        Ok((try!(reader.read_u8()),))
+    }
+}
+
+struct TwoByteInteger;
+
+impl TwoByteInteger {
+    fn encode<W: Write>((value,): (&u16,), writer: &mut W) -> Result<(), VariableHeaderError> {
+        writer.write_u16::<BigEndian>(*value).map_err(From::from)
+    }
+
+    fn encoded_length(_: (&u16,)) -> u32 {
+        2
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> Result<(u16,), VariableHeaderError> {
+        Ok((try!(reader.read_u16::<BigEndian>()),))
+    }
+}
+
+struct FourByteInteger;
+
+impl FourByteInteger {
+    fn encode<W: Write>((value,): (&u32,), writer: &mut W) -> Result<(), VariableHeaderError> {
+        writer.write_u32::<BigEndian>(*value).map_err(From::from)
+    }
+
+    fn encoded_length(_: (&u32,)) -> u32 {
+        4
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> Result<(u32,), VariableHeaderError> {
+        Ok((try!(reader.read_u32::<BigEndian>()),))
+    }
+}
+
+struct VariableByteInteger;
+
+impl VariableByteInteger {
+    fn encode<W: Write>((value,): (&u32,), writer: &mut W) -> Result<(), VariableHeaderError> {
+        VarInt(*value).encode(writer).map_err(From::from)
+    }
+
+    fn encoded_length((value,): (&u32,)) -> u32 {
+        VarInt(*value).encoded_length()
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> Result<(u32,), VariableHeaderError> {
+        Ok((try!(VarInt::decode_with(reader, Some(true))).0,))
+    }
+}
+
+struct Utf8String;
+
+impl Utf8String {
+    fn encode<W: Write>((value,): (&String,), writer: &mut W) -> Result<(), VariableHeaderError> {
+        value.encode(writer).map_err(From::from)
+    }
+
+    fn encoded_length((value,): (&String,)) -> u32 {
+        value.encoded_length()
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> Result<(String,), VariableHeaderError> {
+        Ok((try!(String::decode(reader)),))
+    }
+}
+
+// Only used by User Properties, both strings are checked whatever the decode options say
+struct Utf8StringPair;
+
+impl Utf8StringPair {
+    fn encode<W: Write>((key, value): (&String, &String), writer: &mut W) -> Result<(), VariableHeaderError> {
+        try!(Utf8StringPair::validate(key));
+        try!(Utf8StringPair::validate(value));
+        try!(key.encode(writer));
+        value.encode(writer).map_err(From::from)
+    }
+
+    fn encoded_length((key, value): (&String, &String)) -> u32 {
+        key.encoded_length() + value.encoded_length()
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> Result<(String, String), VariableHeaderError> {
+        let key = try!(String::decode(reader));
+        try!(Utf8StringPair::validate(&key));
+        let value = try!(String::decode(reader));
+        try!(Utf8StringPair::validate(&value));
+        Ok((key, value))
+    }
+
+    fn validate(s: &str) -> Result<(), VariableHeaderError> {
+        if s.len() > u16::max_value() as usize {
+            return Err(VariableHeaderError::StringTooLong(s.len()));
+        }
+        if s.contains('\0') {
+            return Err(VariableHeaderError::NullCharacterInString);
+        }
+        Ok(())
+    }
+}
+
+/// Name and value of a User Property, sent in the order they were added and possibly several
+/// times with the same name (MQTT 5 section 3.1.2.11.8)
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub struct UserProperty(pub String, pub String);
+
+impl UserProperty {
+    pub fn new<K: Into<String>, V: Into<String>>(key: K, value: V) -> UserProperty {
+        UserProperty(key.into(), value.into())
+    }
+
+    pub fn key(&self) -> &str {
+        &self.0[..]
+    }
+
+    pub fn value(&self) -> &str {
+        &self.1[..]
+    }
+}
+
+impl<'a> Encodable<'a> for UserProperty {
+    type Err = VariableHeaderError;
+
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), VariableHeaderError> {
+        Utf8StringPair::encode((&self.0, &self.1), writer)
+    }
+
+    fn encoded_length(&self) -> u32 {
+        Utf8StringPair::encoded_length((&self.0, &self.1))
+    }
+}
+
+impl<'a> Decodable<'a> for UserProperty {
+    type Err = VariableHeaderError;
+    type Cond = ();
+
+    fn decode_with<R: Read>(reader: &mut R, _rest: Option<()>) -> Result<UserProperty, VariableHeaderError> {
+        let (key, value) = try!(Utf8StringPair::decode(reader));
+        Ok(UserProperty(key, value))
+    }
+}
+
+impl From<UserProperty> for Property {
+    fn from(property: UserProperty) -> Property {
+        Property::UserProperty(property.0, property.1)
+    }
+}
+
+struct BinaryData;
+
+impl BinaryData {
+    fn encode<W: Write>((value,): (&Vec<u8>,), writer: &mut W) -> Result<(), VariableHeaderError> {
+        assert!(value.len() <= u16::max_value() as usize);
+
+        try!(writer.write_u16::<BigEndian>(value.len() as u16));
+        writer.write_all(value).map_err(From::from)
+    }
+
+    fn encoded_length((value,): (&Vec<u8>,)) -> u32 {
+        2 + value.len() as u32
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> Result<(Vec<u8>,), VariableHeaderError> {
+        Ok((try!(VarBytes::decode(reader)).0,))
+    }
+}
+
+macro_rules! impl_properties {
+    ($($name:ident = $id:expr => $data_type:ident($($value:ident: $repr:ty),+),)*) => {
+        /// Property identifiers
+        #[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+        pub enum PropertyType {
+            $(
+                $name = $id,
+            )*
+        }
+
+        impl PropertyType {
+            pub fn from_u8(id: u8) -> Option<PropertyType> {
+                match id {
+                    $(
+                        $id => Some(PropertyType::$name),
+                    )*
+                    _ => None,
+                }
+            }
+
+            pub fn to_u8(&self) -> u8 {
+                *self as u8
+            }
+
+            /// Data type of the value, fixed by the identifier
+            pub fn data_type(&self) -> PropertyDataType {
+                match self {
+                    $(
+                        &PropertyType::$name => PropertyDataType::$data_type,
+                    )*
+                }
+            }
+        }
+
+        /// A property and its value
+        #[derive(Debug, Eq, PartialEq, Clone)]
+        #[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+        pub enum Property {
+            $(
+                $name($($repr),+),
+            )*
+        }
+
+        impl Property {
+            pub fn property_type(&self) -> PropertyType {
+                match self {
+                    $(
+                        &Property::$name(..) => PropertyType::$name,
+                    )*
+                }
+            }
+
+            fn encode_value<W: Write>(&self, writer: &mut W) -> Result<(), VariableHeaderError> {
+                match self {
+                    $(
+                        &Property::$name($(ref $value),+) => $data_type::encode(($($value,)+), writer),
+                    )*
+                }
+            }
+
+            fn encoded_value_length(&self) -> u32 {
+                match self {
+                    $(
+                        &Property::$name($(ref $value),+) => $data_type::encoded_length(($($value,)+)),
+                    )*
+                }
+            }
+
+            fn decode_value<R: Read>(property_type: PropertyType, reader: &mut R)
+                    -> Result<Property, VariableHeaderError> {
+                match property_type {
+                    $(
+                        PropertyType::$name => {
+                            let ($($value,)+) = try!($data_type::decode(reader));
+                            Ok(Property::$name($($value),+))
+                        },
+                    )*
+                }
+            }
+        }
+    }
+}
+
+impl_properties! {
+    PayloadFormatIndicator          = 0x01 => Byte(value: u8),
+    MessageExpiryInterval           = 0x02 => FourByteInteger(value: u32),
+    ContentType                     = 0x03 => Utf8String(value: String),
+    ResponseTopic                   = 0x08 => Utf8String(value: String),
+    CorrelationData                 = 0x09 => BinaryData(value: Vec<u8>),
+    SubscriptionIdentifier          = 0x0B => VariableByteInteger(value: u32),
+    SessionExpiryInterval           = 0x11 => FourByteInteger(value: u32),
+    AssignedClientIdentifier        = 0x12 => Utf8String(value: String),
+    ServerKeepAlive                 = 0x13 => TwoByteInteger(value: u16),
+    AuthenticationMethod            = 0x15 => Utf8String(value: String),
+    AuthenticationData              = 0x16 => BinaryData(value: Vec<u8>),
+    RequestProblemInformation       = 0x17 => Byte(value: u8),
+    WillDelayInterval               = 0x18 => FourByteInteger(value: u32),
+    RequestResponseInformation      = 0x19 => Byte(value: u8),
+    ResponseInformation             = 0x1A => Utf8String(value: String),
+    ServerReference                 = 0x1C => Utf8String(value: String),
+    ReasonString                    = 0x1F => Utf8String(value: String),
+    ReceiveMaximum                  = 0x21 => TwoByteInteger(value: u16),
+    TopicAliasMaximum               = 0x22 => TwoByteInteger(value: u16),
+    TopicAlias                      = 0x23 => TwoByteInteger(value: u16),
+    MaximumQoS                      = 0x24 => Byte(value: u8),
+    RetainAvailable                 = 0x25 => Byte(value: u8),
+    UserProperty                    = 0x26 => Utf8StringPair(key: String, value: String),
+    MaximumPacketSize               = 0x27 => FourByteInteger(value: u32),
+    WildcardSubscriptionAvailable   = 0x28 => Byte(value: u8),
+    SubscriptionIdentifierAvailable = 0x29 => Byte(value: u8),
+    SharedSubscriptionAvailable     = 0x2A => Byte(value: u8),
+}
+
+impl PropertyType {
+    /// Whether the property may appear more than once in a packet
+    ///
+    /// Subscription Identifier may only repeat in PUBLISH, which the packet has to check.
+    pub fn may_repeat(&self) -> bool {
+        match self {
+            &PropertyType::UserProperty | &PropertyType::SubscriptionIdentifier => true,
+            _ => false,
+        }
+    }
+}
+
+impl Property {
+    fn encoded_length(&self) -> u32 {
+        VarInt(self.property_type().to_u8() as u32).encoded_length() + self.encoded_value_length()
+    }
+}
+
+/// Property block of a packet, in wire order
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct Properties {
+    properties: Vec<Property>,
+}
+
+macro_rules! property_getters {
+    ($($getter:ident -> $ret:ty = $name:ident($value:ident) => $conv:expr,)*) => {
+        $(
+            pub fn $getter(&self) -> Option<$ret> {
+                self.properties.iter().filter_map(|property| match property {
+                    &Property::$name(ref $value) => Some($conv),
+                    _ => None,
+                }).next()
+            }
+        )*
+    }
+}
+
+impl Properties {
+    pub fn new() -> Properties {
+        Properties { properties: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.properties.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.properties.len()
+    }
+
+    pub fn iter<'b>(&'b self) -> slice::Iter<'b, Property> {
+        self.properties.iter()
+    }
+
+    /// First property of the type
+    pub fn get(&self, property_type: PropertyType) -> Option<&Property> {
+        self.properties.iter().find(|property| property.property_type() == property_type)
+    }
+
+    /// All properties of the type, in wire order
+    pub fn get_all<'b>(&'b self, property_type: PropertyType) -> impl Iterator<Item = &'b Property> + 'b {
+        self.properties.iter().filter(move |property| property.property_type() == property_type)
+    }
+
+    /// Values of the Subscription Identifiers, a delivered PUBLISH carries one per matching subscription
+    pub fn subscription_identifiers<'b>(&'b self) -> impl Iterator<Item = u32> + 'b {
+        self.get_all(PropertyType::SubscriptionIdentifier).filter_map(|property| match property {
+            &Property::SubscriptionIdentifier(id) => Some(id),
+            _ => None,
+        })
+    }
+
+    /// Adds a property, replacing the one of the same type unless the type may repeat
+    pub fn insert(&mut self, property: Property) {
+        let property_type = property.property_type();
+        if !property_type.may_repeat() {
+            if let Some(existing) = self.properties.iter_mut().find(|p| p.property_type() == property_type) {
+                *existing = property;
+                return;
+            }
+        }
+        self.properties.push(property);
+    }
+
+    /// Adds a User Property after the ones already present, even if one has the same key
+    pub fn push_user_property<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        self.properties.push(Property::UserProperty(key.into(), value.into()));
+    }
+
+    /// Keys and values of the User Properties, in wire order
+    pub fn user_properties<'b>(&'b self) -> impl Iterator<Item = (&'b str, &'b str)> + 'b {
+        self.properties.iter().filter_map(|property| match property {
+            &Property::UserProperty(ref key, ref value) => Some((&key[..], &value[..])),
+            _ => None,
+        })
+    }
+
+    /// Value of the first User Property with the key
+    pub fn first_user_property(&self, key: &str) -> Option<&str> {
+        self.user_properties().find(|&(k, _)| k == key).map(|(_, value)| value)
+    }
+
+    /// Sets or removes the Reason String
+    pub fn set_reason_string(&mut self, reason: Option<String>) {
+        match reason {
+            Some(reason) => self.insert(Property::ReasonString(reason)),
+            None => self.remove(PropertyType::ReasonString),
+        }
+    }
+
+    /// Removes all properties of the type
+    pub fn remove(&mut self, property_type: PropertyType) {
+        self.properties.retain(|property| property.property_type() != property_type);
+    }
+
+    /// Checks that every property may be sent in `packet`, naming the first one that may not
+    pub fn check_allowed(&self, allowed: &[PropertyType], packet: &str) -> Result<(), String> {
+        match self.properties.iter().map(Property::property_type).find(|t| !allowed.contains(t)) {
+            Some(property_type) => Err(format!("Property {:?} (0x{:02X}) is not allowed in {}",
+                                               property_type, property_type.to_u8(), packet)),
+            None => Ok(()),
+        }
+    }
+
+    /// Length of the properties without the Property Length in front of them
+    pub fn properties_length(&self) -> u32 {
+        self.properties.iter().map(Property::encoded_length).sum()
+    }
+
+    property_getters! {
+        payload_format_indicator -> u8 = PayloadFormatIndicator(value) => *value,
+        message_expiry_interval -> u32 = MessageExpiryInterval(value) => *value,
+        content_type -> &str = ContentType(value) => &value[..],
+        response_topic -> &str = ResponseTopic(value) => &value[..],
+        correlation_data -> &[u8] = CorrelationData(value) => &value[..],
+        session_expiry_interval -> u32 = SessionExpiryInterval(value) => *value,
+        assigned_client_identifier -> &str = AssignedClientIdentifier(value) => &value[..],
+        server_keep_alive -> u16 = ServerKeepAlive(value) => *value,
+        authentication_method -> &str = AuthenticationMethod(value) => &value[..],
+        authentication_data -> &[u8] = AuthenticationData(value) => &value[..],
+        request_problem_information -> u8 = RequestProblemInformation(value) => *value,
+        will_delay_interval -> u32 = WillDelayInterval(value) => *value,
+        request_response_information -> u8 = RequestResponseInformation(value) => *value,
+        response_information -> &str = ResponseInformation(value) => &value[..],
+        server_reference -> &str = ServerReference(value) => &value[..],
+        reason_string -> &str = ReasonString(value) => &value[..],
+        receive_maximum -> u16 = ReceiveMaximum(value) => *value,
+        topic_alias_maximum -> u16 = TopicAliasMaximum(value) => *value,
+        topic_alias -> u16 = TopicAlias(value) => *value,
+        maximum_qos -> u8 = MaximumQoS(value) => *value,
+        retain_available -> u8 = RetainAvailable(value) => *value,
+        maximum_packet_size -> u32 = MaximumPacketSize(value) => *value,
+        wildcard_subscription_available -> u8 = WildcardSubscriptionAvailable(value) => *value,
+        subscription_identifier_available -> u8 = SubscriptionIdentifierAvailable(value) => *value,
+        shared_subscription_available -> u8 = SharedSubscriptionAvailable(value) => *value,
+    }
+}
+
+/// Drops the Reason String and then User Properties, last one first, until a packet with these
+/// properties is at most `max_packet_size` bytes long (MQTT 5 section 3.1.2.11.4)
+///
+/// `base_len` is the Remaining Length of the packet without the properties and their Property
+/// Length. Returns whether the packet fits, other properties are never dropped.
+pub fn fit_within(properties: &mut Properties, max_packet_size: u32, base_len: u32) -> bool {
+    let packet_size = |properties: &Properties| {
+        let remaining_length = base_len as u64 + properties.encoded_length() as u64;
+        1 + VarInt(remaining_length as u32).encoded_length() as u64 + remaining_length
+    };
+
+    if packet_size(properties) <= max_packet_size as u64 {
+        return true;
+    }
+
+    properties.remove(PropertyType::ReasonString);
+    while packet_size(properties) > max_packet_size as u64 {
+        match properties.properties.iter().rposition(|p| p.property_type() == PropertyType::UserProperty) {
+            Some(index) => { properties.properties.remove(index); },
+            None => return false,
+        }
+    }
+    true
+}
+
+impl<'a> Encodable<'a> for Properties {
+    type Err = VariableHeaderError;
+
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), VariableHeaderError> {
+        try!(VarInt(self.properties_length()).encode(writer));
+        for property in &self.properties {
+            try!(VarInt(property.property_type().to_u8() as u32).encode(writer));
+            try!(property.encode_value(writer));
+        }
+        Ok(())
+    }
+
+    fn encoded_length(&self) -> u32 {
+        let len = self.properties_length();
+        VarInt(len).encoded_length() + len
+    }
+}
+
+impl<'a> Decodable<'a> for Properties {
+    type Err = VariableHeaderError;
+    type Cond = ();
+
+    // Variable Byte Integers must be minimal (MQTT 5 section 1.5.5), so `encoded_length` is the
+    // number of bytes read
+    fn decode_with<R: Read>(reader: &mut R, _rest: Option<()>) -> Result<Properties, VariableHeaderError> {
+        let len = try!(VarInt::decode_with(reader, Some(true))).0;
+        let mut reader = reader.take(len as u64);
+
+        let mut properties = Properties::new();
+        while reader.limit() > 0 {
+            let id = try!(VarInt::decode_with(&mut reader, Some(true))).0;
+            let property_type = match PropertyType::from_u8(id as u8) {
+                Some(property_type) if id <= 0xFF => property_type,
+                _ => return Err(VariableHeaderError::InvalidPropertyIdentifier(id)),
+            };
+
+            // MQTT 5 section 2.2.2.2: Including a property more than once is a Protocol Error
+            // unless the property says otherwise
+            if !property_type.may_repeat() && properties.get(property_type).is_some() {
+                return Err(VariableHeaderError::DuplicateProperty(property_type));
+            }
+
+            let property = try!(Property::decode_value(property_type, &mut reader));
+            properties.properties.push(property);
+        }
+
+        Ok(properties)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use control::variable_header::VariableHeaderError;
+    use {Encodable, Decodable};
+
+    fn assert_roundtrip(property: Property, encoded: &[u8]) {
+        let mut properties = Properties::new();
+        properties.insert(property.clone());
+
+        let mut buf = Vec::new();
+        properties.encode(&mut buf).unwrap();
+        assert_eq!(encoded, &buf[..], "{:?}", property);
+        assert_eq!(encoded.len() as u32, properties.encoded_length());
+
+        let decoded = Properties::decode(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(properties, decoded);
+        assert_eq!(Some(&property), decoded.get(property.property_type()));
+    }
+
+    #[test]
+    fn test_properties_data_types() {
+        assert_roundtrip(Property::PayloadFormatIndicator(1), b"\x02\x01\x01");
+        assert_roundtrip(Property::ServerKeepAlive(0x1234), b"\x03\x13\x12\x34");
+        assert_roundtrip(Property::SessionExpiryInterval(0x01020304), b"\x05\x11\x01\x02\x03\x04");
+        assert_roundtrip(Property::SubscriptionIdentifier(1), b"\x02\x0b\x01");
+        assert_roundtrip(Property::SubscriptionIdentifier(128), b"\x03\x0b\x80\x01");
+        assert_roundtrip(Property::SubscriptionIdentifier(268_435_455), b"\x05\x0b\xff\xff\xff\x7f");
+        assert_roundtrip(Property::ContentType("text/plain".to_owned()), b"\x0d\x03\x00\x0atext/plain");
+        assert_roundtrip(Property::UserProperty("k".to_owned(), "v".to_owned()), b"\x07\x26\x00\x01k\x00\x01v");
+        assert_roundtrip(Property::CorrelationData(vec![0, 0xff]), b"\x05\x09\x00\x02\x00\xff");
+    }
+
+    #[test]
+    fn test_properties_every_type() {
+        let all = vec![
+            Property::PayloadFormatIndicator(1),
+            Property::MessageExpiryInterval(60),
+            Property::ContentType("application/json".to_owned()),
+            Property::ResponseTopic("replies/1".to_owned()),
+            Property::CorrelationData(b"req-1".to_vec()),
+            Property::SubscriptionIdentifier(16_384),
+            Property::SessionExpiryInterval(0xFFFFFFFF),
+            Property::AssignedClientIdentifier("auto-1".to_owned()),
+            Property::ServerKeepAlive(30),
+            Property::AuthenticationMethod("SCRAM-SHA-1".to_owned()),
+            Property::AuthenticationData(b"client-first".to_vec()),
+            Property::RequestProblemInformation(0),
+            Property::WillDelayInterval(10),
+            Property::RequestResponseInformation(1),
+            Property::ResponseInformation("responses/".to_owned()),
+            Property::ServerReference("other:1883".to_owned()),
+            Property::ReasonString("ok".to_owned()),
+            Property::ReceiveMaximum(20),
+            Property::TopicAliasMaximum(10),
+            Property::TopicAlias(3),
+            Property::MaximumQoS(1),
+            Property::RetainAvailable(0),
+            Property::UserProperty("a".to_owned(), "1".to_owned()),
+            Property::MaximumPacketSize(1024),
+            Property::WildcardSubscriptionAvailable(1),
+            Property::SubscriptionIdentifierAvailable(1),
+            Property::SharedSubscriptionAvailable(0),
+        ];
+        assert_eq!(27, all.len());
+
+        let mut properties = Properties::new();
+        for property in &all {
+            assert_eq!(Some(property.property_type()), PropertyType::from_u8(property.property_type().to_u8()));
+            properties.insert(property.clone());
+        }
+
+        let mut buf = Vec::new();
+        properties.encode(&mut buf).unwrap();
+        // The Property Length takes two bytes
+        assert_eq!(buf.len() as u32, 2 + properties.properties_length());
+        let decoded = Properties::decode(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(all, decoded.iter().cloned().collect::<Vec<_>>());
+
+        assert_eq!(Some(16_384), decoded.get(PropertyType::SubscriptionIdentifier).and_then(|p| match p {
+            &Property::SubscriptionIdentifier(id) => Some(id),
+            _ => None,
+        }));
+        assert_eq!(Some(0xFFFFFFFF), decoded.session_expiry_interval());
+        assert_eq!(Some("application/json"), decoded.content_type());
+        assert_eq!(Some(&b"client-first"[..]), decoded.authentication_data());
+        assert_eq!(Some(1024), decoded.maximum_packet_size());
+        assert_eq!(None, Properties::new().receive_maximum());
+    }
+
+    #[test]
+    fn test_properties_data_type_of_identifier() {
+        assert_eq!(PropertyDataType::Byte, PropertyType::PayloadFormatIndicator.data_type());
+        assert_eq!(PropertyDataType::TwoByteInteger, PropertyType::ReceiveMaximum.data_type());
+        assert_eq!(PropertyDataType::FourByteInteger, PropertyType::MaximumPacketSize.data_type());
+        assert_eq!(PropertyDataType::VariableByteInteger, PropertyType::SubscriptionIdentifier.data_type());
+        assert_eq!(PropertyDataType::Utf8String, PropertyType::ReasonString.data_type());
+        assert_eq!(PropertyDataType::Utf8StringPair, PropertyType::UserProperty.data_type());
+        assert_eq!(PropertyDataType::BinaryData, PropertyType::CorrelationData.data_type());
+        assert_eq!(None, PropertyType::from_u8(0x04));
+        assert_eq!(None, PropertyType::from_u8(0x2B));
+    }
+
+    #[test]
+    fn test_properties_user_property_order() {
+        let mut properties = Properties::new();
+        properties.insert(Property::UserProperty("trace".to_owned(), "1".to_owned()));
+        properties.insert(Property::ReasonString("first".to_owned()));
+        properties.insert(Property::UserProperty("trace".to_owned(), "2".to_owned()));
+        properties.insert(Property::ReasonString("second".to_owned()));
+        assert_eq!(3, properties.len());
+        assert_eq!(Some("second"), properties.reason_string());
+
+        let mut buf = Vec::new();
+        properties.encode(&mut buf).unwrap();
+        let decoded = Properties::decode(&mut Cursor::new(&buf[..])).unwrap();
+        let users = decoded.get_all(PropertyType::UserProperty).cloned().collect::<Vec<_>>();
+        assert_eq!(vec![Property::UserProperty("trace".to_owned(), "1".to_owned()),
+                        Property::UserProperty("trace".to_owned(), "2".to_owned())],
+                   users);
+
+        properties.remove(PropertyType::UserProperty);
+        assert_eq!(vec![Property::ReasonString("second".to_owned())], properties.iter().cloned().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_properties_decode_errors() {
+        // Reason String twice
+        match Properties::decode(&mut Cursor::new(&b"\x08\x1f\x00\x01a\x1f\x00\x01b"[..])) {
+            Err(VariableHeaderError::DuplicateProperty(PropertyType::ReasonString)) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        match Properties::decode(&mut Cursor::new(&b"\x02\x04\x00"[..])) {
+            Err(VariableHeaderError::InvalidPropertyIdentifier(0x04)) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // The Property Length ends in the middle of the Session Expiry Interval
+        assert!(Properties::decode(&mut Cursor::new(&b"\x03\x11\x00\x00\x00\x00"[..])).is_err());
+
+        assert_eq!(Properties::new(), Properties::decode(&mut Cursor::new(&b"\x00"[..])).unwrap());
+    }
+
+    #[test]
+    fn test_properties_user_property_helpers() {
+        let mut properties = Properties::new();
+        properties.push_user_property("traceparent", "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01");
+        properties.insert(Property::ContentType("text/plain".to_owned()));
+        properties.push_user_property("hop", "edge");
+        properties.push_user_property("hop", "core");
+        properties.insert(UserProperty::new("", "empty key").into());
+
+        let mut buf = Vec::new();
+        properties.encode(&mut buf).unwrap();
+        let decoded = Properties::decode(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(properties, decoded);
+
+        assert_eq!(vec![("traceparent", "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+                        ("hop", "edge"), ("hop", "core"), ("", "empty key")],
+                   decoded.user_properties().collect::<Vec<_>>());
+        assert_eq!(Some("edge"), decoded.first_user_property("hop"));
+        assert_eq!(Some("empty key"), decoded.first_user_property(""));
+        assert_eq!(None, decoded.first_user_property("missing"));
+        assert_eq!(Some("text/plain"), decoded.content_type());
+    }
+
+    #[test]
+    fn test_user_property_codec() {
+        let property = UserProperty::new("k", "v");
+        let mut buf = Vec::new();
+        property.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x00\x01k\x00\x01v"[..], &buf[..]);
+        assert_eq!(buf.len() as u32, property.encoded_length());
+        assert_eq!(property, UserProperty::decode(&mut Cursor::new(&buf[..])).unwrap());
+
+        match UserProperty::new("k", "a\0b").encode(&mut Vec::new()) {
+            Err(VariableHeaderError::NullCharacterInString) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+        match UserProperty::decode(&mut Cursor::new(&b"\x00\x01\x00\x00\x01v"[..])) {
+            Err(VariableHeaderError::NullCharacterInString) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let long = "x".repeat(65_536);
+        match UserProperty::new("k", long).encode(&mut Vec::new()) {
+            Err(VariableHeaderError::StringTooLong(65_536)) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+        UserProperty::new("x".repeat(65_535), "v").encode(&mut Vec::new()).unwrap();
+
+        // Checked inside a property block as well
+        let mut properties = Properties::new();
+        properties.push_user_property("k", "\0");
+        assert!(properties.encode(&mut Vec::new()).is_err());
+        match Properties::decode(&mut Cursor::new(&b"\x07\x26\x00\x01k\x00\x01\x00"[..])) {
+            Err(VariableHeaderError::NullCharacterInString) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_properties_fit_within() {
+        let mut properties = Properties::new();
+        properties.insert(Property::SessionExpiryInterval(0));
+        properties.push_user_property("a", "1");
+        properties.set_reason_string(Some("reason".to_owned()));
+        properties.push_user_property("b", "2");
+        // 5 + 7 + 9 + 7 bytes of properties, 1 byte of Property Length
+        assert_eq!(29, properties.encoded_length());
+
+        // Fixed header, Reason Code and properties
+        let mut unchanged = properties.clone();
+        assert!(fit_within(&mut unchanged, 32, 1));
+        assert_eq!(properties, unchanged);
+
+        let mut trimmed = properties.clone();
+        assert!(fit_within(&mut trimmed, 31, 1));
+        assert_eq!(None, trimmed.reason_string());
+        assert_eq!(vec![("a", "1"), ("b", "2")], trimmed.user_properties().collect::<Vec<_>>());
+
+        let mut trimmed = properties.clone();
+        assert!(fit_within(&mut trimmed, 22, 1));
+        assert_eq!(vec![("a", "1")], trimmed.user_properties().collect::<Vec<_>>());
+
+        let mut trimmed = properties.clone();
+        assert!(!fit_within(&mut trimmed, 8, 1));
+        assert_eq!(vec![&Property::SessionExpiryInterval(0)], trimmed.iter().collect::<Vec<_>>());
+
+        properties.set_reason_string(None);
+        assert_eq!(None, properties.get(PropertyType::ReasonString));
+    }
+}
@@ -6,7 +6,58 @@ use byteorder::{ReadBytesExt, WriteBytesExt};
 use control::variable_header::VariableHeaderError;
 use {Encodable, Decodable};
 
+pub const SPEC_3_1: u8 = 0x03;
 pub const SPEC_3_1_1: u8 = 0x04;
+pub const SPEC_5: u8 = 0x05;
+/// Bit 7 of the protocol level, set by mosquitto bridges (0x83 for 3.1, 0x84 for 3.1.1)
+pub const BRIDGE_FLAG: u8 = 0x80;
+
+/// Protocol versions, identified by the protocol name and level pair in CONNECT
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ProtocolVersion {
+    /// MQTT 3.1, protocol name "MQIsdp" and level 3
+    V3_1,
+    /// MQTT 3.1.1, protocol name "MQTT" and level 4
+    V3_1_1,
+    /// MQTT 5, protocol name "MQTT" and level 5
+    V5,
+}
+
+impl ProtocolVersion {
+    pub fn protocol_name(&self) -> &'static str {
+        match *self {
+            ProtocolVersion::V3_1 => "MQIsdp",
+            ProtocolVersion::V3_1_1 | ProtocolVersion::V5 => "MQTT",
+        }
+    }
+
+    pub fn level(&self) -> u8 {
+        match *self {
+            ProtocolVersion::V3_1 => SPEC_3_1,
+            ProtocolVersion::V3_1_1 => SPEC_3_1_1,
+            ProtocolVersion::V5 => SPEC_5,
+        }
+    }
+
+    /// Version of a protocol level alone, 3.1 is only told apart from 3.1.1 by its level
+    pub fn from_level(level: u8) -> Option<ProtocolVersion> {
+        match level {
+            SPEC_3_1 => Some(ProtocolVersion::V3_1),
+            SPEC_3_1_1 => Some(ProtocolVersion::V3_1_1),
+            SPEC_5 => Some(ProtocolVersion::V5),
+            _ => None,
+        }
+    }
+
+    pub fn from_name_and_level(name: &str, level: u8) -> Option<ProtocolVersion> {
+        match (name, level) {
+            ("MQIsdp", SPEC_3_1) => Some(ProtocolVersion::V3_1),
+            ("MQTT", SPEC_3_1_1) => Some(ProtocolVersion::V3_1_1),
+            ("MQTT", SPEC_5) => Some(ProtocolVersion::V5),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub struct ProtocolLevel(pub u8);
@@ -1,3 +1,4 @@
+use std::prelude::v1::*;
 use std::io::{Read, Write};
 use std::convert::From;
 
@@ -0,0 +1,23 @@
+use std::io::{Read, Write};
+use std::convert::From;
+use std::fmt;
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+
+use control::variable_header::VariableHeaderError;
+use {Encodable, Decodable};
+
+impl_reason_code! {
+    /// Reason Code of an MQTT 5 PUBACK
+    pub enum PubackReasonCode {
+        Success                     = 0x00 => "Success",
+        NoMatchingSubscribers       = 0x10 => "No matching subscribers",
+        UnspecifiedError            = 0x80 => "Unspecified error",
+        ImplementationSpecificError = 0x83 => "Implementation specific error",
+        NotAuthorized               = 0x87 => "Not authorized",
+        TopicNameInvalid            = 0x90 => "Topic Name invalid",
+        PacketIdentifierInUse       = 0x91 => "Packet Identifier in use",
+        QuotaExceeded               = 0x97 => "Quota exceeded",
+        PayloadFormatInvalid        = 0x99 => "Payload format invalid",
+    } Unspecified
+}
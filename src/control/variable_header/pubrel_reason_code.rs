@@ -0,0 +1,16 @@
+use std::io::{Read, Write};
+use std::convert::From;
+use std::fmt;
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+
+use control::variable_header::VariableHeaderError;
+use {Encodable, Decodable};
+
+impl_reason_code! {
+    /// Reason Code of an MQTT 5 PUBREL
+    pub enum PubrelReasonCode {
+        Success                  = 0x00 => "Success",
+        PacketIdentifierNotFound = 0x92 => "Packet Identifier not found",
+    } Unspecified
+}
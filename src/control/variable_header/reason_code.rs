@@ -0,0 +1,93 @@
+/// Defines a one byte MQTT 5 Reason Code enum, values not defined by the spec are kept in
+/// the `$unknown` variant
+macro_rules! impl_reason_code {
+    ($(#[$attr:meta])* pub enum $name:ident {
+        $($variant:ident = $value:expr => $desc:expr,)+
+    } $unknown:ident) => {
+        $(#[$attr])*
+        #[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+        pub enum $name {
+            $(
+                $variant,
+            )+
+            /// Value not defined by the spec
+            $unknown(u8),
+        }
+
+        impl $name {
+            pub fn to_u8(&self) -> u8 {
+                match self {
+                    $(
+                        &$name::$variant => $value,
+                    )+
+                    &$name::$unknown(code) => code,
+                }
+            }
+
+            pub fn from_u8(code: u8) -> $name {
+                match code {
+                    $(
+                        $value => $name::$variant,
+                    )+
+                    _ => $name::$unknown(code),
+                }
+            }
+
+            /// Reason Codes below 0x80 indicate success
+            pub fn is_success(&self) -> bool {
+                self.to_u8() < 0x80
+            }
+
+            /// Reason Codes of 0x80 and above indicate failure
+            pub fn is_error(&self) -> bool {
+                !self.is_success()
+            }
+
+            /// Whether the value is defined by the spec
+            pub fn is_defined(&self) -> bool {
+                match self {
+                    &$name::$unknown(..) => false,
+                    _ => true,
+                }
+            }
+        }
+
+        impl From<u8> for $name {
+            fn from(code: u8) -> $name {
+                $name::from_u8(code)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match self {
+                    $(
+                        &$name::$variant => write!(f, $desc),
+                    )+
+                    &$name::$unknown(code) => write!(f, "Unknown reason code (0x{:02x})", code),
+                }
+            }
+        }
+
+        impl<'a> Encodable<'a> for $name {
+            type Err = VariableHeaderError;
+
+            fn encode<W: Write>(&self, writer: &mut W) -> Result<(), VariableHeaderError> {
+                writer.write_u8(self.to_u8()).map_err(From::from)
+            }
+
+            fn encoded_length(&self) -> u32 {
+                1
+            }
+        }
+
+        impl<'a> Decodable<'a> for $name {
+            type Err = VariableHeaderError;
+            type Cond = ();
+
+            fn decode_with<R: Read>(reader: &mut R, _rest: Option<()>) -> Result<$name, VariableHeaderError> {
+                reader.read_u8().map($name::from_u8).map_err(From::from)
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::io::{Read, Write};
+use std::convert::From;
+use std::fmt;
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+
+use control::variable_header::VariableHeaderError;
+use {Encodable, Decodable, QualityOfService};
+
+impl_reason_code! {
+    /// Reason Code of each subscription in an MQTT 5 SUBACK, it replaces the Subscribe Return Code
+    pub enum SubackReasonCode {
+        GrantedQoS0                         = 0x00 => "Granted QoS 0",
+        GrantedQoS1                         = 0x01 => "Granted QoS 1",
+        GrantedQoS2                         = 0x02 => "Granted QoS 2",
+        UnspecifiedError                    = 0x80 => "Unspecified error",
+        ImplementationSpecificError         = 0x83 => "Implementation specific error",
+        NotAuthorized                       = 0x87 => "Not authorized",
+        TopicFilterInvalid                  = 0x8F => "Topic Filter invalid",
+        PacketIdentifierInUse               = 0x91 => "Packet Identifier in use",
+        QuotaExceeded                       = 0x97 => "Quota exceeded",
+        SharedSubscriptionsNotSupported     = 0x9E => "Shared Subscriptions not supported",
+        SubscriptionIdentifiersNotSupported = 0xA1 => "Subscription Identifiers not supported",
+        WildcardSubscriptionsNotSupported   = 0xA2 => "Wildcard Subscriptions not supported",
+    } Unspecified
+}
+
+impl SubackReasonCode {
+    /// Granted QoS, `None` if the subscription failed
+    pub fn granted_qos(&self) -> Option<QualityOfService> {
+        match self {
+            &SubackReasonCode::GrantedQoS0 => Some(QualityOfService::Level0),
+            &SubackReasonCode::GrantedQoS1 => Some(QualityOfService::Level1),
+            &SubackReasonCode::GrantedQoS2 => Some(QualityOfService::Level2),
+            _ => None,
+        }
+    }
+}
+
+impl From<QualityOfService> for SubackReasonCode {
+    fn from(qos: QualityOfService) -> SubackReasonCode {
+        match qos {
+            QualityOfService::Level0 => SubackReasonCode::GrantedQoS0,
+            QualityOfService::Level1 => SubackReasonCode::GrantedQoS1,
+            QualityOfService::Level2 => SubackReasonCode::GrantedQoS2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::prelude::v1::*;
+
+    #[test]
+    fn test_suback_reason_code() {
+        for code in 0..256 {
+            assert_eq!(code as u8, SubackReasonCode::from_u8(code as u8).to_u8());
+        }
+
+        assert!(SubackReasonCode::GrantedQoS2.is_success());
+        assert_eq!(Some(QualityOfService::Level2), SubackReasonCode::GrantedQoS2.granted_qos());
+        assert!(SubackReasonCode::QuotaExceeded.is_error());
+        assert_eq!(None, SubackReasonCode::QuotaExceeded.granted_qos());
+        assert_eq!(SubackReasonCode::Unspecified(0x03), SubackReasonCode::from(0x03));
+        assert_eq!(None, SubackReasonCode::from(0x03).granted_qos());
+        assert_eq!(SubackReasonCode::GrantedQoS1, SubackReasonCode::from(QualityOfService::Level1));
+        assert_eq!("Wildcard Subscriptions not supported",
+                   SubackReasonCode::WildcardSubscriptionsNotSupported.to_string());
+    }
+}
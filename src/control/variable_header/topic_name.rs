@@ -1,3 +1,4 @@
+use std::prelude::v1::*;
 use std::io::{Read, Write};
 use std::convert::From;
 
@@ -7,6 +8,27 @@ use {Encodable, Decodable};
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct TopicName(pub String);
 
+impl TopicName {
+    /// Topic names must not contain the `+` and `#` wildcards (MQTT-3.3.2-2), `None` if they do
+    pub fn new(topic_name: String) -> Option<TopicName> {
+        let topic_name = TopicName(topic_name);
+        if topic_name.contains_wildcard() {
+            None
+        } else {
+            Some(topic_name)
+        }
+    }
+
+    /// Topic `$SYS/{path}`, `None` if `path` contains wildcards
+    pub fn sys(path: &str) -> Option<TopicName> {
+        TopicName::new(format!("$SYS/{}", path))
+    }
+
+    pub fn contains_wildcard(&self) -> bool {
+        self.0.contains(|c| c == '+' || c == '#')
+    }
+}
+
 impl<'a> Encodable<'a> for TopicName {
     type Err = VariableHeaderError;
 
@@ -27,3 +49,22 @@ impl<'a> Decodable<'a> for TopicName {
         Ok(TopicName(try!(Decodable::decode(reader))))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_topic_name_wildcards() {
+        assert!(TopicName::new("a/b".to_owned()).is_some());
+        assert!(TopicName::new("$SYS/uptime".to_owned()).is_some());
+        assert!(TopicName::new("a/+/b".to_owned()).is_none());
+        assert!(TopicName::new("a/#".to_owned()).is_none());
+    }
+
+    #[test]
+    fn test_topic_name_sys() {
+        assert_eq!(Some(TopicName("$SYS/broker/uptime".to_owned())), TopicName::sys("broker/uptime"));
+        assert!(TopicName::sys("broker/+").is_none());
+    }
+}
@@ -0,0 +1,21 @@
+use std::io::{Read, Write};
+use std::convert::From;
+use std::fmt;
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+
+use control::variable_header::VariableHeaderError;
+use {Encodable, Decodable};
+
+impl_reason_code! {
+    /// Reason Code of each topic filter in an MQTT 5 UNSUBACK
+    pub enum UnsubackReasonCode {
+        Success                     = 0x00 => "Success",
+        NoSubscriptionExisted       = 0x11 => "No subscription existed",
+        UnspecifiedError            = 0x80 => "Unspecified error",
+        ImplementationSpecificError = 0x83 => "Implementation specific error",
+        NotAuthorized               = 0x87 => "Not authorized",
+        TopicFilterInvalid          = 0x8F => "Topic Filter invalid",
+        PacketIdentifierInUse       = 0x91 => "Packet Identifier in use",
+    } Unspecified
+}
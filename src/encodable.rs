@@ -1,4 +1,6 @@
+use std::prelude::v1::*;
 use std::io::{self, Read, Write};
+use std::cmp;
 use std::error::Error;
 use std::string::FromUtf8Error;
 use std::fmt;
@@ -7,6 +9,9 @@ use std::marker::Sized;
 
 use byteorder::{self, BigEndian, WriteBytesExt, ReadBytesExt};
 
+/// Upper bound of the buffer reserved before reading length-prefixed data
+const MAX_PREALLOCATED_LENGTH: u32 = 4096;
+
 pub trait Encodable<'a> {
     type Err: Error + 'a;
 
@@ -100,8 +105,14 @@ impl<'a> Decodable<'a> for Vec<u8> {
     fn decode_with<R: Read>(reader: &mut R, length: Option<u32>) -> Result<Vec<u8>, io::Error> {
         match length {
             Some(length) => {
-                let mut buf = Vec::with_capacity(length as usize);
+                // The length comes from the peer, do not trust it for the allocation
+                let mut buf = Vec::with_capacity(cmp::min(length, MAX_PREALLOCATED_LENGTH) as usize);
                 try!(reader.take(length as u64).read_to_end(&mut buf));
+
+                if buf.len() < length as usize {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated binary data"));
+                }
+
                 Ok(buf)
             },
             None => {
@@ -113,6 +124,204 @@ impl<'a> Decodable<'a> for Vec<u8> {
     }
 }
 
+/// Binary data prefixed by a two byte length, as used by the password field in CONNECT
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct VarBytes(pub Vec<u8>);
+
+impl<'a> Encodable<'a> for VarBytes {
+    type Err = io::Error;
+
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        if self.0.len() > u16::max_value() as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Binary data longer than 65535 bytes"));
+        }
+
+        try!(writer.write_u16::<BigEndian>(self.0.len() as u16));
+        writer.write_all(&self.0[..])
+    }
+
+    fn encoded_length(&self) -> u32 {
+        2 + self.0.len() as u32
+    }
+}
+
+impl<'a> Decodable<'a> for VarBytes {
+    type Err = io::Error;
+    type Cond = ();
+
+    fn decode_with<R: Read>(reader: &mut R, _rest: Option<()>) -> Result<VarBytes, io::Error> {
+        let len = try!(reader.read_u16::<BigEndian>()) as usize;
+        let mut buf = Vec::with_capacity(len);
+        try!(reader.take(len as u64).read_to_end(&mut buf));
+
+        if buf.len() < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Malformed binary data"));
+        }
+
+        Ok(VarBytes(buf))
+    }
+}
+
+/// Two byte integer in big-endian order
+impl<'a> Encodable<'a> for u16 {
+    type Err = io::Error;
+
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        writer.write_u16::<BigEndian>(*self).map_err(From::from)
+    }
+
+    fn encoded_length(&self) -> u32 {
+        2
+    }
+}
+
+impl<'a> Decodable<'a> for u16 {
+    type Err = io::Error;
+    type Cond = ();
+
+    fn decode_with<R: Read>(reader: &mut R, _rest: Option<()>) -> Result<u16, io::Error> {
+        reader.read_u16::<BigEndian>().map_err(From::from)
+    }
+}
+
+/// Four byte integer in big-endian order
+impl<'a> Encodable<'a> for u32 {
+    type Err = io::Error;
+
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        writer.write_u32::<BigEndian>(*self).map_err(From::from)
+    }
+
+    fn encoded_length(&self) -> u32 {
+        4
+    }
+}
+
+impl<'a> Decodable<'a> for u32 {
+    type Err = io::Error;
+    type Cond = ();
+
+    fn decode_with<R: Read>(reader: &mut R, _rest: Option<()>) -> Result<u32, io::Error> {
+        reader.read_u32::<BigEndian>().map_err(From::from)
+    }
+}
+
+/// Largest value of a Variable Byte Integer
+pub const VAR_INT_MAX: u32 = 268_435_455;
+
+/// Variable Byte Integer, the encoding of the Remaining Length and of MQTT 5 property lengths
+///
+/// Each byte carries 7 bits of the value, least significant first, and the high bit is set when
+/// another byte follows. At most 4 bytes are used.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct VarInt(pub u32);
+
+impl<'a> Encodable<'a> for VarInt {
+    type Err = io::Error;
+
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        assert!(self.0 <= VAR_INT_MAX);
+
+        let mut value = self.0;
+        loop {
+            let mut byte = (value & 0x7F) as u8;
+            value >>= 7;
+
+            if value > 0 {
+                byte |= 0x80;
+            }
+
+            try!(writer.write_u8(byte));
+
+            if value == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn encoded_length(&self) -> u32 {
+        if self.0 >= 2_097_152 {
+            4
+        } else if self.0 >= 16_384 {
+            3
+        } else if self.0 >= 128 {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+impl<'a> Decodable<'a> for VarInt {
+    type Err = VarIntError;
+    /// With `Some(true)`, a value encoded with more bytes than needed is rejected
+    type Cond = bool;
+
+    fn decode_with<R: Read>(reader: &mut R, require_minimal: Option<bool>) -> Result<VarInt, VarIntError> {
+        let mut value = 0u32;
+        for i in 0..4 {
+            let byte = try!(reader.read_u8());
+            value |= ((byte as u32) & 0x7F) << (7 * i);
+
+            if byte & 0x80 == 0 {
+                if require_minimal == Some(true) && i > 0 && byte == 0 {
+                    return Err(VarIntError::NonMinimal);
+                }
+
+                return Ok(VarInt(value));
+            }
+        }
+
+        Err(VarIntError::TooLong)
+    }
+}
+
+#[derive(Debug)]
+pub enum VarIntError {
+    IoError(io::Error),
+    TooLong,
+    NonMinimal,
+}
+
+impl fmt::Display for VarIntError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &VarIntError::IoError(ref err) => err.fmt(f),
+            &VarIntError::TooLong => write!(f, "Variable byte integer is longer than 4 bytes"),
+            &VarIntError::NonMinimal => write!(f, "Variable byte integer is not minimally encoded"),
+        }
+    }
+}
+
+impl Error for VarIntError {
+    fn description(&self) -> &str {
+        match self {
+            &VarIntError::IoError(ref err) => err.description(),
+            &VarIntError::TooLong => "Variable byte integer is longer than 4 bytes",
+            &VarIntError::NonMinimal => "Variable byte integer is not minimally encoded",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match self {
+            &VarIntError::IoError(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VarIntError {
+    fn from(err: io::Error) -> VarIntError {
+        VarIntError::IoError(err)
+    }
+}
+
+impl From<byteorder::Error> for VarIntError {
+    fn from(err: byteorder::Error) -> VarIntError {
+        VarIntError::IoError(From::from(err))
+    }
+}
+
 impl<'a> Encodable<'a> for () {
     type Err = NoError;
 
@@ -0,0 +1,469 @@
+//! C interface for decoding and encoding packets
+//!
+//! Build the C library with `cargo rustc --release --features ffi --crate-type cdylib` (or
+//! `staticlib`). The declarations are in `ffi/mqtt.h`, generated from this module with
+//! `cbindgen --config ffi/cbindgen.toml --output ffi/mqtt.h`.
+//!
+//! Packets are opaque `mqtt_packet_t` handles owned by the caller and released with
+//! `mqtt_packet_free`. Strings and byte arrays cross the boundary as a pointer and a length, they
+//! are not NUL-terminated. Every fallible function returns an `mqtt_status_t`, the message of the
+//! last error on the calling thread is available from `mqtt_last_error_message`.
+
+#![allow(non_camel_case_types)]
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::Cursor;
+use std::ptr;
+use std::slice;
+use std::str;
+
+use control::FixedHeader;
+use control::variable_header::PacketIdentifier;
+use packet::*;
+use {Encodable, Decodable};
+
+#[repr(C)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum mqtt_status_t {
+    MQTT_OK = 0,
+    /// The buffer ends before the end of the packet
+    MQTT_INCOMPLETE = 1,
+    MQTT_MALFORMED_PACKET = 2,
+    /// A null pointer or a value that does not make a valid packet
+    MQTT_INVALID_ARGUMENT = 3,
+    MQTT_BUFFER_TOO_SMALL = 4,
+    /// The packet does not have the requested field
+    MQTT_NO_SUCH_FIELD = 5,
+    MQTT_ENCODE_ERROR = 6,
+}
+
+/// Opaque packet handle
+pub struct mqtt_packet_t {
+    packet: VariablePacket,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<String> = RefCell::new(String::new());
+}
+
+fn fail<E: fmt::Display>(status: mqtt_status_t, err: E) -> mqtt_status_t {
+    LAST_ERROR.with(|last| *last.borrow_mut() = err.to_string());
+    status
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(data, len)
+    }
+}
+
+unsafe fn new_handle(packet: VariablePacket, out: *mut *mut mqtt_packet_t) -> mqtt_status_t {
+    *out = Box::into_raw(Box::new(mqtt_packet_t { packet: packet }));
+    mqtt_status_t::MQTT_OK
+}
+
+/// Decodes the packet at the start of `data` into a new handle stored in `out`
+///
+/// `out` is set to NULL on failure. Use `mqtt_packet_encoded_length` to find where the next
+/// packet starts.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes, or be NULL if `len` is 0. `out` must be NULL or
+/// point to writable memory for a handle.
+#[no_mangle]
+pub unsafe extern "C" fn mqtt_decode(data: *const u8, len: usize, out: *mut *mut mqtt_packet_t) -> mqtt_status_t {
+    if out.is_null() || (data.is_null() && len != 0) {
+        return fail(mqtt_status_t::MQTT_INVALID_ARGUMENT, "Null pointer");
+    }
+    *out = ptr::null_mut();
+
+    let buf = bytes(data, len);
+    match FixedHeader::packet_length(buf) {
+        Some(packet_len) if packet_len <= buf.len() => {},
+        _ => return fail(mqtt_status_t::MQTT_INCOMPLETE, "The buffer ends before the end of the packet"),
+    }
+
+    match VariablePacket::decode(&mut Cursor::new(buf)) {
+        Ok(packet) => new_handle(packet, out),
+        Err(err) => fail(mqtt_status_t::MQTT_MALFORMED_PACKET, err),
+    }
+}
+
+/// Control packet type of the fixed header, e.g. 3 for PUBLISH, 0 for a NULL handle
+///
+/// # Safety
+///
+/// `packet` must be NULL or a handle that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn mqtt_packet_type(packet: *const mqtt_packet_t) -> u8 {
+    match packet.as_ref() {
+        Some(packet) => packet.packet.control_type().to_u8(),
+        None => 0,
+    }
+}
+
+/// Topic name of a PUBLISH, valid until the packet is freed
+///
+/// # Safety
+///
+/// `packet` must be NULL or a handle that has not been freed. `topic` and `len` must be NULL or
+/// point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn mqtt_publish_topic(packet: *const mqtt_packet_t, topic: *mut *const u8, len: *mut usize)
+        -> mqtt_status_t {
+    if topic.is_null() || len.is_null() {
+        return fail(mqtt_status_t::MQTT_INVALID_ARGUMENT, "Null pointer");
+    }
+
+    match packet.as_ref().map(|packet| &packet.packet) {
+        Some(&VariablePacket::PublishPacket(ref publish)) => {
+            *topic = publish.topic_name().as_ptr();
+            *len = publish.topic_name().len();
+            mqtt_status_t::MQTT_OK
+        },
+        Some(..) => fail(mqtt_status_t::MQTT_NO_SUCH_FIELD, "Not a PUBLISH packet"),
+        None => fail(mqtt_status_t::MQTT_INVALID_ARGUMENT, "Null pointer"),
+    }
+}
+
+/// Application message of a PUBLISH, valid until the packet is freed
+///
+/// # Safety
+///
+/// `packet` must be NULL or a handle that has not been freed. `payload` and `len` must be NULL
+/// or point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn mqtt_publish_payload(packet: *const mqtt_packet_t, payload: *mut *const u8, len: *mut usize)
+        -> mqtt_status_t {
+    if payload.is_null() || len.is_null() {
+        return fail(mqtt_status_t::MQTT_INVALID_ARGUMENT, "Null pointer");
+    }
+
+    match packet.as_ref().map(|packet| &packet.packet) {
+        Some(&VariablePacket::PublishPacket(ref publish)) => {
+            *payload = publish.payload().as_ptr();
+            *len = publish.payload().len();
+            mqtt_status_t::MQTT_OK
+        },
+        Some(..) => fail(mqtt_status_t::MQTT_NO_SUCH_FIELD, "Not a PUBLISH packet"),
+        None => fail(mqtt_status_t::MQTT_INVALID_ARGUMENT, "Null pointer"),
+    }
+}
+
+/// Packet Identifier of a QoS 1 or 2 PUBLISH, an acknowledgement, SUBSCRIBE or UNSUBSCRIBE
+///
+/// # Safety
+///
+/// `packet` must be NULL or a handle that has not been freed. `packet_id` must be NULL or point
+/// to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn mqtt_packet_id(packet: *const mqtt_packet_t, packet_id: *mut u16) -> mqtt_status_t {
+    if packet_id.is_null() {
+        return fail(mqtt_status_t::MQTT_INVALID_ARGUMENT, "Null pointer");
+    }
+
+    let packet = match packet.as_ref() {
+        Some(packet) => &packet.packet,
+        None => return fail(mqtt_status_t::MQTT_INVALID_ARGUMENT, "Null pointer"),
+    };
+    let pkid = match packet {
+        &VariablePacket::PublishPacket(ref pk) => match pk.qos() {
+            QoSWithPacketIdentifier::Level0 => None,
+            QoSWithPacketIdentifier::Level1(pkid) | QoSWithPacketIdentifier::Level2(pkid) => Some(pkid.get()),
+        },
+        &VariablePacket::PubackPacket(ref pk) => Some(pk.packet_identifier()),
+        &VariablePacket::PubrecPacket(ref pk) => Some(pk.packet_identifier()),
+        &VariablePacket::PubrelPacket(ref pk) => Some(pk.packet_identifier()),
+        &VariablePacket::PubcompPacket(ref pk) => Some(pk.packet_identifier()),
+        &VariablePacket::SubscribePacket(ref pk) => Some(pk.packet_identifier().get()),
+        &VariablePacket::SubackPacket(ref pk) => Some(pk.packet_identifier()),
+        &VariablePacket::UnsubscribePacket(ref pk) => Some(pk.packet_identifier().get()),
+        &VariablePacket::UnsubackPacket(ref pk) => Some(pk.packet_identifier()),
+        _ => None,
+    };
+
+    match pkid {
+        Some(pkid) => {
+            *packet_id = pkid;
+            mqtt_status_t::MQTT_OK
+        },
+        None => fail(mqtt_status_t::MQTT_NO_SUCH_FIELD, "Packet without a Packet Identifier"),
+    }
+}
+
+/// Number of bytes written by `mqtt_encode`, 0 for a NULL handle
+///
+/// # Safety
+///
+/// `packet` must be NULL or a handle that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn mqtt_packet_encoded_length(packet: *const mqtt_packet_t) -> usize {
+    match packet.as_ref() {
+        Some(packet) => packet.packet.encoded_length() as usize,
+        None => 0,
+    }
+}
+
+/// Encodes the packet into `buf`
+///
+/// `written` receives the length of the packet, also when `MQTT_BUFFER_TOO_SMALL` is returned.
+///
+/// # Safety
+///
+/// `packet` must be NULL or a handle that has not been freed. `buf` must point to `cap` writable
+/// bytes, or be NULL if `cap` is 0. `written` must be NULL or point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn mqtt_encode(packet: *const mqtt_packet_t, buf: *mut u8, cap: usize, written: *mut usize)
+        -> mqtt_status_t {
+    let packet = match packet.as_ref() {
+        Some(packet) => &packet.packet,
+        None => return fail(mqtt_status_t::MQTT_INVALID_ARGUMENT, "Null pointer"),
+    };
+    if written.is_null() || (buf.is_null() && cap != 0) {
+        return fail(mqtt_status_t::MQTT_INVALID_ARGUMENT, "Null pointer");
+    }
+
+    let len = packet.encoded_length() as usize;
+    *written = len;
+    if len > cap {
+        return fail(mqtt_status_t::MQTT_BUFFER_TOO_SMALL,
+                    format!("The packet needs {} bytes, the buffer holds {}", len, cap));
+    }
+
+    let mut out = slice::from_raw_parts_mut(buf, cap);
+    match packet.encode(&mut out) {
+        Ok(()) => mqtt_status_t::MQTT_OK,
+        Err(err) => fail(mqtt_status_t::MQTT_ENCODE_ERROR, err),
+    }
+}
+
+/// Creates a PUBLISH, `packet_id` is ignored for QoS 0
+///
+/// # Safety
+///
+/// `topic` and `payload` must point to `topic_len` and `payload_len` readable bytes, or be NULL
+/// if their length is 0. `out` must be NULL or point to writable memory for a handle.
+#[no_mangle]
+pub unsafe extern "C" fn mqtt_publish_new(topic: *const u8,
+                                          topic_len: usize,
+                                          payload: *const u8,
+                                          payload_len: usize,
+                                          qos: u8,
+                                          packet_id: u16,
+                                          retain: bool,
+                                          out: *mut *mut mqtt_packet_t)
+                                          -> mqtt_status_t {
+    if out.is_null() || (topic.is_null() && topic_len != 0) || (payload.is_null() && payload_len != 0) {
+        return fail(mqtt_status_t::MQTT_INVALID_ARGUMENT, "Null pointer");
+    }
+    *out = ptr::null_mut();
+
+    let topic = match str::from_utf8(bytes(topic, topic_len)) {
+        Ok(topic) => topic,
+        Err(err) => return fail(mqtt_status_t::MQTT_INVALID_ARGUMENT, err),
+    };
+    let qos = match (qos, PacketIdentifier::new(packet_id)) {
+        (0, _) => QoSWithPacketIdentifier::Level0,
+        (1, Some(pkid)) => QoSWithPacketIdentifier::Level1(pkid),
+        (2, Some(pkid)) => QoSWithPacketIdentifier::Level2(pkid),
+        (1, None) | (2, None) => return fail(mqtt_status_t::MQTT_INVALID_ARGUMENT, "Packet Identifier is zero"),
+        (qos, _) => return fail(mqtt_status_t::MQTT_INVALID_ARGUMENT, format!("Invalid QoS ({})", qos)),
+    };
+
+    let mut publish = PublishPacket::new(topic.to_owned(), qos, bytes(payload, payload_len).to_vec());
+    publish.set_retain(retain);
+    new_handle(VariablePacket::new(publish), out)
+}
+
+/// Creates a PINGREQ
+///
+/// # Safety
+///
+/// `out` must be NULL or point to writable memory for a handle.
+#[no_mangle]
+pub unsafe extern "C" fn mqtt_pingreq_new(out: *mut *mut mqtt_packet_t) -> mqtt_status_t {
+    if out.is_null() {
+        return fail(mqtt_status_t::MQTT_INVALID_ARGUMENT, "Null pointer");
+    }
+    new_handle(VariablePacket::new(PingreqPacket::new()), out)
+}
+
+/// Creates a PUBACK
+///
+/// # Safety
+///
+/// `out` must be NULL or point to writable memory for a handle.
+#[no_mangle]
+pub unsafe extern "C" fn mqtt_puback_new(packet_id: u16, out: *mut *mut mqtt_packet_t) -> mqtt_status_t {
+    if out.is_null() {
+        return fail(mqtt_status_t::MQTT_INVALID_ARGUMENT, "Null pointer");
+    }
+    new_handle(VariablePacket::new(PubackPacket::new(packet_id)), out)
+}
+
+/// Releases a handle, NULL is ignored
+///
+/// # Safety
+///
+/// `packet` must be NULL or a handle that has not been freed yet, it must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn mqtt_packet_free(packet: *mut mqtt_packet_t) {
+    if !packet.is_null() {
+        drop(Box::from_raw(packet));
+    }
+}
+
+/// Message of the last error on the calling thread, valid until the next failing call on it
+///
+/// # Safety
+///
+/// `len` must be NULL or point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn mqtt_last_error_message(len: *mut usize) -> *const u8 {
+    LAST_ERROR.with(|last| {
+        let last = last.borrow();
+        if !len.is_null() {
+            *len = last.len();
+        }
+        last.as_ptr()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::path::Path;
+    use std::process::Command;
+
+    fn last_error() -> String {
+        unsafe {
+            let mut len = 0;
+            let msg = mqtt_last_error_message(&mut len);
+            String::from_utf8(bytes(msg, len).to_vec()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_ffi_decode_publish() {
+        let data = b"\x32\x0c\x00\x03a/b\x00\x07hello";
+        unsafe {
+            let mut packet = ptr::null_mut();
+            assert_eq!(mqtt_status_t::MQTT_OK, mqtt_decode(data.as_ptr(), data.len(), &mut packet));
+            assert_eq!(3, mqtt_packet_type(packet));
+
+            let (mut ptr, mut len) = (ptr::null(), 0);
+            assert_eq!(mqtt_status_t::MQTT_OK, mqtt_publish_topic(packet, &mut ptr, &mut len));
+            assert_eq!(b"a/b", bytes(ptr, len));
+            assert_eq!(mqtt_status_t::MQTT_OK, mqtt_publish_payload(packet, &mut ptr, &mut len));
+            assert_eq!(b"hello", bytes(ptr, len));
+            let mut pkid = 0;
+            assert_eq!(mqtt_status_t::MQTT_OK, mqtt_packet_id(packet, &mut pkid));
+            assert_eq!(7, pkid);
+
+            let mut buf = [0; 32];
+            let mut written = 0;
+            assert_eq!(mqtt_status_t::MQTT_OK, mqtt_encode(packet, buf.as_mut_ptr(), buf.len(), &mut written));
+            assert_eq!(&data[..], &buf[..written]);
+            assert_eq!(mqtt_status_t::MQTT_BUFFER_TOO_SMALL, mqtt_encode(packet, buf.as_mut_ptr(), 4, &mut written));
+            assert_eq!(data.len(), written);
+
+            mqtt_packet_free(packet);
+        }
+    }
+
+    #[test]
+    fn test_ffi_decode_errors() {
+        unsafe {
+            let mut packet = ptr::null_mut();
+            let data = b"\x32\x0c\x00\x03a/b";
+            assert_eq!(mqtt_status_t::MQTT_INCOMPLETE, mqtt_decode(data.as_ptr(), data.len(), &mut packet));
+            assert_eq!(mqtt_status_t::MQTT_INCOMPLETE, mqtt_decode(ptr::null(), 0, &mut packet));
+            assert!(packet.is_null());
+
+            let data = b"\x30\x02\x00\x05";
+            assert_eq!(mqtt_status_t::MQTT_MALFORMED_PACKET, mqtt_decode(data.as_ptr(), data.len(), &mut packet));
+            assert!(!last_error().is_empty());
+
+            let data = b"\xc0\x00";
+            assert_eq!(mqtt_status_t::MQTT_OK, mqtt_decode(data.as_ptr(), data.len(), &mut packet));
+            let mut pkid = 0;
+            assert_eq!(mqtt_status_t::MQTT_NO_SUCH_FIELD, mqtt_packet_id(packet, &mut pkid));
+            assert_eq!("Packet without a Packet Identifier", last_error());
+            mqtt_packet_free(packet);
+        }
+    }
+
+    #[test]
+    fn test_ffi_constructors() {
+        unsafe {
+            let mut packet = ptr::null_mut();
+            let (topic, payload) = (b"x/y", b"hi");
+            assert_eq!(mqtt_status_t::MQTT_OK,
+                       mqtt_publish_new(topic.as_ptr(), topic.len(), payload.as_ptr(), payload.len(), 0, 0, true, &mut packet));
+            let mut buf = [0; 16];
+            let mut written = 0;
+            assert_eq!(mqtt_status_t::MQTT_OK, mqtt_encode(packet, buf.as_mut_ptr(), buf.len(), &mut written));
+            assert_eq!(b"\x31\x07\x00\x03x/yhi", &buf[..written]);
+            mqtt_packet_free(packet);
+
+            assert_eq!(mqtt_status_t::MQTT_INVALID_ARGUMENT,
+                       mqtt_publish_new(topic.as_ptr(), topic.len(), payload.as_ptr(), payload.len(), 1, 0, false, &mut packet));
+            assert_eq!(mqtt_status_t::MQTT_INVALID_ARGUMENT,
+                       mqtt_publish_new(b"\xff".as_ptr(), 1, ptr::null(), 0, 0, 0, false, &mut packet));
+            assert!(packet.is_null());
+
+            assert_eq!(mqtt_status_t::MQTT_OK, mqtt_puback_new(9, &mut packet));
+            assert_eq!(mqtt_status_t::MQTT_OK, mqtt_encode(packet, buf.as_mut_ptr(), buf.len(), &mut written));
+            assert_eq!(b"\x40\x02\x00\x09", &buf[..written]);
+            mqtt_packet_free(packet);
+
+            assert_eq!(mqtt_status_t::MQTT_OK, mqtt_pingreq_new(&mut packet));
+            assert_eq!(2, mqtt_packet_encoded_length(packet));
+            mqtt_packet_free(packet);
+            mqtt_packet_free(ptr::null_mut());
+        }
+    }
+
+    /// Builds the crate as a static library and runs `ffi/test.c` against it and `ffi/mqtt.h`
+    ///
+    /// Needs a C compiler, `CC` or `cc`. Run it with `cargo test --features ffi -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_ffi_c_program() {
+        let cc = env::var("CC").unwrap_or("cc".to_owned());
+
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let ffi_dir = manifest_dir.join("ffi");
+        let target_dir = env::temp_dir().join("mqtt-ffi-test");
+        let cargo = env::var("CARGO").unwrap_or("cargo".to_owned());
+
+        let output = Command::new(cargo)
+                        .current_dir(manifest_dir)
+                        .args(&["rustc", "--lib", "--features", "ffi", "--crate-type", "staticlib", "--target-dir"])
+                        .arg(&target_dir)
+                        .output()
+                        .unwrap();
+        assert!(output.status.success(), "Failed to build the static library: {}", String::from_utf8_lossy(&output.stderr));
+
+        let program = target_dir.join("test_ffi");
+        let status = Command::new(&cc)
+                        .arg("-Wall").arg("-Werror")
+                        .arg("-I").arg(&ffi_dir)
+                        .arg(ffi_dir.join("test.c"))
+                        .arg(target_dir.join("debug").join("libmqtt.a"))
+                        .args(&["-lpthread", "-ldl", "-lm", "-o"])
+                        .arg(&program)
+                        .status()
+                        .unwrap();
+        assert!(status.success(), "Failed to compile ffi/test.c");
+
+        let status = Command::new(&program).status().unwrap();
+        assert!(status.success(), "ffi/test.c failed");
+    }
+}
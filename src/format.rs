@@ -0,0 +1,423 @@
+//! `defmt::Format` for the packet types, for logging on embedded targets
+//!
+//! Packets are rendered on one line with the payloads reduced to their length, e.g.
+//! `PUBLISH{topic=a/b, qos=1, pkid=7, len=64}`. Passwords and will messages are redacted like in
+//! the `Debug` output of `ConnectPacket`. Errors are rendered by their description.
+
+use std::error::Error;
+use std::fmt;
+
+use defmt::{self, Format, Formatter};
+
+use control::{ControlType, FixedHeader};
+use control::packet_type::PacketTypeError;
+use control::fixed_header::FixedHeaderError;
+use control::variable_header::{ConnectReturnCode, PacketIdentifier, VariableHeaderError};
+use encodable::StringEncodeError;
+use inspect::InspectError;
+use packet::*;
+use packet::connect::{ConnectBuildError, ConnectPropertyError, KeepAliveError, ClientIdError, ConnectPacketPayloadError};
+use packet::publish::PublishPropertyError;
+use packet::suback::{SubscribeReturnCode, SubackPacketPayloadError};
+use packet::subscribe::{SubscribePacketPayloadError, SubscribePropertyError};
+use packet::size_limit::PacketTooLarge;
+use packet::topic_alias::TopicAliasError;
+use packet::unsuback::UnsubackMismatchError;
+use packet::unsubscribe::UnsubscribePacketPayloadError;
+use qos::InvalidQoSError;
+use topic_filter::TopicFilterError;
+use QualityOfService;
+
+impl Format for QualityOfService {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "{=u8}", *self as u8)
+    }
+}
+
+impl Format for PacketIdentifier {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "{=u16}", self.get())
+    }
+}
+
+impl Format for ControlType {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "{=str}", self.name())
+    }
+}
+
+impl Format for FixedHeader {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "{}{{flags={=u8:#x}, remaining_length={=u32}}}",
+                      self.packet_type.control_type, self.packet_type.flags, self.remaining_length)
+    }
+}
+
+impl Format for ConnectReturnCode {
+    fn format(&self, f: Formatter) {
+        match self {
+            &ConnectReturnCode::ConnectionAccepted => defmt::write!(f, "ConnectionAccepted"),
+            &ConnectReturnCode::UnacceptableProtocolVersion => defmt::write!(f, "UnacceptableProtocolVersion"),
+            &ConnectReturnCode::IdentifierRejected => defmt::write!(f, "IdentifierRejected"),
+            &ConnectReturnCode::ServiceUnavailable => defmt::write!(f, "ServiceUnavailable"),
+            &ConnectReturnCode::BadUserNameOrPassword => defmt::write!(f, "BadUserNameOrPassword"),
+            &ConnectReturnCode::NotAuthorized => defmt::write!(f, "NotAuthorized"),
+            &ConnectReturnCode::Reserved(code) => defmt::write!(f, "Reserved({=u8})", code),
+        }
+    }
+}
+
+impl Format for SubscribeReturnCode {
+    fn format(&self, f: Formatter) {
+        match self {
+            &SubscribeReturnCode::MaximumQoSLevel0 => defmt::write!(f, "MaximumQoSLevel0"),
+            &SubscribeReturnCode::MaximumQoSLevel1 => defmt::write!(f, "MaximumQoSLevel1"),
+            &SubscribeReturnCode::MaximumQoSLevel2 => defmt::write!(f, "MaximumQoSLevel2"),
+            &SubscribeReturnCode::Failure => defmt::write!(f, "Failure"),
+        }
+    }
+}
+
+impl Format for ConnectPacket {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "CONNECT{{client_id={=str}, keep_alive={=u16}, clean_session={=bool}",
+                      self.client_identifier(), self.keep_alive(), self.clean_session());
+
+        if let Some(user_name) = self.user_name() {
+            defmt::write!(f, ", user_name={=str}", user_name);
+        }
+
+        if let Some(password) = self.password() {
+            defmt::write!(f, ", password=<redacted, {=usize} bytes>", password.len());
+        }
+
+        if let Some(will) = self.will() {
+            defmt::write!(f, ", will={{topic={=str}, qos={}, retain={=bool}, len={=usize}}}",
+                          &will.topic.0[..], will.qos, will.retain, will.message.len());
+        }
+
+        defmt::write!(f, "}}")
+    }
+}
+
+impl Format for ConnackPacket {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "CONNACK{{session_present={=bool}, code={}}}",
+                      self.session_present(), self.connect_return_code())
+    }
+}
+
+impl Format for AuthPacket {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "AUTH{{reason={=u8}}}", self.reason_code().to_u8())
+    }
+}
+
+impl Format for PublishPacket {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "PUBLISH{{topic={=str}", self.topic_name());
+
+        match self.qos() {
+            QoSWithPacketIdentifier::Level0 => defmt::write!(f, ", qos=0"),
+            QoSWithPacketIdentifier::Level1(pkid) => defmt::write!(f, ", qos=1, pkid={}", pkid),
+            QoSWithPacketIdentifier::Level2(pkid) => defmt::write!(f, ", qos=2, pkid={}", pkid),
+        }
+
+        if self.retain() {
+            defmt::write!(f, ", retain");
+        }
+
+        if self.dup() {
+            defmt::write!(f, ", dup");
+        }
+
+        defmt::write!(f, ", len={=usize}}}", self.payload().len())
+    }
+}
+
+macro_rules! impl_format_for_ack {
+    ($($name:ident => $tag:expr,)+) => {
+        $(
+            impl Format for $name {
+                fn format(&self, f: Formatter) {
+                    defmt::write!(f, "{=str}{{pkid={=u16}}}", $tag, self.packet_identifier())
+                }
+            }
+        )+
+    }
+}
+
+impl_format_for_ack! {
+    PubackPacket => "PUBACK",
+    PubrecPacket => "PUBREC",
+    PubrelPacket => "PUBREL",
+    PubcompPacket => "PUBCOMP",
+    UnsubackPacket => "UNSUBACK",
+}
+
+macro_rules! impl_format_for_empty {
+    ($($name:ident => $tag:expr,)+) => {
+        $(
+            impl Format for $name {
+                fn format(&self, f: Formatter) {
+                    defmt::write!(f, "{=str}", $tag)
+                }
+            }
+        )+
+    }
+}
+
+impl_format_for_empty! {
+    PingreqPacket => "PINGREQ",
+    PingrespPacket => "PINGRESP",
+    DisconnectPacket => "DISCONNECT",
+}
+
+impl Format for SubscribePacket {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "SUBSCRIBE{{pkid={}", self.packet_identifier());
+
+        for (filter, qos) in self.subscriptions() {
+            defmt::write!(f, ", {=str}@{}", filter.as_str(), qos);
+        }
+
+        defmt::write!(f, "}}")
+    }
+}
+
+impl Format for SubackPacket {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "SUBACK{{pkid={=u16}, codes={}}}", self.packet_identifier(), self.return_codes())
+    }
+}
+
+impl Format for UnsubscribePacket {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "UNSUBSCRIBE{{pkid={}", self.packet_identifier());
+
+        for filter in self.topic_filters() {
+            defmt::write!(f, ", {=str}", filter.as_str());
+        }
+
+        defmt::write!(f, "}}")
+    }
+}
+
+impl Format for VariablePacket {
+    fn format(&self, f: Formatter) {
+        match self {
+            &VariablePacket::ConnectPacket(ref pk) => pk.format(f),
+            &VariablePacket::ConnackPacket(ref pk) => pk.format(f),
+            &VariablePacket::PublishPacket(ref pk) => pk.format(f),
+            &VariablePacket::PubackPacket(ref pk) => pk.format(f),
+            &VariablePacket::PubrecPacket(ref pk) => pk.format(f),
+            &VariablePacket::PubrelPacket(ref pk) => pk.format(f),
+            &VariablePacket::PubcompPacket(ref pk) => pk.format(f),
+            &VariablePacket::PingreqPacket(ref pk) => pk.format(f),
+            &VariablePacket::PingrespPacket(ref pk) => pk.format(f),
+            &VariablePacket::SubscribePacket(ref pk) => pk.format(f),
+            &VariablePacket::SubackPacket(ref pk) => pk.format(f),
+            &VariablePacket::UnsubscribePacket(ref pk) => pk.format(f),
+            &VariablePacket::UnsubackPacket(ref pk) => pk.format(f),
+            &VariablePacket::DisconnectPacket(ref pk) => pk.format(f),
+            &VariablePacket::AuthPacket(ref pk) => pk.format(f),
+        }
+    }
+}
+
+impl<'a> Format for VariablePacketError<'a> {
+    fn format(&self, f: Formatter) {
+        match self {
+            &VariablePacketError::UnrecognizedFixedHeader(ref header) =>
+                defmt::write!(f, "Unrecognized fixed header {}", header),
+            &VariablePacketError::PacketTooLarge(size) => defmt::write!(f, "Packet too large ({=u32} bytes)", size),
+            err => defmt::write!(f, "{=str}", err.description()),
+        }
+    }
+}
+
+impl<'a, T: Packet<'a> + fmt::Debug> Format for PacketError<'a, T> {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "{=str}", self.description())
+    }
+}
+
+macro_rules! impl_format_by_description {
+    ($($name:ty,)+) => {
+        $(
+            impl Format for $name {
+                fn format(&self, f: Formatter) {
+                    defmt::write!(f, "{=str}", self.description())
+                }
+            }
+        )+
+    }
+}
+
+impl_format_by_description! {
+    FixedHeaderError,
+    PacketTypeError,
+    VariableHeaderError,
+    StringEncodeError,
+    TopicFilterError,
+    InvalidQoSError,
+    InspectError,
+    ConnectBuildError,
+    ConnectPropertyError,
+    KeepAliveError,
+    ClientIdError,
+    ConnectPacketPayloadError,
+    PublishPropertyError,
+    TopicAliasError,
+    PacketTooLarge,
+    SubackPacketPayloadError,
+    SubscribePacketPayloadError,
+    SubscribePropertyError,
+    UnsubscribePacketPayloadError,
+    UnsubackMismatchError,
+}
+
+#[cfg(feature = "heapless")]
+mod bounded {
+    use defmt::{self, Format, Formatter};
+
+    use packet::QoSWithPacketIdentifier;
+    use packet::bounded::*;
+
+    impl<const N: usize> Format for BoundedConnectPacket<N> {
+        fn format(&self, f: Formatter) {
+            defmt::write!(f, "CONNECT{{client_id={=str}, keep_alive={=u16}, clean_session={=bool}",
+                          self.client_identifier(), self.keep_alive(), self.clean_session());
+
+            if let Some(user_name) = self.user_name() {
+                defmt::write!(f, ", user_name={=str}", user_name);
+            }
+
+            if let Some(password) = self.password() {
+                defmt::write!(f, ", password=<redacted, {=usize} bytes>", password.len());
+            }
+
+            if let Some(will) = self.will() {
+                defmt::write!(f, ", will={{topic={=str}, qos={}, retain={=bool}, len={=usize}}}",
+                              will.topic.as_str(), will.qos, will.retain, will.message.len());
+            }
+
+            defmt::write!(f, "}}")
+        }
+    }
+
+    impl<const T: usize, const P: usize> Format for BoundedPublishPacket<T, P> {
+        fn format(&self, f: Formatter) {
+            defmt::write!(f, "PUBLISH{{topic={=str}", self.topic_name());
+
+            match self.qos() {
+                QoSWithPacketIdentifier::Level0 => defmt::write!(f, ", qos=0"),
+                QoSWithPacketIdentifier::Level1(pkid) => defmt::write!(f, ", qos=1, pkid={}", pkid),
+                QoSWithPacketIdentifier::Level2(pkid) => defmt::write!(f, ", qos=2, pkid={}", pkid),
+            }
+
+            if self.retain() {
+                defmt::write!(f, ", retain");
+            }
+
+            if self.dup() {
+                defmt::write!(f, ", dup");
+            }
+
+            defmt::write!(f, ", len={=usize}}}", self.payload_ref().len())
+        }
+    }
+
+    impl<const F: usize, const N: usize> Format for BoundedSubscribePacket<F, N> {
+        fn format(&self, f: Formatter) {
+            defmt::write!(f, "SUBSCRIBE{{pkid={}", self.packet_identifier());
+
+            for (filter, qos) in self.subscriptions() {
+                defmt::write!(f, ", {=str}@{}", filter, qos);
+            }
+
+            defmt::write!(f, "}}")
+        }
+    }
+
+    impl Format for CapacityError {
+        fn format(&self, f: Formatter) {
+            defmt::write!(f, "Capacity of the bounded packet exceeded")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::prelude::v1::*;
+
+    use packet::connect::LastWill;
+    use {Decodable, TopicFilter};
+
+    fn assert_format<T: Format + ?Sized>(_: &T) {}
+
+    // Raw frame of `value` as logged through defmt's test harness, interned strings are indices
+    // but `{=str}` arguments are copied verbatim
+    fn emitted<T: Format>(value: &T) -> Vec<u8> {
+        defmt::export::fetch_bytes();
+        defmt::println!("{}", value);
+        defmt::export::fetch_bytes()
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn test_defmt_format_output() {
+        let mut connect = ConnectPacket::new("client-1".to_owned());
+        connect.set_user_name(Some("user".to_owned()));
+        connect.set_password(Some(b"secret".to_vec()));
+        connect.set_will(Some(LastWill::new("status".to_owned(), b"offline".to_vec(), QualityOfService::Level1, true))).unwrap();
+        let frame = emitted(&connect);
+        assert!(contains(&frame, b"client-1"), "{:?}", frame);
+        assert!(contains(&frame, b"user"), "{:?}", frame);
+        assert!(contains(&frame, b"status"), "{:?}", frame);
+        assert!(!contains(&frame, b"secret"), "{:?}", frame);
+        assert!(!contains(&frame, b"offline"), "{:?}", frame);
+
+        let pkid = PacketIdentifier::new(7).unwrap();
+        let publish = PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level1(pkid), b"payload".to_vec());
+        let frame = emitted(&VariablePacket::new(publish));
+        assert!(contains(&frame, b"a/b"), "{:?}", frame);
+        assert!(!contains(&frame, b"payload"), "{:?}", frame);
+
+        let frame = emitted(&PubackPacket::new(0x1234));
+        assert!(contains(&frame, b"PUBACK"), "{:?}", frame);
+        assert!(contains(&frame, &[0x34, 0x12]), "{:?}", frame);
+    }
+
+    #[test]
+    fn test_defmt_format_packets() {
+        let mut connect = ConnectPacket::new("client-1".to_owned());
+        connect.set_user_name(Some("user".to_owned()));
+        connect.set_password(Some(b"secret".to_vec()));
+        connect.set_will(Some(LastWill::new("status".to_owned(), b"offline".to_vec(), QualityOfService::Level1, true))).unwrap();
+        assert_format(&connect);
+        assert_format(&ConnackPacket::rejected(ConnectReturnCode::NotAuthorized));
+        assert_format(&SubackPacket::new(1, vec![SubscribeReturnCode::MaximumQoSLevel1, SubscribeReturnCode::Failure]));
+        assert_format(&connect.fixed_header().clone());
+        assert_format(&ControlType::Publish);
+
+        let pkid = PacketIdentifier::new(7).unwrap();
+        assert_format(&VariablePacket::new(PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level1(pkid), vec![0; 64])));
+        assert_format(&VariablePacket::new(UnsubscribePacket::new(pkid, vec![TopicFilter::new("a/#")]).unwrap()));
+        assert_format(&VariablePacket::new(PingreqPacket::new()));
+    }
+
+    #[test]
+    fn test_defmt_format_errors() {
+        let err = VariablePacket::decode(&mut &b"\x30\x02\x00\x03"[..]).unwrap_err();
+        assert_format(&err);
+        assert_format(&TopicFilterError::Empty);
+        assert_format(&VariableHeaderError::PasswordWithoutUserName);
+        assert_format(&PacketError::<PublishPacket>::InvalidDupFlag);
+    }
+}
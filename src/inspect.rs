@@ -0,0 +1,411 @@
+//! Annotated hex dumps of encoded packets
+
+use std::prelude::v1::*;
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::str;
+
+use control::ControlType;
+use control::variable_header::ConnectReturnCode;
+use packet::VariablePacket;
+use Encodable;
+
+const BYTES_PER_LINE: usize = 16;
+
+/// Payload bytes shown before the rest is summarized
+const PAYLOAD_LIMIT: usize = 32;
+
+/// Renders the packets in `buf` as a hex dump, one field per line
+///
+/// ```text
+/// 0000: 30 0c | PUBLISH, dup=0, qos=0, retain=0, remaining=12
+/// 0002: 00 05 61 2f 62 2f 63 | topic "a/b/c"
+/// 0009: 68 65 6c 6c 6f | payload "hello"
+/// ```
+///
+/// Malformed input is annotated up to the field that could not be read, the error holds that
+/// partial dump.
+pub fn annotate(buf: &[u8]) -> Result<String, InspectError> {
+    let mut annotator = Annotator {
+        buf: buf,
+        pos: 0,
+        out: String::new(),
+    };
+
+    while annotator.pos < buf.len() {
+        if let Err(message) = annotator.packet() {
+            let offset = annotator.pos;
+            let rest = buf.len() - offset;
+            annotator.line(offset, rest, &format!("!! {}", message));
+            return Err(InspectError {
+                offset: offset,
+                message: message,
+                dump: annotator.out,
+            });
+        }
+    }
+    Ok(annotator.out)
+}
+
+/// Annotated hex dump of the encoded packet
+pub fn annotate_packet(packet: &VariablePacket) -> String {
+    let mut buf = Vec::with_capacity(packet.encoded_length() as usize);
+    match packet.encode(&mut buf) {
+        Ok(..) => annotate(&buf).unwrap_or_else(|err| err.dump),
+        Err(err) => format!("!! {}", err),
+    }
+}
+
+struct Annotator<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    out: String,
+}
+
+impl<'a> Annotator<'a> {
+    fn line(&mut self, offset: usize, len: usize, annotation: &str) {
+        let bytes = &self.buf[offset..offset + len];
+        if bytes.is_empty() {
+            let _ = writeln!(self.out, "{:04x}: | {}", offset, annotation);
+            return;
+        }
+
+        for (i, chunk) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+            let _ = write!(self.out, "{:04x}:", offset + i * BYTES_PER_LINE);
+            for byte in chunk {
+                let _ = write!(self.out, " {:02x}", byte);
+            }
+            if i == 0 {
+                let _ = write!(self.out, " | {}", annotation);
+            }
+            self.out.push('\n');
+        }
+    }
+
+    /// Consumes `len` bytes, or fails without consuming anything
+    fn take(&mut self, len: usize, end: usize, what: &str) -> Result<&'a [u8], String> {
+        if self.pos + len > end {
+            return Err(format!("{} needs {} bytes, {} left", what, len, end - self.pos));
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        Ok(bytes)
+    }
+
+    fn field(&mut self, len: usize, end: usize, what: &str, annotation: &str) -> Result<&'a [u8], String> {
+        let bytes = try!(self.take(len, end, what));
+        let offset = self.pos;
+        self.line(offset, len, annotation);
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8_field<F>(&mut self, end: usize, what: &str, annotate: F) -> Result<u8, String>
+        where F: FnOnce(u8) -> String
+    {
+        let byte = try!(self.take(1, end, what))[0];
+        try!(self.field(1, end, what, &annotate(byte)));
+        Ok(byte)
+    }
+
+    fn u16_field(&mut self, end: usize, what: &str) -> Result<u16, String> {
+        let bytes = try!(self.take(2, end, what));
+        let value = (bytes[0] as u16) << 8 | bytes[1] as u16;
+        try!(self.field(2, end, what, &format!("{} {}", what, value)));
+        Ok(value)
+    }
+
+    /// Length prefixed field, shown as a string if it is valid UTF-8
+    fn bytes_field(&mut self, end: usize, what: &str) -> Result<&'a [u8], String> {
+        let length = {
+            let prefix = try!(self.take(2, end, what));
+            (prefix[0] as usize) << 8 | prefix[1] as usize
+        };
+        let bytes = try!(self.take(2 + length, end, what));
+        let annotation = match str::from_utf8(&bytes[2..]) {
+            Ok(s) => format!("{} {:?}", what, s),
+            Err(..) => format!("{} ({} bytes)", what, length),
+        };
+        try!(self.field(2 + length, end, what, &annotation));
+        Ok(&bytes[2..])
+    }
+
+    fn packet(&mut self) -> Result<(), String> {
+        let start = self.pos;
+        let first = self.buf[start];
+
+        // Remaining Length, up to 4 bytes with the continuation bit
+        let mut remaining = 0usize;
+        let mut header_len = 1;
+        loop {
+            if header_len > 4 {
+                return Err("remaining length is longer than 4 bytes".to_owned());
+            }
+            let byte = match self.buf.get(start + header_len) {
+                Some(&byte) => byte,
+                None => return Err("truncated fixed header".to_owned()),
+            };
+            remaining |= ((byte & 0x7f) as usize) << (7 * (header_len - 1));
+            header_len += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let control_type = match ControlType::from_u8(first >> 4) {
+            Ok(control_type) => control_type,
+            Err(..) => return Err(format!("reserved packet type {}", first >> 4)),
+        };
+        let flags = first & 0x0f;
+        let annotation = match control_type {
+            ControlType::Publish => format!("PUBLISH, dup={}, qos={}, retain={}, remaining={}",
+                                            flags >> 3, (flags >> 1) & 0x03, flags & 0x01, remaining),
+            ControlType::PublishRelease | ControlType::Subscribe | ControlType::Unsubscribe if flags == 0x02 =>
+                format!("{}, remaining={}", control_type, remaining),
+            _ if flags == 0 => format!("{}, remaining={}", control_type, remaining),
+            _ => format!("{}, flags=0x{:x}, remaining={}", control_type, flags, remaining),
+        };
+        let end = self.buf.len();
+        try!(self.field(header_len, end, "fixed header", &annotation));
+
+        let end = self.pos + remaining;
+        if end > self.buf.len() {
+            return Err(format!("truncated packet, {} of {} bytes", self.buf.len() - self.pos, remaining));
+        }
+
+        try!(self.body(control_type, flags, end));
+        if self.pos < end {
+            return Err(format!("{} unexpected bytes", end - self.pos));
+        }
+        Ok(())
+    }
+
+    fn body(&mut self, control_type: ControlType, flags: u8, end: usize) -> Result<(), String> {
+        match control_type {
+            ControlType::Connect => self.connect(end),
+            ControlType::ConnectAcknowledgement => {
+                try!(self.u8_field(end, "acknowledge flags", |b| format!("session present={}", b & 0x01)));
+                try!(self.u8_field(end, "return code", |b| {
+                    format!("return code {} ({:?})", b, ConnectReturnCode::from_u8(b))
+                }));
+                Ok(())
+            },
+            ControlType::Publish => {
+                try!(self.bytes_field(end, "topic"));
+                if (flags >> 1) & 0x03 != 0 {
+                    try!(self.u16_field(end, "packet identifier"));
+                }
+                self.payload(end);
+                Ok(())
+            },
+            ControlType::PublishAcknowledgement | ControlType::PublishReceived |
+            ControlType::PublishRelease | ControlType::PublishComplete |
+            ControlType::UnsubscribeAcknowledgement => {
+                try!(self.u16_field(end, "packet identifier"));
+                Ok(())
+            },
+            ControlType::Subscribe => {
+                try!(self.u16_field(end, "packet identifier"));
+                while self.pos < end {
+                    try!(self.bytes_field(end, "topic filter"));
+                    try!(self.u8_field(end, "requested QoS", |b| format!("requested qos={}", b)));
+                }
+                Ok(())
+            },
+            ControlType::SubscribeAcknowledgement => {
+                try!(self.u16_field(end, "packet identifier"));
+                while self.pos < end {
+                    try!(self.u8_field(end, "return code", |b| match b {
+                        0x80 => "return code 0x80 (failure)".to_owned(),
+                        b => format!("return code {} (qos={})", b, b),
+                    }));
+                }
+                Ok(())
+            },
+            ControlType::Unsubscribe => {
+                try!(self.u16_field(end, "packet identifier"));
+                while self.pos < end {
+                    try!(self.bytes_field(end, "topic filter"));
+                }
+                Ok(())
+            },
+            ControlType::PingRequest | ControlType::PingResponse | ControlType::Disconnect => Ok(()),
+            ControlType::Auth => Err("AUTH is only defined in MQTT 5".to_owned()),
+        }
+    }
+
+    fn connect(&mut self, end: usize) -> Result<(), String> {
+        try!(self.bytes_field(end, "protocol name"));
+        try!(self.u8_field(end, "protocol level", |b| format!("protocol level {}", b)));
+        let flags = try!(self.u8_field(end, "connect flags", |b| {
+            let mut names = Vec::new();
+            for &(mask, name) in [(0x80, "user_name"), (0x40, "password"), (0x20, "will_retain"),
+                                  (0x04, "will"), (0x02, "clean_session"), (0x01, "reserved")].iter() {
+                if b & mask != 0 {
+                    names.push(name.to_owned());
+                }
+            }
+            if b & 0x18 != 0 {
+                names.push(format!("will_qos={}", (b >> 3) & 0x03));
+            }
+            format!("connect flags [{}]", names.join(", "))
+        }));
+        try!(self.u16_field(end, "keep alive"));
+
+        try!(self.bytes_field(end, "client identifier"));
+        if flags & 0x04 != 0 {
+            try!(self.bytes_field(end, "will topic"));
+            try!(self.bytes_field(end, "will message"));
+        }
+        if flags & 0x80 != 0 {
+            try!(self.bytes_field(end, "user name"));
+        }
+        if flags & 0x40 != 0 {
+            let length = {
+                let prefix = try!(self.take(2, end, "password"));
+                (prefix[0] as usize) << 8 | prefix[1] as usize
+            };
+            try!(self.field(2 + length, end, "password", &format!("password ({} bytes)", length)));
+        }
+        Ok(())
+    }
+
+    fn payload(&mut self, end: usize) {
+        let len = end - self.pos;
+        let payload = &self.buf[self.pos..end];
+        let annotation = match str::from_utf8(payload) {
+            Ok(s) if len <= PAYLOAD_LIMIT => format!("payload {:?}", s),
+            _ => format!("payload ({} bytes)", len),
+        };
+
+        let shown = if len > PAYLOAD_LIMIT { PAYLOAD_LIMIT } else { len };
+        let offset = self.pos;
+        self.line(offset, shown, &annotation);
+        if shown < len {
+            let _ = writeln!(self.out, "{:04x}: ... | {} more bytes", offset + shown, len - shown);
+        }
+        self.pos = end;
+    }
+}
+
+/// Malformed input given to `annotate`
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct InspectError {
+    /// Offset of the bytes that could not be annotated
+    pub offset: usize,
+    pub message: String,
+    /// Dump up to and including the offending bytes
+    pub dump: String,
+}
+
+impl fmt::Display for InspectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Malformed packet at offset {}: {}", self.offset, self.message)
+    }
+}
+
+impl Error for InspectError {
+    fn description(&self) -> &str {
+        "Malformed packet"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use packet::*;
+    use packet::suback::SubscribeReturnCode;
+    use control::variable_header::PacketIdentifier;
+    use {QualityOfService, TopicFilter};
+
+    #[test]
+    fn test_annotate_publish() {
+        let packet = PublishPacket::new("a/b/c".to_owned(), QoSWithPacketIdentifier::Level0, b"hello".to_vec());
+        assert_eq!("0000: 30 0c | PUBLISH, dup=0, qos=0, retain=0, remaining=12\n\
+                    0002: 00 05 61 2f 62 2f 63 | topic \"a/b/c\"\n\
+                    0009: 68 65 6c 6c 6f | payload \"hello\"\n",
+                   annotate_packet(&VariablePacket::new(packet)));
+
+        let pkid = PacketIdentifier::new(10).unwrap();
+        let mut packet = PublishPacket::new("t".to_owned(), QoSWithPacketIdentifier::Level1(pkid), vec![0xff; 40]);
+        packet.set_retain(true);
+        assert_eq!("0000: 33 2d | PUBLISH, dup=0, qos=1, retain=1, remaining=45\n\
+                    0002: 00 01 74 | topic \"t\"\n\
+                    0005: 00 0a | packet identifier 10\n\
+                    0007: ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff | payload (40 bytes)\n\
+                    0017: ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff\n\
+                    0027: ... | 8 more bytes\n",
+                   annotate_packet(&VariablePacket::new(packet)));
+    }
+
+    #[test]
+    fn test_annotate_connect() {
+        let packet = ConnectPacket::builder("id")
+            .keep_alive(60)
+            .will("w", b"bye", QualityOfService::Level1, false)
+            .user_name("u")
+            .password(b"pw")
+            .build()
+            .unwrap();
+        assert_eq!("0000: 10 1d | CONNECT, remaining=29\n\
+                    0002: 00 04 4d 51 54 54 | protocol name \"MQTT\"\n\
+                    0008: 04 | protocol level 4\n\
+                    0009: cc | connect flags [user_name, password, will, will_qos=1]\n\
+                    000a: 00 3c | keep alive 60\n\
+                    000c: 00 02 69 64 | client identifier \"id\"\n\
+                    0010: 00 01 77 | will topic \"w\"\n\
+                    0013: 00 03 62 79 65 | will message \"bye\"\n\
+                    0018: 00 01 75 | user name \"u\"\n\
+                    001b: 00 02 70 77 | password (2 bytes)\n",
+                   annotate_packet(&VariablePacket::new(packet)));
+    }
+
+    #[test]
+    fn test_annotate_multiple_packets() {
+        let pkid = PacketIdentifier::new(1).unwrap();
+        let mut buf = Vec::new();
+        VariablePacket::new(SubscribePacket::new(pkid, vec![(TopicFilter::new("a/#"), QualityOfService::Level2)]))
+            .encode(&mut buf).unwrap();
+        VariablePacket::new(SubackPacket::new(pkid.get(), vec![SubscribeReturnCode::MaximumQoSLevel2, SubscribeReturnCode::Failure]))
+            .encode(&mut buf).unwrap();
+        VariablePacket::new(PingreqPacket::new()).encode(&mut buf).unwrap();
+
+        assert_eq!("0000: 82 08 | SUBSCRIBE, remaining=8\n\
+                    0002: 00 01 | packet identifier 1\n\
+                    0004: 00 03 61 2f 23 | topic filter \"a/#\"\n\
+                    0009: 02 | requested qos=2\n\
+                    000a: 90 04 | SUBACK, remaining=4\n\
+                    000c: 00 01 | packet identifier 1\n\
+                    000e: 02 | return code 2 (qos=2)\n\
+                    000f: 80 | return code 0x80 (failure)\n\
+                    0010: c0 00 | PINGREQ, remaining=0\n",
+                   annotate(&buf).unwrap());
+    }
+
+    #[test]
+    fn test_annotate_malformed() {
+        // Topic length runs past the end of the packet
+        let err = annotate(b"\x30\x05\x00\x07a/b").unwrap_err();
+        assert_eq!(2, err.offset);
+        assert_eq!("0000: 30 05 | PUBLISH, dup=0, qos=0, retain=0, remaining=5\n\
+                    0002: 00 07 61 2f 62 | !! topic needs 9 bytes, 5 left\n",
+                   err.dump);
+
+        let err = annotate(b"\x40\x02\x00").unwrap_err();
+        assert_eq!("0000: 40 02 | PUBACK, remaining=2\n\
+                    0002: 00 | !! truncated packet, 1 of 2 bytes\n",
+                   err.dump);
+
+        let err = annotate(b"\xc0\x00\xf0\x00").unwrap_err();
+        assert_eq!("0000: c0 00 | PINGREQ, remaining=0\n\
+                    0002: f0 00 | !! reserved packet type 15\n",
+                   err.dump);
+
+        let err = annotate(b"\x62\x03\x00\x01\xff").unwrap_err();
+        assert_eq!(4, err.offset);
+        assert_eq!("0000: 62 03 | PUBREL, remaining=3\n\
+                    0002: 00 01 | packet identifier 1\n\
+                    0004: ff | !! 1 unexpected bytes\n",
+                   err.dump);
+    }
+}
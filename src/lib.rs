@@ -1,12 +1,63 @@
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "std")]
 extern crate byteorder;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "heapless")]
+extern crate heapless;
+#[cfg(feature = "defmt")]
+extern crate defmt;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(all(test, feature = "serde"))]
+extern crate bincode;
 
 pub use self::encodable::{Encodable, Decodable};
 pub use self::qos::QualityOfService;
+pub use self::topic_filter::TopicFilter;
+
+#[cfg(not(feature = "std"))]
+use nostd::{byteorder, std};
+/// Readers and writers accepted by `Encodable` and `Decodable` without `std`
+#[cfg(not(feature = "std"))]
+pub use nostd::io;
 
+#[cfg(feature = "std")]
+pub mod client;
 pub mod control;
 pub mod packet;
 pub mod encodable;
+#[cfg(feature = "defmt")]
+mod format;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod inspect;
+#[cfg(not(feature = "std"))]
+mod nostd;
 pub mod qos;
+#[cfg(feature = "std")]
+pub mod server;
+#[cfg(feature = "std")]
+pub mod session;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod storage;
+#[cfg(all(feature = "serde", feature = "std"))]
+mod serialize;
+#[cfg(feature = "sn")]
+pub mod sn;
+#[cfg(all(feature = "std", any(test, feature = "test-util")))]
+pub mod testing;
+pub mod topic_filter;
+#[cfg(feature = "std")]
+pub mod transport;
@@ -0,0 +1,157 @@
+//! The subset of the `byteorder` crate used by the packet layer, over `nostd::io`
+
+use core::fmt;
+
+use nostd::io;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    UnexpectedEOF,
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::UnexpectedEOF => io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected EOF"),
+            Error::Io(err) => err,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Error::UnexpectedEOF => f.write_str("Unexpected end of file"),
+            &Error::Io(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl ::core::error::Error for Error {
+    fn description(&self) -> &str {
+        match self {
+            &Error::UnexpectedEOF => "Unexpected end of file",
+            &Error::Io(..) => "I/O error",
+        }
+    }
+}
+
+pub trait ByteOrder {
+    fn read_u16(buf: &[u8]) -> u16;
+    fn read_u32(buf: &[u8]) -> u32;
+    fn write_u16(buf: &mut [u8], n: u16);
+    fn write_u32(buf: &mut [u8], n: u32);
+}
+
+pub enum BigEndian {}
+
+impl ByteOrder for BigEndian {
+    fn read_u16(buf: &[u8]) -> u16 {
+        (buf[0] as u16) << 8 | buf[1] as u16
+    }
+
+    fn read_u32(buf: &[u8]) -> u32 {
+        (buf[0] as u32) << 24 | (buf[1] as u32) << 16 | (buf[2] as u32) << 8 | buf[3] as u32
+    }
+
+    fn write_u16(buf: &mut [u8], n: u16) {
+        buf[0] = (n >> 8) as u8;
+        buf[1] = n as u8;
+    }
+
+    fn write_u32(buf: &mut [u8], n: u32) {
+        buf[0] = (n >> 24) as u8;
+        buf[1] = (n >> 16) as u8;
+        buf[2] = (n >> 8) as u8;
+        buf[3] = n as u8;
+    }
+}
+
+fn read_full<R: io::Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => return Err(Error::UnexpectedEOF),
+            Ok(n) => filled += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(Error::Io(err)),
+        }
+    }
+    Ok(())
+}
+
+pub trait ReadBytesExt: io::Read {
+    fn read_u8(&mut self) -> Result<u8> {
+        let mut buf = [0; 1];
+        try!(read_full(self, &mut buf));
+        Ok(buf[0])
+    }
+
+    fn read_u16<T: ByteOrder>(&mut self) -> Result<u16> {
+        let mut buf = [0; 2];
+        try!(read_full(self, &mut buf));
+        Ok(T::read_u16(&buf))
+    }
+
+    fn read_u32<T: ByteOrder>(&mut self) -> Result<u32> {
+        let mut buf = [0; 4];
+        try!(read_full(self, &mut buf));
+        Ok(T::read_u32(&buf))
+    }
+}
+
+impl<R: io::Read + ?Sized> ReadBytesExt for R {}
+
+pub trait WriteBytesExt: io::Write {
+    fn write_u8(&mut self, n: u8) -> Result<()> {
+        self.write_all(&[n]).map_err(Error::Io)
+    }
+
+    fn write_u16<T: ByteOrder>(&mut self, n: u16) -> Result<()> {
+        let mut buf = [0; 2];
+        T::write_u16(&mut buf, n);
+        self.write_all(&buf).map_err(Error::Io)
+    }
+
+    fn write_u32<T: ByteOrder>(&mut self, n: u32) -> Result<()> {
+        let mut buf = [0; 4];
+        T::write_u32(&mut buf, n);
+        self.write_all(&buf).map_err(Error::Io)
+    }
+}
+
+impl<W: io::Write + ?Sized> WriteBytesExt for W {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::prelude::v1::*;
+
+    #[test]
+    fn test_byteorder_big_endian() {
+        let mut buf = Vec::new();
+        buf.write_u8(0x01).unwrap();
+        buf.write_u16::<BigEndian>(0x0203).unwrap();
+        buf.write_u32::<BigEndian>(0x04050607).unwrap();
+        assert_eq!(b"\x01\x02\x03\x04\x05\x06\x07".to_vec(), buf);
+
+        let mut reader = &buf[..];
+        assert_eq!(0x01, reader.read_u8().unwrap());
+        assert_eq!(0x0203, reader.read_u16::<BigEndian>().unwrap());
+        assert_eq!(0x04050607, reader.read_u32::<BigEndian>().unwrap());
+        match reader.read_u16::<BigEndian>() {
+            Err(Error::UnexpectedEOF) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+}
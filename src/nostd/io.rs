@@ -0,0 +1,404 @@
+//! The subset of `std::io` used by the packet layer
+//!
+//! Readers and writers are slices, `Vec<u8>` and `Cursor`, errors carry a kind and a static
+//! message instead of a boxed error.
+
+use core::cmp;
+use core::fmt;
+
+use alloc::vec::Vec;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ErrorKind {
+    InvalidInput,
+    InvalidData,
+    UnexpectedEof,
+    WriteZero,
+    Interrupted,
+    Other,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Error {
+    kind: ErrorKind,
+    message: &'static str,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: &'static str) -> Error {
+        Error {
+            kind: kind,
+            message: message,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        let message = match kind {
+            ErrorKind::InvalidInput => "invalid input parameter",
+            ErrorKind::InvalidData => "invalid data",
+            ErrorKind::UnexpectedEof => "unexpected end of file",
+            ErrorKind::WriteZero => "write zero",
+            ErrorKind::Interrupted => "operation interrupted",
+            ErrorKind::Other => "other error",
+        };
+        Error::new(kind, message)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.message)
+    }
+}
+
+impl ::core::error::Error for Error {
+    fn description(&self) -> &str {
+        self.message
+    }
+}
+
+pub trait Read {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+    fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<()> {
+        while !buf.is_empty() {
+            match self.read(buf) {
+                Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+                Ok(n) => {
+                    let tmp = buf;
+                    buf = &mut tmp[n..];
+                },
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => {},
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        let start = buf.len();
+        let mut chunk = [0; 64];
+        loop {
+            match self.read(&mut chunk) {
+                Ok(0) => return Ok(buf.len() - start),
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => {},
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn by_ref(&mut self) -> &mut Self
+        where Self: Sized
+    {
+        self
+    }
+
+    fn take(self, limit: u64) -> Take<Self>
+        where Self: Sized
+    {
+        Take {
+            inner: self,
+            limit: limit,
+        }
+    }
+}
+
+pub trait Write {
+    fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+    fn flush(&mut self) -> Result<()>;
+
+    fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf) {
+                Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "failed to write whole buffer")),
+                Ok(n) => buf = &buf[n..],
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => {},
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn by_ref(&mut self) -> &mut Self
+        where Self: Sized
+    {
+        self
+    }
+}
+
+impl<'a, R: Read + ?Sized> Read for &'a mut R {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        (**self).read(buf)
+    }
+}
+
+impl<'a, W: Write + ?Sized> Write for &'a mut W {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        (**self).write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+impl<'a> Read for &'a [u8] {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = cmp::min(buf.len(), self.len());
+        let (head, tail) = self.split_at(len);
+        buf[..len].copy_from_slice(head);
+        *self = tail;
+        Ok(len)
+    }
+}
+
+impl<'a> Write for &'a mut [u8] {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = cmp::min(buf.len(), self.len());
+        let (head, tail) = ::core::mem::replace(self, &mut []).split_at_mut(len);
+        head.copy_from_slice(&buf[..len]);
+        *self = tail;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for Vec<u8> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Reader of at most `limit` bytes of the inner reader, see `Read::take`
+#[derive(Debug)]
+pub struct Take<R> {
+    inner: R,
+    limit: u64,
+}
+
+impl<R> Take<R> {
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for Take<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.limit == 0 {
+            return Ok(0);
+        }
+
+        let len = cmp::min(buf.len() as u64, self.limit) as usize;
+        let n = try!(self.inner.read(&mut buf[..len]));
+        self.limit -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Reader and writer over an in-memory buffer
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Cursor<T> {
+    inner: T,
+    pos: u64,
+}
+
+impl<T> Cursor<T> {
+    pub fn new(inner: T) -> Cursor<T> {
+        Cursor {
+            inner: inner,
+            pos: 0,
+        }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    pub fn set_position(&mut self, pos: u64) {
+        self.pos = pos;
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: AsRef<[u8]>> Read for Cursor<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let data = self.inner.as_ref();
+        let start = cmp::min(self.pos, data.len() as u64) as usize;
+        let n = try!((&data[start..]).read(buf));
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for Cursor<Vec<u8>> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let pos = self.pos as usize;
+        let vec = &mut self.inner;
+        if vec.len() < pos {
+            vec.resize(pos, 0);
+        }
+
+        let overwrite = cmp::min(vec.len() - pos, buf.len());
+        vec[pos..pos + overwrite].copy_from_slice(&buf[..overwrite]);
+        vec.extend_from_slice(&buf[overwrite..]);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Write for Cursor<&'a mut [u8]> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let start = cmp::min(self.pos, self.inner.len() as u64) as usize;
+        let n = try!((&mut self.inner[start..]).write(buf));
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Writer discarding everything, see `sink`
+#[derive(Debug)]
+pub struct Sink;
+
+pub fn sink() -> Sink {
+    Sink
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Copies `reader` to `writer` until the end of the reader, returns the number of bytes copied
+pub fn copy<R: Read + ?Sized, W: Write + ?Sized>(reader: &mut R, writer: &mut W) -> Result<u64> {
+    let mut chunk = [0; 64];
+    let mut copied = 0;
+    loop {
+        let n = match reader.read(&mut chunk) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        try!(writer.write_all(&chunk[..n]));
+        copied += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::prelude::v1::*;
+
+    use packet::{PingreqPacket, PublishPacket, QoSWithPacketIdentifier, VariablePacket};
+    use {Encodable, Decodable};
+
+    #[test]
+    fn test_io_slice_reader() {
+        let mut reader = &b"abcde"[..];
+        let mut buf = [0; 3];
+        assert_eq!(Ok(3), reader.read(&mut buf));
+        assert_eq!(b"abc", &buf);
+        assert_eq!(Err(ErrorKind::UnexpectedEof), reader.read_exact(&mut buf).map_err(|err| err.kind()));
+
+        let mut rest = Vec::new();
+        assert_eq!(Ok(3), (&b"xyz"[..]).take(5).read_to_end(&mut rest));
+        assert_eq!(Ok(2), (&b"xyz"[..]).take(2).read_to_end(&mut rest));
+        assert_eq!(b"xyzxy".to_vec(), rest);
+    }
+
+    #[test]
+    fn test_io_slice_writer() {
+        let mut buf = [0; 4];
+        {
+            let mut writer = &mut buf[..];
+            assert_eq!(Ok(()), writer.write_all(b"ab"));
+            assert_eq!(Err(ErrorKind::WriteZero), writer.write_all(b"cde").map_err(|err| err.kind()));
+        }
+        assert_eq!(b"abcd", &buf);
+    }
+
+    #[test]
+    fn test_io_cursor() {
+        let mut cursor = Cursor::new(Vec::new());
+        cursor.write_all(b"hello").unwrap();
+        cursor.set_position(1);
+        cursor.write_all(b"EL").unwrap();
+        assert_eq!(3, cursor.position());
+        assert_eq!(b"hELlo".to_vec(), *cursor.get_ref());
+
+        let mut copied = Vec::new();
+        assert_eq!(Ok(2), copy(&mut cursor, &mut copied));
+        assert_eq!(b"lo".to_vec(), copied);
+        assert_eq!(Ok(0), copy(&mut cursor, &mut sink()));
+    }
+
+    #[test]
+    fn test_io_packets_in_fixed_buffer() {
+        let publish = VariablePacket::new(PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level0, b"hi".to_vec()));
+        let pingreq = VariablePacket::new(PingreqPacket::new());
+
+        let mut buf = [0; 16];
+        let len = {
+            let mut writer = Cursor::new(&mut buf[..]);
+            publish.encode(&mut writer).unwrap();
+            pingreq.encode(&mut writer).unwrap();
+            writer.position() as usize
+        };
+        assert_eq!(b"\x30\x07\x00\x03a/bhi\xc0\x00", &buf[..len]);
+
+        let mut reader = &buf[..len];
+        assert_eq!(publish, VariablePacket::decode(&mut reader).unwrap());
+        assert_eq!(pingreq, VariablePacket::decode(&mut reader).unwrap());
+        assert!(reader.is_empty());
+
+        // Too small for the PUBLISH
+        assert!(publish.encode(&mut &mut buf[..8]).is_err());
+    }
+}
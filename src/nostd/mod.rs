@@ -0,0 +1,28 @@
+//! Stand-ins for `std` and `byteorder` when the `std` feature is disabled
+//!
+//! The crate root imports `std` and `byteorder` from here, so that `use std::io::Read` and
+//! friends resolve the same way with and without the standard library. Modules that are built
+//! without `std` also import `std::prelude::v1::*`, which brings `Vec`, `String` and the like
+//! into scope from `alloc`.
+
+pub mod byteorder;
+pub mod io;
+
+pub mod std {
+    // Some are only used by the tests
+    #[allow(unused_imports)]
+    pub use core::{cmp, convert, error, fmt, iter, marker, mem, num, ops, slice, str, time};
+    pub use alloc::{collections, string};
+
+    pub use nostd::io;
+
+    pub mod prelude {
+        pub mod v1 {
+            pub use core::prelude::v1::*;
+            pub use alloc::borrow::ToOwned;
+            #[allow(unused_imports)]
+            pub use alloc::string::{String, ToString};
+            pub use alloc::vec::Vec;
+        }
+    }
+}
@@ -0,0 +1,167 @@
+/// Defines one of PUBACK, PUBREC, PUBREL and PUBCOMP, which share their layout
+/// (MQTT 5 sections 3.4 to 3.7)
+///
+/// The variable header is the Packet Identifier, followed in MQTT 5 by a Reason Code and
+/// properties that may be left out. A new packet is written in the shortest form, a decoded one
+/// in the form it was received in.
+macro_rules! impl_ack_packet {
+    ($(#[$attr:meta])* pub struct $name:ident: $control_type:ident, $reason_code:ident, $display:expr) => {
+        $(#[$attr])*
+        #[derive(Debug, Eq, PartialEq, Clone)]
+        pub struct $name {
+            fixed_header: FixedHeader,
+            packet_identifier: u16,
+            reason_code: $reason_code,
+            properties: Properties,
+            /// Length after the Packet Identifier of a decoded packet that spelled out fields it could
+            /// have left out
+            wire_length: u32,
+            payload: (),
+        }
+
+        impl $name {
+            pub fn new(pkid: u16) -> $name {
+                $name::new_with_reason(pkid, $reason_code::Success)
+            }
+
+            /// MQTT 5 packet, success without properties is encoded like in MQTT 3.1.1
+            pub fn new_with_reason(pkid: u16, reason_code: $reason_code) -> $name {
+                let mut pk = $name {
+                    fixed_header: FixedHeader::new(PacketType::with_default(ControlType::$control_type), 2),
+                    packet_identifier: pkid,
+                    reason_code: reason_code,
+                    properties: Properties::new(),
+                    wire_length: 0,
+                    payload: (),
+                };
+                pk.fixed_header.remaining_length = pk.encoded_variable_headers_length();
+                pk
+            }
+
+            pub fn packet_identifier(&self) -> u16 {
+                self.packet_identifier
+            }
+
+            pub fn set_packet_identifier(&mut self, pkid: u16) {
+                self.packet_identifier = pkid;
+            }
+
+            pub fn reason_code(&self) -> $reason_code {
+                self.reason_code
+            }
+
+            pub fn properties(&self) -> &Properties {
+                &self.properties
+            }
+
+            pub fn set_properties(&mut self, properties: Properties) {
+                self.properties = properties;
+                self.fixed_header.remaining_length = self.encoded_variable_headers_length();
+            }
+
+            pub fn reason_string(&self) -> Option<&str> {
+                self.properties.reason_string()
+            }
+
+            pub fn set_reason_string(&mut self, reason: Option<String>) {
+                self.properties.set_reason_string(reason);
+                self.fixed_header.remaining_length = self.encoded_variable_headers_length();
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                if self.reason_code == $reason_code::Success {
+                    write!(f, concat!($display, "(pkid={})"), self.packet_identifier)
+                } else {
+                    write!(f, concat!($display, "(pkid={}, reason={})"), self.packet_identifier, self.reason_code.to_u8())
+                }
+            }
+        }
+
+        impl<'a> Packet<'a> for $name {
+            type Payload = ();
+
+            const CONTROL_TYPE: ControlType = ControlType::$control_type;
+
+            fn fixed_header(&self) -> &FixedHeader {
+                &self.fixed_header
+            }
+
+            fn payload(&self) -> &Self::Payload {
+                &self.payload
+            }
+
+            fn encode_variable_headers<W: Write>(&self, writer: &mut W) -> Result<(), PacketError<'a, Self>> {
+                let length = self.encoded_variable_headers_length();
+                try!(self.packet_identifier.encode(writer));
+                if length >= 3 {
+                    try!(self.reason_code.encode(writer));
+                }
+                if length >= 4 {
+                    try!(self.properties.encode(writer));
+                }
+
+                Ok(())
+            }
+
+            fn encoded_variable_headers_length(&self) -> u32 {
+                self.packet_identifier.encoded_length()
+                    + if !self.properties.is_empty() {
+                        self.reason_code.encoded_length() + self.properties.encoded_length()
+                    } else if self.reason_code != $reason_code::Success {
+                        cmp::max(self.reason_code.encoded_length(), self.wire_length)
+                    } else {
+                        self.wire_length
+                    }
+            }
+
+            fn decode_packet<R: Read>(reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
+                let packet_identifier: u16 = try!(Decodable::decode(reader));
+                Ok($name {
+                    fixed_header: fixed_header,
+                    packet_identifier: packet_identifier,
+                    reason_code: $reason_code::Success,
+                    properties: Properties::new(),
+                    wire_length: 0,
+                    payload: (),
+                })
+            }
+
+            fn decode_packet_with_version<R: Read>(reader: &mut R, fixed_header: FixedHeader,
+                                                   version: ProtocolVersion) -> Result<Self, PacketError<'a, Self>> {
+                if version != ProtocolVersion::V5 {
+                    return $name::decode_packet(reader, fixed_header);
+                }
+
+                let packet_identifier: u16 = try!(Decodable::decode(reader));
+                let reason_code = if fixed_header.remaining_length >= 3 {
+                    try!($reason_code::decode(reader))
+                } else {
+                    $reason_code::Success
+                };
+                let properties = if fixed_header.remaining_length >= 4 {
+                    try!(Properties::decode(reader))
+                } else {
+                    Properties::new()
+                };
+                try!(properties.check_allowed(&[PropertyType::ReasonString, PropertyType::UserProperty], $display)
+                         .map_err(PacketError::MalformedPacket));
+
+                let mut pk = $name {
+                    fixed_header: fixed_header,
+                    packet_identifier: packet_identifier,
+                    reason_code: reason_code,
+                    properties: properties,
+                    wire_length: 0,
+                    payload: (),
+                };
+                if pk.fixed_header.remaining_length > pk.encoded_variable_headers_length() {
+                    // Only the Reason Code and the Property Length may be spelled out
+                    pk.wire_length = cmp::min(pk.fixed_header.remaining_length, 4) - 2;
+                }
+                Ok(pk)
+            }
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use std::prelude::v1::*;
+use std::io::{Read, Write};
+use std::cmp;
+use std::fmt;
+
+use control::{FixedHeader, PacketType, ControlType};
+use control::variable_header::{AuthReasonCode, Properties, Property, PropertyType, ProtocolVersion};
+use packet::{Packet, PacketError};
+use {Encodable, Decodable};
+
+/// MQTT 5 AUTH, exchanged for enhanced authentication
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct AuthPacket {
+    fixed_header: FixedHeader,
+    reason_code: AuthReasonCode,
+    properties: Properties,
+    /// Length of a decoded packet that spelled out fields it could have left out
+    wire_length: u32,
+    payload: (),
+}
+
+impl AuthPacket {
+    pub fn new(reason_code: AuthReasonCode) -> AuthPacket {
+        let mut pk = AuthPacket {
+            fixed_header: FixedHeader::new(PacketType::with_default(ControlType::Auth), 0),
+            reason_code: reason_code,
+            properties: Properties::new(),
+            wire_length: 0,
+            payload: (),
+        };
+        pk.fixed_header.remaining_length = pk.encoded_variable_headers_length();
+        pk
+    }
+
+    /// Step of an authentication exchange using `method`
+    pub fn with_data(reason_code: AuthReasonCode, method: String, data: Vec<u8>) -> AuthPacket {
+        let mut properties = Properties::new();
+        properties.insert(Property::AuthenticationMethod(method));
+        properties.insert(Property::AuthenticationData(data));
+
+        let mut pk = AuthPacket::new(reason_code);
+        pk.set_properties(properties);
+        pk
+    }
+
+    pub fn reason_code(&self) -> AuthReasonCode {
+        self.reason_code
+    }
+
+    pub fn set_reason_code(&mut self, reason_code: AuthReasonCode) {
+        self.reason_code = reason_code;
+        self.fixed_header.remaining_length = self.encoded_variable_headers_length();
+    }
+
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    pub fn set_properties(&mut self, properties: Properties) {
+        self.properties = properties;
+        self.fixed_header.remaining_length = self.encoded_variable_headers_length();
+    }
+
+    pub fn authentication_method(&self) -> Option<&str> {
+        self.properties.authentication_method()
+    }
+
+    pub fn authentication_data(&self) -> Option<&[u8]> {
+        self.properties.authentication_data()
+    }
+
+    pub fn reason_string(&self) -> Option<&str> {
+        self.properties.reason_string()
+    }
+
+    pub fn set_reason_string(&mut self, reason: Option<String>) {
+        self.properties.set_reason_string(reason);
+        self.fixed_header.remaining_length = self.encoded_variable_headers_length();
+    }
+}
+
+/// Properties allowed in AUTH
+const AUTH_PROPERTIES: &'static [PropertyType] = &[
+    PropertyType::AuthenticationMethod,
+    PropertyType::AuthenticationData,
+    PropertyType::ReasonString,
+    PropertyType::UserProperty,
+];
+
+impl fmt::Display for AuthPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "AUTH(reason={}", self.reason_code.to_u8()));
+
+        if let Some(method) = self.authentication_method() {
+            try!(write!(f, ", method={:?}", method));
+        }
+
+        write!(f, ")")
+    }
+}
+
+impl<'a> Packet<'a> for AuthPacket {
+    type Payload = ();
+
+    const CONTROL_TYPE: ControlType = ControlType::Auth;
+
+    fn fixed_header(&self) -> &FixedHeader {
+        &self.fixed_header
+    }
+
+    fn payload(&self) -> &Self::Payload {
+        &self.payload
+    }
+
+    fn encode_variable_headers<W: Write>(&self, writer: &mut W) -> Result<(), PacketError<'a, Self>> {
+        let length = self.encoded_variable_headers_length();
+        if length >= 1 {
+            try!(self.reason_code.encode(writer));
+        }
+        if length >= 2 {
+            try!(self.properties.encode(writer));
+        }
+        Ok(())
+    }
+
+    /// Reason Code 0x00 without properties is sent as an empty packet, unless it was decoded
+    /// from a longer one
+    fn encoded_variable_headers_length(&self) -> u32 {
+        if !self.properties.is_empty() {
+            self.reason_code.encoded_length() + self.properties.encoded_length()
+        } else if self.reason_code != AuthReasonCode::Success {
+            cmp::max(self.reason_code.encoded_length(), self.wire_length)
+        } else {
+            self.wire_length
+        }
+    }
+
+    fn decode_packet<R: Read>(_reader: &mut R, _fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
+        Err(PacketError::MalformedPacket("AUTH is only defined in MQTT 5".to_owned()))
+    }
+
+    fn decode_packet_with_version<R: Read>(reader: &mut R, fixed_header: FixedHeader, version: ProtocolVersion)
+            -> Result<Self, PacketError<'a, Self>> {
+        if version != ProtocolVersion::V5 {
+            return AuthPacket::decode_packet(reader, fixed_header);
+        }
+
+        // The Reason Code and the Property Length may be left out (MQTT 5 section 3.15.2)
+        let reason_code = if fixed_header.remaining_length >= 1 {
+            try!(AuthReasonCode::decode(reader))
+        } else {
+            AuthReasonCode::Success
+        };
+        let properties = if fixed_header.remaining_length >= 2 {
+            try!(Properties::decode(reader))
+        } else {
+            Properties::new()
+        };
+        try!(properties.check_allowed(AUTH_PROPERTIES, "AUTH").map_err(PacketError::MalformedPacket));
+
+        let mut pk = AuthPacket {
+            fixed_header: fixed_header,
+            reason_code: reason_code,
+            properties: properties,
+            wire_length: 0,
+            payload: (),
+        };
+        if pk.fixed_header.remaining_length > pk.encoded_variable_headers_length() {
+            pk.wire_length = cmp::min(pk.fixed_header.remaining_length, 2);
+        }
+        Ok(pk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use packet::{self, DecodeOptions, PacketError, VariablePacket, VariablePacketError};
+    use Encodable;
+
+    #[test]
+    fn test_auth_packet_short_form() {
+        let packet = AuthPacket::new(AuthReasonCode::Success);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\xf0\x00"[..], &buf[..]);
+
+        let decoded: AuthPacket = packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet, decoded);
+        assert_eq!(AuthReasonCode::Success, decoded.reason_code());
+        assert!(decoded.properties().is_empty());
+
+        // Reason Code without a Property Length
+        let decoded: AuthPacket =
+            packet::decode_with_version(&mut Cursor::new(&b"\xf0\x01\x19"[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(AuthReasonCode::ReAuthenticate, decoded.reason_code());
+        assert!(decoded.properties().is_empty());
+    }
+
+    #[test]
+    fn test_auth_packet_long_form_round_trip() {
+        for encoded_data in [&b"\xf0\x01\x00"[..], &b"\xf0\x02\x00\x00"[..], &b"\xf0\x01\x19"[..],
+                             &b"\xf0\x02\x19\x00"[..]].iter() {
+            let decoded: AuthPacket =
+                packet::decode_with_version(&mut Cursor::new(*encoded_data), ProtocolVersion::V5).unwrap();
+            assert_eq!(encoded_data.len() as u32, decoded.encoded_length());
+
+            let mut buf = Vec::new();
+            decoded.encode(&mut buf).unwrap();
+            assert_eq!(*encoded_data, &buf[..]);
+        }
+    }
+
+    #[test]
+    fn test_auth_packet_basic() {
+        let mut packet = AuthPacket::with_data(AuthReasonCode::ContinueAuthentication,
+                                               "SCRAM-SHA-1".to_owned(), b"server-first".to_vec());
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\xf0\x1f\x18\x1d\x15\x00\x0bSCRAM-SHA-1\x16\x00\x0cserver-first"[..], &buf[..]);
+
+        let decoded: AuthPacket = packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet, decoded);
+        assert_eq!(Some("SCRAM-SHA-1"), decoded.authentication_method());
+        assert_eq!(Some(&b"server-first"[..]), decoded.authentication_data());
+        assert_eq!("AUTH(reason=24, method=\"SCRAM-SHA-1\")", decoded.to_string());
+
+        let mut properties = packet.properties().clone();
+        properties.insert(Property::ReasonString("step 2".to_owned()));
+        packet.set_properties(properties);
+        packet.set_reason_code(AuthReasonCode::Success);
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        let decoded: AuthPacket = packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet, decoded);
+        assert_eq!(Some("step 2"), decoded.reason_string());
+    }
+
+    #[test]
+    fn test_auth_packet_version() {
+        let encoded_data = b"\xf0\x00";
+
+        let options = DecodeOptions::new().protocol_version(ProtocolVersion::V5);
+        let decoded = VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &options).unwrap();
+        assert_eq!(VariablePacket::new(AuthPacket::new(AuthReasonCode::Success)), decoded);
+
+        // Type 15 is reserved in MQTT 3.1.1
+        match VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &DecodeOptions::new()) {
+            Err(VariablePacketError::FixedHeaderError(..)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+        match packet::decode_with_version::<AuthPacket, _>(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V3_1_1) {
+            Err(PacketError::FixedHeaderError(..)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        // Only the authentication properties are allowed
+        let encoded_data = b"\xf0\x04\x18\x02\x17\x01";
+        match packet::decode_with_version::<AuthPacket, _>(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5) {
+            Err(PacketError::MalformedPacket(ref err)) => assert!(err.contains("0x17"), "{}", err),
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+}
@@ -0,0 +1,1105 @@
+//! Client packets held in fixed-capacity `heapless` buffers
+//!
+//! The capacities are const generic parameters, so building, encoding and decoding these
+//! packets does not touch the heap. They implement `Packet` like the other packets and encode
+//! to exactly the same bytes. Decoding a packet whose fields do not fit fails with
+//! `PacketError::CapacityExceeded`, after skipping the rest of the packet so that the next one
+//! can still be decoded.
+
+use std::prelude::v1::*;
+use std::io::{self, Read, Write};
+use std::error::Error;
+use std::fmt;
+use std::str;
+use std::convert::{From, TryFrom};
+
+use byteorder::{self, BigEndian, ReadBytesExt, WriteBytesExt};
+use heapless;
+
+use control::{FixedHeader, PacketType, ControlType};
+use control::variable_header::{ConnectFlags, KeepAlive, PacketIdentifier, ProtocolLevel, ProtocolVersion};
+use control::variable_header::VariableHeaderError;
+use encodable::StringEncodeError;
+use packet::{Packet, PacketError, QoSWithPacketIdentifier};
+use {Encodable, Decodable, QualityOfService, TopicFilter};
+
+/// A value does not fit in the capacity of a bounded packet
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct CapacityError;
+
+impl fmt::Display for CapacityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Capacity of the bounded packet exceeded")
+    }
+}
+
+impl Error for CapacityError {
+    fn description(&self) -> &str {
+        "Capacity of the bounded packet exceeded"
+    }
+}
+
+#[derive(Debug)]
+pub enum BoundedPayloadError {
+    IoError(io::Error),
+    StringEncodeError(StringEncodeError),
+    InvalidUtf8,
+    InvalidQoS(u8),
+    CapacityExceeded,
+}
+
+impl fmt::Display for BoundedPayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &BoundedPayloadError::IoError(ref err) => err.fmt(f),
+            &BoundedPayloadError::StringEncodeError(ref err) => err.fmt(f),
+            &BoundedPayloadError::InvalidUtf8 => write!(f, "Invalid UTF-8 string"),
+            &BoundedPayloadError::InvalidQoS(qos) => write!(f, "Invalid QoS ({})", qos),
+            &BoundedPayloadError::CapacityExceeded => write!(f, "Capacity of the bounded packet exceeded"),
+        }
+    }
+}
+
+impl Error for BoundedPayloadError {
+    fn description(&self) -> &str {
+        match self {
+            &BoundedPayloadError::IoError(ref err) => err.description(),
+            &BoundedPayloadError::StringEncodeError(ref err) => err.description(),
+            &BoundedPayloadError::InvalidUtf8 => "Invalid UTF-8 string",
+            &BoundedPayloadError::InvalidQoS(..) => "Invalid QoS",
+            &BoundedPayloadError::CapacityExceeded => "Capacity of the bounded packet exceeded",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match self {
+            &BoundedPayloadError::IoError(ref err) => Some(err),
+            &BoundedPayloadError::StringEncodeError(ref err) => Some(err),
+            &BoundedPayloadError::InvalidUtf8 => None,
+            &BoundedPayloadError::InvalidQoS(..) => None,
+            &BoundedPayloadError::CapacityExceeded => None,
+        }
+    }
+}
+
+impl From<io::Error> for BoundedPayloadError {
+    fn from(err: io::Error) -> BoundedPayloadError {
+        BoundedPayloadError::IoError(err)
+    }
+}
+
+impl From<byteorder::Error> for BoundedPayloadError {
+    fn from(err: byteorder::Error) -> BoundedPayloadError {
+        BoundedPayloadError::IoError(From::from(err))
+    }
+}
+
+impl From<StringEncodeError> for BoundedPayloadError {
+    fn from(err: StringEncodeError) -> BoundedPayloadError {
+        BoundedPayloadError::StringEncodeError(err)
+    }
+}
+
+impl From<CapacityError> for BoundedPayloadError {
+    fn from(_: CapacityError) -> BoundedPayloadError {
+        BoundedPayloadError::CapacityExceeded
+    }
+}
+
+impl<'a, const N: usize> Encodable<'a> for heapless::String<N> {
+    type Err = StringEncodeError;
+
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), StringEncodeError> {
+        self.as_str().encode(writer)
+    }
+
+    fn encoded_length(&self) -> u32 {
+        self.as_str().encoded_length()
+    }
+}
+
+impl<'a, const N: usize> Decodable<'a> for heapless::String<N> {
+    type Err = BoundedPayloadError;
+    type Cond = ();
+
+    fn decode_with<R: Read>(reader: &mut R, _rest: Option<()>) -> Result<heapless::String<N>, BoundedPayloadError> {
+        let len = try!(reader.read_u16::<BigEndian>()) as usize;
+        let bytes: heapless::Vec<u8, N> = try!(read_bytes(reader, len));
+        let string = try!(str::from_utf8(&bytes).map_err(|_| BoundedPayloadError::InvalidUtf8));
+        copy_str(string).map_err(From::from)
+    }
+}
+
+impl<'a, const N: usize> Encodable<'a> for heapless::Vec<u8, N> {
+    type Err = BoundedPayloadError;
+
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), BoundedPayloadError> {
+        try!(writer.write_all(self));
+        Ok(())
+    }
+
+    fn encoded_length(&self) -> u32 {
+        self.len() as u32
+    }
+}
+
+impl<'a, const N: usize> Decodable<'a> for heapless::Vec<u8, N> {
+    type Err = BoundedPayloadError;
+    type Cond = u32;
+
+    fn decode_with<R: Read>(reader: &mut R, length: Option<u32>) -> Result<heapless::Vec<u8, N>, BoundedPayloadError> {
+        if let Some(length) = length {
+            return read_bytes(reader, length as usize);
+        }
+
+        let mut bytes = heapless::Vec::new();
+        let mut chunk = [0; 64];
+        loop {
+            let n = try!(reader.read(&mut chunk));
+            if n == 0 {
+                return Ok(bytes);
+            }
+            try!(bytes.extend_from_slice(&chunk[..n]).map_err(|_| BoundedPayloadError::CapacityExceeded));
+        }
+    }
+}
+
+fn copy_str<const N: usize>(string: &str) -> Result<heapless::String<N>, CapacityError> {
+    let mut copy = heapless::String::new();
+    try!(copy.push_str(string).map_err(|_| CapacityError));
+    Ok(copy)
+}
+
+fn copy_bytes<const N: usize>(bytes: &[u8]) -> Result<heapless::Vec<u8, N>, CapacityError> {
+    heapless::Vec::from_slice(bytes).map_err(|_| CapacityError)
+}
+
+/// Reads exactly `len` bytes, failing before reading anything if they do not fit
+fn read_bytes<R: Read, const N: usize>(reader: &mut R, len: usize) -> Result<heapless::Vec<u8, N>, BoundedPayloadError> {
+    let mut bytes = heapless::Vec::new();
+    try!(bytes.resize_default(len).map_err(|_| BoundedPayloadError::CapacityExceeded));
+    try!(reader.read_exact(&mut bytes));
+    Ok(bytes)
+}
+
+/// Same as `VarBytes`, a two byte length followed by the bytes
+fn write_var_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<(), BoundedPayloadError> {
+    assert!(bytes.len() <= u16::max_value() as usize);
+
+    try!(writer.write_u16::<BigEndian>(bytes.len() as u16));
+    try!(writer.write_all(bytes));
+    Ok(())
+}
+
+fn payload_error<'a, T>(err: BoundedPayloadError) -> PacketError<'a, T>
+    where T: Packet<'a>,
+          T::Payload: Encodable<'a, Err = BoundedPayloadError>
+{
+    match err {
+        BoundedPayloadError::CapacityExceeded => PacketError::CapacityExceeded,
+        BoundedPayloadError::InvalidQoS(qos) => PacketError::InvalidQoS(qos),
+        err => PacketError::PayloadError(err),
+    }
+}
+
+/// Skips the rest of a packet that did not fit, so that the stream stays in sync
+fn skip_if_exceeded<'a, T, R>(reader: &mut R, result: Result<T, PacketError<'a, T>>) -> Result<T, PacketError<'a, T>>
+    where T: Packet<'a>,
+          R: Read
+{
+    if let Err(PacketError::CapacityExceeded) = result {
+        try!(io::copy(reader, &mut io::sink()));
+    }
+    result
+}
+
+/// Will Message of a `BoundedConnectPacket`
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct BoundedLastWill<const N: usize> {
+    pub topic: heapless::String<N>,
+    pub message: heapless::Vec<u8, N>,
+    pub qos: QualityOfService,
+    pub retain: bool,
+}
+
+impl<const N: usize> BoundedLastWill<N> {
+    pub fn new(topic: &str, message: &[u8], qos: QualityOfService, retain: bool) -> Result<BoundedLastWill<N>, CapacityError> {
+        Ok(BoundedLastWill {
+            topic: try!(copy_str(topic)),
+            message: try!(copy_bytes(message)),
+            qos: qos,
+            retain: retain,
+        })
+    }
+}
+
+/// CONNECT packet whose strings and binary fields hold at most `N` bytes each
+///
+/// Only MQTT 3.1 and 3.1.1 are supported. The `Debug` output redacts the password and the will
+/// message like `ConnectPacket` does.
+#[derive(Eq, PartialEq, Clone)]
+pub struct BoundedConnectPacket<const N: usize> {
+    fixed_header: FixedHeader,
+    protocol_version: ProtocolVersion,
+    clean_session: bool,
+    keep_alive: KeepAlive,
+    payload: BoundedConnectPayload<N>,
+}
+
+impl<const N: usize> BoundedConnectPacket<N> {
+    pub fn new(client_identifier: &str) -> Result<BoundedConnectPacket<N>, CapacityError> {
+        BoundedConnectPacket::with_version(client_identifier, ProtocolVersion::V3_1_1)
+    }
+
+    /// Panics for `ProtocolVersion::V5`
+    pub fn with_version(client_identifier: &str, version: ProtocolVersion) -> Result<BoundedConnectPacket<N>, CapacityError> {
+        assert!(version != ProtocolVersion::V5, "MQTT 5 is not supported");
+
+        let mut pk = BoundedConnectPacket {
+            fixed_header: FixedHeader::new(PacketType::with_default(ControlType::Connect), 0),
+            protocol_version: version,
+            clean_session: false,
+            keep_alive: KeepAlive(0),
+            payload: BoundedConnectPayload {
+                client_identifier: try!(copy_str(client_identifier)),
+                will: None,
+                user_name: None,
+                password: None,
+            },
+        };
+        pk.fixed_header.remaining_length = pk.calculate_remaining_length();
+        Ok(pk)
+    }
+
+    #[inline]
+    fn calculate_remaining_length(&self) -> u32 {
+        self.encoded_variable_headers_length() + self.payload.encoded_length()
+    }
+
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+    }
+
+    pub fn client_identifier(&self) -> &str {
+        &self.payload.client_identifier
+    }
+
+    pub fn set_keep_alive(&mut self, keep_alive: u16) {
+        self.keep_alive = KeepAlive(keep_alive);
+    }
+
+    pub fn keep_alive(&self) -> u16 {
+        self.keep_alive.0
+    }
+
+    pub fn set_clean_session(&mut self, clean_session: bool) {
+        self.clean_session = clean_session;
+    }
+
+    pub fn clean_session(&self) -> bool {
+        self.clean_session
+    }
+
+    pub fn set_user_name(&mut self, name: Option<&str>) -> Result<(), CapacityError> {
+        self.payload.user_name = match name {
+            Some(name) => Some(try!(copy_str(name))),
+            None => None,
+        };
+        self.fixed_header.remaining_length = self.calculate_remaining_length();
+        Ok(())
+    }
+
+    pub fn user_name(&self) -> Option<&str> {
+        self.payload.user_name.as_ref().map(|name| &name[..])
+    }
+
+    pub fn set_password(&mut self, password: Option<&[u8]>) -> Result<(), CapacityError> {
+        self.payload.password = match password {
+            Some(password) => Some(try!(copy_bytes(password))),
+            None => None,
+        };
+        self.fixed_header.remaining_length = self.calculate_remaining_length();
+        Ok(())
+    }
+
+    pub fn password(&self) -> Option<&[u8]> {
+        self.payload.password.as_ref().map(|password| &password[..])
+    }
+
+    pub fn set_will(&mut self, will: Option<BoundedLastWill<N>>) {
+        self.payload.will = will;
+        self.fixed_header.remaining_length = self.calculate_remaining_length();
+    }
+
+    pub fn will(&self) -> Option<&BoundedLastWill<N>> {
+        self.payload.will.as_ref()
+    }
+
+    fn connect_flags(&self) -> ConnectFlags {
+        let mut flags = ConnectFlags::empty();
+        flags.user_name = self.payload.user_name.is_some();
+        flags.password = self.payload.password.is_some();
+        flags.clean_session = self.clean_session;
+        if let Some(ref will) = self.payload.will {
+            flags.will_flag = true;
+            flags.will_qos = will.qos as u8;
+            flags.will_retain = will.retain;
+        }
+        flags
+    }
+
+    fn decode_connect<'a, R: Read>(reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
+        // Long enough for "MQIsdp", anything longer is not a supported protocol name
+        let mut name = [0; 6];
+        let name_len = try!(reader.read_u16::<BigEndian>().map_err(io::Error::from)) as usize;
+        if name_len > name.len() {
+            return Err(PacketError::MalformedPacket("Expecting protocol name \"MQTT\" or \"MQIsdp\"".to_owned()));
+        }
+        try!(reader.read_exact(&mut name[..name_len]));
+
+        let protocol_level: ProtocolLevel = try!(Decodable::decode(reader));
+        let protocol_version = match str::from_utf8(&name[..name_len]).ok()
+                                         .and_then(|name| ProtocolVersion::from_name_and_level(name, protocol_level.0)) {
+            Some(ProtocolVersion::V5) | None =>
+                return Err(PacketError::MalformedPacket("Expecting MQTT 3.1 or 3.1.1".to_owned())),
+            Some(version) => version,
+        };
+
+        let flags: ConnectFlags = try!(Decodable::decode(reader));
+        if flags.password && !flags.user_name {
+            return Err(PacketError::VariableHeaderError(VariableHeaderError::PasswordWithoutUserName));
+        }
+        let keep_alive: KeepAlive = try!(Decodable::decode(reader));
+        let payload: BoundedConnectPayload<N> =
+            try!(Decodable::decode_with(reader, Some(flags)).map_err(payload_error));
+
+        Ok(BoundedConnectPacket {
+            fixed_header: fixed_header,
+            protocol_version: protocol_version,
+            clean_session: flags.clean_session,
+            keep_alive: keep_alive,
+            payload: payload,
+        })
+    }
+}
+
+impl<const N: usize> fmt::Debug for BoundedConnectPacket<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BoundedConnectPacket")
+            .field("fixed_header", &self.fixed_header)
+            .field("protocol_version", &self.protocol_version)
+            .field("clean_session", &self.clean_session)
+            .field("keep_alive", &self.keep_alive)
+            .field("payload", &self.payload)
+            .finish()
+    }
+}
+
+impl<const N: usize> fmt::Display for BoundedConnectPacket<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "CONNECT(client_id={:?}, keep_alive={}", self.client_identifier(), self.keep_alive.0));
+
+        if self.clean_session {
+            try!(write!(f, ", clean_session"));
+        }
+
+        if let Some(user_name) = self.user_name() {
+            try!(write!(f, ", user_name={:?}", user_name));
+        }
+
+        if self.payload.password.is_some() {
+            try!(write!(f, ", password"));
+        }
+
+        if let Some(ref will) = self.payload.will {
+            try!(write!(f, ", will(topic={:?}, qos={}, payload={}B", will.topic.as_str(), will.qos, will.message.len()));
+            if will.retain {
+                try!(write!(f, ", retain"));
+            }
+            try!(write!(f, ")"));
+        }
+
+        write!(f, ")")
+    }
+}
+
+impl<'a, const N: usize> Packet<'a> for BoundedConnectPacket<N> {
+    type Payload = BoundedConnectPayload<N>;
+
+    const CONTROL_TYPE: ControlType = ControlType::Connect;
+
+    fn fixed_header(&self) -> &FixedHeader {
+        &self.fixed_header
+    }
+
+    fn payload(&self) -> &BoundedConnectPayload<N> {
+        &self.payload
+    }
+
+    fn encode_variable_headers<W: Write>(&self, writer: &mut W) -> Result<(), PacketError<'a, Self>> {
+        if self.payload.password.is_some() && self.payload.user_name.is_none() {
+            return Err(PacketError::VariableHeaderError(VariableHeaderError::PasswordWithoutUserName));
+        }
+
+        try!(self.protocol_version.protocol_name().encode(writer));
+        try!(ProtocolLevel(self.protocol_version.level()).encode(writer));
+        try!(self.connect_flags().encode(writer));
+        try!(self.keep_alive.encode(writer));
+
+        Ok(())
+    }
+
+    fn encoded_variable_headers_length(&self) -> u32 {
+        self.protocol_version.protocol_name().encoded_length()
+            + ProtocolLevel(self.protocol_version.level()).encoded_length()
+            + self.connect_flags().encoded_length()
+            + self.keep_alive.encoded_length()
+    }
+
+    fn decode_packet<R: Read>(reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
+        let result = BoundedConnectPacket::decode_connect(reader, fixed_header);
+        skip_if_exceeded(reader, result)
+    }
+}
+
+#[derive(Eq, PartialEq, Clone)]
+pub struct BoundedConnectPayload<const N: usize> {
+    client_identifier: heapless::String<N>,
+    will: Option<BoundedLastWill<N>>,
+    user_name: Option<heapless::String<N>>,
+    password: Option<heapless::Vec<u8, N>>,
+}
+
+impl<const N: usize> fmt::Debug for BoundedConnectPayload<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BoundedConnectPayload")
+            .field("client_identifier", &self.client_identifier)
+            .field("will", &self.will.as_ref().map(|will| WillDebug(will)))
+            .field("user_name", &self.user_name)
+            .field("password", &self.password.as_ref().map(|password| Redacted(password.len())))
+            .finish()
+    }
+}
+
+struct WillDebug<'a, const N: usize>(&'a BoundedLastWill<N>);
+
+impl<'a, const N: usize> fmt::Debug for WillDebug<'a, N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BoundedLastWill")
+            .field("topic", &self.0.topic)
+            .field("message", &Redacted(self.0.message.len()))
+            .field("qos", &self.0.qos)
+            .field("retain", &self.0.retain)
+            .finish()
+    }
+}
+
+struct Redacted(usize);
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<redacted, {} bytes>", self.0)
+    }
+}
+
+impl<'a, const N: usize> Encodable<'a> for BoundedConnectPayload<N> {
+    type Err = BoundedPayloadError;
+
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), BoundedPayloadError> {
+        try!(self.client_identifier.encode(writer));
+
+        if let Some(ref will) = self.will {
+            try!(will.topic.encode(writer));
+            try!(write_var_bytes(writer, &will.message));
+        }
+
+        if let Some(ref user_name) = self.user_name {
+            try!(user_name.encode(writer));
+        }
+
+        if let Some(ref password) = self.password {
+            try!(write_var_bytes(writer, password));
+        }
+
+        Ok(())
+    }
+
+    fn encoded_length(&self) -> u32 {
+        self.client_identifier.encoded_length()
+            + self.will.as_ref().map(|will| will.topic.encoded_length() + 2 + will.message.len() as u32).unwrap_or(0)
+            + self.user_name.as_ref().map(|name| name.encoded_length()).unwrap_or(0)
+            + self.password.as_ref().map(|password| 2 + password.len() as u32).unwrap_or(0)
+    }
+}
+
+impl<'a, const N: usize> Decodable<'a> for BoundedConnectPayload<N> {
+    type Err = BoundedPayloadError;
+    type Cond = ConnectFlags;
+
+    fn decode_with<R: Read>(reader: &mut R, flags: Option<ConnectFlags>) -> Result<BoundedConnectPayload<N>, BoundedPayloadError> {
+        let flags = flags.unwrap_or(ConnectFlags::empty());
+
+        let client_identifier = try!(Decodable::decode(reader));
+        let will = if flags.will_flag {
+            let qos = try!(QualityOfService::try_from(flags.will_qos)
+                               .map_err(|err| BoundedPayloadError::InvalidQoS(err.0)));
+            let topic = try!(Decodable::decode(reader));
+            let message_len = try!(reader.read_u16::<BigEndian>()) as usize;
+            let message = try!(read_bytes(reader, message_len));
+            Some(BoundedLastWill {
+                topic: topic,
+                message: message,
+                qos: qos,
+                retain: flags.will_retain,
+            })
+        } else {
+            None
+        };
+        let user_name = if flags.user_name {
+            Some(try!(Decodable::decode(reader)))
+        } else {
+            None
+        };
+        let password = if flags.password {
+            let password_len = try!(reader.read_u16::<BigEndian>()) as usize;
+            Some(try!(read_bytes(reader, password_len)))
+        } else {
+            None
+        };
+
+        Ok(BoundedConnectPayload {
+            client_identifier: client_identifier,
+            will: will,
+            user_name: user_name,
+            password: password,
+        })
+    }
+}
+
+/// PUBLISH packet with a topic name of at most `T` bytes and a payload of at most `P` bytes
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct BoundedPublishPacket<const T: usize, const P: usize> {
+    fixed_header: FixedHeader,
+    topic_name: heapless::String<T>,
+    packet_identifier: Option<PacketIdentifier>,
+    payload: heapless::Vec<u8, P>,
+}
+
+impl<const T: usize, const P: usize> BoundedPublishPacket<T, P> {
+    pub fn new(topic_name: &str, qos: QoSWithPacketIdentifier, payload: &[u8]) -> Result<BoundedPublishPacket<T, P>, CapacityError> {
+        let mut pk = BoundedPublishPacket {
+            fixed_header: FixedHeader::new(PacketType::with_default(ControlType::Publish), 0),
+            topic_name: try!(copy_str(topic_name)),
+            packet_identifier: None,
+            payload: try!(copy_bytes(payload)),
+        };
+        pk.set_qos(qos);
+        Ok(pk)
+    }
+
+    #[inline]
+    fn calculate_remaining_length(&self) -> u32 {
+        self.encoded_variable_headers_length() + self.payload.encoded_length()
+    }
+
+    /// Sets the DUP flag, see `PublishPacket::set_dup`
+    pub fn set_dup(&mut self, dup: bool) {
+        debug_assert!(!dup || self.packet_identifier.is_some(), "DUP flag must not be set on a QoS 0 PUBLISH");
+        if dup && self.packet_identifier.is_none() {
+            return;
+        }
+
+        self.fixed_header.packet_type.flags &= !0x08;
+        self.fixed_header.packet_type.flags |= (dup as u8) << 3;
+    }
+
+    pub fn dup(&self) -> bool {
+        self.fixed_header.packet_type.flags & 0x08 != 0
+    }
+
+    /// Changing to QoS 0 clears the DUP flag
+    pub fn set_qos(&mut self, qos: QoSWithPacketIdentifier) {
+        let (qos, pkid) = match qos {
+            QoSWithPacketIdentifier::Level0 => (0, None),
+            QoSWithPacketIdentifier::Level1(pkid) => (1, Some(pkid)),
+            QoSWithPacketIdentifier::Level2(pkid) => (2, Some(pkid)),
+        };
+        self.fixed_header.packet_type.flags &= !0x06;
+        self.fixed_header.packet_type.flags |= qos << 1;
+        if pkid.is_none() {
+            self.fixed_header.packet_type.flags &= !0x08;
+        }
+        self.packet_identifier = pkid;
+        self.fixed_header.remaining_length = self.calculate_remaining_length();
+    }
+
+    pub fn qos(&self) -> QoSWithPacketIdentifier {
+        match self.packet_identifier {
+            None => QoSWithPacketIdentifier::Level0,
+            Some(pkid) => {
+                match QualityOfService::try_from((self.fixed_header.packet_type.flags & 0x06) >> 1) {
+                    Ok(QualityOfService::Level1) => QoSWithPacketIdentifier::Level1(pkid),
+                    Ok(QualityOfService::Level2) => QoSWithPacketIdentifier::Level2(pkid),
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+
+    pub fn set_retain(&mut self, ret: bool) {
+        self.fixed_header.packet_type.flags &= !0x01;
+        self.fixed_header.packet_type.flags |= ret as u8;
+    }
+
+    pub fn retain(&self) -> bool {
+        self.fixed_header.packet_type.flags & 0x01 != 0
+    }
+
+    pub fn set_topic_name(&mut self, topic_name: &str) -> Result<(), CapacityError> {
+        self.topic_name = try!(copy_str(topic_name));
+        self.fixed_header.remaining_length = self.calculate_remaining_length();
+        Ok(())
+    }
+
+    pub fn topic_name(&self) -> &str {
+        &self.topic_name
+    }
+
+    pub fn set_payload(&mut self, payload: &[u8]) -> Result<(), CapacityError> {
+        self.payload = try!(copy_bytes(payload));
+        self.fixed_header.remaining_length = self.calculate_remaining_length();
+        Ok(())
+    }
+
+    pub fn payload_ref(&self) -> &[u8] {
+        &self.payload
+    }
+
+    fn contains_wildcard(&self) -> bool {
+        self.topic_name.contains('+') || self.topic_name.contains('#')
+    }
+
+    fn decode_publish<'a, R: Read>(reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
+        // MQTT-3.3.1-4: A PUBLISH Packet MUST NOT have both QoS bits set to 1
+        let qos = try!(QualityOfService::try_from((fixed_header.packet_type.flags & 0x06) >> 1)
+                           .map_err(|err| PacketError::InvalidQoS(err.0)));
+
+        // MQTT-3.3.1-2: The DUP flag MUST be set to 0 for all QoS 0 messages
+        if qos == QualityOfService::Level0 && fixed_header.packet_type.flags & 0x08 != 0 {
+            return Err(PacketError::InvalidDupFlag);
+        }
+
+        let topic_name: heapless::String<T> = try!(Decodable::decode(reader).map_err(payload_error));
+
+        let packet_identifier = if qos != QualityOfService::Level0 {
+            Some(try!(PacketIdentifier::decode(reader)))
+        } else {
+            None
+        };
+
+        let vhead_len = topic_name.encoded_length()
+            + packet_identifier.as_ref().map(|x| x.encoded_length()).unwrap_or(0);
+        let payload_len = match fixed_header.remaining_length.checked_sub(vhead_len) {
+            Some(len) => len,
+            None => return Err(PacketError::MalformedPacket("Remaining length is shorter than the variable header".to_owned())),
+        };
+
+        let payload = try!(Decodable::decode_with(reader, Some(payload_len)).map_err(payload_error));
+
+        let pk = BoundedPublishPacket {
+            fixed_header: fixed_header,
+            topic_name: topic_name,
+            packet_identifier: packet_identifier,
+            payload: payload,
+        };
+
+        // MQTT-3.3.2-2: The Topic Name MUST NOT contain wildcard characters
+        if pk.contains_wildcard() {
+            return Err(PacketError::WildcardInPublishTopic { topic: pk.topic_name.as_str().to_owned() });
+        }
+        Ok(pk)
+    }
+}
+
+impl<const T: usize, const P: usize> fmt::Display for BoundedPublishPacket<T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "PUBLISH(topic={:?}", self.topic_name.as_str()));
+
+        match self.qos() {
+            QoSWithPacketIdentifier::Level0 => try!(write!(f, ", qos=0")),
+            QoSWithPacketIdentifier::Level1(pkid) => try!(write!(f, ", qos=1, pkid={}", pkid)),
+            QoSWithPacketIdentifier::Level2(pkid) => try!(write!(f, ", qos=2, pkid={}", pkid)),
+        }
+
+        try!(write!(f, ", payload={}B", self.payload.len()));
+
+        if self.retain() {
+            try!(write!(f, ", retain"));
+        }
+
+        if self.dup() {
+            try!(write!(f, ", dup"));
+        }
+
+        write!(f, ")")
+    }
+}
+
+impl<'a, const T: usize, const P: usize> Packet<'a> for BoundedPublishPacket<T, P> {
+    type Payload = heapless::Vec<u8, P>;
+
+    const CONTROL_TYPE: ControlType = ControlType::Publish;
+
+    fn fixed_header(&self) -> &FixedHeader {
+        &self.fixed_header
+    }
+
+    fn payload(&self) -> &Self::Payload {
+        &self.payload
+    }
+
+    fn encode_variable_headers<W: Write>(&self, writer: &mut W) -> Result<(), PacketError<'a, Self>> {
+        // MQTT-3.3.2-2: The Topic Name MUST NOT contain wildcard characters
+        if self.contains_wildcard() {
+            return Err(PacketError::WildcardInPublishTopic { topic: self.topic_name.as_str().to_owned() });
+        }
+
+        try!(self.topic_name.encode(writer));
+
+        if let Some(pkid) = self.packet_identifier.as_ref() {
+            try!(pkid.encode(writer));
+        }
+
+        Ok(())
+    }
+
+    fn encoded_variable_headers_length(&self) -> u32 {
+        self.topic_name.encoded_length()
+            + self.packet_identifier.as_ref().map(|x| x.encoded_length()).unwrap_or(0)
+    }
+
+    fn decode_packet<R: Read>(reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
+        let result = BoundedPublishPacket::decode_publish(reader, fixed_header);
+        skip_if_exceeded(reader, result)
+    }
+}
+
+/// SUBSCRIBE packet with at most `F` topic filters of at most `N` bytes each
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct BoundedSubscribePacket<const F: usize, const N: usize> {
+    fixed_header: FixedHeader,
+    packet_identifier: PacketIdentifier,
+    payload: BoundedSubscribePayload<F, N>,
+}
+
+impl<const F: usize, const N: usize> BoundedSubscribePacket<F, N> {
+    /// An empty SUBSCRIBE, it needs at least one subscription before it can be encoded
+    pub fn new(pkid: PacketIdentifier) -> BoundedSubscribePacket<F, N> {
+        let mut pk = BoundedSubscribePacket {
+            fixed_header: FixedHeader::new(PacketType::with_default(ControlType::Subscribe), 0),
+            packet_identifier: pkid,
+            payload: BoundedSubscribePayload { subscribes: heapless::Vec::new() },
+        };
+        pk.fixed_header.remaining_length = pk.encoded_variable_headers_length() + pk.payload.encoded_length();
+        pk
+    }
+
+    pub fn packet_identifier(&self) -> PacketIdentifier {
+        self.packet_identifier
+    }
+
+    pub fn set_packet_identifier(&mut self, pkid: PacketIdentifier) {
+        self.packet_identifier = pkid;
+    }
+
+    pub fn subscriptions<'b>(&'b self) -> impl Iterator<Item = (&'b str, QualityOfService)> + 'b {
+        self.payload.subscribes.iter().map(|&(ref filter, qos)| (filter.as_str(), qos))
+    }
+
+    pub fn len(&self) -> usize {
+        self.payload.subscribes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.payload.subscribes.is_empty()
+    }
+
+    pub fn add_subscription(&mut self, filter: &str, qos: QualityOfService) -> Result<(), CapacityError> {
+        let filter = try!(copy_str(filter));
+        try!(self.payload.subscribes.push((filter, qos)).map_err(|_| CapacityError));
+        self.fixed_header.remaining_length = self.encoded_variable_headers_length() + self.payload.encoded_length();
+        Ok(())
+    }
+
+    fn decode_subscribe<'a, R: Read>(reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
+        let packet_identifier: PacketIdentifier = try!(PacketIdentifier::decode(reader));
+        let payload_len = match fixed_header.remaining_length.checked_sub(packet_identifier.encoded_length()) {
+            Some(len) => len,
+            None => return Err(PacketError::MalformedPacket("Remaining length is shorter than the variable header".to_owned())),
+        };
+        if payload_len == 0 {
+            return Err(PacketError::EmptySubscription);
+        }
+
+        let payload: BoundedSubscribePayload<F, N> =
+            try!(Decodable::decode_with(reader, Some(payload_len)).map_err(payload_error));
+
+        for (idx, &(ref filter, _)) in payload.subscribes.iter().enumerate() {
+            try!(TopicFilter::validate_str(filter).map_err(|reason| PacketError::InvalidTopicFilter { index: idx, reason: reason }));
+        }
+        Ok(BoundedSubscribePacket {
+            fixed_header: fixed_header,
+            packet_identifier: packet_identifier,
+            payload: payload,
+        })
+    }
+}
+
+impl<const F: usize, const N: usize> fmt::Display for BoundedSubscribePacket<F, N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "SUBSCRIBE(pkid={}", self.packet_identifier));
+
+        for &(ref filter, qos) in self.payload.subscribes.iter() {
+            try!(write!(f, ", {:?}@{}", filter.as_str(), qos));
+        }
+
+        write!(f, ")")
+    }
+}
+
+impl<'a, const F: usize, const N: usize> Packet<'a> for BoundedSubscribePacket<F, N> {
+    type Payload = BoundedSubscribePayload<F, N>;
+
+    const CONTROL_TYPE: ControlType = ControlType::Subscribe;
+
+    fn fixed_header(&self) -> &FixedHeader {
+        &self.fixed_header
+    }
+
+    fn payload(&self) -> &Self::Payload {
+        &self.payload
+    }
+
+    fn encode_variable_headers<W: Write>(&self, writer: &mut W) -> Result<(), PacketError<'a, Self>> {
+        // MQTT-3.8.3-3: The payload MUST contain at least one Topic Filter / QoS pair
+        if self.payload.subscribes.is_empty() {
+            return Err(PacketError::EmptySubscription);
+        }
+
+        try!(self.packet_identifier.encode(writer));
+
+        Ok(())
+    }
+
+    fn encoded_variable_headers_length(&self) -> u32 {
+        self.packet_identifier.encoded_length()
+    }
+
+    fn decode_packet<R: Read>(reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
+        let result = BoundedSubscribePacket::decode_subscribe(reader, fixed_header);
+        skip_if_exceeded(reader, result)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct BoundedSubscribePayload<const F: usize, const N: usize> {
+    subscribes: heapless::Vec<(heapless::String<N>, QualityOfService), F>,
+}
+
+impl<'a, const F: usize, const N: usize> Encodable<'a> for BoundedSubscribePayload<F, N> {
+    type Err = BoundedPayloadError;
+
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), BoundedPayloadError> {
+        for &(ref filter, qos) in self.subscribes.iter() {
+            try!(filter.encode(writer));
+            try!(writer.write_u8(qos as u8));
+        }
+
+        Ok(())
+    }
+
+    fn encoded_length(&self) -> u32 {
+        self.subscribes.iter().fold(0, |b, a| b + a.0.encoded_length() + 1)
+    }
+}
+
+impl<'a, const F: usize, const N: usize> Decodable<'a> for BoundedSubscribePayload<F, N> {
+    type Err = BoundedPayloadError;
+    type Cond = u32;
+
+    fn decode_with<R: Read>(reader: &mut R, payload_len: Option<u32>) -> Result<BoundedSubscribePayload<F, N>, BoundedPayloadError> {
+        let mut payload_len = payload_len.expect("Must provide payload length");
+        let mut subscribes = heapless::Vec::new();
+
+        while payload_len > 0 {
+            let filter: heapless::String<N> = try!(Decodable::decode(reader));
+            let qos = try!(QualityOfService::try_from(try!(reader.read_u8()))
+                               .map_err(|err| BoundedPayloadError::InvalidQoS(err.0)));
+
+            payload_len = try!(payload_len.checked_sub(filter.encoded_length() + 1)
+                                   .ok_or(BoundedPayloadError::IoError(
+                                       io::Error::new(io::ErrorKind::InvalidData, "Topic filter exceeds the payload"))));
+            try!(subscribes.push((filter, qos)).map_err(|_| BoundedPayloadError::CapacityExceeded));
+        }
+
+        Ok(BoundedSubscribePayload { subscribes: subscribes })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use packet::{ConnectPacket, PublishPacket, SubscribePacket, PingreqPacket, VariablePacket};
+    use packet::connect::LastWill;
+
+    fn encode<'a, T: Encodable<'a>>(packet: &T) -> Vec<u8> {
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).ok().unwrap();
+        assert_eq!(packet.encoded_length() as usize, buf.len());
+        buf
+    }
+
+    #[test]
+    fn test_bounded_connect_same_bytes_as_owned() {
+        let mut owned = ConnectPacket::new("client-1".to_owned());
+        owned.set_keep_alive(30);
+        owned.set_clean_session(true);
+        owned.set_user_name(Some("user".to_owned()));
+        owned.set_password(Some(b"secret".to_vec()));
+        owned.set_will(Some(LastWill::new("status".to_owned(), b"offline".to_vec(), QualityOfService::Level1, true))).unwrap();
+
+        let mut bounded = BoundedConnectPacket::<16>::new("client-1").unwrap();
+        bounded.set_keep_alive(30);
+        bounded.set_clean_session(true);
+        bounded.set_user_name(Some("user")).unwrap();
+        bounded.set_password(Some(b"secret")).unwrap();
+        bounded.set_will(Some(BoundedLastWill::new("status", b"offline", QualityOfService::Level1, true).unwrap()));
+
+        let buf = encode(&bounded);
+        assert_eq!(encode(&owned), buf);
+        assert_eq!(bounded, BoundedConnectPacket::decode(&mut Cursor::new(&buf)).unwrap());
+
+        let v31 = BoundedConnectPacket::<16>::with_version("c", ProtocolVersion::V3_1).unwrap();
+        let buf = encode(&v31);
+        assert_eq!(encode(&ConnectPacket::with_version("c".to_owned(), ProtocolVersion::V3_1)), buf);
+        assert_eq!(v31, BoundedConnectPacket::decode(&mut Cursor::new(&buf)).unwrap());
+
+        assert!(!format!("{:?}", bounded).contains("secret"));
+    }
+
+    #[test]
+    fn test_bounded_publish_same_bytes_as_owned() {
+        let qos = QoSWithPacketIdentifier::Level2(PacketIdentifier::new(10).unwrap());
+        let mut owned = PublishPacket::new("a/b".to_owned(), qos, b"payload".to_vec());
+        owned.set_retain(true);
+        let mut bounded = BoundedPublishPacket::<8, 16>::new("a/b", qos, b"payload").unwrap();
+        bounded.set_retain(true);
+
+        let buf = encode(&bounded);
+        assert_eq!(encode(&owned), buf);
+
+        let decoded = BoundedPublishPacket::<8, 16>::decode(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(bounded, decoded);
+        assert_eq!(b"payload", decoded.payload_ref());
+        assert_eq!(qos, decoded.qos());
+
+        assert_eq!(Err(CapacityError), BoundedPublishPacket::<2, 16>::new("a/b", qos, b""));
+        assert_eq!(Err(CapacityError), bounded.set_payload(&[0; 17]));
+    }
+
+    #[test]
+    fn test_bounded_subscribe_same_bytes_as_owned() {
+        let pkid = PacketIdentifier::new(3).unwrap();
+        let owned = SubscribePacket::new(pkid, vec![(TopicFilter::new("a/+"), QualityOfService::Level1),
+                                                    (TopicFilter::new("b/#"), QualityOfService::Level2)]);
+        let mut bounded = BoundedSubscribePacket::<2, 8>::new(pkid);
+        bounded.add_subscription("a/+", QualityOfService::Level1).unwrap();
+        bounded.add_subscription("b/#", QualityOfService::Level2).unwrap();
+        assert_eq!(Err(CapacityError), bounded.add_subscription("c", QualityOfService::Level0));
+
+        let buf = encode(&bounded);
+        assert_eq!(encode(&owned), buf);
+        assert_eq!(bounded, BoundedSubscribePacket::decode(&mut Cursor::new(&buf)).unwrap());
+
+        match BoundedSubscribePacket::<2, 8>::new(pkid).encode(&mut Vec::new()) {
+            Err(PacketError::EmptySubscription) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_bounded_decode_capacity_exceeded() {
+        let mut buf = Vec::new();
+        VariablePacket::new(PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level0, vec![0; 32]))
+            .encode(&mut buf).unwrap();
+        VariablePacket::new(PingreqPacket::new()).encode(&mut buf).unwrap();
+
+        let mut reader = Cursor::new(&buf);
+        match BoundedPublishPacket::<8, 16>::decode(&mut reader) {
+            Err(PacketError::CapacityExceeded) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+        // The rest of the PUBLISH was skipped
+        assert_eq!(PingreqPacket::new(), PingreqPacket::decode(&mut reader).unwrap());
+
+        let owned = SubscribePacket::new(PacketIdentifier::new(1).unwrap(),
+                                         vec![(TopicFilter::new("a"), QualityOfService::Level0),
+                                              (TopicFilter::new("b"), QualityOfService::Level0)]);
+        match BoundedSubscribePacket::<1, 8>::decode(&mut Cursor::new(encode(&owned))) {
+            Err(PacketError::CapacityExceeded) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let owned = ConnectPacket::new("a-rather-long-client-id".to_owned());
+        match BoundedConnectPacket::<8>::decode(&mut Cursor::new(encode(&owned))) {
+            Err(PacketError::CapacityExceeded) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_bounded_decode_at_capacity() {
+        let owned = PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level0, vec![7; 16]);
+        let decoded = BoundedPublishPacket::<3, 16>::decode(&mut Cursor::new(encode(&owned))).unwrap();
+        assert_eq!("a/b", decoded.topic_name());
+        assert_eq!(&[7; 16][..], decoded.payload_ref());
+        match BoundedPublishPacket::<3, 15>::decode(&mut Cursor::new(encode(&owned))) {
+            Err(PacketError::CapacityExceeded) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+        match BoundedPublishPacket::<2, 16>::decode(&mut Cursor::new(encode(&owned))) {
+            Err(PacketError::CapacityExceeded) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let owned = SubscribePacket::new(PacketIdentifier::new(1).unwrap(),
+                                         vec![(TopicFilter::new("a/+"), QualityOfService::Level1),
+                                              (TopicFilter::new("b/#"), QualityOfService::Level2)]);
+        let decoded = BoundedSubscribePacket::<2, 3>::decode(&mut Cursor::new(encode(&owned))).unwrap();
+        assert_eq!(vec![("a/+", QualityOfService::Level1), ("b/#", QualityOfService::Level2)],
+                   decoded.subscriptions().collect::<Vec<_>>());
+
+        let owned = ConnectPacket::new("client-1".to_owned());
+        let decoded = BoundedConnectPacket::<8>::decode(&mut Cursor::new(encode(&owned))).unwrap();
+        assert_eq!("client-1", decoded.client_identifier());
+        assert_eq!(encode(&owned), encode(&decoded));
+    }
+
+    #[test]
+    fn test_bounded_decode_validates_like_owned() {
+        let buf = b"\x30\x05\x00\x03a/#";
+        match BoundedPublishPacket::<8, 8>::decode(&mut Cursor::new(&buf[..])) {
+            Err(PacketError::WildcardInPublishTopic { ref topic }) if topic == "a/#" => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let buf = b"\x82\x06\x00\x01\x00\x01#\x03";
+        match BoundedSubscribePacket::<2, 8>::decode(&mut Cursor::new(&buf[..])) {
+            Err(PacketError::InvalidQoS(3)) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+}
@@ -1,41 +1,233 @@
+use std::prelude::v1::*;
 use std::io::{Read, Write};
-
+use std::fmt;
 
 use control::{FixedHeader, PacketType, ControlType};
-use control::variable_header::{ConnackFlags, ConnectReturnCode};
+use control::variable_header::{ConnackFlags, ConnectReasonCode, ConnectReturnCode, Properties, PropertyType};
+use control::variable_header::{ProtocolVersion, VariableHeaderError};
 use packet::{Packet, PacketError};
-use {Encodable, Decodable};
+use {Encodable, Decodable, QualityOfService};
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct ConnackPacket {
     fixed_header: FixedHeader,
     flags: ConnackFlags,
-    ret_code: ConnectReturnCode,
+    ret_code: ConnackCode,
+    properties: Properties,
     payload: (),
 }
 
+/// The CONNACK layout is chosen by the kind of code, properties are only sent along with an
+/// MQTT 5 Reason Code
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum ConnackCode {
+    ReturnCode(ConnectReturnCode),
+    ReasonCode(ConnectReasonCode),
+}
+
 impl ConnackPacket {
     pub fn new(session_present: bool, ret_code: ConnectReturnCode) -> ConnackPacket {
         ConnackPacket {
             fixed_header: FixedHeader::new(PacketType::with_default(ControlType::ConnectAcknowledgement), 2),
             flags: ConnackFlags { session_present: session_present },
-            ret_code: ret_code,
+            ret_code: ConnackCode::ReturnCode(ret_code),
+            properties: Properties::new(),
             payload: (),
         }
     }
 
+    /// MQTT 5 CONNACK
+    pub fn with_reason_code(session_present: bool, reason_code: ConnectReasonCode) -> ConnackPacket {
+        let mut pk = ConnackPacket {
+            fixed_header: FixedHeader::new(PacketType::with_default(ControlType::ConnectAcknowledgement), 0),
+            flags: ConnackFlags { session_present: session_present },
+            ret_code: ConnackCode::ReasonCode(reason_code),
+            properties: Properties::new(),
+            payload: (),
+        };
+        pk.fixed_header.remaining_length = pk.encoded_variable_headers_length();
+        pk
+    }
+
+    pub fn accepted(session_present: bool) -> ConnackPacket {
+        ConnackPacket::new(session_present, ConnectReturnCode::ConnectionAccepted)
+    }
+
+    /// Session Present is always 0 for a rejected connection (MQTT-3.2.2-4)
+    pub fn rejected(code: ConnectReturnCode) -> ConnackPacket {
+        assert!(!code.is_accepted(), "Rejected CONNACK with ConnectionAccepted return code");
+        ConnackPacket::new(false, code)
+    }
+
+    pub fn session_present(&self) -> bool {
+        self.flags.session_present
+    }
+
     pub fn connack_flags(&self) -> ConnackFlags {
         self.flags
     }
 
+    /// For an MQTT 5 CONNACK this is the return code with the same meaning, or `Reserved` with
+    /// the Reason Code if there is none
     pub fn connect_return_code(&self) -> ConnectReturnCode {
-        self.ret_code
+        match self.ret_code {
+            ConnackCode::ReturnCode(code) => code,
+            ConnackCode::ReasonCode(code) =>
+                code.to_return_code().unwrap_or(ConnectReturnCode::Reserved(code.to_u8())),
+        }
+    }
+
+    /// For an MQTT 3.1.1 CONNACK this is the Reason Code that replaced its return code
+    pub fn connect_reason_code(&self) -> ConnectReasonCode {
+        match self.ret_code {
+            ConnackCode::ReturnCode(code) => ConnectReasonCode::from(code),
+            ConnackCode::ReasonCode(code) => code,
+        }
+    }
+
+    /// Whether the packet is laid out as an MQTT 5 CONNACK
+    pub fn is_mqtt5(&self) -> bool {
+        match self.ret_code {
+            ConnackCode::ReasonCode(..) => true,
+            ConnackCode::ReturnCode(..) => false,
+        }
+    }
+
+    fn is_accepted(&self) -> bool {
+        match self.ret_code {
+            ConnackCode::ReturnCode(code) => code.is_accepted(),
+            ConnackCode::ReasonCode(code) => code.is_success(),
+        }
+    }
+
+    fn code_byte(&self) -> u8 {
+        match self.ret_code {
+            ConnackCode::ReturnCode(code) => code.to_u8(),
+            ConnackCode::ReasonCode(code) => code.to_u8(),
+        }
+    }
+
+    /// Properties of the CONNACK, always empty when decoded at an earlier protocol level
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    /// Only encoded in MQTT 5
+    pub fn set_properties(&mut self, properties: Properties) {
+        self.properties = properties;
+        self.fixed_header.remaining_length = self.encoded_variable_headers_length();
+    }
+
+    pub fn assigned_client_identifier(&self) -> Option<&str> {
+        self.properties.assigned_client_identifier()
+    }
+
+    /// Keep Alive the client has to use instead of the one it sent
+    pub fn server_keep_alive(&self) -> Option<u16> {
+        self.properties.server_keep_alive()
+    }
+
+    pub fn session_expiry_interval(&self) -> Option<u32> {
+        self.properties.session_expiry_interval()
+    }
+
+    /// Number of QoS 1 and 2 PUBLISH the server processes concurrently, 65535 if absent
+    pub fn receive_maximum(&self) -> u16 {
+        self.properties.receive_maximum().unwrap_or(u16::max_value())
+    }
+
+    /// Highest QoS the server supports, QoS 2 if absent
+    pub fn maximum_qos(&self) -> QualityOfService {
+        match self.properties.maximum_qos() {
+            Some(0) => QualityOfService::Level0,
+            Some(1) => QualityOfService::Level1,
+            _ => QualityOfService::Level2,
+        }
+    }
+
+    /// True if absent
+    pub fn retain_available(&self) -> bool {
+        self.properties.retain_available().map_or(true, |value| value != 0)
+    }
+
+    /// Largest packet the server accepts, `None` if it has no limit
+    pub fn maximum_packet_size(&self) -> Option<u32> {
+        self.properties.maximum_packet_size()
+    }
+
+    /// Highest Topic Alias the server accepts, 0 if absent
+    pub fn topic_alias_maximum(&self) -> u16 {
+        self.properties.topic_alias_maximum().unwrap_or(0)
+    }
+
+    /// True if absent
+    pub fn wildcard_subscription_available(&self) -> bool {
+        self.properties.wildcard_subscription_available().map_or(true, |value| value != 0)
+    }
+
+    /// True if absent
+    pub fn subscription_identifier_available(&self) -> bool {
+        self.properties.subscription_identifier_available().map_or(true, |value| value != 0)
+    }
+
+    /// True if absent
+    pub fn shared_subscription_available(&self) -> bool {
+        self.properties.shared_subscription_available().map_or(true, |value| value != 0)
+    }
+
+    pub fn reason_string(&self) -> Option<&str> {
+        self.properties.reason_string()
+    }
+
+    /// Only encoded in MQTT 5
+    pub fn set_reason_string(&mut self, reason: Option<String>) {
+        self.properties.set_reason_string(reason);
+        self.fixed_header.remaining_length = self.encoded_variable_headers_length();
+    }
+}
+
+/// Properties a server may send in CONNACK
+const CONNACK_PROPERTIES: &'static [PropertyType] = &[
+    PropertyType::SessionExpiryInterval,
+    PropertyType::ReceiveMaximum,
+    PropertyType::MaximumQoS,
+    PropertyType::RetainAvailable,
+    PropertyType::MaximumPacketSize,
+    PropertyType::AssignedClientIdentifier,
+    PropertyType::TopicAliasMaximum,
+    PropertyType::ReasonString,
+    PropertyType::UserProperty,
+    PropertyType::WildcardSubscriptionAvailable,
+    PropertyType::SubscriptionIdentifierAvailable,
+    PropertyType::SharedSubscriptionAvailable,
+    PropertyType::ServerKeepAlive,
+    PropertyType::ResponseInformation,
+    PropertyType::ServerReference,
+    PropertyType::AuthenticationMethod,
+    PropertyType::AuthenticationData,
+];
+
+impl fmt::Display for ConnackPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_accepted() {
+            try!(write!(f, "CONNACK(accepted"));
+        } else {
+            try!(write!(f, "CONNACK(rejected={}", self.code_byte()));
+        }
+
+        if self.flags.session_present {
+            try!(write!(f, ", session_present"));
+        }
+
+        write!(f, ")")
     }
 }
 
 impl<'a> Packet<'a> for ConnackPacket {
     type Payload = ();
 
+    const CONTROL_TYPE: ControlType = ControlType::ConnectAcknowledgement;
+
     fn fixed_header(&self) -> &FixedHeader {
         &self.fixed_header
     }
@@ -46,22 +238,64 @@ impl<'a> Packet<'a> for ConnackPacket {
 
     fn encode_variable_headers<W: Write>(&self, writer: &mut W) -> Result<(), PacketError<'a, Self>> {
         try!(self.flags.encode(writer));
-        try!(self.ret_code.encode(writer));
+        match self.ret_code {
+            ConnackCode::ReturnCode(code) => try!(code.encode(writer)),
+            ConnackCode::ReasonCode(code) => {
+                try!(code.encode(writer));
+                try!(self.properties.encode(writer));
+            },
+        }
         Ok(())
     }
 
     fn encoded_variable_headers_length(&self) -> u32 {
-        self.flags.encoded_length() + self.ret_code.encoded_length()
+        match self.ret_code {
+            ConnackCode::ReturnCode(code) => self.flags.encoded_length() + code.encoded_length(),
+            ConnackCode::ReasonCode(code) =>
+                self.flags.encoded_length() + code.encoded_length() + self.properties.encoded_length(),
+        }
     }
 
     fn decode_packet<R: Read>(reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
         let flags: ConnackFlags = try!(Decodable::decode(reader));
         let code: ConnectReturnCode = try!(Decodable::decode(reader));
 
+        // MQTT-3.2.2-4: Session Present MUST be 0 if the return code is non-zero
+        if flags.session_present && !code.is_accepted() {
+            return Err(PacketError::VariableHeaderError(VariableHeaderError::SessionPresentWithRejection));
+        }
+
         Ok(ConnackPacket {
             fixed_header: fixed_header,
             flags: flags,
-            ret_code: code,
+            ret_code: ConnackCode::ReturnCode(code),
+            properties: Properties::new(),
+            payload: (),
+        })
+    }
+
+    fn decode_packet_with_version<R: Read>(reader: &mut R, fixed_header: FixedHeader, version: ProtocolVersion)
+            -> Result<Self, PacketError<'a, Self>> {
+        if version != ProtocolVersion::V5 {
+            return ConnackPacket::decode_packet(reader, fixed_header);
+        }
+
+        let flags: ConnackFlags = try!(Decodable::decode(reader));
+        let code: ConnectReasonCode = try!(Decodable::decode(reader));
+
+        // MQTT-3.2.2-6 in MQTT 5
+        if flags.session_present && !code.is_success() {
+            return Err(PacketError::VariableHeaderError(VariableHeaderError::SessionPresentWithRejection));
+        }
+
+        let properties: Properties = try!(Decodable::decode(reader));
+        try!(properties.check_allowed(CONNACK_PROPERTIES, "CONNACK").map_err(PacketError::MalformedPacket));
+
+        Ok(ConnackPacket {
+            fixed_header: fixed_header,
+            flags: flags,
+            ret_code: ConnackCode::ReasonCode(code),
+            properties: properties,
             payload: (),
         })
     }
@@ -73,7 +307,8 @@ mod test {
 
     use std::io::Cursor;
 
-    use control::variable_header::ConnectReturnCode;
+    use control::variable_header::{ConnectReturnCode, Property};
+    use packet::{self, DecodeOptions, PacketError, VariablePacket, VariablePacketError};
     use {Encodable, Decodable};
 
     #[test]
@@ -88,4 +323,166 @@ mod test {
 
         assert_eq!(packet, decoded);
     }
+
+    #[test]
+    pub fn test_connack_packet_constructors() {
+        let packet = ConnackPacket::accepted(true);
+        assert!(packet.session_present());
+        assert!(packet.connect_return_code().is_accepted());
+
+        let packet = ConnackPacket::rejected(ConnectReturnCode::NotAuthorized);
+        assert!(!packet.session_present());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x20\x02\x00\x05"[..], &buf[..]);
+    }
+
+    #[test]
+    pub fn test_connack_packet_reserved_flags() {
+        let encoded_data = b"\x20\x02\x02\x00";
+
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        match ConnackPacket::decode(&mut decode_buf) {
+            Err(PacketError::VariableHeaderError(VariableHeaderError::InvalidReservedFlag)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    pub fn test_connack_packet_reserved_return_code() {
+        let encoded_data = b"\x20\x02\x00\x06";
+
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        let decoded = ConnackPacket::decode(&mut decode_buf).unwrap();
+
+        assert_eq!(ConnectReturnCode::Reserved(6), decoded.connect_return_code());
+        assert!(!decoded.connect_return_code().is_accepted());
+        assert!(ConnectReturnCode::from(0).is_accepted());
+        assert_eq!(0x05, ConnectReturnCode::from(5).to_u8());
+    }
+
+    #[test]
+    pub fn test_connack_packet_session_present_with_rejection() {
+        let encoded_data = b"\x20\x02\x01\x05";
+
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        match ConnackPacket::decode(&mut decode_buf) {
+            Err(PacketError::VariableHeaderError(ref err @ VariableHeaderError::SessionPresentWithRejection)) =>
+                assert_eq!(Some("MQTT-3.2.2-4"), err.spec_rule()),
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let encoded_data = b"\x20\x02\x01\x00";
+        let decoded = ConnackPacket::decode(&mut Cursor::new(&encoded_data[..])).unwrap();
+        assert!(decoded.session_present());
+    }
+
+    #[test]
+    pub fn test_connack_packet_mqtt_5_synthetic_defaults() {
+        // Synthetic, built by hand after the spec with the Receive Maximum and Topic Alias
+        // Maximum of mosquitto's default settings. It is not a capture of a mosquitto CONNACK
+        let encoded_data = b"\x20\x09\x00\x00\x06\x22\x00\x0a\x21\x00\x14";
+
+        let decoded: ConnackPacket =
+            packet::decode_with_version(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5).unwrap();
+        assert!(decoded.is_mqtt5());
+        assert!(!decoded.session_present());
+        assert_eq!(ConnectReasonCode::Success, decoded.connect_reason_code());
+        assert!(decoded.connect_return_code().is_accepted());
+        assert_eq!(10, decoded.topic_alias_maximum());
+        assert_eq!(20, decoded.receive_maximum());
+        assert_eq!(QualityOfService::Level2, decoded.maximum_qos());
+        assert!(decoded.retain_available());
+        assert_eq!(None, decoded.maximum_packet_size());
+
+        let mut buf = Vec::new();
+        decoded.encode(&mut buf).unwrap();
+        assert_eq!(&encoded_data[..], &buf[..]);
+
+        // Without the version it is an MQTT 3.1.1 CONNACK with trailing bytes
+        match ConnackPacket::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(PacketError::MalformedPacket(..)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    pub fn test_connack_packet_mqtt_5_properties() {
+        let mut packet = ConnackPacket::with_reason_code(false, ConnectReasonCode::Success);
+        let mut properties = Properties::new();
+        properties.insert(Property::AssignedClientIdentifier("auto-3F2A".to_owned()));
+        properties.insert(Property::ServerKeepAlive(30));
+        properties.insert(Property::MaximumQoS(1));
+        properties.insert(Property::RetainAvailable(0));
+        properties.insert(Property::MaximumPacketSize(4096));
+        properties.insert(Property::WildcardSubscriptionAvailable(0));
+        properties.insert(Property::SubscriptionIdentifierAvailable(0));
+        properties.insert(Property::SharedSubscriptionAvailable(0));
+        packet.set_properties(properties);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        let decoded: ConnackPacket = packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet, decoded);
+        assert_eq!(Some("auto-3F2A"), decoded.assigned_client_identifier());
+        assert_eq!(Some(30), decoded.server_keep_alive());
+        assert_eq!(QualityOfService::Level1, decoded.maximum_qos());
+        assert!(!decoded.retain_available());
+        assert_eq!(Some(4096), decoded.maximum_packet_size());
+        assert!(!decoded.wildcard_subscription_available());
+        assert!(!decoded.subscription_identifier_available());
+        assert!(!decoded.shared_subscription_available());
+
+        let packet = ConnackPacket::with_reason_code(false, ConnectReasonCode::Banned);
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x20\x03\x00\x8a\x00"[..], &buf[..]);
+        assert_eq!(ConnectReturnCode::Reserved(0x8a), packet.connect_return_code());
+        assert_eq!("CONNACK(rejected=138)", packet.to_string());
+
+        // The same packet at MQTT 3.1.1 is unchanged
+        let mut packet = ConnackPacket::rejected(ConnectReturnCode::NotAuthorized);
+        assert_eq!(ConnectReasonCode::NotAuthorized, packet.connect_reason_code());
+        assert!(packet.properties().is_empty());
+
+        // and its properties are not encoded
+        packet.set_reason_string(Some("banned".to_owned()));
+        let mut properties = packet.properties().clone();
+        properties.insert(Property::ServerKeepAlive(30));
+        packet.set_properties(properties);
+        assert_eq!(Some("banned"), packet.reason_string());
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x20\x02\x00\x05"[..], &buf[..]);
+    }
+
+    #[test]
+    pub fn test_connack_packet_mqtt_5_invalid() {
+        // Will Delay Interval only belongs in the will of a CONNECT
+        let encoded_data = b"\x20\x08\x00\x00\x05\x18\x00\x00\x00\x05";
+        match packet::decode_with_version::<ConnackPacket, _>(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5) {
+            Err(PacketError::MalformedPacket(ref err)) => assert!(err.contains("WillDelayInterval (0x18)"), "{}", err),
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let encoded_data = b"\x20\x03\x01\x87\x00";
+        match packet::decode_with_version::<ConnackPacket, _>(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5) {
+            Err(PacketError::VariableHeaderError(VariableHeaderError::SessionPresentWithRejection)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        // Unknown Reason Codes are kept unless the decode options are strict
+        let encoded_data = b"\x20\x03\x00\xa0\x00";
+        let options = DecodeOptions::new().protocol_version(ProtocolVersion::V5);
+        match VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &options) {
+            Err(VariablePacketError::UnknownReasonCode(0xa0)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let options = options.strict_reason_codes(false);
+        let decoded = VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &options).unwrap();
+        assert_eq!(VariablePacket::new(ConnackPacket::with_reason_code(false, ConnectReasonCode::Unspecified(0xa0))),
+                   decoded);
+    }
 }
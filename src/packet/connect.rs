@@ -1,22 +1,52 @@
+use std::prelude::v1::*;
 use std::io::{self, Read, Write};
 use std::error::Error;
 use std::fmt;
+use std::convert::{From, TryFrom};
+use std::time::Duration;
+
+use byteorder::{self, BigEndian, ReadBytesExt, WriteBytesExt};
 
 
 use control::{FixedHeader, PacketType, ControlType};
-use control::variable_header::{ProtocolName, ProtocolLevel, ConnectFlags, KeepAlive};
-use control::variable_header::protocol_level::SPEC_3_1_1;
+use control::variable_header::{ProtocolName, ProtocolLevel, ProtocolVersion, ConnectFlags, KeepAlive, TopicName};
+use control::variable_header::{ConnectReturnCode, Properties, Property, PropertyType, VariableHeaderError};
+use control::variable_header::protocol_level::{BRIDGE_FLAG, SPEC_3_1_1, SPEC_5};
 use packet::{Packet, PacketError};
-use {Encodable, Decodable};
-use encodable::StringEncodeError;
-
-#[derive(Debug, Eq, PartialEq)]
+use {Encodable, Decodable, QualityOfService};
+use encodable::{StringEncodeError, VarBytes};
+
+/// CONNECT packet
+///
+/// The recommended way to construct one is through `ConnectPacket::builder`, which keeps
+/// the connect flags consistent with the payload:
+///
+/// ```rust
+/// use mqtt::QualityOfService;
+/// use mqtt::packet::ConnectPacket;
+///
+/// let packet = ConnectPacket::builder("client-1")
+///                 .keep_alive(30)
+///                 .clean_session(true)
+///                 .user_name("user")
+///                 .password(b"secret")
+///                 .will("clients/client-1/status", b"offline", QualityOfService::Level1, true)
+///                 .build()
+///                 .unwrap();
+/// assert_eq!(packet.user_name(), Some("user"));
+/// ```
+///
+/// The `Debug` output redacts the password and summarizes the will message by its length,
+/// use `debug_unredacted` to print them verbatim.
+#[derive(Eq, PartialEq, Clone)]
 pub struct ConnectPacket {
     fixed_header: FixedHeader,
 
+    protocol_name: ProtocolName,
     protocol_level: ProtocolLevel,
     flags: ConnectFlags,
     keep_alive: KeepAlive,
+    properties: Properties,
 
     payload: ConnectPacketPayload,
 }
@@ -26,20 +56,34 @@ impl ConnectPacket {
         ConnectPacket::with_level(client_identifier, SPEC_3_1_1)
     }
 
+    pub fn with_version(client_identifier: String, version: ProtocolVersion) -> ConnectPacket {
+        let mut pk = ConnectPacket::with_level(client_identifier, version.level());
+        pk.protocol_name = ProtocolName(version.protocol_name().to_owned());
+        pk.fixed_header.remaining_length = pk.calculate_remaining_length();
+        pk
+    }
+
     pub fn with_level(client_identifier: String, level: u8) -> ConnectPacket {
         let mut pk = ConnectPacket {
             fixed_header: FixedHeader::new(PacketType::with_default(ControlType::Connect), 0),
+            protocol_name: ProtocolName("MQTT".to_owned()),
             protocol_level: ProtocolLevel(level),
             flags: ConnectFlags::empty(),
             keep_alive: KeepAlive(0),
+            properties: Properties::new(),
             payload: ConnectPacketPayload::new(client_identifier),
         };
+        pk.payload.mqtt5 = has_properties(level);
 
         pk.fixed_header.remaining_length = pk.calculate_remaining_length();
 
         pk
     }
 
+    pub fn builder(client_identifier: &str) -> ConnectPacketBuilder {
+        ConnectPacketBuilder::new(client_identifier)
+    }
+
     #[inline]
     fn calculate_remaining_length(&self) -> u32 {
         self.encoded_variable_headers_length() +
@@ -52,43 +96,38 @@ impl ConnectPacket {
         self.fixed_header.remaining_length = self.calculate_remaining_length();
     }
 
-    pub fn set_will(&mut self, topic_message: Option<(String, String)>) {
-        self.flags.will_flag = topic_message.is_some();
-
-        match topic_message {
-            Some((topic, msg)) => {
-                self.payload.will_topic = Some(topic);
-                self.payload.will_message = Some(msg);
-            },
-            None => {
-                self.payload.will_topic = None;
-                self.payload.will_message = None;
+    /// Sets or clears the Will Message. The will flag, will QoS and will retain bits of the
+    /// connect flags are derived from it when the packet is encoded.
+    ///
+    /// A will with an empty topic is rejected like `ConnectPacketBuilder::build` does, the
+    /// packet is left unchanged.
+    pub fn set_will(&mut self, will: Option<LastWill>) -> Result<(), ConnectBuildError> {
+        if let Some(ref will) = will {
+            if will.topic.0.is_empty() {
+                return Err(ConnectBuildError::EmptyWillTopic);
             }
         }
-
+        self.payload.will = will;
         self.fixed_header.remaining_length = self.calculate_remaining_length();
+        Ok(())
     }
 
-    pub fn set_password(&mut self, password: Option<String>) {
+    pub fn set_password(&mut self, password: Option<Vec<u8>>) {
         self.flags.password = password.is_some();
-        self.payload.password = password;
+        self.payload.password = password.map(VarBytes);
         self.fixed_header.remaining_length = self.calculate_remaining_length();
     }
 
+    pub fn set_password_str(&mut self, password: &str) {
+        self.set_password(Some(password.as_bytes().to_vec()));
+    }
+
     pub fn set_client_identifier(&mut self, id: String) {
         self.payload.client_identifier = id;
         self.fixed_header.remaining_length = self.calculate_remaining_length();
     }
 
-    pub fn set_will_retain(&mut self, will_retain: bool) {
-        self.flags.will_retain = will_retain;
-    }
-
-    pub fn set_will_qos(&mut self, will_qos: u8) {
-        assert!(will_qos <= 2);
-        self.flags.will_qos = will_qos;
-    }
-
+    /// Only touches the Clean Session bit, the remaining length is unaffected
     pub fn set_clean_session(&mut self, clean_session: bool) {
         self.flags.clean_session = clean_session;
     }
@@ -97,22 +136,42 @@ impl ConnectPacket {
         self.payload.user_name.as_ref().map(|x| &x[..])
     }
 
-    pub fn password(&self) -> Option<&str> {
-        self.payload.password.as_ref().map(|x| &x[..])
+    pub fn password(&self) -> Option<&[u8]> {
+        self.payload.password.as_ref().map(|x| &x.0[..])
     }
 
-    pub fn will(&self) -> Option<(&str, &str)> {
-        self.payload.will_topic.as_ref().map(|x| &x[..])
-            .and_then(|topic| self.payload.will_message.as_ref().map(|x| &x[..])
-                             .map(|msg| (topic, msg)))
+    pub fn will(&self) -> Option<&LastWill> {
+        self.payload.will.as_ref()
     }
 
     pub fn will_retain(&self) -> bool {
-        self.flags.will_retain
+        self.payload.will.as_ref().map(|w| w.retain).unwrap_or(false)
+    }
+
+    pub fn will_qos(&self) -> QualityOfService {
+        self.payload.will.as_ref().map(|w| w.qos).unwrap_or(QualityOfService::Level0)
+    }
+
+    /// Returns `false` without changing anything if no will is set
+    pub fn set_will_retain(&mut self, will_retain: bool) -> bool {
+        match self.payload.will {
+            Some(ref mut will) => {
+                will.retain = will_retain;
+                true
+            },
+            None => false,
+        }
     }
 
-    pub fn will_qos(&self) -> u8 {
-        self.flags.will_qos
+    /// Returns `false` without changing anything if no will is set
+    pub fn set_will_qos(&mut self, will_qos: QualityOfService) -> bool {
+        match self.payload.will {
+            Some(ref mut will) => {
+                will.qos = will_qos;
+                true
+            },
+            None => false,
+        }
     }
 
     pub fn client_identifier(&self) -> &str {
@@ -122,11 +181,323 @@ impl ConnectPacket {
     pub fn clean_session(&self) -> bool {
         self.flags.clean_session
     }
+
+    /// Checks the Client Identifier the way a server has to before accepting the connection
+    pub fn validate_client_id_rules(&self) -> Result<(), ClientIdError> {
+        // MQTT-3.1.3-8: A zero-byte ClientId requires CleanSession set to 1
+        if self.payload.client_identifier.is_empty() && !self.flags.clean_session {
+            return Err(ClientIdError::EmptyWithoutCleanSession);
+        }
+
+        // MQTT-1.5.3-2: A UTF-8 encoded string MUST NOT include the null character
+        if self.payload.client_identifier.contains('\0') {
+            return Err(ClientIdError::NullCharacter);
+        }
+
+        Ok(())
+    }
+
+    /// Checks the Client Identifier against `policy`, on top of `validate_client_id_rules`
+    pub fn validate_client_id(&self, policy: ClientIdPolicy) -> Result<(), ClientIdError> {
+        try!(self.validate_client_id_rules());
+
+        match policy {
+            ClientIdPolicy::Relaxed => Ok(()),
+            ClientIdPolicy::Strict => {
+                // MQTT-3.1.3-5: 1 to 23 UTF-8 encoded bytes of "0-9a-zA-Z" must be allowed
+                let id = &self.payload.client_identifier[..];
+                if id.is_empty() {
+                    return Err(ClientIdError::Empty);
+                }
+
+                if id.len() > 23 {
+                    return Err(ClientIdError::TooLong(id.len()));
+                }
+
+                match id.char_indices().find(|&(_, c)| !c.is_ascii_alphanumeric()) {
+                    Some((idx, c)) => Err(ClientIdError::InvalidCharacter(c, idx)),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    pub fn protocol_name(&self) -> &str {
+        &self.protocol_name.0[..]
+    }
+
+    pub fn protocol_level(&self) -> u8 {
+        self.protocol_level.0
+    }
+
+    /// Protocol version of the packet, `None` if the protocol name and level pair is not
+    /// supported. Servers should respond with `UnacceptableProtocolVersion` (0x01) in that case.
+    ///
+    /// The bridge bit of the protocol level is ignored, a bridge connecting with 0x84 is
+    /// `V3_1_1`. Check `is_bridge` to tell them apart.
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        ProtocolVersion::from_name_and_level(&self.protocol_name.0[..], self.protocol_level.0 & !BRIDGE_FLAG)
+    }
+
+    /// Whether bit 7 of the protocol level is set, as mosquitto bridges do
+    pub fn is_bridge(&self) -> bool {
+        self.protocol_level.0 & BRIDGE_FLAG != 0
+    }
+
+    /// Sets or clears bit 7 of the protocol level, for connecting to a broker as a bridge
+    pub fn set_bridge_mode(&mut self, bridge: bool) {
+        if bridge {
+            self.protocol_level.0 |= BRIDGE_FLAG;
+        } else {
+            self.protocol_level.0 &= !BRIDGE_FLAG;
+        }
+    }
+
+    /// Properties of an MQTT 5 CONNECT, they are not encoded for earlier protocol levels
+    pub fn connect_properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    pub fn set_connect_properties(&mut self, properties: Properties) {
+        self.properties = properties;
+        self.fixed_header.remaining_length = self.calculate_remaining_length();
+    }
+
+    pub fn session_expiry_interval(&self) -> Option<u32> {
+        self.properties.session_expiry_interval()
+    }
+
+    pub fn set_session_expiry_interval(&mut self, interval: Option<u32>) {
+        self.set_property(PropertyType::SessionExpiryInterval, interval.map(Property::SessionExpiryInterval));
+    }
+
+    pub fn receive_maximum(&self) -> Option<u16> {
+        self.properties.receive_maximum()
+    }
+
+    pub fn set_receive_maximum(&mut self, receive_maximum: Option<u16>) -> Result<(), ConnectPropertyError> {
+        if receive_maximum == Some(0) {
+            return Err(ConnectPropertyError::ZeroReceiveMaximum);
+        }
+        self.set_property(PropertyType::ReceiveMaximum, receive_maximum.map(Property::ReceiveMaximum));
+        Ok(())
+    }
+
+    pub fn maximum_packet_size(&self) -> Option<u32> {
+        self.properties.maximum_packet_size()
+    }
+
+    pub fn set_maximum_packet_size(&mut self, size: Option<u32>) -> Result<(), ConnectPropertyError> {
+        if size == Some(0) {
+            return Err(ConnectPropertyError::ZeroMaximumPacketSize);
+        }
+        self.set_property(PropertyType::MaximumPacketSize, size.map(Property::MaximumPacketSize));
+        Ok(())
+    }
+
+    pub fn topic_alias_maximum(&self) -> Option<u16> {
+        self.properties.topic_alias_maximum()
+    }
+
+    pub fn set_topic_alias_maximum(&mut self, maximum: Option<u16>) {
+        self.set_property(PropertyType::TopicAliasMaximum, maximum.map(Property::TopicAliasMaximum));
+    }
+
+    pub fn request_response_information(&self) -> Option<bool> {
+        self.properties.request_response_information().map(|value| value != 0)
+    }
+
+    pub fn set_request_response_information(&mut self, request: Option<bool>) {
+        self.set_property(PropertyType::RequestResponseInformation,
+                          request.map(|request| Property::RequestResponseInformation(request as u8)));
+    }
+
+    pub fn request_problem_information(&self) -> Option<bool> {
+        self.properties.request_problem_information().map(|value| value != 0)
+    }
+
+    pub fn set_request_problem_information(&mut self, request: Option<bool>) {
+        self.set_property(PropertyType::RequestProblemInformation,
+                          request.map(|request| Property::RequestProblemInformation(request as u8)));
+    }
+
+    pub fn authentication_method(&self) -> Option<&str> {
+        self.properties.authentication_method()
+    }
+
+    pub fn set_authentication_method(&mut self, method: Option<String>) {
+        self.set_property(PropertyType::AuthenticationMethod, method.map(Property::AuthenticationMethod));
+    }
+
+    pub fn authentication_data(&self) -> Option<&[u8]> {
+        self.properties.authentication_data()
+    }
+
+    pub fn set_authentication_data(&mut self, data: Option<Vec<u8>>) {
+        self.set_property(PropertyType::AuthenticationData, data.map(Property::AuthenticationData));
+    }
+
+    fn set_property(&mut self, property_type: PropertyType, property: Option<Property>) {
+        match property {
+            Some(property) => self.properties.insert(property),
+            None => self.properties.remove(property_type),
+        }
+        self.fixed_header.remaining_length = self.calculate_remaining_length();
+    }
+
+    /// Keep Alive in seconds, 0 means the keep alive mechanism is turned off
+    pub fn keep_alive(&self) -> u16 {
+        self.keep_alive.0
+    }
+
+    pub fn set_keep_alive(&mut self, keep_alive: u16) {
+        self.keep_alive = KeepAlive(keep_alive);
+    }
+
+    /// Sets Keep Alive from a `Duration`, sub-second values are rounded up to the next second
+    pub fn set_keep_alive_duration(&mut self, keep_alive: Duration) -> Result<(), KeepAliveError> {
+        let mut secs = keep_alive.as_secs();
+        if keep_alive.subsec_nanos() > 0 {
+            secs += 1;
+        }
+
+        if secs > u16::max_value() as u64 {
+            return Err(KeepAliveError::OutOfRange(keep_alive));
+        }
+
+        self.keep_alive = KeepAlive(secs as u16);
+        Ok(())
+    }
+
+    fn connect_flags(&self) -> ConnectFlags {
+        let mut flags = self.flags;
+
+        match self.payload.will {
+            Some(ref will) => {
+                flags.will_flag = true;
+                flags.will_qos = will.qos as u8;
+                flags.will_retain = will.retain;
+            },
+            None => {
+                flags.will_flag = false;
+                flags.will_qos = 0;
+                flags.will_retain = false;
+            }
+        }
+
+        flags
+    }
+}
+
+impl ConnectPacket {
+    /// `Debug` representation including the password and will message, do not log it
+    pub fn debug_unredacted<'a>(&'a self) -> UnredactedConnectPacket<'a> {
+        UnredactedConnectPacket(self)
+    }
+
+    fn fmt_debug(&self, f: &mut fmt::Formatter, redacted: bool) -> fmt::Result {
+        f.debug_struct("ConnectPacket")
+            .field("fixed_header", &self.fixed_header)
+            .field("protocol_name", &self.protocol_name)
+            .field("protocol_level", &self.protocol_level)
+            .field("flags", &self.flags)
+            .field("keep_alive", &self.keep_alive)
+            .field("properties", &self.properties)
+            .field("payload", &PayloadDebug(&self.payload, redacted))
+            .finish()
+    }
+}
+
+impl fmt::Debug for ConnectPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_debug(f, true)
+    }
+}
+
+/// Returned by `ConnectPacket::debug_unredacted`
+pub struct UnredactedConnectPacket<'a>(&'a ConnectPacket);
+
+impl<'a> fmt::Debug for UnredactedConnectPacket<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt_debug(f, false)
+    }
+}
+
+struct PayloadDebug<'a>(&'a ConnectPacketPayload, bool);
+
+impl<'a> fmt::Debug for PayloadDebug<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let &PayloadDebug(payload, redacted) = self;
+
+        f.debug_struct("ConnectPacketPayload")
+            .field("client_identifier", &payload.client_identifier)
+            .field("will", &payload.will.as_ref().map(|w| WillDebug(w, redacted)))
+            .field("user_name", &payload.user_name)
+            .field("password", &payload.password.as_ref().map(|p| BytesDebug(&p.0[..], redacted)))
+            .finish()
+    }
+}
+
+struct WillDebug<'a>(&'a LastWill, bool);
+
+impl<'a> fmt::Debug for WillDebug<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let &WillDebug(will, redacted) = self;
+
+        f.debug_struct("LastWill")
+            .field("topic", &will.topic)
+            .field("message", &BytesDebug(&will.message[..], redacted))
+            .field("qos", &will.qos)
+            .field("retain", &will.retain)
+            .field("properties", &will.properties)
+            .finish()
+    }
+}
+
+struct BytesDebug<'a>(&'a [u8], bool);
+
+impl<'a> fmt::Debug for BytesDebug<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &BytesDebug(bytes, true) => write!(f, "<redacted, {} bytes>", bytes.len()),
+            &BytesDebug(bytes, false) => bytes.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for ConnectPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "CONNECT(client_id={:?}, keep_alive={}", self.payload.client_identifier, self.keep_alive.0));
+
+        if self.flags.clean_session {
+            try!(write!(f, ", clean_session"));
+        }
+
+        if let Some(ref user_name) = self.payload.user_name {
+            try!(write!(f, ", user_name={:?}", user_name));
+        }
+
+        if self.payload.password.is_some() {
+            try!(write!(f, ", password"));
+        }
+
+        if let Some(ref will) = self.payload.will {
+            try!(write!(f, ", will(topic={:?}, qos={}, payload={}B", will.topic.0, will.qos, will.message.len()));
+            if will.retain {
+                try!(write!(f, ", retain"));
+            }
+            try!(write!(f, ")"));
+        }
+
+        write!(f, ")")
+    }
 }
 
 impl<'a> Packet<'a> for ConnectPacket {
     type Payload = ConnectPacketPayload;
 
+    const CONTROL_TYPE: ControlType = ControlType::Connect;
+
     fn fixed_header(&self) -> &FixedHeader {
         &self.fixed_header
     }
@@ -136,160 +507,624 @@ impl<'a> Packet<'a> for ConnectPacket {
     }
 
     fn encode_variable_headers<W: Write>(&self, writer: &mut W) -> Result<(), PacketError<'a, Self>> {
-        try!("MQTT".encode(writer));
+        if self.flags.password && !self.flags.user_name && password_requires_user_name(self.protocol_level.0) {
+            return Err(PacketError::VariableHeaderError(VariableHeaderError::PasswordWithoutUserName));
+        }
+
+        try!(self.protocol_name.encode(writer));
         try!(self.protocol_level.encode(writer));
-        try!(self.flags.encode(writer));
+        try!(self.connect_flags().encode(writer));
         try!(self.keep_alive.encode(writer));
+        if has_properties(self.protocol_level.0) {
+            try!(self.properties.encode(writer));
+        }
 
         Ok(())
     }
 
     fn encoded_variable_headers_length(&self) -> u32 {
-        "MQTT".encoded_length()
+        let properties_length = if has_properties(self.protocol_level.0) {
+            self.properties.encoded_length()
+        } else {
+            0
+        };
+
+        self.protocol_name.encoded_length()
             + self.protocol_level.encoded_length()
             + self.flags.encoded_length()
             + self.keep_alive.encoded_length()
+            + properties_length
     }
 
     fn decode_packet<R: Read>(reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
         let protoname: ProtocolName = try!(Decodable::decode(reader));
-        if protoname.0 != "MQTT" {
-            return Err(PacketError::MalformedPacket("Expecting protocol name \"MQTT\"".to_owned()));
+        if protoname.0 != "MQTT" && protoname.0 != "MQIsdp" {
+            return Err(PacketError::MalformedPacket("Expecting protocol name \"MQTT\" or \"MQIsdp\"".to_owned()));
         }
 
         let protocol_level: ProtocolLevel = try!(Decodable::decode(reader));
-        let flags: ConnectFlags = try!(Decodable::decode(reader));
+        let mut flags: ConnectFlags = try!(Decodable::decode(reader));
+        if flags.password && !flags.user_name && password_requires_user_name(protocol_level.0) {
+            return Err(PacketError::VariableHeaderError(VariableHeaderError::PasswordWithoutUserName));
+        }
         let keep_alive: KeepAlive = try!(Decodable::decode(reader));
-        let payload: ConnectPacketPayload =
-            try!(Decodable::decode_with(reader, Some(&flags))
-                    .map_err(PacketError::PayloadError));
+        let mqtt5 = has_properties(protocol_level.0);
+        let properties = if mqtt5 {
+            let properties: Properties = try!(Decodable::decode(reader));
+            try!(validate_connect_properties(&properties).map_err(PacketError::MalformedPacket));
+            properties
+        } else {
+            Properties::new()
+        };
+        let payload = try!(ConnectPacketPayload::decode_payload(reader, Some(&flags), mqtt5)
+                               .map_err(PacketError::PayloadError));
+
+        // The will is now carried by the payload
+        flags.will_flag = false;
+        flags.will_qos = 0;
+        flags.will_retain = false;
 
         Ok(ConnectPacket {
             fixed_header: fixed_header,
+            protocol_name: protoname,
             protocol_level: protocol_level,
             flags: flags,
             keep_alive: keep_alive,
+            properties: properties,
             payload: payload,
         })
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
-pub struct ConnectPacketPayload {
+/// Properties a client may send in CONNECT
+const CONNECT_PROPERTIES: &'static [PropertyType] = &[
+    PropertyType::SessionExpiryInterval,
+    PropertyType::ReceiveMaximum,
+    PropertyType::MaximumPacketSize,
+    PropertyType::TopicAliasMaximum,
+    PropertyType::RequestResponseInformation,
+    PropertyType::RequestProblemInformation,
+    PropertyType::UserProperty,
+    PropertyType::AuthenticationMethod,
+    PropertyType::AuthenticationData,
+];
+
+fn validate_connect_properties(properties: &Properties) -> Result<(), String> {
+    try!(properties.check_allowed(CONNECT_PROPERTIES, "CONNECT"));
+
+    for property in properties.iter() {
+        match property {
+            &Property::ReceiveMaximum(0) => return Err("Receive Maximum must not be 0".to_owned()),
+            &Property::MaximumPacketSize(0) => return Err("Maximum Packet Size must not be 0".to_owned()),
+            &Property::RequestResponseInformation(value) | &Property::RequestProblemInformation(value)
+                if value > 1 => return Err(format!("Property {:?} must be 0 or 1, got {}", property.property_type(), value)),
+            _ => {},
+        }
+    }
+
+    Ok(())
+}
+
+/// MQTT 5 adds properties to the variable header and to the will
+fn has_properties(protocol_level: u8) -> bool {
+    protocol_level & !BRIDGE_FLAG == SPEC_5
+}
+
+/// MQTT-3.1.2-22: If the User Name Flag is set to 0, the Password Flag MUST be set to 0.
+/// MQTT 5 allows a password without a user name.
+fn password_requires_user_name(protocol_level: u8) -> bool {
+    protocol_level & !BRIDGE_FLAG <= SPEC_3_1_1
+}
+
+/// Builder for `ConnectPacket`
+///
+/// All fields are collected first; the connect flags and the remaining length are computed
+/// once in `build`, after the cross-field rules have been checked.
+#[derive(Clone)]
+pub struct ConnectPacketBuilder {
     client_identifier: String,
-    will_topic: Option<String>,
-    will_message: Option<String>,
+    protocol_version: ProtocolVersion,
+    keep_alive: u16,
+    clean_session: bool,
     user_name: Option<String>,
-    password: Option<String>,
+    password: Option<Vec<u8>>,
+    will: Option<LastWill>,
 }
 
-impl ConnectPacketPayload {
-    pub fn new(client_identifier: String) -> ConnectPacketPayload {
-        ConnectPacketPayload {
-            client_identifier: client_identifier,
-            will_topic: None,
-            will_message: None,
+impl ConnectPacketBuilder {
+    pub fn new(client_identifier: &str) -> ConnectPacketBuilder {
+        ConnectPacketBuilder {
+            client_identifier: client_identifier.to_owned(),
+            protocol_version: ProtocolVersion::V3_1_1,
+            keep_alive: 0,
+            clean_session: false,
             user_name: None,
             password: None,
+            will: None,
         }
     }
-}
-
-impl<'a> Encodable<'a> for ConnectPacketPayload {
-    type Err = ConnectPacketPayloadError;
-
-    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), ConnectPacketPayloadError> {
-        try!(self.client_identifier.encode(writer));
 
-        if let Some(ref will_topic) = self.will_topic {
-            try!(will_topic.encode(writer));
-        }
+    pub fn protocol_version(mut self, version: ProtocolVersion) -> ConnectPacketBuilder {
+        self.protocol_version = version;
+        self
+    }
 
-        if let Some(ref will_message) = self.will_message {
-            try!(will_message.encode(writer));
-        }
+    pub fn keep_alive(mut self, keep_alive: u16) -> ConnectPacketBuilder {
+        self.keep_alive = keep_alive;
+        self
+    }
 
-        if let Some(ref user_name) = self.user_name {
-            try!(user_name.encode(writer));
-        }
+    pub fn clean_session(mut self, clean_session: bool) -> ConnectPacketBuilder {
+        self.clean_session = clean_session;
+        self
+    }
 
-        if let Some(ref password) = self.password {
-            try!(password.encode(writer));
-        }
+    pub fn user_name(mut self, user_name: &str) -> ConnectPacketBuilder {
+        self.user_name = Some(user_name.to_owned());
+        self
+    }
 
-        Ok(())
+    pub fn password(mut self, password: &[u8]) -> ConnectPacketBuilder {
+        self.password = Some(password.to_vec());
+        self
     }
 
-    fn encoded_length(&self) -> u32 {
-        self.client_identifier.encoded_length()
-            + self.will_topic.as_ref().map(|t| t.encoded_length()).unwrap_or(0)
-            + self.will_message.as_ref().map(|t| t.encoded_length()).unwrap_or(0)
-            + self.user_name.as_ref().map(|t| t.encoded_length()).unwrap_or(0)
-            + self.password.as_ref().map(|t| t.encoded_length()).unwrap_or(0)
+    pub fn will(mut self, topic: &str, message: &[u8], qos: QualityOfService, retain: bool)
+            -> ConnectPacketBuilder {
+        self.will = Some(LastWill::new(topic.to_owned(), message.to_vec(), qos, retain));
+        self
     }
-}
 
-impl<'a> Decodable<'a> for ConnectPacketPayload {
-    type Err = ConnectPacketPayloadError;
-    type Cond = &'a ConnectFlags;
+    pub fn build(self) -> Result<ConnectPacket, ConnectBuildError> {
+        // MQTT-3.1.2-22: If the User Name Flag is set to 0, the Password Flag MUST be set to 0
+        if self.password.is_some() && self.user_name.is_none()
+                && password_requires_user_name(self.protocol_version.level()) {
+            return Err(ConnectBuildError::PasswordWithoutUserName);
+        }
 
-    fn decode_with<R: Read>(reader: &mut R, rest: Option<&'a ConnectFlags>)
-            -> Result<ConnectPacketPayload, ConnectPacketPayloadError> {
-        let mut need_will_topic = false;
-        let mut need_will_message = false;
-        let mut need_user_name = false;
-        let mut need_password = false;
+        let mut flags = ConnectFlags::empty();
+        flags.clean_session = self.clean_session;
+        flags.user_name = self.user_name.is_some();
+        flags.password = self.password.is_some();
 
-        if let Some(r) = rest {
-            need_will_topic = r.will_flag;
-            need_will_message = r.will_flag;
-            need_user_name = r.user_name;
-            need_password = r.password;
+        let mut payload = ConnectPacketPayload::new(self.client_identifier);
+        payload.mqtt5 = has_properties(self.protocol_version.level());
+        payload.user_name = self.user_name;
+        payload.password = self.password.map(VarBytes);
+
+        if let Some(ref will) = self.will {
+            if will.topic.0.is_empty() {
+                return Err(ConnectBuildError::EmptyWillTopic);
+            }
         }
+        payload.will = self.will;
 
-        let ident: String = try!(Decodable::decode(reader));
-        let topic = if need_will_topic {
-            Some(try!(Decodable::decode(reader)))
-        } else {
-            None
-        };
-        let msg = if need_will_message {
-            Some(try!(Decodable::decode(reader)))
-        } else {
-            None
-        };
-        let uname = if need_user_name {
-            Some(try!(Decodable::decode(reader)))
-        } else {
-            None
-        };
-        let pwd = if need_password {
-            Some(try!(Decodable::decode(reader)))
-        } else {
-            None
+        let mut pk = ConnectPacket {
+            fixed_header: FixedHeader::new(PacketType::with_default(ControlType::Connect), 0),
+            protocol_name: ProtocolName(self.protocol_version.protocol_name().to_owned()),
+            protocol_level: ProtocolLevel(self.protocol_version.level()),
+            flags: flags,
+            keep_alive: KeepAlive(self.keep_alive),
+            properties: Properties::new(),
+            payload: payload,
         };
+        pk.fixed_header.remaining_length = pk.calculate_remaining_length();
 
-        Ok(ConnectPacketPayload {
-            client_identifier: ident,
-            will_topic: topic,
-            will_message: msg,
-            user_name: uname,
-            password: pwd,
-        })
+        Ok(pk)
     }
 }
 
-#[derive(Debug)]
-pub enum ConnectPacketPayloadError {
-    IoError(io::Error),
-    StringEncodeError(StringEncodeError),
-}
+impl fmt::Debug for ConnectPacketBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConnectPacketBuilder")
+            .field("client_identifier", &self.client_identifier)
+            .field("protocol_version", &self.protocol_version)
+            .field("keep_alive", &self.keep_alive)
+            .field("clean_session", &self.clean_session)
+            .field("user_name", &self.user_name)
+            .field("password", &self.password.as_ref().map(|p| BytesDebug(&p[..], true)))
+            .field("will", &self.will.as_ref().map(|w| WillDebug(w, true)))
+            .finish()
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ConnectBuildError {
+    PasswordWithoutUserName,
+    EmptyWillTopic,
+}
+
+impl fmt::Display for ConnectBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl Error for ConnectBuildError {
+    fn description(&self) -> &str {
+        match self {
+            &ConnectBuildError::PasswordWithoutUserName => "Password is set without a user name",
+            &ConnectBuildError::EmptyWillTopic => "Will topic is empty",
+        }
+    }
+}
+
+/// Will Message published by the server when the network connection is closed abnormally
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct LastWill {
+    pub topic: TopicName,
+    pub message: Vec<u8>,
+    pub qos: QualityOfService,
+    pub retain: bool,
+    /// Will Properties, only encoded for MQTT 5
+    pub properties: Properties,
+}
+
+impl LastWill {
+    pub fn new(topic: String, message: Vec<u8>, qos: QualityOfService, retain: bool) -> LastWill {
+        LastWill {
+            topic: TopicName(topic),
+            message: message,
+            qos: qos,
+            retain: retain,
+            properties: Properties::new(),
+        }
+    }
+
+    /// Seconds the server waits after the connection is lost before publishing the will
+    pub fn will_delay_interval(&self) -> Option<u32> {
+        self.properties.will_delay_interval()
+    }
+
+    pub fn set_will_delay_interval(&mut self, interval: Option<u32>) {
+        match interval {
+            Some(interval) => self.properties.insert(Property::WillDelayInterval(interval)),
+            None => self.properties.remove(PropertyType::WillDelayInterval),
+        }
+    }
+
+    pub fn payload_format_indicator(&self) -> Option<u8> {
+        self.properties.payload_format_indicator()
+    }
+
+    pub fn message_expiry_interval(&self) -> Option<u32> {
+        self.properties.message_expiry_interval()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.properties.content_type()
+    }
+
+    pub fn response_topic(&self) -> Option<&str> {
+        self.properties.response_topic()
+    }
+
+    pub fn correlation_data(&self) -> Option<&[u8]> {
+        self.properties.correlation_data()
+    }
+
+    pub fn user_properties<'b>(&'b self) -> impl Iterator<Item = (&'b str, &'b str)> + 'b {
+        self.properties.user_properties()
+    }
+
+    /// Properties of the PUBLISH carrying the will, i.e. all but the Will Delay Interval
+    pub fn publish_properties(&self) -> Properties {
+        let mut properties = self.properties.clone();
+        properties.remove(PropertyType::WillDelayInterval);
+        properties
+    }
+}
+
+/// Properties allowed in the will of an MQTT 5 CONNECT
+const WILL_PROPERTIES: &'static [PropertyType] = &[
+    PropertyType::WillDelayInterval,
+    PropertyType::PayloadFormatIndicator,
+    PropertyType::MessageExpiryInterval,
+    PropertyType::ContentType,
+    PropertyType::ResponseTopic,
+    PropertyType::CorrelationData,
+    PropertyType::UserProperty,
+];
+
+fn validate_will_properties(properties: &Properties) -> Result<(), String> {
+    try!(properties.check_allowed(WILL_PROPERTIES, "Will Properties"));
+
+    for property in properties.iter() {
+        match property {
+            &Property::PayloadFormatIndicator(value) if value > 1 =>
+                return Err(format!("Payload Format Indicator must be 0 or 1, got {}", value)),
+            &Property::ResponseTopic(ref topic) if topic.contains(|c| c == '+' || c == '#') =>
+                return Err(format!("Response Topic {:?} contains a wildcard", topic)),
+            _ => {},
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ConnectPropertyError {
+    ZeroReceiveMaximum,
+    ZeroMaximumPacketSize,
+}
+
+impl fmt::Display for ConnectPropertyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl Error for ConnectPropertyError {
+    fn description(&self) -> &str {
+        match self {
+            &ConnectPropertyError::ZeroReceiveMaximum => "Receive Maximum must not be 0",
+            &ConnectPropertyError::ZeroMaximumPacketSize => "Maximum Packet Size must not be 0",
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum KeepAliveError {
+    OutOfRange(Duration),
+}
+
+impl fmt::Display for KeepAliveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &KeepAliveError::OutOfRange(d) => write!(f, "Keep alive {:?} exceeds 65535 seconds", d),
+        }
+    }
+}
+
+impl Error for KeepAliveError {
+    fn description(&self) -> &str {
+        match self {
+            &KeepAliveError::OutOfRange(..) => "Keep alive exceeds 65535 seconds",
+        }
+    }
+}
+
+/// How strictly `ConnectPacket::validate_client_id` checks the Client Identifier
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ClientIdPolicy {
+    /// Only the identifiers every server must accept: 1 to 23 characters of "0-9a-zA-Z"
+    Strict,
+
+    /// Anything the spec allows a server to accept
+    Relaxed,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ClientIdError {
+    EmptyWithoutCleanSession,
+    NullCharacter,
+    Empty,
+    TooLong(usize),
+    InvalidCharacter(char, usize),
+}
+
+impl ClientIdError {
+    /// Return code of the CONNACK to respond with, `None` if the network connection has to be
+    /// closed without one
+    pub fn connect_return_code(&self) -> Option<ConnectReturnCode> {
+        match self {
+            // MQTT-3.1.3-9
+            &ClientIdError::EmptyWithoutCleanSession => Some(ConnectReturnCode::IdentifierRejected),
+            &ClientIdError::NullCharacter => None,
+            &ClientIdError::Empty => Some(ConnectReturnCode::IdentifierRejected),
+            &ClientIdError::TooLong(..) => Some(ConnectReturnCode::IdentifierRejected),
+            &ClientIdError::InvalidCharacter(..) => Some(ConnectReturnCode::IdentifierRejected),
+        }
+    }
+}
+
+impl fmt::Display for ClientIdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ClientIdError::TooLong(len) => write!(f, "Client identifier is too long ({} bytes)", len),
+            &ClientIdError::InvalidCharacter(c, idx) =>
+                write!(f, "Invalid character {:?} at {} in client identifier", c, idx),
+            err => write!(f, "{}", err.description()),
+        }
+    }
+}
+
+impl Error for ClientIdError {
+    fn description(&self) -> &str {
+        match self {
+            &ClientIdError::EmptyWithoutCleanSession => "Empty client identifier without clean session",
+            &ClientIdError::NullCharacter => "Client identifier contains the null character",
+            &ClientIdError::Empty => "Client identifier is empty",
+            &ClientIdError::TooLong(..) => "Client identifier is too long",
+            &ClientIdError::InvalidCharacter(..) => "Invalid character in client identifier",
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Clone)]
+pub struct ConnectPacketPayload {
+    client_identifier: String,
+    will: Option<LastWill>,
+    user_name: Option<String>,
+    password: Option<VarBytes>,
+    mqtt5: bool,
+}
+
+impl ConnectPacketPayload {
+    pub fn new(client_identifier: String) -> ConnectPacketPayload {
+        ConnectPacketPayload {
+            client_identifier: client_identifier,
+            will: None,
+            user_name: None,
+            password: None,
+            mqtt5: false,
+        }
+    }
+}
+
+impl fmt::Debug for ConnectPacketPayload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        PayloadDebug(self, true).fmt(f)
+    }
+}
+
+impl<'a> Encodable<'a> for ConnectPacketPayload {
+    type Err = ConnectPacketPayloadError;
+
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), ConnectPacketPayloadError> {
+        try!(self.client_identifier.encode(writer));
+
+        if let Some(ref will) = self.will {
+            if will.message.len() > u16::max_value() as usize {
+                return Err(ConnectPacketPayloadError::IoError(
+                        io::Error::new(io::ErrorKind::InvalidInput, "Will Message longer than 65535 bytes")));
+            }
+
+            if self.mqtt5 {
+                try!(will.properties.encode(writer));
+            }
+            try!(will.topic.0.encode(writer));
+            try!(writer.write_u16::<BigEndian>(will.message.len() as u16));
+            try!(writer.write_all(&will.message[..]));
+        }
+
+        if let Some(ref user_name) = self.user_name {
+            try!(user_name.encode(writer));
+        }
+
+        if let Some(ref password) = self.password {
+            try!(password.encode(writer));
+        }
+
+        Ok(())
+    }
+
+    fn encoded_length(&self) -> u32 {
+        let will_length = self.will.as_ref().map(|will| {
+            let properties_length = if self.mqtt5 { will.properties.encoded_length() } else { 0 };
+            properties_length + will.topic.0.encoded_length() + 2 + will.message.len() as u32
+        }).unwrap_or(0);
+
+        self.client_identifier.encoded_length()
+            + will_length
+            + self.user_name.as_ref().map(|t| t.encoded_length()).unwrap_or(0)
+            + self.password.as_ref().map(|t| t.encoded_length()).unwrap_or(0)
+    }
+}
+
+impl<'a> Decodable<'a> for ConnectPacketPayload {
+    type Err = ConnectPacketPayloadError;
+    type Cond = &'a ConnectFlags;
+
+    fn decode_with<R: Read>(reader: &mut R, rest: Option<&'a ConnectFlags>)
+            -> Result<ConnectPacketPayload, ConnectPacketPayloadError> {
+        ConnectPacketPayload::decode_payload(reader, rest, false)
+    }
+}
+
+impl ConnectPacketPayload {
+    fn decode_payload<R: Read>(reader: &mut R, rest: Option<&ConnectFlags>, mqtt5: bool)
+            -> Result<ConnectPacketPayload, ConnectPacketPayloadError> {
+        let mut need_will = false;
+        let mut will_qos = QualityOfService::Level0;
+        let mut will_retain = false;
+        let mut need_user_name = false;
+        let mut need_password = false;
+
+        if let Some(r) = rest {
+            need_will = r.will_flag;
+            will_qos = try!(QualityOfService::try_from(r.will_qos)
+                                .map_err(|_| ConnectPacketPayloadError::InvalidWillQualityOfService));
+            will_retain = r.will_retain;
+            need_user_name = r.user_name;
+            need_password = r.password;
+        }
+
+        let ident: String = try!(Decodable::decode(reader));
+        let will = if need_will {
+            let properties = if mqtt5 {
+                let properties = try!(Properties::decode(reader));
+                try!(validate_will_properties(&properties).map_err(ConnectPacketPayloadError::InvalidWillProperty));
+                properties
+            } else {
+                Properties::new()
+            };
+
+            let topic = try!(decode_will_field(reader));
+            let topic = try!(String::from_utf8(topic).map_err(StringEncodeError::FromUtf8Error));
+            let message = try!(decode_will_field(reader));
+            let mut will = LastWill::new(topic, message, will_qos, will_retain);
+            will.properties = properties;
+            Some(will)
+        } else {
+            None
+        };
+        let uname = if need_user_name {
+            Some(try!(Decodable::decode(reader)))
+        } else {
+            None
+        };
+        let pwd = if need_password {
+            Some(try!(Decodable::decode(reader)))
+        } else {
+            None
+        };
+
+        Ok(ConnectPacketPayload {
+            client_identifier: ident,
+            will: will,
+            user_name: uname,
+            password: pwd,
+            mqtt5: mqtt5,
+        })
+    }
+}
+
+// MQTT-3.1.2-9: If the Will Flag is set, the Will Topic and Will Message MUST be present
+//
+// Only a packet ending before the field is `MissingWill`, a truncated field is malformed
+fn decode_will_field<R: Read>(reader: &mut R) -> Result<Vec<u8>, ConnectPacketPayloadError> {
+    let mut high = [0u8; 1];
+    loop {
+        match reader.read(&mut high) {
+            Ok(0) => return Err(ConnectPacketPayloadError::MissingWill),
+            Ok(..) => break,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(From::from(err)),
+        }
+    }
+    let low = try!(reader.read_u8());
+    let len = ((high[0] as usize) << 8) | low as usize;
+
+    let mut buf = Vec::with_capacity(len);
+    try!(reader.take(len as u64).read_to_end(&mut buf));
+    if buf.len() < len {
+        return Err(ConnectPacketPayloadError::IoError(
+                io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated Will Topic or Will Message")));
+    }
+    Ok(buf)
+}
+
+#[derive(Debug)]
+pub enum ConnectPacketPayloadError {
+    IoError(io::Error),
+    StringEncodeError(StringEncodeError),
+    InvalidWillQualityOfService,
+    MissingWill,
+    WillPropertiesError(VariableHeaderError),
+    InvalidWillProperty(String),
+}
 
 impl fmt::Display for ConnectPacketPayloadError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             &ConnectPacketPayloadError::IoError(ref err) => err.fmt(f),
             &ConnectPacketPayloadError::StringEncodeError(ref err) => err.fmt(f),
+            &ConnectPacketPayloadError::InvalidWillQualityOfService => write!(f, "Invalid will quality of service"),
+            &ConnectPacketPayloadError::MissingWill =>
+                write!(f, "Will Flag is set without Will Topic and Will Message (MQTT-3.1.2-9)"),
+            &ConnectPacketPayloadError::WillPropertiesError(ref err) => write!(f, "Invalid Will Properties: {}", err),
+            &ConnectPacketPayloadError::InvalidWillProperty(ref err) => write!(f, "Invalid Will Properties: {}", err),
         }
     }
 }
@@ -299,6 +1134,10 @@ impl Error for ConnectPacketPayloadError {
         match self {
             &ConnectPacketPayloadError::IoError(ref err) => err.description(),
             &ConnectPacketPayloadError::StringEncodeError(ref err) => err.description(),
+            &ConnectPacketPayloadError::InvalidWillQualityOfService => "Invalid will quality of service",
+            &ConnectPacketPayloadError::MissingWill => "Will Flag is set without Will Topic and Will Message",
+            &ConnectPacketPayloadError::WillPropertiesError(..) => "Invalid Will Properties",
+            &ConnectPacketPayloadError::InvalidWillProperty(..) => "Invalid Will Properties",
         }
     }
 
@@ -306,10 +1145,32 @@ impl Error for ConnectPacketPayloadError {
         match self {
             &ConnectPacketPayloadError::IoError(ref err) => Some(err),
             &ConnectPacketPayloadError::StringEncodeError(ref err) => Some(err),
+            &ConnectPacketPayloadError::InvalidWillQualityOfService => None,
+            &ConnectPacketPayloadError::MissingWill => None,
+            &ConnectPacketPayloadError::WillPropertiesError(ref err) => Some(err),
+            &ConnectPacketPayloadError::InvalidWillProperty(..) => None,
         }
     }
 }
 
+impl From<io::Error> for ConnectPacketPayloadError {
+    fn from(err: io::Error) -> ConnectPacketPayloadError {
+        ConnectPacketPayloadError::IoError(err)
+    }
+}
+
+impl From<byteorder::Error> for ConnectPacketPayloadError {
+    fn from(err: byteorder::Error) -> ConnectPacketPayloadError {
+        ConnectPacketPayloadError::IoError(From::from(err))
+    }
+}
+
+impl From<VariableHeaderError> for ConnectPacketPayloadError {
+    fn from(err: VariableHeaderError) -> ConnectPacketPayloadError {
+        ConnectPacketPayloadError::WillPropertiesError(err)
+    }
+}
+
 impl From<StringEncodeError> for ConnectPacketPayloadError {
     fn from(err: StringEncodeError) -> ConnectPacketPayloadError {
         ConnectPacketPayloadError::StringEncodeError(err)
@@ -321,8 +1182,11 @@ mod test {
     use super::*;
 
     use std::io::Cursor;
+    use std::time::Duration;
 
-    use {Encodable, Decodable};
+    use control::variable_header::{Properties, Property, PropertyType, ProtocolVersion, VariableHeaderError};
+    use packet::PacketError;
+    use {Encodable, Decodable, QualityOfService};
 
     #[test]
     fn test_connect_packet_encode_basic() {
@@ -346,6 +1210,304 @@ mod test {
         assert_eq!(expected, packet);
     }
 
+    #[test]
+    fn test_connect_packet_mqtt_3_1() {
+        let packet = ConnectPacket::with_version("12345".to_owned(), ProtocolVersion::V3_1);
+        let expected = b"\x10\x13\x00\x06MQIsdp\x03\x00\x00\x00\x00\x0512345";
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&expected[..], &buf[..]);
+
+        let mut decode_buf = Cursor::new(&expected[..]);
+        let decoded = ConnectPacket::decode(&mut decode_buf).unwrap();
+        assert_eq!(Some(ProtocolVersion::V3_1), decoded.protocol_version());
+        assert_eq!(packet, decoded);
+
+        let built = ConnectPacket::builder("12345").protocol_version(ProtocolVersion::V3_1).build().unwrap();
+        assert_eq!(packet, built);
+    }
+
+    #[test]
+    fn test_connect_packet_unsupported_level() {
+        let encoded_data = b"\x10\x11\x00\x04MQTT\x03\x00\x00\x00\x00\x0512345";
+
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        let decoded = ConnectPacket::decode(&mut decode_buf).unwrap();
+        assert_eq!(None, decoded.protocol_version());
+        assert_eq!(3, decoded.protocol_level());
+
+        let encoded_data = b"\x10\x11\x00\x04MQTX\x04\x00\x00\x00\x00\x0512345";
+
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        assert!(ConnectPacket::decode(&mut decode_buf).is_err());
+    }
+
+    #[test]
+    fn test_connect_packet_mqtt_5() {
+        // Property Length 0 follows the Keep Alive
+        let packet = ConnectPacket::builder("12345")
+                        .protocol_version(ProtocolVersion::V5)
+                        .clean_session(true)
+                        .build()
+                        .unwrap();
+        let expected = b"\x10\x12\x00\x04MQTT\x05\x02\x00\x00\x00\x00\x0512345";
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&expected[..], &buf[..]);
+        assert_eq!(packet, ConnectPacket::decode(&mut Cursor::new(&expected[..])).unwrap());
+        let mut with_version = ConnectPacket::with_version("12345".to_owned(), ProtocolVersion::V5);
+        with_version.set_clean_session(true);
+        assert_eq!(packet, with_version);
+
+        // MQTT 5 allows a password without a user name
+        let packet = ConnectPacket::builder("12345").protocol_version(ProtocolVersion::V5).password(b"p").build().unwrap();
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(packet, ConnectPacket::decode(&mut Cursor::new(&buf[..])).unwrap());
+    }
+
+    #[test]
+    fn test_connect_packet_mqtt_5_synthetic_session_and_will() {
+        // Synthetic, built by hand after the spec for the options of
+        // mosquitto_sub -V 5 -i sensor-1 -t # --will-topic status/sensor-1 --will-payload offline
+        //     -D connect session-expiry-interval 3600 -D will will-delay-interval 5
+        // It is not a capture of what mosquitto_sub sends
+        let encoded_data = b"\x10\x3a\x00\x04MQTT\x05\x06\x00\x3c\x05\x11\x00\x00\x0e\x10\
+                             \x00\x08sensor-1\
+                             \x05\x18\x00\x00\x00\x05\x00\x0fstatus/sensor-1\x00\x07offline";
+
+        let decoded = ConnectPacket::decode(&mut Cursor::new(&encoded_data[..])).unwrap();
+        assert_eq!(Some(ProtocolVersion::V5), decoded.protocol_version());
+        assert_eq!("sensor-1", decoded.client_identifier());
+        assert!(decoded.clean_session());
+        assert_eq!(60, decoded.keep_alive());
+        assert_eq!(Some(3600), decoded.connect_properties().session_expiry_interval());
+
+        let will = decoded.will().unwrap();
+        assert_eq!("status/sensor-1", &will.topic.0[..]);
+        assert_eq!(b"offline", &will.message[..]);
+        assert_eq!(Some(5), will.properties.will_delay_interval());
+
+        let mut buf = Vec::new();
+        decoded.encode(&mut buf).unwrap();
+        assert_eq!(&encoded_data[..], &buf[..]);
+
+        // The same packet at level 4 has neither property block
+        let mut properties = Properties::new();
+        properties.insert(Property::SessionExpiryInterval(3600));
+        let mut will = LastWill::new("status/sensor-1".to_owned(), b"offline".to_vec(), QualityOfService::Level0, false);
+        will.properties.insert(Property::WillDelayInterval(5));
+        let mut packet = ConnectPacket::builder("sensor-1").clean_session(true).keep_alive(60).build().unwrap();
+        packet.set_will(Some(will)).unwrap();
+        packet.set_connect_properties(properties);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x10\x2e\x00\x04MQTT\x04\x06\x00\x3c\x00\x08sensor-1\x00\x0fstatus/sensor-1\x00\x07offline"[..],
+                   &buf[..]);
+    }
+
+    #[test]
+    fn test_connect_packet_mqtt_5_will_properties() {
+        let mut will = LastWill::new("w".to_owned(), b"bye".to_vec(), QualityOfService::Level0, false);
+        will.set_will_delay_interval(Some(30));
+        will.properties.insert(Property::ContentType("text/plain".to_owned()));
+        let mut packet = ConnectPacket::with_version("c".to_owned(), ProtocolVersion::V5);
+        packet.set_keep_alive(0);
+        packet.set_will(Some(will)).unwrap();
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x10\x29\x00\x04MQTT\x05\x04\x00\x00\x00\x00\x01c\
+                       \x12\x18\x00\x00\x00\x1e\x03\x00\x0atext/plain\x00\x01w\x00\x03bye"[..], &buf[..]);
+
+        let decoded = ConnectPacket::decode(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(packet, decoded);
+        let will = decoded.will().unwrap();
+        assert_eq!(Some(30), will.will_delay_interval());
+        assert_eq!(Some("text/plain"), will.content_type());
+        assert_eq!(None, will.publish_properties().will_delay_interval());
+        assert_eq!(Some("text/plain"), will.publish_properties().content_type());
+
+        // Every will property survives a round trip
+        let mut will = will.clone();
+        will.set_will_delay_interval(None);
+        will.properties.insert(Property::PayloadFormatIndicator(1));
+        will.properties.insert(Property::MessageExpiryInterval(3600));
+        will.properties.insert(Property::ResponseTopic("replies/c".to_owned()));
+        will.properties.insert(Property::CorrelationData(b"\x00\x01".to_vec()));
+        will.properties.push_user_property("reason", "crash");
+        will.properties.push_user_property("reason", "timeout");
+        packet.set_will(Some(will.clone())).unwrap();
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(packet.encoded_length() as usize, buf.len());
+        let decoded = ConnectPacket::decode(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(Some(&will), decoded.will());
+        let will = decoded.will().unwrap();
+        assert_eq!(None, will.will_delay_interval());
+        assert_eq!(Some(1), will.payload_format_indicator());
+        assert_eq!(Some(3600), will.message_expiry_interval());
+        assert_eq!(Some("replies/c"), will.response_topic());
+        assert_eq!(Some(&b"\x00\x01"[..]), will.correlation_data());
+        assert_eq!(vec![("reason", "crash"), ("reason", "timeout")], will.user_properties().collect::<Vec<_>>());
+
+        // Session Expiry Interval belongs to the CONNECT properties, not to the will
+        let encoded_data = b"\x10\x3a\x00\x04MQTT\x05\x06\x00\x3c\x05\x11\x00\x00\x0e\x10\
+                             \x00\x08sensor-1\
+                             \x05\x11\x00\x00\x00\x05\x00\x0fstatus/sensor-1\x00\x07offline";
+        match ConnectPacket::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(PacketError::PayloadError(ConnectPacketPayloadError::InvalidWillProperty(ref err))) =>
+                assert!(err.contains("0x11"), "{}", err),
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_connect_packet_mqtt_5_properties() {
+        fn roundtrip(packet: &ConnectPacket) -> ConnectPacket {
+            let mut buf = Vec::new();
+            packet.encode(&mut buf).unwrap();
+            assert_eq!(packet.encoded_length() as usize, buf.len());
+            ConnectPacket::decode(&mut Cursor::new(&buf[..])).unwrap()
+        }
+
+        let new_packet = || ConnectPacket::with_version("12345".to_owned(), ProtocolVersion::V5);
+
+        let mut packet = new_packet();
+        packet.set_receive_maximum(Some(10)).unwrap();
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x10\x15\x00\x04MQTT\x05\x00\x00\x00\x03\x21\x00\x0a\x00\x0512345"[..], &buf[..]);
+
+        let mut packets = Vec::new();
+        let mut packet = new_packet();
+        packet.set_session_expiry_interval(Some(3600));
+        assert_eq!(Some(3600), roundtrip(&packet).session_expiry_interval());
+        packets.push(packet);
+        let mut packet = new_packet();
+        packet.set_receive_maximum(Some(20)).unwrap();
+        assert_eq!(Some(20), roundtrip(&packet).receive_maximum());
+        packets.push(packet);
+        let mut packet = new_packet();
+        packet.set_maximum_packet_size(Some(1024)).unwrap();
+        assert_eq!(Some(1024), roundtrip(&packet).maximum_packet_size());
+        packets.push(packet);
+        let mut packet = new_packet();
+        packet.set_topic_alias_maximum(Some(8));
+        assert_eq!(Some(8), roundtrip(&packet).topic_alias_maximum());
+        packets.push(packet);
+        let mut packet = new_packet();
+        packet.set_request_response_information(Some(true));
+        assert_eq!(Some(true), roundtrip(&packet).request_response_information());
+        packets.push(packet);
+        let mut packet = new_packet();
+        packet.set_request_problem_information(Some(false));
+        assert_eq!(Some(false), roundtrip(&packet).request_problem_information());
+        packets.push(packet);
+        let mut packet = new_packet();
+        let mut properties = Properties::new();
+        properties.insert(Property::UserProperty("region".to_owned(), "eu".to_owned()));
+        properties.insert(Property::UserProperty("region".to_owned(), "us".to_owned()));
+        packet.set_connect_properties(properties);
+        assert_eq!(2, roundtrip(&packet).connect_properties().get_all(PropertyType::UserProperty).count());
+        packets.push(packet);
+        let mut packet = new_packet();
+        packet.set_authentication_method(Some("SCRAM-SHA-1".to_owned()));
+        assert_eq!(Some("SCRAM-SHA-1"), roundtrip(&packet).authentication_method());
+        packets.push(packet);
+        let mut packet = new_packet();
+        packet.set_authentication_data(Some(b"client-first".to_vec()));
+        assert_eq!(Some(&b"client-first"[..]), roundtrip(&packet).authentication_data());
+        packets.push(packet);
+
+        for packet in &packets {
+            assert_eq!(packet, &roundtrip(packet));
+        }
+
+        // All of them together
+        let mut all = new_packet();
+        for packet in &packets {
+            for property in packet.connect_properties().iter() {
+                let mut properties = all.connect_properties().clone();
+                properties.insert(property.clone());
+                all.set_connect_properties(properties);
+            }
+        }
+        assert_eq!(10, all.connect_properties().len());
+        assert_eq!(all, roundtrip(&all));
+
+        // Clearing a property shrinks the packet again
+        all.set_session_expiry_interval(None);
+        assert_eq!(None, all.session_expiry_interval());
+        assert_eq!(all, roundtrip(&all));
+
+        assert_eq!(Err(ConnectPropertyError::ZeroReceiveMaximum), all.set_receive_maximum(Some(0)));
+        assert_eq!(Err(ConnectPropertyError::ZeroMaximumPacketSize), all.set_maximum_packet_size(Some(0)));
+        assert_eq!(Some(20), all.receive_maximum());
+    }
+
+    #[test]
+    fn test_connect_packet_mqtt_5_illegal_property() {
+        // Maximum QoS is only sent by the server
+        let encoded_data = b"\x10\x14\x00\x04MQTT\x05\x02\x00\x00\x02\x24\x01\x00\x0512345";
+        match ConnectPacket::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(PacketError::MalformedPacket(ref err)) => assert!(err.contains("MaximumQoS (0x24)"), "{}", err),
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        // Receive Maximum of 0 is a protocol error
+        let encoded_data = b"\x10\x15\x00\x04MQTT\x05\x02\x00\x00\x03\x21\x00\x00\x00\x0512345";
+        match ConnectPacket::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(PacketError::MalformedPacket(..)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_connect_packet_bridge() {
+        // CONNECT of a mosquitto 3.1.1 bridge "bridge-01" on host "gateway" with the default
+        // notifications, cleansession false and keepalive 60
+        let encoded_data = b"\x10\x50\x00\x04MQTT\x84\x2c\x00\x3c\
+                             \x00\x11gateway.bridge-01\
+                             \x00\x2e$SYS/broker/connection/gateway.bridge-01/state\x00\x010";
+
+        let decoded = ConnectPacket::decode(&mut Cursor::new(&encoded_data[..])).unwrap();
+        assert!(decoded.is_bridge());
+        assert_eq!(0x84, decoded.protocol_level());
+        assert_eq!(Some(ProtocolVersion::V3_1_1), decoded.protocol_version());
+        assert_eq!("gateway.bridge-01", decoded.client_identifier());
+        assert_eq!(60, decoded.keep_alive());
+        assert!(!decoded.clean_session());
+        let will = decoded.will().unwrap();
+        assert_eq!("$SYS/broker/connection/gateway.bridge-01/state", &will.topic.0[..]);
+        assert_eq!(b"0", &will.message[..]);
+        assert_eq!(QualityOfService::Level1, will.qos);
+        assert!(will.retain);
+
+        let mut packet = ConnectPacket::builder("gateway.bridge-01")
+                            .keep_alive(60)
+                            .will("$SYS/broker/connection/gateway.bridge-01/state", b"0", QualityOfService::Level1, true)
+                            .build()
+                            .unwrap();
+        assert!(!packet.is_bridge());
+        packet.set_bridge_mode(true);
+        assert_eq!(decoded, packet);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&encoded_data[..], &buf[..]);
+
+        let mut v3_1 = ConnectPacket::with_version("bridge".to_owned(), ProtocolVersion::V3_1);
+        v3_1.set_bridge_mode(true);
+        assert_eq!(0x83, v3_1.protocol_level());
+        assert_eq!(Some(ProtocolVersion::V3_1), v3_1.protocol_version());
+        v3_1.set_bridge_mode(false);
+        assert_eq!(3, v3_1.protocol_level());
+    }
+
     #[test]
     fn test_connect_packet_user_name() {
         let mut packet = ConnectPacket::new("12345".to_owned());
@@ -359,4 +1521,368 @@ mod test {
 
         assert_eq!(packet, decoded_packet);
     }
+
+    #[test]
+    fn test_connect_packet_builder() {
+        let packet = ConnectPacket::builder("12345")
+                        .keep_alive(60)
+                        .clean_session(true)
+                        .user_name("mqtt_player")
+                        .password(b"secret")
+                        .will("a/b", b"bye", QualityOfService::Level1, true)
+                        .build()
+                        .unwrap();
+
+        let mut expected = ConnectPacket::new("12345".to_owned());
+        expected.set_clean_session(true);
+        expected.set_user_name(Some("mqtt_player".to_owned()));
+        expected.set_password_str("secret");
+        expected.set_will(Some(LastWill::new("a/b".to_owned(), b"bye".to_vec(), QualityOfService::Level1, true))).unwrap();
+        expected.set_keep_alive(60);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+
+        let mut expected_buf = Vec::new();
+        expected.encode(&mut expected_buf).unwrap();
+        assert_eq!(expected_buf, buf);
+
+        let mut decode_buf = Cursor::new(buf);
+        let decoded_packet = ConnectPacket::decode(&mut decode_buf).unwrap();
+        assert_eq!(packet, decoded_packet);
+    }
+
+    #[test]
+    fn test_connect_packet_will() {
+        let mut packet = ConnectPacket::new("12345".to_owned());
+        packet.set_will(Some(LastWill::new("a/b".to_owned(), vec![0xff, 0x00], QualityOfService::Level2, true))).unwrap();
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+
+        let expected = b"\x10\x1a\x00\x04MQTT\x04\x34\x00\x00\x00\x0512345\x00\x03a/b\x00\x02\xff\x00";
+        assert_eq!(&expected[..], &buf[..]);
+
+        let mut decode_buf = Cursor::new(buf);
+        let decoded_packet = ConnectPacket::decode(&mut decode_buf).unwrap();
+
+        assert_eq!(Some(&b"\xff\x00"[..]), decoded_packet.will().map(|w| &w.message[..]));
+        assert_eq!(packet, decoded_packet);
+
+        packet.set_will(None).unwrap();
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x10\x11\x00\x04MQTT\x04\x00\x00\x00\x00\x0512345"[..], &buf[..]);
+    }
+
+    #[test]
+    fn test_connect_packet_set_will_empty_topic() {
+        let mut packet = ConnectPacket::new("12345".to_owned());
+        let will = LastWill::new(String::new(), b"bye".to_vec(), QualityOfService::Level0, false);
+        assert_eq!(Err(ConnectBuildError::EmptyWillTopic), packet.set_will(Some(will)));
+        assert!(packet.will().is_none());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x10\x11\x00\x04MQTT\x04\x00\x00\x00\x00\x0512345"[..], &buf[..]);
+    }
+
+    #[test]
+    fn test_connect_packet_will_message_too_long() {
+        let mut packet = ConnectPacket::new("12345".to_owned());
+        packet.set_will(Some(LastWill::new("a/b".to_owned(), vec![0; 65536], QualityOfService::Level0, false))).unwrap();
+        match packet.encode(&mut Vec::new()) {
+            Err(PacketError::PayloadError(ConnectPacketPayloadError::IoError(ref err)))
+                if err.kind() == io::ErrorKind::InvalidInput => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_connect_packet_will_qos_retain() {
+        let mut packet = ConnectPacket::new("12345".to_owned());
+        packet.set_will(Some(LastWill::new("a/b".to_owned(), b"bye".to_vec(), QualityOfService::Level0, false))).unwrap();
+        assert!(packet.set_will_qos(QualityOfService::Level1));
+        assert!(packet.set_will_retain(true));
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(0x2c, buf[9]);
+
+        let mut decode_buf = Cursor::new(buf);
+        let decoded_packet = ConnectPacket::decode(&mut decode_buf).unwrap();
+        assert_eq!(QualityOfService::Level1, decoded_packet.will_qos());
+        assert!(decoded_packet.will_retain());
+    }
+
+    #[test]
+    fn test_connect_packet_will_qos_without_will() {
+        let mut packet = ConnectPacket::new("12345".to_owned());
+        assert!(!packet.set_will_qos(QualityOfService::Level1));
+        assert!(!packet.set_will_retain(true));
+        assert_eq!(QualityOfService::Level0, packet.will_qos());
+        assert!(!packet.will_retain());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(0x00, buf[9]);
+    }
+
+    #[test]
+    fn test_connect_packet_decode_reserved_flag() {
+        let encoded_data = b"\x10\x11\x00\x04MQTT\x04\x03\x00\x3c\x00\x0512345";
+        let mut buf = Cursor::new(&encoded_data[..]);
+        match ConnectPacket::decode(&mut buf) {
+            Err(PacketError::VariableHeaderError(VariableHeaderError::ConnectFlagsReservedBitSet)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_connect_packet_decode_invalid_will_flags() {
+        // Will QoS 3
+        let encoded_data = b"\x10\x11\x00\x04MQTT\x04\x1c\x00\x00\x00\x0512345";
+        let mut buf = Cursor::new(&encoded_data[..]);
+        match ConnectPacket::decode(&mut buf) {
+            Err(PacketError::VariableHeaderError(VariableHeaderError::InvalidWillQoS(3))) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        // Will QoS 1 without will flag
+        let encoded_data = b"\x10\x11\x00\x04MQTT\x04\x08\x00\x00\x00\x0512345";
+        let mut buf = Cursor::new(&encoded_data[..]);
+        match ConnectPacket::decode(&mut buf) {
+            Err(PacketError::VariableHeaderError(VariableHeaderError::WillQoSWithoutWillFlag)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        // Will retain without will flag
+        let encoded_data = b"\x10\x11\x00\x04MQTT\x04\x20\x00\x00\x00\x0512345";
+        let mut buf = Cursor::new(&encoded_data[..]);
+        match ConnectPacket::decode(&mut buf) {
+            Err(PacketError::VariableHeaderError(err @ VariableHeaderError::WillRetainWithoutWillFlag)) => {
+                assert_eq!(Some("MQTT-3.1.2-15"), err.spec_rule());
+            },
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        // Will flag without will topic and message
+        let encoded_data = b"\x10\x11\x00\x04MQTT\x04\x04\x00\x00\x00\x0512345";
+        let mut buf = Cursor::new(&encoded_data[..]);
+        match ConnectPacket::decode(&mut buf) {
+            Err(PacketError::PayloadError(ConnectPacketPayloadError::MissingWill)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        // Will flag with a will topic but without the will message
+        let encoded_data = b"\x10\x16\x00\x04MQTT\x04\x04\x00\x00\x00\x0512345\x00\x03a/b";
+        let mut buf = Cursor::new(&encoded_data[..]);
+        match ConnectPacket::decode(&mut buf) {
+            Err(PacketError::PayloadError(ConnectPacketPayloadError::MissingWill)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        // A truncated will message is not a missing one
+        let encoded_data = b"\x10\x1a\x00\x04MQTT\x04\x04\x00\x00\x00\x0512345\x00\x03a/b\x00\x05by";
+        let mut buf = Cursor::new(&encoded_data[..]);
+        match ConnectPacket::decode(&mut buf) {
+            Err(PacketError::PayloadError(ConnectPacketPayloadError::IoError(ref err)))
+                if err.kind() == io::ErrorKind::UnexpectedEof => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        // Neither is a will topic that is not UTF-8
+        let encoded_data = b"\x10\x1a\x00\x04MQTT\x04\x04\x00\x00\x00\x0512345\x00\x02\xff\xfe\x00\x03bye";
+        let mut buf = Cursor::new(&encoded_data[..]);
+        match ConnectPacket::decode(&mut buf) {
+            Err(PacketError::PayloadError(ConnectPacketPayloadError::StringEncodeError(
+                    StringEncodeError::FromUtf8Error(..)))) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_connect_packet_toggle_clean_session() {
+        let encoded_data = b"\x10\x21\x00\x04MQTT\x04\xee\x00\x3c\x00\x0512345\x00\x03a/b\x00\x03bye\x00\x01u\x00\x01p";
+
+        let mut buf = Cursor::new(&encoded_data[..]);
+        let mut packet = ConnectPacket::decode(&mut buf).unwrap();
+        assert!(packet.clean_session());
+
+        packet.set_clean_session(false);
+        assert!(!packet.clean_session());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+
+        assert_eq!(encoded_data.len(), buf.len());
+        for (idx, (a, b)) in encoded_data.iter().zip(buf.iter()).enumerate() {
+            if idx == 9 {
+                assert_eq!(0xec, *b);
+            } else {
+                assert_eq!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_connect_packet_keep_alive() {
+        let encoded_data = b"\x10\x11\x00\x04MQTT\x04\x00\x00\x3c\x00\x0512345";
+
+        let mut buf = Cursor::new(&encoded_data[..]);
+        let mut packet = ConnectPacket::decode(&mut buf).unwrap();
+        assert_eq!(60, packet.keep_alive());
+
+        packet.set_keep_alive_duration(Duration::from_millis(1500)).unwrap();
+        assert_eq!(2, packet.keep_alive());
+
+        packet.set_keep_alive_duration(Duration::from_secs(65535)).unwrap();
+        assert_eq!(65535, packet.keep_alive());
+
+        let too_long = Duration::new(65535, 1);
+        assert_eq!(Err(KeepAliveError::OutOfRange(too_long)), packet.set_keep_alive_duration(too_long));
+        assert_eq!(65535, packet.keep_alive());
+    }
+
+    #[test]
+    fn test_connect_packet_binary_password() {
+        let mut packet = ConnectPacket::new("12345".to_owned());
+        packet.set_user_name(Some("mqtt_player".to_owned()));
+        packet.set_password(Some(vec![0xff, 0x00, 0xc3, 0x28]));
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+
+        let mut decode_buf = Cursor::new(buf);
+        let decoded_packet = ConnectPacket::decode(&mut decode_buf).unwrap();
+
+        assert_eq!(Some(&[0xff, 0x00, 0xc3, 0x28][..]), decoded_packet.password());
+        assert_eq!(packet, decoded_packet);
+    }
+
+    #[test]
+    fn test_connect_packet_decode_binary_password() {
+        let encoded_data = b"\x10\x18\x00\x04MQTT\x04\xc0\x00\x00\x00\x0512345\x00\x01u\x00\x02\xfe\xff";
+
+        let mut buf = Cursor::new(&encoded_data[..]);
+        let packet = ConnectPacket::decode(&mut buf).unwrap();
+
+        assert_eq!(Some("u"), packet.user_name());
+        assert_eq!(Some(&b"\xfe\xff"[..]), packet.password());
+    }
+
+    #[test]
+    fn test_connect_packet_password_too_long() {
+        let packet = ConnectPacket::builder("12345").user_name("u").password(&vec![0; 65536]).build().unwrap();
+        match packet.encode(&mut Vec::new()) {
+            Err(PacketError::PayloadError(ConnectPacketPayloadError::IoError(ref err)))
+                if err.kind() == io::ErrorKind::InvalidInput => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let packet = ConnectPacket::builder("12345").user_name("u").password(&vec![0; 65535]).build().unwrap();
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(packet.encoded_length() as usize, buf.len());
+    }
+
+    #[test]
+    fn test_connect_packet_debug_redacted() {
+        let packet = ConnectPacket::builder("12345")
+                        .user_name("mqtt_player")
+                        .password(b"hunter2")
+                        .will("a/b", b"farewell", QualityOfService::Level0, false)
+                        .build()
+                        .unwrap();
+
+        let output = format!("{:?}", packet);
+        assert!(output.contains("password: Some(<redacted, 7 bytes>)"), "{}", output);
+        assert!(output.contains("message: <redacted, 8 bytes>"), "{}", output);
+        assert!(output.contains("user_name: Some(\"mqtt_player\")"), "{}", output);
+        assert!(!output.contains("104, 117, 110, 116, 101, 114, 50"), "{}", output);
+        assert!(!output.contains("hunter2"), "{}", output);
+
+        let output = format!("{:?}", ConnectPacket::builder("12345").password(b"hunter2"));
+        assert!(output.contains("password: Some(<redacted, 7 bytes>)"), "{}", output);
+
+        let output = format!("{:?}", packet.debug_unredacted());
+        assert!(output.contains("password: Some([104, 117, 110, 116, 101, 114, 50])"), "{}", output);
+    }
+
+    #[test]
+    fn test_connect_packet_builder_password_without_user_name() {
+        let result = ConnectPacket::builder("12345").password(b"secret").build();
+        assert_eq!(Some(ConnectBuildError::PasswordWithoutUserName), result.err());
+    }
+
+    #[test]
+    fn test_connect_packet_password_without_user_name() {
+        let mut packet = ConnectPacket::new("12345".to_owned());
+        packet.set_password_str("secret");
+
+        let mut buf = Vec::new();
+        match packet.encode(&mut buf) {
+            Err(PacketError::VariableHeaderError(VariableHeaderError::PasswordWithoutUserName)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let encoded_data = b"\x10\x14\x00\x04MQTT\x04\x40\x00\x3c\x00\x0512345\x00\x01p";
+        let mut buf = Cursor::new(&encoded_data[..]);
+        match ConnectPacket::decode(&mut buf) {
+            Err(PacketError::VariableHeaderError(err @ VariableHeaderError::PasswordWithoutUserName)) => {
+                assert_eq!(Some("MQTT-3.1.2-22"), err.spec_rule());
+            },
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_connect_packet_empty_client_identifier() {
+        let mut packet = ConnectPacket::new(String::new());
+        assert_eq!(Err(ClientIdError::EmptyWithoutCleanSession), packet.validate_client_id_rules());
+        assert_eq!(Some(ConnectReturnCode::IdentifierRejected),
+                   packet.validate_client_id_rules().unwrap_err().connect_return_code());
+
+        packet.set_clean_session(true);
+        assert_eq!(Ok(()), packet.validate_client_id_rules());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x10\x0c\x00\x04MQTT\x04\x02\x00\x00\x00\x00"[..], &buf[..]);
+
+        let decoded = ConnectPacket::decode(&mut Cursor::new(buf)).unwrap();
+        assert_eq!("", decoded.client_identifier());
+        assert_eq!(packet, decoded);
+
+        let packet = ConnectPacket::builder("a\0b").clean_session(true).build().unwrap();
+        assert_eq!(Err(ClientIdError::NullCharacter), packet.validate_client_id_rules());
+        assert_eq!(None, ClientIdError::NullCharacter.connect_return_code());
+    }
+
+    #[test]
+    fn test_connect_packet_client_id_policy() {
+        let packet = ConnectPacket::new("abcXYZ0123456789".to_owned());
+        assert_eq!(Ok(()), packet.validate_client_id(ClientIdPolicy::Strict));
+
+        let packet = ConnectPacket::builder("").clean_session(true).build().unwrap();
+        assert_eq!(Ok(()), packet.validate_client_id(ClientIdPolicy::Relaxed));
+        assert_eq!(Err(ClientIdError::Empty), packet.validate_client_id(ClientIdPolicy::Strict));
+
+        let packet = ConnectPacket::new("client-1".to_owned());
+        assert_eq!(Ok(()), packet.validate_client_id(ClientIdPolicy::Relaxed));
+        assert_eq!(Err(ClientIdError::InvalidCharacter('-', 6)), packet.validate_client_id(ClientIdPolicy::Strict));
+
+        // Longer than 23 bytes, still fine on the wire
+        let id = "\u{00e9}tag\u{00e8}re-num\u{00e9}ro-vingt-quatre";
+        let packet = ConnectPacket::new(id.to_owned());
+        assert_eq!(Ok(()), packet.validate_client_id(ClientIdPolicy::Relaxed));
+        let err = packet.validate_client_id(ClientIdPolicy::Strict).unwrap_err();
+        assert_eq!(ClientIdError::TooLong(id.len()), err);
+        assert_eq!(Some(ConnectReturnCode::IdentifierRejected), err.connect_return_code());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        let decoded = ConnectPacket::decode(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(id, decoded.client_identifier());
+    }
 }
@@ -0,0 +1,222 @@
+use control::variable_header::ProtocolVersion;
+
+/// Validation knobs of `VariablePacket::decode_with_options`
+///
+/// The default options are strict and follow the spec, `DecodeOptions::lenient()` relaxes
+/// the checks that misbehaving peers are known to trip over.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct DecodeOptions {
+    /// Maximum size of a whole packet in bytes, including the fixed header
+    pub max_packet_size: Option<u32>,
+
+    /// Rejects reserved flags in the fixed header that do not match the control type (MQTT-2.2.2-2),
+    /// otherwise they are replaced with the expected ones
+    pub strict_flags: bool,
+
+    /// Rejects strings containing the null character U+0000 (MQTT-1.5.3-2)
+    pub strict_utf8: bool,
+
+    /// Rejects bytes left within the Remaining Length after the packet, otherwise they are skipped
+    pub strict_length: bool,
+
+    /// Skips packets with a reserved control type instead of failing
+    pub allow_unknown_packets: bool,
+
+    /// Rejects a Remaining Length encoded with more bytes than needed, e.g. `0x81 0x00` for 1
+    pub minimal_remaining_length: bool,
+
+    /// Protocol version of the connection, it decides the layout of the packets that changed
+    /// in MQTT 5
+    pub protocol_version: ProtocolVersion,
+
+    /// Rejects MQTT 5 Reason Codes the spec does not define, otherwise they are kept as they are
+    pub strict_reason_codes: bool,
+
+    /// Recorded on the `tracing` span of the decode to tell connections apart
+    pub connection: Option<u64>,
+}
+
+impl DecodeOptions {
+    pub fn new() -> DecodeOptions {
+        DecodeOptions {
+            max_packet_size: None,
+            strict_flags: true,
+            strict_utf8: true,
+            strict_length: true,
+            allow_unknown_packets: false,
+            minimal_remaining_length: false,
+            protocol_version: ProtocolVersion::V3_1_1,
+            strict_reason_codes: true,
+            connection: None,
+        }
+    }
+
+    /// Accepts anything that can still be decoded unambiguously
+    pub fn lenient() -> DecodeOptions {
+        DecodeOptions {
+            max_packet_size: None,
+            strict_flags: false,
+            strict_utf8: false,
+            strict_length: false,
+            allow_unknown_packets: true,
+            minimal_remaining_length: false,
+            protocol_version: ProtocolVersion::V3_1_1,
+            strict_reason_codes: false,
+            connection: None,
+        }
+    }
+
+    pub fn max_packet_size(mut self, max_packet_size: Option<u32>) -> DecodeOptions {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    pub fn strict_flags(mut self, strict_flags: bool) -> DecodeOptions {
+        self.strict_flags = strict_flags;
+        self
+    }
+
+    pub fn strict_utf8(mut self, strict_utf8: bool) -> DecodeOptions {
+        self.strict_utf8 = strict_utf8;
+        self
+    }
+
+    pub fn strict_length(mut self, strict_length: bool) -> DecodeOptions {
+        self.strict_length = strict_length;
+        self
+    }
+
+    pub fn allow_unknown_packets(mut self, allow_unknown_packets: bool) -> DecodeOptions {
+        self.allow_unknown_packets = allow_unknown_packets;
+        self
+    }
+
+    pub fn minimal_remaining_length(mut self, minimal_remaining_length: bool) -> DecodeOptions {
+        self.minimal_remaining_length = minimal_remaining_length;
+        self
+    }
+
+    pub fn protocol_version(mut self, protocol_version: ProtocolVersion) -> DecodeOptions {
+        self.protocol_version = protocol_version;
+        self
+    }
+
+    pub fn strict_reason_codes(mut self, strict_reason_codes: bool) -> DecodeOptions {
+        self.strict_reason_codes = strict_reason_codes;
+        self
+    }
+
+    pub fn connection(mut self, connection: Option<u64>) -> DecodeOptions {
+        self.connection = connection;
+        self
+    }
+}
+
+impl Default for DecodeOptions {
+    fn default() -> DecodeOptions {
+        DecodeOptions::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use control::ControlType;
+    use control::fixed_header::FixedHeaderError;
+    use control::packet_type::PacketTypeError;
+    use packet::*;
+
+    #[test]
+    fn test_decode_options_max_packet_size() {
+        let encoded_data = b"\x30\x0a\x00\x03a/bhello";
+
+        let options = DecodeOptions::new().max_packet_size(Some(11));
+        match VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &options) {
+            Err(VariablePacketError::PacketTooLarge(12)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let options = options.max_packet_size(Some(12));
+        let packet = VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &options).unwrap();
+        assert_eq!(Some("a/b"), packet.publish_topic());
+    }
+
+    #[test]
+    fn test_decode_options_strict_flags() {
+        let encoded_data = b"\x60\x02\x00\x0a";
+
+        match VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &DecodeOptions::new()) {
+            Err(VariablePacketError::PubrelPacketError(
+                    PacketError::InvalidFixedHeaderFlags { control_type: ControlType::PublishRelease, flags: 0 })) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let options = DecodeOptions::new().strict_flags(false);
+        let packet = VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &options).unwrap();
+        assert_eq!(VariablePacket::new(PubrelPacket::new(10)), packet);
+    }
+
+    #[test]
+    fn test_decode_options_strict_utf8() {
+        let encoded_data = b"\x30\x05\x00\x03a\x00b";
+
+        match VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &DecodeOptions::new()) {
+            Err(VariablePacketError::NullCharacter) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let options = DecodeOptions::new().strict_utf8(false);
+        let packet = VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &options).unwrap();
+        assert_eq!(Some("a\0b"), packet.publish_topic());
+    }
+
+    #[test]
+    fn test_decode_options_strict_length() {
+        let encoded_data = b"\x40\x04\x00\x0a\x00\x00\xc0\x00";
+
+        match VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &DecodeOptions::new()) {
+            Err(VariablePacketError::PubackPacketError(PacketError::MalformedPacket(..))) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        let packet = VariablePacket::decode_with_options(&mut decode_buf, &DecodeOptions::lenient()).unwrap();
+        assert_eq!(ControlType::PublishAcknowledgement, packet.control_type());
+        assert_eq!(Some(10), packet.packet_identifier());
+        let packet = VariablePacket::decode_with_options(&mut decode_buf, &DecodeOptions::lenient()).unwrap();
+        assert_eq!(VariablePacket::new(PingreqPacket::new()), packet);
+    }
+
+    #[test]
+    fn test_decode_options_allow_unknown_packets() {
+        let encoded_data = b"\xf0\x02\x01\x02\xc0\x00";
+
+        match VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &DecodeOptions::new()) {
+            Err(VariablePacketError::FixedHeaderError(
+                    FixedHeaderError::PacketTypeError(PacketTypeError::ReservedType(15)))) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        let packet = VariablePacket::decode_with_options(&mut decode_buf, &DecodeOptions::lenient()).unwrap();
+        assert_eq!(VariablePacket::new(PingreqPacket::new()), packet);
+        assert_eq!(encoded_data.len() as u64, decode_buf.position());
+    }
+
+    #[test]
+    fn test_decode_options_minimal_remaining_length() {
+        let encoded_data = b"\xc0\x80\x00";
+
+        let packet = VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &DecodeOptions::new()).unwrap();
+        assert_eq!(VariablePacket::new(PingreqPacket::new()), packet);
+
+        let options = DecodeOptions::new().minimal_remaining_length(true);
+        match VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &options) {
+            Err(VariablePacketError::FixedHeaderError(FixedHeaderError::NonMinimalRemainingLength)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+}
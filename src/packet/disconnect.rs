@@ -1,20 +1,92 @@
+use std::prelude::v1::*;
 use std::io::{Read, Write};
-
+use std::cmp;
+use std::fmt;
 
 use control::{FixedHeader, PacketType, ControlType};
+use control::variable_header::{DisconnectReasonCode, Properties, PropertyType, ProtocolVersion};
 use packet::{Packet, PacketError};
+use {Encodable, Decodable};
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct DisconnectPacket {
     fixed_header: FixedHeader,
+    reason_code: DisconnectReasonCode,
+    properties: Properties,
+    /// Length of a decoded packet that spelled out fields it could have left out
+    wire_length: u32,
     payload: (),
 }
 
 impl DisconnectPacket {
+    /// The empty DISCONNECT of MQTT 3.1.1, which is a normal disconnection in MQTT 5
     pub fn new() -> DisconnectPacket {
-        DisconnectPacket {
+        DisconnectPacket::new_with_reason(DisconnectReasonCode::NormalDisconnection)
+    }
+
+    /// MQTT 5 DISCONNECT
+    pub fn new_with_reason(reason_code: DisconnectReasonCode) -> DisconnectPacket {
+        let mut pk = DisconnectPacket {
             fixed_header: FixedHeader::new(PacketType::with_default(ControlType::Disconnect), 0),
+            reason_code: reason_code,
+            properties: Properties::new(),
+            wire_length: 0,
             payload: (),
+        };
+        pk.fixed_header.remaining_length = pk.encoded_variable_headers_length();
+        pk
+    }
+
+    /// Reason Code 0x95, sent after receiving a packet larger than our Maximum Packet Size
+    pub fn packet_too_large() -> DisconnectPacket {
+        DisconnectPacket::new_with_reason(DisconnectReasonCode::PacketTooLarge)
+    }
+
+    pub fn reason_code(&self) -> DisconnectReasonCode {
+        self.reason_code
+    }
+
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    pub fn set_properties(&mut self, properties: Properties) {
+        self.properties = properties;
+        self.fixed_header.remaining_length = self.encoded_variable_headers_length();
+    }
+
+    pub fn session_expiry_interval(&self) -> Option<u32> {
+        self.properties.session_expiry_interval()
+    }
+
+    pub fn reason_string(&self) -> Option<&str> {
+        self.properties.reason_string()
+    }
+
+    pub fn set_reason_string(&mut self, reason: Option<String>) {
+        self.properties.set_reason_string(reason);
+        self.fixed_header.remaining_length = self.encoded_variable_headers_length();
+    }
+
+    pub fn server_reference(&self) -> Option<&str> {
+        self.properties.server_reference()
+    }
+}
+
+/// Properties allowed in DISCONNECT
+const DISCONNECT_PROPERTIES: &'static [PropertyType] = &[
+    PropertyType::SessionExpiryInterval,
+    PropertyType::ReasonString,
+    PropertyType::UserProperty,
+    PropertyType::ServerReference,
+];
+
+impl fmt::Display for DisconnectPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.reason_code == DisconnectReasonCode::NormalDisconnection {
+            write!(f, "DISCONNECT")
+        } else {
+            write!(f, "DISCONNECT(reason={})", self.reason_code.to_u8())
         }
     }
 }
@@ -22,6 +94,8 @@ impl DisconnectPacket {
 impl<'a> Packet<'a> for DisconnectPacket {
     type Payload = ();
 
+    const CONTROL_TYPE: ControlType = ControlType::Disconnect;
+
     fn fixed_header(&self) -> &FixedHeader {
         &self.fixed_header
     }
@@ -30,18 +104,223 @@ impl<'a> Packet<'a> for DisconnectPacket {
         &self.payload
     }
 
-    fn encode_variable_headers<W: Write>(&self, _writer: &mut W) -> Result<(), PacketError<'a, Self>> {
+    fn encode_variable_headers<W: Write>(&self, writer: &mut W) -> Result<(), PacketError<'a, Self>> {
+        let length = self.encoded_variable_headers_length();
+        if length >= 1 {
+            try!(self.reason_code.encode(writer));
+        }
+        if length >= 2 {
+            try!(self.properties.encode(writer));
+        }
         Ok(())
     }
 
+    // The shortest of the three forms of MQTT 5 section 3.14.2, unless the packet was decoded
+    // from a longer one
     fn encoded_variable_headers_length(&self) -> u32 {
-        0
+        if !self.properties.is_empty() {
+            self.reason_code.encoded_length() + self.properties.encoded_length()
+        } else if self.reason_code != DisconnectReasonCode::NormalDisconnection {
+            cmp::max(self.reason_code.encoded_length(), self.wire_length)
+        } else {
+            self.wire_length
+        }
     }
 
     fn decode_packet<R: Read>(_reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
+        if fixed_header.remaining_length != 0 {
+            return Err(PacketError::MalformedPacket("DISCONNECT packet must not have a body".to_owned()));
+        }
+
         Ok(DisconnectPacket {
             fixed_header: fixed_header,
+            reason_code: DisconnectReasonCode::NormalDisconnection,
+            properties: Properties::new(),
+            wire_length: 0,
             payload: (),
         })
     }
+
+    fn decode_packet_with_version<R: Read>(reader: &mut R, fixed_header: FixedHeader, version: ProtocolVersion)
+            -> Result<Self, PacketError<'a, Self>> {
+        if version != ProtocolVersion::V5 {
+            return DisconnectPacket::decode_packet(reader, fixed_header);
+        }
+
+        let reason_code = if fixed_header.remaining_length >= 1 {
+            try!(DisconnectReasonCode::decode(reader))
+        } else {
+            DisconnectReasonCode::NormalDisconnection
+        };
+        let properties = if fixed_header.remaining_length >= 2 {
+            try!(Properties::decode(reader))
+        } else {
+            Properties::new()
+        };
+        try!(properties.check_allowed(DISCONNECT_PROPERTIES, "DISCONNECT").map_err(PacketError::MalformedPacket));
+
+        let mut pk = DisconnectPacket {
+            fixed_header: fixed_header,
+            reason_code: reason_code,
+            properties: properties,
+            wire_length: 0,
+            payload: (),
+        };
+        if pk.fixed_header.remaining_length > pk.encoded_variable_headers_length() {
+            pk.wire_length = cmp::min(pk.fixed_header.remaining_length, 2);
+        }
+        Ok(pk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use control::variable_header::{fit_within, Property};
+    use packet::{self, PacketError, VariablePacket, VariablePacketError};
+    use {Encodable, Decodable};
+
+    #[test]
+    fn test_disconnect_packet_basic() {
+        let packet = DisconnectPacket::new();
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\xe0\x00"[..], &buf[..]);
+
+        let mut decode_buf = Cursor::new(buf);
+        let decoded = DisconnectPacket::decode(&mut decode_buf).unwrap();
+
+        assert_eq!(packet, decoded);
+    }
+
+    #[test]
+    fn test_disconnect_packet_with_body() {
+        let encoded_data = b"\xe0\x02\x00\x00";
+
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        match DisconnectPacket::decode(&mut decode_buf) {
+            Err(PacketError::MalformedPacket(..)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_disconnect_variable_packet() {
+        let decoded = VariablePacket::decode(&mut Cursor::new(&b"\xe0\x00"[..])).unwrap();
+        assert_eq!(VariablePacket::new(DisconnectPacket::new()), decoded);
+
+        match VariablePacket::decode(&mut Cursor::new(&b"\xe0\x02\x00\x00"[..])) {
+            Err(VariablePacketError::DisconnectPacketError(PacketError::MalformedPacket(..))) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_disconnect_packet_mqtt_5() {
+        fn roundtrip(packet: &DisconnectPacket, expected: &[u8]) {
+            let mut buf = Vec::new();
+            packet.encode(&mut buf).unwrap();
+            assert_eq!(expected, &buf[..]);
+
+            let decoded: DisconnectPacket =
+                packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+            assert_eq!(packet, &decoded);
+        }
+
+        // Normal disconnection without properties is the empty MQTT 3.1.1 form
+        let packet = DisconnectPacket::new_with_reason(DisconnectReasonCode::NormalDisconnection);
+        assert_eq!(DisconnectPacket::new(), packet);
+        roundtrip(&packet, b"\xe0\x00");
+
+        let packet = DisconnectPacket::new_with_reason(DisconnectReasonCode::SessionTakenOver);
+        roundtrip(&packet, b"\xe0\x01\x8e");
+        assert!(packet.reason_code().is_error());
+        assert_eq!("DISCONNECT(reason=142)", packet.to_string());
+
+        let mut packet = DisconnectPacket::new_with_reason(DisconnectReasonCode::UseAnotherServer);
+        let mut properties = Properties::new();
+        properties.insert(Property::ServerReference("broker-2:1883".to_owned()));
+        packet.set_properties(properties);
+        roundtrip(&packet, b"\xe0\x12\x9c\x10\x1c\x00\x0dbroker-2:1883");
+        assert_eq!(Some("broker-2:1883"), packet.server_reference());
+
+        let mut packet = DisconnectPacket::new_with_reason(DisconnectReasonCode::DisconnectWithWillMessage);
+        let mut properties = Properties::new();
+        properties.insert(Property::SessionExpiryInterval(0));
+        packet.set_properties(properties);
+        roundtrip(&packet, b"\xe0\x07\x04\x05\x11\x00\x00\x00\x00");
+        assert!(!packet.reason_code().is_error());
+        assert_eq!(Some(0), packet.session_expiry_interval());
+
+        // A Property Length of 0 is the same as leaving it out
+        let decoded: DisconnectPacket =
+            packet::decode_with_version(&mut Cursor::new(&b"\xe0\x02\x8e\x00"[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(DisconnectReasonCode::SessionTakenOver, decoded.reason_code());
+        assert!(decoded.properties().is_empty());
+
+        let encoded_data = b"\xe0\x04\x00\x02\x24\x01";
+        match packet::decode_with_version::<DisconnectPacket, _>(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5) {
+            Err(PacketError::MalformedPacket(..)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_disconnect_packet_long_form_round_trip() {
+        for encoded_data in [&b"\xe0\x01\x00"[..], &b"\xe0\x02\x00\x00"[..], &b"\xe0\x02\x8e\x00"[..]].iter() {
+            let decoded: DisconnectPacket =
+                packet::decode_with_version(&mut Cursor::new(*encoded_data), ProtocolVersion::V5).unwrap();
+            assert_eq!(encoded_data.len() as u32, decoded.encoded_length());
+
+            let mut buf = Vec::new();
+            decoded.encode(&mut buf).unwrap();
+            assert_eq!(*encoded_data, &buf[..]);
+        }
+    }
+
+    #[test]
+    fn test_disconnect_packet_reason_string_fit_within() {
+        let mut packet = DisconnectPacket::new_with_reason(DisconnectReasonCode::ImplementationSpecificError);
+        packet.set_reason_string(Some("Storage quota of the session exceeded".to_owned()));
+        let mut properties = packet.properties().clone();
+        properties.push_user_property("node", "broker-2");
+        packet.set_properties(properties);
+        assert_eq!(Some("Storage quota of the session exceeded"), packet.reason_string());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(61, buf.len());
+
+        // The client only accepts packets of up to 32 bytes
+        let mut properties = packet.properties().clone();
+        assert!(fit_within(&mut properties, 32, packet.reason_code().encoded_length()));
+        packet.set_properties(properties);
+        assert_eq!(None, packet.reason_string());
+        assert_eq!(Some("broker-2"), packet.properties().first_user_property("node"));
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\xe0\x13\x83\x11\x26\x00\x04node\x00\x08broker-2"[..], &buf[..]);
+        assert!(buf.len() <= 32);
+
+        // Removing the Reason String also shortens the packet
+        packet.set_reason_string(Some("full".to_owned()));
+        packet.set_reason_string(None);
+        assert_eq!(19, packet.fixed_header().remaining_length);
+    }
+
+    #[test]
+    fn test_disconnect_packet_mqtt_3_1_1_strict() {
+        for encoded_data in [&b"\xe0\x01\x8e"[..], &b"\xe0\x07\x04\x05\x11\x00\x00\x00\x00"[..]].iter() {
+            match packet::decode_with_version::<DisconnectPacket, _>(&mut Cursor::new(*encoded_data),
+                                                                     ProtocolVersion::V3_1_1) {
+                Err(PacketError::MalformedPacket(..)) => {},
+                err => panic!("Unexpected result {:?}", err),
+            }
+        }
+    }
 }
@@ -1,13 +1,16 @@
+use std::prelude::v1::*;
 use std::io::{self, Read, Write};
 use std::error::Error;
 use std::fmt;
-use std::convert::From;
+use std::convert::{From, TryFrom};
 
 use control::FixedHeader;
 use control::fixed_header::FixedHeaderError;
-use control::variable_header::VariableHeaderError;
-use control::ControlType;
+use control::packet_type::PacketTypeError;
+use control::variable_header::{VariableHeaderError, PacketIdentifier, ProtocolVersion};
+use control::{ControlType, PacketType};
 use encodable::StringEncodeError;
+use topic_filter::TopicFilterError;
 use {Encodable, Decodable};
 
 pub use self::connect::ConnectPacket;
@@ -20,12 +23,20 @@ pub use self::pubcomp::PubcompPacket;
 pub use self::pingreq::PingreqPacket;
 pub use self::pingresp::PingrespPacket;
 pub use self::disconnect::DisconnectPacket;
+pub use self::auth::AuthPacket;
 pub use self::subscribe::SubscribePacket;
 pub use self::suback::SubackPacket;
 pub use self::unsuback::UnsubackPacket;
 pub use self::unsubscribe::UnsubscribePacket;
 
 pub use self::publish::QoSWithPacketIdentifier;
+pub use self::subscribe::{SubscriptionOptions, RetainHandling};
+pub use self::decode_options::DecodeOptions;
+pub use self::topic_alias::TopicAliasMap;
+pub use self::size_limit::{PacketSizeLimit, PacketTooLarge};
+
+#[macro_use]
+mod ack;
 
 pub mod connect;
 pub mod connack;
@@ -37,34 +48,109 @@ pub mod pubcomp;
 pub mod pingreq;
 pub mod pingresp;
 pub mod disconnect;
+pub mod auth;
 pub mod subscribe;
 pub mod suback;
 pub mod unsuback;
 pub mod unsubscribe;
+pub mod decode_options;
+pub mod topic_alias;
+pub mod size_limit;
+#[cfg(feature = "heapless")]
+pub mod bounded;
 
 pub trait Packet<'a> {
     type Payload: Encodable<'a> + Decodable<'a> + 'a;
 
+    /// Control type in the fixed header of this packet
+    const CONTROL_TYPE: ControlType;
+
     fn fixed_header(&self) -> &FixedHeader;
     fn payload(&self) -> &Self::Payload;
 
     fn encode_variable_headers<W: Write>(&self, writer: &mut W) -> Result<(), PacketError<'a, Self>>;
     fn encoded_variable_headers_length(&self) -> u32;
     fn decode_packet<R: Read>(reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>>;
+
+    /// Decodes the packet as sent on a connection at protocol `version`, packets whose layout
+    /// did not change in MQTT 5 ignore it
+    fn decode_packet_with_version<R: Read>(reader: &mut R, fixed_header: FixedHeader, _version: ProtocolVersion)
+            -> Result<Self, PacketError<'a, Self>> {
+        Self::decode_packet(reader, fixed_header)
+    }
+}
+
+/// Packets carrying a Packet Identifier in their variable header
+pub trait PacketIdentifierAccessor {
+    fn packet_identifier(&self) -> u16;
+    fn set_packet_identifier(&mut self, pkid: PacketIdentifier);
+}
+
+macro_rules! impl_packet_identifier_accessor {
+    ($($name:ident,)+) => {
+        $(
+            impl PacketIdentifierAccessor for $name {
+                fn packet_identifier(&self) -> u16 {
+                    $name::packet_identifier(self)
+                }
+
+                fn set_packet_identifier(&mut self, pkid: PacketIdentifier) {
+                    $name::set_packet_identifier(self, pkid.get())
+                }
+            }
+        )+
+    }
+}
+
+impl_packet_identifier_accessor! {
+    PubackPacket,
+    PubrecPacket,
+    PubrelPacket,
+    PubcompPacket,
+    SubackPacket,
+    UnsubackPacket,
+}
+
+macro_rules! impl_non_zero_packet_identifier_accessor {
+    ($($name:ident,)+) => {
+        $(
+            impl PacketIdentifierAccessor for $name {
+                fn packet_identifier(&self) -> u16 {
+                    $name::packet_identifier(self).get()
+                }
+
+                fn set_packet_identifier(&mut self, pkid: PacketIdentifier) {
+                    $name::set_packet_identifier(self, pkid)
+                }
+            }
+        )+
+    }
+}
+
+impl_non_zero_packet_identifier_accessor! {
+    SubscribePacket,
+    UnsubscribePacket,
+}
+
+/// Fixed header with the Remaining Length of the fields being encoded, the decoded one also
+/// counted the bytes a lenient decode skipped
+fn encoded_fixed_header<'a, T: Packet<'a>>(packet: &T) -> FixedHeader {
+    let remaining_length = packet.encoded_variable_headers_length() + packet.payload().encoded_length();
+    FixedHeader::new(packet.fixed_header().packet_type, remaining_length)
 }
 
 impl<'a, T: Packet<'a> + fmt::Debug + 'a> Encodable<'a> for T {
     type Err = PacketError<'a, T>;
 
     fn encode<W: Write>(&self, writer: &mut W) -> Result<(), PacketError<'a, T>> {
-        try!(self.fixed_header().encode(writer));
+        try!(encoded_fixed_header(self).encode(writer));
         try!(self.encode_variable_headers(writer));
 
         self.payload().encode(writer).map_err(PacketError::PayloadError)
     }
 
     fn encoded_length(&self) -> u32 {
-        self.fixed_header().encoded_length()
+        encoded_fixed_header(self).encoded_length()
             + self.encoded_variable_headers_length()
             + self.payload().encoded_length()
     }
@@ -76,15 +162,62 @@ impl<'a, T: Packet<'a> + fmt::Debug + 'a> Decodable<'a> for T {
 
     fn decode_with<R: Read>(reader: &mut R, fixed_header: Option<FixedHeader>)
             -> Result<Self, PacketError<'a, Self>> {
-        let fixed_header: FixedHeader =
-            if let Some(hdr) = fixed_header {
-                hdr
-            } else {
-                try!(Decodable::decode(reader))
-            };
+        decode_packet(reader, fixed_header, ProtocolVersion::V3_1_1, true)
+    }
+}
+
+/// Decodes a packet of type `T` received on a connection at protocol `version`
+pub fn decode_with_version<'a, T, R>(reader: &mut R, version: ProtocolVersion) -> Result<T, PacketError<'a, T>>
+    where T: Packet<'a> + fmt::Debug + 'a,
+          R: Read
+{
+    decode_packet(reader, None, version, true)
+}
+
+fn decode_packet<'a, T, R>(reader: &mut R, fixed_header: Option<FixedHeader>, version: ProtocolVersion,
+                           strict_length: bool) -> Result<T, PacketError<'a, T>>
+    where T: Packet<'a> + fmt::Debug + 'a,
+          R: Read
+{
+    let fixed_header: FixedHeader =
+        if let Some(hdr) = fixed_header {
+            debug_assert_eq!(hdr.packet_type.control_type, <T as Packet<'a>>::CONTROL_TYPE);
+            hdr
+        } else {
+            let (type_val, remaining_len) = try!(FixedHeader::decode_raw(reader, false));
+            let packet_type = try!(PacketType::from_u8_unchecked_flags_with_version(type_val, version)
+                                       .map_err(FixedHeaderError::from));
+            FixedHeader::new(packet_type, remaining_len)
+        };
+    let reader = &mut reader.take(fixed_header.remaining_length as u64);
+
+    try!(validate_fixed_header_flags(&fixed_header));
+
+    let packet = try!(<T as Packet>::decode_packet_with_version(reader, fixed_header, version));
+
+    // Bytes left within the Remaining Length are skipped so that the next packet can still be read
+    let trailing = try!(io::copy(reader, &mut io::sink()));
+    if reader.limit() != 0 {
+        return Err(PacketError::IoError(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated packet")));
+    }
+    if trailing != 0 && strict_length {
+        return Err(PacketError::MalformedPacket(format!("{} bytes left after the packet", trailing)));
+    }
+    Ok(packet)
+}
 
-        <Self as Packet>::decode_packet(reader, fixed_header)
+/// MQTT-2.2.2-2: Flags other than the ones of PUBLISH are reserved and must be exactly
+/// the ones defined by the spec
+fn validate_fixed_header_flags<'a, T: Packet<'a>>(fixed_header: &FixedHeader) -> Result<(), PacketError<'a, T>> {
+    let packet_type = fixed_header.packet_type;
+    if !packet_type.has_valid_flags() {
+        return Err(PacketError::InvalidFixedHeaderFlags {
+            control_type: packet_type.control_type,
+            flags: packet_type.flags,
+        });
     }
+
+    Ok(())
 }
 
 #[derive(Debug)]
@@ -95,6 +228,16 @@ pub enum PacketError<'a, T: Packet<'a>> {
     MalformedPacket(String),
     StringEncodeError(StringEncodeError),
     IoError(io::Error),
+    EmptySubscription,
+    EmptyUnsubscription,
+    InvalidQoS(u8),
+    InvalidDupFlag,
+    WildcardInPublishTopic { topic: String },
+    WildcardInResponseTopic { topic: String },
+    InvalidTopicFilter { index: usize, reason: TopicFilterError },
+    NoLocalOnSharedSubscription { index: usize },
+    InvalidFixedHeaderFlags { control_type: ControlType, flags: u8 },
+    CapacityExceeded,
 }
 
 impl<'a, T: Packet<'a>> fmt::Display for PacketError<'a, T> {
@@ -106,6 +249,21 @@ impl<'a, T: Packet<'a>> fmt::Display for PacketError<'a, T> {
             &PacketError::MalformedPacket(ref err) => err.fmt(f),
             &PacketError::StringEncodeError(ref err) => err.fmt(f),
             &PacketError::IoError(ref err) => err.fmt(f),
+            &PacketError::EmptySubscription => write!(f, "SUBSCRIBE without topic filters"),
+            &PacketError::EmptyUnsubscription => write!(f, "UNSUBSCRIBE without topic filters"),
+            &PacketError::InvalidQoS(qos) => write!(f, "Invalid QoS ({})", qos),
+            &PacketError::InvalidDupFlag => write!(f, "DUP flag is set on a QoS 0 PUBLISH (MQTT-3.3.1-2)"),
+            &PacketError::WildcardInPublishTopic { ref topic } =>
+                write!(f, "Wildcard in PUBLISH topic name {:?} (MQTT-3.3.2-2)", topic),
+            &PacketError::WildcardInResponseTopic { ref topic } =>
+                write!(f, "Wildcard in Response Topic {:?}", topic),
+            &PacketError::InvalidTopicFilter { index, ref reason } =>
+                write!(f, "Invalid topic filter at index {}: {}", index, reason),
+            &PacketError::NoLocalOnSharedSubscription { index } =>
+                write!(f, "No Local is set on the shared subscription at index {}", index),
+            &PacketError::InvalidFixedHeaderFlags { control_type, flags } =>
+                write!(f, "Invalid fixed header flags {:#06b} for {:?}", flags, control_type),
+            &PacketError::CapacityExceeded => write!(f, "Packet does not fit in the capacity of the bounded packet type"),
         }
     }
 }
@@ -119,6 +277,16 @@ impl<'a, T: Packet<'a> + fmt::Debug> Error for PacketError<'a, T> {
             &PacketError::MalformedPacket(ref err) => &err[..],
             &PacketError::StringEncodeError(ref err) => err.description(),
             &PacketError::IoError(ref err) => err.description(),
+            &PacketError::EmptySubscription => "SUBSCRIBE without topic filters",
+            &PacketError::EmptyUnsubscription => "UNSUBSCRIBE without topic filters",
+            &PacketError::InvalidQoS(..) => "Invalid QoS",
+            &PacketError::InvalidDupFlag => "DUP flag is set on a QoS 0 PUBLISH",
+            &PacketError::WildcardInPublishTopic { .. } => "Wildcard in PUBLISH topic name",
+            &PacketError::WildcardInResponseTopic { .. } => "Wildcard in Response Topic",
+            &PacketError::InvalidTopicFilter { .. } => "Invalid topic filter",
+            &PacketError::NoLocalOnSharedSubscription { .. } => "No Local on a shared subscription",
+            &PacketError::InvalidFixedHeaderFlags { .. } => "Invalid fixed header flags",
+            &PacketError::CapacityExceeded => "Capacity of the bounded packet type exceeded",
         }
     }
 
@@ -130,6 +298,16 @@ impl<'a, T: Packet<'a> + fmt::Debug> Error for PacketError<'a, T> {
             &PacketError::MalformedPacket(..) => None,
             &PacketError::StringEncodeError(ref err) => Some(err),
             &PacketError::IoError(ref err) => Some(err),
+            &PacketError::EmptySubscription => None,
+            &PacketError::EmptyUnsubscription => None,
+            &PacketError::InvalidQoS(..) => None,
+            &PacketError::InvalidDupFlag => None,
+            &PacketError::WildcardInPublishTopic { .. } => None,
+            &PacketError::WildcardInResponseTopic { .. } => None,
+            &PacketError::InvalidTopicFilter { ref reason, .. } => Some(reason),
+            &PacketError::NoLocalOnSharedSubscription { .. } => None,
+            &PacketError::InvalidFixedHeaderFlags { .. } => None,
+            &PacketError::CapacityExceeded => None,
         }
     }
 }
@@ -160,7 +338,7 @@ impl<'a, T: Packet<'a>> From<StringEncodeError> for PacketError<'a, T> {
 
 macro_rules! impl_variable_packet {
     ($($name:ident & $errname:ident => $hdr:ident,)+) => {
-        #[derive(Debug, Eq, PartialEq)]
+        #[derive(Debug, Eq, PartialEq, Clone)]
         pub enum VariablePacket {
             $(
                 $name($name),
@@ -173,34 +351,69 @@ macro_rules! impl_variable_packet {
                     VariablePacket::$name(pk)
                 }
             }
+
+            impl TryFrom<VariablePacket> for $name {
+                type Error = VariablePacket;
+
+                fn try_from(pk: VariablePacket) -> Result<$name, VariablePacket> {
+                    match pk {
+                        VariablePacket::$name(pk) => Ok(pk),
+                        #[allow(unreachable_patterns)]
+                        pk => Err(pk),
+                    }
+                }
+            }
         )+
 
-        impl<'a> Encodable<'a> for VariablePacket {
-            type Err = VariablePacketError<'a>;
+        impl fmt::Display for VariablePacket {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match self {
+                    $(
+                        &VariablePacket::$name(ref pk) => pk.fmt(f),
+                    )+
+                }
+            }
+        }
 
-            fn encode<W: Write>(&self, writer: &mut W) -> Result<(), VariablePacketError<'a>> {
+        impl VariablePacket {
+            pub fn control_type(&self) -> ControlType {
                 match self {
                     $(
-                        &VariablePacket::$name(ref pk) => pk.encode(writer).map_err(From::from),
+                        &VariablePacket::$name(..) => ControlType::$hdr,
                     )+
                 }
             }
 
-            fn encoded_length(&self) -> u32 {
+            #[cfg(feature = "tracing")]
+            fn fixed_header(&self) -> &FixedHeader {
                 match self {
                     $(
-                        &VariablePacket::$name(ref pk) => pk.encoded_length(),
+                        &VariablePacket::$name(ref pk) => pk.fixed_header(),
                     )+
                 }
             }
-        }
 
-        impl<'a> Decodable<'a> for VariablePacket {
-            type Err = VariablePacketError<'a>;
-            type Cond = FixedHeader;
+            /// Packet Identifier of the packet, `None` for packets without one (including QoS 0 PUBLISH)
+            pub fn packet_identifier(&self) -> Option<u16> {
+                match self {
+                    $(
+                        &VariablePacket::$name(ref pk) => VariablePacketAccessor::packet_identifier(pk),
+                    )+
+                }
+            }
 
-            fn decode_with<R: Read>(reader: &mut R, fixed_header: Option<FixedHeader>)
-                    -> Result<VariablePacket, Self::Err> {
+            /// Topic name if it is a PUBLISH packet
+            pub fn publish_topic(&self) -> Option<&str> {
+                match self {
+                    $(
+                        &VariablePacket::$name(ref pk) => VariablePacketAccessor::publish_topic(pk),
+                    )+
+                }
+            }
+
+            fn decode_untraced<'a, R: Read>(reader: &mut R, fixed_header: Option<FixedHeader>,
+                                            version: ProtocolVersion, strict_length: bool)
+                    -> Result<VariablePacket, VariablePacketError<'a>> {
                 let fixed_header = match fixed_header {
                     Some(fh) => fh,
                     None => try!(FixedHeader::decode(reader)),
@@ -210,20 +423,63 @@ macro_rules! impl_variable_packet {
                 match fixed_header.packet_type.control_type {
                     $(
                         ControlType::$hdr => {
-                            let pk = try!(<$name as Packet<'a>>::decode_packet(reader, fixed_header));
+                            let pk = try!(decode_packet::<$name, _>(reader, Some(fixed_header), version, strict_length));
                             Ok(VariablePacket::$name(pk))
                         }
                     )+
 
+                    #[allow(unreachable_patterns)]
                     _ => return Err(VariablePacketError::UnrecognizedFixedHeader(fixed_header)),
                 }
             }
         }
 
+        impl<'a> Encodable<'a> for VariablePacket {
+            type Err = VariablePacketError<'a>;
+
+            fn encode<W: Write>(&self, writer: &mut W) -> Result<(), VariablePacketError<'a>> {
+                let result = match self {
+                    $(
+                        &VariablePacket::$name(ref pk) => pk.encode(writer).map_err(From::from),
+                    )+
+                };
+
+                #[cfg(feature = "tracing")]
+                {
+                    if let Err(ref err) = result {
+                        ::tracing::debug!(control_type = ?self.control_type(), error = %err, "Failed to encode packet");
+                    }
+                }
+                result
+            }
+
+            fn encoded_length(&self) -> u32 {
+                match self {
+                    $(
+                        &VariablePacket::$name(ref pk) => pk.encoded_length(),
+                    )+
+                }
+            }
+        }
+
+        impl<'a> Decodable<'a> for VariablePacket {
+            type Err = VariablePacketError<'a>;
+            type Cond = FixedHeader;
+
+            fn decode_with<R: Read>(reader: &mut R, fixed_header: Option<FixedHeader>)
+                    -> Result<VariablePacket, Self::Err> {
+                traced_decode(None, || VariablePacket::decode_untraced(reader, fixed_header, ProtocolVersion::V3_1_1, true))
+            }
+        }
+
         #[derive(Debug)]
         pub enum VariablePacketError<'a> {
             FixedHeaderError(FixedHeaderError),
             UnrecognizedFixedHeader(FixedHeader),
+            IoError(io::Error),
+            PacketTooLarge(u32),
+            NullCharacter,
+            UnknownReasonCode(u8),
             $(
                 $errname(PacketError<'a, $name>),
             )+
@@ -248,6 +504,10 @@ macro_rules! impl_variable_packet {
                 match self {
                     &VariablePacketError::FixedHeaderError(ref err) => err.fmt(f),
                     &VariablePacketError::UnrecognizedFixedHeader(..) => write!(f, "Unrecognized fixed header"),
+                    &VariablePacketError::IoError(ref err) => err.fmt(f),
+                    &VariablePacketError::PacketTooLarge(size) => write!(f, "Packet too large ({} bytes)", size),
+                    &VariablePacketError::NullCharacter => write!(f, "String contains the null character"),
+                    &VariablePacketError::UnknownReasonCode(code) => write!(f, "Unknown reason code 0x{:02x}", code),
                     $(
                         &VariablePacketError::$errname(ref err) => err.fmt(f),
                     )+
@@ -260,6 +520,10 @@ macro_rules! impl_variable_packet {
                 match self {
                     &VariablePacketError::FixedHeaderError(ref err) => err.description(),
                     &VariablePacketError::UnrecognizedFixedHeader(..) => "Unrecognized fixed header",
+                    &VariablePacketError::IoError(ref err) => err.description(),
+                    &VariablePacketError::PacketTooLarge(..) => "Packet too large",
+                    &VariablePacketError::NullCharacter => "String contains the null character",
+                    &VariablePacketError::UnknownReasonCode(..) => "Unknown reason code",
                     $(
                         &VariablePacketError::$errname(ref err) => err.description(),
                     )+
@@ -270,6 +534,10 @@ macro_rules! impl_variable_packet {
                 match self {
                     &VariablePacketError::FixedHeaderError(ref err) => Some(err),
                     &VariablePacketError::UnrecognizedFixedHeader(..) => None,
+                    &VariablePacketError::IoError(ref err) => Some(err),
+                    &VariablePacketError::PacketTooLarge(..) => None,
+                    &VariablePacketError::NullCharacter => None,
+                    &VariablePacketError::UnknownReasonCode(..) => None,
                     $(
                         &VariablePacketError::$errname(ref err) => Some(err),
                     )+
@@ -297,6 +565,67 @@ impl_variable_packet! {
 
     UnsubscribePacket   & UnsubscribePacketError    => Unsubscribe,
     UnsubackPacket      & UnsubackPacketError       => UnsubscribeAcknowledgement,
+
+    DisconnectPacket    & DisconnectPacketError     => Disconnect,
+
+    AuthPacket          & AuthPacketError           => Auth,
+}
+
+/// Parts of the `VariablePacket` accessors that differ per packet type
+///
+/// Every packet type has to implement it, so a new variant cannot fall through to a default.
+trait VariablePacketAccessor {
+    fn packet_identifier(&self) -> Option<u16>;
+    fn publish_topic(&self) -> Option<&str>;
+}
+
+macro_rules! impl_variable_packet_accessor {
+    ($($name:ident($pk:ident) => $pkid:expr, $topic:expr;)+) => {
+        $(
+            impl VariablePacketAccessor for $name {
+                #[allow(unused_variables)]
+                fn packet_identifier(&self) -> Option<u16> {
+                    let $pk = self;
+                    $pkid
+                }
+
+                #[allow(unused_variables)]
+                fn publish_topic(&self) -> Option<&str> {
+                    let $pk = self;
+                    $topic
+                }
+            }
+        )+
+    }
+}
+
+impl_variable_packet_accessor! {
+    ConnectPacket(pk)       => None, None;
+    ConnackPacket(pk)       => None, None;
+
+    PublishPacket(pk)       => match pk.qos() {
+                                    QoSWithPacketIdentifier::Level0 => None,
+                                    QoSWithPacketIdentifier::Level1(pkid) => Some(pkid.get()),
+                                    QoSWithPacketIdentifier::Level2(pkid) => Some(pkid.get()),
+                                },
+                                Some(pk.topic_name());
+    PubackPacket(pk)        => Some(pk.packet_identifier()), None;
+    PubrecPacket(pk)        => Some(pk.packet_identifier()), None;
+    PubrelPacket(pk)        => Some(pk.packet_identifier()), None;
+    PubcompPacket(pk)       => Some(pk.packet_identifier()), None;
+
+    PingreqPacket(pk)       => None, None;
+    PingrespPacket(pk)      => None, None;
+
+    SubscribePacket(pk)     => Some(pk.packet_identifier().get()), None;
+    SubackPacket(pk)        => Some(pk.packet_identifier()), None;
+
+    UnsubscribePacket(pk)   => Some(pk.packet_identifier().get()), None;
+    UnsubackPacket(pk)      => Some(pk.packet_identifier()), None;
+
+    DisconnectPacket(pk)    => None, None;
+
+    AuthPacket(pk)          => None, None;
 }
 
 impl VariablePacket {
@@ -305,6 +634,188 @@ impl VariablePacket {
     {
         From::from(t)
     }
+
+    /// Unwraps the packet of type `T`, or gives back the packet itself if it is of another type
+    pub fn expect<'a, T>(self) -> Result<T, VariablePacket>
+        where T: Packet<'a> + TryFrom<VariablePacket, Error = VariablePacket>
+    {
+        if self.control_type() != T::CONTROL_TYPE {
+            return Err(self);
+        }
+
+        T::try_from(self)
+    }
+
+    /// Decodes a packet with the validations configured by `options`
+    pub fn decode_with_options<'a, R: Read>(reader: &mut R, options: &DecodeOptions)
+            -> Result<VariablePacket, VariablePacketError<'a>> {
+        traced_decode(options.connection, || VariablePacket::decode_with_options_untraced(reader, options))
+    }
+
+    fn decode_with_options_untraced<'a, R: Read>(reader: &mut R, options: &DecodeOptions)
+            -> Result<VariablePacket, VariablePacketError<'a>> {
+        loop {
+            let (type_val, remaining_len) = try!(FixedHeader::decode_raw(reader, options.minimal_remaining_length));
+
+            let packet_type = match PacketType::from_u8_unchecked_flags_with_version(type_val, options.protocol_version) {
+                Ok(packet_type) => packet_type,
+                Err(PacketTypeError::ReservedType(..)) if options.allow_unknown_packets => {
+                    let skipped = try!(io::copy(&mut reader.take(remaining_len as u64), &mut io::sink())
+                                           .map_err(VariablePacketError::IoError));
+                    if skipped != remaining_len as u64 {
+                        return Err(VariablePacketError::IoError(
+                                io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated packet")));
+                    }
+                    continue;
+                },
+                Err(err) => return Err(From::from(FixedHeaderError::from(err))),
+            };
+
+            let mut fixed_header = FixedHeader::new(packet_type, remaining_len);
+
+            if let Some(max_packet_size) = options.max_packet_size {
+                let size = fixed_header.encoded_length() + remaining_len;
+                if size > max_packet_size {
+                    return Err(VariablePacketError::PacketTooLarge(size));
+                }
+            }
+
+            if !options.strict_flags {
+                if !fixed_header.packet_type.has_valid_flags() {
+                    fixed_header.packet_type = PacketType::with_default(packet_type.control_type);
+                }
+
+                // DUP flag of a QoS 0 PUBLISH
+                if packet_type.control_type == ControlType::Publish && packet_type.flags & 0x0E == 0x08 {
+                    fixed_header.packet_type.flags &= !0x08;
+                }
+            }
+
+            let packet = try!(VariablePacket::decode_untraced(reader, Some(fixed_header), options.protocol_version,
+                                                              options.strict_length));
+
+            if options.strict_utf8 && packet.contains_null_character() {
+                return Err(VariablePacketError::NullCharacter);
+            }
+
+            if options.strict_reason_codes {
+                if let Some(code) = packet.unknown_reason_code() {
+                    return Err(VariablePacketError::UnknownReasonCode(code));
+                }
+            }
+
+            return Ok(packet);
+        }
+    }
+
+    fn contains_null_character(&self) -> bool {
+        match self {
+            &VariablePacket::ConnectPacket(ref pk) => {
+                pk.client_identifier().contains('\0')
+                    || pk.will().map_or(false, |will| will.topic.0.contains('\0'))
+                    || pk.user_name().map_or(false, |name| name.contains('\0'))
+            },
+            &VariablePacket::PublishPacket(ref pk) => pk.topic_name().contains('\0'),
+            &VariablePacket::SubscribePacket(ref pk) => pk.subscriptions().any(|(filter, _)| filter.as_str().contains('\0')),
+            &VariablePacket::UnsubscribePacket(ref pk) => pk.topic_filters().any(|filter| filter.as_str().contains('\0')),
+            _ => false,
+        }
+    }
+
+    /// Reason Code of an MQTT 5 packet that is not defined by the spec
+    fn unknown_reason_code(&self) -> Option<u8> {
+        match self {
+            &VariablePacket::ConnackPacket(ref pk) if pk.is_mqtt5() => {
+                let code = pk.connect_reason_code();
+                if code.is_defined() { None } else { Some(code.to_u8()) }
+            },
+            &VariablePacket::DisconnectPacket(ref pk) if !pk.reason_code().is_defined() => Some(pk.reason_code().to_u8()),
+            &VariablePacket::AuthPacket(ref pk) if !pk.reason_code().is_defined() => Some(pk.reason_code().to_u8()),
+            &VariablePacket::PubackPacket(ref pk) if !pk.reason_code().is_defined() => Some(pk.reason_code().to_u8()),
+            &VariablePacket::PubrecPacket(ref pk) if !pk.reason_code().is_defined() => Some(pk.reason_code().to_u8()),
+            &VariablePacket::PubrelPacket(ref pk) if !pk.reason_code().is_defined() => Some(pk.reason_code().to_u8()),
+            &VariablePacket::PubcompPacket(ref pk) if !pk.reason_code().is_defined() => Some(pk.reason_code().to_u8()),
+            &VariablePacket::SubackPacket(ref pk) if pk.is_mqtt5() =>
+                pk.reason_codes().iter().find(|code| !code.is_defined()).map(|code| code.to_u8()),
+            &VariablePacket::UnsubackPacket(ref pk) =>
+                pk.reason_codes().and_then(|codes| codes.iter().find(|code| !code.is_defined())).map(|code| code.to_u8()),
+            _ => None,
+        }
+    }
+
+}
+
+// Runs `decode` in a span of `connection` and emits an event with its result. Never records
+// the payload, it may carry credentials or application data
+#[cfg(feature = "tracing")]
+fn traced_decode<'a, F>(connection: Option<u64>, decode: F) -> Result<VariablePacket, VariablePacketError<'a>>
+    where F: FnOnce() -> Result<VariablePacket, VariablePacketError<'a>>
+{
+    let span = ::tracing::trace_span!("decode", connection = connection);
+    let _entered = span.enter();
+
+    let result = decode();
+    match result {
+        Ok(ref packet) => ::tracing::trace!(control_type = ?packet.control_type(),
+                                            remaining_length = packet.fixed_header().remaining_length,
+                                            packet_identifier = packet.packet_identifier(),
+                                            "Decoded packet"),
+        Err(ref err) => ::tracing::debug!(error = %err, "Failed to decode packet"),
+    }
+    result
+}
+
+#[cfg(not(feature = "tracing"))]
+fn traced_decode<'a, F>(_connection: Option<u64>, decode: F) -> Result<VariablePacket, VariablePacketError<'a>>
+    where F: FnOnce() -> Result<VariablePacket, VariablePacketError<'a>>
+{
+    decode()
+}
+
+/// Decodes the next packet from `reader` and checks that it is of type `T`
+pub fn read_expected<'a, T, R>(reader: &mut R) -> Result<T, ReadExpectedError<'a>>
+    where T: Packet<'a> + TryFrom<VariablePacket, Error = VariablePacket>,
+          R: Read
+{
+    let packet = try!(VariablePacket::decode(reader));
+    packet.expect::<T>().map_err(ReadExpectedError::UnexpectedPacket)
+}
+
+#[derive(Debug)]
+pub enum ReadExpectedError<'a> {
+    VariablePacketError(VariablePacketError<'a>),
+    UnexpectedPacket(VariablePacket),
+}
+
+impl<'a> From<VariablePacketError<'a>> for ReadExpectedError<'a> {
+    fn from(err: VariablePacketError<'a>) -> ReadExpectedError<'a> {
+        ReadExpectedError::VariablePacketError(err)
+    }
+}
+
+impl<'a> fmt::Display for ReadExpectedError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ReadExpectedError::VariablePacketError(ref err) => err.fmt(f),
+            &ReadExpectedError::UnexpectedPacket(ref pk) => write!(f, "Unexpected packet {}", pk),
+        }
+    }
+}
+
+impl<'a> Error for ReadExpectedError<'a> {
+    fn description(&self) -> &str {
+        match self {
+            &ReadExpectedError::VariablePacketError(ref err) => err.description(),
+            &ReadExpectedError::UnexpectedPacket(..) => "Unexpected packet",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match self {
+            &ReadExpectedError::VariablePacketError(ref err) => Some(err),
+            &ReadExpectedError::UnexpectedPacket(..) => None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -313,7 +824,93 @@ mod test {
 
     use std::io::Cursor;
 
-    use {Encodable, Decodable};
+    use control::ControlType;
+    use control::variable_header::AuthReasonCode;
+    use {Encodable, Decodable, QualityOfService, TopicFilter};
+
+    // Inputs that used to panic with an arithmetic overflow or allocate the declared
+    // remaining length up front
+    const MALFORMED_PACKETS: &'static [&'static [u8]] = &[
+        b"\x30\x02\x00\x05hello",
+        b"\x32\x06\x00\x03a/b\x00",
+        b"\x30\x06\x00\x01ab",
+        b"\x30\xff\xff\xff\x7f\x00\x01a",
+        b"\x82\x00\x00\x0a\x00\x03a/b\x00",
+        b"\x82\x07\x00\x0a\x00\x03a/b\x00",
+        b"\xa2\x01\x00\x0a\x00\x01a",
+        b"\xa2\x06\x00\x0a\x00\x03a/b",
+        b"\x90\x01\x00\x0a\x00",
+        b"\x10\x05\x00\x04MQTT\x04\x02\x00\x3c\x00\x01a",
+    ];
+
+    #[test]
+    fn test_decode_malformed_packets() {
+        for encoded_data in MALFORMED_PACKETS.iter() {
+            assert!(VariablePacket::decode(&mut Cursor::new(&encoded_data[..])).is_err(),
+                    "{:?} was decoded", encoded_data);
+
+            let mut decode_buf = Cursor::new(&encoded_data[..]);
+            let fixed_header = FixedHeader::decode(&mut decode_buf).unwrap();
+            let decoded = match fixed_header.packet_type.control_type {
+                ControlType::Connect => ConnectPacket::decode_with(&mut decode_buf, Some(fixed_header)).is_ok(),
+                ControlType::Publish => PublishPacket::decode_with(&mut decode_buf, Some(fixed_header)).is_ok(),
+                ControlType::Subscribe => SubscribePacket::decode_with(&mut decode_buf, Some(fixed_header)).is_ok(),
+                ControlType::SubscribeAcknowledgement =>
+                    SubackPacket::decode_with(&mut decode_buf, Some(fixed_header)).is_ok(),
+                ControlType::Unsubscribe =>
+                    UnsubscribePacket::decode_with(&mut decode_buf, Some(fixed_header)).is_ok(),
+                _ => unreachable!(),
+            };
+            assert!(!decoded, "{:?} was decoded", encoded_data);
+        }
+    }
+
+    #[test]
+    fn test_decode_trailing_bytes() {
+        // A PUBACK claiming 4 bytes followed by a PINGREQ
+        let encoded_data = b"\x40\x04\x00\x0a\x00\x00\xc0\x00";
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        match VariablePacket::decode(&mut decode_buf) {
+            Err(VariablePacketError::PubackPacketError(PacketError::MalformedPacket(..))) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+        // The whole Remaining Length was read, the next packet is intact
+        assert_eq!(VariablePacket::new(PingreqPacket::new()), VariablePacket::decode(&mut decode_buf).unwrap());
+
+        let encoded_data = b"\x20\x03\x00\x00\x00";
+        match VariablePacket::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(VariablePacketError::ConnackPacketError(PacketError::MalformedPacket(..))) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+        match ConnackPacket::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(PacketError::MalformedPacket(..)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        // A PUBCOMP claiming more bytes than the stream has
+        let encoded_data = b"\x70\x38\x35\xbd";
+        match VariablePacket::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(VariablePacketError::PubcompPacketError(PacketError::IoError(ref err)))
+                if err.kind() == io::ErrorKind::UnexpectedEof => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_encode_recomputes_remaining_length() {
+        let cases: &[(&[u8], &[u8])] = &[
+            (b"\x40\x04\x00\x0a\x00\x00", b"\x40\x02\x00\x0a"),
+            (b"\x20\x03\x00\x00\x00", b"\x20\x02\x00\x00"),
+        ];
+        for &(encoded_data, expected) in cases.iter() {
+            let packet = VariablePacket::decode_with_options(&mut Cursor::new(encoded_data), &DecodeOptions::lenient())
+                .unwrap();
+            let mut buf = Vec::new();
+            packet.encode(&mut buf).unwrap();
+            assert_eq!(expected, &buf[..]);
+            assert_eq!(expected.len() as u32, packet.encoded_length());
+        }
+    }
 
     #[test]
     fn test_variable_packet_basic() {
@@ -332,4 +929,376 @@ mod test {
 
         assert_eq!(var_packet, decoded_packet);
     }
+
+    #[test]
+    fn test_reserved_fixed_header_flags() {
+        let mut buf = Vec::new();
+        PubrelPacket::new(10).encode(&mut buf).unwrap();
+        SubscribePacket::new(PacketIdentifier::new(10).unwrap(), vec![(TopicFilter::new("a/b"), QualityOfService::Level0)]).encode(&mut buf).unwrap();
+        UnsubscribePacket::new(PacketIdentifier::new(10).unwrap(), vec![TopicFilter::new("a/b")]).unwrap().encode(&mut buf).unwrap();
+        assert_eq!(&b"\x62\x02\x00\x0a\x82\x08\x00\x0a\x00\x03a/b\x00\xa2\x07\x00\x0a\x00\x03a/b"[..], &buf[..]);
+
+        let encoded_data = b"\x60\x02\x00\x0a";
+        match PubrelPacket::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(PacketError::InvalidFixedHeaderFlags { control_type: ControlType::PublishRelease, flags: 0 }) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let encoded_data = b"\x80\x08\x00\x0a\x00\x03a/b\x00";
+        match VariablePacket::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(VariablePacketError::SubscribePacketError(
+                    PacketError::InvalidFixedHeaderFlags { control_type: ControlType::Subscribe, flags: 0 })) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let encoded_data = b"\xa3\x07\x00\x0a\x00\x03a/b";
+        match VariablePacket::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(VariablePacketError::UnsubscribePacketError(
+                    PacketError::InvalidFixedHeaderFlags { control_type: ControlType::Unsubscribe, flags: 3 })) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_variable_packet_accessors() {
+        let packet = VariablePacket::new(PublishPacket::new("a/b".to_owned(),
+                                                            QoSWithPacketIdentifier::Level2(PacketIdentifier::new(10).unwrap()),
+                                                            Vec::new()));
+        assert_eq!(ControlType::Publish, packet.control_type());
+        assert_eq!(Some("a/b"), packet.publish_topic());
+
+        let packet = VariablePacket::new(DisconnectPacket::new());
+        assert_eq!(ControlType::Disconnect, packet.control_type());
+        assert_eq!(None, packet.publish_topic());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        let decoded = VariablePacket::decode(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(packet, decoded);
+    }
+
+    #[test]
+    fn test_variable_packet_display() {
+        use control::variable_header::ConnectReturnCode;
+        use packet::connect::LastWill;
+        use packet::suback::SubscribeReturnCode;
+
+        let mut connect = ConnectPacket::new("12345".to_owned());
+        connect.set_clean_session(true);
+        connect.set_keep_alive(60);
+        connect.set_user_name(Some("user".to_owned()));
+        connect.set_password_str("secret");
+        connect.set_will(Some(LastWill::new("a/b".to_owned(), b"bye".to_vec(), QualityOfService::Level1, true))).unwrap();
+
+        let mut publish = PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level1(PacketIdentifier::new(42).unwrap()), vec![0; 128]);
+        publish.set_retain(true);
+
+        let cases: Vec<(VariablePacket, &str)> = vec![
+            (VariablePacket::new(connect),
+             "CONNECT(client_id=\"12345\", keep_alive=60, clean_session, user_name=\"user\", password, \
+              will(topic=\"a/b\", qos=1, payload=3B, retain))"),
+            (VariablePacket::new(ConnackPacket::accepted(true)), "CONNACK(accepted, session_present)"),
+            (VariablePacket::new(ConnackPacket::rejected(ConnectReturnCode::NotAuthorized)), "CONNACK(rejected=5)"),
+            (VariablePacket::new(publish), "PUBLISH(topic=\"a/b\", qos=1, pkid=42, payload=128B, retain)"),
+            (VariablePacket::new(PubackPacket::new(1)), "PUBACK(pkid=1)"),
+            (VariablePacket::new(PubrecPacket::new(2)), "PUBREC(pkid=2)"),
+            (VariablePacket::new(PubrelPacket::new(3)), "PUBREL(pkid=3)"),
+            (VariablePacket::new(PubcompPacket::new(4)), "PUBCOMP(pkid=4)"),
+            (VariablePacket::new(SubscribePacket::new(PacketIdentifier::new(5).unwrap(), vec![(TopicFilter::new("a/+"), QualityOfService::Level2)])),
+             "SUBSCRIBE(pkid=5, \"a/+\"@2)"),
+            (VariablePacket::new(SubackPacket::new(5, vec![SubscribeReturnCode::MaximumQoSLevel1,
+                                                           SubscribeReturnCode::Failure])),
+             "SUBACK(pkid=5, 1, failure)"),
+            (VariablePacket::new(UnsubscribePacket::new(PacketIdentifier::new(6).unwrap(), vec![TopicFilter::new("a/#")]).unwrap()), "UNSUBSCRIBE(pkid=6, \"a/#\")"),
+            (VariablePacket::new(UnsubackPacket::new(6)), "UNSUBACK(pkid=6)"),
+            (VariablePacket::new(PingreqPacket::new()), "PINGREQ"),
+            (VariablePacket::new(PingrespPacket::new()), "PINGRESP"),
+            (VariablePacket::new(DisconnectPacket::new()), "DISCONNECT"),
+        ];
+
+        for (packet, expected) in cases {
+            assert_eq!(expected, packet.to_string());
+            assert_eq!(&format!("{}", packet.control_type())[..], expected.split('(').next().unwrap());
+        }
+    }
+
+    /// Fails every other call with `ErrorKind::Interrupted` and transfers at most one byte otherwise
+    struct InterruptingStream<T> {
+        inner: T,
+        calls: usize,
+    }
+
+    impl<T> InterruptingStream<T> {
+        fn new(inner: T) -> InterruptingStream<T> {
+            InterruptingStream {
+                inner: inner,
+                calls: 0,
+            }
+        }
+
+        fn interrupt(&mut self) -> io::Result<()> {
+            self.calls += 1;
+            if self.calls % 2 == 1 {
+                Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl<T: Read> Read for InterruptingStream<T> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            try!(self.interrupt());
+            let len = buf.len().min(1);
+            self.inner.read(&mut buf[..len])
+        }
+    }
+
+    impl<T: Write> Write for InterruptingStream<T> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            try!(self.interrupt());
+            let len = buf.len().min(1);
+            self.inner.write(&buf[..len])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn test_variable_packet_interrupted_io() {
+        let packets = vec![
+            VariablePacket::new(ConnectPacket::builder("12345")
+                                    .user_name("user")
+                                    .password(b"secret")
+                                    .will("a/b", b"bye", QualityOfService::Level1, false)
+                                    .build()
+                                    .unwrap()),
+            VariablePacket::new(PublishPacket::new("a/b".to_owned(),
+                                                   QoSWithPacketIdentifier::Level1(PacketIdentifier::new(1).unwrap()),
+                                                   vec![0; 200])),
+            VariablePacket::new(SubscribePacket::new(PacketIdentifier::new(1).unwrap(), vec![(TopicFilter::new("a/b"), QualityOfService::Level1)])),
+            VariablePacket::new(SubackPacket::new(1, vec![suback::SubscribeReturnCode::MaximumQoSLevel1])),
+            VariablePacket::new(UnsubscribePacket::new(PacketIdentifier::new(1).unwrap(), vec![TopicFilter::new("a/b")]).unwrap()),
+            VariablePacket::new(PingreqPacket::new()),
+        ];
+
+        for packet in packets {
+            let mut expected = Vec::new();
+            packet.encode(&mut expected).unwrap();
+
+            let mut writer = InterruptingStream::new(Vec::new());
+            packet.encode(&mut writer).unwrap();
+            assert_eq!(expected, writer.inner);
+
+            let mut reader = InterruptingStream::new(Cursor::new(expected));
+            assert_eq!(packet, VariablePacket::decode(&mut reader).unwrap());
+
+            let mut reader = InterruptingStream::new(Cursor::new(writer.inner));
+            assert_eq!(packet, VariablePacket::decode_with_options(&mut reader, &DecodeOptions::lenient()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_variable_packet_clone() {
+        let packets = vec![
+            VariablePacket::new(ConnectPacket::builder("12345").user_name("user").password(b"secret").build().unwrap()),
+            VariablePacket::new(ConnackPacket::accepted(false)),
+            VariablePacket::new(PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level2(PacketIdentifier::new(1).unwrap()), b"x".to_vec())),
+            VariablePacket::new(PubackPacket::new(1)),
+            VariablePacket::new(PubrecPacket::new(1)),
+            VariablePacket::new(PubrelPacket::new(1)),
+            VariablePacket::new(PubcompPacket::new(1)),
+            VariablePacket::new(SubscribePacket::new(PacketIdentifier::new(1).unwrap(), vec![(TopicFilter::new("a/b"), QualityOfService::Level1)])),
+            VariablePacket::new(SubackPacket::new(1, Vec::new())),
+            VariablePacket::new(UnsubscribePacket::new(PacketIdentifier::new(1).unwrap(), vec![TopicFilter::new("a/b")]).unwrap()),
+            VariablePacket::new(UnsubackPacket::new(1)),
+            VariablePacket::new(PingreqPacket::new()),
+            VariablePacket::new(PingrespPacket::new()),
+            VariablePacket::new(DisconnectPacket::new()),
+        ];
+
+        for packet in packets {
+            let cloned = packet.clone();
+            assert_eq!(packet, cloned);
+
+            let mut buf = Vec::new();
+            packet.encode(&mut buf).unwrap();
+            let mut cloned_buf = Vec::new();
+            cloned.encode(&mut cloned_buf).unwrap();
+            assert_eq!(buf, cloned_buf);
+        }
+    }
+
+    #[test]
+    fn test_packet_identifier_accessor() {
+        fn bump<P: PacketIdentifierAccessor>(pk: &mut P) {
+            let pkid = pk.packet_identifier();
+            pk.set_packet_identifier(PacketIdentifier::new(pkid + 1).unwrap());
+        }
+
+        let mut puback = PubackPacket::new(10);
+        bump(&mut puback);
+        assert_eq!(11, puback.packet_identifier());
+
+        let mut suback = SubackPacket::new(20, Vec::new());
+        bump(&mut suback);
+        assert_eq!(21, suback.packet_identifier());
+
+        let mut subscribe = SubscribePacket::new(PacketIdentifier::new(30).unwrap(),
+                                                 vec![(TopicFilter::new("a/b"), QualityOfService::Level1)]);
+        bump(&mut subscribe);
+        assert_eq!(31, subscribe.packet_identifier().get());
+
+        assert_eq!(Some(11), VariablePacket::new(puback).packet_identifier());
+        assert_eq!(Some(5), VariablePacket::new(PublishPacket::new("a/b".to_owned(),
+                                                                    QoSWithPacketIdentifier::Level1(PacketIdentifier::new(5).unwrap()),
+                                                                    Vec::new())).packet_identifier());
+        assert_eq!(None, VariablePacket::new(PublishPacket::new("a/b".to_owned(),
+                                                                 QoSWithPacketIdentifier::Level0,
+                                                                 Vec::new())).packet_identifier());
+        assert_eq!(None, VariablePacket::new(PingreqPacket::new()).packet_identifier());
+        assert_eq!(Some(7), VariablePacket::new(PubcompPacket::new(7)).packet_identifier());
+        assert_eq!(Some(8), VariablePacket::new(UnsubackPacket::new(8)).packet_identifier());
+        assert_eq!(None, VariablePacket::new(DisconnectPacket::new()).packet_identifier());
+        assert_eq!(None, VariablePacket::new(AuthPacket::new(AuthReasonCode::ContinueAuthentication)).packet_identifier());
+    }
+
+    #[test]
+    fn test_expect_packet_type() {
+        assert_eq!(ControlType::PublishAcknowledgement, <PubackPacket as Packet>::CONTROL_TYPE);
+
+        let packet = VariablePacket::new(PubackPacket::new(10));
+        let packet = match packet.expect::<PubrecPacket>() {
+            Err(pk) => pk,
+            Ok(pk) => panic!("Unexpected packet {:?}", pk),
+        };
+        assert_eq!(PubackPacket::new(10), packet.expect::<PubackPacket>().unwrap());
+
+        let mut buf = Vec::new();
+        PubrecPacket::new(1).encode(&mut buf).unwrap();
+        PubcompPacket::new(1).encode(&mut buf).unwrap();
+
+        let mut decode_buf = Cursor::new(buf);
+        assert_eq!(PubrecPacket::new(1), read_expected::<PubrecPacket, _>(&mut decode_buf).unwrap());
+        match read_expected::<PubrelPacket, _>(&mut decode_buf) {
+            Err(ReadExpectedError::UnexpectedPacket(VariablePacket::PubcompPacket(..))) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_reserved_fixed_header_flags_all_types() {
+        fn check<'a, T: Packet<'a> + fmt::Debug + 'a>() {
+            let control_type = T::CONTROL_TYPE;
+            let expected = control_type.reserved_flags().unwrap();
+
+            for flags in (0..16u8).filter(|&flags| flags != expected) {
+                let encoded_data = [(control_type.to_u8() << 4) | flags, 0x00];
+
+                match T::decode(&mut Cursor::new(&encoded_data[..])) {
+                    Err(PacketError::InvalidFixedHeaderFlags { control_type: t, flags: f })
+                        if t == control_type && f == flags => {},
+                    err => panic!("Unexpected result {:?} for {:#04x}", err, encoded_data[0]),
+                }
+
+                assert!(VariablePacket::decode(&mut Cursor::new(&encoded_data[..])).is_err());
+            }
+        }
+
+        check::<ConnectPacket>();
+        check::<ConnackPacket>();
+        check::<PubackPacket>();
+        check::<PubrecPacket>();
+        check::<PubrelPacket>();
+        check::<PubcompPacket>();
+        check::<SubscribePacket>();
+        check::<SubackPacket>();
+        check::<UnsubscribePacket>();
+        check::<UnsubackPacket>();
+        check::<PingreqPacket>();
+        check::<PingrespPacket>();
+        check::<DisconnectPacket>();
+    }
+
+    // Records the spans and events of the current thread as text
+    #[cfg(feature = "tracing")]
+    struct CaptureSubscriber {
+        records: ::std::sync::Arc<::std::sync::Mutex<Vec<String>>>,
+        next_span: ::std::sync::atomic::AtomicU64,
+    }
+
+    #[cfg(feature = "tracing")]
+    struct FieldRecorder(String);
+
+    #[cfg(feature = "tracing")]
+    impl ::tracing::field::Visit for FieldRecorder {
+        fn record_debug(&mut self, field: &::tracing::field::Field, value: &dyn (::std::fmt::Debug)) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl ::tracing::Subscriber for CaptureSubscriber {
+        fn enabled(&self, _: &::tracing::Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &::tracing::span::Attributes) -> ::tracing::span::Id {
+            let mut recorder = FieldRecorder(format!("span {}", span.metadata().name()));
+            span.record(&mut recorder);
+            self.records.lock().unwrap().push(recorder.0);
+            let id = self.next_span.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed);
+            ::tracing::span::Id::from_u64(id)
+        }
+
+        fn record(&self, _: &::tracing::span::Id, _: &::tracing::span::Record) {}
+
+        fn record_follows_from(&self, _: &::tracing::span::Id, _: &::tracing::span::Id) {}
+
+        fn event(&self, event: &::tracing::Event) {
+            let mut recorder = FieldRecorder(format!("{}", event.metadata().level()));
+            event.record(&mut recorder);
+            self.records.lock().unwrap().push(recorder.0);
+        }
+
+        fn enter(&self, _: &::tracing::span::Id) {}
+
+        fn exit(&self, _: &::tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    fn capture_traces<F: FnOnce()>(f: F) -> Vec<String> {
+        let records = ::std::sync::Arc::new(::std::sync::Mutex::new(Vec::new()));
+        let subscriber = CaptureSubscriber {
+            records: records.clone(),
+            next_span: ::std::sync::atomic::AtomicU64::new(1),
+        };
+        ::tracing::subscriber::with_default(subscriber, f);
+        let records = records.lock().unwrap();
+        records.clone()
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_variable_packet_tracing() {
+        let encoded_data = b"\x32\x0c\x00\x03a/b\x00\x0asecret";
+
+        let records = capture_traces(|| {
+            VariablePacket::decode(&mut Cursor::new(&encoded_data[..])).unwrap();
+            let options = DecodeOptions::new().connection(Some(7));
+            VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &options).unwrap();
+            VariablePacket::decode(&mut Cursor::new(&b"\x30\x02\x00\x05hello"[..])).unwrap_err();
+        });
+
+        let decoded = "TRACE message=Decoded packet control_type=Publish remaining_length=12 packet_identifier=10";
+        assert_eq!(vec!["span decode".to_owned(), decoded.to_owned(),
+                        "span decode connection=7".to_owned(), decoded.to_owned(),
+                        "span decode".to_owned()],
+                   records[..5].to_vec());
+        assert_eq!(6, records.len());
+        assert!(records[5].starts_with("DEBUG message=Failed to decode packet error="), "{:?}", records[5]);
+        assert!(records.iter().all(|message| !message.contains("secret") && !message.contains("hello")));
+    }
 }
@@ -1,10 +1,11 @@
+use std::prelude::v1::*;
 use std::io::{Read, Write};
-
+use std::fmt;
 
 use control::{FixedHeader, PacketType, ControlType};
 use packet::{Packet, PacketError};
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct PingreqPacket {
     fixed_header: FixedHeader,
     payload: (),
@@ -19,9 +20,17 @@ impl PingreqPacket {
     }
 }
 
+impl fmt::Display for PingreqPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PINGREQ")
+    }
+}
+
 impl<'a> Packet<'a> for PingreqPacket {
     type Payload = ();
 
+    const CONTROL_TYPE: ControlType = ControlType::PingRequest;
+
     fn fixed_header(&self) -> &FixedHeader {
         &self.fixed_header
     }
@@ -39,9 +48,48 @@ impl<'a> Packet<'a> for PingreqPacket {
     }
 
     fn decode_packet<R: Read>(_reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
+        if fixed_header.remaining_length != 0 {
+            return Err(PacketError::MalformedPacket("PINGREQ packet must not have a body".to_owned()));
+        }
+
         Ok(PingreqPacket {
             fixed_header: fixed_header,
             payload: (),
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use packet::PacketError;
+    use {Encodable, Decodable};
+
+    #[test]
+    fn test_pingreq_packet_basic() {
+        let packet = PingreqPacket::new();
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\xc0\x00"[..], &buf[..]);
+
+        let mut decode_buf = Cursor::new(buf);
+        let decoded = PingreqPacket::decode(&mut decode_buf).unwrap();
+
+        assert_eq!(packet, decoded);
+    }
+
+    #[test]
+    fn test_pingreq_packet_with_body() {
+        let encoded_data = b"\xc0\x02\x00\x00";
+
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        match PingreqPacket::decode(&mut decode_buf) {
+            Err(PacketError::MalformedPacket(..)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+}
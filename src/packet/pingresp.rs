@@ -1,10 +1,11 @@
+use std::prelude::v1::*;
 use std::io::{Read, Write};
-
+use std::fmt;
 
 use control::{FixedHeader, PacketType, ControlType};
 use packet::{Packet, PacketError};
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct PingrespPacket {
     fixed_header: FixedHeader,
     payload: (),
@@ -19,9 +20,17 @@ impl PingrespPacket {
     }
 }
 
+impl fmt::Display for PingrespPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PINGRESP")
+    }
+}
+
 impl<'a> Packet<'a> for PingrespPacket {
     type Payload = ();
 
+    const CONTROL_TYPE: ControlType = ControlType::PingResponse;
+
     fn fixed_header(&self) -> &FixedHeader {
         &self.fixed_header
     }
@@ -39,9 +48,48 @@ impl<'a> Packet<'a> for PingrespPacket {
     }
 
     fn decode_packet<R: Read>(_reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
+        if fixed_header.remaining_length != 0 {
+            return Err(PacketError::MalformedPacket("PINGRESP packet must not have a body".to_owned()));
+        }
+
         Ok(PingrespPacket {
             fixed_header: fixed_header,
             payload: (),
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use packet::PacketError;
+    use {Encodable, Decodable};
+
+    #[test]
+    fn test_pingresp_packet_basic() {
+        let packet = PingrespPacket::new();
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\xd0\x00"[..], &buf[..]);
+
+        let mut decode_buf = Cursor::new(buf);
+        let decoded = PingrespPacket::decode(&mut decode_buf).unwrap();
+
+        assert_eq!(packet, decoded);
+    }
+
+    #[test]
+    fn test_pingresp_packet_with_body() {
+        let encoded_data = b"\xd0\x02\x00\x00";
+
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        match PingrespPacket::decode(&mut decode_buf) {
+            Err(PacketError::MalformedPacket(..)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+}
@@ -1,23 +1,41 @@
+use std::prelude::v1::*;
 use std::io::{Read, Write};
-
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
 
 use control::{FixedHeader, PacketType, ControlType};
-use control::variable_header::{TopicName, PacketIdentifier};
+use control::variable_header::{TopicName, PacketIdentifier, Properties, Property, PropertyType, ProtocolVersion};
 use packet::{Packet, PacketError};
-use {Encodable, Decodable};
+use encodable::VAR_INT_MAX;
+use packet::topic_alias::{AliasDecision, TopicAliasMap};
+use {Encodable, Decodable, QualityOfService};
 
-#[derive(Debug)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum QoSWithPacketIdentifier {
     Level0,
-    Level1(u16),
-    Level2(u16),
+    Level1(PacketIdentifier),
+    Level2(PacketIdentifier),
 }
 
-#[derive(Debug, Eq, PartialEq)]
+impl QoSWithPacketIdentifier {
+    pub fn qos(&self) -> QualityOfService {
+        match self {
+            &QoSWithPacketIdentifier::Level0 => QualityOfService::Level0,
+            &QoSWithPacketIdentifier::Level1(..) => QualityOfService::Level1,
+            &QoSWithPacketIdentifier::Level2(..) => QualityOfService::Level2,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct PublishPacket {
     fixed_header: FixedHeader,
     topic_name: TopicName,
     packet_identifier: Option<PacketIdentifier>,
+    mqtt5: bool,
+    properties: Properties,
     payload: Vec<u8>,
 }
 
@@ -25,14 +43,16 @@ impl PublishPacket {
     pub fn new(topic_name: String, qos: QoSWithPacketIdentifier, payload: Vec<u8>) -> PublishPacket {
         let (qos, pkid) = match qos {
             QoSWithPacketIdentifier::Level0 => (0, None),
-            QoSWithPacketIdentifier::Level1(pkid) => (1, Some(PacketIdentifier(pkid))),
-            QoSWithPacketIdentifier::Level2(pkid) => (2, Some(PacketIdentifier(pkid))),
+            QoSWithPacketIdentifier::Level1(pkid) => (1, Some(pkid)),
+            QoSWithPacketIdentifier::Level2(pkid) => (2, Some(pkid)),
         };
 
         let mut pk = PublishPacket {
             fixed_header: FixedHeader::new(PacketType::with_default(ControlType::Publish), 0),
             topic_name: TopicName(topic_name),
             packet_identifier: pkid,
+            mqtt5: false,
+            properties: Properties::new(),
             payload: payload,
         };
         pk.fixed_header.packet_type.flags |= qos << 1;
@@ -40,28 +60,144 @@ impl PublishPacket {
         pk
     }
 
+    pub fn with_version(topic_name: String, qos: QoSWithPacketIdentifier, payload: Vec<u8>,
+                        version: ProtocolVersion) -> PublishPacket {
+        let mut pk = PublishPacket::new(topic_name, qos, payload);
+        pk.set_protocol_version(version);
+        pk
+    }
+
+    /// Properties are only encoded for MQTT 5, they are kept when switching to an earlier version
+    pub fn set_protocol_version(&mut self, version: ProtocolVersion) {
+        self.mqtt5 = version == ProtocolVersion::V5;
+        self.fixed_header.remaining_length = self.calculate_remaining_length();
+    }
+
+    /// Whether the packet is laid out as an MQTT 5 PUBLISH
+    pub fn is_mqtt5(&self) -> bool {
+        self.mqtt5
+    }
+
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    pub fn properties_mut<'a>(&'a mut self) -> PropertiesMut<'a> {
+        PropertiesMut { packet: self }
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.properties.content_type()
+    }
+
+    pub fn set_content_type(&mut self, content_type: &str) {
+        self.properties_mut().insert(Property::ContentType(content_type.to_owned()));
+    }
+
+    pub fn response_topic(&self) -> Option<&str> {
+        self.properties.response_topic()
+    }
+
+    /// The Response Topic must not contain wildcards
+    pub fn set_response_topic(&mut self, topic: TopicName) -> Result<(), PublishPropertyError> {
+        if topic.contains_wildcard() {
+            return Err(PublishPropertyError::WildcardInResponseTopic(topic.0));
+        }
+        self.properties_mut().insert(Property::ResponseTopic(topic.0));
+        Ok(())
+    }
+
+    pub fn topic_alias(&self) -> Option<u16> {
+        self.properties.topic_alias()
+    }
+
+    pub fn set_topic_alias(&mut self, alias: Option<u16>) {
+        let mut properties = self.properties_mut();
+        match alias {
+            Some(alias) => properties.insert(Property::TopicAlias(alias)),
+            None => properties.remove(PropertyType::TopicAlias),
+        };
+    }
+
+    /// Replaces the Topic Name with a Topic Alias if `aliases` has one, `max` is the Topic Alias
+    /// Maximum of the receiver
+    ///
+    /// Packets that are not MQTT 5 are left alone.
+    pub fn apply_alias(&mut self, aliases: &mut TopicAliasMap, max: u16) -> AliasDecision {
+        if !self.mqtt5 {
+            return AliasDecision::NoAlias;
+        }
+
+        let decision = aliases.assign_or_reuse(&self.topic_name, max);
+        match decision {
+            AliasDecision::Register(alias) => self.set_topic_alias(Some(alias)),
+            AliasDecision::Reuse(alias) => {
+                self.topic_name = TopicName(String::new());
+                self.set_topic_alias(Some(alias));
+            },
+            AliasDecision::NoAlias => {},
+        }
+        decision
+    }
+
+    /// Subscription Identifiers of the subscriptions the message was delivered for
+    pub fn subscription_identifiers<'b>(&'b self) -> impl Iterator<Item = u32> + 'b {
+        self.properties.subscription_identifiers()
+    }
+
+    /// Subscription Identifiers range from 1 to 268,435,455
+    pub fn add_subscription_identifier(&mut self, id: u32) -> Result<(), PublishPropertyError> {
+        if id == 0 || id > VAR_INT_MAX {
+            return Err(PublishPropertyError::InvalidSubscriptionIdentifier(id));
+        }
+        self.properties_mut().insert(Property::SubscriptionIdentifier(id));
+        Ok(())
+    }
+
+    pub fn correlation_data(&self) -> Option<&[u8]> {
+        self.properties.correlation_data()
+    }
+
+    pub fn set_correlation_data(&mut self, data: Vec<u8>) {
+        self.properties_mut().insert(Property::CorrelationData(data));
+    }
+
     #[inline]
     fn calculate_remaining_length(&self) -> u32 {
         self.encoded_variable_headers_length() +
             self.payload().encoded_length()
     }
 
+    /// Sets the DUP flag. It must not be set on QoS 0 messages (MQTT-3.3.1-2), doing so is
+    /// a no-op.
     pub fn set_dup(&mut self, dup: bool) {
+        debug_assert!(!dup || self.packet_identifier.is_some(), "DUP flag must not be set on a QoS 0 PUBLISH");
+        if dup && self.packet_identifier.is_none() {
+            return;
+        }
+
+        self.fixed_header.packet_type.flags &= !0x08;
         self.fixed_header.packet_type.flags |= (dup as u8) << 3;
     }
 
     pub fn dup(&self) -> bool {
-        self.fixed_header.packet_type.flags & 0x80 != 0
+        self.fixed_header.packet_type.flags & 0x08 != 0
     }
 
+    /// Changing to QoS 0 clears the DUP flag
     pub fn set_qos(&mut self, qos: QoSWithPacketIdentifier) {
         let (qos, pkid) = match qos {
             QoSWithPacketIdentifier::Level0 => (0, None),
-            QoSWithPacketIdentifier::Level1(pkid) => (1, Some(PacketIdentifier(pkid))),
-            QoSWithPacketIdentifier::Level2(pkid) => (2, Some(PacketIdentifier(pkid))),
+            QoSWithPacketIdentifier::Level1(pkid) => (1, Some(pkid)),
+            QoSWithPacketIdentifier::Level2(pkid) => (2, Some(pkid)),
         };
+        self.fixed_header.packet_type.flags &= !0x06;
         self.fixed_header.packet_type.flags |= qos << 1;
+        if pkid.is_none() {
+            self.fixed_header.packet_type.flags &= !0x08;
+        }
         self.packet_identifier = pkid;
+        self.fixed_header.remaining_length = self.calculate_remaining_length();
     }
 
     pub fn qos(&self) -> QoSWithPacketIdentifier {
@@ -69,9 +205,9 @@ impl PublishPacket {
             None => QoSWithPacketIdentifier::Level0,
             Some(pkid) => {
                 let qos_val = (self.fixed_header.packet_type.flags & 0x06) >> 1;
-                match qos_val {
-                    1 => QoSWithPacketIdentifier::Level1(pkid.0),
-                    2 => QoSWithPacketIdentifier::Level2(pkid.0),
+                match QualityOfService::try_from(qos_val) {
+                    Ok(QualityOfService::Level1) => QoSWithPacketIdentifier::Level1(pkid),
+                    Ok(QualityOfService::Level2) => QoSWithPacketIdentifier::Level2(pkid),
                     _ => unreachable!(),
                 }
             }
@@ -79,6 +215,7 @@ impl PublishPacket {
     }
 
     pub fn set_retain(&mut self, ret: bool) {
+        self.fixed_header.packet_type.flags &= !0x01;
         self.fixed_header.packet_type.flags |= ret as u8;
     }
 
@@ -94,11 +231,139 @@ impl PublishPacket {
     pub fn topic_name(&self) -> &str {
         &self.topic_name.0[..]
     }
+
+    /// A copy to be sent again, with the DUP flag set if it is a QoS 1 or 2 message (MQTT-3.3.1-1)
+    pub fn for_retransmission(&self) -> PublishPacket {
+        let mut packet = self.clone();
+        if packet.packet_identifier.is_some() {
+            packet.set_dup(true);
+        }
+        packet
+    }
+
+    /// A copy to forward to a subscriber that was granted `granted` and connected with `version`
+    ///
+    /// The copy has the lower of both QoS levels, with an identifier from `next_pkid` unless it
+    /// is QoS 0, and the DUP flag cleared. RETAIN is kept with `retain_as_stored`, for sending
+    /// retained messages to a new subscription, and cleared otherwise (MQTT-3.3.1-9). A copy for
+    /// an MQTT 3.1.1 subscriber has no properties.
+    pub fn for_delivery<F>(&self, granted: QualityOfService, mut next_pkid: F, retain_as_stored: bool,
+                           version: ProtocolVersion) -> PublishPacket
+        where F: FnMut() -> PacketIdentifier
+    {
+        let qos = match self.qos().qos().min(granted) {
+            QualityOfService::Level0 => QoSWithPacketIdentifier::Level0,
+            QualityOfService::Level1 => QoSWithPacketIdentifier::Level1(next_pkid()),
+            QualityOfService::Level2 => QoSWithPacketIdentifier::Level2(next_pkid()),
+        };
+
+        let mut packet = PublishPacket::with_version(self.topic_name.0.clone(), qos, self.payload.clone(), version);
+        packet.set_retain(retain_as_stored && self.retain());
+
+        // Topic Aliases and Subscription Identifiers belong to the connection of the sender
+        if packet.mqtt5 {
+            let mut properties = self.properties.clone();
+            properties.remove(PropertyType::TopicAlias);
+            properties.remove(PropertyType::SubscriptionIdentifier);
+            *packet.properties_mut() = properties;
+        }
+        packet
+    }
+}
+
+/// Mutable access to the properties of a `PublishPacket`, the remaining length is updated
+/// when it is dropped
+pub struct PropertiesMut<'a> {
+    packet: &'a mut PublishPacket,
+}
+
+impl<'a> Deref for PropertiesMut<'a> {
+    type Target = Properties;
+
+    fn deref(&self) -> &Properties {
+        &self.packet.properties
+    }
+}
+
+impl<'a> DerefMut for PropertiesMut<'a> {
+    fn deref_mut(&mut self) -> &mut Properties {
+        &mut self.packet.properties
+    }
+}
+
+impl<'a> Drop for PropertiesMut<'a> {
+    fn drop(&mut self) {
+        self.packet.fixed_header.remaining_length = self.packet.calculate_remaining_length();
+    }
+}
+
+/// Properties allowed in PUBLISH
+const PUBLISH_PROPERTIES: &'static [PropertyType] = &[
+    PropertyType::PayloadFormatIndicator,
+    PropertyType::MessageExpiryInterval,
+    PropertyType::TopicAlias,
+    PropertyType::ResponseTopic,
+    PropertyType::CorrelationData,
+    PropertyType::UserProperty,
+    PropertyType::SubscriptionIdentifier,
+    PropertyType::ContentType,
+];
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum PublishPropertyError {
+    WildcardInResponseTopic(String),
+    InvalidSubscriptionIdentifier(u32),
+}
+
+impl fmt::Display for PublishPropertyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &PublishPropertyError::WildcardInResponseTopic(ref topic) =>
+                write!(f, "Wildcard in Response Topic {:?}", topic),
+            &PublishPropertyError::InvalidSubscriptionIdentifier(id) =>
+                write!(f, "Invalid Subscription Identifier {}", id),
+        }
+    }
+}
+
+impl Error for PublishPropertyError {
+    fn description(&self) -> &str {
+        match self {
+            &PublishPropertyError::WildcardInResponseTopic(..) => "Wildcard in Response Topic",
+            &PublishPropertyError::InvalidSubscriptionIdentifier(..) => "Invalid Subscription Identifier",
+        }
+    }
+}
+
+impl fmt::Display for PublishPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "PUBLISH(topic={:?}", self.topic_name.0));
+
+        match self.qos() {
+            QoSWithPacketIdentifier::Level0 => try!(write!(f, ", qos=0")),
+            QoSWithPacketIdentifier::Level1(pkid) => try!(write!(f, ", qos=1, pkid={}", pkid)),
+            QoSWithPacketIdentifier::Level2(pkid) => try!(write!(f, ", qos=2, pkid={}", pkid)),
+        }
+
+        try!(write!(f, ", payload={}B", self.payload.len()));
+
+        if self.retain() {
+            try!(write!(f, ", retain"));
+        }
+
+        if self.dup() {
+            try!(write!(f, ", dup"));
+        }
+
+        write!(f, ")")
+    }
 }
 
 impl<'a> Packet<'a> for PublishPacket {
     type Payload = Vec<u8>;
 
+    const CONTROL_TYPE: ControlType = ControlType::Publish;
+
     fn fixed_header(&self) -> &FixedHeader {
         &self.fixed_header
     }
@@ -108,32 +373,91 @@ impl<'a> Packet<'a> for PublishPacket {
     }
 
     fn encode_variable_headers<W: Write>(&self, writer: &mut W) -> Result<(), PacketError<'a, Self>> {
+        // MQTT-3.3.2-2: The Topic Name MUST NOT contain wildcard characters
+        if self.topic_name.contains_wildcard() {
+            return Err(PacketError::WildcardInPublishTopic { topic: self.topic_name.0.clone() });
+        }
+
         try!(self.topic_name.encode(writer));
 
         if let Some(pkid) = self.packet_identifier.as_ref() {
             try!(pkid.encode(writer));
         }
 
+        if self.mqtt5 {
+            if let Some(topic) = self.properties.response_topic() {
+                if TopicName(topic.to_owned()).contains_wildcard() {
+                    return Err(PacketError::WildcardInResponseTopic { topic: topic.to_owned() });
+                }
+            }
+            try!(self.properties.encode(writer));
+        }
+
         Ok(())
     }
 
     fn encoded_variable_headers_length(&self) -> u32 {
         self.topic_name.encoded_length()
             + self.packet_identifier.as_ref().map(|x| x.encoded_length()).unwrap_or(0)
+            + if self.mqtt5 { self.properties.encoded_length() } else { 0 }
     }
 
     fn decode_packet<R: Read>(reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
+        PublishPacket::decode_publish(reader, fixed_header, false)
+    }
+
+    fn decode_packet_with_version<R: Read>(reader: &mut R, fixed_header: FixedHeader, version: ProtocolVersion)
+            -> Result<Self, PacketError<'a, Self>> {
+        PublishPacket::decode_publish(reader, fixed_header, version == ProtocolVersion::V5)
+    }
+}
+
+impl PublishPacket {
+    fn decode_publish<'a, R: Read>(reader: &mut R, fixed_header: FixedHeader, mqtt5: bool)
+            -> Result<PublishPacket, PacketError<'a, PublishPacket>> {
+        // MQTT-3.3.1-4: A PUBLISH Packet MUST NOT have both QoS bits set to 1
+        let qos = try!(QualityOfService::try_from((fixed_header.packet_type.flags & 0x06) >> 1)
+                           .map_err(|err| PacketError::InvalidQoS(err.0)));
+
+        // MQTT-3.3.1-2: The DUP flag MUST be set to 0 for all QoS 0 messages
+        if qos == QualityOfService::Level0 && fixed_header.packet_type.flags & 0x08 != 0 {
+            return Err(PacketError::InvalidDupFlag);
+        }
+
         let topic_name: TopicName = try!(TopicName::decode(reader));
+        if topic_name.contains_wildcard() {
+            return Err(PacketError::WildcardInPublishTopic { topic: topic_name.0 });
+        }
 
-        let packet_identifier = if fixed_header.packet_type.flags & 0x06 != 0 {
+        let packet_identifier = if qos != QualityOfService::Level0 {
             Some(try!(PacketIdentifier::decode(reader)))
         } else {
             None
         };
 
+        let properties = if mqtt5 {
+            let properties: Properties = try!(Decodable::decode(reader));
+            try!(properties.check_allowed(PUBLISH_PROPERTIES, "PUBLISH").map_err(PacketError::MalformedPacket));
+            if properties.subscription_identifiers().any(|id| id == 0) {
+                return Err(PacketError::MalformedPacket("Subscription Identifier must not be 0".to_owned()));
+            }
+            if let Some(topic) = properties.response_topic() {
+                if TopicName(topic.to_owned()).contains_wildcard() {
+                    return Err(PacketError::WildcardInResponseTopic { topic: topic.to_owned() });
+                }
+            }
+            properties
+        } else {
+            Properties::new()
+        };
+
         let vhead_len = topic_name.encoded_length()
-            + packet_identifier.as_ref().map(|x| x.encoded_length()).unwrap_or(0);
-        let payload_len = fixed_header.remaining_length - vhead_len;
+            + packet_identifier.as_ref().map(|x| x.encoded_length()).unwrap_or(0)
+            + if mqtt5 { properties.encoded_length() } else { 0 };
+        let payload_len = match fixed_header.remaining_length.checked_sub(vhead_len) {
+            Some(len) => len,
+            None => return Err(PacketError::MalformedPacket("Remaining length is shorter than the variable header".to_owned())),
+        };
 
         let payload: Vec<u8> = try!(Decodable::decode_with(reader, Some(payload_len)));
 
@@ -141,6 +465,8 @@ impl<'a> Packet<'a> for PublishPacket {
             fixed_header: fixed_header,
             topic_name: topic_name,
             packet_identifier: packet_identifier,
+            mqtt5: mqtt5,
+            properties: properties,
             payload: payload,
         })
     }
@@ -151,12 +477,17 @@ mod test {
     use super::*;
 
     use std::io::Cursor;
+    use std::mem;
 
+    use control::variable_header::{PacketIdentifier, VariableHeaderError};
+    use packet::{self, PacketError, VariablePacket, VariablePacketError, DecodeOptions};
     use {Encodable, Decodable};
 
     #[test]
     fn test_publish_packet_basic() {
-        let packet = PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level2(10), b"Hello world!".to_vec());
+        let packet = PublishPacket::new("a/b".to_owned(),
+                                        QoSWithPacketIdentifier::Level2(PacketIdentifier::new(10).unwrap()),
+                                        b"Hello world!".to_vec());
 
         let mut buf = Vec::new();
         packet.encode(&mut buf).unwrap();
@@ -166,4 +497,372 @@ mod test {
 
         assert_eq!(packet, decoded);
     }
+
+    #[test]
+    fn test_publish_packet_zero_packet_identifier() {
+        let encoded_data = b"\x32\x07\x00\x03a/b\x00\x00";
+
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        match PublishPacket::decode(&mut decode_buf) {
+            Err(PacketError::VariableHeaderError(VariableHeaderError::ZeroPacketIdentifier)) => {}
+            err => panic!("Unexpected result: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_qos_with_packet_identifier_size() {
+        assert_eq!(mem::size_of::<u32>(), mem::size_of::<QoSWithPacketIdentifier>());
+        assert_eq!(mem::size_of::<PacketIdentifier>(), mem::size_of::<Option<PacketIdentifier>>());
+    }
+
+    #[test]
+    fn test_publish_packet_invalid_qos() {
+        let encoded_data = b"\x36\x07\x00\x03a/b\x00\x0a";
+
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        match PublishPacket::decode(&mut decode_buf) {
+            Err(PacketError::InvalidQoS(3)) => {}
+            err => panic!("Unexpected result: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_publish_packet_all_flags() {
+        for flags in 0..16u8 {
+            let encoded_data = [0x30 | flags, 0x07, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x0a];
+
+            let mut decode_buf = Cursor::new(&encoded_data[..]);
+            match (VariablePacket::decode(&mut decode_buf), (flags & 0x06) >> 1) {
+                (Err(VariablePacketError::PublishPacketError(PacketError::InvalidQoS(3))), 3) => {},
+                (Err(VariablePacketError::PublishPacketError(PacketError::InvalidDupFlag)), 0)
+                    if flags & 0x08 != 0 => {},
+                (Ok(VariablePacket::PublishPacket(packet)), qos) => {
+                    let pkid = PacketIdentifier::new(10).unwrap();
+                    match (packet.qos(), qos) {
+                        (QoSWithPacketIdentifier::Level0, 0) => assert_eq!(2, packet.payload().len()),
+                        (QoSWithPacketIdentifier::Level1(id), 1) => assert_eq!(pkid, id),
+                        (QoSWithPacketIdentifier::Level2(id), 2) => assert_eq!(pkid, id),
+                        (qos, _) => panic!("Unexpected {:?} for flags {:#06b}", qos, flags),
+                    }
+                    assert_eq!(flags & 0x01 != 0, packet.retain());
+                    assert_eq!(flags & 0x08 != 0, packet.dup());
+                },
+                (res, _) => panic!("Unexpected result {:?} for flags {:#06b}", res, flags),
+            }
+        }
+    }
+
+    #[test]
+    fn test_publish_packet_dup_with_qos0() {
+        let encoded_data = b"\x38\x07\x00\x03a/bhi";
+
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        match PublishPacket::decode(&mut decode_buf) {
+            Err(PacketError::InvalidDupFlag) => {}
+            err => panic!("Unexpected result: {:?}", err),
+        }
+
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        let packet = VariablePacket::decode_with_options(&mut decode_buf, &DecodeOptions::lenient()).unwrap();
+        assert_eq!(VariablePacket::new(PublishPacket::new("a/b".to_owned(),
+                                                          QoSWithPacketIdentifier::Level0,
+                                                          b"hi".to_vec())),
+                   packet);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn test_publish_packet_set_dup_with_qos0() {
+        let mut packet = PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level0, Vec::new());
+        packet.set_dup(true);
+    }
+
+    #[test]
+    fn test_publish_packet_for_retransmission() {
+        let packet = PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level0, b"hi".to_vec());
+        let retransmission = packet.for_retransmission();
+        assert!(!retransmission.dup());
+        assert_eq!(packet, retransmission);
+
+        let pkid = PacketIdentifier::new(10).unwrap();
+        let mut packet = PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level1(pkid), b"hi".to_vec());
+        packet.set_retain(true);
+        let retransmission = packet.for_retransmission();
+        assert!(!packet.dup());
+        assert!(retransmission.dup());
+        assert!(retransmission.retain());
+
+        let mut buf = Vec::new();
+        retransmission.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x3b\x09\x00\x03a/b\x00\x0ahi"[..], &buf[..]);
+
+        let decoded = PublishPacket::decode(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(retransmission, decoded);
+
+        let mut packet = decoded;
+        packet.set_qos(QoSWithPacketIdentifier::Level0);
+        assert!(!packet.dup());
+        assert_eq!(QoSWithPacketIdentifier::Level0, packet.qos());
+        packet.set_retain(false);
+        assert!(!packet.retain());
+    }
+
+    #[test]
+    fn test_publish_packet_for_delivery() {
+        use QualityOfService::*;
+
+        let cases = [
+            // publish, granted, delivered
+            (Level0, Level0, Level0), (Level0, Level1, Level0), (Level0, Level2, Level0),
+            (Level1, Level0, Level0), (Level1, Level1, Level1), (Level1, Level2, Level1),
+            (Level2, Level0, Level0), (Level2, Level1, Level1), (Level2, Level2, Level2),
+        ];
+
+        let stored_pkid = PacketIdentifier::new(10).unwrap();
+        for &(publish, granted, delivered) in cases.iter() {
+            let qos = match publish {
+                Level0 => QoSWithPacketIdentifier::Level0,
+                Level1 => QoSWithPacketIdentifier::Level1(stored_pkid),
+                Level2 => QoSWithPacketIdentifier::Level2(stored_pkid),
+            };
+            let mut packet = PublishPacket::new("a/b".to_owned(), qos, b"hi".to_vec());
+            packet.set_retain(true);
+            if publish != Level0 {
+                packet.set_dup(true);
+            }
+
+            for &retain_as_stored in [false, true].iter() {
+                let mut allocated = 0;
+                let delivery = packet.for_delivery(granted, || {
+                    allocated += 1;
+                    PacketIdentifier::new(20).unwrap()
+                }, retain_as_stored, ProtocolVersion::V3_1_1);
+
+                assert_eq!(delivered, delivery.qos().qos());
+                match delivery.qos() {
+                    QoSWithPacketIdentifier::Level0 => assert_eq!(0, allocated),
+                    QoSWithPacketIdentifier::Level1(pkid) | QoSWithPacketIdentifier::Level2(pkid) => {
+                        assert_eq!(1, allocated);
+                        assert_eq!(20, pkid.get());
+                    },
+                }
+                assert!(!delivery.dup());
+                assert_eq!(retain_as_stored, delivery.retain());
+                assert_eq!("a/b", delivery.topic_name());
+                assert_eq!(b"hi", &delivery.payload()[..]);
+                assert_eq!(delivery.calculate_remaining_length(), delivery.fixed_header().remaining_length);
+            }
+        }
+    }
+
+    #[test]
+    fn test_publish_packet_wildcard_topic() {
+        for topic in ["a/+/b", "a/#"].iter() {
+            let packet = PublishPacket::new(topic.to_string(), QoSWithPacketIdentifier::Level0, Vec::new());
+            let mut buf = Vec::new();
+            match packet.encode(&mut buf) {
+                Err(PacketError::WildcardInPublishTopic { ref topic }) if topic == packet.topic_name() => {},
+                err => panic!("Unexpected result: {:?}", err),
+            }
+        }
+
+        let encoded_data = b"\x30\x07\x00\x05a/+/b";
+        match PublishPacket::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(PacketError::WildcardInPublishTopic { ref topic }) if topic == "a/+/b" => {},
+            err => panic!("Unexpected result: {:?}", err),
+        }
+
+        let encoded_data = b"\x30\x05\x00\x03a/#";
+        match PublishPacket::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(PacketError::WildcardInPublishTopic { ref topic }) if topic == "a/#" => {},
+            err => panic!("Unexpected result: {:?}", err),
+        }
+
+        let packet = PublishPacket::new("$SYS/uptime".to_owned(), QoSWithPacketIdentifier::Level0, Vec::new());
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(packet, PublishPacket::decode(&mut Cursor::new(buf)).unwrap());
+    }
+
+    fn encode_decode_v5(packet: &PublishPacket) -> PublishPacket {
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(packet.encoded_length() as usize, buf.len());
+
+        let decoded: PublishPacket =
+            packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet, &decoded);
+        decoded
+    }
+
+    fn qos1() -> QoSWithPacketIdentifier {
+        QoSWithPacketIdentifier::Level1(PacketIdentifier::new(10).unwrap())
+    }
+
+    #[test]
+    fn test_publish_packet_mqtt_5_content_type() {
+        let mut packet = PublishPacket::with_version("a/b".to_owned(), qos1(), b"{}".to_vec(), ProtocolVersion::V5);
+        packet.set_content_type("application/json");
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x32\x1d\x00\x03a/b\x00\x0a\x13\x03\x00\x10application/json{}"[..], &buf[..]);
+
+        let decoded = encode_decode_v5(&packet);
+        assert_eq!(Some("application/json"), decoded.content_type());
+        assert_eq!(b"{}", &decoded.payload()[..]);
+    }
+
+    #[test]
+    fn test_publish_packet_mqtt_5_response_topic() {
+        let mut packet = PublishPacket::with_version("req".to_owned(), QoSWithPacketIdentifier::Level0, Vec::new(),
+                                                     ProtocolVersion::V5);
+        packet.set_response_topic(TopicName::new("resp/1".to_owned()).unwrap()).unwrap();
+
+        let decoded = encode_decode_v5(&packet);
+        assert_eq!(Some("resp/1"), decoded.response_topic());
+
+        let mut packet = PublishPacket::with_version("req".to_owned(), QoSWithPacketIdentifier::Level0, Vec::new(),
+                                                     ProtocolVersion::V5);
+        assert_eq!(Err(PublishPropertyError::WildcardInResponseTopic("resp/#".to_owned())),
+                   packet.set_response_topic(TopicName("resp/#".to_owned())));
+        assert!(packet.properties().is_empty());
+
+        // Bypassing the setter is caught when encoding
+        packet.properties_mut().insert(Property::ResponseTopic("resp/+".to_owned()));
+        match packet.encode(&mut Vec::new()) {
+            Err(PacketError::WildcardInResponseTopic { ref topic }) if topic == "resp/+" => {},
+            err => panic!("Unexpected result: {:?}", err),
+        }
+
+        let encoded_data = b"\x30\x0d\x00\x03req\x07\x08\x00\x04re/#";
+        match packet::decode_with_version::<PublishPacket, _>(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5) {
+            Err(PacketError::WildcardInResponseTopic { ref topic }) if topic == "re/#" => {},
+            err => panic!("Unexpected result: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_publish_packet_mqtt_5_correlation_data() {
+        let mut packet = PublishPacket::with_version("a/b".to_owned(), qos1(), b"x".to_vec(), ProtocolVersion::V5);
+        packet.set_correlation_data(b"\x00\x01\x02".to_vec());
+
+        let decoded = encode_decode_v5(&packet);
+        assert_eq!(Some(&b"\x00\x01\x02"[..]), decoded.correlation_data());
+        assert_eq!(b"x", &decoded.payload()[..]);
+    }
+
+    #[test]
+    fn test_publish_packet_mqtt_5_properties() {
+        let mut packet = PublishPacket::with_version("a/b".to_owned(), qos1(), b"payload".to_vec(),
+                                                     ProtocolVersion::V5);
+        packet.set_content_type("text/plain");
+        packet.set_response_topic(TopicName::new("resp".to_owned()).unwrap()).unwrap();
+        packet.set_correlation_data(b"id-1".to_vec());
+        {
+            let mut properties = packet.properties_mut();
+            properties.insert(Property::PayloadFormatIndicator(1));
+            properties.insert(Property::MessageExpiryInterval(60));
+        }
+        assert_eq!(packet.calculate_remaining_length(), packet.fixed_header().remaining_length);
+
+        let decoded = encode_decode_v5(&packet);
+        assert_eq!(Some("text/plain"), decoded.content_type());
+        assert_eq!(Some("resp"), decoded.response_topic());
+        assert_eq!(Some(&b"id-1"[..]), decoded.correlation_data());
+        assert_eq!(Some(60), decoded.properties().message_expiry_interval());
+
+        // Connection specific properties are not carried over to the subscriber
+        let mut properties = packet.properties().clone();
+        properties.insert(Property::TopicAlias(3));
+        *packet.properties_mut() = properties;
+        let delivery = packet.for_delivery(QualityOfService::Level1, || PacketIdentifier::new(20).unwrap(), false,
+                                           ProtocolVersion::V5);
+        assert!(delivery.is_mqtt5());
+        assert!(delivery.properties().get(PropertyType::TopicAlias).is_none());
+        assert_eq!(Some("resp"), delivery.response_topic());
+
+        // A subscriber on MQTT 3.1.1 gets the plain packet
+        let delivery = packet.for_delivery(QualityOfService::Level1, || PacketIdentifier::new(20).unwrap(), false,
+                                           ProtocolVersion::V3_1_1);
+        assert!(!delivery.is_mqtt5());
+        assert!(delivery.properties().is_empty());
+        let mut buf = Vec::new();
+        delivery.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x32\x0e\x00\x03a/b\x00\x14payload"[..], &buf[..]);
+
+        // And the other way around, no properties appear from nowhere
+        let plain = PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level0, b"hi".to_vec());
+        let delivery = plain.for_delivery(QualityOfService::Level0, || unreachable!(), false, ProtocolVersion::V5);
+        assert!(delivery.is_mqtt5());
+        assert!(delivery.properties().is_empty());
+
+        // Properties not defined for PUBLISH
+        let encoded_data = b"\x30\x0b\x00\x03a/b\x05\x11\x00\x00\x00\x0a";
+        match packet::decode_with_version::<PublishPacket, _>(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5) {
+            Err(PacketError::MalformedPacket(ref err)) => assert!(err.contains("0x11"), "{}", err),
+            err => panic!("Unexpected result: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_publish_packet_mqtt_5_subscription_identifiers() {
+        // Delivered for two subscriptions
+        let encoded_data = b"\x30\x0f\x00\x03a/b\x07\x0b\x01\x0b\x80\x01\x0b\x07hi";
+        let decoded: PublishPacket =
+            packet::decode_with_version(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(vec![1, 128, 7], decoded.subscription_identifiers().collect::<Vec<_>>());
+        assert_eq!(b"hi", &decoded.payload()[..]);
+
+        let mut packet = PublishPacket::with_version("a/b".to_owned(), QoSWithPacketIdentifier::Level0,
+                                                     b"hi".to_vec(), ProtocolVersion::V5);
+        for &id in [1, 128, 7].iter() {
+            packet.add_subscription_identifier(id).unwrap();
+        }
+        assert_eq!(decoded, encode_decode_v5(&packet));
+
+        assert_eq!(Err(PublishPropertyError::InvalidSubscriptionIdentifier(0)), packet.add_subscription_identifier(0));
+        assert_eq!(Err(PublishPropertyError::InvalidSubscriptionIdentifier(268_435_456)),
+                   packet.add_subscription_identifier(268_435_456));
+        assert_eq!(3, packet.subscription_identifiers().count());
+
+        let encoded_data = b"\x30\x0a\x00\x03a/b\x04\x0b\x01\x0b\x00";
+        match packet::decode_with_version::<PublishPacket, _>(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5) {
+            Err(PacketError::MalformedPacket(ref err)) => assert!(err.contains("must not be 0"), "{}", err),
+            err => panic!("Unexpected result: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_publish_packet_mqtt_3_1_1_unchanged() {
+        let mut packet = PublishPacket::new("a/b".to_owned(), qos1(), b"Hello".to_vec());
+        let mut expected = Vec::new();
+        packet.encode(&mut expected).unwrap();
+        assert_eq!(&b"\x32\x0c\x00\x03a/b\x00\x0aHello"[..], &expected[..]);
+
+        // Properties are kept, but not encoded below MQTT 5
+        packet.set_content_type("text/plain");
+        packet.set_correlation_data(b"id".to_vec());
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(expected, buf);
+        assert_eq!(expected.len() as u32, packet.encoded_length());
+
+        packet.set_protocol_version(ProtocolVersion::V5);
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert!(buf.len() > expected.len());
+
+        packet.set_protocol_version(ProtocolVersion::V3_1_1);
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(expected, buf);
+
+        let decoded: PublishPacket =
+            packet::decode_with_version(&mut Cursor::new(&expected[..]), ProtocolVersion::V3_1_1).unwrap();
+        assert!(!decoded.is_mqtt5());
+        assert!(decoded.properties().is_empty());
+        assert_eq!(b"Hello", &decoded.payload()[..]);
+    }
 }
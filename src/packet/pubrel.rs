@@ -1,63 +1,96 @@
+use std::prelude::v1::*;
 use std::io::{Read, Write};
-
+use std::cmp;
+use std::fmt;
 
 use control::{FixedHeader, PacketType, ControlType};
-use control::variable_header::PacketIdentifier;
+use control::variable_header::{Properties, PropertyType, ProtocolVersion, PubrelReasonCode};
 use packet::{Packet, PacketError};
 use {Encodable, Decodable};
 
-#[derive(Debug, Eq, PartialEq)]
-pub struct PubrelPacket {
-    fixed_header: FixedHeader,
-    packet_identifier: PacketIdentifier,
-    payload: (),
+impl_ack_packet! {
+    pub struct PubrelPacket: PublishRelease, PubrelReasonCode, "PUBREL"
 }
 
-impl PubrelPacket {
-    pub fn new(pkid: u16) -> PubrelPacket {
-        PubrelPacket {
-            fixed_header: FixedHeader::new(PacketType::with_default(ControlType::PublishRelease), 2),
-            packet_identifier: PacketIdentifier(pkid),
-            payload: (),
-        }
-    }
+#[cfg(test)]
+mod test {
+    use super::*;
 
-    pub fn packet_identifier(&self) -> u16 {
-        self.packet_identifier.0
-    }
+    use std::io::Cursor;
 
-    pub fn set_packet_identifier(&mut self, pkid: u16) {
-        self.packet_identifier.0 = pkid;
-    }
-}
+    use control::variable_header::Property;
+    use packet;
+    use Encodable;
 
-impl<'a> Packet<'a> for PubrelPacket {
-    type Payload = ();
+    #[test]
+    fn test_pubrel_packet_mqtt_5() {
+        // Short form
+        let packet = PubrelPacket::new(10);
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x62\x02\x00\x0a"[..], &buf[..]);
+        let decoded: PubrelPacket = packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet, decoded);
 
-    fn fixed_header(&self) -> &FixedHeader {
-        &self.fixed_header
-    }
+        // Reason Code only
+        let packet = PubrelPacket::new_with_reason(10, PubrelReasonCode::PacketIdentifierNotFound);
+        assert!(packet.reason_code().is_error());
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x62\x03\x00\x0a\x92"[..], &buf[..]);
+        let decoded: PubrelPacket = packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet, decoded);
+        assert_eq!("PUBREL(pkid=10, reason=146)", decoded.to_string());
 
-    fn payload(&self) -> &Self::Payload {
-        &self.payload
-    }
+        // Reason Code and properties
+        let mut packet = PubrelPacket::new_with_reason(10, PubrelReasonCode::Success);
+        let mut properties = Properties::new();
+        properties.insert(Property::ReasonString("ok".to_owned()));
+        packet.set_properties(properties);
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x62\x09\x00\x0a\x00\x05\x1f\x00\x02ok"[..], &buf[..]);
+        let decoded: PubrelPacket = packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet, decoded);
+        assert_eq!(Some("ok"), decoded.reason_string());
 
-    fn encode_variable_headers<W: Write>(&self, writer: &mut W) -> Result<(), PacketError<'a, Self>> {
-        try!(self.packet_identifier.encode(writer));
+        // Reason Code followed by an empty property list
+        let decoded: PubrelPacket = packet::decode_with_version(&mut Cursor::new(&b"\x62\x04\x00\x0a\x92\x00"[..]),
+                                                         ProtocolVersion::V5).unwrap();
+        assert_eq!(PubrelReasonCode::PacketIdentifierNotFound, decoded.reason_code());
+        assert!(decoded.properties().is_empty());
 
-        Ok(())
+        let encoded_data = b"\x62\x07\x00\x0a\x00\x03\x23\x00\x01";
+        match packet::decode_with_version::<PubrelPacket, _>(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5) {
+            Err(PacketError::MalformedPacket(ref err)) => assert!(err.contains("0x23"), "{}", err),
+            err => panic!("Unexpected result {:?}", err),
+        }
     }
 
-    fn encoded_variable_headers_length(&self) -> u32 {
-        self.packet_identifier.encoded_length()
+    #[test]
+    fn test_pubrel_packet_long_form_round_trip() {
+        for encoded_data in [&b"\x62\x03\x00\x0a\x00"[..], &b"\x62\x04\x00\x0a\x00\x00"[..],
+                             &b"\x62\x04\x00\x0a\x92\x00"[..]].iter() {
+            let decoded: PubrelPacket =
+                packet::decode_with_version(&mut Cursor::new(*encoded_data), ProtocolVersion::V5).unwrap();
+            assert_eq!(encoded_data.len() as u32, decoded.encoded_length());
+
+            let mut buf = Vec::new();
+            decoded.encode(&mut buf).unwrap();
+            assert_eq!(*encoded_data, &buf[..]);
+        }
     }
 
-    fn decode_packet<R: Read>(reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
-        let packet_identifier: PacketIdentifier = try!(PacketIdentifier::decode(reader));
-        Ok(PubrelPacket {
-            fixed_header: fixed_header,
-            packet_identifier: packet_identifier,
-            payload: (),
-        })
+    #[test]
+    fn test_pubrel_packet_mqtt_3_1_1_unchanged() {
+        let encoded_data = b"\x62\x02\x00\x0a";
+        let decoded: PubrelPacket =
+            packet::decode_with_version(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V3_1_1).unwrap();
+        assert_eq!(PubrelPacket::new(10), decoded);
+        assert_eq!("PUBREL(pkid=10)", decoded.to_string());
+
+        let mut buf = Vec::new();
+        decoded.encode(&mut buf).unwrap();
+        assert_eq!(&encoded_data[..], &buf[..]);
     }
 }
@@ -0,0 +1,165 @@
+use std::prelude::v1::*;
+use std::error::Error;
+use std::fmt;
+
+use packet::{ConnackPacket, ConnectPacket, DecodeOptions, VariablePacket};
+use Encodable;
+
+/// Maximum Packet Sizes of one MQTT 5 connection (MQTT 5 section 3.1.2.11.4)
+///
+/// The local limit is the Maximum Packet Size sent in our CONNECT or CONNACK and applies to
+/// received packets, the peer's limit applies to sent packets. `None` means no limit other than
+/// the protocol's.
+///
+/// A packet over the peer's limit must not be sent at all, it is never truncated
+/// (MQTT-3.1.2-24): a client or server that wants to send it anyway has to drop it, a server
+/// forwarding a PUBLISH behaves as if it had been delivered (MQTT-3.1.2-25). A received packet
+/// over the local limit is a protocol error, answered with `DisconnectPacket::packet_too_large`.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct PacketSizeLimit {
+    local: Option<u32>,
+    peer: Option<u32>,
+}
+
+impl PacketSizeLimit {
+    pub fn new(local: Option<u32>, peer: Option<u32>) -> PacketSizeLimit {
+        PacketSizeLimit {
+            local: local,
+            peer: peer,
+        }
+    }
+
+    /// Limits of a server that received `connect`
+    pub fn from_connect(local: Option<u32>, connect: &ConnectPacket) -> PacketSizeLimit {
+        PacketSizeLimit::new(local, connect.maximum_packet_size())
+    }
+
+    /// Limits of a client that received `connack`
+    pub fn from_connack(local: Option<u32>, connack: &ConnackPacket) -> PacketSizeLimit {
+        PacketSizeLimit::new(local, connack.maximum_packet_size())
+    }
+
+    /// Largest packet we accept
+    pub fn incoming_limit(&self) -> Option<u32> {
+        self.local
+    }
+
+    /// Largest packet the peer accepts
+    pub fn outgoing_limit(&self) -> Option<u32> {
+        self.peer
+    }
+
+    /// `options` with the incoming limit as `max_packet_size`
+    pub fn decode_options(&self, options: DecodeOptions) -> DecodeOptions {
+        options.max_packet_size(self.local)
+    }
+
+    /// Whether `packet` may be sent to the peer, it has to be dropped otherwise
+    pub fn check_outgoing(&self, packet: &VariablePacket) -> Result<(), PacketTooLarge> {
+        match self.peer {
+            Some(maximum) => {
+                let size = packet.encoded_length();
+                if size > maximum {
+                    Err(PacketTooLarge { size: size, maximum: maximum })
+                } else {
+                    Ok(())
+                }
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+/// A packet is larger than the Maximum Packet Size of the peer
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct PacketTooLarge {
+    pub size: u32,
+    pub maximum: u32,
+}
+
+impl fmt::Display for PacketTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Packet of {} bytes exceeds the Maximum Packet Size {} of the peer", self.size, self.maximum)
+    }
+}
+
+impl Error for PacketTooLarge {
+    fn description(&self) -> &str {
+        "Packet exceeds the Maximum Packet Size of the peer"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use control::variable_header::{ConnectReasonCode, PacketIdentifier, Properties, Property, ProtocolVersion};
+    use packet::{DisconnectPacket, PublishPacket, QoSWithPacketIdentifier, VariablePacketError};
+
+    fn publish() -> VariablePacket {
+        let qos = QoSWithPacketIdentifier::Level1(PacketIdentifier::new(10).unwrap());
+        VariablePacket::new(PublishPacket::new("a/b".to_owned(), qos, b"hello".to_vec()))
+    }
+
+    #[test]
+    fn test_packet_size_limit_outgoing_boundary() {
+        let packet = publish();
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        let size = buf.len() as u32;
+        assert_eq!(14, size);
+
+        assert_eq!(Err(PacketTooLarge { size: size, maximum: size - 1 }),
+                   PacketSizeLimit::new(None, Some(size - 1)).check_outgoing(&packet));
+        assert_eq!(Ok(()), PacketSizeLimit::new(None, Some(size)).check_outgoing(&packet));
+        assert_eq!(Ok(()), PacketSizeLimit::new(None, Some(size + 1)).check_outgoing(&packet));
+        assert_eq!(Ok(()), PacketSizeLimit::default().check_outgoing(&packet));
+
+        // Only the peer's limit applies to sent packets
+        assert_eq!(Ok(()), PacketSizeLimit::new(Some(1), None).check_outgoing(&packet));
+    }
+
+    #[test]
+    fn test_packet_size_limit_incoming_boundary() {
+        let mut buf = Vec::new();
+        publish().encode(&mut buf).unwrap();
+        let size = buf.len() as u32;
+
+        let limit = PacketSizeLimit::new(Some(size - 1), None);
+        assert_eq!(Some(size - 1), limit.incoming_limit());
+        match VariablePacket::decode_with_options(&mut Cursor::new(&buf[..]), &limit.decode_options(DecodeOptions::new())) {
+            Err(VariablePacketError::PacketTooLarge(s)) => assert_eq!(size, s),
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        for maximum in [size, size + 1].iter() {
+            let options = PacketSizeLimit::new(Some(*maximum), Some(1)).decode_options(DecodeOptions::new());
+            assert_eq!(publish(), VariablePacket::decode_with_options(&mut Cursor::new(&buf[..]), &options).unwrap());
+        }
+
+        let mut buf = Vec::new();
+        DisconnectPacket::packet_too_large().encode(&mut buf).unwrap();
+        assert_eq!(&b"\xe0\x01\x95"[..], &buf[..]);
+    }
+
+    #[test]
+    fn test_packet_size_limit_negotiation() {
+        let mut connect = ConnectPacket::with_version("client".to_owned(), ProtocolVersion::V5);
+        connect.set_maximum_packet_size(Some(1024)).unwrap();
+        let limit = PacketSizeLimit::from_connect(Some(4096), &connect);
+        assert_eq!(Some(4096), limit.incoming_limit());
+        assert_eq!(Some(1024), limit.outgoing_limit());
+
+        let mut connack = ConnackPacket::with_reason_code(false, ConnectReasonCode::Success);
+        let limit = PacketSizeLimit::from_connack(None, &connack);
+        assert_eq!(PacketSizeLimit::default(), limit);
+
+        let mut properties = Properties::new();
+        properties.insert(Property::MaximumPacketSize(2048));
+        connack.set_properties(properties);
+        let limit = PacketSizeLimit::from_connack(None, &connack);
+        assert_eq!(PacketSizeLimit::new(None, Some(2048)), limit);
+    }
+}
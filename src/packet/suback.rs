@@ -1,3 +1,4 @@
+use std::prelude::v1::*;
 use std::io::{self, Read, Write};
 use std::error::Error;
 use std::fmt;
@@ -6,9 +7,9 @@ use std::convert::From;
 use byteorder::{self, WriteBytesExt, ReadBytesExt};
 
 use control::{FixedHeader, PacketType, ControlType};
-use control::variable_header::PacketIdentifier;
-use packet::{Packet, PacketError};
-use {Encodable, Decodable};
+use control::variable_header::{Properties, PropertyType, ProtocolVersion, SubackReasonCode};
+use packet::{Packet, PacketError, SubscribePacket};
+use {Encodable, Decodable, QualityOfService, TopicFilter};
 
 #[repr(u8)]
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -19,37 +20,193 @@ pub enum SubscribeReturnCode {
     Failure             = 0x80,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+impl SubscribeReturnCode {
+    pub fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn from_u8(code: u8) -> Option<SubscribeReturnCode> {
+        match code {
+            0x00 => Some(SubscribeReturnCode::MaximumQoSLevel0),
+            0x01 => Some(SubscribeReturnCode::MaximumQoSLevel1),
+            0x02 => Some(SubscribeReturnCode::MaximumQoSLevel2),
+            0x80 => Some(SubscribeReturnCode::Failure),
+            _ => None,
+        }
+    }
+
+    /// Granted QoS, `None` for `Failure`
+    pub fn granted_qos(&self) -> Option<QualityOfService> {
+        match *self {
+            SubscribeReturnCode::MaximumQoSLevel0 => Some(QualityOfService::Level0),
+            SubscribeReturnCode::MaximumQoSLevel1 => Some(QualityOfService::Level1),
+            SubscribeReturnCode::MaximumQoSLevel2 => Some(QualityOfService::Level2),
+            SubscribeReturnCode::Failure => None,
+        }
+    }
+}
+
+impl From<QualityOfService> for SubscribeReturnCode {
+    fn from(qos: QualityOfService) -> SubscribeReturnCode {
+        match qos {
+            QualityOfService::Level0 => SubscribeReturnCode::MaximumQoSLevel0,
+            QualityOfService::Level1 => SubscribeReturnCode::MaximumQoSLevel1,
+            QualityOfService::Level2 => SubscribeReturnCode::MaximumQoSLevel2,
+        }
+    }
+}
+
+impl From<SubscribeReturnCode> for SubackReasonCode {
+    fn from(code: SubscribeReturnCode) -> SubackReasonCode {
+        match code.granted_qos() {
+            Some(qos) => SubackReasonCode::from(qos),
+            None => SubackReasonCode::UnspecifiedError,
+        }
+    }
+}
+
+impl From<SubackReasonCode> for SubscribeReturnCode {
+    /// Every failure becomes `Failure`
+    fn from(code: SubackReasonCode) -> SubscribeReturnCode {
+        match code.granted_qos() {
+            Some(qos) => SubscribeReturnCode::from(qos),
+            None => SubscribeReturnCode::Failure,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct SubackPacket {
     fixed_header: FixedHeader,
-    packet_identifier: PacketIdentifier,
+    packet_identifier: u16,
+    properties: Properties,
     payload: SubackPacketPayload,
 }
 
 impl SubackPacket {
     pub fn new(pkid: u16, subscribes: Vec<SubscribeReturnCode>) -> SubackPacket {
+        SubackPacket::with_payload(pkid, SubackPacketPayload::new(subscribes))
+    }
+
+    /// MQTT 5 SUBACK
+    pub fn with_reason_codes(pkid: u16, reason_codes: Vec<SubackReasonCode>) -> SubackPacket {
+        SubackPacket::with_payload(pkid, SubackPacketPayload::with_reason_codes(reason_codes))
+    }
+
+    fn with_payload(pkid: u16, payload: SubackPacketPayload) -> SubackPacket {
         let mut pk = SubackPacket {
             fixed_header: FixedHeader::new(PacketType::with_default(ControlType::SubscribeAcknowledgement), 0),
-            packet_identifier: PacketIdentifier(pkid),
-            payload: SubackPacketPayload::new(subscribes),
+            packet_identifier: pkid,
+            properties: Properties::new(),
+            payload: payload,
         };
-        pk.fixed_header.remaining_length =
-            pk.encoded_variable_headers_length() + pk.payload.encoded_length();
+        pk.update_remaining_length();
         pk
     }
 
+    fn update_remaining_length(&mut self) {
+        self.fixed_header.remaining_length =
+            self.encoded_variable_headers_length() + self.payload.encoded_length();
+    }
+
+    /// Builds from the grant decisions, `None` refuses the subscription
+    pub fn from_grants<I>(pkid: u16, grants: I) -> SubackPacket
+        where I: IntoIterator<Item = Option<QualityOfService>>
+    {
+        SubackPacket::new(pkid, grants.into_iter()
+                                      .map(|grant| grant.map(SubscribeReturnCode::from)
+                                                        .unwrap_or(SubscribeReturnCode::Failure))
+                                      .collect())
+    }
+
+    /// Builds the response to `request`, calling `policy` with each requested topic filter and QoS.
+    ///
+    /// The packet identifier, the order of return codes and the protocol version always match the request.
+    pub fn for_request<F>(request: &SubscribePacket, mut policy: F) -> SubackPacket
+        where F: FnMut(&TopicFilter, QualityOfService) -> SubscribeReturnCode
+    {
+        let pkid = request.packet_identifier().get();
+        let codes: Vec<SubscribeReturnCode> =
+            request.subscriptions().map(|(filter, qos)| policy(filter, qos)).collect();
+
+        if request.is_mqtt5() {
+            SubackPacket::with_reason_codes(pkid, codes.into_iter().map(SubackReasonCode::from).collect())
+        } else {
+            SubackPacket::new(pkid, codes)
+        }
+    }
+
     pub fn packet_identifier(&self) -> u16 {
-        self.packet_identifier.0
+        self.packet_identifier
     }
 
     pub fn set_packet_identifier(&mut self, pkid: u16) {
-        self.packet_identifier.0 = pkid;
+        self.packet_identifier = pkid;
+    }
+
+    /// MQTT 3.1.1 view of the result, MQTT 5 failures are all `Failure`
+    pub fn return_codes(&self) -> &[SubscribeReturnCode] {
+        self.payload.subscribes()
+    }
+
+    /// MQTT 5 view of the result, MQTT 3.1.1 failures are `UnspecifiedError`
+    pub fn reason_codes(&self) -> &[SubackReasonCode] {
+        self.payload.reason_codes()
+    }
+
+    /// Whether the packet is laid out as an MQTT 5 SUBACK
+    pub fn is_mqtt5(&self) -> bool {
+        self.payload.mqtt5
+    }
+
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    /// Only encoded in MQTT 5
+    pub fn set_properties(&mut self, properties: Properties) {
+        self.properties = properties;
+        self.update_remaining_length();
+    }
+
+    pub fn reason_string(&self) -> Option<&str> {
+        self.properties.reason_string()
+    }
+
+    /// Only encoded in MQTT 5
+    pub fn set_reason_string(&mut self, reason: Option<String>) {
+        self.properties.set_reason_string(reason);
+        self.update_remaining_length();
+    }
+}
+
+/// Properties allowed in SUBACK
+const SUBACK_PROPERTIES: &'static [PropertyType] = &[
+    PropertyType::ReasonString,
+    PropertyType::UserProperty,
+];
+
+impl fmt::Display for SubackPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "SUBACK(pkid={}", self.packet_identifier));
+
+        for code in self.payload.reason_codes.iter() {
+            match code.granted_qos() {
+                Some(qos) => try!(write!(f, ", {}", qos)),
+                None if self.payload.mqtt5 => try!(write!(f, ", failure({:#04x})", code.to_u8())),
+                None => try!(write!(f, ", failure")),
+            }
+        }
+
+        write!(f, ")")
     }
 }
 
 impl<'a> Packet<'a> for SubackPacket {
     type Payload = SubackPacketPayload;
 
+    const CONTROL_TYPE: ControlType = ControlType::SubscribeAcknowledgement;
+
     fn fixed_header(&self) -> &FixedHeader {
         &self.fixed_header
     }
@@ -60,51 +217,119 @@ impl<'a> Packet<'a> for SubackPacket {
 
     fn encode_variable_headers<W: Write>(&self, writer: &mut W) -> Result<(), PacketError<'a, Self>> {
         try!(self.packet_identifier.encode(writer));
+        if self.payload.mqtt5 {
+            try!(self.properties.encode(writer));
+        }
 
         Ok(())
     }
 
     fn encoded_variable_headers_length(&self) -> u32 {
         self.packet_identifier.encoded_length()
+            + if self.payload.mqtt5 { self.properties.encoded_length() } else { 0 }
     }
 
     fn decode_packet<R: Read>(reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
-        let packet_identifier: PacketIdentifier = try!(PacketIdentifier::decode(reader));
+        SubackPacket::decode_suback(reader, fixed_header, false)
+    }
+
+    fn decode_packet_with_version<R: Read>(reader: &mut R, fixed_header: FixedHeader, version: ProtocolVersion)
+            -> Result<Self, PacketError<'a, Self>> {
+        SubackPacket::decode_suback(reader, fixed_header, version == ProtocolVersion::V5)
+    }
+}
+
+impl SubackPacket {
+    fn decode_suback<'a, R: Read>(reader: &mut R, fixed_header: FixedHeader, mqtt5: bool)
+            -> Result<SubackPacket, PacketError<'a, SubackPacket>> {
+        let packet_identifier: u16 = try!(Decodable::decode(reader));
+        let properties = if mqtt5 {
+            let properties: Properties = try!(Decodable::decode(reader));
+            try!(properties.check_allowed(SUBACK_PROPERTIES, "SUBACK").map_err(PacketError::MalformedPacket));
+            properties
+        } else {
+            Properties::new()
+        };
+
+        let vhead_len = packet_identifier.encoded_length()
+            + if mqtt5 { properties.encoded_length() } else { 0 };
+        let payload_len = match fixed_header.remaining_length.checked_sub(vhead_len) {
+            Some(len) => len,
+            None => return Err(PacketError::MalformedPacket("Remaining length is shorter than the variable header".to_owned())),
+        };
         let payload: SubackPacketPayload =
-            try!(SubackPacketPayload::decode_with(reader, Some(fixed_header.remaining_length
-                                                                - packet_identifier.encoded_length()))
+            try!(SubackPacketPayload::decode_payload(reader, payload_len, mqtt5)
                     .map_err(PacketError::PayloadError));
         Ok(SubackPacket {
             fixed_header: fixed_header,
             packet_identifier: packet_identifier,
+            properties: properties,
             payload: payload,
         })
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// Both the MQTT 3.1.1 and the MQTT 5 codes are kept, the ones of the protocol version are encoded
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct SubackPacketPayload {
     subscribes: Vec<SubscribeReturnCode>,
+    reason_codes: Vec<SubackReasonCode>,
+    mqtt5: bool,
 }
 
 impl SubackPacketPayload {
     pub fn new(subs: Vec<SubscribeReturnCode>) -> SubackPacketPayload {
         SubackPacketPayload {
+            reason_codes: subs.iter().map(|&code| SubackReasonCode::from(code)).collect(),
             subscribes: subs,
+            mqtt5: false,
+        }
+    }
+
+    pub fn with_reason_codes(reason_codes: Vec<SubackReasonCode>) -> SubackPacketPayload {
+        SubackPacketPayload {
+            subscribes: reason_codes.iter().map(|&code| SubscribeReturnCode::from(code)).collect(),
+            reason_codes: reason_codes,
+            mqtt5: true,
         }
     }
 
     pub fn subscribes(&self) -> &[SubscribeReturnCode] {
         &self.subscribes[..]
     }
+
+    pub fn reason_codes(&self) -> &[SubackReasonCode] {
+        &self.reason_codes[..]
+    }
+
+    /// Reason Codes not defined by the spec are kept, `VariablePacket` rejects them in strict mode
+    fn decode_payload<R: Read>(reader: &mut R, payload_len: u32, mqtt5: bool)
+            -> Result<SubackPacketPayload, SubackPacketPayloadError> {
+        if !mqtt5 {
+            return SubackPacketPayload::decode_with(reader, Some(payload_len));
+        }
+
+        let mut reason_codes = Vec::new();
+        for _ in 0..payload_len {
+            reason_codes.push(SubackReasonCode::from_u8(try!(reader.read_u8())));
+        }
+
+        Ok(SubackPacketPayload::with_reason_codes(reason_codes))
+    }
 }
 
 impl<'a> Encodable<'a> for SubackPacketPayload {
     type Err = SubackPacketPayloadError;
 
     fn encode<W: Write>(&self, writer: &mut W) -> Result<(), Self::Err> {
-        for code in self.subscribes.iter() {
-            try!(writer.write_u8(*code as u8));
+        if self.mqtt5 {
+            for code in self.reason_codes.iter() {
+                try!(writer.write_u8(code.to_u8()));
+            }
+        } else {
+            for code in self.subscribes.iter() {
+                try!(writer.write_u8(code.to_u8()));
+            }
         }
 
         Ok(())
@@ -124,13 +349,11 @@ impl<'a> Decodable<'a> for SubackPacketPayload {
         let payload_len = payload_len.expect("Must provide payload length");
         let mut subs = Vec::new();
 
-        for _ in 0..payload_len {
-            let retcode = match try!(reader.read_u8()) {
-                0x00 => SubscribeReturnCode::MaximumQoSLevel0,
-                0x01 => SubscribeReturnCode::MaximumQoSLevel1,
-                0x02 => SubscribeReturnCode::MaximumQoSLevel2,
-                0x80 => SubscribeReturnCode::Failure,
-                code => return Err(SubackPacketPayloadError::InvalidSubscribeReturnCode(code)),
+        for index in 0..payload_len as usize {
+            let code = try!(reader.read_u8());
+            let retcode = match SubscribeReturnCode::from_u8(code) {
+                Some(retcode) => retcode,
+                None => return Err(SubackPacketPayloadError::InvalidSubscribeReturnCode { index: index, code: code }),
             };
 
             subs.push(retcode);
@@ -143,15 +366,15 @@ impl<'a> Decodable<'a> for SubackPacketPayload {
 #[derive(Debug)]
 pub enum SubackPacketPayloadError {
     IoError(io::Error),
-    InvalidSubscribeReturnCode(u8),
+    InvalidSubscribeReturnCode { index: usize, code: u8 },
 }
 
 impl fmt::Display for SubackPacketPayloadError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             &SubackPacketPayloadError::IoError(ref err) => err.fmt(f),
-            &SubackPacketPayloadError::InvalidSubscribeReturnCode(code) =>
-                write!(f, "Invalid subscribe return code {}", code),
+            &SubackPacketPayloadError::InvalidSubscribeReturnCode { index, code } =>
+                write!(f, "Invalid subscribe return code {:#04x} at index {}", code, index),
         }
     }
 }
@@ -160,14 +383,14 @@ impl Error for SubackPacketPayloadError {
     fn description(&self) -> &str {
         match self {
             &SubackPacketPayloadError::IoError(ref err) => err.description(),
-            &SubackPacketPayloadError::InvalidSubscribeReturnCode(..) => "Invalid subscribe return code",
+            &SubackPacketPayloadError::InvalidSubscribeReturnCode { .. } => "Invalid subscribe return code",
         }
     }
 
     fn cause(&self) -> Option<&Error> {
         match self {
             &SubackPacketPayloadError::IoError(ref err) => Some(err),
-            &SubackPacketPayloadError::InvalidSubscribeReturnCode(..) => None,
+            &SubackPacketPayloadError::InvalidSubscribeReturnCode { .. } => None,
         }
     }
 }
@@ -177,3 +400,169 @@ impl From<byteorder::Error> for SubackPacketPayloadError {
         SubackPacketPayloadError::IoError(From::from(err))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::cmp;
+    use std::io::Cursor;
+
+    use control::variable_header::{PacketIdentifier, Property};
+    use packet::{self, DecodeOptions, PacketError, SubscribePacket, VariablePacket, VariablePacketError};
+    use packet::subscribe::SubscriptionOptions;
+    use {Encodable, Decodable, QualityOfService, TopicFilter};
+
+    #[test]
+    fn test_suback_packet_basic() {
+        let packet = SubackPacket::new(10, vec![SubscribeReturnCode::from(QualityOfService::Level1),
+                                                SubscribeReturnCode::Failure]);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x90\x04\x00\x0a\x01\x80"[..], &buf[..]);
+
+        let mut decode_buf = Cursor::new(buf);
+        let decoded = SubackPacket::decode(&mut decode_buf).unwrap();
+
+        assert_eq!(packet, decoded);
+        assert_eq!(&[SubscribeReturnCode::MaximumQoSLevel1, SubscribeReturnCode::Failure][..],
+                   decoded.return_codes());
+        assert_eq!(None, decoded.return_codes()[1].granted_qos());
+    }
+
+    #[test]
+    fn test_suback_packet_from_grants() {
+        let packet = SubackPacket::from_grants(10, vec![Some(QualityOfService::Level0),
+                                                        None,
+                                                        Some(QualityOfService::Level2)]);
+        assert_eq!(&[SubscribeReturnCode::MaximumQoSLevel0,
+                     SubscribeReturnCode::Failure,
+                     SubscribeReturnCode::MaximumQoSLevel2][..],
+                   packet.return_codes());
+    }
+
+    #[test]
+    fn test_suback_packet_for_request() {
+        let request = SubscribePacket::new(PacketIdentifier::new(0x1234).unwrap(),
+                                           vec![(TopicFilter::new("a/b"), QualityOfService::Level2),
+                                                (TopicFilter::new("secret/#"), QualityOfService::Level1),
+                                                (TopicFilter::new("c/+"), QualityOfService::Level2)]);
+
+        let packet = SubackPacket::for_request(&request, |filter, qos| {
+            if filter.as_str().starts_with("secret/") {
+                SubscribeReturnCode::Failure
+            } else {
+                SubscribeReturnCode::from(cmp::min(qos, QualityOfService::Level1))
+            }
+        });
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x90\x05\x12\x34\x01\x80\x01"[..], &buf[..]);
+    }
+
+    #[test]
+    fn test_suback_packet_invalid_return_code() {
+        let encoded_data = b"\x90\x03\x00\x0a\x03";
+        match SubackPacket::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(PacketError::PayloadError(SubackPacketPayloadError::InvalidSubscribeReturnCode { index: 0, code: 0x03 })) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let encoded_data = b"\x90\x05\x00\x0a\x00\x80\x81";
+        match SubackPacket::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(PacketError::PayloadError(SubackPacketPayloadError::InvalidSubscribeReturnCode { index: 2, code: 0x81 })) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_suback_packet_mqtt_5() {
+        let codes = vec![SubackReasonCode::GrantedQoS0, SubackReasonCode::GrantedQoS1, SubackReasonCode::GrantedQoS2,
+                         SubackReasonCode::UnspecifiedError, SubackReasonCode::ImplementationSpecificError,
+                         SubackReasonCode::NotAuthorized, SubackReasonCode::TopicFilterInvalid,
+                         SubackReasonCode::PacketIdentifierInUse, SubackReasonCode::QuotaExceeded,
+                         SubackReasonCode::SharedSubscriptionsNotSupported,
+                         SubackReasonCode::SubscriptionIdentifiersNotSupported,
+                         SubackReasonCode::WildcardSubscriptionsNotSupported];
+        let packet = SubackPacket::with_reason_codes(10, codes.clone());
+        assert!(packet.is_mqtt5());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x90\x0f\x00\x0a\x00\x00\x01\x02\x80\x83\x87\x8f\x91\x97\x9e\xa1\xa2"[..], &buf[..]);
+
+        let decoded: SubackPacket = packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet, decoded);
+        assert_eq!(&codes[..], decoded.reason_codes());
+        assert_eq!(&[SubscribeReturnCode::MaximumQoSLevel0, SubscribeReturnCode::MaximumQoSLevel1,
+                     SubscribeReturnCode::MaximumQoSLevel2][..], &decoded.return_codes()[..3]);
+        assert!(decoded.return_codes()[3..].iter().all(|&code| code == SubscribeReturnCode::Failure));
+
+        let mut packet = SubackPacket::with_reason_codes(10, vec![SubackReasonCode::GrantedQoS1,
+                                                                  SubackReasonCode::NotAuthorized]);
+        let mut properties = Properties::new();
+        properties.insert(Property::ReasonString("secret/# is not allowed".to_owned()));
+        packet.set_properties(properties);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        let decoded: SubackPacket = packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet, decoded);
+        assert_eq!(Some("secret/# is not allowed"), decoded.reason_string());
+        assert_eq!("SUBACK(pkid=10, 1, failure(0x87))", decoded.to_string());
+
+        // Only the Reason String and User Properties are allowed
+        let encoded_data = b"\x90\x06\x00\x0a\x03\x23\x00\x01";
+        match packet::decode_with_version::<SubackPacket, _>(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5) {
+            Err(PacketError::MalformedPacket(ref err)) => assert!(err.contains("0x23"), "{}", err),
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_suback_packet_mqtt_5_unknown_reason_code() {
+        let encoded_data = b"\x90\x05\x00\x0a\x00\x01\x03";
+        let options = DecodeOptions::new().protocol_version(ProtocolVersion::V5);
+        match VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &options) {
+            Err(VariablePacketError::UnknownReasonCode(0x03)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let options = options.strict_reason_codes(false);
+        let decoded = VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &options).unwrap();
+        let expected = SubackPacket::with_reason_codes(10, vec![SubackReasonCode::GrantedQoS1,
+                                                                SubackReasonCode::Unspecified(0x03)]);
+        assert_eq!(VariablePacket::new(expected), decoded);
+    }
+
+    #[test]
+    fn test_suback_packet_mqtt_3_1_1_unchanged() {
+        let packet = SubackPacket::new(10, vec![SubscribeReturnCode::MaximumQoSLevel2, SubscribeReturnCode::Failure]);
+        assert!(!packet.is_mqtt5());
+        assert_eq!(&[SubackReasonCode::GrantedQoS2, SubackReasonCode::UnspecifiedError][..], packet.reason_codes());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x90\x04\x00\x0a\x02\x80"[..], &buf[..]);
+
+        let decoded: SubackPacket =
+            packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V3_1_1).unwrap();
+        assert_eq!(packet, decoded);
+
+        // Undefined return codes are still rejected by the MQTT 3.1.1 decoder
+        let encoded_data = b"\x90\x03\x00\x0a\x87";
+        match packet::decode_with_version::<SubackPacket, _>(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V3_1_1) {
+            Err(PacketError::PayloadError(SubackPacketPayloadError::InvalidSubscribeReturnCode { index: 0, code: 0x87 })) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        // The response follows the version of the request
+        let request = SubscribePacket::with_options(PacketIdentifier::new(3).unwrap(),
+                                                    vec![(TopicFilter::new("a"), SubscriptionOptions::new(QualityOfService::Level1))]);
+        let response = SubackPacket::for_request(&request, |_, _| SubscribeReturnCode::Failure);
+        assert!(response.is_mqtt5());
+        assert_eq!(&[SubackReasonCode::UnspecifiedError][..], response.reason_codes());
+    }
+}
@@ -1,48 +1,234 @@
+use std::prelude::v1::*;
 use std::io::{self, Read, Write};
 use std::string::FromUtf8Error;
 use std::error::Error;
 use std::fmt;
-use std::convert::From;
+use std::convert::{From, TryFrom};
+use std::iter::FromIterator;
 
 use byteorder::{self, WriteBytesExt, ReadBytesExt};
 
 use control::{FixedHeader, PacketType, ControlType};
-use control::variable_header::PacketIdentifier;
+use control::variable_header::{PacketIdentifier, Properties, Property, PropertyType, ProtocolVersion};
 use packet::{Packet, PacketError};
-use {Encodable, Decodable, QualityOfService};
-use encodable::StringEncodeError;
+use {Encodable, Decodable, QualityOfService, TopicFilter};
+use encodable::{StringEncodeError, VAR_INT_MAX};
 
-#[derive(Debug, Eq, PartialEq)]
+/// How retained messages are sent when a subscription is made (MQTT 5 section 3.8.3.1)
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum RetainHandling {
+    SendAtSubscribe = 0,
+    SendAtSubscribeIfNew = 1,
+    DoNotSend = 2,
+}
+
+/// Subscription Options byte of an MQTT 5 SUBSCRIBE, only the QoS exists in MQTT 3.1.1
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct SubscriptionOptions {
+    pub qos: QualityOfService,
+    /// Messages published by this connection are not sent back to it
+    pub no_local: bool,
+    /// Forwarded messages keep the RETAIN flag they were published with
+    pub retain_as_published: bool,
+    pub retain_handling: RetainHandling,
+}
+
+impl SubscriptionOptions {
+    pub fn new(qos: QualityOfService) -> SubscriptionOptions {
+        SubscriptionOptions {
+            qos: qos,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::SendAtSubscribe,
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        let mut options = self.qos as u8;
+        if self.no_local {
+            options |= 0x04;
+        }
+        if self.retain_as_published {
+            options |= 0x08;
+        }
+        options | (self.retain_handling as u8) << 4
+    }
+}
+
+impl TryFrom<u8> for SubscriptionOptions {
+    type Error = SubscribePacketPayloadError;
+
+    fn try_from(options: u8) -> Result<SubscriptionOptions, SubscribePacketPayloadError> {
+        let qos = try!(QualityOfService::try_from(options & 0x03)
+                           .map_err(|err| SubscribePacketPayloadError::InvalidQualityOfService(err.0)));
+
+        // Bits 6 and 7 are reserved (MQTT-3.8.3-5) and Retain Handling 3 is not defined
+        let retain_handling = match (options >> 4) & 0x0F {
+            0 => RetainHandling::SendAtSubscribe,
+            1 => RetainHandling::SendAtSubscribeIfNew,
+            2 => RetainHandling::DoNotSend,
+            _ => return Err(SubscribePacketPayloadError::InvalidSubscriptionOptions(options)),
+        };
+
+        Ok(SubscriptionOptions {
+            qos: qos,
+            no_local: options & 0x04 != 0,
+            retain_as_published: options & 0x08 != 0,
+            retain_handling: retain_handling,
+        })
+    }
+}
+
+impl From<QualityOfService> for SubscriptionOptions {
+    fn from(qos: QualityOfService) -> SubscriptionOptions {
+        SubscriptionOptions::new(qos)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct SubscribePacket {
     fixed_header: FixedHeader,
     packet_identifier: PacketIdentifier,
+    properties: Properties,
     payload: SubscribePacketPayload,
 }
 
 impl SubscribePacket {
-    pub fn new(pkid: u16, subscribes: Vec<(String, QualityOfService)>) -> SubscribePacket {
+    pub fn new(pkid: PacketIdentifier, subscribes: Vec<(TopicFilter, QualityOfService)>) -> SubscribePacket {
+        let payload = SubscribePacketPayload::new(subscribes);
+        SubscribePacket::with_payload(pkid, payload)
+    }
+
+    /// MQTT 5 SUBSCRIBE
+    pub fn with_options(pkid: PacketIdentifier, subscribes: Vec<(TopicFilter, SubscriptionOptions)>) -> SubscribePacket {
+        let payload = SubscribePacketPayload::with_options(subscribes);
+        SubscribePacket::with_payload(pkid, payload)
+    }
+
+    fn with_payload(pkid: PacketIdentifier, payload: SubscribePacketPayload) -> SubscribePacket {
         let mut pk = SubscribePacket {
             fixed_header: FixedHeader::new(PacketType::with_default(ControlType::Subscribe), 0),
-            packet_identifier: PacketIdentifier(pkid),
-            payload: SubscribePacketPayload::new(subscribes),
+            packet_identifier: pkid,
+            properties: Properties::new(),
+            payload: payload,
         };
-        pk.fixed_header.remaining_length =
-            pk.encoded_variable_headers_length() + pk.payload.encoded_length();
+        pk.update_remaining_length();
         pk
     }
 
-    pub fn packet_identifier(&self) -> u16 {
-        self.packet_identifier.0
+    pub fn new_from_iter<I>(pkid: PacketIdentifier, subscribes: I) -> SubscribePacket
+        where I: IntoIterator<Item = (TopicFilter, QualityOfService)>
+    {
+        SubscribePacket::with_payload(pkid, subscribes.into_iter().collect())
+    }
+
+    pub fn packet_identifier(&self) -> PacketIdentifier {
+        self.packet_identifier
+    }
+
+    pub fn subscriptions<'b>(&'b self) -> impl Iterator<Item = (&'b TopicFilter, QualityOfService)> + 'b {
+        self.payload.subscribes.iter().map(|&(ref filter, options)| (filter, options.qos))
+    }
+
+    /// Subscriptions with their MQTT 5 options, only the QoS is set for MQTT 3.1.1
+    pub fn subscription_options<'b>(&'b self) -> impl Iterator<Item = (&'b TopicFilter, SubscriptionOptions)> + 'b {
+        self.payload.subscribes.iter().map(|&(ref filter, options)| (filter, options))
+    }
+
+    /// Whether the packet is laid out as an MQTT 5 SUBSCRIBE
+    pub fn is_mqtt5(&self) -> bool {
+        self.payload.mqtt5
+    }
+
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    /// Only encoded in MQTT 5
+    pub fn set_properties(&mut self, properties: Properties) {
+        self.properties = properties;
+        self.update_remaining_length();
+    }
+
+    pub fn len(&self) -> usize {
+        self.payload.subscribes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.payload.subscribes.is_empty()
+    }
+
+    pub fn add_subscription(&mut self, filter: TopicFilter, qos: QualityOfService) {
+        self.add_subscription_with_options(filter, SubscriptionOptions::new(qos));
+    }
+
+    pub fn subscription_identifier(&self) -> Option<u32> {
+        self.properties.subscription_identifiers().next()
+    }
+
+    /// Identifies the subscriptions in the PUBLISH packets they deliver, from 1 to 268,435,455
+    pub fn set_subscription_identifier(&mut self, id: Option<u32>) -> Result<(), SubscribePropertyError> {
+        if let Some(id) = id {
+            if id == 0 || id > VAR_INT_MAX {
+                return Err(SubscribePropertyError::InvalidSubscriptionIdentifier(id));
+            }
+        }
+
+        self.properties.remove(PropertyType::SubscriptionIdentifier);
+        if let Some(id) = id {
+            self.properties.insert(Property::SubscriptionIdentifier(id));
+        }
+        self.update_remaining_length();
+        Ok(())
+    }
+
+    /// Everything but the QoS is dropped when the packet is encoded as MQTT 3.1.1
+    pub fn add_subscription_with_options(&mut self, filter: TopicFilter, options: SubscriptionOptions) {
+        self.payload.subscribes.push((filter, options));
+        self.update_remaining_length();
+    }
+
+    fn update_remaining_length(&mut self) {
+        self.fixed_header.remaining_length =
+            self.encoded_variable_headers_length() + self.payload.encoded_length();
+    }
+
+    /// MQTT-3.8.3-4: No Local must not be set on a shared subscription
+    fn validate_no_local<'a>(&self) -> Result<(), PacketError<'a, SubscribePacket>> {
+        if !self.payload.mqtt5 {
+            return Ok(());
+        }
+
+        for (idx, &(ref filter, options)) in self.payload.subscribes.iter().enumerate() {
+            if options.no_local && filter.is_shared() {
+                return Err(PacketError::NoLocalOnSharedSubscription { index: idx });
+            }
+        }
+        Ok(())
     }
 
-    pub fn set_packet_identifier(&mut self, pkid: u16) {
-        self.packet_identifier.0 = pkid;
+    pub fn set_packet_identifier(&mut self, pkid: PacketIdentifier) {
+        self.packet_identifier = pkid;
+    }
+}
+
+impl fmt::Display for SubscribePacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "SUBSCRIBE(pkid={}", self.packet_identifier));
+
+        for &(ref filter, options) in self.payload.subscribes.iter() {
+            try!(write!(f, ", {:?}@{}", filter.as_str(), options.qos));
+        }
+
+        write!(f, ")")
     }
 }
 
 impl<'a> Packet<'a> for SubscribePacket {
     type Payload = SubscribePacketPayload;
 
+    const CONTROL_TYPE: ControlType = ControlType::Subscribe;
+
     fn fixed_header(&self) -> &FixedHeader {
         &self.fixed_header
     }
@@ -52,53 +238,203 @@ impl<'a> Packet<'a> for SubscribePacket {
     }
 
     fn encode_variable_headers<W: Write>(&self, writer: &mut W) -> Result<(), PacketError<'a, Self>> {
+        // MQTT-3.8.3-3: The payload MUST contain at least one Topic Filter / QoS pair
+        if self.payload.subscribes.is_empty() {
+            return Err(PacketError::EmptySubscription);
+        }
+
+        try!(self.validate_no_local());
+
         try!(self.packet_identifier.encode(writer));
+        if self.payload.mqtt5 {
+            try!(self.properties.encode(writer));
+        }
 
         Ok(())
     }
 
     fn encoded_variable_headers_length(&self) -> u32 {
         self.packet_identifier.encoded_length()
+            + if self.payload.mqtt5 { self.properties.encoded_length() } else { 0 }
     }
 
     fn decode_packet<R: Read>(reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
+        SubscribePacket::decode_subscribe(reader, fixed_header, false)
+    }
+
+    fn decode_packet_with_version<R: Read>(reader: &mut R, fixed_header: FixedHeader, version: ProtocolVersion)
+            -> Result<Self, PacketError<'a, Self>> {
+        SubscribePacket::decode_subscribe(reader, fixed_header, version == ProtocolVersion::V5)
+    }
+}
+
+/// Properties allowed in SUBSCRIBE
+const SUBSCRIBE_PROPERTIES: &'static [PropertyType] = &[
+    PropertyType::SubscriptionIdentifier,
+    PropertyType::UserProperty,
+];
+
+fn validate_subscribe_properties(properties: &Properties) -> Result<(), String> {
+    try!(properties.check_allowed(SUBSCRIBE_PROPERTIES, "SUBSCRIBE"));
+
+    let mut ids = properties.subscription_identifiers();
+    match (ids.next(), ids.next()) {
+        (Some(0), _) => Err("Subscription Identifier must not be 0".to_owned()),
+        (Some(_), Some(_)) => Err("SUBSCRIBE has more than one Subscription Identifier".to_owned()),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum SubscribePropertyError {
+    InvalidSubscriptionIdentifier(u32),
+}
+
+impl fmt::Display for SubscribePropertyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &SubscribePropertyError::InvalidSubscriptionIdentifier(id) =>
+                write!(f, "Invalid Subscription Identifier {}", id),
+        }
+    }
+}
+
+impl Error for SubscribePropertyError {
+    fn description(&self) -> &str {
+        match self {
+            &SubscribePropertyError::InvalidSubscriptionIdentifier(..) => "Invalid Subscription Identifier",
+        }
+    }
+}
+
+impl SubscribePacket {
+    fn decode_subscribe<'a, R: Read>(reader: &mut R, fixed_header: FixedHeader, mqtt5: bool)
+            -> Result<SubscribePacket, PacketError<'a, SubscribePacket>> {
         let packet_identifier: PacketIdentifier = try!(PacketIdentifier::decode(reader));
+        let properties = if mqtt5 {
+            let properties: Properties = try!(Decodable::decode(reader));
+            try!(validate_subscribe_properties(&properties).map_err(PacketError::MalformedPacket));
+            properties
+        } else {
+            Properties::new()
+        };
+
+        let vhead_len = packet_identifier.encoded_length()
+            + if mqtt5 { properties.encoded_length() } else { 0 };
+        let payload_len = match fixed_header.remaining_length.checked_sub(vhead_len) {
+            Some(len) => len,
+            None => return Err(PacketError::MalformedPacket("Remaining length is shorter than the variable header".to_owned())),
+        };
+        if payload_len == 0 {
+            return Err(PacketError::EmptySubscription);
+        }
+
         let payload: SubscribePacketPayload =
-            try!(SubscribePacketPayload::decode_with(reader, Some(fixed_header.remaining_length
-                                                                - packet_identifier.encoded_length()))
-                    .map_err(PacketError::PayloadError));
-        Ok(SubscribePacket {
+            try!(SubscribePacketPayload::decode_payload(reader, payload_len, mqtt5)
+                    .map_err(|err| match err {
+                        SubscribePacketPayloadError::InvalidQualityOfService(qos) => PacketError::InvalidQoS(qos),
+                        err => PacketError::PayloadError(err),
+                    }));
+
+        for (idx, &(ref filter, _)) in payload.subscribes.iter().enumerate() {
+            try!(filter.validate().map_err(|reason| PacketError::InvalidTopicFilter { index: idx, reason: reason }));
+        }
+
+        let packet = SubscribePacket {
             fixed_header: fixed_header,
             packet_identifier: packet_identifier,
+            properties: properties,
             payload: payload,
-        })
+        };
+        try!(packet.validate_no_local());
+        Ok(packet)
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct SubscribePacketPayload {
-    subscribes: Vec<(String, QualityOfService)>,
+    subscribes: Vec<(TopicFilter, SubscriptionOptions)>,
+    mqtt5: bool,
 }
 
 impl SubscribePacketPayload {
-    pub fn new(subs: Vec<(String, QualityOfService)>) -> SubscribePacketPayload {
+    pub fn new(subs: Vec<(TopicFilter, QualityOfService)>) -> SubscribePacketPayload {
+        SubscribePacketPayload {
+            subscribes: subs.into_iter().map(|(filter, qos)| (filter, SubscriptionOptions::new(qos))).collect(),
+            mqtt5: false,
+        }
+    }
+
+    /// MQTT 5 payload, the whole Subscription Options byte is encoded
+    pub fn with_options(subs: Vec<(TopicFilter, SubscriptionOptions)>) -> SubscribePacketPayload {
         SubscribePacketPayload {
             subscribes: subs,
+            mqtt5: true,
         }
     }
 
-    pub fn subscribes(&self) -> &[(String, QualityOfService)] {
+    pub fn subscribes(&self) -> &[(TopicFilter, SubscriptionOptions)] {
         &self.subscribes[..]
     }
+
+    fn decode_payload<R: Read>(reader: &mut R, mut payload_len: u32, mqtt5: bool)
+            -> Result<SubscribePacketPayload, SubscribePacketPayloadError> {
+        let mut subs = Vec::new();
+
+        while payload_len > 0 {
+            let filter = try!(TopicFilter::decode(reader));
+            let byte = try!(reader.read_u8());
+            let options = if mqtt5 {
+                try!(SubscriptionOptions::try_from(byte))
+            } else {
+                SubscriptionOptions::new(try!(QualityOfService::try_from(byte)
+                    .map_err(|err| SubscribePacketPayloadError::InvalidQualityOfService(err.0))))
+            };
+
+            payload_len = try!(payload_len.checked_sub(filter.encoded_length() + 1)
+                                   .ok_or(SubscribePacketPayloadError::IoError(
+                                       io::Error::new(io::ErrorKind::InvalidData, "Topic filter exceeds the payload"))));
+            subs.push((filter, options));
+        }
+
+        Ok(SubscribePacketPayload {
+            subscribes: subs,
+            mqtt5: mqtt5,
+        })
+    }
+}
+
+impl FromIterator<(TopicFilter, QualityOfService)> for SubscribePacketPayload {
+    fn from_iter<I>(iter: I) -> SubscribePacketPayload
+        where I: IntoIterator<Item = (TopicFilter, QualityOfService)>
+    {
+        SubscribePacketPayload {
+            subscribes: iter.into_iter().map(|(filter, qos)| (filter, SubscriptionOptions::new(qos))).collect(),
+            mqtt5: false,
+        }
+    }
+}
+
+/// The packet identifier is 1, replace it with `set_packet_identifier` or use `new_from_iter`
+impl FromIterator<(TopicFilter, QualityOfService)> for SubscribePacket {
+    fn from_iter<I>(iter: I) -> SubscribePacket
+        where I: IntoIterator<Item = (TopicFilter, QualityOfService)>
+    {
+        SubscribePacket::new_from_iter(PacketIdentifier::new(1).unwrap(), iter)
+    }
 }
 
 impl<'a> Encodable<'a> for SubscribePacketPayload {
     type Err = SubscribePacketPayloadError;
 
     fn encode<W: Write>(&self, writer: &mut W) -> Result<(), Self::Err> {
-        for &(ref filter, ref qos) in self.subscribes.iter() {
+        for &(ref filter, ref options) in self.subscribes.iter() {
             try!(filter.encode(writer));
-            try!(writer.write_u8(*qos as u8));
+            if self.mqtt5 {
+                try!(writer.write_u8(options.to_u8()));
+            } else {
+                try!(writer.write_u8(options.qos as u8));
+            }
         }
 
         Ok(())
@@ -116,23 +452,8 @@ impl<'a> Decodable<'a> for SubscribePacketPayload {
 
     fn decode_with<R: Read>(reader: &mut R, payload_len: Option<u32>)
             -> Result<SubscribePacketPayload, SubscribePacketPayloadError> {
-        let mut payload_len = payload_len.expect("Must provide payload length");
-        let mut subs = Vec::new();
-
-        while payload_len > 0 {
-            let filter = try!(String::decode(reader));
-            let qos = match try!(reader.read_u8()) {
-                0 => QualityOfService::Level0,
-                1 => QualityOfService::Level1,
-                2 => QualityOfService::Level2,
-                _ => return Err(SubscribePacketPayloadError::InvalidQualityOfService),
-            };
-
-            payload_len -= filter.encoded_length() + 1;
-            subs.push((filter, qos));
-        }
-
-        Ok(SubscribePacketPayload::new(subs))
+        let payload_len = payload_len.expect("Must provide payload length");
+        SubscribePacketPayload::decode_payload(reader, payload_len, false)
     }
 }
 
@@ -141,7 +462,8 @@ pub enum SubscribePacketPayloadError {
     IoError(io::Error),
     FromUtf8Error(FromUtf8Error),
     StringEncodeError(StringEncodeError),
-    InvalidQualityOfService,
+    InvalidQualityOfService(u8),
+    InvalidSubscriptionOptions(u8),
 }
 
 impl fmt::Display for SubscribePacketPayloadError {
@@ -150,7 +472,10 @@ impl fmt::Display for SubscribePacketPayloadError {
             &SubscribePacketPayloadError::IoError(ref err) => err.fmt(f),
             &SubscribePacketPayloadError::FromUtf8Error(ref err) => err.fmt(f),
             &SubscribePacketPayloadError::StringEncodeError(ref err) => err.fmt(f),
-            &SubscribePacketPayloadError::InvalidQualityOfService => write!(f, "Invalid quality of service"),
+            &SubscribePacketPayloadError::InvalidQualityOfService(qos) =>
+                write!(f, "Invalid quality of service ({})", qos),
+            &SubscribePacketPayloadError::InvalidSubscriptionOptions(options) =>
+                write!(f, "Invalid subscription options (0x{:02X})", options),
         }
     }
 }
@@ -161,7 +486,8 @@ impl Error for SubscribePacketPayloadError {
             &SubscribePacketPayloadError::IoError(ref err) => err.description(),
             &SubscribePacketPayloadError::FromUtf8Error(ref err) => err.description(),
             &SubscribePacketPayloadError::StringEncodeError(ref err) => err.description(),
-            &SubscribePacketPayloadError::InvalidQualityOfService => "Invalid quality of service",
+            &SubscribePacketPayloadError::InvalidQualityOfService(..) => "Invalid quality of service",
+            &SubscribePacketPayloadError::InvalidSubscriptionOptions(..) => "Invalid subscription options",
         }
     }
 
@@ -170,7 +496,8 @@ impl Error for SubscribePacketPayloadError {
             &SubscribePacketPayloadError::IoError(ref err) => Some(err),
             &SubscribePacketPayloadError::FromUtf8Error(ref err) => Some(err),
             &SubscribePacketPayloadError::StringEncodeError(ref err) => Some(err),
-            &SubscribePacketPayloadError::InvalidQualityOfService => None,
+            &SubscribePacketPayloadError::InvalidQualityOfService(..) => None,
+            &SubscribePacketPayloadError::InvalidSubscriptionOptions(..) => None,
         }
     }
 }
@@ -186,3 +513,274 @@ impl From<byteorder::Error> for SubscribePacketPayloadError {
         SubscribePacketPayloadError::IoError(From::from(err))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use control::variable_header::{PacketIdentifier, VariableHeaderError};
+    use packet::{self, PacketError};
+    use topic_filter::TopicFilterError;
+    use {Encodable, Decodable, QualityOfService, TopicFilter};
+
+    #[test]
+    fn test_subscribe_packet_from_iter() {
+        let filters = vec![("a/b", QualityOfService::Level0), ("c/#", QualityOfService::Level2)];
+        let mut packet = SubscribePacket::new_from_iter(PacketIdentifier::new(10).unwrap(), filters.into_iter()
+                                                                 .map(|(f, q)| (TopicFilter::from(f), q)));
+        packet.add_subscription(TopicFilter::new("d/+"), QualityOfService::Level1);
+        assert_eq!(3, packet.len());
+
+        let subs: Vec<(&str, QualityOfService)> = packet.subscriptions().map(|(f, q)| (f.as_str(), q)).collect();
+        assert_eq!(vec![("a/b", QualityOfService::Level0),
+                        ("c/#", QualityOfService::Level2),
+                        ("d/+", QualityOfService::Level1)], subs);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+
+        let mut decode_buf = Cursor::new(buf);
+        let decoded = SubscribePacket::decode(&mut decode_buf).unwrap();
+
+        assert_eq!(packet, decoded);
+    }
+
+    #[test]
+    fn test_subscribe_packet_collect() {
+        let mut packet: SubscribePacket = vec![(TopicFilter::new("a/b"), QualityOfService::Level0),
+                                               (TopicFilter::new("c/#"), QualityOfService::Level2)]
+                                              .into_iter().collect();
+        assert_eq!(1, packet.packet_identifier().get());
+        packet.set_packet_identifier(PacketIdentifier::new(10).unwrap());
+
+        let expected = SubscribePacket::new(PacketIdentifier::new(10).unwrap(),
+                                            vec![(TopicFilter::new("a/b"), QualityOfService::Level0),
+                                                 (TopicFilter::new("c/#"), QualityOfService::Level2)]);
+        assert_eq!(expected, packet);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(expected.encoded_length(), buf.len() as u32);
+    }
+
+    #[test]
+    fn test_subscribe_packet_empty() {
+        let packet = SubscribePacket::new(PacketIdentifier::new(10).unwrap(), Vec::new());
+
+        let mut buf = Vec::new();
+        match packet.encode(&mut buf) {
+            Err(PacketError::EmptySubscription) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let encoded_data = b"\x82\x02\x00\x0a";
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        match SubscribePacket::decode(&mut decode_buf) {
+            Err(PacketError::EmptySubscription) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_subscribe_packet_invalid_qos() {
+        let encoded_data = b"\x82\x08\x00\x0a\x00\x03a/b\x83";
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        match SubscribePacket::decode(&mut decode_buf) {
+            Err(PacketError::InvalidQoS(0x83)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_subscribe_packet_invalid_topic_filter() {
+        let encoded_data = b"\x82\x0e\x00\x0a\x00\x03a/b\x00\x00\x03a#b\x01";
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        match SubscribePacket::decode(&mut decode_buf) {
+            Err(PacketError::InvalidTopicFilter { index: 1, reason: TopicFilterError::InvalidMultiLevelWildcard }) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_subscribe_packet_zero_packet_identifier() {
+        let encoded_data = b"\x82\x08\x00\x00\x00\x03a/b\x00";
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        match SubscribePacket::decode(&mut decode_buf) {
+            Err(PacketError::VariableHeaderError(VariableHeaderError::ZeroPacketIdentifier)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_subscription_options() {
+        let mut valid = 0;
+        for byte in 0..256 {
+            let byte = byte as u8;
+            match SubscriptionOptions::try_from(byte) {
+                Ok(options) => {
+                    valid += 1;
+                    assert_eq!(byte, options.to_u8());
+                },
+                Err(SubscribePacketPayloadError::InvalidQualityOfService(qos)) => assert_eq!(3, qos),
+                Err(SubscribePacketPayloadError::InvalidSubscriptionOptions(options)) => {
+                    assert_eq!(byte, options);
+                    assert!(byte & 0xC0 != 0 || byte & 0x30 == 0x30);
+                },
+                err => panic!("Unexpected result {:?}", err),
+            }
+        }
+        // 3 QoS levels, No Local, Retain As Published and 3 Retain Handling values
+        assert_eq!(3 * 2 * 2 * 3, valid);
+
+        let options = SubscriptionOptions {
+            qos: QualityOfService::Level1,
+            no_local: true,
+            retain_as_published: false,
+            retain_handling: RetainHandling::DoNotSend,
+        };
+        assert_eq!(0x25, options.to_u8());
+    }
+
+    fn options(byte: u8) -> SubscriptionOptions {
+        SubscriptionOptions::try_from(byte).unwrap()
+    }
+
+    #[test]
+    fn test_subscribe_packet_mqtt_5_options() {
+        let qos = [QualityOfService::Level0, QualityOfService::Level1, QualityOfService::Level2];
+        let retain_handling = [RetainHandling::SendAtSubscribe, RetainHandling::SendAtSubscribeIfNew,
+                               RetainHandling::DoNotSend];
+
+        let mut subscribes = Vec::new();
+        for &qos in qos.iter() {
+            for &no_local in [false, true].iter() {
+                for &retain_as_published in [false, true].iter() {
+                    for &retain_handling in retain_handling.iter() {
+                        let options = SubscriptionOptions {
+                            qos: qos,
+                            no_local: no_local,
+                            retain_as_published: retain_as_published,
+                            retain_handling: retain_handling,
+                        };
+                        subscribes.push((TopicFilter::new(format!("t/{}", options.to_u8())), options));
+                    }
+                }
+            }
+        }
+
+        let packet = SubscribePacket::with_options(PacketIdentifier::new(10).unwrap(), subscribes.clone());
+        assert!(packet.is_mqtt5());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(packet.encoded_length() as usize, buf.len());
+
+        let decoded: SubscribePacket =
+            packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet, decoded);
+        let decoded_subscribes: Vec<_> = decoded.subscription_options().map(|(f, o)| (f.clone(), o)).collect();
+        assert_eq!(subscribes, decoded_subscribes);
+
+        let packet = SubscribePacket::with_options(PacketIdentifier::new(10).unwrap(),
+                                                   vec![(TopicFilter::new("a/b"), options(0x2D))]);
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x82\x09\x00\x0a\x00\x00\x03a/b\x2d"[..], &buf[..]);
+    }
+
+    #[test]
+    fn test_subscribe_packet_mqtt_5_malformed_options() {
+        // Retain Handling 3
+        let encoded_data = b"\x82\x09\x00\x0a\x00\x00\x03a/b\x31";
+        match packet::decode_with_version::<SubscribePacket, _>(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5) {
+            Err(PacketError::PayloadError(SubscribePacketPayloadError::InvalidSubscriptionOptions(0x31))) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        // The same byte is an invalid QoS in MQTT 3.1.1
+        let encoded_data = b"\x82\x08\x00\x0a\x00\x03a/b\x31";
+        match SubscribePacket::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(PacketError::InvalidQoS(0x31)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_subscribe_packet_mqtt_5_no_local_shared() {
+        let mut packet = SubscribePacket::with_options(PacketIdentifier::new(10).unwrap(),
+                                                       vec![(TopicFilter::new("a/b"), options(0x05))]);
+        packet.add_subscription_with_options(TopicFilter::new("$share/group/a/b"), options(0x01));
+        packet.encode(&mut Vec::new()).unwrap();
+
+        packet.add_subscription_with_options(TopicFilter::new("$share/group/c"), options(0x05));
+        match packet.encode(&mut Vec::new()) {
+            Err(PacketError::NoLocalOnSharedSubscription { index: 2 }) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let encoded_data = b"\x82\x16\x00\x0a\x00\x00\x10$share/group/a/b\x04";
+        match packet::decode_with_version::<SubscribePacket, _>(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5) {
+            Err(PacketError::NoLocalOnSharedSubscription { index: 0 }) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_subscribe_packet_mqtt_3_1_1_unchanged() {
+        let mut packet = SubscribePacket::new(PacketIdentifier::new(10).unwrap(),
+                                              vec![(TopicFilter::new("a/b"), QualityOfService::Level1)]);
+        assert!(!packet.is_mqtt5());
+        // Only the QoS of the options is encoded
+        packet.add_subscription_with_options(TopicFilter::new("c"), options(0x2e));
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x82\x0c\x00\x0a\x00\x03a/b\x01\x00\x01c\x02"[..], &buf[..]);
+
+        let decoded: SubscribePacket =
+            packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V3_1_1).unwrap();
+        let subs: Vec<(&str, QualityOfService)> = decoded.subscriptions().map(|(f, q)| (f.as_str(), q)).collect();
+        assert_eq!(vec![("a/b", QualityOfService::Level1), ("c", QualityOfService::Level2)], subs);
+    }
+
+    #[test]
+    fn test_subscribe_packet_mqtt_5_subscription_identifier() {
+        let mut packet = SubscribePacket::with_options(PacketIdentifier::new(10).unwrap(),
+                                                       vec![(TopicFilter::new("a/b"), options(0x01))]);
+        packet.set_subscription_identifier(Some(200)).unwrap();
+        packet.set_subscription_identifier(Some(300)).unwrap();
+        assert_eq!(Some(300), packet.subscription_identifier());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\x82\x0c\x00\x0a\x03\x0b\xac\x02\x00\x03a/b\x01"[..], &buf[..]);
+
+        let decoded: SubscribePacket =
+            packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet, decoded);
+        assert_eq!(Some(300), decoded.subscription_identifier());
+
+        assert_eq!(Err(SubscribePropertyError::InvalidSubscriptionIdentifier(0)),
+                   packet.set_subscription_identifier(Some(0)));
+        assert_eq!(Err(SubscribePropertyError::InvalidSubscriptionIdentifier(268_435_456)),
+                   packet.set_subscription_identifier(Some(268_435_456)));
+        assert_eq!(Some(300), packet.subscription_identifier());
+
+        packet.set_subscription_identifier(None).unwrap();
+        assert!(packet.properties().is_empty());
+
+        let encoded_data = b"\x82\x0a\x00\x0a\x02\x0b\x00\x00\x03a/b\x01";
+        match packet::decode_with_version::<SubscribePacket, _>(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5) {
+            Err(PacketError::MalformedPacket(..)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let encoded_data = b"\x82\x0c\x00\x0a\x04\x0b\x01\x0b\x02\x00\x03a/b\x01";
+        match packet::decode_with_version::<SubscribePacket, _>(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5) {
+            Err(PacketError::MalformedPacket(..)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::prelude::v1::*;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use control::variable_header::{DisconnectReasonCode, TopicName};
+
+/// What to send in an outgoing PUBLISH according to `TopicAliasMap::assign_or_reuse`
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum AliasDecision {
+    /// First use of the alias, send the Topic Name together with the alias
+    Register(u16),
+    /// The peer knows the alias, send an empty Topic Name with the alias
+    Reuse(u16),
+    /// All aliases are taken or the peer does not accept any, send the Topic Name only
+    NoAlias,
+}
+
+/// Topic Aliases of one MQTT 5 connection (MQTT 5 section 3.3.2.3.4)
+///
+/// Both directions are independent: the outbound side assigns aliases up to the Topic Alias
+/// Maximum of the peer, the inbound side accepts aliases up to the maximum we announced.
+/// Aliases never outlive the network connection, see `clear`.
+#[derive(Debug, Clone)]
+pub struct TopicAliasMap {
+    inbound_maximum: u16,
+    inbound: BTreeMap<u16, TopicName>,
+    outbound: BTreeMap<String, u16>,
+}
+
+impl TopicAliasMap {
+    /// `inbound_maximum` is the Topic Alias Maximum sent in our CONNECT or CONNACK
+    pub fn new(inbound_maximum: u16) -> TopicAliasMap {
+        TopicAliasMap {
+            inbound_maximum: inbound_maximum,
+            inbound: BTreeMap::new(),
+            outbound: BTreeMap::new(),
+        }
+    }
+
+    pub fn inbound_maximum(&self) -> u16 {
+        self.inbound_maximum
+    }
+
+    /// Alias for publishing to `topic`, `max` is the Topic Alias Maximum of the peer
+    ///
+    /// Aliases are handed out in increasing order and are never reassigned, topics that come
+    /// after the maximum is reached are sent without an alias.
+    pub fn assign_or_reuse(&mut self, topic: &TopicName, max: u16) -> AliasDecision {
+        if let Some(&alias) = self.outbound.get(&topic.0) {
+            if alias <= max {
+                return AliasDecision::Reuse(alias);
+            }
+        }
+
+        let alias = self.outbound.len() + 1;
+        if alias > max as usize {
+            return AliasDecision::NoAlias;
+        }
+
+        self.outbound.insert(topic.0.clone(), alias as u16);
+        AliasDecision::Register(alias as u16)
+    }
+
+    /// Topic Name of an incoming PUBLISH with a Topic Alias
+    ///
+    /// A non-empty `topic` (re)defines the alias, otherwise the alias must have been defined
+    /// by an earlier PUBLISH.
+    pub fn resolve(&mut self, alias: u16, topic: Option<&TopicName>) -> Result<TopicName, TopicAliasError> {
+        if alias == 0 {
+            return Err(TopicAliasError::ZeroAlias);
+        }
+        if alias > self.inbound_maximum {
+            return Err(TopicAliasError::AliasExceedsMaximum { alias: alias, maximum: self.inbound_maximum });
+        }
+
+        match topic {
+            Some(topic) if !topic.0.is_empty() => {
+                self.inbound.insert(alias, topic.clone());
+                Ok(topic.clone())
+            },
+            _ => self.inbound.get(&alias).cloned().ok_or(TopicAliasError::UnknownAlias(alias)),
+        }
+    }
+
+    /// Forgets all aliases, e.g. when the connection is closed
+    pub fn clear(&mut self) {
+        self.inbound.clear();
+        self.outbound.clear();
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum TopicAliasError {
+    ZeroAlias,
+    AliasExceedsMaximum { alias: u16, maximum: u16 },
+    UnknownAlias(u16),
+}
+
+impl TopicAliasError {
+    /// Reason Code of the DISCONNECT that closes the connection
+    pub fn reason_code(&self) -> DisconnectReasonCode {
+        match self {
+            &TopicAliasError::ZeroAlias => DisconnectReasonCode::TopicAliasInvalid,
+            &TopicAliasError::AliasExceedsMaximum { .. } => DisconnectReasonCode::TopicAliasInvalid,
+            &TopicAliasError::UnknownAlias(..) => DisconnectReasonCode::ProtocolError,
+        }
+    }
+}
+
+impl fmt::Display for TopicAliasError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &TopicAliasError::ZeroAlias => write!(f, "Topic Alias 0 is not allowed"),
+            &TopicAliasError::AliasExceedsMaximum { alias, maximum } =>
+                write!(f, "Topic Alias {} is greater than the maximum {}", alias, maximum),
+            &TopicAliasError::UnknownAlias(alias) => write!(f, "Topic Alias {} is not defined", alias),
+        }
+    }
+}
+
+impl Error for TopicAliasError {
+    fn description(&self) -> &str {
+        match self {
+            &TopicAliasError::ZeroAlias => "Zero Topic Alias",
+            &TopicAliasError::AliasExceedsMaximum { .. } => "Topic Alias exceeds maximum",
+            &TopicAliasError::UnknownAlias(..) => "Unknown Topic Alias",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use control::variable_header::ProtocolVersion;
+    use packet::{self, PublishPacket, QoSWithPacketIdentifier};
+    use Encodable;
+
+    fn topic(name: &str) -> TopicName {
+        TopicName::new(name.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn test_topic_alias_outbound() {
+        let mut map = TopicAliasMap::new(0);
+
+        assert_eq!(AliasDecision::Register(1), map.assign_or_reuse(&topic("a/b"), 2));
+        assert_eq!(AliasDecision::Reuse(1), map.assign_or_reuse(&topic("a/b"), 2));
+        assert_eq!(AliasDecision::Register(2), map.assign_or_reuse(&topic("c"), 2));
+        assert_eq!(AliasDecision::NoAlias, map.assign_or_reuse(&topic("d"), 2));
+        assert_eq!(AliasDecision::Reuse(2), map.assign_or_reuse(&topic("c"), 2));
+
+        // The peer does not accept aliases
+        assert_eq!(AliasDecision::NoAlias, TopicAliasMap::new(0).assign_or_reuse(&topic("a/b"), 0));
+
+        map.clear();
+        assert_eq!(AliasDecision::Register(1), map.assign_or_reuse(&topic("d"), 2));
+    }
+
+    #[test]
+    fn test_topic_alias_inbound() {
+        let mut map = TopicAliasMap::new(10);
+
+        assert_eq!(Err(TopicAliasError::UnknownAlias(1)), map.resolve(1, None));
+        assert_eq!(Ok(topic("a/b")), map.resolve(1, Some(&topic("a/b"))));
+        assert_eq!(Ok(topic("a/b")), map.resolve(1, None));
+        assert_eq!(Ok(topic("a/b")), map.resolve(1, Some(&TopicName::new(String::new()).unwrap())));
+
+        // Redefined
+        assert_eq!(Ok(topic("c")), map.resolve(1, Some(&topic("c"))));
+        assert_eq!(Ok(topic("c")), map.resolve(1, None));
+
+        let err = map.resolve(0, Some(&topic("a/b"))).unwrap_err();
+        assert_eq!(TopicAliasError::ZeroAlias, err);
+        assert_eq!(DisconnectReasonCode::TopicAliasInvalid, err.reason_code());
+
+        let err = map.resolve(11, Some(&topic("a/b"))).unwrap_err();
+        assert_eq!(TopicAliasError::AliasExceedsMaximum { alias: 11, maximum: 10 }, err);
+        assert_eq!(DisconnectReasonCode::TopicAliasInvalid, err.reason_code());
+
+        assert_eq!(DisconnectReasonCode::ProtocolError, map.resolve(2, None).unwrap_err().reason_code());
+    }
+
+    #[test]
+    fn test_topic_alias_publish_sequence() {
+        let mut sender = TopicAliasMap::new(0);
+        let mut receiver = TopicAliasMap::new(2);
+
+        let topics = ["sensors/building-1/floor-3/temperature", "sensors/building-1/floor-3/humidity",
+                      "sensors/building-1/floor-3/temperature", "sensors/building-1/floor-3/co2",
+                      "sensors/building-1/floor-3/humidity", "sensors/building-1/floor-3/co2"];
+        let mut sent_topics = Vec::new();
+        for name in topics.iter() {
+            let mut packet = PublishPacket::with_version(name.to_string(), QoSWithPacketIdentifier::Level0,
+                                                         b"1".to_vec(), ProtocolVersion::V5);
+            packet.apply_alias(&mut sender, 2);
+
+            let mut buf = Vec::new();
+            packet.encode(&mut buf).unwrap();
+            let received: PublishPacket =
+                packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+            sent_topics.push(received.topic_name().to_owned());
+
+            let topic_name = TopicName(received.topic_name().to_owned());
+            let resolved = match received.topic_alias() {
+                Some(alias) => receiver.resolve(alias, Some(&topic_name)).unwrap(),
+                None => topic_name,
+            };
+            assert_eq!(*name, resolved.0);
+        }
+
+        assert_eq!(vec!["sensors/building-1/floor-3/temperature", "sensors/building-1/floor-3/humidity", "",
+                        "sensors/building-1/floor-3/co2", "", "sensors/building-1/floor-3/co2"],
+                   sent_topics);
+    }
+}
@@ -1,15 +1,20 @@
+use std::prelude::v1::*;
 use std::io::{Read, Write};
-
+use std::error::Error;
+use std::fmt;
 
 use control::{FixedHeader, PacketType, ControlType};
-use control::variable_header::PacketIdentifier;
-use packet::{Packet, PacketError};
+use control::variable_header::{Properties, PropertyType, ProtocolVersion, UnsubackReasonCode};
+use packet::{Packet, PacketError, UnsubscribePacket};
 use {Encodable, Decodable};
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct UnsubackPacket {
     fixed_header: FixedHeader,
-    packet_identifier: PacketIdentifier,
+    packet_identifier: u16,
+    /// One per topic filter of the UNSUBSCRIBE, only in MQTT 5
+    reason_codes: Option<Vec<UnsubackReasonCode>>,
+    properties: Properties,
     payload: (),
 }
 
@@ -17,23 +22,102 @@ impl UnsubackPacket {
     pub fn new(pkid: u16) -> UnsubackPacket {
         UnsubackPacket {
             fixed_header: FixedHeader::new(PacketType::with_default(ControlType::UnsubscribeAcknowledgement), 2),
-            packet_identifier: PacketIdentifier(pkid),
+            packet_identifier: pkid,
+            reason_codes: None,
+            properties: Properties::new(),
             payload: (),
         }
     }
 
+    /// MQTT 5 UNSUBACK, `validate_for` checks it against the UNSUBSCRIBE it answers
+    pub fn with_reasons(pkid: u16, reason_codes: Vec<UnsubackReasonCode>) -> UnsubackPacket {
+        let mut pk = UnsubackPacket::new(pkid);
+        pk.reason_codes = Some(reason_codes);
+        pk.fixed_header.remaining_length = pk.encoded_variable_headers_length();
+        pk
+    }
+
     pub fn packet_identifier(&self) -> u16 {
-        self.packet_identifier.0
+        self.packet_identifier
     }
 
     pub fn set_packet_identifier(&mut self, pkid: u16) {
-        self.packet_identifier.0 = pkid;
+        self.packet_identifier = pkid;
+    }
+
+    /// `None` for MQTT 3.1.1, which has no payload
+    pub fn reason_codes(&self) -> Option<&[UnsubackReasonCode]> {
+        self.reason_codes.as_ref().map(|codes| &codes[..])
+    }
+
+    /// Whether the packet is laid out as an MQTT 5 UNSUBACK
+    pub fn is_mqtt5(&self) -> bool {
+        self.reason_codes.is_some()
+    }
+
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    /// Only encoded in MQTT 5
+    pub fn set_properties(&mut self, properties: Properties) {
+        self.properties = properties;
+        self.fixed_header.remaining_length = self.encoded_variable_headers_length();
+    }
+
+    pub fn reason_string(&self) -> Option<&str> {
+        self.properties.reason_string()
+    }
+
+    /// Only encoded in MQTT 5
+    pub fn set_reason_string(&mut self, reason: Option<String>) {
+        self.properties.set_reason_string(reason);
+        self.fixed_header.remaining_length = self.encoded_variable_headers_length();
+    }
+
+    /// Checks that this is the answer to `request`: same packet identifier and, in MQTT 5, one
+    /// Reason Code per topic filter (MQTT-3.11.3-1)
+    pub fn validate_for(&self, request: &UnsubscribePacket) -> Result<(), UnsubackMismatchError> {
+        if self.packet_identifier != request.packet_identifier().get() {
+            return Err(UnsubackMismatchError::PacketIdentifier {
+                expected: request.packet_identifier().get(),
+                actual: self.packet_identifier,
+            });
+        }
+
+        match self.reason_codes {
+            Some(ref codes) if codes.len() != request.len() =>
+                Err(UnsubackMismatchError::ReasonCodeCount { expected: request.len(), actual: codes.len() }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Properties allowed in UNSUBACK
+const UNSUBACK_PROPERTIES: &'static [PropertyType] = &[
+    PropertyType::ReasonString,
+    PropertyType::UserProperty,
+];
+
+impl fmt::Display for UnsubackPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "UNSUBACK(pkid={}", self.packet_identifier));
+
+        if let Some(ref codes) = self.reason_codes {
+            for code in codes.iter() {
+                try!(write!(f, ", {}", code.to_u8()));
+            }
+        }
+
+        write!(f, ")")
     }
 }
 
 impl<'a> Packet<'a> for UnsubackPacket {
     type Payload = ();
 
+    const CONTROL_TYPE: ControlType = ControlType::UnsubscribeAcknowledgement;
+
     fn fixed_header(&self) -> &FixedHeader {
         &self.fixed_header
     }
@@ -45,19 +129,180 @@ impl<'a> Packet<'a> for UnsubackPacket {
     fn encode_variable_headers<W: Write>(&self, writer: &mut W) -> Result<(), PacketError<'a, Self>> {
         try!(self.packet_identifier.encode(writer));
 
+        // The Reason Codes are the payload, they are written here as the payload type is shared
+        // with MQTT 3.1.1
+        if let Some(ref codes) = self.reason_codes {
+            try!(self.properties.encode(writer));
+            for code in codes.iter() {
+                try!(code.encode(writer));
+            }
+        }
+
         Ok(())
     }
 
     fn encoded_variable_headers_length(&self) -> u32 {
         self.packet_identifier.encoded_length()
+            + match self.reason_codes {
+                Some(ref codes) => self.properties.encoded_length() + codes.len() as u32,
+                None => 0,
+            }
     }
 
     fn decode_packet<R: Read>(reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
-        let packet_identifier: PacketIdentifier = try!(PacketIdentifier::decode(reader));
+        let packet_identifier: u16 = try!(Decodable::decode(reader));
+        Ok(UnsubackPacket {
+            fixed_header: fixed_header,
+            packet_identifier: packet_identifier,
+            reason_codes: None,
+            properties: Properties::new(),
+            payload: (),
+        })
+    }
+
+    fn decode_packet_with_version<R: Read>(reader: &mut R, fixed_header: FixedHeader, version: ProtocolVersion)
+            -> Result<Self, PacketError<'a, Self>> {
+        if version != ProtocolVersion::V5 {
+            return UnsubackPacket::decode_packet(reader, fixed_header);
+        }
+
+        let packet_identifier: u16 = try!(Decodable::decode(reader));
+        let properties: Properties = try!(Decodable::decode(reader));
+        try!(properties.check_allowed(UNSUBACK_PROPERTIES, "UNSUBACK").map_err(PacketError::MalformedPacket));
+
+        let vhead_len = packet_identifier.encoded_length() + properties.encoded_length();
+        let payload_len = match fixed_header.remaining_length.checked_sub(vhead_len) {
+            Some(len) => len,
+            None => return Err(PacketError::MalformedPacket("Remaining length is shorter than the variable header".to_owned())),
+        };
+
+        // Reason Codes not defined by the spec are kept, `VariablePacket` rejects them in strict mode
+        let mut reason_codes = Vec::new();
+        for _ in 0..payload_len {
+            reason_codes.push(try!(UnsubackReasonCode::decode(reader)));
+        }
+
         Ok(UnsubackPacket {
             fixed_header: fixed_header,
             packet_identifier: packet_identifier,
+            reason_codes: Some(reason_codes),
+            properties: properties,
             payload: (),
         })
     }
 }
+
+/// An UNSUBACK that does not answer the UNSUBSCRIBE it is paired with
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum UnsubackMismatchError {
+    PacketIdentifier { expected: u16, actual: u16 },
+    ReasonCodeCount { expected: usize, actual: usize },
+}
+
+impl fmt::Display for UnsubackMismatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &UnsubackMismatchError::PacketIdentifier { expected, actual } =>
+                write!(f, "Packet identifier {} does not match the UNSUBSCRIBE ({})", actual, expected),
+            &UnsubackMismatchError::ReasonCodeCount { expected, actual } =>
+                write!(f, "{} Reason Codes for {} topic filters", actual, expected),
+        }
+    }
+}
+
+impl Error for UnsubackMismatchError {
+    fn description(&self) -> &str {
+        match self {
+            &UnsubackMismatchError::PacketIdentifier { .. } => "Packet identifier does not match the UNSUBSCRIBE",
+            &UnsubackMismatchError::ReasonCodeCount { .. } => "Reason Code count does not match the UNSUBSCRIBE",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use control::variable_header::{PacketIdentifier, Property};
+    use packet::{self, DecodeOptions, VariablePacket, VariablePacketError};
+    use {Encodable, TopicFilter};
+
+    #[test]
+    fn test_unsuback_packet_mqtt_3_1_1() {
+        let packet = UnsubackPacket::new(10);
+        assert!(!packet.is_mqtt5());
+        assert_eq!(None, packet.reason_codes());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\xb0\x02\x00\x0a"[..], &buf[..]);
+
+        let decoded: UnsubackPacket =
+            packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V3_1_1).unwrap();
+        assert_eq!(packet, decoded);
+
+        let request = UnsubscribePacket::new(PacketIdentifier::new(10).unwrap(),
+                                             vec![TopicFilter::new("a"), TopicFilter::new("b")]).unwrap();
+        assert_eq!(Ok(()), packet.validate_for(&request));
+    }
+
+    #[test]
+    fn test_unsuback_packet_mqtt_5() {
+        let mut packet = UnsubackPacket::with_reasons(10, vec![UnsubackReasonCode::Success,
+                                                               UnsubackReasonCode::NoSubscriptionExisted]);
+        assert!(packet.is_mqtt5());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&b"\xb0\x05\x00\x0a\x00\x00\x11"[..], &buf[..]);
+
+        let decoded: UnsubackPacket = packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet, decoded);
+        assert_eq!("UNSUBACK(pkid=10, 0, 17)", decoded.to_string());
+
+        let mut properties = Properties::new();
+        properties.insert(Property::ReasonString("done".to_owned()));
+        packet.set_properties(properties);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        let decoded: UnsubackPacket = packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet, decoded);
+        assert_eq!(Some("done"), decoded.reason_string());
+        assert_eq!(Some(&[UnsubackReasonCode::Success, UnsubackReasonCode::NoSubscriptionExisted][..]),
+                   decoded.reason_codes());
+
+        // Unknown Reason Codes are kept unless the decode options are strict
+        let encoded_data = b"\xb0\x04\x00\x0a\x00\x12";
+        let options = DecodeOptions::new().protocol_version(ProtocolVersion::V5);
+        match VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &options) {
+            Err(VariablePacketError::UnknownReasonCode(0x12)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let options = options.strict_reason_codes(false);
+        let decoded = VariablePacket::decode_with_options(&mut Cursor::new(&encoded_data[..]), &options).unwrap();
+        assert_eq!(VariablePacket::new(UnsubackPacket::with_reasons(10, vec![UnsubackReasonCode::Unspecified(0x12)])),
+                   decoded);
+    }
+
+    #[test]
+    fn test_unsuback_packet_validate_for() {
+        let request = UnsubscribePacket::new(PacketIdentifier::new(10).unwrap(),
+                                             vec![TopicFilter::new("a"), TopicFilter::new("b")]).unwrap();
+
+        let packet = UnsubackPacket::with_reasons(10, vec![UnsubackReasonCode::Success,
+                                                           UnsubackReasonCode::NotAuthorized]);
+        assert_eq!(Ok(()), packet.validate_for(&request));
+
+        let packet = UnsubackPacket::with_reasons(10, vec![UnsubackReasonCode::Success]);
+        assert_eq!(Err(UnsubackMismatchError::ReasonCodeCount { expected: 2, actual: 1 }),
+                   packet.validate_for(&request));
+
+        let packet = UnsubackPacket::with_reasons(11, vec![UnsubackReasonCode::Success; 2]);
+        assert_eq!(Err(UnsubackMismatchError::PacketIdentifier { expected: 10, actual: 11 }),
+                   packet.validate_for(&request));
+    }
+}
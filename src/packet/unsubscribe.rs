@@ -1,3 +1,4 @@
+use std::prelude::v1::*;
 use std::io::{self, Read, Write};
 use std::string::FromUtf8Error;
 use std::error::Error;
@@ -7,42 +8,157 @@ use std::convert::From;
 use byteorder;
 
 use control::{FixedHeader, PacketType, ControlType};
-use control::variable_header::PacketIdentifier;
+use control::variable_header::{PacketIdentifier, Properties, PropertyType, ProtocolVersion};
 use packet::{Packet, PacketError};
-use {Encodable, Decodable};
+use {Encodable, Decodable, TopicFilter};
 use encodable::StringEncodeError;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct UnsubscribePacket {
     fixed_header: FixedHeader,
     packet_identifier: PacketIdentifier,
+    mqtt5: bool,
+    properties: Properties,
     payload: UnsubscribePacketPayload,
 }
 
 impl UnsubscribePacket {
-    pub fn new(pkid: u16, subscribes: Vec<String>) -> UnsubscribePacket {
+    /// Fails with `EmptyUnsubscription` if `subscribes` is empty (MQTT-3.10.3-2)
+    pub fn new(pkid: PacketIdentifier, subscribes: Vec<TopicFilter>)
+            -> Result<UnsubscribePacket, PacketError<'static, UnsubscribePacket>> {
+        if subscribes.is_empty() {
+            return Err(PacketError::EmptyUnsubscription);
+        }
+
         let mut pk = UnsubscribePacket {
             fixed_header: FixedHeader::new(PacketType::with_default(ControlType::Unsubscribe), 0),
-            packet_identifier: PacketIdentifier(pkid),
+            packet_identifier: pkid,
+            mqtt5: false,
+            properties: Properties::new(),
             payload: UnsubscribePacketPayload::new(subscribes),
         };
-        pk.fixed_header.remaining_length =
-            pk.encoded_variable_headers_length() + pk.payload.encoded_length();
-        pk
+        pk.update_remaining_length();
+        Ok(pk)
+    }
+
+    pub fn with_version(pkid: PacketIdentifier, subscribes: Vec<TopicFilter>, version: ProtocolVersion)
+            -> Result<UnsubscribePacket, PacketError<'static, UnsubscribePacket>> {
+        let mut pk = try!(UnsubscribePacket::new(pkid, subscribes));
+        pk.set_protocol_version(version);
+        Ok(pk)
+    }
+
+    /// Properties are only encoded for MQTT 5, they are kept when switching to an earlier version
+    pub fn set_protocol_version(&mut self, version: ProtocolVersion) {
+        self.mqtt5 = version == ProtocolVersion::V5;
+        self.update_remaining_length();
+    }
+
+    /// Whether the packet is laid out as an MQTT 5 UNSUBSCRIBE
+    pub fn is_mqtt5(&self) -> bool {
+        self.mqtt5
+    }
+
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    /// Only encoded in MQTT 5
+    pub fn set_properties(&mut self, properties: Properties) {
+        self.properties = properties;
+        self.update_remaining_length();
+    }
+
+    pub fn packet_identifier(&self) -> PacketIdentifier {
+        self.packet_identifier
+    }
+
+    pub fn set_packet_identifier(&mut self, pkid: PacketIdentifier) {
+        self.packet_identifier = pkid;
+    }
+
+    pub fn topic_filters<'b>(&'b self) -> impl Iterator<Item = &'b TopicFilter> + 'b {
+        self.payload.subscribes.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.payload.subscribes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.payload.subscribes.is_empty()
+    }
+
+    pub fn add_topic_filter(&mut self, filter: TopicFilter) {
+        self.payload.subscribes.push(filter);
+        self.update_remaining_length();
     }
 
-    pub fn packet_identifier(&self) -> u16 {
-        self.packet_identifier.0
+    fn update_remaining_length(&mut self) {
+        self.fixed_header.remaining_length =
+            self.encoded_variable_headers_length() + self.payload.encoded_length();
     }
 
-    pub fn set_packet_identifier(&mut self, pkid: u16) {
-        self.packet_identifier.0 = pkid;
+    fn decode_unsubscribe<'a, R: Read>(reader: &mut R, fixed_header: FixedHeader, mqtt5: bool)
+            -> Result<UnsubscribePacket, PacketError<'a, UnsubscribePacket>> {
+        let packet_identifier: PacketIdentifier = try!(PacketIdentifier::decode(reader));
+        let properties = if mqtt5 {
+            let properties: Properties = try!(Decodable::decode(reader));
+            try!(properties.check_allowed(UNSUBSCRIBE_PROPERTIES, "UNSUBSCRIBE").map_err(PacketError::MalformedPacket));
+            properties
+        } else {
+            Properties::new()
+        };
+
+        let vhead_len = packet_identifier.encoded_length()
+            + if mqtt5 { properties.encoded_length() } else { 0 };
+        let payload_len = match fixed_header.remaining_length.checked_sub(vhead_len) {
+            Some(len) => len,
+            None => return Err(PacketError::MalformedPacket("Remaining length is shorter than the variable header".to_owned())),
+        };
+        if payload_len == 0 {
+            return Err(PacketError::EmptyUnsubscription);
+        }
+
+        let payload: UnsubscribePacketPayload =
+            try!(UnsubscribePacketPayload::decode_with(reader, Some(payload_len))
+                    .map_err(PacketError::PayloadError));
+
+        for (idx, filter) in payload.subscribes.iter().enumerate() {
+            try!(filter.validate().map_err(|reason| PacketError::InvalidTopicFilter { index: idx, reason: reason }));
+        }
+        Ok(UnsubscribePacket {
+            fixed_header: fixed_header,
+            packet_identifier: packet_identifier,
+            mqtt5: mqtt5,
+            properties: properties,
+            payload: payload,
+        })
+    }
+}
+
+/// Properties allowed in UNSUBSCRIBE
+const UNSUBSCRIBE_PROPERTIES: &'static [PropertyType] = &[
+    PropertyType::UserProperty,
+];
+
+impl fmt::Display for UnsubscribePacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "UNSUBSCRIBE(pkid={}", self.packet_identifier));
+
+        for filter in self.payload.subscribes.iter() {
+            try!(write!(f, ", {:?}", filter.as_str()));
+        }
+
+        write!(f, ")")
     }
 }
 
 impl<'a> Packet<'a> for UnsubscribePacket {
     type Payload = UnsubscribePacketPayload;
 
+    const CONTROL_TYPE: ControlType = ControlType::Unsubscribe;
+
     fn fixed_header(&self) -> &FixedHeader {
         &self.fixed_header
     }
@@ -52,42 +168,47 @@ impl<'a> Packet<'a> for UnsubscribePacket {
     }
 
     fn encode_variable_headers<W: Write>(&self, writer: &mut W) -> Result<(), PacketError<'a, Self>> {
+        // MQTT-3.10.3-2: The payload MUST contain at least one Topic Filter
+        if self.payload.subscribes.is_empty() {
+            return Err(PacketError::EmptyUnsubscription);
+        }
+
         try!(self.packet_identifier.encode(writer));
+        if self.mqtt5 {
+            try!(self.properties.encode(writer));
+        }
 
         Ok(())
     }
 
     fn encoded_variable_headers_length(&self) -> u32 {
         self.packet_identifier.encoded_length()
+            + if self.mqtt5 { self.properties.encoded_length() } else { 0 }
     }
 
     fn decode_packet<R: Read>(reader: &mut R, fixed_header: FixedHeader) -> Result<Self, PacketError<'a, Self>> {
-        let packet_identifier: PacketIdentifier = try!(PacketIdentifier::decode(reader));
-        let payload: UnsubscribePacketPayload =
-            try!(UnsubscribePacketPayload::decode_with(reader, Some(fixed_header.remaining_length
-                                                                - packet_identifier.encoded_length()))
-                    .map_err(PacketError::PayloadError));
-        Ok(UnsubscribePacket {
-            fixed_header: fixed_header,
-            packet_identifier: packet_identifier,
-            payload: payload,
-        })
+        UnsubscribePacket::decode_unsubscribe(reader, fixed_header, false)
+    }
+
+    fn decode_packet_with_version<R: Read>(reader: &mut R, fixed_header: FixedHeader, version: ProtocolVersion)
+            -> Result<Self, PacketError<'a, Self>> {
+        UnsubscribePacket::decode_unsubscribe(reader, fixed_header, version == ProtocolVersion::V5)
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct UnsubscribePacketPayload {
-    subscribes: Vec<String>,
+    subscribes: Vec<TopicFilter>,
 }
 
 impl UnsubscribePacketPayload {
-    pub fn new(subs: Vec<String>) -> UnsubscribePacketPayload {
+    pub fn new(subs: Vec<TopicFilter>) -> UnsubscribePacketPayload {
         UnsubscribePacketPayload {
             subscribes: subs,
         }
     }
 
-    pub fn subscribes(&self) -> &[String] {
+    pub fn subscribes(&self) -> &[TopicFilter] {
         &self.subscribes[..]
     }
 }
@@ -119,8 +240,10 @@ impl<'a> Decodable<'a> for UnsubscribePacketPayload {
         let mut subs = Vec::new();
 
         while payload_len > 0 {
-            let filter = try!(String::decode(reader));
-            payload_len -= filter.encoded_length();
+            let filter = try!(TopicFilter::decode(reader));
+            payload_len = try!(payload_len.checked_sub(filter.encoded_length())
+                                   .ok_or(UnsubscribePacketPayloadError::IoError(
+                                       io::Error::new(io::ErrorKind::InvalidData, "Topic filter exceeds the payload"))));
             subs.push(filter);
         }
 
@@ -174,3 +297,108 @@ impl From<byteorder::Error> for UnsubscribePacketPayloadError {
         UnsubscribePacketPayloadError::IoError(From::from(err))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use control::variable_header::{PacketIdentifier, Properties, Property, ProtocolVersion, VariableHeaderError};
+    use packet::{self, PacketError};
+    use topic_filter::TopicFilterError;
+    use {Encodable, Decodable, TopicFilter};
+
+    #[test]
+    fn test_unsubscribe_packet_basic() {
+        let mut packet = UnsubscribePacket::new(PacketIdentifier::new(10).unwrap(), vec![TopicFilter::new("a/b")]).unwrap();
+        packet.add_topic_filter(TopicFilter::new("c/#"));
+        assert_eq!(2, packet.len());
+
+        let filters: Vec<&str> = packet.topic_filters().map(|f| f.as_str()).collect();
+        assert_eq!(vec!["a/b", "c/#"], filters);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+
+        let mut decode_buf = Cursor::new(buf);
+        let decoded = UnsubscribePacket::decode(&mut decode_buf).unwrap();
+
+        assert_eq!(packet, decoded);
+    }
+
+    #[test]
+    fn test_unsubscribe_packet_empty() {
+        match UnsubscribePacket::new(PacketIdentifier::new(10).unwrap(), Vec::new()) {
+            Err(PacketError::EmptyUnsubscription) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let encoded_data = b"\xa2\x02\x00\x0a";
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        match UnsubscribePacket::decode(&mut decode_buf) {
+            Err(PacketError::EmptyUnsubscription) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_unsubscribe_packet_invalid_topic_filter() {
+        let encoded_data = b"\xa2\x0c\x00\x0a\x00\x03a/b\x00\x03a+b";
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        match UnsubscribePacket::decode(&mut decode_buf) {
+            Err(PacketError::InvalidTopicFilter { index: 1, reason: TopicFilterError::InvalidSingleLevelWildcard }) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_unsubscribe_packet_zero_packet_identifier() {
+        let encoded_data = b"\xa2\x07\x00\x00\x00\x03a/b";
+        let mut decode_buf = Cursor::new(&encoded_data[..]);
+        match UnsubscribePacket::decode(&mut decode_buf) {
+            Err(PacketError::VariableHeaderError(VariableHeaderError::ZeroPacketIdentifier)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_unsubscribe_packet_mqtt_5() {
+        let encoded_data = b"\xa2\x06\x00\x0a\x00\x00\x01a";
+        let packet: UnsubscribePacket =
+            packet::decode_with_version(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5).unwrap();
+        assert!(packet.is_mqtt5());
+        assert_eq!(10, packet.packet_identifier().get());
+        let filters: Vec<&str> = packet.topic_filters().map(|f| f.as_str()).collect();
+        assert_eq!(vec!["a"], filters);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(&encoded_data[..], &buf[..]);
+
+        let mut packet = UnsubscribePacket::with_version(PacketIdentifier::new(10).unwrap(),
+                                                         vec![TopicFilter::new("a/b")], ProtocolVersion::V5).unwrap();
+        let mut properties = Properties::new();
+        properties.insert(Property::UserProperty("region".to_owned(), "eu".to_owned()));
+        packet.set_properties(properties);
+        packet.add_topic_filter(TopicFilter::new("c/#"));
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(packet.encoded_length() as usize, buf.len());
+
+        let decoded: UnsubscribePacket =
+            packet::decode_with_version(&mut Cursor::new(&buf[..]), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet, decoded);
+    }
+
+    #[test]
+    fn test_unsubscribe_packet_mqtt_5_invalid_property() {
+        // Reason String
+        let encoded_data = b"\xa2\x0a\x00\x0a\x04\x1f\x00\x01x\x00\x01a";
+        match packet::decode_with_version::<UnsubscribePacket, _>(&mut Cursor::new(&encoded_data[..]), ProtocolVersion::V5) {
+            Err(PacketError::MalformedPacket(..)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+}
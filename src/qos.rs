@@ -1,3 +1,8 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+/// Quality of Service levels, ordered from `Level0` to `Level2`
 #[repr(u8)]
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
 pub enum QualityOfService {
@@ -5,3 +10,69 @@ pub enum QualityOfService {
     Level1 = 1,
     Level2 = 2,
 }
+
+impl QualityOfService {
+    pub fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
+impl TryFrom<u8> for QualityOfService {
+    type Error = InvalidQoSError;
+
+    fn try_from(qos: u8) -> Result<QualityOfService, InvalidQoSError> {
+        match qos {
+            0 => Ok(QualityOfService::Level0),
+            1 => Ok(QualityOfService::Level1),
+            2 => Ok(QualityOfService::Level2),
+            _ => Err(InvalidQoSError(qos)),
+        }
+    }
+}
+
+impl fmt::Display for QualityOfService {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", *self as u8)
+    }
+}
+
+/// A QoS value other than 0, 1 or 2
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct InvalidQoSError(pub u8);
+
+impl fmt::Display for InvalidQoSError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid quality of service ({})", self.0)
+    }
+}
+
+impl Error for InvalidQoSError {
+    fn description(&self) -> &str {
+        "Invalid quality of service"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_qos_ordering() {
+        assert!(QualityOfService::Level0 < QualityOfService::Level1);
+        assert!(QualityOfService::Level1 < QualityOfService::Level2);
+        assert_eq!(QualityOfService::Level1, QualityOfService::Level2.min(QualityOfService::Level1));
+        assert_eq!(QualityOfService::Level2, QualityOfService::Level0.max(QualityOfService::Level2));
+    }
+
+    #[test]
+    fn test_qos_try_from_u8() {
+        for qos in [QualityOfService::Level0, QualityOfService::Level1, QualityOfService::Level2].iter() {
+            assert_eq!(Ok(*qos), QualityOfService::try_from(qos.to_u8()));
+        }
+
+        assert_eq!(Err(InvalidQoSError(3)), QualityOfService::try_from(3));
+        assert_eq!(Err(InvalidQoSError(0x80)), QualityOfService::try_from(0x80));
+    }
+}
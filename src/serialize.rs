@@ -0,0 +1,796 @@
+//! serde support, enabled by the `serde` feature
+//!
+//! Packets are (de)serialized through plain representations of their fields, the wire format
+//! is not involved. Deserializing applies the checks of `VariablePacket::decode_with_options`
+//! with the default options, e.g. topic names with wildcards or null characters are rejected.
+//!
+//! Payloads and passwords are base64 strings in human-readable formats and raw bytes otherwise.
+//! MQTT 5 properties are a list in wire order, each tagged with its name, e.g.
+//! `[{"ContentType": "text/plain"}, {"UserProperty": ["key", "value"]}]` in JSON.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Cursor;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, Visitor};
+use serde::ser;
+
+use control::variable_header::{ConnectReasonCode, ConnectReturnCode, PacketIdentifier, Properties, Property};
+use control::variable_header::{ProtocolVersion, TopicName};
+use control::variable_header::protocol_level::BRIDGE_FLAG;
+use packet::*;
+use packet::suback::SubscribeReturnCode;
+use {Encodable, QualityOfService, TopicFilter};
+
+const BASE64_CHARS: &'static [u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_CHARS[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if encoded.len() % 4 != 0 {
+        return None;
+    }
+
+    let mut bytes = Vec::with_capacity(encoded.len() / 4 * 3);
+    for (index, chunk) in encoded.chunks(4).enumerate() {
+        let last = index == encoded.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let value = match BASE64_CHARS.iter().position(|&b| b == c) {
+                Some(value) => value as u32,
+                None => return None,
+            };
+            n = n << 6 | value;
+        }
+        n <<= 6 * padding as u32;
+
+        bytes.push((n >> 16) as u8);
+        if padding < 2 {
+            bytes.push((n >> 8) as u8);
+        }
+        if padding < 1 {
+            bytes.push(n as u8);
+        }
+    }
+    Some(bytes)
+}
+
+/// Binary data, base64 in human-readable formats
+struct Bytes(Vec<u8>);
+
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&base64_encode(&self.0))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = try!(String::deserialize(deserializer));
+            base64_decode(&encoded).map(Bytes).ok_or_else(|| de::Error::custom("invalid base64"))
+        } else {
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Bytes;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("bytes")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Bytes, E> {
+        Ok(Bytes(bytes.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Bytes, E> {
+        Ok(Bytes(bytes))
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Bytes, A::Error> {
+        let mut bytes = Vec::new();
+        while let Some(b) = try!(seq.next_element()) {
+            bytes.push(b);
+        }
+        Ok(Bytes(bytes))
+    }
+}
+
+// MQTT-1.5.3-2, rejected by the decoder with `DecodeOptions::strict_utf8`
+fn check_null_character<E: de::Error>(s: &str) -> Result<(), E> {
+    if s.contains('\0') {
+        Err(E::custom("string contains the null character"))
+    } else {
+        Ok(())
+    }
+}
+
+// MQTT 5 packets are checked by decoding their encoding, which covers the properties allowed in
+// the packet and their values without repeating the decoder's rules here
+fn check_mqtt5_packet<E: de::Error>(packet: VariablePacket) -> Result<(), E> {
+    let mut buf = Vec::new();
+    try!(packet.encode(&mut buf).map_err(E::custom));
+    let options = DecodeOptions::new().protocol_version(ProtocolVersion::V5);
+    VariablePacket::decode_with_options(&mut Cursor::new(&buf[..]), &options).map(|_| ()).map_err(E::custom)
+}
+
+impl Serialize for Properties {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for Properties {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Properties, D::Error> {
+        let mut properties = Properties::new();
+        for property in try!(Vec::<Property>::deserialize(deserializer)) {
+            let property_type = property.property_type();
+            // `insert` would replace it, the decoder rejects it
+            if !property_type.may_repeat() && properties.get(property_type).is_some() {
+                return Err(de::Error::custom(format!("property {:?} included more than once", property_type)));
+            }
+            properties.insert(property);
+        }
+        Ok(properties)
+    }
+}
+
+impl Serialize for QualityOfService {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.to_u8())
+    }
+}
+
+impl<'de> Deserialize<'de> for QualityOfService {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<QualityOfService, D::Error> {
+        let qos = try!(u8::deserialize(deserializer));
+        QualityOfService::try_from(qos).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for PacketIdentifier {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(self.get())
+    }
+}
+
+impl<'de> Deserialize<'de> for PacketIdentifier {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PacketIdentifier, D::Error> {
+        let pkid = try!(u16::deserialize(deserializer));
+        PacketIdentifier::new(pkid).ok_or_else(|| de::Error::custom("packet identifier must be non-zero"))
+    }
+}
+
+impl Serialize for TopicName {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for TopicName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TopicName, D::Error> {
+        let topic_name = try!(String::deserialize(deserializer));
+        try!(check_null_character(&topic_name));
+        TopicName::new(topic_name).ok_or_else(|| de::Error::custom("topic name contains wildcards"))
+    }
+}
+
+impl Serialize for TopicFilter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for TopicFilter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TopicFilter, D::Error> {
+        let filter = TopicFilter::new(try!(String::deserialize(deserializer)));
+        try!(filter.validate().map_err(de::Error::custom));
+        Ok(filter)
+    }
+}
+
+impl Serialize for ConnectReturnCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.to_u8())
+    }
+}
+
+impl<'de> Deserialize<'de> for ConnectReturnCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ConnectReturnCode, D::Error> {
+        u8::deserialize(deserializer).map(ConnectReturnCode::from_u8)
+    }
+}
+
+impl Serialize for SubscribeReturnCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.to_u8())
+    }
+}
+
+impl<'de> Deserialize<'de> for SubscribeReturnCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SubscribeReturnCode, D::Error> {
+        let code = try!(u8::deserialize(deserializer));
+        SubscribeReturnCode::from_u8(code).ok_or_else(|| de::Error::custom(format!("invalid return code {}", code)))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct QoSRepr {
+    qos: QualityOfService,
+    packet_identifier: Option<PacketIdentifier>,
+}
+
+impl Serialize for QoSWithPacketIdentifier {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let packet_identifier = match *self {
+            QoSWithPacketIdentifier::Level0 => None,
+            QoSWithPacketIdentifier::Level1(pkid) | QoSWithPacketIdentifier::Level2(pkid) => Some(pkid),
+        };
+        QoSRepr { qos: self.qos(), packet_identifier: packet_identifier }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for QoSWithPacketIdentifier {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<QoSWithPacketIdentifier, D::Error> {
+        let repr = try!(QoSRepr::deserialize(deserializer));
+        match (repr.qos, repr.packet_identifier) {
+            (QualityOfService::Level0, None) => Ok(QoSWithPacketIdentifier::Level0),
+            (QualityOfService::Level1, Some(pkid)) => Ok(QoSWithPacketIdentifier::Level1(pkid)),
+            (QualityOfService::Level2, Some(pkid)) => Ok(QoSWithPacketIdentifier::Level2(pkid)),
+            (QualityOfService::Level0, Some(..)) => Err(de::Error::custom("QoS 0 messages have no packet identifier")),
+            (_, None) => Err(de::Error::custom("missing packet identifier")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct LastWillRepr {
+    topic: TopicName,
+    message: Bytes,
+    qos: QualityOfService,
+    retain: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ConnectRepr {
+    protocol_level: u8,
+    client_identifier: String,
+    clean_session: bool,
+    keep_alive: u16,
+    will: Option<LastWillRepr>,
+    user_name: Option<String>,
+    password: Option<Bytes>,
+}
+
+impl Serialize for ConnectPacket {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ConnectRepr {
+            protocol_level: self.protocol_level(),
+            client_identifier: self.client_identifier().to_owned(),
+            clean_session: self.clean_session(),
+            keep_alive: self.keep_alive(),
+            will: self.will().map(|will| LastWillRepr {
+                topic: will.topic.clone(),
+                message: Bytes(will.message.clone()),
+                qos: will.qos,
+                retain: will.retain,
+            }),
+            user_name: self.user_name().map(ToOwned::to_owned),
+            password: self.password().map(|password| Bytes(password.to_vec())),
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ConnectPacket {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ConnectPacket, D::Error> {
+        let repr = try!(ConnectRepr::deserialize(deserializer));
+
+        // Bridges set bit 7 of the protocol level
+        let version = match repr.protocol_level & !BRIDGE_FLAG {
+            3 => ProtocolVersion::V3_1,
+            4 => ProtocolVersion::V3_1_1,
+            _ => return Err(de::Error::custom(format!("unsupported protocol level {}", repr.protocol_level))),
+        };
+        try!(check_null_character(&repr.client_identifier));
+
+        let mut builder = ConnectPacket::builder(&repr.client_identifier)
+            .protocol_version(version)
+            .clean_session(repr.clean_session)
+            .keep_alive(repr.keep_alive);
+        if let Some(will) = repr.will {
+            builder = builder.will(&will.topic.0, &will.message.0, will.qos, will.retain);
+        }
+        if let Some(user_name) = repr.user_name {
+            try!(check_null_character(&user_name));
+            builder = builder.user_name(&user_name);
+        }
+        if let Some(password) = repr.password {
+            builder = builder.password(&password.0);
+        }
+        let mut connect = try!(builder.build().map_err(de::Error::custom));
+        connect.set_bridge_mode(repr.protocol_level & BRIDGE_FLAG != 0);
+        Ok(connect)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ConnackRepr {
+    session_present: bool,
+    mqtt5: bool,
+    /// Return Code, or Reason Code of an MQTT 5 CONNACK
+    code: u8,
+    properties: Properties,
+}
+
+impl Serialize for ConnackPacket {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let code = if self.is_mqtt5() {
+            self.connect_reason_code().to_u8()
+        } else {
+            self.connect_return_code().to_u8()
+        };
+        ConnackRepr {
+            session_present: self.session_present(),
+            mqtt5: self.is_mqtt5(),
+            code: code,
+            properties: self.properties().clone(),
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ConnackPacket {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ConnackPacket, D::Error> {
+        let repr = try!(ConnackRepr::deserialize(deserializer));
+
+        if !repr.mqtt5 {
+            let return_code = ConnectReturnCode::from_u8(repr.code);
+            // MQTT-3.2.2-4
+            if repr.session_present && !return_code.is_accepted() {
+                return Err(de::Error::custom("session present with a rejected connection"));
+            }
+            if !repr.properties.is_empty() {
+                return Err(de::Error::custom("properties on an MQTT 3.1.1 CONNACK"));
+            }
+            return Ok(ConnackPacket::new(repr.session_present, return_code));
+        }
+
+        let mut connack = ConnackPacket::with_reason_code(repr.session_present, ConnectReasonCode::from_u8(repr.code));
+        connack.set_properties(repr.properties);
+        try!(check_mqtt5_packet(VariablePacket::new(connack.clone())));
+        Ok(connack)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PublishRepr {
+    topic_name: TopicName,
+    qos: QoSWithPacketIdentifier,
+    retain: bool,
+    dup: bool,
+    mqtt5: bool,
+    properties: Properties,
+    payload: Bytes,
+}
+
+impl Serialize for PublishPacket {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        PublishRepr {
+            topic_name: TopicName(self.topic_name().to_owned()),
+            qos: self.qos(),
+            retain: self.retain(),
+            dup: self.dup(),
+            mqtt5: self.is_mqtt5(),
+            properties: self.properties().clone(),
+            payload: Bytes(self.payload().clone()),
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PublishPacket {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PublishPacket, D::Error> {
+        let repr = try!(PublishRepr::deserialize(deserializer));
+        // MQTT-3.3.1-2
+        if repr.dup && repr.qos == QoSWithPacketIdentifier::Level0 {
+            return Err(de::Error::custom("QoS 0 messages cannot be DUP"));
+        }
+
+        let version = if repr.mqtt5 { ProtocolVersion::V5 } else { ProtocolVersion::V3_1_1 };
+        let mut publish = PublishPacket::with_version(repr.topic_name.0, repr.qos, repr.payload.0, version);
+        publish.set_retain(repr.retain);
+        publish.set_dup(repr.dup);
+        *publish.properties_mut() = repr.properties;
+        if repr.mqtt5 {
+            try!(check_mqtt5_packet(VariablePacket::new(publish.clone())));
+        }
+        Ok(publish)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct AcknowledgementRepr {
+    packet_identifier: u16,
+}
+
+macro_rules! impl_serde_for_acknowledgements {
+    ($($name:ident,)+) => {
+        $(
+            impl Serialize for $name {
+                fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    AcknowledgementRepr { packet_identifier: self.packet_identifier() }.serialize(serializer)
+                }
+            }
+
+            impl<'de> Deserialize<'de> for $name {
+                fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<$name, D::Error> {
+                    let repr = try!(AcknowledgementRepr::deserialize(deserializer));
+                    Ok($name::new(repr.packet_identifier))
+                }
+            }
+        )+
+    }
+}
+
+impl_serde_for_acknowledgements! {
+    PubackPacket,
+    PubrecPacket,
+    PubrelPacket,
+    PubcompPacket,
+    UnsubackPacket,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SubscribeRepr {
+    packet_identifier: PacketIdentifier,
+    subscriptions: Vec<(TopicFilter, QualityOfService)>,
+}
+
+impl Serialize for SubscribePacket {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SubscribeRepr {
+            packet_identifier: self.packet_identifier(),
+            subscriptions: self.subscriptions().map(|(filter, qos)| (filter.clone(), qos)).collect(),
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SubscribePacket {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SubscribePacket, D::Error> {
+        let repr = try!(SubscribeRepr::deserialize(deserializer));
+        // MQTT-3.8.3-3
+        if repr.subscriptions.is_empty() {
+            return Err(de::Error::custom("SUBSCRIBE without subscriptions"));
+        }
+        Ok(SubscribePacket::new(repr.packet_identifier, repr.subscriptions))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SubackRepr {
+    packet_identifier: u16,
+    return_codes: Vec<SubscribeReturnCode>,
+}
+
+impl Serialize for SubackPacket {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SubackRepr {
+            packet_identifier: self.packet_identifier(),
+            return_codes: self.return_codes().to_vec(),
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SubackPacket {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SubackPacket, D::Error> {
+        let repr = try!(SubackRepr::deserialize(deserializer));
+        Ok(SubackPacket::new(repr.packet_identifier, repr.return_codes))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct UnsubscribeRepr {
+    packet_identifier: PacketIdentifier,
+    topic_filters: Vec<TopicFilter>,
+}
+
+impl Serialize for UnsubscribePacket {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        UnsubscribeRepr {
+            packet_identifier: self.packet_identifier(),
+            topic_filters: self.topic_filters().cloned().collect(),
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for UnsubscribePacket {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<UnsubscribePacket, D::Error> {
+        let repr = try!(UnsubscribeRepr::deserialize(deserializer));
+        UnsubscribePacket::new(repr.packet_identifier, repr.topic_filters)
+            .map_err(|_| de::Error::custom("UNSUBSCRIBE without topic filters"))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct EmptyRepr;
+
+macro_rules! impl_serde_for_empty_packets {
+    ($($name:ident,)+) => {
+        $(
+            impl Serialize for $name {
+                fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    EmptyRepr.serialize(serializer)
+                }
+            }
+
+            impl<'de> Deserialize<'de> for $name {
+                fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<$name, D::Error> {
+                    try!(EmptyRepr::deserialize(deserializer));
+                    Ok($name::new())
+                }
+            }
+        )+
+    }
+}
+
+impl_serde_for_empty_packets! {
+    PingreqPacket,
+    PingrespPacket,
+    DisconnectPacket,
+}
+
+#[derive(Serialize)]
+enum VariablePacketRef<'a> {
+    Connect(&'a ConnectPacket),
+    Connack(&'a ConnackPacket),
+    Publish(&'a PublishPacket),
+    Puback(&'a PubackPacket),
+    Pubrec(&'a PubrecPacket),
+    Pubrel(&'a PubrelPacket),
+    Pubcomp(&'a PubcompPacket),
+    Subscribe(&'a SubscribePacket),
+    Suback(&'a SubackPacket),
+    Unsubscribe(&'a UnsubscribePacket),
+    Unsuback(&'a UnsubackPacket),
+    Pingreq(&'a PingreqPacket),
+    Pingresp(&'a PingrespPacket),
+    Disconnect(&'a DisconnectPacket),
+}
+
+#[derive(Deserialize)]
+enum VariablePacketRepr {
+    Connect(ConnectPacket),
+    Connack(ConnackPacket),
+    Publish(PublishPacket),
+    Puback(PubackPacket),
+    Pubrec(PubrecPacket),
+    Pubrel(PubrelPacket),
+    Pubcomp(PubcompPacket),
+    Subscribe(SubscribePacket),
+    Suback(SubackPacket),
+    Unsubscribe(UnsubscribePacket),
+    Unsuback(UnsubackPacket),
+    Pingreq(PingreqPacket),
+    Pingresp(PingrespPacket),
+    Disconnect(DisconnectPacket),
+}
+
+/// Externally tagged by the packet type, e.g. `{"Puback": {"packet_identifier": 10}}` in JSON
+impl Serialize for VariablePacket {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let packet = match self {
+            &VariablePacket::ConnectPacket(ref pk) => VariablePacketRef::Connect(pk),
+            &VariablePacket::ConnackPacket(ref pk) => VariablePacketRef::Connack(pk),
+            &VariablePacket::PublishPacket(ref pk) => VariablePacketRef::Publish(pk),
+            &VariablePacket::PubackPacket(ref pk) => VariablePacketRef::Puback(pk),
+            &VariablePacket::PubrecPacket(ref pk) => VariablePacketRef::Pubrec(pk),
+            &VariablePacket::PubrelPacket(ref pk) => VariablePacketRef::Pubrel(pk),
+            &VariablePacket::PubcompPacket(ref pk) => VariablePacketRef::Pubcomp(pk),
+            &VariablePacket::SubscribePacket(ref pk) => VariablePacketRef::Subscribe(pk),
+            &VariablePacket::SubackPacket(ref pk) => VariablePacketRef::Suback(pk),
+            &VariablePacket::UnsubscribePacket(ref pk) => VariablePacketRef::Unsubscribe(pk),
+            &VariablePacket::UnsubackPacket(ref pk) => VariablePacketRef::Unsuback(pk),
+            &VariablePacket::PingreqPacket(ref pk) => VariablePacketRef::Pingreq(pk),
+            &VariablePacket::PingrespPacket(ref pk) => VariablePacketRef::Pingresp(pk),
+            &VariablePacket::DisconnectPacket(ref pk) => VariablePacketRef::Disconnect(pk),
+            // MQTT 5 properties have no representation yet
+            &VariablePacket::AuthPacket(..) => return Err(ser::Error::custom("AUTH packets are not supported")),
+        };
+        packet.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for VariablePacket {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<VariablePacket, D::Error> {
+        Ok(match try!(VariablePacketRepr::deserialize(deserializer)) {
+            VariablePacketRepr::Connect(pk) => VariablePacket::new(pk),
+            VariablePacketRepr::Connack(pk) => VariablePacket::new(pk),
+            VariablePacketRepr::Publish(pk) => VariablePacket::new(pk),
+            VariablePacketRepr::Puback(pk) => VariablePacket::new(pk),
+            VariablePacketRepr::Pubrec(pk) => VariablePacket::new(pk),
+            VariablePacketRepr::Pubrel(pk) => VariablePacket::new(pk),
+            VariablePacketRepr::Pubcomp(pk) => VariablePacket::new(pk),
+            VariablePacketRepr::Subscribe(pk) => VariablePacket::new(pk),
+            VariablePacketRepr::Suback(pk) => VariablePacket::new(pk),
+            VariablePacketRepr::Unsubscribe(pk) => VariablePacket::new(pk),
+            VariablePacketRepr::Unsuback(pk) => VariablePacket::new(pk),
+            VariablePacketRepr::Pingreq(pk) => VariablePacket::new(pk),
+            VariablePacketRepr::Pingresp(pk) => VariablePacket::new(pk),
+            VariablePacketRepr::Disconnect(pk) => VariablePacket::new(pk),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json;
+    use bincode;
+
+    use testing::arbitrary::Gen;
+    use testing::vectors::vectors;
+
+    fn packets() -> Vec<VariablePacket> {
+        // Only PUBLISH and CONNACK have a representation of MQTT 5 fields
+        let mut packets: Vec<VariablePacket> = vectors().into_iter()
+            .filter(|v| v.version != ProtocolVersion::V5)
+            .map(|v| v.packet)
+            .collect();
+        let mut g = Gen::new(0);
+        packets.extend((0..500).map(|_| g.generate::<VariablePacket>()));
+        packets
+    }
+
+    #[test]
+    fn test_base64() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"", ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"hello", "aGVsbG8="),
+            (b"\xff\x00\xfe", "/wD+"),
+        ];
+        for &(bytes, encoded) in cases {
+            assert_eq!(encoded, base64_encode(bytes));
+            assert_eq!(Some(bytes.to_vec()), base64_decode(encoded));
+        }
+
+        assert_eq!(None, base64_decode("Zg="));
+        assert_eq!(None, base64_decode("Z==="));
+        assert_eq!(None, base64_decode("Zg==Zg=="));
+        assert_eq!(None, base64_decode("Zm9*"));
+    }
+
+    #[test]
+    fn test_serde_json_round_trip() {
+        for packet in packets() {
+            let json = serde_json::to_string(&packet).unwrap();
+            let decoded: VariablePacket = serde_json::from_str(&json).unwrap_or_else(|err| panic!("{}: {}", json, err));
+            assert_eq!(packet, decoded, "{}", json);
+        }
+    }
+
+    #[test]
+    fn test_serde_bincode_round_trip() {
+        for packet in packets() {
+            let encoded = bincode::serialize(&packet).unwrap();
+            let decoded: VariablePacket = bincode::deserialize(&encoded).unwrap();
+            assert_eq!(packet, decoded);
+        }
+    }
+
+    #[test]
+    fn test_serde_round_trip_bridge_and_mqtt5() {
+        let mut packets = Vec::new();
+        for &version in [ProtocolVersion::V3_1, ProtocolVersion::V3_1_1].iter() {
+            let mut connect = ConnectPacket::with_version("bridge".to_owned(), version);
+            connect.set_bridge_mode(true);
+            packets.push(VariablePacket::new(connect));
+        }
+
+        let mut publish = PublishPacket::with_version("a/b".to_owned(),
+                                                      QoSWithPacketIdentifier::Level2(PacketIdentifier::new(3).unwrap()),
+                                                      b"hello".to_vec(), ProtocolVersion::V5);
+        publish.set_correlation_data(b"\x00\xff".to_vec());
+        publish.add_subscription_identifier(7).unwrap();
+        publish.add_subscription_identifier(9).unwrap();
+        packets.push(VariablePacket::new(publish));
+
+        let mut connack = ConnackPacket::with_reason_code(true, ConnectReasonCode::Success);
+        let mut properties = Properties::new();
+        properties.insert(Property::ReceiveMaximum(10));
+        properties.push_user_property("k", "v");
+        connack.set_properties(properties);
+        packets.push(VariablePacket::new(connack));
+        packets.push(VariablePacket::new(ConnackPacket::with_reason_code(false, ConnectReasonCode::BadUserNameOrPassword)));
+
+        for packet in packets {
+            let json = serde_json::to_string(&packet).unwrap();
+            assert_eq!(packet, serde_json::from_str::<VariablePacket>(&json).unwrap(), "{}", json);
+            let encoded = bincode::serialize(&packet).unwrap();
+            assert_eq!(packet, bincode::deserialize::<VariablePacket>(&encoded).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_serde_json_format() {
+        let publish = PublishPacket::new("a/b".to_owned(),
+                                         QoSWithPacketIdentifier::Level1(PacketIdentifier::new(10).unwrap()),
+                                         b"hello".to_vec());
+        assert_eq!(r#"{"Publish":{"topic_name":"a/b","qos":{"qos":1,"packet_identifier":10},"retain":false,"dup":false,"mqtt5":false,"properties":[],"payload":"aGVsbG8="}}"#,
+                   serde_json::to_string(&VariablePacket::new(publish)).unwrap());
+        let mut publish = PublishPacket::with_version("a/b".to_owned(), QoSWithPacketIdentifier::Level0,
+                                                      b"hi".to_vec(), ProtocolVersion::V5);
+        publish.set_content_type("text/plain");
+        publish.properties_mut().push_user_property("k", "v");
+        assert_eq!(r#"{"Publish":{"topic_name":"a/b","qos":{"qos":0,"packet_identifier":null},"retain":false,"dup":false,"mqtt5":true,"properties":[{"ContentType":"text/plain"},{"UserProperty":["k","v"]}],"payload":"aGk="}}"#,
+                   serde_json::to_string(&VariablePacket::new(publish)).unwrap());
+
+        let mut connack = ConnackPacket::with_reason_code(false, ConnectReasonCode::NotAuthorized);
+        connack.set_reason_string(Some("denied".to_owned()));
+        assert_eq!(r#"{"Connack":{"session_present":false,"mqtt5":true,"code":135,"properties":[{"ReasonString":"denied"}]}}"#,
+                   serde_json::to_string(&VariablePacket::new(connack)).unwrap());
+        assert_eq!(r#"{"Pingreq":null}"#, serde_json::to_string(&VariablePacket::new(PingreqPacket::new())).unwrap());
+    }
+
+    #[test]
+    fn test_serde_json_validation() {
+        let invalid = [
+            r#"{"Publish":{"topic_name":"a/+","qos":{"qos":0,"packet_identifier":null},"retain":false,"dup":false,"mqtt5":false,"properties":[],"payload":""}}"#,
+            r#"{"Publish":{"topic_name":"a\u0000","qos":{"qos":0,"packet_identifier":null},"retain":false,"dup":false,"mqtt5":false,"properties":[],"payload":""}}"#,
+            r#"{"Publish":{"topic_name":"a","qos":{"qos":0,"packet_identifier":null},"retain":false,"dup":true,"mqtt5":false,"properties":[],"payload":""}}"#,
+            r#"{"Publish":{"topic_name":"a","qos":{"qos":1,"packet_identifier":null},"retain":false,"dup":false,"mqtt5":false,"properties":[],"payload":""}}"#,
+            r#"{"Publish":{"topic_name":"a","qos":{"qos":3,"packet_identifier":1},"retain":false,"dup":false,"mqtt5":false,"properties":[],"payload":""}}"#,
+            r#"{"Publish":{"topic_name":"a","qos":{"qos":0,"packet_identifier":null},"retain":false,"dup":false,"mqtt5":false,"properties":[],"payload":"!"}}"#,
+            r#"{"Subscribe":{"packet_identifier":0,"subscriptions":[["a",1]]}}"#,
+            r#"{"Subscribe":{"packet_identifier":1,"subscriptions":[["a/#/b",1]]}}"#,
+            r#"{"Subscribe":{"packet_identifier":1,"subscriptions":[]}}"#,
+            r#"{"Suback":{"packet_identifier":1,"return_codes":[3]}}"#,
+            r#"{"Connack":{"session_present":true,"mqtt5":false,"code":5,"properties":[]}}"#,
+            r#"{"Connack":{"session_present":false,"mqtt5":false,"code":0,"properties":[{"ReceiveMaximum":10}]}}"#,
+            r#"{"Connack":{"session_present":true,"mqtt5":true,"code":135,"properties":[]}}"#,
+            r#"{"Connack":{"session_present":false,"mqtt5":true,"code":0,"properties":[{"TopicAlias":1}]}}"#,
+            r#"{"Connack":{"session_present":false,"mqtt5":true,"code":0,"properties":[{"ReceiveMaximum":1},{"ReceiveMaximum":2}]}}"#,
+            r#"{"Publish":{"topic_name":"a","qos":{"qos":0,"packet_identifier":null},"retain":false,"dup":false,"mqtt5":true,"properties":[{"SubscriptionIdentifier":0}],"payload":""}}"#,
+            r#"{"Publish":{"topic_name":"a","qos":{"qos":0,"packet_identifier":null},"retain":false,"dup":false,"mqtt5":true,"properties":[{"ReceiveMaximum":1}],"payload":""}}"#,
+            r#"{"Publish":{"topic_name":"a","qos":{"qos":0,"packet_identifier":null},"retain":false,"dup":false,"mqtt5":true,"properties":[{"UserProperty":["a\u0000","b"]}],"payload":""}}"#,
+            r#"{"Connect":{"protocol_level":133,"client_identifier":"a","clean_session":true,"keep_alive":0,"will":null,"user_name":null,"password":null}}"#,
+            r#"{"Connect":{"protocol_level":5,"client_identifier":"a","clean_session":true,"keep_alive":0,"will":null,"user_name":null,"password":null}}"#,
+            r#"{"Connect":{"protocol_level":4,"client_identifier":"a","clean_session":true,"keep_alive":0,"will":null,"user_name":null,"password":"cA=="}}"#,
+        ];
+
+        for json in invalid.iter() {
+            assert!(serde_json::from_str::<VariablePacket>(json).is_err(), "{} was accepted", json);
+        }
+    }
+}
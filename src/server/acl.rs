@@ -0,0 +1,341 @@
+//! Topic authorization of PUBLISH and SUBSCRIBE packets
+
+use control::variable_header::TopicName;
+use server::trie::TopicTrie;
+use topic_filter::TopicFilter;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum AclAction {
+    Publish,
+    Subscribe,
+    Both,
+}
+
+impl AclAction {
+    fn includes(&self, action: AclAction) -> bool {
+        *self == AclAction::Both || *self == action
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Permission {
+    Allow,
+    Deny,
+}
+
+/// Allows or denies an action on the topics matching the filter
+///
+/// The filter may contain `%c` and `%u`, replaced by the client identifier and the user name
+/// when the policy is instantiated for a connection.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct AclRule {
+    pub action: AclAction,
+    pub permission: Permission,
+    pub filter: TopicFilter,
+}
+
+impl AclRule {
+    pub fn new(action: AclAction, permission: Permission, filter: TopicFilter) -> AclRule {
+        AclRule {
+            action: action,
+            permission: permission,
+            filter: filter,
+        }
+    }
+
+    pub fn allow<F: Into<TopicFilter>>(action: AclAction, filter: F) -> AclRule {
+        AclRule::new(action, Permission::Allow, filter.into())
+    }
+
+    pub fn deny<F: Into<TopicFilter>>(action: AclAction, filter: F) -> AclRule {
+        AclRule::new(action, Permission::Deny, filter.into())
+    }
+
+    /// Replaces `%c` and `%u`, `None` if the rule has to be dropped
+    ///
+    /// A client identifier or user name could widen the filter with wildcards or level
+    /// separators, or be missing. An allow rule is then dropped, a deny rule instead denies
+    /// everything from the level of the placeholder on.
+    fn instantiate(&self, client_id: &str, user_name: Option<&str>) -> Option<AclRule> {
+        let mut levels = Vec::new();
+        let mut widened = false;
+        for level in self.filter.as_str().split('/') {
+            let substituted = substitute(level, "%c", Some(client_id))
+                                  .and_then(|level| substitute(&level, "%u", user_name));
+            match substituted {
+                Some(ref substituted) if substituted == level || is_plain_level(substituted) => {
+                    levels.push(substituted.clone());
+                },
+                _ => {
+                    levels.push("#".to_owned());
+                    widened = true;
+                    break;
+                },
+            }
+        }
+
+        let filter = TopicFilter::new(levels.join("/"));
+        if (widened || filter.validate().is_err()) && self.permission == Permission::Allow {
+            return None;
+        }
+        Some(AclRule::new(self.action, self.permission, filter))
+    }
+}
+
+fn substitute(level: &str, placeholder: &str, value: Option<&str>) -> Option<String> {
+    if !level.contains(placeholder) {
+        return Some(level.to_owned());
+    }
+    value.map(|value| level.replace(placeholder, value))
+}
+
+fn is_plain_level(level: &str) -> bool {
+    !level.is_empty() && !level.contains(|c| c == '/' || c == '+' || c == '#' || c == '\0')
+}
+
+/// Ordered rules, the first rule that applies decides
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct AclPolicy {
+    rules: Vec<AclRule>,
+    default: Permission,
+}
+
+impl AclPolicy {
+    /// Policy applying `default` when no rule applies
+    pub fn new(default: Permission) -> AclPolicy {
+        AclPolicy {
+            rules: Vec::new(),
+            default: default,
+        }
+    }
+
+    pub fn rule(mut self, rule: AclRule) -> AclPolicy {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn push(&mut self, rule: AclRule) {
+        self.rules.push(rule);
+    }
+
+    pub fn rules(&self) -> &[AclRule] {
+        &self.rules[..]
+    }
+
+    /// Rules of one connection, with `%c` and `%u` replaced
+    pub fn instantiate(&self, client_id: &str, user_name: Option<&str>) -> Acl {
+        let rules: Vec<AclRule> = self.rules.iter()
+            .filter_map(|rule| rule.instantiate(client_id, user_name))
+            .collect();
+
+        let mut publish: TopicTrie<Vec<usize>> = TopicTrie::new();
+        for (index, rule) in rules.iter().enumerate() {
+            if !rule.action.includes(AclAction::Publish) {
+                continue;
+            }
+            let found = match publish.get_mut(rule.filter.as_str()) {
+                Some(indices) => {
+                    indices.push(index);
+                    true
+                },
+                None => false,
+            };
+            if !found {
+                publish.insert(rule.filter.as_str(), vec![index]);
+            }
+        }
+
+        Acl {
+            rules: rules,
+            publish: publish,
+            default: self.default,
+        }
+    }
+}
+
+/// `AclPolicy` instantiated for one connection
+#[derive(Debug, Clone)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+    publish: TopicTrie<Vec<usize>>,
+    default: Permission,
+}
+
+impl Acl {
+    pub fn rules(&self) -> &[AclRule] {
+        &self.rules[..]
+    }
+
+    pub fn check_publish(&self, topic_name: &TopicName) -> Permission {
+        self.publish.matching_topic(&topic_name.0).into_iter()
+            .flat_map(|indices| indices.iter())
+            .min()
+            .map(|&index| self.rules[index].permission)
+            .unwrap_or(self.default)
+    }
+
+    /// Checks a subscription against the rules in order
+    ///
+    /// The first rule sharing a topic with the filter decides. An allow rule has to cover
+    /// every topic of the filter, a subscription to `a/#` is denied when only `a/b` is allowed.
+    pub fn check_subscribe(&self, filter: &TopicFilter) -> Permission {
+        let requested = filter.as_str().split('/').collect::<Vec<_>>();
+        for rule in self.rules.iter().filter(|rule| rule.action.includes(AclAction::Subscribe)) {
+            let levels = rule.filter.as_str().split('/').collect::<Vec<_>>();
+            if !intersects(&levels, &requested, true) {
+                continue;
+            }
+
+            return match rule.permission {
+                Permission::Allow if covers(&levels, &requested, true) => Permission::Allow,
+                _ => Permission::Deny,
+            };
+        }
+        self.default
+    }
+}
+
+/// Whether a single-level position of a filter can match the topic level `level`
+///
+/// Wildcards in the first level do not match topics starting with `$` (MQTT-4.7.2-1).
+fn wildcard_matches(level: &str, first_level: bool) -> bool {
+    !(first_level && level.starts_with('$'))
+}
+
+/// Whether every topic matched by `inner` is also matched by `outer`
+fn covers(outer: &[&str], inner: &[&str], first_level: bool) -> bool {
+    match (outer.split_first(), inner.split_first()) {
+        (None, None) => true,
+        (Some((&"#", _)), None) => !first_level,
+        (Some((&"#", _)), Some((level, _))) => wildcard_matches(level, first_level),
+        (_, None) | (None, _) => false,
+        (Some(_), Some((&"#", _))) => false,
+        (Some((&"+", outer_rest)), Some((level, inner_rest))) =>
+            wildcard_matches(level, first_level) && covers(outer_rest, inner_rest, false),
+        (Some((outer_level, outer_rest)), Some((inner_level, inner_rest))) =>
+            outer_level == inner_level && covers(outer_rest, inner_rest, false),
+    }
+}
+
+/// Whether some topic is matched by both filters
+fn intersects(a: &[&str], b: &[&str], first_level: bool) -> bool {
+    match (a.split_first(), b.split_first()) {
+        (None, None) => true,
+        (Some((&"#", _)), None) | (None, Some((&"#", _))) => !first_level,
+        (None, _) | (_, None) => false,
+        (Some((&"#", _)), Some((level, _))) | (Some((level, _)), Some((&"#", _))) =>
+            *level == "#" || *level == "+" || wildcard_matches(level, first_level),
+        (Some((&"+", a_rest)), Some((level, b_rest))) | (Some((level, b_rest)), Some((&"+", a_rest))) =>
+            (*level == "+" || wildcard_matches(level, first_level)) && intersects(a_rest, b_rest, false),
+        (Some((a_level, a_rest)), Some((b_level, b_rest))) =>
+            a_level == b_level && intersects(a_rest, b_rest, false),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use control::variable_header::TopicName;
+    use topic_filter::TopicFilter;
+
+    fn publish(acl: &Acl, topic_name: &str) -> Permission {
+        acl.check_publish(&TopicName(topic_name.to_owned()))
+    }
+
+    fn subscribe(acl: &Acl, filter: &str) -> Permission {
+        acl.check_subscribe(&TopicFilter::new(filter))
+    }
+
+    fn levels(filter: &str) -> Vec<&str> {
+        filter.split('/').collect()
+    }
+
+    #[test]
+    fn test_acl_filter_relations() {
+        let covers = |outer, inner| covers(&levels(outer), &levels(inner), true);
+        assert!(covers("a/#", "a"));
+        assert!(covers("a/#", "a/+/c"));
+        assert!(covers("a/+", "a/b"));
+        assert!(covers("#", "+/b"));
+        assert!(!covers("a/b", "a/#"));
+        assert!(!covers("a/+", "a/#"));
+        assert!(!covers("#", "$SYS/#"));
+        assert!(!covers("+/b", "$SYS/b"));
+
+        let intersects = |a, b| intersects(&levels(a), &levels(b), true);
+        assert!(intersects("a/b", "a/#"));
+        assert!(intersects("a/+/c", "+/b/#"));
+        assert!(intersects("a/#", "a"));
+        assert!(intersects("#", "+"));
+        assert!(!intersects("a/+", "a/b/c"));
+        assert!(!intersects("a/b", "a/c"));
+        assert!(!intersects("#", "$SYS/uptime"));
+        assert!(intersects("$SYS/#", "$SYS/+"));
+    }
+
+    #[test]
+    fn test_acl_first_match_wins() {
+        let policy = AclPolicy::new(Permission::Deny)
+            .rule(AclRule::deny(AclAction::Both, "$SYS/#"))
+            .rule(AclRule::deny(AclAction::Publish, "devices/+/config"))
+            .rule(AclRule::allow(AclAction::Publish, "devices/#"))
+            .rule(AclRule::allow(AclAction::Subscribe, "devices/+/state"))
+            .rule(AclRule::allow(AclAction::Both, "#"));
+        let acl = policy.instantiate("client", None);
+
+        assert_eq!(Permission::Deny, publish(&acl, "$SYS/uptime"));
+        assert_eq!(Permission::Deny, publish(&acl, "devices/a/config"));
+        assert_eq!(Permission::Allow, publish(&acl, "devices/a/state"));
+        assert_eq!(Permission::Allow, publish(&acl, "other"));
+
+        assert_eq!(Permission::Deny, subscribe(&acl, "$SYS/+"));
+        assert_eq!(Permission::Allow, subscribe(&acl, "devices/+/state"));
+        assert_eq!(Permission::Allow, subscribe(&acl, "devices/a/state"));
+        // devices/+/state is the first rule sharing topics, but does not cover the filter
+        assert_eq!(Permission::Deny, subscribe(&acl, "devices/#"));
+        assert_eq!(Permission::Deny, subscribe(&acl, "#"));
+        assert_eq!(Permission::Allow, subscribe(&acl, "other/#"));
+    }
+
+    #[test]
+    fn test_acl_broader_subscription() {
+        let policy = AclPolicy::new(Permission::Deny)
+            .rule(AclRule::allow(AclAction::Subscribe, "a/b"))
+            .rule(AclRule::allow(AclAction::Subscribe, "c/+/d"));
+        let acl = policy.instantiate("client", None);
+
+        assert_eq!(Permission::Allow, subscribe(&acl, "a/b"));
+        assert_eq!(Permission::Deny, subscribe(&acl, "a/#"));
+        assert_eq!(Permission::Deny, subscribe(&acl, "a/+"));
+        assert_eq!(Permission::Allow, subscribe(&acl, "c/+/d"));
+        assert_eq!(Permission::Allow, subscribe(&acl, "c/x/d"));
+        assert_eq!(Permission::Deny, subscribe(&acl, "c/#"));
+        assert_eq!(Permission::Deny, subscribe(&acl, "x"));
+        assert_eq!(Permission::Deny, publish(&acl, "a/b"));
+    }
+
+    #[test]
+    fn test_acl_substitution() {
+        let policy = AclPolicy::new(Permission::Deny)
+            .rule(AclRule::deny(AclAction::Both, "users/%u/private"))
+            .rule(AclRule::allow(AclAction::Both, "devices/%c/+/state"))
+            .rule(AclRule::allow(AclAction::Subscribe, "users/%u/#"));
+
+        let acl = policy.instantiate("device-1", Some("alice"));
+        assert_eq!(Permission::Allow, publish(&acl, "devices/device-1/sensor/state"));
+        assert_eq!(Permission::Deny, publish(&acl, "devices/device-2/sensor/state"));
+        assert_eq!(Permission::Allow, subscribe(&acl, "users/alice/inbox"));
+        assert_eq!(Permission::Deny, subscribe(&acl, "users/alice/private"));
+        assert_eq!(Permission::Deny, subscribe(&acl, "users/alice/#"));
+        assert_eq!(Permission::Deny, subscribe(&acl, "users/bob/inbox"));
+
+        // Identifiers must not widen the rules
+        let acl = policy.instantiate("+", None);
+        assert_eq!(vec![AclRule::deny(AclAction::Both, "users/#")], acl.rules().to_vec());
+        assert_eq!(Permission::Deny, publish(&acl, "devices/other/sensor/state"));
+
+        let acl = policy.instantiate("a/b", Some("#"));
+        assert_eq!(vec![AclRule::deny(AclAction::Both, "users/#")], acl.rules().to_vec());
+    }
+}
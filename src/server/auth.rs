@@ -0,0 +1,153 @@
+//! Authentication of CONNECT packets
+
+use std::cmp;
+use std::collections::HashMap;
+
+use control::variable_header::ConnectReturnCode;
+
+/// Outcome of `Authenticator::authenticate`
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum AuthDecision {
+    Accept,
+    /// Refused with `BadUserNameOrPassword` (0x04)
+    BadCredentials,
+    /// Refused with `NotAuthorized` (0x05)
+    NotAuthorized,
+}
+
+impl AuthDecision {
+    /// Return code of the CONNACK answering the CONNECT
+    pub fn connect_return_code(&self) -> ConnectReturnCode {
+        match self {
+            &AuthDecision::Accept => ConnectReturnCode::ConnectionAccepted,
+            &AuthDecision::BadCredentials => ConnectReturnCode::BadUserNameOrPassword,
+            &AuthDecision::NotAuthorized => ConnectReturnCode::NotAuthorized,
+        }
+    }
+}
+
+/// Decides whether a client may connect with the credentials of its CONNECT
+pub trait Authenticator {
+    fn authenticate(&self, client_id: &str, user_name: Option<&str>, password: Option<&[u8]>) -> AuthDecision;
+}
+
+impl<F> Authenticator for F
+    where F: Fn(&str, Option<&str>, Option<&[u8]>) -> AuthDecision
+{
+    fn authenticate(&self, client_id: &str, user_name: Option<&str>, password: Option<&[u8]>) -> AuthDecision {
+        self(client_id, user_name, password)
+    }
+}
+
+/// Accepts every client, with or without credentials
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct AllowAll;
+
+impl Authenticator for AllowAll {
+    fn authenticate(&self, _: &str, _: Option<&str>, _: Option<&[u8]>) -> AuthDecision {
+        AuthDecision::Accept
+    }
+}
+
+/// Fixed map of user names to passwords
+///
+/// Clients without a user name are not authorized, an unknown user name or a wrong password
+/// are bad credentials. Passwords are compared in constant time.
+#[derive(Debug, Clone, Default)]
+pub struct StaticCredentials {
+    credentials: HashMap<String, Vec<u8>>,
+}
+
+impl StaticCredentials {
+    pub fn new() -> StaticCredentials {
+        StaticCredentials {
+            credentials: HashMap::new(),
+        }
+    }
+
+    /// Adds a user, replacing the password if it already exists
+    pub fn insert(&mut self, user_name: &str, password: &[u8]) -> Option<Vec<u8>> {
+        self.credentials.insert(user_name.to_owned(), password.to_owned())
+    }
+
+    pub fn remove(&mut self, user_name: &str) -> Option<Vec<u8>> {
+        self.credentials.remove(user_name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.credentials.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.credentials.is_empty()
+    }
+}
+
+impl Authenticator for StaticCredentials {
+    fn authenticate(&self, _: &str, user_name: Option<&str>, password: Option<&[u8]>) -> AuthDecision {
+        let user_name = match user_name {
+            Some(user_name) => user_name,
+            None => return AuthDecision::NotAuthorized,
+        };
+
+        let password = password.unwrap_or(b"");
+        match self.credentials.get(user_name) {
+            Some(expected) if constant_time_eq(expected, password) => AuthDecision::Accept,
+            Some(..) => AuthDecision::BadCredentials,
+            None => {
+                // Same amount of work as for a known user name
+                constant_time_eq(password, password);
+                AuthDecision::BadCredentials
+            },
+        }
+    }
+}
+
+/// Compares without returning early, the time only depends on the longer length
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = (a.len() != b.len()) as u8;
+    for i in 0..cmp::max(a.len(), b.len()) {
+        let x = a.get(i).cloned().unwrap_or(0);
+        let y = b.get(i).cloned().unwrap_or(0);
+        diff |= x ^ y;
+    }
+    diff == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use control::variable_header::ConnectReturnCode;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+        assert!(!constant_time_eq(b"secret!", b"secret"));
+        assert!(!constant_time_eq(b"", b"\0"));
+
+        // Lengths differing by a multiple of 65536
+        let mut long = b"secret".to_vec();
+        long.extend_from_slice(&[0; 65536]);
+        assert!(!constant_time_eq(&long, b"secret"));
+        assert!(!constant_time_eq(b"secret", &long));
+    }
+
+    #[test]
+    fn test_static_credentials() {
+        let mut credentials = StaticCredentials::new();
+        credentials.insert("user", b"secret");
+
+        assert_eq!(AuthDecision::Accept, credentials.authenticate("client", Some("user"), Some(b"secret")));
+        assert_eq!(AuthDecision::BadCredentials, credentials.authenticate("client", Some("user"), Some(b"wrong")));
+        assert_eq!(AuthDecision::BadCredentials, credentials.authenticate("client", Some("user"), None));
+        assert_eq!(AuthDecision::BadCredentials, credentials.authenticate("client", Some("other"), Some(b"secret")));
+        assert_eq!(AuthDecision::NotAuthorized, credentials.authenticate("client", None, None));
+
+        assert_eq!(ConnectReturnCode::BadUserNameOrPassword, AuthDecision::BadCredentials.connect_return_code());
+        assert_eq!(ConnectReturnCode::NotAuthorized, AuthDecision::NotAuthorized.connect_return_code());
+    }
+}
@@ -0,0 +1,397 @@
+//! Building blocks for servers, starting with the connection handshake
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use control::variable_header::{ConnectReturnCode, ProtocolVersion};
+use packet::{ConnackPacket, ConnectPacket, DecodeOptions, PacketError, VariablePacket, VariablePacketError};
+use packet::connect::{ClientIdError, ClientIdPolicy};
+use Encodable;
+
+pub use self::acl::{Acl, AclAction, AclPolicy, AclRule, Permission};
+pub use self::auth::{AllowAll, AuthDecision, Authenticator, StaticCredentials};
+pub use self::offline::{EvictionPolicy, OfflineQueue};
+pub use self::registry::{ClientRegistry, ConnectionId, TakeoverAction};
+pub use self::retain::{MemoryRetainedStore, RetainedStore};
+pub use self::subscriptions::SubscriptionTable;
+pub use self::sys_topics::{BrokerGauges, SysTopics};
+pub use self::trie::TopicTrie;
+pub use self::will::WillState;
+
+pub mod acl;
+pub mod auth;
+pub mod offline;
+pub mod registry;
+pub mod retain;
+pub mod subscriptions;
+pub mod sys_topics;
+pub mod trie;
+pub mod will;
+
+/// Checks applied by `accept_connection`
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct AcceptOptions {
+    pub client_id_policy: ClientIdPolicy,
+    pub decode_options: DecodeOptions,
+    /// Accept mosquitto bridges, which set bit 7 of the protocol level
+    pub allow_bridge: bool,
+}
+
+impl AcceptOptions {
+    pub fn new() -> AcceptOptions {
+        AcceptOptions {
+            client_id_policy: ClientIdPolicy::Relaxed,
+            decode_options: DecodeOptions::new(),
+            allow_bridge: false,
+        }
+    }
+
+    pub fn client_id_policy(mut self, client_id_policy: ClientIdPolicy) -> AcceptOptions {
+        self.client_id_policy = client_id_policy;
+        self
+    }
+
+    pub fn decode_options(mut self, decode_options: DecodeOptions) -> AcceptOptions {
+        self.decode_options = decode_options;
+        self
+    }
+
+    pub fn allow_bridge(mut self, allow_bridge: bool) -> AcceptOptions {
+        self.allow_bridge = allow_bridge;
+        self
+    }
+}
+
+impl Default for AcceptOptions {
+    fn default() -> AcceptOptions {
+        AcceptOptions::new()
+    }
+}
+
+/// A client whose CONNECT has been answered with an accepting CONNACK
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct AcceptedConnection {
+    pub connect: ConnectPacket,
+    pub keep_alive: u16,
+    pub clean_session: bool,
+    pub session_present: bool,
+}
+
+/// Reads the CONNECT of a new network connection and answers it with a CONNACK
+///
+/// `authenticator` is asked once the packet passed the protocol checks. For an accepted client
+/// `session_present` tells whether a stored session is resumed, it is not called when the
+/// client requested a clean session.
+///
+/// Whenever the spec requires a CONNACK for a refused connection, it is written before the
+/// error is returned. Otherwise the caller has to close the connection without sending
+/// anything (MQTT-3.1.0-1, MQTT-4.8.0-1).
+pub fn accept_connection<'a, S, F>(stream: &mut S,
+                                   options: &AcceptOptions,
+                                   authenticator: &dyn Authenticator,
+                                   session_present: F)
+        -> Result<AcceptedConnection, RejectedConnection<'a>>
+    where S: Read + Write,
+          F: FnOnce(&ConnectPacket) -> bool
+{
+    let packet = try!(VariablePacket::decode_with_options(stream, &options.decode_options)
+                          .map_err(RejectedConnection::DecodeError));
+    let connect = try!(packet.expect::<ConnectPacket>().map_err(RejectedConnection::NotConnect));
+
+    // MQTT-3.1.2-2: Unsupported protocol level. The CONNACK written here is the 3.1.1 one, so
+    // MQTT 5 clients are refused as well.
+    let supported = match connect.protocol_version() {
+        Some(ProtocolVersion::V3_1) | Some(ProtocolVersion::V3_1_1) => true,
+        Some(ProtocolVersion::V5) | None => false,
+    };
+    if !supported || (connect.is_bridge() && !options.allow_bridge) {
+        try!(refuse(stream, ConnectReturnCode::UnacceptableProtocolVersion));
+        return Err(RejectedConnection::UnacceptableProtocolVersion(connect.protocol_level()));
+    }
+
+    if let Err(err) = connect.validate_client_id(options.client_id_policy) {
+        if let Some(code) = err.connect_return_code() {
+            try!(refuse(stream, code));
+        }
+        return Err(RejectedConnection::ClientIdRejected(err));
+    }
+
+    let decision = authenticator.authenticate(connect.client_identifier(), connect.user_name(), connect.password());
+    if decision != AuthDecision::Accept {
+        let code = decision.connect_return_code();
+        try!(refuse(stream, code));
+        return Err(RejectedConnection::Refused(code));
+    }
+
+    // MQTT-3.2.2-1: No session is present with CleanSession set to 1
+    let session_present = !connect.clean_session() && session_present(&connect);
+    try!(write_connack(stream, ConnackPacket::accepted(session_present)));
+
+    Ok(AcceptedConnection {
+        keep_alive: connect.keep_alive(),
+        clean_session: connect.clean_session(),
+        session_present: session_present,
+        connect: connect,
+    })
+}
+
+fn refuse<'a, S: Write>(stream: &mut S, code: ConnectReturnCode) -> Result<(), RejectedConnection<'a>> {
+    write_connack(stream, ConnackPacket::rejected(code))
+}
+
+fn write_connack<'a, S: Write>(stream: &mut S, packet: ConnackPacket) -> Result<(), RejectedConnection<'a>> {
+    match packet.encode(stream) {
+        Ok(..) => stream.flush().map_err(RejectedConnection::IoError),
+        Err(PacketError::IoError(err)) => Err(RejectedConnection::IoError(err)),
+        Err(err) => Err(RejectedConnection::IoError(io::Error::new(io::ErrorKind::Other, err.to_string()))),
+    }
+}
+
+#[derive(Debug)]
+pub enum RejectedConnection<'a> {
+    /// The first packet could not be decoded, no CONNACK was sent
+    DecodeError(VariablePacketError<'a>),
+    /// The first packet is not a CONNECT, no CONNACK was sent
+    NotConnect(VariablePacket),
+    /// Refused with `UnacceptableProtocolVersion`
+    UnacceptableProtocolVersion(u8),
+    /// Refused with `IdentifierRejected`, or not answered for a null character
+    ClientIdRejected(ClientIdError),
+    /// Refused by the `Authenticator` with the return code
+    Refused(ConnectReturnCode),
+    /// Writing the CONNACK failed
+    IoError(io::Error),
+}
+
+impl<'a> RejectedConnection<'a> {
+    /// Return code of the CONNACK that was sent, `None` if none was
+    pub fn connect_return_code(&self) -> Option<ConnectReturnCode> {
+        match self {
+            &RejectedConnection::UnacceptableProtocolVersion(..) => Some(ConnectReturnCode::UnacceptableProtocolVersion),
+            &RejectedConnection::ClientIdRejected(ref err) => err.connect_return_code(),
+            &RejectedConnection::Refused(code) => Some(code),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> fmt::Display for RejectedConnection<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &RejectedConnection::DecodeError(ref err) => err.fmt(f),
+            &RejectedConnection::NotConnect(ref packet) => write!(f, "Expected CONNECT, received {}", packet),
+            &RejectedConnection::UnacceptableProtocolVersion(level) =>
+                write!(f, "Unacceptable protocol level {}", level),
+            &RejectedConnection::ClientIdRejected(ref err) => err.fmt(f),
+            &RejectedConnection::Refused(code) => write!(f, "Connection refused ({})", code.to_u8()),
+            &RejectedConnection::IoError(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl<'a> Error for RejectedConnection<'a> {
+    fn description(&self) -> &str {
+        match self {
+            &RejectedConnection::DecodeError(ref err) => err.description(),
+            &RejectedConnection::NotConnect(..) => "Expected CONNECT",
+            &RejectedConnection::UnacceptableProtocolVersion(..) => "Unacceptable protocol level",
+            &RejectedConnection::ClientIdRejected(ref err) => err.description(),
+            &RejectedConnection::Refused(..) => "Connection refused",
+            &RejectedConnection::IoError(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match self {
+            &RejectedConnection::DecodeError(ref err) => Some(err),
+            &RejectedConnection::ClientIdRejected(ref err) => Some(err),
+            &RejectedConnection::IoError(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::{self, Cursor, Read, Write};
+
+    use control::variable_header::ConnectReturnCode;
+    use packet::{ConnackPacket, ConnectPacket, PingreqPacket, VariablePacket};
+    use packet::connect::{ClientIdError, ClientIdPolicy};
+    use {Encodable, Decodable};
+
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl MockStream {
+        fn new<P>(packet: P) -> MockStream
+            where VariablePacket: From<P>
+        {
+            let mut input = Vec::new();
+            VariablePacket::new(packet).encode(&mut input).unwrap();
+            MockStream::with_bytes(input)
+        }
+
+        fn with_bytes(input: Vec<u8>) -> MockStream {
+            MockStream {
+                input: Cursor::new(input),
+                output: Vec::new(),
+            }
+        }
+
+        fn connack(&self) -> Option<ConnackPacket> {
+            if self.output.is_empty() {
+                return None;
+            }
+            Some(ConnackPacket::decode(&mut Cursor::new(&self.output[..])).unwrap())
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn connect(client_id: &str, clean_session: bool) -> ConnectPacket {
+        ConnectPacket::builder(client_id).clean_session(clean_session).keep_alive(30).build().unwrap()
+    }
+
+    #[test]
+    fn test_accept_connection() {
+        let mut stream = MockStream::new(connect("client", false));
+        let accepted = accept_connection(&mut stream, &AcceptOptions::new(), &AllowAll, |_| true).unwrap();
+        assert_eq!(30, accepted.keep_alive);
+        assert!(!accepted.clean_session);
+        assert!(accepted.session_present);
+        assert_eq!("client", accepted.connect.client_identifier());
+        assert_eq!(Some(ConnackPacket::accepted(true)), stream.connack());
+
+        // No session can be present with clean session
+        let mut stream = MockStream::new(connect("client", true));
+        let accepted = accept_connection(&mut stream, &AcceptOptions::new(), &AllowAll, |_| true).unwrap();
+        assert!(!accepted.session_present);
+        assert_eq!(Some(ConnackPacket::accepted(false)), stream.connack());
+    }
+
+    #[test]
+    fn test_accept_connection_without_connack() {
+        let mut stream = MockStream::new(PingreqPacket::new());
+        match accept_connection(&mut stream, &AcceptOptions::new(), &AllowAll, |_| false) {
+            Err(err @ RejectedConnection::NotConnect(..)) => assert_eq!(None, err.connect_return_code()),
+            res => panic!("Unexpected result {:?}", res),
+        }
+        assert_eq!(None, stream.connack());
+
+        let mut stream = MockStream::with_bytes(b"\x10\x0c\x00\x04MQTX\x04\x02\x00\x00\x00\x00".to_vec());
+        match accept_connection(&mut stream, &AcceptOptions::new(), &AllowAll, |_| false) {
+            Err(RejectedConnection::DecodeError(..)) => {},
+            res => panic!("Unexpected result {:?}", res),
+        }
+        assert_eq!(None, stream.connack());
+    }
+
+    #[test]
+    fn test_accept_connection_protocol_level() {
+        let mut stream = MockStream::new(ConnectPacket::with_level("client".to_owned(), 5));
+        match accept_connection(&mut stream, &AcceptOptions::new(), &AllowAll, |_| false) {
+            Err(RejectedConnection::UnacceptableProtocolVersion(5)) => {},
+            res => panic!("Unexpected result {:?}", res),
+        }
+        assert_eq!(Some(ConnackPacket::rejected(ConnectReturnCode::UnacceptableProtocolVersion)), stream.connack());
+    }
+
+    #[test]
+    fn test_accept_connection_bridge() {
+        let mut bridge = connect("gateway.bridge-01", true);
+        bridge.set_bridge_mode(true);
+
+        let mut stream = MockStream::new(bridge.clone());
+        match accept_connection(&mut stream, &AcceptOptions::new(), &AllowAll, |_| false) {
+            Err(RejectedConnection::UnacceptableProtocolVersion(0x84)) => {},
+            res => panic!("Unexpected result {:?}", res),
+        }
+        assert_eq!(Some(ConnackPacket::rejected(ConnectReturnCode::UnacceptableProtocolVersion)), stream.connack());
+
+        let mut stream = MockStream::new(bridge);
+        let accepted = accept_connection(&mut stream, &AcceptOptions::new().allow_bridge(true), &AllowAll, |_| false)
+                           .unwrap();
+        assert!(accepted.connect.is_bridge());
+        assert_eq!(Some(ConnackPacket::accepted(false)), stream.connack());
+    }
+
+    #[test]
+    fn test_accept_connection_client_id() {
+        let mut stream = MockStream::new(connect("", false));
+        match accept_connection(&mut stream, &AcceptOptions::new(), &AllowAll, |_| false) {
+            Err(RejectedConnection::ClientIdRejected(ClientIdError::EmptyWithoutCleanSession)) => {},
+            res => panic!("Unexpected result {:?}", res),
+        }
+        assert_eq!(Some(ConnackPacket::rejected(ConnectReturnCode::IdentifierRejected)), stream.connack());
+
+        let options = AcceptOptions::new().client_id_policy(ClientIdPolicy::Strict);
+        let mut stream = MockStream::new(connect("client-1", true));
+        match accept_connection(&mut stream, &options, &AllowAll, |_| false) {
+            Err(RejectedConnection::ClientIdRejected(ClientIdError::InvalidCharacter('-', 6))) => {},
+            res => panic!("Unexpected result {:?}", res),
+        }
+        assert_eq!(Some(ConnackPacket::rejected(ConnectReturnCode::IdentifierRejected)), stream.connack());
+    }
+
+    #[test]
+    fn test_accept_connection_authentication() {
+        let mut credentials = StaticCredentials::new();
+        credentials.insert("user", b"secret");
+
+        let cases: [(Option<&str>, &[u8], &[u8]); 4] = [
+            (Some("user"), b"secret", b"\x20\x02\x00\x00"),
+            (Some("user"), b"wrong", b"\x20\x02\x00\x04"),
+            (Some("other"), b"secret", b"\x20\x02\x00\x04"),
+            (None, b"", b"\x20\x02\x00\x05"),
+        ];
+
+        for &(user_name, password, connack) in cases.iter() {
+            let mut builder = ConnectPacket::builder("client");
+            if let Some(user_name) = user_name {
+                builder = builder.user_name(user_name).password(password);
+            }
+            let mut stream = MockStream::new(builder.build().unwrap());
+            match accept_connection(&mut stream, &AcceptOptions::new(), &credentials, |_| false) {
+                Ok(..) => assert_eq!(b"\x00", &connack[3..]),
+                Err(ref err @ RejectedConnection::Refused(..)) =>
+                    assert_eq!(Some(connack[3]), err.connect_return_code().map(|code| code.to_u8())),
+                res => panic!("Unexpected result {:?}", res),
+            }
+            assert_eq!(connack, &stream.output[..]);
+        }
+    }
+
+    #[test]
+    fn test_accept_connection_closure_authenticator() {
+        let authenticator = |client_id: &str, _: Option<&str>, _: Option<&[u8]>| {
+            if client_id.starts_with("admin") { AuthDecision::NotAuthorized } else { AuthDecision::Accept }
+        };
+
+        let mut stream = MockStream::new(connect("admin", true));
+        match accept_connection(&mut stream, &AcceptOptions::new(), &authenticator, |_| false) {
+            Err(RejectedConnection::Refused(ConnectReturnCode::NotAuthorized)) => {},
+            res => panic!("Unexpected result {:?}", res),
+        }
+        assert_eq!(b"\x20\x02\x00\x05", &stream.output[..]);
+    }
+}
@@ -0,0 +1,288 @@
+//! Messages for disconnected clients with a persistent session
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use packet::{Packet, PublishPacket};
+use QualityOfService;
+
+/// Message dropped when an `OfflineQueue` is full
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum EvictionPolicy {
+    /// Drops queued messages, oldest first, to make room for the new one
+    DropOldest,
+    /// Keeps the queued messages and drops the new one
+    DropNew,
+}
+
+/// Bounded queue of the messages a client misses while it is disconnected
+///
+/// QoS 1 and 2 messages must be queued for a persistent session (MQTT-3.1.2-5), QoS 0 messages
+/// only with `queue_qos0`. Every limit is disabled by default; messages dropped to stay within
+/// them are counted by `evicted`.
+#[derive(Debug, Clone)]
+pub struct OfflineQueue {
+    messages: VecDeque<(Instant, PublishPacket)>,
+    bytes: usize,
+    evicted: u64,
+
+    max_messages: Option<usize>,
+    max_bytes: Option<usize>,
+    max_age: Option<Duration>,
+    eviction_policy: EvictionPolicy,
+    queue_qos0: bool,
+}
+
+impl OfflineQueue {
+    pub fn new() -> OfflineQueue {
+        OfflineQueue {
+            messages: VecDeque::new(),
+            bytes: 0,
+            evicted: 0,
+
+            max_messages: None,
+            max_bytes: None,
+            max_age: None,
+            eviction_policy: EvictionPolicy::DropOldest,
+            queue_qos0: false,
+        }
+    }
+
+    pub fn max_messages(mut self, max_messages: Option<usize>) -> OfflineQueue {
+        self.max_messages = max_messages;
+        self
+    }
+
+    /// Limit of the sum of all payload lengths
+    pub fn max_bytes(mut self, max_bytes: Option<usize>) -> OfflineQueue {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Option<Duration>) -> OfflineQueue {
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn eviction_policy(mut self, eviction_policy: EvictionPolicy) -> OfflineQueue {
+        self.eviction_policy = eviction_policy;
+        self
+    }
+
+    pub fn queue_qos0(mut self, queue_qos0: bool) -> OfflineQueue {
+        self.queue_qos0 = queue_qos0;
+        self
+    }
+
+    /// Queues the message, returns whether it was queued
+    pub fn push(&mut self, packet: PublishPacket, now: Instant) -> bool {
+        if packet.qos().qos() == QualityOfService::Level0 && !self.queue_qos0 {
+            return false;
+        }
+
+        self.expire(now);
+
+        let size = packet.payload().len();
+        if self.max_bytes.map(|max| size > max).unwrap_or(false) {
+            self.evicted += 1;
+            return false;
+        }
+
+        while self.is_full(size) {
+            match self.eviction_policy {
+                EvictionPolicy::DropOldest => {
+                    self.pop_front();
+                    self.evicted += 1;
+                },
+                EvictionPolicy::DropNew => {
+                    self.evicted += 1;
+                    return false;
+                },
+            }
+        }
+
+        self.bytes += size;
+        self.messages.push_back((now, packet));
+        true
+    }
+
+    /// Drops the messages older than `max_age`
+    pub fn expire(&mut self, now: Instant) {
+        let max_age = match self.max_age {
+            Some(max_age) => max_age,
+            None => return,
+        };
+
+        while self.messages.front().map(|&(queued_at, _)| queued_at + max_age <= now).unwrap_or(false) {
+            self.pop_front();
+            self.evicted += 1;
+        }
+    }
+
+    /// Removes the messages that have not expired at `now`, in the order they were queued
+    pub fn drain(&mut self, now: Instant) -> impl Iterator<Item = PublishPacket> {
+        self.expire(now);
+        self.bytes = 0;
+        let messages: Vec<_> = self.messages.drain(..).map(|(_, packet)| packet).collect();
+        messages.into_iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Sum of the payload lengths of all queued messages
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Number of messages dropped because of the limits
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    fn is_full(&self, size: usize) -> bool {
+        self.max_messages.map(|max| self.messages.len() >= max).unwrap_or(false)
+            || self.max_bytes.map(|max| self.bytes + size > max).unwrap_or(false)
+    }
+
+    fn pop_front(&mut self) {
+        if let Some((_, packet)) = self.messages.pop_front() {
+            self.bytes -= packet.payload().len();
+        }
+    }
+}
+
+impl Default for OfflineQueue {
+    fn default() -> OfflineQueue {
+        OfflineQueue::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use control::variable_header::{PacketIdentifier, ProtocolVersion};
+    use packet::{Packet, PublishPacket, QoSWithPacketIdentifier, VariablePacket};
+    use session::SessionState;
+    use QualityOfService;
+
+    fn publish(payload: &str, qos: QualityOfService) -> PublishPacket {
+        let pkid = PacketIdentifier::new(1).unwrap();
+        let qos = match qos {
+            QualityOfService::Level0 => QoSWithPacketIdentifier::Level0,
+            QualityOfService::Level1 => QoSWithPacketIdentifier::Level1(pkid),
+            QualityOfService::Level2 => QoSWithPacketIdentifier::Level2(pkid),
+        };
+        PublishPacket::new("a/b".to_owned(), qos, payload.as_bytes().to_vec())
+    }
+
+    fn payloads(queue: &mut OfflineQueue, now: Instant) -> Vec<String> {
+        queue.drain(now).map(|packet| String::from_utf8(packet.payload().clone()).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_offline_queue_qos0() {
+        let now = Instant::now();
+        let mut queue = OfflineQueue::new();
+        assert!(!queue.push(publish("0", QualityOfService::Level0), now));
+        assert!(queue.push(publish("1", QualityOfService::Level1), now));
+        assert!(queue.push(publish("2", QualityOfService::Level2), now));
+        assert_eq!(0, queue.evicted());
+
+        let mut queue = queue.queue_qos0(true);
+        assert!(queue.push(publish("0", QualityOfService::Level0), now));
+        assert_eq!(vec!["1", "2", "0"], payloads(&mut queue, now));
+        assert!(queue.is_empty());
+        assert_eq!(0, queue.bytes());
+    }
+
+    #[test]
+    fn test_offline_queue_max_messages() {
+        let now = Instant::now();
+        let mut queue = OfflineQueue::new().max_messages(Some(2));
+        for payload in ["1", "2", "3"].iter() {
+            assert!(queue.push(publish(payload, QualityOfService::Level1), now));
+        }
+        assert_eq!(1, queue.evicted());
+        assert_eq!(vec!["2", "3"], payloads(&mut queue, now));
+
+        let mut queue = OfflineQueue::new().max_messages(Some(2)).eviction_policy(EvictionPolicy::DropNew);
+        assert!(queue.push(publish("1", QualityOfService::Level1), now));
+        assert!(queue.push(publish("2", QualityOfService::Level1), now));
+        assert!(!queue.push(publish("3", QualityOfService::Level1), now));
+        assert_eq!(1, queue.evicted());
+        assert_eq!(vec!["1", "2"], payloads(&mut queue, now));
+    }
+
+    #[test]
+    fn test_offline_queue_max_bytes() {
+        let now = Instant::now();
+        let mut queue = OfflineQueue::new().max_bytes(Some(6));
+        assert!(queue.push(publish("aaa", QualityOfService::Level1), now));
+        assert!(queue.push(publish("bb", QualityOfService::Level1), now));
+        assert_eq!(5, queue.bytes());
+        assert!(queue.push(publish("cccc", QualityOfService::Level1), now));
+        assert_eq!(1, queue.evicted());
+        assert_eq!(6, queue.bytes());
+
+        // Never fits, nothing else is dropped for it
+        assert!(!queue.push(publish("ddddddd", QualityOfService::Level1), now));
+        assert_eq!(2, queue.evicted());
+        assert_eq!(vec!["bb", "cccc"], payloads(&mut queue, now));
+    }
+
+    #[test]
+    fn test_offline_queue_max_age() {
+        let now = Instant::now();
+        let mut queue = OfflineQueue::new().max_age(Some(Duration::from_secs(60)));
+        assert!(queue.push(publish("1", QualityOfService::Level1), now));
+        assert!(queue.push(publish("2", QualityOfService::Level1), now + Duration::from_secs(30)));
+        assert!(queue.push(publish("3", QualityOfService::Level1), now + Duration::from_secs(60)));
+        assert_eq!(1, queue.evicted());
+        assert_eq!(2, queue.len());
+
+        assert_eq!(vec!["3"], payloads(&mut queue, now + Duration::from_secs(90)));
+        assert_eq!(2, queue.evicted());
+    }
+
+    #[test]
+    fn test_offline_queue_session_resume() {
+        let now = Instant::now();
+        let mut session = SessionState::new();
+        let pkid = session.packet_ids_mut().allocate().unwrap();
+        session.qos1_mut().register(publish("in flight", QualityOfService::Level1).for_delivery(
+            QualityOfService::Level1, || pkid, false, ProtocolVersion::V3_1_1), now).unwrap();
+
+        let mut queue = OfflineQueue::new();
+        queue.push(publish("queued 1", QualityOfService::Level2), now);
+        queue.push(publish("queued 2", QualityOfService::Level1), now);
+
+        // Messages in flight before the disconnect go first, the queued ones follow with new identifiers
+        let mut packets = session.resume_packets();
+        for packet in queue.drain(now) {
+            let delivery = packet.for_delivery(QualityOfService::Level2, || session.packet_ids_mut().allocate().unwrap(),
+                                               false, ProtocolVersion::V3_1_1);
+            packets.push(VariablePacket::new(delivery));
+        }
+
+        let sent: Vec<_> = packets.iter().map(|packet| match packet {
+            &VariablePacket::PublishPacket(ref packet) =>
+                (String::from_utf8(packet.payload().clone()).unwrap(), packet.dup(), packet.qos().qos()),
+            packet => panic!("Unexpected packet {:?}", packet),
+        }).collect();
+        assert_eq!(vec![("in flight".to_owned(), true, QualityOfService::Level1),
+                        ("queued 1".to_owned(), false, QualityOfService::Level2),
+                        ("queued 2".to_owned(), false, QualityOfService::Level1)],
+                   sent);
+        assert_eq!(vec![Some(1), Some(2), Some(3)],
+                   packets.iter().map(VariablePacket::packet_identifier).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,251 @@
+//! Connected clients by client identifier
+
+use std::collections::HashMap;
+
+use session::SessionState;
+
+/// Identifies one network connection among those of the same client identifier
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Copy, Clone)]
+pub struct ConnectionId(u64);
+
+/// What to do after registering a new connection
+#[derive(Debug, Eq, PartialEq)]
+pub struct TakeoverAction<H> {
+    pub connection: ConnectionId,
+    /// Existing connection with the same client identifier, which must be closed (MQTT-3.1.4-2)
+    pub close: Option<H>,
+    /// Whether the stored session passes to the new connection, to be sent as Session Present
+    pub session_present: bool,
+}
+
+#[derive(Debug)]
+struct Registration<H> {
+    connection: ConnectionId,
+    handle: H,
+    clean_session: bool,
+}
+
+#[derive(Debug)]
+struct StoredSession {
+    state: SessionState,
+    /// Started without clean session, only these are resumed
+    persistent: bool,
+}
+
+/// Connections and persistent sessions of all clients
+///
+/// Sessions stay owned by the registry, only the latest connection of a client can access its
+/// session through `session_mut`. A connection that was taken over can therefore neither
+/// modify the session nor remove it when it finally goes away.
+#[derive(Debug)]
+pub struct ClientRegistry<H> {
+    connections: HashMap<String, Registration<H>>,
+    sessions: HashMap<String, StoredSession>,
+    next_connection: u64,
+}
+
+impl<H> ClientRegistry<H> {
+    pub fn new() -> ClientRegistry<H> {
+        ClientRegistry {
+            connections: HashMap::new(),
+            sessions: HashMap::new(),
+            next_connection: 0,
+        }
+    }
+
+    /// Registers the connection of an accepted CONNECT
+    ///
+    /// With `clean_session` set any stored session is discarded and a new one is started
+    /// (MQTT-3.1.2-6), otherwise the stored session is resumed (MQTT-3.1.2-4).
+    pub fn register(&mut self, client_id: &str, clean_session: bool, handle: H) -> TakeoverAction<H> {
+        let connection = ConnectionId(self.next_connection);
+        self.next_connection += 1;
+
+        let registration = Registration {
+            connection: connection,
+            handle: handle,
+            clean_session: clean_session,
+        };
+        let close = self.connections.insert(client_id.to_owned(), registration).map(|previous| previous.handle);
+
+        // The session of a connection with clean session set is never resumed, even while
+        // that connection is still registered
+        let session_present = !clean_session
+            && self.sessions.get(client_id).map(|session| session.persistent).unwrap_or(false);
+        if !session_present {
+            let session = StoredSession {
+                state: SessionState::new(),
+                persistent: !clean_session,
+            };
+            self.sessions.insert(client_id.to_owned(), session);
+        }
+
+        TakeoverAction {
+            connection: connection,
+            close: close,
+            session_present: session_present,
+        }
+    }
+
+    /// Removes the connection once it is closed, unless it was already taken over
+    ///
+    /// The session of a connection with clean session set ends with it, other sessions are
+    /// kept for the next connection.
+    pub fn deregister(&mut self, client_id: &str, connection: ConnectionId) -> Option<H> {
+        match self.connections.get(client_id) {
+            Some(registration) if registration.connection == connection => {},
+            _ => return None,
+        }
+
+        let registration = self.connections.remove(client_id).unwrap();
+        if registration.clean_session {
+            self.sessions.remove(client_id);
+        }
+        Some(registration.handle)
+    }
+
+    pub fn is_connected(&self, client_id: &str) -> bool {
+        self.connections.contains_key(client_id)
+    }
+
+    /// Whether `connection` is the latest connection of the client
+    pub fn is_current(&self, client_id: &str, connection: ConnectionId) -> bool {
+        self.connections.get(client_id).map(|r| r.connection == connection).unwrap_or(false)
+    }
+
+    pub fn handle(&self, client_id: &str) -> Option<&H> {
+        self.connections.get(client_id).map(|r| &r.handle)
+    }
+
+    pub fn connected_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn session(&self, client_id: &str) -> Option<&SessionState> {
+        self.sessions.get(client_id).map(|session| &session.state)
+    }
+
+    /// Session of the client, `None` if `connection` is not its latest connection
+    pub fn session_mut(&mut self, client_id: &str, connection: ConnectionId) -> Option<&mut SessionState> {
+        if !self.is_current(client_id, connection) {
+            return None;
+        }
+        self.sessions.get_mut(client_id).map(|session| &mut session.state)
+    }
+
+    /// Discards the stored session of a client that is not connected, e.g. once it expired
+    pub fn remove_session(&mut self, client_id: &str) -> Option<SessionState> {
+        if self.is_connected(client_id) {
+            return None;
+        }
+        self.sessions.remove(client_id).map(|session| session.state)
+    }
+
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+}
+
+impl<H> Default for ClientRegistry<H> {
+    fn default() -> ClientRegistry<H> {
+        ClientRegistry::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use {QualityOfService, TopicFilter};
+
+    #[test]
+    fn test_client_registry_takeover() {
+        let mut registry = ClientRegistry::new();
+
+        let first = registry.register("client", false, "first");
+        assert_eq!(None, first.close);
+        assert!(!first.session_present);
+        registry.session_mut("client", first.connection).unwrap()
+            .add_subscription(TopicFilter::new("a/b"), QualityOfService::Level1);
+
+        let second = registry.register("client", false, "second");
+        assert_eq!(Some("first"), second.close);
+        assert!(second.session_present);
+        assert!(registry.session_mut("client", first.connection).is_none());
+
+        // The first connection goes away after the takeover
+        assert_eq!(None, registry.deregister("client", first.connection));
+        assert!(registry.is_current("client", second.connection));
+        assert_eq!(1, registry.session_mut("client", second.connection).unwrap().subscriptions().len());
+
+        assert_eq!(Some("second"), registry.deregister("client", second.connection));
+        assert!(!registry.is_connected("client"));
+        assert_eq!(1, registry.session("client").unwrap().subscriptions().len());
+
+        let third = registry.register("client", true, "third");
+        assert_eq!(None, third.close);
+        assert!(!third.session_present);
+        assert!(registry.session("client").unwrap().subscriptions().is_empty());
+
+        assert_eq!(Some("third"), registry.deregister("client", third.connection));
+        assert!(registry.session("client").is_none());
+    }
+
+    #[test]
+    fn test_client_registry_takeover_of_clean_session() {
+        let mut registry = ClientRegistry::new();
+
+        let first = registry.register("client", true, "first");
+        registry.session_mut("client", first.connection).unwrap()
+            .add_subscription(TopicFilter::new("a/b"), QualityOfService::Level1);
+
+        // The clean session is not resumed by a takeover without clean session
+        let second = registry.register("client", false, "second");
+        assert_eq!(Some("first"), second.close);
+        assert!(!second.session_present);
+        assert!(registry.session("client").unwrap().subscriptions().is_empty());
+        registry.session_mut("client", second.connection).unwrap()
+            .add_subscription(TopicFilter::new("c/d"), QualityOfService::Level1);
+
+        // The session started by the second connection is persistent
+        assert_eq!(None, registry.deregister("client", first.connection));
+        assert_eq!(Some("second"), registry.deregister("client", second.connection));
+        let third = registry.register("client", false, "third");
+        assert!(third.session_present);
+        assert_eq!(1, registry.session("client").unwrap().subscriptions().len());
+    }
+
+    #[test]
+    fn test_client_registry_rapid_reconnects() {
+        let mut registry = ClientRegistry::new();
+        let mut connections = Vec::new();
+
+        for i in 0..100 {
+            let action = registry.register("client", false, i);
+            assert_eq!(i > 0, action.session_present);
+            if i > 0 {
+                assert_eq!(Some(i - 1), action.close);
+            }
+
+            let filter = TopicFilter::new(format!("topic/{}", i));
+            registry.session_mut("client", action.connection).unwrap()
+                .add_subscription(filter, QualityOfService::Level0);
+
+            // Every other stale connection closes only after its successor registered
+            connections.push(action.connection);
+            if i % 2 == 1 {
+                let stale = connections[i - 1];
+                assert_eq!(None, registry.deregister("client", stale));
+                assert!(registry.session_mut("client", stale).is_none());
+            }
+        }
+
+        assert_eq!(1, registry.connected_count());
+        assert_eq!(1, registry.session_count());
+        assert_eq!(100, registry.session("client").unwrap().subscriptions().len());
+        assert!(registry.remove_session("client").is_none());
+
+        assert_eq!(Some(99), registry.deregister("client", connections[99]));
+        assert_eq!(100, registry.remove_session("client").unwrap().subscriptions().len());
+    }
+}
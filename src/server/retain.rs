@@ -0,0 +1,114 @@
+//! Retained messages
+
+use control::variable_header::TopicName;
+use packet::{Packet, PublishPacket};
+use server::trie::TopicTrie;
+use topic_filter::TopicFilter;
+
+/// Storage of the last retained PUBLISH of every topic
+pub trait RetainedStore {
+    /// Replaces the retained message of the packet's topic
+    ///
+    /// A packet with an empty payload deletes it instead (MQTT-3.3.1-10, MQTT-3.3.1-11).
+    fn store(&mut self, packet: PublishPacket);
+
+    fn remove(&mut self, topic_name: &TopicName) -> Option<PublishPacket>;
+
+    /// Retained messages to send to a new subscription of `filter`, all with RETAIN set (MQTT-3.3.1-8)
+    fn matching(&self, filter: &TopicFilter) -> Vec<&PublishPacket>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryRetainedStore {
+    messages: TopicTrie<PublishPacket>,
+}
+
+impl MemoryRetainedStore {
+    pub fn new() -> MemoryRetainedStore {
+        MemoryRetainedStore {
+            messages: TopicTrie::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+impl RetainedStore for MemoryRetainedStore {
+    fn store(&mut self, mut packet: PublishPacket) {
+        if packet.payload().is_empty() {
+            self.messages.remove(packet.topic_name());
+            return;
+        }
+
+        packet.set_retain(true);
+        packet.set_dup(false);
+        let topic_name = packet.topic_name().to_owned();
+        self.messages.insert(&topic_name, packet);
+    }
+
+    fn remove(&mut self, topic_name: &TopicName) -> Option<PublishPacket> {
+        self.messages.remove(&topic_name.0)
+    }
+
+    fn matching(&self, filter: &TopicFilter) -> Vec<&PublishPacket> {
+        self.messages.matching(filter)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use control::variable_header::{PacketIdentifier, TopicName};
+    use packet::{Packet, PublishPacket, QoSWithPacketIdentifier};
+    use topic_filter::TopicFilter;
+
+    fn publish(topic_name: &str, payload: &[u8]) -> PublishPacket {
+        let mut packet = PublishPacket::new(topic_name.to_owned(), QoSWithPacketIdentifier::Level1(PacketIdentifier::new(10).unwrap()), payload.to_vec());
+        packet.set_retain(true);
+        packet
+    }
+
+    #[test]
+    fn test_memory_retained_store() {
+        let mut store = MemoryRetainedStore::new();
+        store.store(publish("a/b", b"first"));
+        store.store(publish("a/b", b"second"));
+        store.store(publish("a/c", b"third"));
+        store.store(publish("a/d/e", b"fourth"));
+        assert_eq!(3, store.len());
+
+        let matching = store.matching(&TopicFilter::new("a/+"));
+        assert_eq!(2, matching.len());
+        assert_eq!(("a/b", &b"second"[..]), (matching[0].topic_name(), &matching[0].payload()[..]));
+        assert_eq!(("a/c", &b"third"[..]), (matching[1].topic_name(), &matching[1].payload()[..]));
+        assert!(matching.iter().all(|packet| packet.retain()));
+
+        // MQTT-3.3.1-10: A zero byte payload removes the retained message
+        store.store(publish("a/b", b""));
+        assert_eq!(1, store.matching(&TopicFilter::new("a/+")).len());
+
+        let removed = store.remove(&TopicName("a/d/e".to_owned())).unwrap();
+        assert_eq!(b"fourth", &removed.payload()[..]);
+        assert_eq!(1, store.len());
+    }
+
+    #[test]
+    fn test_memory_retained_store_sets_retain() {
+        let mut store = MemoryRetainedStore::new();
+        let mut packet = publish("a/b", b"payload");
+        packet.set_retain(false);
+        packet.set_dup(true);
+        store.store(packet);
+
+        let matching = store.matching(&TopicFilter::new("#"));
+        assert!(matching[0].retain());
+        assert!(!matching[0].dup());
+    }
+}
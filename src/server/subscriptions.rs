@@ -0,0 +1,205 @@
+//! Subscriptions of all clients of a server
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::btree_map::Entry;
+
+use packet::PublishPacket;
+use server::trie::TopicTrie;
+use topic_filter::TopicFilter;
+use QualityOfService;
+
+/// Subscriptions of every client, keyed by topic filter
+///
+/// A client subscribes at most once to the same filter, a new subscription replaces the granted
+/// QoS of the existing one (MQTT-3.8.4-3).
+#[derive(Debug, Clone)]
+pub struct SubscriptionTable<C: Ord + Clone> {
+    filters: TopicTrie<BTreeMap<C, QualityOfService>>,
+    clients: BTreeMap<C, BTreeSet<TopicFilter>>,
+}
+
+impl<C: Ord + Clone> SubscriptionTable<C> {
+    pub fn new() -> SubscriptionTable<C> {
+        SubscriptionTable {
+            filters: TopicTrie::new(),
+            clients: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the QoS the filter was previously granted with
+    pub fn subscribe(&mut self, client: C, filter: TopicFilter, qos: QualityOfService) -> Option<QualityOfService> {
+        let previous = match self.filters.get_mut(filter.as_str()) {
+            Some(subscribers) => subscribers.insert(client.clone(), qos),
+            None => {
+                let mut subscribers = BTreeMap::new();
+                subscribers.insert(client.clone(), qos);
+                self.filters.insert(filter.as_str(), subscribers);
+                None
+            },
+        };
+
+        self.clients.entry(client).or_insert_with(BTreeSet::new).insert(filter);
+        previous
+    }
+
+    /// Returns whether the client was subscribed to the filter
+    pub fn unsubscribe(&mut self, client: &C, filter: &TopicFilter) -> bool {
+        if let Entry::Occupied(mut entry) = self.clients.entry(client.clone()) {
+            if !entry.get_mut().remove(filter) {
+                return false;
+            }
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+        } else {
+            return false;
+        }
+
+        self.remove_subscriber(client, filter);
+        true
+    }
+
+    /// Removes all subscriptions of the client, returning the filters
+    pub fn remove_client(&mut self, client: &C) -> Vec<TopicFilter> {
+        let filters = match self.clients.remove(client) {
+            Some(filters) => filters,
+            None => return Vec::new(),
+        };
+
+        for filter in filters.iter() {
+            self.remove_subscriber(client, filter);
+        }
+        filters.into_iter().collect()
+    }
+
+    fn remove_subscriber(&mut self, client: &C, filter: &TopicFilter) {
+        let now_empty = match self.filters.get_mut(filter.as_str()) {
+            Some(subscribers) => {
+                subscribers.remove(client);
+                subscribers.is_empty()
+            },
+            None => false,
+        };
+        if now_empty {
+            self.filters.remove(filter.as_str());
+        }
+    }
+
+    /// Filters the client is subscribed to
+    pub fn subscriptions(&self, client: &C) -> Vec<(&TopicFilter, QualityOfService)> {
+        self.clients.get(client).into_iter()
+            .flat_map(|filters| filters.iter())
+            .filter_map(|filter| {
+                self.filters.get(filter.as_str())
+                    .and_then(|subscribers| subscribers.get(client))
+                    .map(|&qos| (filter, qos))
+            })
+            .collect()
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Clients receiving the packet, with the QoS to deliver it at
+    ///
+    /// A client with several matching subscriptions receives the packet once, at the maximum
+    /// QoS granted to them, but never above the QoS of the packet itself.
+    pub fn dispatch(&self, packet: &PublishPacket) -> impl Iterator<Item = (C, QualityOfService)> {
+        let publish_qos = packet.qos().qos();
+
+        let mut receivers = BTreeMap::new();
+        for subscribers in self.filters.matching_topic(packet.topic_name()) {
+            for (client, &qos) in subscribers.iter() {
+                let qos = qos.min(publish_qos);
+                let receiver = receivers.entry(client.clone()).or_insert(qos);
+                *receiver = (*receiver).max(qos);
+            }
+        }
+        receivers.into_iter()
+    }
+}
+
+impl<C: Ord + Clone> Default for SubscriptionTable<C> {
+    fn default() -> SubscriptionTable<C> {
+        SubscriptionTable::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use control::variable_header::PacketIdentifier;
+    use packet::{PublishPacket, QoSWithPacketIdentifier};
+    use topic_filter::TopicFilter;
+    use QualityOfService;
+
+    fn publish(topic_name: &str, qos: QualityOfService) -> PublishPacket {
+        let pkid = PacketIdentifier::new(10).unwrap();
+        let qos = match qos {
+            QualityOfService::Level0 => QoSWithPacketIdentifier::Level0,
+            QualityOfService::Level1 => QoSWithPacketIdentifier::Level1(pkid),
+            QualityOfService::Level2 => QoSWithPacketIdentifier::Level2(pkid),
+        };
+        PublishPacket::new(topic_name.to_owned(), qos, b"payload".to_vec())
+    }
+
+    fn dispatch(table: &SubscriptionTable<&'static str>, topic_name: &str, qos: QualityOfService)
+            -> Vec<(&'static str, QualityOfService)> {
+        table.dispatch(&publish(topic_name, qos)).collect()
+    }
+
+    #[test]
+    fn test_subscription_table_overlapping_filters() {
+        let mut table = SubscriptionTable::new();
+        table.subscribe("alice", TopicFilter::new("a/#"), QualityOfService::Level0);
+        table.subscribe("alice", TopicFilter::new("a/+"), QualityOfService::Level2);
+        table.subscribe("alice", TopicFilter::new("a/b"), QualityOfService::Level1);
+        table.subscribe("bob", TopicFilter::new("a/b"), QualityOfService::Level0);
+        table.subscribe("bob", TopicFilter::new("a/#"), QualityOfService::Level1);
+
+        assert_eq!(vec![("alice", QualityOfService::Level2), ("bob", QualityOfService::Level1)],
+                   dispatch(&table, "a/b", QualityOfService::Level2));
+        assert_eq!(vec![("alice", QualityOfService::Level1), ("bob", QualityOfService::Level1)],
+                   dispatch(&table, "a/b", QualityOfService::Level1));
+        assert_eq!(vec![("alice", QualityOfService::Level0), ("bob", QualityOfService::Level1)],
+                   dispatch(&table, "a/b/c", QualityOfService::Level2));
+        assert_eq!(vec![("alice", QualityOfService::Level0), ("bob", QualityOfService::Level1)],
+                   dispatch(&table, "a", QualityOfService::Level2));
+        assert_eq!(Vec::<(&str, QualityOfService)>::new(), dispatch(&table, "b", QualityOfService::Level2));
+
+        assert!(table.unsubscribe(&"alice", &TopicFilter::new("a/+")));
+        assert!(!table.unsubscribe(&"alice", &TopicFilter::new("a/+")));
+        assert_eq!(vec![("alice", QualityOfService::Level1), ("bob", QualityOfService::Level1)],
+                   dispatch(&table, "a/b", QualityOfService::Level2));
+
+        assert_eq!(vec![TopicFilter::new("a/#"), TopicFilter::new("a/b")], table.remove_client(&"bob"));
+        assert_eq!(vec![("alice", QualityOfService::Level1)], dispatch(&table, "a/b", QualityOfService::Level2));
+        assert_eq!(1, table.client_count());
+    }
+
+    #[test]
+    fn test_subscription_table_resubscribe() {
+        let mut table = SubscriptionTable::new();
+        assert_eq!(None, table.subscribe(1, TopicFilter::new("a/b"), QualityOfService::Level2));
+        assert_eq!(Some(QualityOfService::Level2), table.subscribe(1, TopicFilter::new("a/b"), QualityOfService::Level0));
+        assert_eq!(vec![(&TopicFilter::new("a/b"), QualityOfService::Level0)], table.subscriptions(&1));
+
+        table.remove_client(&1);
+        assert!(table.filters.is_empty());
+        assert!(table.subscriptions(&1).is_empty());
+    }
+
+    #[test]
+    fn test_subscription_table_sys_topics() {
+        let mut table = SubscriptionTable::new();
+        table.subscribe("alice", TopicFilter::new("#"), QualityOfService::Level0);
+        table.subscribe("bob", TopicFilter::new("+/uptime"), QualityOfService::Level0);
+        table.subscribe("carol", TopicFilter::new("$SYS/#"), QualityOfService::Level0);
+
+        assert_eq!(vec![("carol", QualityOfService::Level0)], dispatch(&table, "$SYS/uptime", QualityOfService::Level0));
+        assert_eq!(vec![("alice", QualityOfService::Level0), ("bob", QualityOfService::Level0)],
+                   dispatch(&table, "broker/uptime", QualityOfService::Level0));
+    }
+}
@@ -0,0 +1,145 @@
+//! `$SYS/broker/...` statistics topics, named and formatted like mosquitto's
+
+use std::time::Duration;
+
+use control::ControlType;
+use control::variable_header::TopicName;
+use packet::{PublishPacket, QoSWithPacketIdentifier};
+use stats::PacketStatsSnapshot;
+
+/// Values tracked by the broker itself rather than by `PacketStats`
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct BrokerGauges {
+    pub clients_connected: u64,
+    pub uptime: Duration,
+    pub version: String,
+}
+
+/// Payloads of the `$SYS/broker/...` topics at one point in time
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct SysTopics {
+    topics: Vec<(TopicName, String)>,
+}
+
+impl SysTopics {
+    pub fn new(stats: &PacketStatsSnapshot, gauges: &BrokerGauges) -> SysTopics {
+        let total = stats.total();
+        let publish = stats.get(ControlType::Publish);
+
+        let topics = vec![
+            ("broker/version", gauges.version.clone()),
+            ("broker/uptime", format!("{} seconds", gauges.uptime.as_secs())),
+            ("broker/clients/connected", gauges.clients_connected.to_string()),
+            ("broker/messages/received", total.packets_in.to_string()),
+            ("broker/messages/sent", total.packets_out.to_string()),
+            ("broker/publish/messages/received", publish.packets_in.to_string()),
+            ("broker/publish/messages/sent", publish.packets_out.to_string()),
+            ("broker/load/bytes/received", total.bytes_in.to_string()),
+            ("broker/load/bytes/sent", total.bytes_out.to_string()),
+        ];
+
+        SysTopics {
+            topics: topics.into_iter()
+                .map(|(path, payload)| (TopicName::sys(path).expect("Invalid $SYS topic"), payload))
+                .collect(),
+        }
+    }
+
+    /// Topic names and payloads, in a fixed order
+    pub fn topics(&self) -> &[(TopicName, String)] {
+        &self.topics
+    }
+
+    /// Retained QoS 0 PUBLISH packets of every topic
+    pub fn packets(&self) -> Vec<PublishPacket> {
+        self.topics.iter().map(|&(ref topic, ref payload)| sys_publish(topic, payload)).collect()
+    }
+
+    /// Retained QoS 0 PUBLISH packets of the topics whose payload differs from `previous`
+    pub fn diff(&self, previous: &SysTopics) -> Vec<PublishPacket> {
+        self.topics.iter()
+            .filter(|&&(ref topic, ref payload)| {
+                previous.topics.iter().find(|&&(ref t, _)| t == topic).map(|&(_, ref p)| p) != Some(payload)
+            })
+            .map(|&(ref topic, ref payload)| sys_publish(topic, payload))
+            .collect()
+    }
+}
+
+fn sys_publish(topic: &TopicName, payload: &str) -> PublishPacket {
+    let mut packet = PublishPacket::new(topic.0.clone(), QoSWithPacketIdentifier::Level0, payload.as_bytes().to_vec());
+    packet.set_retain(true);
+    packet
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Duration;
+
+    use packet::{Packet, PingreqPacket, VariablePacket};
+    use stats::PacketStats;
+
+    fn pairs(packets: &[PublishPacket]) -> Vec<(&str, &str)> {
+        packets.iter()
+            .inspect(|pk| assert!(pk.retain()))
+            .map(|pk| (pk.topic_name(), ::std::str::from_utf8(pk.payload()).unwrap()))
+            .collect()
+    }
+
+    fn sample_stats() -> PacketStats {
+        let stats = PacketStats::new();
+        let publish = VariablePacket::new(PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level0,
+                                                             b"hello".to_vec()));
+        stats.record_decoded(&publish, 12);
+        stats.record_encoded(&publish, 12);
+        stats.record_encoded(&publish, 12);
+        stats.record_decoded(&VariablePacket::new(PingreqPacket::new()), 2);
+        stats
+    }
+
+    fn gauges(uptime: u64) -> BrokerGauges {
+        BrokerGauges {
+            clients_connected: 3,
+            uptime: Duration::from_secs(uptime),
+            version: "mqtt-rs 0.1.0".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_sys_topics_packets() {
+        let topics = SysTopics::new(&sample_stats().snapshot(), &gauges(42));
+
+        assert_eq!(vec![
+            ("$SYS/broker/version", "mqtt-rs 0.1.0"),
+            ("$SYS/broker/uptime", "42 seconds"),
+            ("$SYS/broker/clients/connected", "3"),
+            ("$SYS/broker/messages/received", "2"),
+            ("$SYS/broker/messages/sent", "2"),
+            ("$SYS/broker/publish/messages/received", "1"),
+            ("$SYS/broker/publish/messages/sent", "2"),
+            ("$SYS/broker/load/bytes/received", "14"),
+            ("$SYS/broker/load/bytes/sent", "24"),
+        ], pairs(&topics.packets()));
+    }
+
+    #[test]
+    fn test_sys_topics_diff() {
+        let stats = sample_stats();
+        let previous = SysTopics::new(&stats.snapshot(), &gauges(42));
+        assert!(previous.diff(&previous).is_empty());
+
+        let publish = VariablePacket::new(PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level0,
+                                                             b"hi".to_vec()));
+        stats.record_encoded(&publish, 9);
+        let current = SysTopics::new(&stats.snapshot(), &gauges(52));
+
+        assert_eq!(vec![
+            ("$SYS/broker/uptime", "52 seconds"),
+            ("$SYS/broker/messages/sent", "3"),
+            ("$SYS/broker/publish/messages/sent", "3"),
+            ("$SYS/broker/load/bytes/sent", "33"),
+        ], pairs(&current.diff(&previous)));
+    }
+}
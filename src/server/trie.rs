@@ -0,0 +1,288 @@
+//! Values stored by topic level
+
+use std::collections::BTreeMap;
+
+use topic_filter::TopicFilter;
+
+#[derive(Debug, Clone)]
+struct Node<T> {
+    value: Option<T>,
+    children: BTreeMap<String, Node<T>>,
+}
+
+impl<T> Node<T> {
+    fn new() -> Node<T> {
+        Node {
+            value: None,
+            children: BTreeMap::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.value.is_none() && self.children.is_empty()
+    }
+
+    fn remove(&mut self, levels: &[&str]) -> Option<T> {
+        match levels.split_first() {
+            None => self.value.take(),
+            Some((level, rest)) => {
+                let (value, prune) = match self.children.get_mut(*level) {
+                    Some(child) => {
+                        let value = child.remove(rest);
+                        (value, child.is_empty())
+                    },
+                    None => return None,
+                };
+                if prune {
+                    self.children.remove(*level);
+                }
+                value
+            },
+        }
+    }
+
+    fn collect<'a>(&'a self, out: &mut Vec<&'a T>) {
+        out.extend(self.value.iter());
+        for child in self.children.values() {
+            child.collect(out);
+        }
+    }
+
+    fn match_topic<'a>(&'a self, levels: &[&str], first_level: bool, out: &mut Vec<&'a T>) {
+        let (level, rest) = match levels.split_first() {
+            Some(split) => split,
+            None => {
+                out.extend(self.value.iter());
+                // `a/#` also matches `a`
+                if let Some(child) = self.children.get("#") {
+                    out.extend(child.value.iter());
+                }
+                return;
+            },
+        };
+
+        if let Some(child) = self.children.get(*level) {
+            child.match_topic(rest, false, out);
+        }
+
+        // MQTT-4.7.2-1: Wildcards in the first level do not match topics starting with `$`
+        if first_level && level.starts_with('$') {
+            return;
+        }
+        if let Some(child) = self.children.get("+") {
+            child.match_topic(rest, false, out);
+        }
+        if let Some(child) = self.children.get("#") {
+            out.extend(child.value.iter());
+        }
+    }
+
+    fn match_filter<'a>(&'a self, levels: &[&str], first_level: bool, out: &mut Vec<&'a T>) {
+        let (level, rest) = match levels.split_first() {
+            Some(split) => split,
+            None => {
+                out.extend(self.value.iter());
+                return;
+            },
+        };
+
+        // MQTT-4.7.2-1: Wildcards in the first level do not match topics starting with `$`
+        let wildcard_children = self.children.iter()
+                                    .filter(|&(name, _)| !(first_level && name.starts_with('$')));
+        match *level {
+            "#" => {
+                // `a/#` also matches `a`
+                if !first_level {
+                    out.extend(self.value.iter());
+                }
+                for (_, child) in wildcard_children {
+                    child.collect(out);
+                }
+            },
+            "+" => {
+                for (_, child) in wildcard_children {
+                    child.match_filter(rest, false, out);
+                }
+            },
+            level => {
+                if let Some(child) = self.children.get(level) {
+                    child.match_filter(rest, false, out);
+                }
+            },
+        }
+    }
+}
+
+/// Map from topics to values, answering wildcard queries without a scan over all topics
+///
+/// Keys are topic names for `matching`, which looks up the topics matched by a filter, and
+/// topic filters for `matching_topic`, which looks up the filters matching a topic name.
+#[derive(Debug, Clone)]
+pub struct TopicTrie<T> {
+    root: Node<T>,
+    len: usize,
+}
+
+impl<T> TopicTrie<T> {
+    pub fn new() -> TopicTrie<T> {
+        TopicTrie {
+            root: Node::new(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn insert(&mut self, topic: &str, value: T) -> Option<T> {
+        let node = topic.split('/').fold(&mut self.root, |node, level| {
+            node.children.entry(level.to_owned()).or_insert_with(Node::new)
+        });
+        let old = node.value.take();
+        node.value = Some(value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn get(&self, topic: &str) -> Option<&T> {
+        let mut node = &self.root;
+        for level in topic.split('/') {
+            node = match node.children.get(level) {
+                Some(child) => child,
+                None => return None,
+            };
+        }
+        node.value.as_ref()
+    }
+
+    pub fn get_mut(&mut self, topic: &str) -> Option<&mut T> {
+        let mut node = &mut self.root;
+        for level in topic.split('/') {
+            node = match node.children.get_mut(level) {
+                Some(child) => child,
+                None => return None,
+            };
+        }
+        node.value.as_mut()
+    }
+
+    /// Removes the value and the levels that are left without values below them
+    pub fn remove(&mut self, topic: &str) -> Option<T> {
+        let levels = topic.split('/').collect::<Vec<_>>();
+        let value = self.root.remove(&levels);
+        if value.is_some() {
+            self.len -= 1;
+        }
+        value
+    }
+
+    /// Values of all topics matching the filter, ordered by topic level
+    pub fn matching(&self, filter: &TopicFilter) -> Vec<&T> {
+        let levels = filter.as_str().split('/').collect::<Vec<_>>();
+        let mut out = Vec::new();
+        self.root.match_filter(&levels, true, &mut out);
+        out
+    }
+
+    /// Values of all filters matching the topic name, in no particular order
+    pub fn matching_topic(&self, topic_name: &str) -> Vec<&T> {
+        let levels = topic_name.split('/').collect::<Vec<_>>();
+        let mut out = Vec::new();
+        self.root.match_topic(&levels, true, &mut out);
+        out
+    }
+}
+
+impl<T> Default for TopicTrie<T> {
+    fn default() -> TopicTrie<T> {
+        TopicTrie::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use topic_filter::TopicFilter;
+
+    fn matching(trie: &TopicTrie<&'static str>, filter: &str) -> Vec<&'static str> {
+        trie.matching(&TopicFilter::new(filter)).into_iter().cloned().collect()
+    }
+
+    #[test]
+    fn test_topic_trie_matching() {
+        let mut trie = TopicTrie::new();
+        for topic in ["sport", "sport/tennis", "sport/tennis/player1", "sport/tennis/player1/ranking",
+                      "sport/golf", "/finance", "$SYS/uptime"].iter() {
+            assert_eq!(None, trie.insert(topic, *topic));
+        }
+        assert_eq!(7, trie.len());
+
+        assert_eq!(vec!["sport/tennis/player1"], matching(&trie, "sport/tennis/player1"));
+        assert_eq!(vec!["sport/tennis/player1", "sport/tennis/player1/ranking"],
+                   matching(&trie, "sport/tennis/player1/#"));
+        assert_eq!(vec!["sport", "sport/golf", "sport/tennis", "sport/tennis/player1", "sport/tennis/player1/ranking"],
+                   matching(&trie, "sport/#"));
+        assert_eq!(vec!["sport/golf", "sport/tennis"], matching(&trie, "sport/+"));
+        assert_eq!(vec!["sport/tennis/player1"], matching(&trie, "+/tennis/+"));
+        assert_eq!(vec!["sport"], matching(&trie, "+"));
+        assert_eq!(vec!["/finance", "sport/golf", "sport/tennis"], matching(&trie, "+/+"));
+        assert_eq!(vec!["/finance"], matching(&trie, "/+"));
+        assert_eq!(Vec::<&str>::new(), matching(&trie, "sport/tennis/+/ranking/+"));
+
+        // MQTT-4.7.2-1
+        assert!(!matching(&trie, "#").contains(&"$SYS/uptime"));
+        assert_eq!(Vec::<&str>::new(), matching(&trie, "+/uptime"));
+        assert_eq!(vec!["$SYS/uptime"], matching(&trie, "$SYS/#"));
+    }
+
+    #[test]
+    fn test_topic_trie_matching_topic() {
+        let mut trie = TopicTrie::new();
+        for filter in ["#", "+", "+/+", "sport/#", "sport/+", "sport/tennis/#", "sport/tennis/+",
+                       "+/tennis/player1", "$SYS/#", "/+"].iter() {
+            trie.insert(filter, *filter);
+        }
+
+        let matching_topic = |topic_name| {
+            let mut matching = trie.matching_topic(topic_name).into_iter().cloned().collect::<Vec<_>>();
+            matching.sort();
+            matching
+        };
+
+        assert_eq!(vec!["#", "+", "sport/#"], matching_topic("sport"));
+        assert_eq!(vec!["#", "+/+", "sport/#", "sport/+", "sport/tennis/#"], matching_topic("sport/tennis"));
+        assert_eq!(vec!["#", "+/tennis/player1", "sport/#", "sport/tennis/#", "sport/tennis/+"],
+                   matching_topic("sport/tennis/player1"));
+        assert_eq!(vec!["#", "+/+", "/+"], matching_topic("/finance"));
+
+        // MQTT-4.7.2-1
+        assert_eq!(vec!["$SYS/#"], matching_topic("$SYS/uptime"));
+    }
+
+    #[test]
+    fn test_topic_trie_insert_remove() {
+        let mut trie = TopicTrie::new();
+        assert_eq!(None, trie.insert("a/b/c", 1));
+        assert_eq!(None, trie.insert("a", 2));
+        assert_eq!(Some(1), trie.insert("a/b/c", 3));
+        assert_eq!(2, trie.len());
+
+        assert_eq!(None, trie.remove("a/b"));
+        assert_eq!(Some(3), trie.remove("a/b/c"));
+        assert_eq!(None, trie.get("a/b/c"));
+        assert!(trie.root.children["a"].children.is_empty());
+
+        assert_eq!(Some(&2), trie.get("a"));
+        assert_eq!(Some(2), trie.remove("a"));
+        assert!(trie.is_empty());
+        assert!(trie.root.is_empty());
+    }
+}
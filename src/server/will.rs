@@ -0,0 +1,255 @@
+//! Will Messages of connected clients
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use control::variable_header::{PacketIdentifier, ProtocolVersion};
+use packet::{ConnectPacket, PublishPacket, QoSWithPacketIdentifier};
+use packet::connect::LastWill;
+use session::SessionExpiry;
+use QualityOfService;
+
+/// The Will Message of a connection, published unless the client disconnects cleanly
+///
+/// The will is armed when the network connection is lost and published once its delay has
+/// passed: the Will Delay Interval of an MQTT 5 will, capped by the Session Expiry Interval
+/// (MQTT 5 section 3.1.3.2.2). MQTT 3.1.1 wills have no delay. Time is passed in by the caller,
+/// nothing happens on its own.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct WillState {
+    will: Option<LastWill>,
+    mqtt5: bool,
+    delay: Duration,
+    publish_at: Option<Instant>,
+}
+
+impl WillState {
+    pub fn new(connect: &ConnectPacket) -> WillState {
+        let mqtt5 = connect.protocol_version() == Some(ProtocolVersion::V5);
+        let will_delay = match connect.will() {
+            Some(will) if mqtt5 => will.will_delay_interval().unwrap_or(0),
+            _ => 0,
+        };
+        let session_expiry = SessionExpiry::from_connect(connect).interval();
+
+        WillState {
+            will: connect.will().cloned(),
+            mqtt5: mqtt5,
+            delay: Duration::from_secs(cmp::min(will_delay, session_expiry) as u64),
+            publish_at: None,
+        }
+    }
+
+    pub fn will(&self) -> Option<&LastWill> {
+        self.will.as_ref()
+    }
+
+    /// Whether a will is left to publish
+    pub fn is_armed(&self) -> bool {
+        self.will.is_some()
+    }
+
+    /// Time between the loss of the connection and the publication of the will
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// When the will is due, `None` while the connection is open or once there is no will
+    pub fn publish_at(&self) -> Option<Instant> {
+        self.will.as_ref().and(self.publish_at)
+    }
+
+    /// Discards the will after a DISCONNECT packet (MQTT-3.1.2-10, MQTT-3.14.4-3)
+    pub fn on_disconnect_packet(&mut self) {
+        self.will = None;
+    }
+
+    /// Starts the delay when the network connection is closed without a DISCONNECT
+    ///
+    /// Later calls do not restart it.
+    pub fn on_connection_lost(&mut self, now: Instant) {
+        if self.publish_at.is_none() {
+            self.publish_at = Some(now + self.delay);
+        }
+    }
+
+    /// Discards the will when a new connection resumes the session before the delay has passed
+    /// (MQTT-3.1.3-9)
+    pub fn on_session_resumed(&mut self) {
+        self.will = None;
+    }
+
+    /// Makes the will due at `now` at the latest, when the session ends before the delay has
+    /// passed, e.g. because a new connection started a clean session
+    pub fn on_session_end(&mut self, now: Instant) {
+        self.publish_at = Some(self.publish_at.map_or(now, |at| cmp::min(at, now)));
+    }
+
+    /// The PUBLISH to send once the delay after losing the connection has passed
+    ///
+    /// It has the QoS and RETAIN flag of the will (MQTT-3.1.2-8) and, in MQTT 5, the Will
+    /// Properties other than the Will Delay Interval. `next_pkid` is called for QoS 1 and 2
+    /// wills. The will is published at most once, later calls return `None`.
+    pub fn take_will_publish<F>(&mut self, now: Instant, mut next_pkid: F) -> Option<PublishPacket>
+        where F: FnMut() -> PacketIdentifier
+    {
+        match self.publish_at() {
+            Some(publish_at) if now >= publish_at => {},
+            _ => return None,
+        }
+
+        let mqtt5 = self.mqtt5;
+        self.will.take().map(|will| {
+            let qos = match will.qos {
+                QualityOfService::Level0 => QoSWithPacketIdentifier::Level0,
+                QualityOfService::Level1 => QoSWithPacketIdentifier::Level1(next_pkid()),
+                QualityOfService::Level2 => QoSWithPacketIdentifier::Level2(next_pkid()),
+            };
+            let mut packet = if mqtt5 {
+                let properties = will.publish_properties();
+                let mut packet = PublishPacket::with_version(will.topic.0, qos, will.message, ProtocolVersion::V5);
+                *packet.properties_mut() = properties;
+                packet
+            } else {
+                PublishPacket::new(will.topic.0, qos, will.message)
+            };
+            packet.set_retain(will.retain);
+            packet
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use control::variable_header::{PacketIdentifier, Property, ProtocolVersion};
+    use packet::{ConnectPacket, Packet, QoSWithPacketIdentifier};
+    use packet::connect::LastWill;
+    use server::retain::{MemoryRetainedStore, RetainedStore};
+    use {QualityOfService, TopicFilter};
+
+    fn connect(qos: QualityOfService, retain: bool) -> ConnectPacket {
+        ConnectPacket::builder("client")
+            .will("clients/client/status", b"offline", qos, retain)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_will_state_abnormal_disconnect() {
+        let now = Instant::now();
+        let mut will = WillState::new(&connect(QualityOfService::Level2, false));
+        assert!(will.is_armed());
+        assert_eq!(None, will.take_will_publish(now, || panic!("The connection is still open")));
+
+        will.on_connection_lost(now);
+        assert_eq!(Some(now), will.publish_at());
+
+        let packet = will.take_will_publish(now, || PacketIdentifier::new(7).unwrap()).unwrap();
+        assert_eq!("clients/client/status", packet.topic_name());
+        assert_eq!(b"offline", &packet.payload()[..]);
+        assert_eq!(QoSWithPacketIdentifier::Level2(PacketIdentifier::new(7).unwrap()), packet.qos());
+        assert!(!packet.retain());
+        assert!(!packet.dup());
+
+        assert!(!will.is_armed());
+        assert_eq!(None, will.take_will_publish(now, || panic!("No packet identifier needed")));
+    }
+
+    #[test]
+    fn test_will_state_disconnect_packet() {
+        let now = Instant::now();
+        let mut will = WillState::new(&connect(QualityOfService::Level1, true));
+        will.on_disconnect_packet();
+        will.on_connection_lost(now);
+        assert_eq!(None, will.take_will_publish(now, || panic!("No packet identifier needed")));
+
+        let mut will = WillState::new(&ConnectPacket::new("client".to_owned()));
+        assert!(!will.is_armed());
+        will.on_connection_lost(now);
+        assert_eq!(None, will.take_will_publish(now, || panic!("No packet identifier needed")));
+    }
+
+    #[test]
+    fn test_will_state_retained() {
+        let now = Instant::now();
+        let mut will = WillState::new(&connect(QualityOfService::Level0, true));
+        will.on_connection_lost(now);
+        let packet = will.take_will_publish(now, || panic!("No packet identifier needed")).unwrap();
+        assert_eq!(QoSWithPacketIdentifier::Level0, packet.qos());
+        assert!(packet.retain());
+
+        let mut store = MemoryRetainedStore::new();
+        store.store(packet);
+        let retained = store.matching(&TopicFilter::new("clients/+/status"));
+        assert_eq!(1, retained.len());
+        assert_eq!(b"offline", &retained[0].payload()[..]);
+    }
+
+    fn connect_v5(will_delay: Option<u32>, session_expiry: Option<u32>) -> ConnectPacket {
+        let mut will = LastWill::new("clients/client/status".to_owned(), b"offline".to_vec(),
+                                     QualityOfService::Level1, false);
+        will.set_will_delay_interval(will_delay);
+        will.properties.insert(Property::ContentType("text/plain".to_owned()));
+        let mut connect = ConnectPacket::with_version("client".to_owned(), ProtocolVersion::V5);
+        connect.set_session_expiry_interval(session_expiry);
+        connect.set_will(Some(will)).unwrap();
+        connect
+    }
+
+    #[test]
+    fn test_will_state_delay() {
+        let start = Instant::now();
+        let mut will = WillState::new(&connect_v5(Some(10), Some(3600)));
+        assert_eq!(Duration::from_secs(10), will.delay());
+        assert_eq!(None, will.publish_at());
+
+        will.on_connection_lost(start);
+        will.on_connection_lost(start + Duration::from_secs(5));
+        assert_eq!(Some(start + Duration::from_secs(10)), will.publish_at());
+        assert_eq!(None, will.take_will_publish(start + Duration::from_secs(9), || panic!("Too early")));
+        assert!(will.is_armed());
+
+        let packet = will.take_will_publish(start + Duration::from_secs(10), || PacketIdentifier::new(1).unwrap())
+                         .unwrap();
+        assert!(packet.is_mqtt5());
+        assert_eq!(Some("text/plain"), packet.content_type());
+        assert_eq!(None, packet.properties().will_delay_interval());
+        assert!(!will.is_armed());
+        assert_eq!(None, will.publish_at());
+    }
+
+    #[test]
+    fn test_will_state_session_resumed() {
+        let start = Instant::now();
+        let mut will = WillState::new(&connect_v5(Some(10), Some(3600)));
+        will.on_connection_lost(start);
+        will.on_session_resumed();
+        assert_eq!(None, will.take_will_publish(start + Duration::from_secs(60), || panic!("Will discarded")));
+    }
+
+    #[test]
+    fn test_will_state_session_end() {
+        let start = Instant::now();
+
+        // The session ends before the Will Delay Interval
+        let mut will = WillState::new(&connect_v5(Some(60), Some(5)));
+        assert_eq!(Duration::from_secs(5), will.delay());
+        will.on_connection_lost(start);
+        assert_eq!(Some(start + Duration::from_secs(5)), will.publish_at());
+
+        // No Session Expiry Interval means the session ends with the connection
+        let will = WillState::new(&connect_v5(Some(60), None));
+        assert_eq!(Duration::from_secs(0), will.delay());
+
+        // A clean session started by a new connection
+        let mut will = WillState::new(&connect_v5(Some(60), Some(3600)));
+        will.on_connection_lost(start);
+        will.on_session_end(start + Duration::from_secs(2));
+        assert_eq!(Some(start + Duration::from_secs(2)), will.publish_at());
+        assert!(will.take_will_publish(start + Duration::from_secs(2), || PacketIdentifier::new(1).unwrap()).is_some());
+    }
+}
@@ -0,0 +1,201 @@
+//! When the state of a disconnected session may be discarded
+
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use control::variable_header::{DisconnectReasonCode, ProtocolVersion};
+use packet::{ConnectPacket, DisconnectPacket};
+
+/// Session Expiry Interval of a session that is never discarded
+pub const NEVER_EXPIRES: u32 = 0xFFFF_FFFF;
+
+/// Session Expiry Interval of one connection (MQTT 5 section 3.1.2.11.2)
+///
+/// MQTT 3.1.1 sessions map onto the same rules: a clean session ends with the connection like
+/// an interval of 0, any other session is kept like `NEVER_EXPIRES`.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct SessionExpiry {
+    interval: u32,
+    disconnected_at: Option<Instant>,
+}
+
+impl SessionExpiry {
+    /// `interval` in seconds
+    pub fn new(interval: u32) -> SessionExpiry {
+        SessionExpiry {
+            interval: interval,
+            disconnected_at: None,
+        }
+    }
+
+    pub fn from_connect(connect: &ConnectPacket) -> SessionExpiry {
+        if connect.protocol_version() == Some(ProtocolVersion::V5) {
+            SessionExpiry::new(connect.session_expiry_interval().unwrap_or(0))
+        } else if connect.clean_session() {
+            SessionExpiry::new(0)
+        } else {
+            SessionExpiry::new(NEVER_EXPIRES)
+        }
+    }
+
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    pub fn never_expires(&self) -> bool {
+        self.interval == NEVER_EXPIRES
+    }
+
+    /// Time the network connection was closed, `None` while it is open
+    pub fn disconnected_at(&self) -> Option<Instant> {
+        self.disconnected_at
+    }
+
+    /// Records the end of the network connection at `now`, with the DISCONNECT the client sent
+    /// if there was one
+    ///
+    /// The DISCONNECT may change the interval, except from 0 to anything else (MQTT-3.14.2-2).
+    /// That is an error and the interval of the CONNECT is kept, the server should answer with
+    /// a DISCONNECT carrying `err.reason_code()`.
+    pub fn record_disconnect(&mut self, disconnect: Option<&DisconnectPacket>, now: Instant)
+            -> Result<(), SessionExpiryError> {
+        self.disconnected_at = Some(now);
+
+        match disconnect.and_then(DisconnectPacket::session_expiry_interval) {
+            Some(interval) if self.interval == 0 && interval != 0 =>
+                Err(SessionExpiryError::ZeroIntervalRaised(interval)),
+            Some(interval) => {
+                self.interval = interval;
+                Ok(())
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// When a session disconnected at `disconnect_time` expires, `None` if it never does
+    pub fn expires_at(&self, disconnect_time: Instant) -> Option<Instant> {
+        if self.never_expires() {
+            return None;
+        }
+        disconnect_time.checked_add(Duration::from_secs(self.interval as u64))
+    }
+
+    /// Whether the session state may be discarded, never while the client is connected
+    pub fn is_expired(&self, now: Instant) -> bool {
+        match self.disconnected_at.and_then(|at| self.expires_at(at)) {
+            Some(expires_at) => now >= expires_at,
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum SessionExpiryError {
+    /// A DISCONNECT set a non-zero interval after the CONNECT set 0
+    ZeroIntervalRaised(u32),
+}
+
+impl SessionExpiryError {
+    /// Reason Code of the DISCONNECT the server sends
+    pub fn reason_code(&self) -> DisconnectReasonCode {
+        match self {
+            &SessionExpiryError::ZeroIntervalRaised(..) => DisconnectReasonCode::ProtocolError,
+        }
+    }
+}
+
+impl fmt::Display for SessionExpiryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &SessionExpiryError::ZeroIntervalRaised(interval) =>
+                write!(f, "Session Expiry Interval raised from 0 to {} on DISCONNECT", interval),
+        }
+    }
+}
+
+impl Error for SessionExpiryError {
+    fn description(&self) -> &str {
+        match self {
+            &SessionExpiryError::ZeroIntervalRaised(..) => "Session Expiry Interval raised from 0",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use control::variable_header::{Properties, Property};
+
+    fn disconnect(interval: Option<u32>) -> DisconnectPacket {
+        let mut packet = DisconnectPacket::new();
+        if let Some(interval) = interval {
+            let mut properties = Properties::new();
+            properties.insert(Property::SessionExpiryInterval(interval));
+            packet.set_properties(properties);
+        }
+        packet
+    }
+
+    #[test]
+    fn test_session_expiry_from_connect() {
+        let mut connect = ConnectPacket::new("client".to_owned());
+        connect.set_clean_session(true);
+        assert_eq!(0, SessionExpiry::from_connect(&connect).interval());
+        connect.set_clean_session(false);
+        assert!(SessionExpiry::from_connect(&connect).never_expires());
+
+        let mut connect = ConnectPacket::with_version("client".to_owned(), ProtocolVersion::V5);
+        connect.set_clean_session(false);
+        assert_eq!(0, SessionExpiry::from_connect(&connect).interval());
+        connect.set_session_expiry_interval(Some(120));
+        assert_eq!(120, SessionExpiry::from_connect(&connect).interval());
+    }
+
+    #[test]
+    fn test_session_expiry_interval() {
+        let start = Instant::now();
+        let mut expiry = SessionExpiry::new(60);
+        assert!(!expiry.is_expired(start + Duration::from_secs(3600)));
+
+        expiry.record_disconnect(None, start).unwrap();
+        assert_eq!(Some(start + Duration::from_secs(60)), expiry.expires_at(start));
+        assert!(!expiry.is_expired(start + Duration::from_secs(59)));
+        assert!(expiry.is_expired(start + Duration::from_secs(60)));
+
+        // Lowered to 0 by the DISCONNECT
+        let mut expiry = SessionExpiry::new(60);
+        expiry.record_disconnect(Some(&disconnect(Some(0))), start).unwrap();
+        assert!(expiry.is_expired(start));
+
+        let mut expiry = SessionExpiry::new(0);
+        expiry.record_disconnect(Some(&disconnect(None)), start).unwrap();
+        assert!(expiry.is_expired(start));
+    }
+
+    #[test]
+    fn test_session_expiry_raised_from_zero() {
+        let start = Instant::now();
+        let mut expiry = SessionExpiry::new(0);
+        let err = expiry.record_disconnect(Some(&disconnect(Some(30))), start).unwrap_err();
+        assert_eq!(SessionExpiryError::ZeroIntervalRaised(30), err);
+        assert_eq!(DisconnectReasonCode::ProtocolError, err.reason_code());
+        assert_eq!(0, expiry.interval());
+        assert!(expiry.is_expired(start));
+    }
+
+    #[test]
+    fn test_session_expiry_never_expires() {
+        let start = Instant::now();
+        let mut expiry = SessionExpiry::new(10);
+        expiry.record_disconnect(Some(&disconnect(Some(NEVER_EXPIRES))), start).unwrap();
+        assert!(expiry.never_expires());
+        assert_eq!(None, expiry.expires_at(start));
+        assert!(!expiry.is_expired(start + Duration::from_secs(u32::max_value() as u64)));
+
+        // Just below the special value the session does expire
+        let expiry = SessionExpiry::new(NEVER_EXPIRES - 1);
+        assert_eq!(start.checked_add(Duration::from_secs(NEVER_EXPIRES as u64 - 1)), expiry.expires_at(start));
+    }
+}
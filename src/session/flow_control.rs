@@ -0,0 +1,294 @@
+//! Receive Maximum flow control of MQTT 5 (MQTT 5 section 4.9)
+
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+
+use control::variable_header::{DisconnectReasonCode, PacketIdentifier};
+use packet::{ConnackPacket, ConnectPacket, VariablePacket};
+use session::PacketIdAllocator;
+use QualityOfService;
+
+/// Send quota for QoS 1 and 2 PUBLISH packets, limited by the Receive Maximum of the peer
+///
+/// A quota is taken before sending a QoS 1 or 2 PUBLISH and given back when its PUBACK or
+/// PUBCOMP arrives, or a PUBREC with an error Reason Code. Packets without quota have to wait,
+/// sending them anyway is a protocol error the peer answers with Reason Code 0x93.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct FlowControl {
+    receive_maximum: u16,
+    in_flight: u16,
+}
+
+impl FlowControl {
+    /// Panics if `receive_maximum` is 0, the spec does not allow it
+    pub fn new(receive_maximum: u16) -> FlowControl {
+        assert!(receive_maximum > 0, "Receive Maximum must not be 0");
+        FlowControl {
+            receive_maximum: receive_maximum,
+            in_flight: 0,
+        }
+    }
+
+    /// Quota of a server that received `connect`
+    pub fn from_connect(connect: &ConnectPacket) -> FlowControl {
+        FlowControl::new(connect.receive_maximum().unwrap_or(u16::max_value()))
+    }
+
+    /// Quota of a client that received `connack`, a Receive Maximum of 0 counts as 1
+    pub fn from_connack(connack: &ConnackPacket) -> FlowControl {
+        FlowControl::new(cmp::max(connack.receive_maximum(), 1))
+    }
+
+    pub fn receive_maximum(&self) -> u16 {
+        self.receive_maximum
+    }
+
+    pub fn in_flight(&self) -> u16 {
+        self.in_flight
+    }
+
+    /// Number of QoS 1 and 2 PUBLISH packets that can be sent right now
+    pub fn available(&self) -> u16 {
+        self.receive_maximum - self.in_flight
+    }
+
+    /// Takes one quota, `false` if none is left
+    pub fn try_acquire(&mut self) -> bool {
+        if self.in_flight >= self.receive_maximum {
+            return false;
+        }
+        self.in_flight += 1;
+        true
+    }
+
+    /// Gives back one quota, extra releases are ignored
+    pub fn release(&mut self) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+    }
+
+    /// Gives back the quota of the PUBLISH acknowledged by a received `packet`, returns whether
+    /// it did
+    pub fn release_on(&mut self, packet: &VariablePacket) -> bool {
+        let completed = ends_flow(packet);
+        if completed {
+            self.release();
+        }
+        completed
+    }
+
+    /// Takes one quota together with a packet identifier from `allocator`
+    ///
+    /// Neither is taken if the other is not available. Going through this and
+    /// `release_packet_id` keeps the quota and the identifiers in flight in step, while the
+    /// allocator can still hand out identifiers for SUBSCRIBE and UNSUBSCRIBE on its own.
+    pub fn allocate(&mut self, allocator: &mut PacketIdAllocator) -> Option<PacketIdentifier> {
+        if !self.try_acquire() {
+            return None;
+        }
+        let pkid = allocator.allocate();
+        if pkid.is_none() {
+            self.release();
+        }
+        pkid
+    }
+
+    /// Returns `pkid` to `allocator` and gives back its quota, `false` if it was not in flight
+    pub fn release_packet_id(&mut self, allocator: &mut PacketIdAllocator, pkid: PacketIdentifier) -> bool {
+        if !allocator.release(pkid) {
+            return false;
+        }
+        self.release();
+        true
+    }
+}
+
+/// Counts the QoS 1 and 2 PUBLISH packets received and not acknowledged yet, which must stay
+/// within the Receive Maximum we sent in CONNECT or CONNACK
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct InboundFlowControl {
+    receive_maximum: u16,
+    in_flight: u16,
+}
+
+impl InboundFlowControl {
+    /// Panics if `receive_maximum` is 0, the spec does not allow it
+    pub fn new(receive_maximum: u16) -> InboundFlowControl {
+        assert!(receive_maximum > 0, "Receive Maximum must not be 0");
+        InboundFlowControl {
+            receive_maximum: receive_maximum,
+            in_flight: 0,
+        }
+    }
+
+    pub fn receive_maximum(&self) -> u16 {
+        self.receive_maximum
+    }
+
+    pub fn in_flight(&self) -> u16 {
+        self.in_flight
+    }
+
+    /// Records a received PUBLISH, fails if the peer has exceeded the Receive Maximum
+    ///
+    /// The connection should then be closed with a DISCONNECT carrying `err.reason_code()`.
+    pub fn record_publish(&mut self, qos: QualityOfService) -> Result<(), ReceiveMaximumExceeded> {
+        if qos == QualityOfService::Level0 {
+            return Ok(());
+        }
+        if self.in_flight >= self.receive_maximum {
+            return Err(ReceiveMaximumExceeded { receive_maximum: self.receive_maximum });
+        }
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// Records the PUBACK, PUBCOMP or error PUBREC that ends an incoming flow
+    pub fn complete(&mut self) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+    }
+
+    /// Records a packet we send, returns whether it ended an incoming flow
+    pub fn complete_on(&mut self, packet: &VariablePacket) -> bool {
+        let completed = ends_flow(packet);
+        if completed {
+            self.complete();
+        }
+        completed
+    }
+}
+
+/// PUBACK, PUBCOMP and PUBREC with an error Reason Code are the last packet of a flow
+fn ends_flow(packet: &VariablePacket) -> bool {
+    match packet {
+        &VariablePacket::PubackPacket(..) => true,
+        &VariablePacket::PubcompPacket(..) => true,
+        &VariablePacket::PubrecPacket(ref pk) => pk.reason_code().is_error(),
+        _ => false,
+    }
+}
+
+/// The peer sent more unacknowledged QoS 1 and 2 PUBLISH packets than our Receive Maximum
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct ReceiveMaximumExceeded {
+    pub receive_maximum: u16,
+}
+
+impl ReceiveMaximumExceeded {
+    /// Reason Code of the DISCONNECT that closes the connection
+    pub fn reason_code(&self) -> DisconnectReasonCode {
+        DisconnectReasonCode::ReceiveMaximumExceeded
+    }
+}
+
+impl fmt::Display for ReceiveMaximumExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "More than {} unacknowledged QoS 1 and 2 PUBLISH packets received", self.receive_maximum)
+    }
+}
+
+impl Error for ReceiveMaximumExceeded {
+    fn description(&self) -> &str {
+        "Receive Maximum exceeded"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use control::variable_header::{ConnectReasonCode, Properties, Property, PubrecReasonCode};
+    use packet::*;
+
+    #[test]
+    fn test_flow_control_quota() {
+        let mut flow = FlowControl::new(2);
+        assert_eq!(2, flow.available());
+        assert!(flow.try_acquire());
+        assert!(flow.try_acquire());
+        assert!(!flow.try_acquire());
+        assert_eq!(0, flow.available());
+
+        flow.release();
+        assert_eq!(1, flow.available());
+        flow.release();
+        flow.release();
+        assert_eq!(0, flow.in_flight());
+        assert_eq!(2, flow.available());
+
+        assert_eq!(65535, FlowControl::from_connect(&ConnectPacket::new("client".to_owned())).receive_maximum());
+        let mut connack = ConnackPacket::with_reason_code(false, ConnectReasonCode::Success);
+        let mut properties = Properties::new();
+        properties.insert(Property::ReceiveMaximum(10));
+        connack.set_properties(properties);
+        assert_eq!(10, FlowControl::from_connack(&connack).available());
+    }
+
+    #[test]
+    fn test_flow_control_qos2_flow() {
+        let mut sender = FlowControl::new(2);
+        let mut allocator = PacketIdAllocator::new();
+        let mut receiver = InboundFlowControl::new(2);
+
+        let first = sender.allocate(&mut allocator).unwrap();
+        let second = sender.allocate(&mut allocator).unwrap();
+        assert_eq!(None, sender.allocate(&mut allocator));
+        assert_eq!(2, allocator.in_flight_count());
+        receiver.record_publish(QualityOfService::Level2).unwrap();
+        receiver.record_publish(QualityOfService::Level2).unwrap();
+
+        // A successful PUBREC keeps the quota until PUBCOMP
+        let pubrec = VariablePacket::new(PubrecPacket::new(first.get()));
+        assert!(!receiver.complete_on(&pubrec));
+        assert!(!sender.release_on(&pubrec));
+        let pubrel = VariablePacket::new(PubrelPacket::new(first.get()));
+        assert!(!sender.release_on(&pubrel));
+        assert_eq!(0, sender.available());
+
+        let pubcomp = VariablePacket::new(PubcompPacket::new(first.get()));
+        assert!(receiver.complete_on(&pubcomp));
+        assert!(sender.release_on(&pubcomp));
+        assert!(allocator.release(first));
+        assert_eq!(1, sender.available());
+        assert_eq!(1, receiver.in_flight());
+
+        // A failed PUBREC ends the flow
+        let third = sender.allocate(&mut allocator).unwrap();
+        receiver.record_publish(QualityOfService::Level2).unwrap();
+        let pubrec = VariablePacket::new(PubrecPacket::new_with_reason(third.get(), PubrecReasonCode::QuotaExceeded));
+        assert!(receiver.complete_on(&pubrec));
+        assert!(sender.release_on(&pubrec));
+        assert!(allocator.release(third));
+
+        assert!(sender.release_packet_id(&mut allocator, second));
+        assert!(!sender.release_packet_id(&mut allocator, second));
+        receiver.complete();
+        assert_eq!(0, sender.in_flight());
+        assert_eq!(0, allocator.in_flight_count());
+        assert_eq!(0, receiver.in_flight());
+    }
+
+    #[test]
+    fn test_flow_control_allocator_exhausted() {
+        let mut flow = FlowControl::new(10);
+        let mut allocator = PacketIdAllocator::with_max_in_flight(1);
+        assert!(flow.allocate(&mut allocator).is_some());
+        assert_eq!(None, flow.allocate(&mut allocator));
+        assert_eq!(1, flow.in_flight());
+    }
+
+    #[test]
+    fn test_inbound_flow_control_exceeded() {
+        let mut inbound = InboundFlowControl::new(1);
+        inbound.record_publish(QualityOfService::Level1).unwrap();
+        inbound.record_publish(QualityOfService::Level0).unwrap();
+
+        let err = inbound.record_publish(QualityOfService::Level2).unwrap_err();
+        assert_eq!(ReceiveMaximumExceeded { receive_maximum: 1 }, err);
+        let disconnect = DisconnectPacket::new_with_reason(err.reason_code());
+        assert_eq!(0x93, disconnect.reason_code().to_u8());
+
+        assert!(inbound.complete_on(&VariablePacket::new(PubackPacket::new(1))));
+        inbound.record_publish(QualityOfService::Level1).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use std::time::{Duration, Instant};
+
+use packet::ConnackPacket;
+use session::Role;
+
+/// Keep alive bookkeeping of a single connection
+///
+/// It does not do any IO, the caller records the packets it sends and receives and asks
+/// when the next PINGREQ is due or whether the peer has gone silent for too long.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct KeepAlive {
+    keep_alive: u16,
+    last_send: Instant,
+    last_recv: Instant,
+}
+
+impl KeepAlive {
+    /// `keep_alive` is the negotiated interval in seconds, 0 disables the mechanism
+    pub fn new(keep_alive: u16, now: Instant) -> KeepAlive {
+        KeepAlive {
+            keep_alive: keep_alive,
+            last_send: now,
+            last_recv: now,
+        }
+    }
+
+    pub fn keep_alive(&self) -> u16 {
+        self.keep_alive
+    }
+
+    /// Switches to the Server Keep Alive of an MQTT 5 CONNACK, if there is one
+    ///
+    /// The client has to use it instead of the value it sent (MQTT-3.2.2-21), 0 disables the
+    /// mechanism. Deadlines move right away, they are computed from the last recorded packets.
+    pub fn apply_connack(&mut self, connack: &ConnackPacket) {
+        if let Some(keep_alive) = connack.server_keep_alive() {
+            self.keep_alive = keep_alive;
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.keep_alive != 0
+    }
+
+    pub fn record_send(&mut self, now: Instant) {
+        if now > self.last_send {
+            self.last_send = now;
+        }
+    }
+
+    pub fn record_recv(&mut self, now: Instant) {
+        if now > self.last_recv {
+            self.last_recv = now;
+        }
+    }
+
+    /// When the client has to send a PINGREQ if nothing else is sent before (MQTT-3.1.2-23)
+    pub fn next_ping_deadline(&self) -> Option<Instant> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        Some(self.last_send + Duration::from_secs(self.keep_alive as u64))
+    }
+
+    pub fn should_ping(&self, now: Instant) -> bool {
+        self.next_ping_deadline().map_or(false, |deadline| now >= deadline)
+    }
+
+    /// When the connection is considered dead if nothing is received before
+    ///
+    /// A client waits one keep alive interval, a server one and a half (MQTT-3.1.2-24).
+    pub fn expiry_deadline(&self, role: Role) -> Option<Instant> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let millis = match role {
+            Role::Client => self.keep_alive as u64 * 1000,
+            Role::Server => self.keep_alive as u64 * 1500,
+        };
+        Some(self.last_recv + Duration::from_millis(millis))
+    }
+
+    pub fn is_expired(&self, now: Instant, role: Role) -> bool {
+        self.expiry_deadline(role).map_or(false, |deadline| now >= deadline)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use control::variable_header::{ConnectReasonCode, ConnectReturnCode, Properties, Property};
+    use session::Role;
+
+    #[test]
+    fn test_keep_alive_ping() {
+        let start = Instant::now();
+        let mut keep_alive = KeepAlive::new(10, start);
+
+        assert_eq!(Some(start + Duration::from_secs(10)), keep_alive.next_ping_deadline());
+        assert!(!keep_alive.should_ping(start + Duration::from_secs(9)));
+        assert!(keep_alive.should_ping(start + Duration::from_secs(10)));
+
+        keep_alive.record_send(start + Duration::from_secs(5));
+        assert!(!keep_alive.should_ping(start + Duration::from_secs(10)));
+        assert!(keep_alive.should_ping(start + Duration::from_secs(15)));
+
+        // Receiving does not postpone the ping
+        keep_alive.record_recv(start + Duration::from_secs(14));
+        assert!(keep_alive.should_ping(start + Duration::from_secs(15)));
+    }
+
+    #[test]
+    fn test_keep_alive_expiry() {
+        let start = Instant::now();
+        let mut keep_alive = KeepAlive::new(10, start);
+
+        assert!(!keep_alive.is_expired(start + Duration::from_millis(9999), Role::Client));
+        assert!(keep_alive.is_expired(start + Duration::from_secs(10), Role::Client));
+        assert!(!keep_alive.is_expired(start + Duration::from_millis(14999), Role::Server));
+        assert!(keep_alive.is_expired(start + Duration::from_secs(15), Role::Server));
+
+        keep_alive.record_recv(start + Duration::from_secs(10));
+        assert!(!keep_alive.is_expired(start + Duration::from_secs(20), Role::Server));
+        assert!(keep_alive.is_expired(start + Duration::from_secs(25), Role::Server));
+
+        // Instants recorded out of order do not move the deadline back
+        keep_alive.record_recv(start);
+        assert_eq!(Some(start + Duration::from_secs(25)), keep_alive.expiry_deadline(Role::Server));
+    }
+
+    #[test]
+    fn test_keep_alive_disabled() {
+        let start = Instant::now();
+        let keep_alive = KeepAlive::new(0, start);
+        let later = start + Duration::from_secs(24 * 60 * 60);
+
+        assert!(!keep_alive.is_enabled());
+        assert_eq!(None, keep_alive.next_ping_deadline());
+        assert!(!keep_alive.should_ping(later));
+        assert!(!keep_alive.is_expired(later, Role::Client));
+        assert!(!keep_alive.is_expired(later, Role::Server));
+    }
+
+    #[test]
+    fn test_keep_alive_server_override() {
+        fn connack(server_keep_alive: Option<u16>) -> ConnackPacket {
+            let mut connack = ConnackPacket::with_reason_code(false, ConnectReasonCode::Success);
+            if let Some(keep_alive) = server_keep_alive {
+                let mut properties = Properties::new();
+                properties.insert(Property::ServerKeepAlive(keep_alive));
+                connack.set_properties(properties);
+            }
+            connack
+        }
+
+        let start = Instant::now();
+        let mut keep_alive = KeepAlive::new(60, start);
+        keep_alive.apply_connack(&connack(None));
+        assert_eq!(60, keep_alive.keep_alive());
+        keep_alive.apply_connack(&ConnackPacket::new(false, ConnectReturnCode::ConnectionAccepted));
+        assert_eq!(60, keep_alive.keep_alive());
+
+        keep_alive.apply_connack(&connack(Some(10)));
+        assert_eq!(10, keep_alive.keep_alive());
+        assert_eq!(Some(start + Duration::from_secs(10)), keep_alive.next_ping_deadline());
+        assert!(keep_alive.is_expired(start + Duration::from_secs(10), Role::Client));
+
+        // The server may also ask for a longer interval, or none at all
+        keep_alive.apply_connack(&connack(Some(120)));
+        assert_eq!(Some(start + Duration::from_secs(120)), keep_alive.next_ping_deadline());
+        keep_alive.apply_connack(&connack(Some(0)));
+        assert!(!keep_alive.is_enabled());
+        assert_eq!(None, keep_alive.next_ping_deadline());
+        assert!(!keep_alive.is_expired(start + Duration::from_secs(3600), Role::Client));
+    }
+}
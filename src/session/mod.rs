@@ -0,0 +1,28 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub use self::expiry::{SessionExpiry, SessionExpiryError, NEVER_EXPIRES};
+pub use self::flow_control::{FlowControl, InboundFlowControl, ReceiveMaximumExceeded};
+pub use self::keep_alive::KeepAlive;
+pub use self::packet_id::PacketIdAllocator;
+pub use self::pump::{PacketPump, PumpError};
+pub use self::qos1::{Qos1Tracker, Qos1TrackerError};
+pub use self::qos2::{Qos2StateMachine, Qos2Error, IncomingPublish};
+pub use self::state::{SessionState, SessionStateError};
+pub use self::validator::{ProtocolStateValidator, ProtocolViolation, Role};
+
+pub mod expiry;
+pub mod flow_control;
+pub mod keep_alive;
+pub mod packet_id;
+pub mod pump;
+pub mod qos1;
+pub mod qos2;
+pub mod state;
+pub mod validator;
+
+// Order in which QoS 1 and 2 messages are sent, shared by the trackers so the messages of a
+// session can be sent again in their original order (MQTT-4.6.0-1)
+fn next_send_sequence() -> u64 {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    SEQUENCE.fetch_add(1, Ordering::Relaxed)
+}
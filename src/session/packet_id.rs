@@ -0,0 +1,234 @@
+use std::fmt;
+
+use control::variable_header::PacketIdentifier;
+
+const WORDS: usize = 65536 / 64;
+
+/// Hands out packet identifiers that are not in flight
+///
+/// Identifiers are allocated in increasing order, wrapping around from 65535 to 1 and
+/// skipping the ones that have not been released yet.
+#[derive(Clone)]
+pub struct PacketIdAllocator {
+    in_flight: Box<[u64; WORDS]>,
+    in_flight_count: u32,
+    max_in_flight: u32,
+    next: u16,
+}
+
+impl PacketIdAllocator {
+    pub fn new() -> PacketIdAllocator {
+        PacketIdAllocator::with_max_in_flight(u16::max_value())
+    }
+
+    /// Allocates at most `max_in_flight` identifiers at once, e.g. the receive maximum of the peer
+    pub fn with_max_in_flight(max_in_flight: u16) -> PacketIdAllocator {
+        PacketIdAllocator {
+            in_flight: Box::new([0; WORDS]),
+            in_flight_count: 0,
+            max_in_flight: max_in_flight as u32,
+            next: 1,
+        }
+    }
+
+    /// Next free identifier, `None` if the maximum number of identifiers is in flight
+    pub fn allocate(&mut self) -> Option<PacketIdentifier> {
+        if self.in_flight_count >= self.max_in_flight {
+            return None;
+        }
+
+        let mut id = self.next;
+        loop {
+            if !self.test(id) {
+                break;
+            }
+            id = next_id(id);
+        }
+
+        self.set(id, true);
+        self.in_flight_count += 1;
+        self.next = next_id(id);
+        PacketIdentifier::new(id)
+    }
+
+    /// Returns the identifier to the pool, `false` if it was not in flight
+    pub fn release(&mut self, pkid: PacketIdentifier) -> bool {
+        let id = pkid.get();
+        if !self.test(id) {
+            return false;
+        }
+
+        self.set(id, false);
+        self.in_flight_count -= 1;
+        true
+    }
+
+    /// Marks an identifier as in flight regardless of the maximum, e.g. when restoring a session
+    pub fn reserve(&mut self, pkid: PacketIdentifier) -> bool {
+        let id = pkid.get();
+        if self.test(id) {
+            return false;
+        }
+
+        self.set(id, true);
+        self.in_flight_count += 1;
+        true
+    }
+
+    /// Where the search for the next free identifier starts
+    pub fn next_packet_identifier(&self) -> PacketIdentifier {
+        PacketIdentifier::new(self.next).expect("Packet identifier must be non-zero")
+    }
+
+    pub fn set_next_packet_identifier(&mut self, pkid: PacketIdentifier) {
+        self.next = pkid.get();
+    }
+
+    pub fn is_in_flight(&self, pkid: PacketIdentifier) -> bool {
+        self.test(pkid.get())
+    }
+
+    pub fn in_flight_count(&self) -> usize {
+        self.in_flight_count as usize
+    }
+
+    fn test(&self, id: u16) -> bool {
+        self.in_flight[id as usize / 64] & (1 << (id % 64)) != 0
+    }
+
+    fn set(&mut self, id: u16, in_flight: bool) {
+        let word = &mut self.in_flight[id as usize / 64];
+        if in_flight {
+            *word |= 1 << (id % 64);
+        } else {
+            *word &= !(1 << (id % 64));
+        }
+    }
+}
+
+fn next_id(id: u16) -> u16 {
+    if id == u16::max_value() { 1 } else { id + 1 }
+}
+
+impl Default for PacketIdAllocator {
+    fn default() -> PacketIdAllocator {
+        PacketIdAllocator::new()
+    }
+}
+
+impl fmt::Debug for PacketIdAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PacketIdAllocator")
+            .field("in_flight_count", &self.in_flight_count)
+            .field("max_in_flight", &self.max_in_flight)
+            .field("next", &self.next)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    use control::variable_header::PacketIdentifier;
+
+    #[test]
+    fn test_packet_id_allocator_wrap_around() {
+        let mut allocator = PacketIdAllocator::new();
+        let first = allocator.allocate().unwrap();
+        assert_eq!(1, first.get());
+
+        for id in 2..u16::max_value() as u32 + 1 {
+            let pkid = allocator.allocate().unwrap();
+            assert_eq!(id as u16, pkid.get());
+            if id != 3 {
+                assert!(allocator.release(pkid));
+            }
+        }
+
+        // 1 and 3 are still in flight
+        assert_eq!(2, allocator.allocate().unwrap().get());
+        assert_eq!(4, allocator.allocate().unwrap().get());
+        assert!(allocator.is_in_flight(first));
+        assert!(!allocator.is_in_flight(PacketIdentifier::new(5).unwrap()));
+        assert!(!allocator.release(PacketIdentifier::new(5).unwrap()));
+    }
+
+    #[test]
+    fn test_packet_id_allocator_exhausted() {
+        let mut allocator = PacketIdAllocator::new();
+        for _ in 0..u16::max_value() {
+            assert!(allocator.allocate().is_some());
+        }
+        assert_eq!(None, allocator.allocate());
+
+        allocator.release(PacketIdentifier::new(42).unwrap());
+        assert_eq!(Some(PacketIdentifier::new(42).unwrap()), allocator.allocate());
+        assert_eq!(None, allocator.allocate());
+    }
+
+    #[test]
+    fn test_packet_id_allocator_max_in_flight() {
+        let mut allocator = PacketIdAllocator::with_max_in_flight(2);
+        let first = allocator.allocate().unwrap();
+        allocator.allocate().unwrap();
+        assert_eq!(None, allocator.allocate());
+
+        allocator.release(first);
+        assert_eq!(3, allocator.allocate().unwrap().get());
+        assert_eq!(2, allocator.in_flight_count());
+    }
+
+    #[test]
+    fn test_packet_id_allocator_reserve() {
+        let mut allocator = PacketIdAllocator::with_max_in_flight(1);
+        assert!(allocator.reserve(PacketIdentifier::new(2).unwrap()));
+        assert!(!allocator.reserve(PacketIdentifier::new(2).unwrap()));
+        assert_eq!(None, allocator.allocate());
+
+        let mut allocator = PacketIdAllocator::new();
+        allocator.reserve(PacketIdentifier::new(2).unwrap());
+        allocator.set_next_packet_identifier(PacketIdentifier::new(2).unwrap());
+        assert_eq!(3, allocator.allocate().unwrap().get());
+        assert_eq!(4, allocator.next_packet_identifier().get());
+    }
+
+    #[test]
+    fn test_packet_id_allocator_never_duplicates() {
+        // Deterministic xorshift so failures are reproducible
+        let mut state: u32 = 0x2545_f491;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+
+        for &max_in_flight in [1, 7, 1000, u16::max_value()].iter() {
+            let mut allocator = PacketIdAllocator::with_max_in_flight(max_in_flight);
+            let mut in_flight = Vec::new();
+            let mut in_flight_set = HashSet::new();
+
+            for _ in 0..200_000 {
+                if random() % 3 != 0 {
+                    match allocator.allocate() {
+                        Some(pkid) => {
+                            assert!(in_flight_set.insert(pkid), "{} allocated twice", pkid);
+                            in_flight.push(pkid);
+                        },
+                        None => assert_eq!(max_in_flight as usize, in_flight.len()),
+                    }
+                } else if !in_flight.is_empty() {
+                    let idx = random() as usize % in_flight.len();
+                    let pkid = in_flight.swap_remove(idx);
+                    in_flight_set.remove(&pkid);
+                    assert!(allocator.release(pkid));
+                }
+
+                assert_eq!(in_flight.len(), allocator.in_flight_count());
+            }
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! Keep-alive handling for connections that only occasionally send packets
+
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::Instant;
+
+use packet::{DecodeOptions, PacketSizeLimit, PacketTooLarge, PingreqPacket, PingrespPacket, VariablePacket};
+use packet::VariablePacketError;
+use session::{KeepAlive, Role};
+use stats::{self, PacketStats};
+use {Encodable, Decodable};
+
+/// Answers and sends the keep-alive pings of a connection
+///
+/// PINGREQ packets are answered with a PINGRESP and PINGRESP packets are consumed, every other
+/// packet is handed to the caller. `next_application_packet` reads from the stream, packets
+/// decoded elsewhere go through `handle`. Either way replies are written to the stream.
+#[derive(Debug)]
+pub struct PacketPump<T> {
+    stream: T,
+    role: Role,
+    keep_alive: KeepAlive,
+    stats: Option<Arc<PacketStats>>,
+    size_limit: PacketSizeLimit,
+}
+
+impl<T: Write> PacketPump<T> {
+    pub fn new(stream: T, role: Role, keep_alive: u16, now: Instant) -> PacketPump<T> {
+        PacketPump {
+            stream: stream,
+            role: role,
+            keep_alive: KeepAlive::new(keep_alive, now),
+            stats: None,
+            size_limit: PacketSizeLimit::default(),
+        }
+    }
+
+    /// Records the packets sent and received from now on
+    pub fn set_stats(&mut self, stats: Option<Arc<PacketStats>>) {
+        self.stats = stats;
+    }
+
+    /// Maximum Packet Sizes negotiated in CONNECT and CONNACK
+    ///
+    /// Packets over the peer's limit are dropped without writing anything, packets over ours
+    /// fail to decode and the connection should be closed with `DisconnectPacket::packet_too_large`.
+    pub fn set_size_limit(&mut self, size_limit: PacketSizeLimit) {
+        self.size_limit = size_limit;
+    }
+
+    pub fn keep_alive(&self) -> &KeepAlive {
+        &self.keep_alive
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> T {
+        self.stream
+    }
+
+    pub fn send<'a, P>(&mut self, packet: P) -> Result<(), PumpError<'a>>
+        where VariablePacket: From<P>
+    {
+        self.send_at(VariablePacket::new(packet), Instant::now())
+    }
+
+    fn send_at<'a>(&mut self, packet: VariablePacket, now: Instant) -> Result<(), PumpError<'a>> {
+        try!(self.size_limit.check_outgoing(&packet).map_err(PumpError::PacketTooLarge));
+        try!(packet.encode(&mut self.stream));
+        try!(self.stream.flush().map_err(VariablePacketError::IoError));
+        stats::record_encoded(self.stats.as_ref().map(|s| &**s), &packet);
+        self.keep_alive.record_send(now);
+        Ok(())
+    }
+
+    /// Handles a received packet, returns it unless it was a ping
+    ///
+    /// A CONNACK is returned as well, after switching to its Server Keep Alive.
+    pub fn handle<'a>(&mut self, packet: VariablePacket, now: Instant) -> Result<Option<VariablePacket>, PumpError<'a>> {
+        self.keep_alive.record_recv(now);
+        if let VariablePacket::ConnackPacket(ref connack) = packet {
+            self.keep_alive.apply_connack(connack);
+        }
+        match packet {
+            VariablePacket::PingreqPacket(..) => {
+                try!(self.send_at(VariablePacket::new(PingrespPacket::new()), now));
+                Ok(None)
+            },
+            VariablePacket::PingrespPacket(..) => Ok(None),
+            packet => Ok(Some(packet)),
+        }
+    }
+
+    /// Sends a PINGREQ when the client has been silent for the keep alive, and fails once
+    /// nothing has been received for too long
+    pub fn poll_keep_alive<'a>(&mut self, now: Instant) -> Result<(), PumpError<'a>> {
+        if self.keep_alive.is_expired(now, self.role) {
+            return Err(PumpError::KeepAliveTimeout);
+        }
+        if self.role == Role::Client && self.keep_alive.should_ping(now) {
+            try!(self.send_at(VariablePacket::new(PingreqPacket::new()), now));
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read + Write> PacketPump<T> {
+    /// Blocks until a packet other than a ping arrives
+    pub fn next_application_packet<'a>(&mut self) -> Result<VariablePacket, PumpError<'a>> {
+        loop {
+            let result = match self.size_limit.incoming_limit() {
+                Some(..) => {
+                    let options = self.size_limit.decode_options(DecodeOptions::new());
+                    VariablePacket::decode_with_options(&mut self.stream, &options)
+                },
+                None => VariablePacket::decode(&mut self.stream),
+            };
+            stats::record_decoded(self.stats.as_ref().map(|s| &**s), &result);
+            let packet = try!(result);
+            if let Some(packet) = try!(self.handle(packet, Instant::now())) {
+                return Ok(packet);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PumpError<'a> {
+    VariablePacketError(VariablePacketError<'a>),
+    KeepAliveTimeout,
+    /// The packet was not sent
+    PacketTooLarge(PacketTooLarge),
+}
+
+impl<'a> From<VariablePacketError<'a>> for PumpError<'a> {
+    fn from(err: VariablePacketError<'a>) -> PumpError<'a> {
+        PumpError::VariablePacketError(err)
+    }
+}
+
+impl<'a> fmt::Display for PumpError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &PumpError::VariablePacketError(ref err) => err.fmt(f),
+            &PumpError::KeepAliveTimeout => write!(f, "Nothing received within the keep alive"),
+            &PumpError::PacketTooLarge(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl<'a> Error for PumpError<'a> {
+    fn description(&self) -> &str {
+        match self {
+            &PumpError::VariablePacketError(ref err) => err.description(),
+            &PumpError::KeepAliveTimeout => "Nothing received within the keep alive",
+            &PumpError::PacketTooLarge(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match self {
+            &PumpError::VariablePacketError(ref err) => Some(err),
+            &PumpError::PacketTooLarge(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::{self, Cursor, Read, Write};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use control::ControlType;
+    use control::variable_header::{ConnectReasonCode, Properties, Property};
+    use packet::*;
+    use session::Role;
+    use stats::PacketStats;
+    use {Encodable, Decodable};
+
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl MockStream {
+        fn new(packets: &[VariablePacket]) -> MockStream {
+            let mut input = Vec::new();
+            for packet in packets {
+                packet.encode(&mut input).unwrap();
+            }
+            MockStream {
+                input: Cursor::new(input),
+                output: Vec::new(),
+            }
+        }
+
+        fn sent(&self) -> Vec<VariablePacket> {
+            let mut output = Cursor::new(&self.output[..]);
+            let mut packets = Vec::new();
+            while (output.position() as usize) < self.output.len() {
+                packets.push(VariablePacket::decode(&mut output).unwrap());
+            }
+            packets
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn publish(topic_name: &str) -> VariablePacket {
+        VariablePacket::new(PublishPacket::new(topic_name.to_owned(), QoSWithPacketIdentifier::Level0, b"hi".to_vec()))
+    }
+
+    #[test]
+    fn test_packet_pump_server() {
+        let stream = MockStream::new(&[
+            VariablePacket::new(PingreqPacket::new()),
+            publish("a"),
+            VariablePacket::new(PingreqPacket::new()),
+            VariablePacket::new(PingreqPacket::new()),
+            publish("b"),
+        ]);
+
+        let stats = Arc::new(PacketStats::new());
+        let mut pump = PacketPump::new(stream, Role::Server, 30, Instant::now());
+        pump.set_stats(Some(stats.clone()));
+        assert_eq!(publish("a"), pump.next_application_packet().unwrap());
+        assert_eq!(publish("b"), pump.next_application_packet().unwrap());
+        match pump.next_application_packet() {
+            Err(PumpError::VariablePacketError(..)) => {},
+            res => panic!("Unexpected result {:?}", res),
+        }
+
+        assert_eq!(vec![VariablePacket::new(PingrespPacket::new()); 3], pump.get_ref().sent());
+
+        let snapshot = stats.snapshot();
+        assert_eq!(3, snapshot.get(ControlType::PingRequest).packets_in);
+        assert_eq!(3, snapshot.get(ControlType::PingResponse).packets_out);
+        assert_eq!(2, snapshot.get(ControlType::Publish).packets_in);
+        assert_eq!(1, snapshot.decode_errors.io);
+    }
+
+    #[test]
+    fn test_packet_pump_client() {
+        let start = Instant::now();
+        let mut pump = PacketPump::new(MockStream::new(&[]), Role::Client, 10, start);
+
+        let now = start + Duration::from_secs(5);
+        assert_eq!(Some(publish("a")), pump.handle(publish("a"), now).unwrap());
+        pump.poll_keep_alive(start + Duration::from_secs(9)).unwrap();
+        assert!(pump.get_ref().sent().is_empty());
+
+        pump.poll_keep_alive(start + Duration::from_secs(10)).unwrap();
+        assert_eq!(vec![VariablePacket::new(PingreqPacket::new())], pump.get_ref().sent());
+
+        // The PINGRESP is consumed and counts as received traffic
+        let now = start + Duration::from_secs(12);
+        assert_eq!(None, pump.handle(VariablePacket::new(PingrespPacket::new()), now).unwrap());
+        pump.poll_keep_alive(start + Duration::from_secs(21)).unwrap();
+        assert_eq!(2, pump.get_ref().sent().len());
+
+        match pump.poll_keep_alive(start + Duration::from_secs(22)) {
+            Err(PumpError::KeepAliveTimeout) => {},
+            res => panic!("Unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_packet_pump_size_limit() {
+        let mut encoded = Vec::new();
+        publish("a/b").encode(&mut encoded).unwrap();
+        let size = encoded.len() as u32;
+
+        let mut pump = PacketPump::new(MockStream::new(&[publish("a/b"), publish("a/b")]), Role::Server, 0, Instant::now());
+        pump.set_size_limit(PacketSizeLimit::new(Some(size), Some(size - 1)));
+        match pump.send(PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level0, b"hi".to_vec())) {
+            Err(PumpError::PacketTooLarge(PacketTooLarge { size: s, maximum })) => {
+                assert_eq!(size, s);
+                assert_eq!(size - 1, maximum);
+            },
+            res => panic!("Unexpected result {:?}", res),
+        }
+        assert!(pump.get_ref().output.is_empty());
+        pump.send(PublishPacket::new("a".to_owned(), QoSWithPacketIdentifier::Level0, b"hi".to_vec())).unwrap();
+        assert_eq!(vec![publish("a")], pump.get_ref().sent());
+
+        assert_eq!(publish("a/b"), pump.next_application_packet().unwrap());
+        pump.set_size_limit(PacketSizeLimit::new(Some(size - 1), None));
+        match pump.next_application_packet() {
+            Err(PumpError::VariablePacketError(VariablePacketError::PacketTooLarge(s))) => assert_eq!(size, s),
+            res => panic!("Unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_packet_pump_server_keep_alive() {
+        let start = Instant::now();
+        let mut pump = PacketPump::new(MockStream::new(&[]), Role::Client, 60, start);
+        assert_eq!(Some(start + Duration::from_secs(60)), pump.keep_alive().next_ping_deadline());
+
+        let mut connack = ConnackPacket::with_reason_code(false, ConnectReasonCode::Success);
+        let mut properties = Properties::new();
+        properties.insert(Property::ServerKeepAlive(10));
+        connack.set_properties(properties);
+        let now = start + Duration::from_secs(1);
+        assert_eq!(Some(VariablePacket::new(connack.clone())),
+                   pump.handle(VariablePacket::new(connack), now).unwrap());
+
+        assert_eq!(10, pump.keep_alive().keep_alive());
+        assert_eq!(Some(start + Duration::from_secs(10)), pump.keep_alive().next_ping_deadline());
+        pump.poll_keep_alive(start + Duration::from_secs(9)).unwrap();
+        assert!(pump.get_ref().sent().is_empty());
+        pump.poll_keep_alive(start + Duration::from_secs(10)).unwrap();
+        assert_eq!(vec![VariablePacket::new(PingreqPacket::new())], pump.get_ref().sent());
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use control::variable_header::PacketIdentifier;
+use packet::{PublishPacket, QoSWithPacketIdentifier};
+use session::next_send_sequence;
+
+/// Outgoing QoS 1 messages waiting for their PUBACK
+///
+/// Messages are kept in the order they were registered, which is also the order they
+/// have to be retransmitted in (MQTT-4.6.0-1).
+#[derive(Debug, Clone)]
+pub struct Qos1Tracker {
+    max_in_flight: Option<usize>,
+    pending: Vec<Pending>,
+    acknowledged: HashSet<PacketIdentifier>,
+}
+
+#[derive(Debug, Clone)]
+struct Pending {
+    packet: PublishPacket,
+    pkid: PacketIdentifier,
+    sent_at: Instant,
+    sequence: u64,
+}
+
+impl Qos1Tracker {
+    pub fn new() -> Qos1Tracker {
+        Qos1Tracker {
+            max_in_flight: None,
+            pending: Vec::new(),
+            acknowledged: HashSet::new(),
+        }
+    }
+
+    /// Refuses to register more than `max_in_flight` unacknowledged messages
+    pub fn with_max_in_flight(max_in_flight: usize) -> Qos1Tracker {
+        Qos1Tracker {
+            max_in_flight: Some(max_in_flight),
+            ..Qos1Tracker::new()
+        }
+    }
+
+    /// Whether another message can be registered without exceeding the in-flight window
+    pub fn has_capacity(&self) -> bool {
+        self.max_in_flight.map_or(true, |max| self.pending.len() < max)
+    }
+
+    /// Keeps a QoS 1 message that has been sent at `now` until it is acknowledged
+    pub fn register(&mut self, packet: PublishPacket, now: Instant) -> Result<(), Qos1TrackerError> {
+        let pkid = match packet.qos() {
+            QoSWithPacketIdentifier::Level1(pkid) => pkid,
+            _ => return Err(Qos1TrackerError::NotQoS1),
+        };
+
+        if self.pending.iter().any(|p| p.pkid == pkid) {
+            return Err(Qos1TrackerError::PacketIdentifierInFlight(pkid));
+        }
+
+        if !self.has_capacity() {
+            return Err(Qos1TrackerError::WindowFull);
+        }
+
+        self.acknowledged.remove(&pkid);
+        self.pending.push(Pending {
+            packet: packet,
+            pkid: pkid,
+            sent_at: now,
+            sequence: next_send_sequence(),
+        });
+        Ok(())
+    }
+
+    /// Removes the message acknowledged by a PUBACK with `pkid`
+    pub fn acknowledge(&mut self, pkid: u16) -> Result<PublishPacket, Qos1TrackerError> {
+        let pkid = match PacketIdentifier::new(pkid) {
+            Some(pkid) => pkid,
+            None => return Err(Qos1TrackerError::UnknownPacketIdentifier(pkid)),
+        };
+
+        match self.pending.iter().position(|p| p.pkid == pkid) {
+            Some(idx) => {
+                self.acknowledged.insert(pkid);
+                Ok(self.pending.remove(idx).packet)
+            },
+            None if self.acknowledged.contains(&pkid) => Err(Qos1TrackerError::DuplicateAcknowledgement(pkid)),
+            None => Err(Qos1TrackerError::UnknownPacketIdentifier(pkid.get())),
+        }
+    }
+
+    /// Forgets a message that will not be acknowledged, e.g. because its publish failed
+    pub fn remove(&mut self, pkid: PacketIdentifier) -> Option<PublishPacket> {
+        match self.pending.iter().position(|p| p.pkid == pkid) {
+            Some(idx) => Some(self.pending.remove(idx).packet),
+            None => None,
+        }
+    }
+
+    /// Position of the message in the order all QoS 1 and 2 messages were sent
+    pub fn send_sequence(&self, pkid: PacketIdentifier) -> Option<u64> {
+        self.pending.iter().find(|p| p.pkid == pkid).map(|p| p.sequence)
+    }
+
+    pub fn pending<'a>(&'a self) -> impl Iterator<Item = &'a PublishPacket> + 'a {
+        self.pending.iter().map(|p| &p.packet)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Messages sent at least `timeout` ago, with the DUP flag set
+    ///
+    /// They are considered sent again at `now`.
+    pub fn due_for_retransmit(&mut self, now: Instant, timeout: Duration) -> Vec<PublishPacket> {
+        let mut packets = Vec::new();
+        for pending in self.pending.iter_mut() {
+            if pending.sent_at + timeout <= now {
+                pending.sent_at = now;
+                packets.push(pending.packet.for_retransmission());
+            }
+        }
+        packets
+    }
+
+    /// All pending messages with the DUP flag set, to be sent again after reconnecting
+    /// with a persistent session (MQTT-4.4.0-1)
+    pub fn retransmit_all(&mut self, now: Instant) -> Vec<PublishPacket> {
+        self.due_for_retransmit(now, Duration::from_secs(0))
+    }
+}
+
+impl Default for Qos1Tracker {
+    fn default() -> Qos1Tracker {
+        Qos1Tracker::new()
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Qos1TrackerError {
+    NotQoS1,
+    PacketIdentifierInFlight(PacketIdentifier),
+    WindowFull,
+    DuplicateAcknowledgement(PacketIdentifier),
+    UnknownPacketIdentifier(u16),
+}
+
+impl fmt::Display for Qos1TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Qos1TrackerError::NotQoS1 => write!(f, "Not a QoS 1 message"),
+            &Qos1TrackerError::PacketIdentifierInFlight(pkid) => write!(f, "Packet identifier {} is in flight", pkid),
+            &Qos1TrackerError::WindowFull => write!(f, "Too many messages in flight"),
+            &Qos1TrackerError::DuplicateAcknowledgement(pkid) =>
+                write!(f, "Packet identifier {} is already acknowledged", pkid),
+            &Qos1TrackerError::UnknownPacketIdentifier(pkid) => write!(f, "Unknown packet identifier {}", pkid),
+        }
+    }
+}
+
+impl Error for Qos1TrackerError {
+    fn description(&self) -> &str {
+        match self {
+            &Qos1TrackerError::NotQoS1 => "Not a QoS 1 message",
+            &Qos1TrackerError::PacketIdentifierInFlight(..) => "Packet identifier is in flight",
+            &Qos1TrackerError::WindowFull => "Too many messages in flight",
+            &Qos1TrackerError::DuplicateAcknowledgement(..) => "Packet identifier is already acknowledged",
+            &Qos1TrackerError::UnknownPacketIdentifier(..) => "Unknown packet identifier",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use control::variable_header::PacketIdentifier;
+    use packet::{PublishPacket, QoSWithPacketIdentifier};
+
+    fn publish(pkid: u16) -> PublishPacket {
+        PublishPacket::new("a/b".to_owned(),
+                           QoSWithPacketIdentifier::Level1(PacketIdentifier::new(pkid).unwrap()),
+                           b"hi".to_vec())
+    }
+
+    #[test]
+    fn test_qos1_tracker_acknowledge() {
+        let now = Instant::now();
+        let mut tracker = Qos1Tracker::new();
+        tracker.register(publish(1), now).unwrap();
+        tracker.register(publish(2), now).unwrap();
+        assert_eq!(Err(Qos1TrackerError::PacketIdentifierInFlight(PacketIdentifier::new(1).unwrap())),
+                   tracker.register(publish(1), now));
+
+        assert_eq!(Ok(publish(2)), tracker.acknowledge(2));
+        assert_eq!(vec![&publish(1)], tracker.pending().collect::<Vec<_>>());
+
+        assert_eq!(Err(Qos1TrackerError::DuplicateAcknowledgement(PacketIdentifier::new(2).unwrap())),
+                   tracker.acknowledge(2));
+        assert_eq!(Err(Qos1TrackerError::UnknownPacketIdentifier(3)), tracker.acknowledge(3));
+        assert_eq!(Err(Qos1TrackerError::UnknownPacketIdentifier(0)), tracker.acknowledge(0));
+
+        // A reused identifier is no longer a duplicate
+        tracker.register(publish(2), now).unwrap();
+        assert_eq!(Ok(publish(2)), tracker.acknowledge(2));
+
+        // Nor is one whose message was given up
+        assert_eq!(Some(publish(1)), tracker.remove(PacketIdentifier::new(1).unwrap()));
+        assert_eq!(None, tracker.remove(PacketIdentifier::new(1).unwrap()));
+        tracker.register(publish(1), now).unwrap();
+
+        let qos0 = PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level0, Vec::new());
+        assert_eq!(Err(Qos1TrackerError::NotQoS1), tracker.register(qos0, now));
+    }
+
+    #[test]
+    fn test_qos1_tracker_retransmit() {
+        let start = Instant::now();
+        let timeout = Duration::from_secs(10);
+        let mut tracker = Qos1Tracker::new();
+        tracker.register(publish(1), start).unwrap();
+        tracker.register(publish(2), start + Duration::from_secs(5)).unwrap();
+
+        assert!(tracker.due_for_retransmit(start + Duration::from_secs(9), timeout).is_empty());
+
+        let due = tracker.due_for_retransmit(start + Duration::from_secs(10), timeout);
+        assert_eq!(vec![publish(1).for_retransmission()], due);
+        assert!(due[0].dup());
+        assert!(!tracker.pending().next().unwrap().dup());
+
+        let due = tracker.due_for_retransmit(start + Duration::from_secs(15), timeout);
+        assert_eq!(vec![publish(2).for_retransmission()], due);
+
+        let all = tracker.retransmit_all(start + Duration::from_secs(16));
+        assert_eq!(vec![publish(1).for_retransmission(), publish(2).for_retransmission()], all);
+    }
+
+    #[test]
+    fn test_qos1_tracker_window() {
+        let now = Instant::now();
+        let mut tracker = Qos1Tracker::with_max_in_flight(1);
+        tracker.register(publish(1), now).unwrap();
+        assert!(!tracker.has_capacity());
+        assert_eq!(Err(Qos1TrackerError::WindowFull), tracker.register(publish(2), now));
+
+        tracker.acknowledge(1).unwrap();
+        tracker.register(publish(2), now).unwrap();
+        assert_eq!(1, tracker.len());
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use control::variable_header::{PacketIdentifier, ProtocolVersion};
+use packet::{DecodeOptions, PublishPacket, PubrecPacket, PubrelPacket, PubcompPacket, QoSWithPacketIdentifier};
+use packet::{VariablePacket, VariablePacketError, ReadExpectedError};
+use session::next_send_sequence;
+use {Encodable, Decodable};
+
+/// Outcome of receiving a QoS 2 PUBLISH, it has to be answered with a PUBREC either way
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum IncomingPublish {
+    /// First time this message is seen, pass it to the application
+    Deliver,
+    /// The message has been delivered already and its PUBREL is still outstanding
+    Duplicate,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+enum Outgoing {
+    /// Sent, waiting for PUBREC
+    Published(PublishPacket),
+    /// PUBREL sent, waiting for PUBCOMP
+    Released(PacketIdentifier),
+}
+
+impl Outgoing {
+    fn packet_identifier(&self) -> PacketIdentifier {
+        match self {
+            &Outgoing::Published(ref packet) => match packet.qos() {
+                QoSWithPacketIdentifier::Level2(pkid) => pkid,
+                _ => unreachable!(),
+            },
+            &Outgoing::Released(pkid) => pkid,
+        }
+    }
+}
+
+/// State of the QoS 2 flows of a session in both directions
+///
+/// Received messages are delivered once their PUBLISH is first seen and remembered until
+/// the PUBREL, so retransmitted PUBLISH packets are not delivered again (method B of
+/// section 4.3.3 of the spec).
+///
+/// The state is `Encodable` and `Decodable` to be persisted with the session. It is
+/// stored as the packets that would be retransmitted: a PUBLISH per message waiting for
+/// PUBREC, a PUBREL per message waiting for PUBCOMP and a PUBREC per received message
+/// waiting for PUBREL. The protocol version is not stored, `decode_with` takes the one of the
+/// session and `decode` reads MQTT 3.1.1.
+///
+/// Send sequences are not compared, a restored state gets new ones in the stored order.
+#[derive(Debug, Clone)]
+pub struct Qos2StateMachine {
+    // With the send sequence of the PUBLISH
+    outgoing: Vec<(Outgoing, u64)>,
+    incoming: BTreeSet<PacketIdentifier>,
+}
+
+impl PartialEq for Qos2StateMachine {
+    fn eq(&self, other: &Qos2StateMachine) -> bool {
+        self.incoming == other.incoming
+            && self.outgoing.len() == other.outgoing.len()
+            && self.outgoing.iter().zip(other.outgoing.iter()).all(|(a, b)| a.0 == b.0)
+    }
+}
+
+impl Eq for Qos2StateMachine {}
+
+impl Qos2StateMachine {
+    pub fn new() -> Qos2StateMachine {
+        Qos2StateMachine {
+            outgoing: Vec::new(),
+            incoming: BTreeSet::new(),
+        }
+    }
+
+    /// Stores a QoS 2 message that has been sent, until its PUBREC
+    pub fn on_outgoing_publish(&mut self, packet: PublishPacket) -> Result<(), Qos2Error> {
+        let pkid = match packet.qos() {
+            QoSWithPacketIdentifier::Level2(pkid) => pkid,
+            _ => return Err(Qos2Error::NotQoS2),
+        };
+
+        if self.outgoing_position(pkid).is_some() {
+            return Err(Qos2Error::PacketIdentifierInFlight(pkid));
+        }
+
+        self.outgoing.push((Outgoing::Published(packet), next_send_sequence()));
+        Ok(())
+    }
+
+    /// Discards the message and returns the PUBREL to send (MQTT-4.3.3-1)
+    ///
+    /// A PUBREC for a message already released returns the same PUBREL again.
+    pub fn on_pubrec(&mut self, pkid: u16) -> Result<PubrelPacket, Qos2Error> {
+        let pkid = try!(PacketIdentifier::new(pkid).ok_or(Qos2Error::UnknownPacketIdentifier(pkid)));
+        let idx = try!(self.outgoing_position(pkid).ok_or(Qos2Error::UnknownPacketIdentifier(pkid.get())));
+
+        self.outgoing[idx].0 = Outgoing::Released(pkid);
+        Ok(PubrelPacket::new(pkid.get()))
+    }
+
+    /// Ends an outgoing flow
+    pub fn on_pubcomp(&mut self, pkid: u16) -> Result<(), Qos2Error> {
+        let pkid = try!(PacketIdentifier::new(pkid).ok_or(Qos2Error::UnknownPacketIdentifier(pkid)));
+        let idx = try!(self.outgoing_position(pkid).ok_or(Qos2Error::UnknownPacketIdentifier(pkid.get())));
+
+        match self.outgoing[idx].0 {
+            Outgoing::Released(..) => {
+                self.outgoing.remove(idx);
+                Ok(())
+            },
+            Outgoing::Published(..) => Err(Qos2Error::PubcompBeforePubrec(pkid)),
+        }
+    }
+
+    /// Records a received QoS 2 message, to be answered with a PUBREC in any case
+    pub fn on_incoming_publish(&mut self, packet: &PublishPacket) -> Result<IncomingPublish, Qos2Error> {
+        let pkid = match packet.qos() {
+            QoSWithPacketIdentifier::Level2(pkid) => pkid,
+            _ => return Err(Qos2Error::NotQoS2),
+        };
+
+        if self.incoming.insert(pkid) {
+            Ok(IncomingPublish::Deliver)
+        } else {
+            Ok(IncomingPublish::Duplicate)
+        }
+    }
+
+    /// Forgets a received message and returns the PUBCOMP to send
+    ///
+    /// A PUBCOMP is due even if the identifier is unknown, e.g. for a retransmitted PUBREL.
+    pub fn on_pubrel(&mut self, pkid: u16) -> PubcompPacket {
+        if let Some(pkid) = PacketIdentifier::new(pkid) {
+            self.incoming.remove(&pkid);
+        }
+        PubcompPacket::new(pkid)
+    }
+
+    /// Forgets an outgoing flow that will not complete, e.g. because its publish failed
+    pub fn remove_outgoing(&mut self, pkid: PacketIdentifier) -> bool {
+        match self.outgoing_position(pkid) {
+            Some(idx) => {
+                self.outgoing.remove(idx);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Position of the outgoing message in the order all QoS 1 and 2 messages were sent
+    pub fn send_sequence(&self, pkid: PacketIdentifier) -> Option<u64> {
+        self.outgoing_position(pkid).map(|idx| self.outgoing[idx].1)
+    }
+
+    pub fn is_awaiting_pubrel(&self, pkid: PacketIdentifier) -> bool {
+        self.incoming.contains(&pkid)
+    }
+
+    pub fn outgoing_len(&self) -> usize {
+        self.outgoing.len()
+    }
+
+    pub fn incoming_len(&self) -> usize {
+        self.incoming.len()
+    }
+
+    /// Packets to send again after reconnecting, in the original order (MQTT-4.4.0-1)
+    pub fn retransmit(&self) -> Vec<VariablePacket> {
+        self.outgoing.iter()
+            .map(|&(ref outgoing, _)| match outgoing {
+                &Outgoing::Published(ref packet) => VariablePacket::new(packet.for_retransmission()),
+                &Outgoing::Released(pkid) => VariablePacket::new(PubrelPacket::new(pkid.get())),
+            })
+            .collect()
+    }
+
+    fn outgoing_position(&self, pkid: PacketIdentifier) -> Option<usize> {
+        self.outgoing.iter().position(|&(ref outgoing, _)| outgoing.packet_identifier() == pkid)
+    }
+
+    /// The packets the state is stored as, the outgoing ones in the order they were sent
+    pub fn stored_packets(&self) -> Vec<VariablePacket> {
+        let mut packets: Vec<VariablePacket> = self.outgoing.iter()
+            .map(|&(ref outgoing, _)| match outgoing {
+                &Outgoing::Published(ref packet) => VariablePacket::new(packet.clone()),
+                &Outgoing::Released(pkid) => VariablePacket::new(PubrelPacket::new(pkid.get())),
+            })
+            .collect();
+        packets.extend(self.incoming.iter().map(|pkid| VariablePacket::new(PubrecPacket::new(pkid.get()))));
+        packets
+    }
+}
+
+impl Default for Qos2StateMachine {
+    fn default() -> Qos2StateMachine {
+        Qos2StateMachine::new()
+    }
+}
+
+impl<'a> Encodable<'a> for Qos2StateMachine {
+    type Err = VariablePacketError<'a>;
+
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), VariablePacketError<'a>> {
+        let packets = self.stored_packets();
+        try!(writer.write_u32::<BigEndian>(packets.len() as u32)
+                 .map_err(|err| VariablePacketError::IoError(From::from(err))));
+        for packet in packets.iter() {
+            try!(packet.encode(writer));
+        }
+        Ok(())
+    }
+
+    fn encoded_length(&self) -> u32 {
+        self.stored_packets().iter().fold(4, |len, packet| len + packet.encoded_length())
+    }
+}
+
+impl<'a> Decodable<'a> for Qos2StateMachine {
+    type Err = ReadExpectedError<'a>;
+    type Cond = ProtocolVersion;
+
+    fn decode_with<R: Read>(reader: &mut R, version: Option<ProtocolVersion>)
+            -> Result<Qos2StateMachine, ReadExpectedError<'a>> {
+        let options = DecodeOptions::new().protocol_version(version.unwrap_or(ProtocolVersion::V3_1_1));
+        let count = try!(reader.read_u32::<BigEndian>()
+                             .map_err(|err| VariablePacketError::IoError(From::from(err))));
+
+        let mut state = Qos2StateMachine::new();
+        for _ in 0..count {
+            let packet = try!(VariablePacket::decode_with_options(reader, &options));
+            try!(state.restore_packet(packet).map_err(ReadExpectedError::UnexpectedPacket));
+        }
+
+        Ok(state)
+    }
+}
+
+impl Qos2StateMachine {
+    /// Adds a packet of the stored form, see `stored_packets`, outgoing flows are considered
+    /// sent now
+    ///
+    /// Gives back packets that are not part of the stored form or repeat an identifier.
+    pub fn restore_packet(&mut self, packet: VariablePacket) -> Result<(), VariablePacket> {
+        let pkid = packet.packet_identifier().and_then(PacketIdentifier::new);
+        let outgoing = match (&packet, pkid) {
+            (&VariablePacket::PublishPacket(ref publish), Some(..)) if is_qos2(publish) =>
+                Some(Outgoing::Published(publish.clone())),
+            (&VariablePacket::PubrelPacket(..), Some(pkid)) => Some(Outgoing::Released(pkid)),
+            (&VariablePacket::PubrecPacket(..), Some(pkid)) if self.incoming.insert(pkid) => None,
+            _ => return Err(packet),
+        };
+
+        if let Some(outgoing) = outgoing {
+            if self.outgoing_position(outgoing.packet_identifier()).is_some() {
+                return Err(packet);
+            }
+            self.outgoing.push((outgoing, next_send_sequence()));
+        }
+        Ok(())
+    }
+}
+
+fn is_qos2(packet: &PublishPacket) -> bool {
+    match packet.qos() {
+        QoSWithPacketIdentifier::Level2(..) => true,
+        _ => false,
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Qos2Error {
+    NotQoS2,
+    PacketIdentifierInFlight(PacketIdentifier),
+    UnknownPacketIdentifier(u16),
+    PubcompBeforePubrec(PacketIdentifier),
+}
+
+impl fmt::Display for Qos2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Qos2Error::NotQoS2 => write!(f, "Not a QoS 2 message"),
+            &Qos2Error::PacketIdentifierInFlight(pkid) => write!(f, "Packet identifier {} is in flight", pkid),
+            &Qos2Error::UnknownPacketIdentifier(pkid) => write!(f, "Unknown packet identifier {}", pkid),
+            &Qos2Error::PubcompBeforePubrec(pkid) => write!(f, "PUBCOMP before PUBREC for packet identifier {}", pkid),
+        }
+    }
+}
+
+impl Error for Qos2Error {
+    fn description(&self) -> &str {
+        match self {
+            &Qos2Error::NotQoS2 => "Not a QoS 2 message",
+            &Qos2Error::PacketIdentifierInFlight(..) => "Packet identifier is in flight",
+            &Qos2Error::UnknownPacketIdentifier(..) => "Unknown packet identifier",
+            &Qos2Error::PubcompBeforePubrec(..) => "PUBCOMP before PUBREC",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use control::variable_header::PacketIdentifier;
+    use packet::*;
+    use {Encodable, Decodable};
+
+    fn pkid(id: u16) -> PacketIdentifier {
+        PacketIdentifier::new(id).unwrap()
+    }
+
+    fn publish(id: u16) -> PublishPacket {
+        PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level2(pkid(id)), b"hi".to_vec())
+    }
+
+    #[test]
+    fn test_qos2_outgoing_flow() {
+        let mut state = Qos2StateMachine::new();
+        state.on_outgoing_publish(publish(1)).unwrap();
+        assert_eq!(Err(Qos2Error::PacketIdentifierInFlight(pkid(1))), state.on_outgoing_publish(publish(1)));
+        assert_eq!(vec![VariablePacket::new(publish(1).for_retransmission())], state.retransmit());
+
+        // PUBCOMP must not overtake the PUBREC
+        assert_eq!(Err(Qos2Error::PubcompBeforePubrec(pkid(1))), state.on_pubcomp(1));
+
+        assert_eq!(Ok(PubrelPacket::new(1)), state.on_pubrec(1));
+        assert_eq!(vec![VariablePacket::new(PubrelPacket::new(1))], state.retransmit());
+
+        // Retransmitted PUBREC
+        assert_eq!(Ok(PubrelPacket::new(1)), state.on_pubrec(1));
+        assert_eq!(1, state.outgoing_len());
+
+        // The identifier stays in use until the PUBCOMP
+        assert_eq!(Err(Qos2Error::PacketIdentifierInFlight(pkid(1))), state.on_outgoing_publish(publish(1)));
+
+        assert_eq!(Ok(()), state.on_pubcomp(1));
+        assert_eq!(0, state.outgoing_len());
+
+        // A flow that is given up frees its identifier
+        state.on_outgoing_publish(publish(2)).unwrap();
+        assert!(state.remove_outgoing(pkid(2)));
+        assert!(!state.remove_outgoing(pkid(2)));
+        state.on_outgoing_publish(publish(2)).unwrap();
+        assert_eq!(Err(Qos2Error::UnknownPacketIdentifier(1)), state.on_pubcomp(1));
+        assert_eq!(Err(Qos2Error::UnknownPacketIdentifier(1)), state.on_pubrec(1));
+        state.on_outgoing_publish(publish(1)).unwrap();
+    }
+
+    #[test]
+    fn test_qos2_unknown_acks() {
+        let mut state = Qos2StateMachine::new();
+        assert_eq!(Err(Qos2Error::UnknownPacketIdentifier(7)), state.on_pubrec(7));
+        assert_eq!(Err(Qos2Error::UnknownPacketIdentifier(7)), state.on_pubcomp(7));
+        assert_eq!(Err(Qos2Error::UnknownPacketIdentifier(0)), state.on_pubrec(0));
+        assert_eq!(Err(Qos2Error::UnknownPacketIdentifier(0)), state.on_pubcomp(0));
+        assert_eq!(PubcompPacket::new(7), state.on_pubrel(7));
+        assert_eq!(PubcompPacket::new(0), state.on_pubrel(0));
+
+        let qos1 = PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level1(pkid(1)), Vec::new());
+        assert_eq!(Err(Qos2Error::NotQoS2), state.on_outgoing_publish(qos1.clone()));
+        assert_eq!(Err(Qos2Error::NotQoS2), state.on_incoming_publish(&qos1));
+        assert_eq!(Qos2StateMachine::new(), state);
+    }
+
+    #[test]
+    fn test_qos2_incoming_flow() {
+        let mut state = Qos2StateMachine::new();
+        assert_eq!(Ok(IncomingPublish::Deliver), state.on_incoming_publish(&publish(1)));
+        assert!(state.is_awaiting_pubrel(pkid(1)));
+
+        // Retransmitted PUBLISH before the PUBREL
+        assert_eq!(Ok(IncomingPublish::Duplicate), state.on_incoming_publish(&publish(1).for_retransmission()));
+        assert_eq!(Ok(IncomingPublish::Deliver), state.on_incoming_publish(&publish(2)));
+        assert_eq!(2, state.incoming_len());
+
+        assert_eq!(PubcompPacket::new(1), state.on_pubrel(1));
+        assert!(!state.is_awaiting_pubrel(pkid(1)));
+
+        // Retransmitted PUBREL
+        assert_eq!(PubcompPacket::new(1), state.on_pubrel(1));
+        assert_eq!(1, state.incoming_len());
+
+        // The identifier can be reused by a new message after the PUBREL
+        assert_eq!(Ok(IncomingPublish::Deliver), state.on_incoming_publish(&publish(1)));
+    }
+
+    #[test]
+    fn test_qos2_persistence() {
+        let mut state = Qos2StateMachine::new();
+        state.on_outgoing_publish(publish(3)).unwrap();
+        state.on_outgoing_publish(publish(1)).unwrap();
+        state.on_pubrec(3).unwrap();
+        state.on_incoming_publish(&publish(1)).unwrap();
+        state.on_incoming_publish(&publish(9)).unwrap();
+
+        let mut buf = Vec::new();
+        state.encode(&mut buf).unwrap();
+        assert_eq!(state.encoded_length() as usize, buf.len());
+
+        let mut decoded = Qos2StateMachine::decode(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(state, decoded);
+        assert_eq!(state.retransmit(), decoded.retransmit());
+
+        assert_eq!(Ok(IncomingPublish::Duplicate), decoded.on_incoming_publish(&publish(9)));
+        assert_eq!(Ok(()), decoded.on_pubcomp(3));
+        assert_eq!(Ok(PubrelPacket::new(1)), decoded.on_pubrec(1));
+
+        // MQTT 5 PUBLISH packets keep their properties
+        let mut publish = PublishPacket::with_version("a/b".to_owned(), QoSWithPacketIdentifier::Level2(pkid(4)),
+                                                      b"payload".to_vec(), ProtocolVersion::V5);
+        publish.set_content_type("text/plain");
+        let mut state = Qos2StateMachine::new();
+        state.on_outgoing_publish(publish.clone()).unwrap();
+        let mut buf = Vec::new();
+        state.encode(&mut buf).unwrap();
+        let decoded = Qos2StateMachine::decode_with(&mut Cursor::new(&buf[..]), Some(ProtocolVersion::V5)).unwrap();
+        assert_eq!(state, decoded);
+        assert_eq!(vec![VariablePacket::new(publish.for_retransmission())], decoded.retransmit());
+
+        let encoded_data = b"\x00\x00\x00\x01\x40\x02\x00\x01";
+        match Qos2StateMachine::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(ReadExpectedError::UnexpectedPacket(VariablePacket::PubackPacket(..))) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let encoded_data = b"\x00\x00\x00\x02\x62\x02\x00\x01\x62\x02\x00\x01";
+        match Qos2StateMachine::decode(&mut Cursor::new(&encoded_data[..])) {
+            Err(ReadExpectedError::UnexpectedPacket(VariablePacket::PubrelPacket(..))) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+}
@@ -0,0 +1,555 @@
+use std::convert::{From, TryFrom};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Cursor};
+use std::time::Instant;
+
+use byteorder::{self, BigEndian, ReadBytesExt, WriteBytesExt};
+
+use control::ControlType;
+use control::variable_header::{PacketIdentifier, ProtocolVersion};
+use encodable::StringEncodeError;
+use packet::{self, ConnackPacket, DecodeOptions, PublishPacket, QoSWithPacketIdentifier};
+use packet::{VariablePacket, VariablePacketError, ReadExpectedError};
+use session::{PacketIdAllocator, Qos1Tracker, Qos1TrackerError, Qos2StateMachine};
+use {Encodable, Decodable, QualityOfService, TopicFilter};
+
+const FORMAT_VERSION: u8 = 3;
+
+/// Client side state of a persistent session (clean session set to 0)
+///
+/// `to_bytes` stores it in a versioned binary format:
+///
+/// * version, 1 byte
+/// * protocol level of the session, 1 byte
+/// * next packet identifier, 2 bytes
+/// * number of subscriptions, 4 bytes, followed by each topic filter and its granted QoS
+/// * number of stored packets, 4 bytes, followed by the QoS 1 PUBLISH packets and the
+///   outgoing part of the QoS 2 state (see `Qos2StateMachine`) in the order they were sent,
+///   then the incoming part of the QoS 2 state, PUBLISH packets laid out for the protocol
+///   level
+///
+/// Version 2 without the protocol level and version 1, which also stored the QoS 1 messages and
+/// the QoS 2 state one after the other, are still read as MQTT 3.1.1 sessions. The send times of QoS 1 messages are not stored, they are due for
+/// retransmission once the session is resumed anyway.
+#[derive(Debug, Clone)]
+pub struct SessionState {
+    version: ProtocolVersion,
+    subscriptions: Vec<(TopicFilter, QualityOfService)>,
+    packet_ids: PacketIdAllocator,
+    qos1: Qos1Tracker,
+    qos2: Qos2StateMachine,
+}
+
+impl SessionState {
+    pub fn new() -> SessionState {
+        SessionState::with_version(ProtocolVersion::V3_1_1)
+    }
+
+    /// State of a session connected with `version`
+    pub fn with_version(version: ProtocolVersion) -> SessionState {
+        SessionState {
+            version: version,
+            subscriptions: Vec::new(),
+            packet_ids: PacketIdAllocator::new(),
+            qos1: Qos1Tracker::new(),
+            qos2: Qos2StateMachine::new(),
+        }
+    }
+
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// Records a granted subscription, replacing the one with the same filter
+    pub fn add_subscription(&mut self, filter: TopicFilter, qos: QualityOfService) {
+        match self.subscriptions.iter_mut().find(|&&mut (ref f, _)| *f == filter) {
+            Some(sub) => sub.1 = qos,
+            None => self.subscriptions.push((filter, qos)),
+        }
+    }
+
+    pub fn remove_subscription(&mut self, filter: &TopicFilter) -> bool {
+        let len = self.subscriptions.len();
+        self.subscriptions.retain(|&(ref f, _)| f != filter);
+        self.subscriptions.len() != len
+    }
+
+    pub fn subscriptions(&self) -> &[(TopicFilter, QualityOfService)] {
+        &self.subscriptions[..]
+    }
+
+    pub fn packet_ids(&self) -> &PacketIdAllocator {
+        &self.packet_ids
+    }
+
+    pub fn packet_ids_mut(&mut self) -> &mut PacketIdAllocator {
+        &mut self.packet_ids
+    }
+
+    pub fn qos1(&self) -> &Qos1Tracker {
+        &self.qos1
+    }
+
+    pub fn qos1_mut(&mut self) -> &mut Qos1Tracker {
+        &mut self.qos1
+    }
+
+    pub fn qos2(&self) -> &Qos2StateMachine {
+        &self.qos2
+    }
+
+    pub fn qos2_mut(&mut self) -> &mut Qos2StateMachine {
+        &mut self.qos2
+    }
+
+    /// Discards everything if the server did not resume the session
+    ///
+    /// A rejected connection leaves the state untouched.
+    pub fn on_connack(&mut self, connack: &ConnackPacket) {
+        if connack.connect_return_code().is_accepted() && !connack.session_present() {
+            *self = SessionState::with_version(self.version);
+        }
+    }
+
+    /// Packets to send again after the session has been resumed (MQTT-4.4.0-1)
+    ///
+    /// QoS 1 and 2 PUBLISH packets have the DUP flag set, PUBREL packets take the place of
+    /// the PUBLISH they release. The packets are in the order their PUBLISH was originally
+    /// sent in (MQTT-4.6.0-1).
+    pub fn resume_packets(&self) -> Vec<VariablePacket> {
+        let mut packets: Vec<VariablePacket> = self.qos1.pending()
+            .map(|packet| VariablePacket::new(packet.for_retransmission()))
+            .collect();
+        packets.extend(self.qos2.retransmit());
+
+        packets.sort_by_key(|packet| self.send_sequence(packet));
+        packets
+    }
+
+    fn send_sequence(&self, packet: &VariablePacket) -> Option<u64> {
+        let pkid = packet.packet_identifier().and_then(PacketIdentifier::new);
+        match packet {
+            &VariablePacket::PublishPacket(ref publish) if publish.qos().qos() == QualityOfService::Level1 =>
+                pkid.and_then(|pkid| self.qos1.send_sequence(pkid)),
+            _ => pkid.and_then(|pkid| self.qos2.send_sequence(pkid)),
+        }
+    }
+
+    /// Same as `resume_packets`, the QoS 1 messages are considered sent again at `now`
+    pub fn retransmit(&mut self, now: Instant) -> Vec<VariablePacket> {
+        self.qos1.retransmit_all(now);
+        self.resume_packets()
+    }
+
+    pub fn to_bytes<'a>(&self) -> Result<Vec<u8>, SessionStateError<'a>> {
+        let mut buf = Vec::new();
+
+        try!(buf.write_u8(FORMAT_VERSION));
+        try!(buf.write_u8(self.version.level()));
+        try!(buf.write_u16::<BigEndian>(self.packet_ids.next_packet_identifier().get()));
+
+        try!(buf.write_u32::<BigEndian>(self.subscriptions.len() as u32));
+        for &(ref filter, qos) in self.subscriptions.iter() {
+            try!(filter.encode(&mut buf));
+            try!(buf.write_u8(qos.to_u8()));
+        }
+
+        let (mut packets, incoming): (Vec<VariablePacket>, Vec<VariablePacket>) = self.qos2.stored_packets()
+            .into_iter()
+            .partition(|packet| packet.control_type() != ControlType::PublishReceived);
+        packets.extend(self.qos1.pending().map(|packet| VariablePacket::new(packet.clone())));
+        packets.sort_by_key(|packet| self.send_sequence(packet));
+        packets.extend(incoming);
+
+        try!(buf.write_u32::<BigEndian>(packets.len() as u32));
+        for packet in packets.iter() {
+            match packet {
+                &VariablePacket::PublishPacket(ref publish) if publish.is_mqtt5() != (self.version == ProtocolVersion::V5) => {
+                    let mut publish = publish.clone();
+                    publish.set_protocol_version(self.version);
+                    try!(VariablePacket::new(publish).encode(&mut buf));
+                },
+                packet => try!(packet.encode(&mut buf)),
+            }
+        }
+
+        Ok(buf)
+    }
+
+    /// Restores a session stored by `to_bytes`
+    pub fn from_bytes<'a>(bytes: &[u8]) -> Result<SessionState, SessionStateError<'a>> {
+        let mut reader = Cursor::new(bytes);
+        let now = Instant::now();
+        let mut state = SessionState::new();
+
+        let version = try!(reader.read_u8());
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(SessionStateError::UnsupportedVersion(version));
+        }
+        if version >= 3 {
+            let level = try!(reader.read_u8());
+            state.version = try!(ProtocolVersion::from_level(level)
+                                     .ok_or(SessionStateError::UnsupportedProtocolLevel(level)));
+        }
+        let options = DecodeOptions::new().protocol_version(state.version);
+
+        let next = try!(reader.read_u16::<BigEndian>());
+        if let Some(next) = PacketIdentifier::new(next) {
+            state.packet_ids.set_next_packet_identifier(next);
+        }
+
+        let count = try!(reader.read_u32::<BigEndian>());
+        for _ in 0..count {
+            let filter = try!(TopicFilter::decode(&mut reader));
+            let qos = try!(reader.read_u8());
+            let qos = try!(QualityOfService::try_from(qos).map_err(|_| SessionStateError::InvalidQoS(qos)));
+            state.subscriptions.push((filter, qos));
+        }
+
+        if version == 1 {
+            let count = try!(reader.read_u32::<BigEndian>());
+            for _ in 0..count {
+                let packet: PublishPacket = try!(packet::read_expected(&mut reader));
+                try!(state.qos1.register(packet, now));
+            }
+            state.qos2 = try!(Qos2StateMachine::decode(&mut reader));
+        } else {
+            let count = try!(reader.read_u32::<BigEndian>());
+            for _ in 0..count {
+                match try!(VariablePacket::decode_with_options(&mut reader, &options)) {
+                    VariablePacket::PublishPacket(ref packet) if packet.qos().qos() == QualityOfService::Level1 =>
+                        try!(state.qos1.register(packet.clone(), now)),
+                    packet => try!(state.qos2.restore_packet(packet).map_err(SessionStateError::UnexpectedPacket)),
+                }
+            }
+        }
+
+        for packet in state.qos1.pending() {
+            if let QoSWithPacketIdentifier::Level1(pkid) = packet.qos() {
+                state.packet_ids.reserve(pkid);
+            }
+        }
+        for packet in state.qos2.retransmit() {
+            if let Some(pkid) = packet.packet_identifier().and_then(PacketIdentifier::new) {
+                state.packet_ids.reserve(pkid);
+            }
+        }
+
+        Ok(state)
+    }
+}
+
+impl Default for SessionState {
+    fn default() -> SessionState {
+        SessionState::new()
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionStateError<'a> {
+    UnsupportedVersion(u8),
+    UnsupportedProtocolLevel(u8),
+    InvalidQoS(u8),
+    IoError(io::Error),
+    StringEncodeError(StringEncodeError),
+    VariablePacketError(VariablePacketError<'a>),
+    UnexpectedPacket(VariablePacket),
+    Qos1TrackerError(Qos1TrackerError),
+}
+
+impl<'a> From<io::Error> for SessionStateError<'a> {
+    fn from(err: io::Error) -> SessionStateError<'a> {
+        SessionStateError::IoError(err)
+    }
+}
+
+impl<'a> From<byteorder::Error> for SessionStateError<'a> {
+    fn from(err: byteorder::Error) -> SessionStateError<'a> {
+        SessionStateError::IoError(From::from(err))
+    }
+}
+
+impl<'a> From<StringEncodeError> for SessionStateError<'a> {
+    fn from(err: StringEncodeError) -> SessionStateError<'a> {
+        SessionStateError::StringEncodeError(err)
+    }
+}
+
+impl<'a> From<VariablePacketError<'a>> for SessionStateError<'a> {
+    fn from(err: VariablePacketError<'a>) -> SessionStateError<'a> {
+        SessionStateError::VariablePacketError(err)
+    }
+}
+
+impl<'a> From<ReadExpectedError<'a>> for SessionStateError<'a> {
+    fn from(err: ReadExpectedError<'a>) -> SessionStateError<'a> {
+        match err {
+            ReadExpectedError::VariablePacketError(err) => SessionStateError::VariablePacketError(err),
+            ReadExpectedError::UnexpectedPacket(packet) => SessionStateError::UnexpectedPacket(packet),
+        }
+    }
+}
+
+impl<'a> From<Qos1TrackerError> for SessionStateError<'a> {
+    fn from(err: Qos1TrackerError) -> SessionStateError<'a> {
+        SessionStateError::Qos1TrackerError(err)
+    }
+}
+
+impl<'a> fmt::Display for SessionStateError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &SessionStateError::UnsupportedVersion(version) => write!(f, "Unsupported session format version {}", version),
+            &SessionStateError::UnsupportedProtocolLevel(level) => write!(f, "Unsupported protocol level {}", level),
+            &SessionStateError::InvalidQoS(qos) => write!(f, "Invalid QoS ({})", qos),
+            &SessionStateError::IoError(ref err) => err.fmt(f),
+            &SessionStateError::StringEncodeError(ref err) => err.fmt(f),
+            &SessionStateError::VariablePacketError(ref err) => err.fmt(f),
+            &SessionStateError::UnexpectedPacket(ref packet) => write!(f, "Unexpected packet {}", packet),
+            &SessionStateError::Qos1TrackerError(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl<'a> Error for SessionStateError<'a> {
+    fn description(&self) -> &str {
+        match self {
+            &SessionStateError::UnsupportedVersion(..) => "Unsupported session format version",
+            &SessionStateError::UnsupportedProtocolLevel(..) => "Unsupported protocol level",
+            &SessionStateError::InvalidQoS(..) => "Invalid QoS",
+            &SessionStateError::IoError(ref err) => err.description(),
+            &SessionStateError::StringEncodeError(ref err) => err.description(),
+            &SessionStateError::VariablePacketError(ref err) => err.description(),
+            &SessionStateError::UnexpectedPacket(..) => "Unexpected packet",
+            &SessionStateError::Qos1TrackerError(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match self {
+            &SessionStateError::UnsupportedVersion(..) => None,
+            &SessionStateError::UnsupportedProtocolLevel(..) => None,
+            &SessionStateError::InvalidQoS(..) => None,
+            &SessionStateError::IoError(ref err) => Some(err),
+            &SessionStateError::StringEncodeError(ref err) => Some(err),
+            &SessionStateError::VariablePacketError(ref err) => Some(err),
+            &SessionStateError::UnexpectedPacket(..) => None,
+            &SessionStateError::Qos1TrackerError(ref err) => Some(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Instant;
+
+    use control::variable_header::{ConnectReturnCode, PacketIdentifier, ProtocolVersion};
+    use packet::*;
+    use {QualityOfService, TopicFilter};
+
+    fn publish(qos: QualityOfService, pkid: PacketIdentifier) -> PublishPacket {
+        let qos = match qos {
+            QualityOfService::Level1 => QoSWithPacketIdentifier::Level1(pkid),
+            _ => QoSWithPacketIdentifier::Level2(pkid),
+        };
+        PublishPacket::new("a/b".to_owned(), qos, b"payload".to_vec())
+    }
+
+    fn pkid(id: u16) -> PacketIdentifier {
+        PacketIdentifier::new(id).unwrap()
+    }
+
+    #[test]
+    fn test_session_state_round_trip() {
+        let now = Instant::now();
+        let mut state = SessionState::new();
+        state.add_subscription(TopicFilter::new("a/+"), QualityOfService::Level1);
+        state.add_subscription(TopicFilter::new("b/#"), QualityOfService::Level2);
+        state.add_subscription(TopicFilter::new("a/+"), QualityOfService::Level0);
+
+        let pkid = state.packet_ids_mut().allocate().unwrap();
+        state.qos1_mut().register(publish(QualityOfService::Level1, pkid), now).unwrap();
+        let pkid = state.packet_ids_mut().allocate().unwrap();
+        state.qos2_mut().on_outgoing_publish(publish(QualityOfService::Level2, pkid)).unwrap();
+        state.qos2_mut().on_incoming_publish(&publish(QualityOfService::Level2, pkid)).unwrap();
+
+        let bytes = state.to_bytes().unwrap();
+        let restored = SessionState::from_bytes(&bytes).unwrap();
+
+        assert_eq!(&[(TopicFilter::new("a/+"), QualityOfService::Level0),
+                     (TopicFilter::new("b/#"), QualityOfService::Level2)][..],
+                   restored.subscriptions());
+        assert_eq!(state.qos1().pending().collect::<Vec<_>>(), restored.qos1().pending().collect::<Vec<_>>());
+        assert_eq!(state.qos2(), restored.qos2());
+        assert_eq!(2, restored.packet_ids().in_flight_count());
+        assert_eq!(3, restored.packet_ids().next_packet_identifier().get());
+        assert_eq!(bytes, restored.to_bytes().unwrap());
+
+        assert_eq!(&b"\x03\x04"[..], &bytes[..2]);
+        let mut bytes = bytes;
+        bytes[0] = 4;
+        match SessionState::from_bytes(&bytes) {
+            Err(SessionStateError::UnsupportedVersion(4)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+        bytes[0] = 3;
+        bytes[1] = 6;
+        match SessionState::from_bytes(&bytes) {
+            Err(SessionStateError::UnsupportedProtocolLevel(6)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        // Version 2 without the protocol level
+        let bytes = b"\x02\x00\x02\x00\x00\x00\x00\x00\x00\x00\x01\x32\x0e\x00\x03a/b\x00\x01payload";
+        let restored = SessionState::from_bytes(&bytes[..]).unwrap();
+        assert_eq!(ProtocolVersion::V3_1_1, restored.protocol_version());
+        assert_eq!(vec![VariablePacket::new(publish(QualityOfService::Level1, PacketIdentifier::new(1).unwrap()).for_retransmission())],
+                   restored.resume_packets());
+
+        // Version 1 with a QoS 1 message and a QoS 2 message waiting for PUBCOMP
+        let bytes = b"\x01\x00\x03\x00\x00\x00\x00\x00\x00\x00\x01\x32\x0e\x00\x03a/b\x00\x01payload\
+                      \x00\x00\x00\x01\x62\x02\x00\x02";
+        let restored = SessionState::from_bytes(&bytes[..]).unwrap();
+        assert_eq!(vec![VariablePacket::new(publish(QualityOfService::Level1, PacketIdentifier::new(1).unwrap()).for_retransmission()),
+                        VariablePacket::new(PubrelPacket::new(2))],
+                   restored.resume_packets());
+        assert_eq!(2, restored.packet_ids().in_flight_count());
+    }
+
+    #[test]
+    fn test_session_state_mqtt_5() {
+        let now = Instant::now();
+        let mut state = SessionState::with_version(ProtocolVersion::V5);
+
+        let mut packet = PublishPacket::with_version("a/b".to_owned(), QoSWithPacketIdentifier::Level1(pkid(1)),
+                                                     b"payload".to_vec(), ProtocolVersion::V5);
+        packet.set_content_type("text/plain");
+        state.packet_ids_mut().reserve(pkid(1));
+        state.qos1_mut().register(packet.clone(), now).unwrap();
+        // Laid out for the version of the session when stored
+        state.packet_ids_mut().reserve(pkid(2));
+        state.qos2_mut().on_outgoing_publish(publish(QualityOfService::Level2, pkid(2))).unwrap();
+
+        let bytes = state.to_bytes().unwrap();
+        assert_eq!(&b"\x03\x05"[..], &bytes[..2]);
+        let restored = SessionState::from_bytes(&bytes).unwrap();
+        assert_eq!(ProtocolVersion::V5, restored.protocol_version());
+        assert_eq!(vec![&packet], restored.qos1().pending().collect::<Vec<_>>());
+        match restored.resume_packets()[1] {
+            VariablePacket::PublishPacket(ref publish) => {
+                assert!(publish.is_mqtt5());
+                assert_eq!(pkid(2), match publish.qos() {
+                    QoSWithPacketIdentifier::Level2(pkid) => pkid,
+                    qos => panic!("Unexpected QoS {:?}", qos),
+                });
+            },
+            ref packet => panic!("Unexpected packet {:?}", packet),
+        }
+
+        let mut restored = restored;
+        restored.on_connack(&ConnackPacket::accepted(false));
+        assert_eq!(ProtocolVersion::V5, restored.protocol_version());
+    }
+
+    #[test]
+    fn test_session_state_resume_qos2_after_crash() {
+        let mut state = SessionState::new();
+        let pkid = state.packet_ids_mut().allocate().unwrap();
+        state.qos2_mut().on_outgoing_publish(publish(QualityOfService::Level2, pkid)).unwrap();
+        assert_eq!(Ok(PubrelPacket::new(pkid.get())), state.qos2_mut().on_pubrec(pkid.get()));
+
+        // The process dies before the PUBCOMP arrives
+        let bytes = state.to_bytes().unwrap();
+        drop(state);
+
+        let mut state = SessionState::from_bytes(&bytes).unwrap();
+        state.on_connack(&ConnackPacket::accepted(true));
+        assert_eq!(vec![VariablePacket::new(PubrelPacket::new(pkid.get()))], state.retransmit(Instant::now()));
+        assert!(state.packet_ids().is_in_flight(pkid));
+
+        assert_eq!(Ok(()), state.qos2_mut().on_pubcomp(pkid.get()));
+        assert!(state.packet_ids_mut().release(pkid));
+    }
+
+    #[test]
+    fn test_session_state_resume_packets() {
+        let now = Instant::now();
+        let mut state = SessionState::new();
+        state.packet_ids_mut().set_next_packet_identifier(PacketIdentifier::new(65534).unwrap());
+
+        let first = state.packet_ids_mut().allocate().unwrap();
+        state.qos1_mut().register(publish(QualityOfService::Level1, first), now).unwrap();
+        let second = state.packet_ids_mut().allocate().unwrap();
+        state.qos2_mut().on_outgoing_publish(publish(QualityOfService::Level2, second)).unwrap();
+        state.qos2_mut().on_pubrec(second.get()).unwrap();
+        let third = state.packet_ids_mut().allocate().unwrap();
+        state.qos1_mut().register(publish(QualityOfService::Level1, third), now).unwrap();
+        assert_eq!((65534, 65535, 1), (first.get(), second.get(), third.get()));
+
+        let mut state = SessionState::from_bytes(&state.to_bytes().unwrap()).unwrap();
+        state.on_connack(&ConnackPacket::accepted(true));
+
+        let packets = state.resume_packets();
+        assert_eq!(vec![Some(65534), Some(65535), Some(1)],
+                   packets.iter().map(VariablePacket::packet_identifier).collect::<Vec<_>>());
+        match (&packets[0], &packets[1], &packets[2]) {
+            (&VariablePacket::PublishPacket(ref first), &VariablePacket::PubrelPacket(..),
+             &VariablePacket::PublishPacket(ref third)) => {
+                assert!(first.dup() && third.dup());
+                assert_eq!(QualityOfService::Level1, first.qos().qos());
+            },
+            packets => panic!("Unexpected packets {:?}", packets),
+        }
+        assert!(state.qos1().pending().all(|packet| !packet.dup()));
+
+        state.on_connack(&ConnackPacket::accepted(false));
+        assert!(state.resume_packets().is_empty());
+    }
+
+    #[test]
+    fn test_session_state_resume_packets_held_across_wrap() {
+        let now = Instant::now();
+        let mut state = SessionState::new();
+
+        // Identifier 1 stays in flight while the others wrap around
+        let first = state.packet_ids_mut().allocate().unwrap();
+        state.qos2_mut().on_outgoing_publish(publish(QualityOfService::Level2, first)).unwrap();
+        state.packet_ids_mut().set_next_packet_identifier(pkid(60000));
+        let second = state.packet_ids_mut().allocate().unwrap();
+        state.qos1_mut().register(publish(QualityOfService::Level1, second), now).unwrap();
+        state.packet_ids_mut().set_next_packet_identifier(pkid(65535));
+        let released = state.packet_ids_mut().allocate().unwrap();
+        state.packet_ids_mut().release(released);
+        let third = state.packet_ids_mut().allocate().unwrap();
+        state.qos1_mut().register(publish(QualityOfService::Level1, third), now).unwrap();
+        assert_eq!((1, 60000, 2), (first.get(), second.get(), third.get()));
+
+        let expected = vec![Some(1), Some(60000), Some(2)];
+        assert_eq!(expected, state.resume_packets().iter().map(VariablePacket::packet_identifier).collect::<Vec<_>>());
+
+        let restored = SessionState::from_bytes(&state.to_bytes().unwrap()).unwrap();
+        assert_eq!(expected, restored.resume_packets().iter().map(VariablePacket::packet_identifier).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_session_state_on_connack() {
+        let mut state = SessionState::new();
+        state.add_subscription(TopicFilter::new("a/b"), QualityOfService::Level1);
+        let pkid = state.packet_ids_mut().allocate().unwrap();
+        state.qos1_mut().register(publish(QualityOfService::Level1, pkid), Instant::now()).unwrap();
+
+        state.on_connack(&ConnackPacket::rejected(ConnectReturnCode::ServiceUnavailable));
+        assert_eq!(1, state.subscriptions().len());
+
+        state.on_connack(&ConnackPacket::accepted(true));
+        assert_eq!(1, state.qos1().len());
+
+        state.on_connack(&ConnackPacket::accepted(false));
+        assert!(state.subscriptions().is_empty());
+        assert!(state.qos1().is_empty());
+        assert_eq!(0, state.packet_ids().in_flight_count());
+    }
+}
@@ -0,0 +1,324 @@
+use std::error::Error;
+use std::fmt;
+
+use control::ControlType;
+use control::variable_header::ProtocolVersion;
+use packet::VariablePacket;
+
+/// Side of the connection that owns the validator
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Role {
+    /// Validates the packets a server receives from a client
+    Server,
+    /// Validates the packets a client receives from a server
+    Client,
+}
+
+impl Role {
+    /// Whether the peer of this role is allowed to send packets of `control_type`
+    fn peer_may_send(&self, control_type: ControlType, version: ProtocolVersion) -> bool {
+        match (*self, control_type) {
+            (Role::Server, ControlType::ConnectAcknowledgement) |
+            (Role::Server, ControlType::SubscribeAcknowledgement) |
+            (Role::Server, ControlType::UnsubscribeAcknowledgement) |
+            (Role::Server, ControlType::PingResponse) => false,
+
+            (Role::Client, ControlType::Connect) |
+            (Role::Client, ControlType::Subscribe) |
+            (Role::Client, ControlType::Unsubscribe) |
+            (Role::Client, ControlType::PingRequest) => false,
+
+            // A server closes an MQTT 5 connection with a Reason Code (MQTT 5 section 3.14)
+            (Role::Client, ControlType::Disconnect) => version == ProtocolVersion::V5,
+
+            (_, ControlType::Auth) => version == ProtocolVersion::V5,
+
+            _ => true,
+        }
+    }
+
+    /// The packet that opens a connection when received by this role
+    fn first_packet(&self) -> ControlType {
+        match *self {
+            Role::Server => ControlType::Connect,
+            Role::Client => ControlType::ConnectAcknowledgement,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Role::Server => write!(f, "server"),
+            Role::Client => write!(f, "client"),
+        }
+    }
+}
+
+/// Tracks the packets received on a single connection and rejects those that break the
+/// ordering rules of the spec
+///
+/// ```rust
+/// use mqtt::packet::*;
+/// use mqtt::session::{ProtocolStateValidator, ProtocolViolation, Role};
+///
+/// let mut validator = ProtocolStateValidator::new(Role::Server);
+/// validator.on_packet(&VariablePacket::new(ConnectPacket::new("client".to_owned()))).unwrap();
+///
+/// match validator.on_packet(&VariablePacket::new(ConnectPacket::new("client".to_owned()))) {
+///     Err(ProtocolViolation::DuplicateConnect) => {},
+///     _ => panic!(),
+/// }
+/// ```
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct ProtocolStateValidator {
+    role: Role,
+    version: ProtocolVersion,
+    connected: bool,
+    disconnected: bool,
+}
+
+impl ProtocolStateValidator {
+    pub fn new(role: Role) -> ProtocolStateValidator {
+        ProtocolStateValidator::with_version(role, ProtocolVersion::V3_1_1)
+    }
+
+    /// Validator of a connection opened with `version`
+    ///
+    /// A client knows the version from the CONNECT it sent, a server takes it from the CONNECT
+    /// it receives.
+    pub fn with_version(role: Role, version: ProtocolVersion) -> ProtocolStateValidator {
+        ProtocolStateValidator {
+            role: role,
+            version: version,
+            connected: false,
+            disconnected: false,
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// Whether the CONNECT (server) or CONNACK (client) has been received
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Checks a packet received from the peer and updates the connection state
+    pub fn on_packet(&mut self, packet: &VariablePacket) -> Result<(), ProtocolViolation> {
+        let control_type = packet.control_type();
+
+        if self.disconnected {
+            return Err(ProtocolViolation::PacketAfterDisconnect(control_type));
+        }
+
+        if !self.role.peer_may_send(control_type, self.version) {
+            return Err(ProtocolViolation::UnexpectedPacket { role: self.role, control_type: control_type });
+        }
+
+        if control_type == self.role.first_packet() {
+            if self.connected {
+                return Err(match self.role {
+                    Role::Server => ProtocolViolation::DuplicateConnect,
+                    Role::Client => ProtocolViolation::DuplicateConnack,
+                });
+            }
+
+            if let &VariablePacket::ConnectPacket(ref connect) = packet {
+                self.version = connect.protocol_version().unwrap_or(self.version);
+            }
+            self.connected = true;
+            return Ok(());
+        }
+
+        if !self.connected {
+            // Enhanced authentication happens between CONNECT and CONNACK (MQTT 5 section 4.12)
+            if control_type == ControlType::Auth && self.role == Role::Client {
+                return Ok(());
+            }
+            return Err(match self.role {
+                Role::Server => ProtocolViolation::PacketBeforeConnect(control_type),
+                Role::Client => ProtocolViolation::PacketBeforeConnack(control_type),
+            });
+        }
+
+        if control_type == ControlType::Disconnect {
+            self.disconnected = true;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ProtocolViolation {
+    PacketBeforeConnect(ControlType),
+    DuplicateConnect,
+    PacketBeforeConnack(ControlType),
+    DuplicateConnack,
+    PacketAfterDisconnect(ControlType),
+    UnexpectedPacket { role: Role, control_type: ControlType },
+}
+
+impl ProtocolViolation {
+    /// Normative statement of the spec violated by the packet, if any
+    pub fn spec_rule(&self) -> Option<&'static str> {
+        match self {
+            &ProtocolViolation::PacketBeforeConnect(..) => Some("MQTT-3.1.0-1"),
+            &ProtocolViolation::DuplicateConnect => Some("MQTT-3.1.0-2"),
+            &ProtocolViolation::PacketBeforeConnack(..) => Some("MQTT-3.2.0-1"),
+            &ProtocolViolation::PacketAfterDisconnect(..) => Some("MQTT-3.14.4-2"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ProtocolViolation::PacketBeforeConnect(t) =>
+                write!(f, "Received {} before CONNECT (MQTT-3.1.0-1)", t),
+            &ProtocolViolation::DuplicateConnect => write!(f, "Received a second CONNECT (MQTT-3.1.0-2)"),
+            &ProtocolViolation::PacketBeforeConnack(t) =>
+                write!(f, "Received {} before CONNACK (MQTT-3.2.0-1)", t),
+            &ProtocolViolation::DuplicateConnack => write!(f, "Received a second CONNACK"),
+            &ProtocolViolation::PacketAfterDisconnect(t) =>
+                write!(f, "Received {} after DISCONNECT (MQTT-3.14.4-2)", t),
+            &ProtocolViolation::UnexpectedPacket { role, control_type } =>
+                write!(f, "A {} must not receive {}", role, control_type),
+        }
+    }
+}
+
+impl Error for ProtocolViolation {
+    fn description(&self) -> &str {
+        match self {
+            &ProtocolViolation::PacketBeforeConnect(..) => "Received a packet before CONNECT",
+            &ProtocolViolation::DuplicateConnect => "Received a second CONNECT",
+            &ProtocolViolation::PacketBeforeConnack(..) => "Received a packet before CONNACK",
+            &ProtocolViolation::DuplicateConnack => "Received a second CONNACK",
+            &ProtocolViolation::PacketAfterDisconnect(..) => "Received a packet after DISCONNECT",
+            &ProtocolViolation::UnexpectedPacket { .. } => "Received a packet the peer must not send",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use control::ControlType;
+    use control::variable_header::{AuthReasonCode, DisconnectReasonCode, PacketIdentifier};
+    use packet::*;
+    use qos::QualityOfService;
+    use TopicFilter;
+
+    fn connect() -> VariablePacket {
+        VariablePacket::new(ConnectPacket::new("client".to_owned()))
+    }
+
+    fn publish() -> VariablePacket {
+        VariablePacket::new(PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level0, b"x".to_vec()))
+    }
+
+    #[test]
+    fn test_validator_double_connect() {
+        let mut validator = ProtocolStateValidator::new(Role::Server);
+        assert!(!validator.is_connected());
+        validator.on_packet(&connect()).unwrap();
+        assert!(validator.is_connected());
+        validator.on_packet(&publish()).unwrap();
+
+        let err = validator.on_packet(&connect()).unwrap_err();
+        assert_eq!(ProtocolViolation::DuplicateConnect, err);
+        assert_eq!(Some("MQTT-3.1.0-2"), err.spec_rule());
+    }
+
+    #[test]
+    fn test_validator_publish_before_connect() {
+        let mut validator = ProtocolStateValidator::new(Role::Server);
+
+        let err = validator.on_packet(&publish()).unwrap_err();
+        assert_eq!(ProtocolViolation::PacketBeforeConnect(ControlType::Publish), err);
+        assert_eq!(Some("MQTT-3.1.0-1"), err.spec_rule());
+    }
+
+    #[test]
+    fn test_validator_role() {
+        let mut validator = ProtocolStateValidator::new(Role::Server);
+        validator.on_packet(&connect()).unwrap();
+        match validator.on_packet(&VariablePacket::new(PingrespPacket::new())) {
+            Err(ProtocolViolation::UnexpectedPacket { role: Role::Server, control_type: ControlType::PingResponse }) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+        validator.on_packet(&VariablePacket::new(PingreqPacket::new())).unwrap();
+        let subscribe = SubscribePacket::new(PacketIdentifier::new(1).unwrap(),
+                                             vec![(TopicFilter::new("a/b"), QualityOfService::Level0)]);
+        validator.on_packet(&VariablePacket::new(subscribe)).unwrap();
+
+        validator.on_packet(&VariablePacket::new(DisconnectPacket::new())).unwrap();
+        assert_eq!(Err(ProtocolViolation::PacketAfterDisconnect(ControlType::Publish)),
+                   validator.on_packet(&publish()));
+
+        let mut validator = ProtocolStateValidator::new(Role::Client);
+        assert_eq!(Err(ProtocolViolation::PacketBeforeConnack(ControlType::Publish)),
+                   validator.on_packet(&publish()));
+        validator.on_packet(&VariablePacket::new(ConnackPacket::accepted(false))).unwrap();
+        validator.on_packet(&publish()).unwrap();
+        assert_eq!(Err(ProtocolViolation::DuplicateConnack),
+                   validator.on_packet(&VariablePacket::new(ConnackPacket::accepted(false))));
+        match validator.on_packet(&connect()) {
+            Err(ProtocolViolation::UnexpectedPacket { role: Role::Client, control_type: ControlType::Connect }) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_validator_auth_before_connack() {
+        let auth = VariablePacket::new(AuthPacket::new(AuthReasonCode::ContinueAuthentication));
+
+        let mut validator = ProtocolStateValidator::with_version(Role::Client, ProtocolVersion::V5);
+        validator.on_packet(&auth).unwrap();
+        validator.on_packet(&auth).unwrap();
+        assert!(!validator.is_connected());
+        validator.on_packet(&VariablePacket::new(ConnackPacket::accepted(false))).unwrap();
+        validator.on_packet(&auth).unwrap();
+
+        // AUTH does not exist in MQTT 3.1.1
+        let mut validator = ProtocolStateValidator::new(Role::Client);
+        match validator.on_packet(&auth) {
+            Err(ProtocolViolation::UnexpectedPacket { role: Role::Client, control_type: ControlType::Auth }) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        // A server learns the version from the CONNECT
+        let mut validator = ProtocolStateValidator::new(Role::Server);
+        let connect = ConnectPacket::with_version("client".to_owned(), ProtocolVersion::V5);
+        validator.on_packet(&VariablePacket::new(connect)).unwrap();
+        assert_eq!(ProtocolVersion::V5, validator.protocol_version());
+        validator.on_packet(&auth).unwrap();
+    }
+
+    #[test]
+    fn test_validator_server_disconnect() {
+        let disconnect = VariablePacket::new(DisconnectPacket::new_with_reason(DisconnectReasonCode::SessionTakenOver));
+
+        let mut validator = ProtocolStateValidator::new(Role::Client);
+        validator.on_packet(&VariablePacket::new(ConnackPacket::accepted(false))).unwrap();
+        match validator.on_packet(&disconnect) {
+            Err(ProtocolViolation::UnexpectedPacket { role: Role::Client, control_type: ControlType::Disconnect }) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        let mut validator = ProtocolStateValidator::with_version(Role::Client, ProtocolVersion::V5);
+        validator.on_packet(&VariablePacket::new(ConnackPacket::accepted(false))).unwrap();
+        validator.on_packet(&disconnect).unwrap();
+        assert_eq!(Err(ProtocolViolation::PacketAfterDisconnect(ControlType::Publish)),
+                   validator.on_packet(&publish()));
+    }
+}
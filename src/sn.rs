@@ -0,0 +1,802 @@
+//! MQTT-SN 1.2 frames for forwarding PUBLISH and SUBSCRIBE through a gateway
+//!
+//! Only the messages needed for publish/subscribe forwarding are covered: PUBLISH, SUBSCRIBE,
+//! REGISTER and REGACK. The gateway state machine (CONNECT, keep alive, sleeping clients, ...)
+//! is left to the application.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str;
+
+use byteorder::{self, BigEndian, ReadBytesExt, WriteBytesExt};
+
+use control::variable_header::PacketIdentifier;
+use packet::{Packet, PublishPacket, QoSWithPacketIdentifier, SubscribePacket};
+use topic_filter::TopicFilterError;
+use {Encodable, Decodable, QualityOfService, TopicFilter};
+
+pub const MSG_TYPE_REGISTER: u8 = 0x0A;
+pub const MSG_TYPE_REGACK: u8 = 0x0B;
+pub const MSG_TYPE_PUBLISH: u8 = 0x0C;
+pub const MSG_TYPE_SUBSCRIBE: u8 = 0x12;
+
+const FLAG_DUP: u8 = 0x80;
+const FLAG_RETAIN: u8 = 0x10;
+
+const TOPIC_ID_TYPE_NORMAL: u8 = 0x00;
+const TOPIC_ID_TYPE_PREDEFINED: u8 = 0x01;
+const TOPIC_ID_TYPE_SHORT: u8 = 0x02;
+
+/// MQTT-SN QoS, `MinusOne` is publishing without a connection
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum SnQoS {
+    MinusOne,
+    Level0,
+    Level1,
+    Level2,
+}
+
+impl SnQoS {
+    /// QoS -1 is forwarded as QoS 0
+    pub fn to_mqtt(&self) -> QualityOfService {
+        match self {
+            &SnQoS::MinusOne | &SnQoS::Level0 => QualityOfService::Level0,
+            &SnQoS::Level1 => QualityOfService::Level1,
+            &SnQoS::Level2 => QualityOfService::Level2,
+        }
+    }
+
+    fn from_flags(flags: u8) -> SnQoS {
+        match (flags >> 5) & 0x03 {
+            0 => SnQoS::Level0,
+            1 => SnQoS::Level1,
+            2 => SnQoS::Level2,
+            _ => SnQoS::MinusOne,
+        }
+    }
+
+    fn to_flags(&self) -> u8 {
+        let bits = match self {
+            &SnQoS::Level0 => 0,
+            &SnQoS::Level1 => 1,
+            &SnQoS::Level2 => 2,
+            &SnQoS::MinusOne => 3,
+        };
+        bits << 5
+    }
+}
+
+impl From<QualityOfService> for SnQoS {
+    fn from(qos: QualityOfService) -> SnQoS {
+        match qos {
+            QualityOfService::Level0 => SnQoS::Level0,
+            QualityOfService::Level1 => SnQoS::Level1,
+            QualityOfService::Level2 => SnQoS::Level2,
+        }
+    }
+}
+
+/// Topic of a PUBLISH
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum SnTopic {
+    /// Topic id assigned with REGISTER/REGACK
+    Normal(u16),
+    Predefined(u16),
+    /// Two character topic name sent in place of the topic id
+    Short([u8; 2]),
+}
+
+impl SnTopic {
+    fn id_type(&self) -> u8 {
+        match self {
+            &SnTopic::Normal(..) => TOPIC_ID_TYPE_NORMAL,
+            &SnTopic::Predefined(..) => TOPIC_ID_TYPE_PREDEFINED,
+            &SnTopic::Short(..) => TOPIC_ID_TYPE_SHORT,
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; 2] {
+        match self {
+            &SnTopic::Normal(id) | &SnTopic::Predefined(id) => [(id >> 8) as u8, id as u8],
+            &SnTopic::Short(name) => name,
+        }
+    }
+
+    fn from_bytes(id_type: u8, bytes: [u8; 2]) -> Result<SnTopic, SnError> {
+        let id = (bytes[0] as u16) << 8 | bytes[1] as u16;
+        match id_type {
+            TOPIC_ID_TYPE_NORMAL => Ok(SnTopic::Normal(id)),
+            TOPIC_ID_TYPE_PREDEFINED => Ok(SnTopic::Predefined(id)),
+            TOPIC_ID_TYPE_SHORT => Ok(SnTopic::Short(bytes)),
+            _ => Err(SnError::InvalidTopicIdType(id_type)),
+        }
+    }
+}
+
+/// Topic of a SUBSCRIBE
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum SnSubscribeTopic {
+    /// Topic filter, may contain wildcards
+    Name(String),
+    Predefined(u16),
+    Short([u8; 2]),
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct SnPublish {
+    pub dup: bool,
+    pub qos: SnQoS,
+    pub retain: bool,
+    pub topic: SnTopic,
+    /// 0 for QoS 0 and -1
+    pub msg_id: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct SnSubscribe {
+    pub dup: bool,
+    pub qos: SnQoS,
+    pub msg_id: u16,
+    pub topic: SnSubscribeTopic,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct SnRegister {
+    /// 0 when sent by a client, the assigned id when sent by the gateway
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub topic_name: String,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum SnReturnCode {
+    Accepted,
+    RejectedCongestion,
+    RejectedInvalidTopicId,
+    RejectedNotSupported,
+}
+
+impl SnReturnCode {
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            &SnReturnCode::Accepted => 0x00,
+            &SnReturnCode::RejectedCongestion => 0x01,
+            &SnReturnCode::RejectedInvalidTopicId => 0x02,
+            &SnReturnCode::RejectedNotSupported => 0x03,
+        }
+    }
+
+    pub fn from_u8(code: u8) -> Result<SnReturnCode, SnError> {
+        match code {
+            0x00 => Ok(SnReturnCode::Accepted),
+            0x01 => Ok(SnReturnCode::RejectedCongestion),
+            0x02 => Ok(SnReturnCode::RejectedInvalidTopicId),
+            0x03 => Ok(SnReturnCode::RejectedNotSupported),
+            _ => Err(SnError::InvalidReturnCode(code)),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct SnRegack {
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub return_code: SnReturnCode,
+}
+
+/// The MQTT-SN messages handled by this module
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum SnMessage {
+    Publish(SnPublish),
+    Subscribe(SnSubscribe),
+    Register(SnRegister),
+    Regack(SnRegack),
+}
+
+impl SnMessage {
+    pub fn msg_type(&self) -> u8 {
+        match self {
+            &SnMessage::Publish(..) => MSG_TYPE_PUBLISH,
+            &SnMessage::Subscribe(..) => MSG_TYPE_SUBSCRIBE,
+            &SnMessage::Register(..) => MSG_TYPE_REGISTER,
+            &SnMessage::Regack(..) => MSG_TYPE_REGACK,
+        }
+    }
+
+    fn body_length(&self) -> usize {
+        match self {
+            &SnMessage::Publish(ref publish) => 5 + publish.data.len(),
+            &SnMessage::Subscribe(ref subscribe) => 3 + match subscribe.topic {
+                SnSubscribeTopic::Name(ref name) => name.len(),
+                SnSubscribeTopic::Predefined(..) | SnSubscribeTopic::Short(..) => 2,
+            },
+            &SnMessage::Register(ref register) => 4 + register.topic_name.len(),
+            &SnMessage::Regack(..) => 5,
+        }
+    }
+
+    fn encode_body<W: Write>(&self, writer: &mut W) -> Result<(), SnError> {
+        match self {
+            &SnMessage::Publish(ref publish) => {
+                let mut flags = publish.qos.to_flags() | publish.topic.id_type();
+                if publish.dup {
+                    flags |= FLAG_DUP;
+                }
+                if publish.retain {
+                    flags |= FLAG_RETAIN;
+                }
+                try!(writer.write_u8(flags));
+                try!(writer.write_all(&publish.topic.to_bytes()));
+                try!(writer.write_u16::<BigEndian>(publish.msg_id));
+                try!(writer.write_all(&publish.data));
+            },
+            &SnMessage::Subscribe(ref subscribe) => {
+                let (id_type, topic) = match subscribe.topic {
+                    SnSubscribeTopic::Name(ref name) => (TOPIC_ID_TYPE_NORMAL, name.as_bytes().to_vec()),
+                    SnSubscribeTopic::Predefined(id) => (TOPIC_ID_TYPE_PREDEFINED, vec![(id >> 8) as u8, id as u8]),
+                    SnSubscribeTopic::Short(name) => (TOPIC_ID_TYPE_SHORT, name.to_vec()),
+                };
+                let mut flags = subscribe.qos.to_flags() | id_type;
+                if subscribe.dup {
+                    flags |= FLAG_DUP;
+                }
+                try!(writer.write_u8(flags));
+                try!(writer.write_u16::<BigEndian>(subscribe.msg_id));
+                try!(writer.write_all(&topic));
+            },
+            &SnMessage::Register(ref register) => {
+                try!(writer.write_u16::<BigEndian>(register.topic_id));
+                try!(writer.write_u16::<BigEndian>(register.msg_id));
+                try!(writer.write_all(register.topic_name.as_bytes()));
+            },
+            &SnMessage::Regack(ref regack) => {
+                try!(writer.write_u16::<BigEndian>(regack.topic_id));
+                try!(writer.write_u16::<BigEndian>(regack.msg_id));
+                try!(writer.write_u8(regack.return_code.to_u8()));
+            },
+        }
+        Ok(())
+    }
+
+    fn decode_body(msg_type: u8, body: &[u8]) -> Result<SnMessage, SnError> {
+        let u16_at = |pos: usize| (body[pos] as u16) << 8 | body[pos + 1] as u16;
+        match msg_type {
+            MSG_TYPE_PUBLISH => {
+                if body.len() < 5 {
+                    return Err(SnError::InvalidLength);
+                }
+                let flags = body[0];
+                Ok(SnMessage::Publish(SnPublish {
+                    dup: flags & FLAG_DUP != 0,
+                    qos: SnQoS::from_flags(flags),
+                    retain: flags & FLAG_RETAIN != 0,
+                    topic: try!(SnTopic::from_bytes(flags & 0x03, [body[1], body[2]])),
+                    msg_id: u16_at(3),
+                    data: body[5..].to_vec(),
+                }))
+            },
+            MSG_TYPE_SUBSCRIBE => {
+                if body.len() < 3 {
+                    return Err(SnError::InvalidLength);
+                }
+                let flags = body[0];
+                let topic = &body[3..];
+                let topic = match flags & 0x03 {
+                    TOPIC_ID_TYPE_NORMAL => SnSubscribeTopic::Name(try!(topic_name(topic))),
+                    TOPIC_ID_TYPE_PREDEFINED if topic.len() == 2 => SnSubscribeTopic::Predefined(u16_at(3)),
+                    TOPIC_ID_TYPE_SHORT if topic.len() == 2 => SnSubscribeTopic::Short([topic[0], topic[1]]),
+                    TOPIC_ID_TYPE_PREDEFINED | TOPIC_ID_TYPE_SHORT => return Err(SnError::InvalidLength),
+                    id_type => return Err(SnError::InvalidTopicIdType(id_type)),
+                };
+                Ok(SnMessage::Subscribe(SnSubscribe {
+                    dup: flags & FLAG_DUP != 0,
+                    qos: SnQoS::from_flags(flags),
+                    msg_id: u16_at(1),
+                    topic: topic,
+                }))
+            },
+            MSG_TYPE_REGISTER => {
+                if body.len() < 4 {
+                    return Err(SnError::InvalidLength);
+                }
+                Ok(SnMessage::Register(SnRegister {
+                    topic_id: u16_at(0),
+                    msg_id: u16_at(2),
+                    topic_name: try!(topic_name(&body[4..])),
+                }))
+            },
+            MSG_TYPE_REGACK => {
+                if body.len() != 5 {
+                    return Err(SnError::InvalidLength);
+                }
+                Ok(SnMessage::Regack(SnRegack {
+                    topic_id: u16_at(0),
+                    msg_id: u16_at(2),
+                    return_code: try!(SnReturnCode::from_u8(body[4])),
+                }))
+            },
+            _ => Err(SnError::UnsupportedMsgType(msg_type)),
+        }
+    }
+}
+
+fn topic_name(buf: &[u8]) -> Result<String, SnError> {
+    str::from_utf8(buf).map(|name| name.to_owned()).map_err(|_| SnError::InvalidUtf8)
+}
+
+impl<'a> Encodable<'a> for SnMessage {
+    type Err = SnError;
+
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), SnError> {
+        let len = self.encoded_length();
+        if len > 0xFFFF {
+            return Err(SnError::InvalidLength);
+        }
+        // The Length field is one octet, or 0x01 followed by two octets from 256 on
+        if len < 256 {
+            try!(writer.write_u8(len as u8));
+        } else {
+            try!(writer.write_u8(0x01));
+            try!(writer.write_u16::<BigEndian>(len as u16));
+        }
+        try!(writer.write_u8(self.msg_type()));
+        self.encode_body(writer)
+    }
+
+    fn encoded_length(&self) -> u32 {
+        let len = 2 + self.body_length() as u32;
+        if len < 256 { len } else { len + 2 }
+    }
+}
+
+impl<'a> Decodable<'a> for SnMessage {
+    type Err = SnError;
+    type Cond = ();
+
+    fn decode_with<R: Read>(reader: &mut R, _rest: Option<()>) -> Result<SnMessage, SnError> {
+        let (len, header_len) = match try!(reader.read_u8()) {
+            0x01 => (try!(reader.read_u16::<BigEndian>()) as usize, 4),
+            len => (len as usize, 2),
+        };
+        if len < header_len {
+            return Err(SnError::InvalidLength);
+        }
+        let msg_type = try!(reader.read_u8());
+
+        let mut body = Vec::with_capacity(len - header_len);
+        try!(reader.take((len - header_len) as u64).read_to_end(&mut body));
+        if body.len() != len - header_len {
+            return Err(SnError::InvalidLength);
+        }
+        SnMessage::decode_body(msg_type, &body)
+    }
+}
+
+/// Topic ids of one client, registered with REGISTER/REGACK, and the predefined topic ids
+#[derive(Debug, Clone, Default)]
+pub struct TopicRegistry {
+    names: HashMap<u16, String>,
+    ids: HashMap<String, u16>,
+    predefined: HashMap<u16, String>,
+    next_id: u16,
+}
+
+impl TopicRegistry {
+    pub fn new() -> TopicRegistry {
+        TopicRegistry::default()
+    }
+
+    /// Records a topic id assigned elsewhere, e.g. in a REGISTER from the gateway
+    pub fn register(&mut self, topic_id: u16, topic_name: String) {
+        if let Some(old_name) = self.names.insert(topic_id, topic_name.clone()) {
+            self.ids.remove(&old_name);
+        }
+        self.ids.insert(topic_name, topic_id);
+    }
+
+    /// Returns the id of `topic_name`, assigning the next free one if it has none yet
+    pub fn assign(&mut self, topic_name: &str) -> Result<u16, SnError> {
+        if let Some(&topic_id) = self.ids.get(topic_name) {
+            return Ok(topic_id);
+        }
+
+        // 0x0000 and 0xFFFF are reserved
+        for _ in 0..0xFFFE {
+            self.next_id = if self.next_id >= 0xFFFE { 1 } else { self.next_id + 1 };
+            if !self.names.contains_key(&self.next_id) {
+                let topic_id = self.next_id;
+                self.register(topic_id, topic_name.to_owned());
+                return Ok(topic_id);
+            }
+        }
+        Err(SnError::TopicIdsExhausted)
+    }
+
+    pub fn lookup(&self, topic_id: u16) -> Option<&str> {
+        self.names.get(&topic_id).map(|name| &name[..])
+    }
+
+    pub fn topic_id(&self, topic_name: &str) -> Option<u16> {
+        self.ids.get(topic_name).cloned()
+    }
+
+    pub fn predefine(&mut self, topic_id: u16, topic_name: String) {
+        self.predefined.insert(topic_id, topic_name);
+    }
+
+    pub fn lookup_predefined(&self, topic_id: u16) -> Option<&str> {
+        self.predefined.get(&topic_id).map(|name| &name[..])
+    }
+
+    fn predefined_id(&self, topic_name: &str) -> Option<u16> {
+        self.predefined.iter().find(|&(_, name)| name == topic_name).map(|(&id, _)| id)
+    }
+
+    fn resolve(&self, topic: &SnTopic) -> Result<String, SnError> {
+        match topic {
+            &SnTopic::Normal(id) => self.lookup(id).map(|name| name.to_owned()).ok_or(SnError::UnknownTopicId(id)),
+            &SnTopic::Predefined(id) =>
+                self.lookup_predefined(id).map(|name| name.to_owned()).ok_or(SnError::UnknownTopicId(id)),
+            &SnTopic::Short(name) => topic_name(&name),
+        }
+    }
+}
+
+fn qos_with_pkid(qos: QualityOfService, msg_id: u16) -> Result<QoSWithPacketIdentifier, SnError> {
+    let pkid = || PacketIdentifier::new(msg_id).ok_or(SnError::InvalidMsgId);
+    match qos {
+        QualityOfService::Level0 => Ok(QoSWithPacketIdentifier::Level0),
+        QualityOfService::Level1 => Ok(QoSWithPacketIdentifier::Level1(try!(pkid()))),
+        QualityOfService::Level2 => Ok(QoSWithPacketIdentifier::Level2(try!(pkid()))),
+    }
+}
+
+/// Translates a PUBLISH from a device, the MsgId is kept as the Packet Identifier
+pub fn to_mqtt_publish(publish: &SnPublish, registry: &TopicRegistry) -> Result<PublishPacket, SnError> {
+    let topic_name = try!(registry.resolve(&publish.topic));
+    let qos = try!(qos_with_pkid(publish.qos.to_mqtt(), publish.msg_id));
+
+    let mut packet = PublishPacket::new(topic_name, qos, publish.data.clone());
+    packet.set_dup(publish.dup && qos != QoSWithPacketIdentifier::Level0);
+    packet.set_retain(publish.retain);
+    Ok(packet)
+}
+
+/// Translates a PUBLISH from the broker for a device
+///
+/// Two character topic names are sent as short topic names, other topics need a registered or
+/// predefined topic id. A gateway that gets `UnregisteredTopic` assigns an id with
+/// `TopicRegistry::assign` and sends a REGISTER to the device first.
+pub fn from_mqtt_publish(publish: &PublishPacket, registry: &TopicRegistry) -> Result<SnPublish, SnError> {
+    let name = publish.topic_name();
+    let topic = if name.len() == 2 {
+        SnTopic::Short([name.as_bytes()[0], name.as_bytes()[1]])
+    } else if let Some(id) = registry.topic_id(name) {
+        SnTopic::Normal(id)
+    } else if let Some(id) = registry.predefined_id(name) {
+        SnTopic::Predefined(id)
+    } else {
+        return Err(SnError::UnregisteredTopic(name.to_owned()));
+    };
+
+    let (qos, msg_id) = match publish.qos() {
+        QoSWithPacketIdentifier::Level0 => (SnQoS::Level0, 0),
+        QoSWithPacketIdentifier::Level1(pkid) => (SnQoS::Level1, pkid.get()),
+        QoSWithPacketIdentifier::Level2(pkid) => (SnQoS::Level2, pkid.get()),
+    };
+
+    Ok(SnPublish {
+        dup: publish.dup(),
+        qos: qos,
+        retain: publish.retain(),
+        topic: topic,
+        msg_id: msg_id,
+        data: publish.payload().to_vec(),
+    })
+}
+
+/// Translates a SUBSCRIBE from a device, the MsgId is kept as the Packet Identifier
+pub fn to_mqtt_subscribe(subscribe: &SnSubscribe, registry: &TopicRegistry) -> Result<SubscribePacket, SnError> {
+    let filter = match subscribe.topic {
+        SnSubscribeTopic::Name(ref name) => name.clone(),
+        SnSubscribeTopic::Predefined(id) => try!(registry.resolve(&SnTopic::Predefined(id))),
+        SnSubscribeTopic::Short(name) => try!(topic_name(&name)),
+    };
+    let filter = TopicFilter::new(filter);
+    try!(filter.validate());
+
+    let pkid = try!(PacketIdentifier::new(subscribe.msg_id).ok_or(SnError::InvalidMsgId));
+    Ok(SubscribePacket::new(pkid, vec![(filter, subscribe.qos.to_mqtt())]))
+}
+
+#[derive(Debug)]
+pub enum SnError {
+    IoError(io::Error),
+    InvalidLength,
+    UnsupportedMsgType(u8),
+    InvalidTopicIdType(u8),
+    InvalidReturnCode(u8),
+    InvalidUtf8,
+    /// MsgId 0 where the MQTT packet needs a Packet Identifier
+    InvalidMsgId,
+    InvalidTopicFilter(TopicFilterError),
+    UnknownTopicId(u16),
+    UnregisteredTopic(String),
+    TopicIdsExhausted,
+}
+
+impl fmt::Display for SnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &SnError::IoError(ref err) => err.fmt(f),
+            &SnError::UnsupportedMsgType(msg_type) => write!(f, "Unsupported MQTT-SN message type 0x{:02x}", msg_type),
+            &SnError::InvalidTopicIdType(id_type) => write!(f, "Invalid topic id type {}", id_type),
+            &SnError::InvalidReturnCode(code) => write!(f, "Invalid return code 0x{:02x}", code),
+            &SnError::InvalidTopicFilter(ref err) => write!(f, "Invalid topic filter: {}", err),
+            &SnError::UnknownTopicId(id) => write!(f, "Unknown topic id {}", id),
+            &SnError::UnregisteredTopic(ref name) => write!(f, "No topic id for {:?}", name),
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl Error for SnError {
+    fn description(&self) -> &str {
+        match self {
+            &SnError::IoError(ref err) => err.description(),
+            &SnError::InvalidLength => "Invalid MQTT-SN message length",
+            &SnError::UnsupportedMsgType(..) => "Unsupported MQTT-SN message type",
+            &SnError::InvalidTopicIdType(..) => "Invalid topic id type",
+            &SnError::InvalidReturnCode(..) => "Invalid return code",
+            &SnError::InvalidUtf8 => "Topic name is not valid UTF-8",
+            &SnError::InvalidMsgId => "MsgId 0 for a message that needs a Packet Identifier",
+            &SnError::InvalidTopicFilter(ref err) => err.description(),
+            &SnError::UnknownTopicId(..) => "Unknown topic id",
+            &SnError::UnregisteredTopic(..) => "No topic id for the topic name",
+            &SnError::TopicIdsExhausted => "All topic ids are in use",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match self {
+            &SnError::IoError(ref err) => Some(err),
+            &SnError::InvalidTopicFilter(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnError {
+    fn from(err: io::Error) -> SnError {
+        SnError::IoError(err)
+    }
+}
+
+impl From<byteorder::Error> for SnError {
+    fn from(err: byteorder::Error) -> SnError {
+        SnError::IoError(From::from(err))
+    }
+}
+
+impl From<TopicFilterError> for SnError {
+    fn from(err: TopicFilterError) -> SnError {
+        SnError::InvalidTopicFilter(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use control::variable_header::PacketIdentifier;
+    use packet::*;
+    use {Encodable, Decodable, QualityOfService, TopicFilter};
+
+    fn roundtrip(bytes: &[u8], message: SnMessage) {
+        assert_eq!(message, SnMessage::decode(&mut Cursor::new(bytes)).unwrap());
+        let mut buf = Vec::new();
+        message.encode(&mut buf).unwrap();
+        assert_eq!(bytes, &buf[..]);
+        assert_eq!(bytes.len() as u32, message.encoded_length());
+    }
+
+    #[test]
+    fn test_sn_message_encoding() {
+        // Layouts of MQTT-SN 1.2 sections 5.4.12 (PUBLISH), 5.4.15 (SUBSCRIBE), 5.4.10
+        // (REGISTER) and 5.4.11 (REGACK)
+        roundtrip(b"\x0b\x0c\x20\x00\x01\x00\x07\x32\x31\x2e\x35",
+                  SnMessage::Publish(SnPublish {
+                      dup: false,
+                      qos: SnQoS::Level1,
+                      retain: false,
+                      topic: SnTopic::Normal(1),
+                      msg_id: 7,
+                      data: b"21.5".to_vec(),
+                  }));
+        roundtrip(b"\x09\x0c\x72\x74\x31\x00\x00\x6f\x6e",
+                  SnMessage::Publish(SnPublish {
+                      dup: false,
+                      qos: SnQoS::MinusOne,
+                      retain: true,
+                      topic: SnTopic::Short(*b"t1"),
+                      msg_id: 0,
+                      data: b"on".to_vec(),
+                  }));
+        roundtrip(b"\x0e\x12\x20\x00\x02sensors/#",
+                  SnMessage::Subscribe(SnSubscribe {
+                      dup: false,
+                      qos: SnQoS::Level1,
+                      msg_id: 2,
+                      topic: SnSubscribeTopic::Name("sensors/#".to_owned()),
+                  }));
+        roundtrip(b"\x07\x12\x81\x00\x03\x00\x05",
+                  SnMessage::Subscribe(SnSubscribe {
+                      dup: true,
+                      qos: SnQoS::Level0,
+                      msg_id: 3,
+                      topic: SnSubscribeTopic::Predefined(5),
+                  }));
+        roundtrip(b"\x12\x0a\x00\x01\x00\x04sensors/temp",
+                  SnMessage::Register(SnRegister {
+                      topic_id: 1,
+                      msg_id: 4,
+                      topic_name: "sensors/temp".to_owned(),
+                  }));
+        roundtrip(b"\x07\x0b\x00\x01\x00\x04\x00",
+                  SnMessage::Regack(SnRegack {
+                      topic_id: 1,
+                      msg_id: 4,
+                      return_code: SnReturnCode::Accepted,
+                  }));
+    }
+
+    #[test]
+    fn test_sn_message_three_octet_length() {
+        let message = SnMessage::Publish(SnPublish {
+            dup: false,
+            qos: SnQoS::Level0,
+            retain: false,
+            topic: SnTopic::Predefined(9),
+            msg_id: 0,
+            data: vec![0xAB; 300],
+        });
+
+        let mut buf = Vec::new();
+        message.encode(&mut buf).unwrap();
+        assert_eq!(b"\x01\x01\x35\x0c\x01\x00\x09\x00\x00", &buf[..9]);
+        assert_eq!(309, buf.len());
+        assert_eq!(message, SnMessage::decode(&mut Cursor::new(&buf[..])).unwrap());
+    }
+
+    #[test]
+    fn test_sn_message_decode_errors() {
+        match SnMessage::decode(&mut Cursor::new(&b"\x0a\x0c\x20\x00"[..])) {
+            Err(SnError::InvalidLength) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+        match SnMessage::decode(&mut Cursor::new(&b"\x02\x16"[..])) {
+            Err(SnError::UnsupportedMsgType(0x16)) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+        match SnMessage::decode(&mut Cursor::new(&b"\x07\x0c\x03\x00\x01\x00\x00"[..])) {
+            Err(SnError::InvalidTopicIdType(3)) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_sn_topic_registry() {
+        let mut registry = TopicRegistry::new();
+        assert_eq!(1, registry.assign("sensors/temp").unwrap());
+        assert_eq!(2, registry.assign("sensors/hum").unwrap());
+        assert_eq!(1, registry.assign("sensors/temp").unwrap());
+        assert_eq!(Some("sensors/hum"), registry.lookup(2));
+
+        registry.register(3, "actuators/fan".to_owned());
+        assert_eq!(4, registry.assign("actuators/led").unwrap());
+        assert_eq!(Some(3), registry.topic_id("actuators/fan"));
+
+        // Re-registering an id replaces its name
+        registry.register(3, "actuators/pump".to_owned());
+        assert_eq!(None, registry.topic_id("actuators/fan"));
+        assert_eq!(Some("actuators/pump"), registry.lookup(3));
+    }
+
+    #[test]
+    fn test_sn_to_mqtt_publish() {
+        let mut registry = TopicRegistry::new();
+        registry.assign("sensors/temp").unwrap();
+
+        let message = SnMessage::decode(&mut Cursor::new(&b"\x0b\x0c\x30\x00\x01\x00\x07\x32\x31\x2e\x35"[..])).unwrap();
+        let publish = match message {
+            SnMessage::Publish(publish) => publish,
+            message => panic!("Unexpected message {:?}", message),
+        };
+        let mut expected = PublishPacket::new("sensors/temp".to_owned(),
+                                              QoSWithPacketIdentifier::Level1(PacketIdentifier::new(7).unwrap()),
+                                              b"21.5".to_vec());
+        expected.set_retain(true);
+        assert_eq!(expected, to_mqtt_publish(&publish, &registry).unwrap());
+
+        // QoS -1 is forwarded as QoS 0
+        let publish = SnPublish {
+            dup: false,
+            qos: SnQoS::MinusOne,
+            retain: false,
+            topic: SnTopic::Short(*b"t1"),
+            msg_id: 0,
+            data: b"on".to_vec(),
+        };
+        assert_eq!(PublishPacket::new("t1".to_owned(), QoSWithPacketIdentifier::Level0, b"on".to_vec()),
+                   to_mqtt_publish(&publish, &registry).unwrap());
+
+        let unknown = SnPublish { topic: SnTopic::Normal(9), ..publish.clone() };
+        match to_mqtt_publish(&unknown, &registry) {
+            Err(SnError::UnknownTopicId(9)) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+        let no_msg_id = SnPublish { qos: SnQoS::Level1, ..publish };
+        match to_mqtt_publish(&no_msg_id, &registry) {
+            Err(SnError::InvalidMsgId) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_sn_from_mqtt_publish() {
+        let mut registry = TopicRegistry::new();
+        registry.predefine(5, "config".to_owned());
+
+        let publish = PublishPacket::new("actuators/fan".to_owned(),
+                                         QoSWithPacketIdentifier::Level2(PacketIdentifier::new(9).unwrap()),
+                                         b"1".to_vec());
+        match from_mqtt_publish(&publish, &registry) {
+            Err(SnError::UnregisteredTopic(ref name)) if name == "actuators/fan" => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let topic_id = registry.assign("actuators/fan").unwrap();
+        let message = SnMessage::Publish(from_mqtt_publish(&publish, &registry).unwrap());
+        let mut buf = Vec::new();
+        message.encode(&mut buf).unwrap();
+        assert_eq!(b"\x08\x0c\x40\x00\x01\x00\x09\x31", &buf[..]);
+        assert_eq!(1, topic_id);
+
+        let short = PublishPacket::new("t1".to_owned(), QoSWithPacketIdentifier::Level0, b"on".to_vec());
+        assert_eq!(SnTopic::Short(*b"t1"), from_mqtt_publish(&short, &registry).unwrap().topic);
+        let predefined = PublishPacket::new("config".to_owned(), QoSWithPacketIdentifier::Level0, Vec::new());
+        assert_eq!(SnTopic::Predefined(5), from_mqtt_publish(&predefined, &registry).unwrap().topic);
+    }
+
+    #[test]
+    fn test_sn_to_mqtt_subscribe() {
+        let mut registry = TopicRegistry::new();
+        registry.predefine(5, "config".to_owned());
+
+        let subscribe = SnSubscribe {
+            dup: false,
+            qos: SnQoS::Level1,
+            msg_id: 2,
+            topic: SnSubscribeTopic::Name("sensors/#".to_owned()),
+        };
+        let expected = SubscribePacket::new(PacketIdentifier::new(2).unwrap(),
+                                            vec![(TopicFilter::new("sensors/#"), QualityOfService::Level1)]);
+        assert_eq!(expected, to_mqtt_subscribe(&subscribe, &registry).unwrap());
+
+        let predefined = SnSubscribe { topic: SnSubscribeTopic::Predefined(5), ..subscribe.clone() };
+        let expected = SubscribePacket::new(PacketIdentifier::new(2).unwrap(),
+                                            vec![(TopicFilter::new("config"), QualityOfService::Level1)]);
+        assert_eq!(expected, to_mqtt_subscribe(&predefined, &registry).unwrap());
+
+        let invalid = SnSubscribe { topic: SnSubscribeTopic::Name("sensors#".to_owned()), ..subscribe };
+        match to_mqtt_subscribe(&invalid, &registry) {
+            Err(SnError::InvalidTopicFilter(TopicFilterError::InvalidMultiLevelWildcard)) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+}
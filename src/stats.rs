@@ -0,0 +1,261 @@
+//! Counters of encoded and decoded packets
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use control::ControlType;
+use control::fixed_header::FixedHeaderError;
+use packet::{VariablePacket, VariablePacketError};
+use Encodable;
+
+const CONTROL_TYPES: [ControlType; 15] = [
+    ControlType::Connect,
+    ControlType::ConnectAcknowledgement,
+    ControlType::Publish,
+    ControlType::PublishAcknowledgement,
+    ControlType::PublishReceived,
+    ControlType::PublishRelease,
+    ControlType::PublishComplete,
+    ControlType::Subscribe,
+    ControlType::SubscribeAcknowledgement,
+    ControlType::Unsubscribe,
+    ControlType::UnsubscribeAcknowledgement,
+    ControlType::PingRequest,
+    ControlType::PingResponse,
+    ControlType::Disconnect,
+    ControlType::Auth,
+];
+
+#[derive(Debug, Default)]
+struct AtomicTraffic {
+    packets_in: AtomicU64,
+    bytes_in: AtomicU64,
+    packets_out: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+/// Packet counters that can be shared between connections, e.g. through an `Arc`
+///
+/// The counters are updated with relaxed atomics, a snapshot taken while packets are
+/// recorded may be slightly inconsistent between counters.
+#[derive(Debug, Default)]
+pub struct PacketStats {
+    traffic: [AtomicTraffic; 15],
+    largest_in: AtomicU64,
+    largest_out: AtomicU64,
+
+    io_errors: AtomicU64,
+    fixed_header_errors: AtomicU64,
+    packets_too_large: AtomicU64,
+    null_characters: AtomicU64,
+    malformed_packets: AtomicU64,
+}
+
+impl PacketStats {
+    pub fn new() -> PacketStats {
+        Default::default()
+    }
+
+    /// Records a packet that was encoded to `bytes` bytes and sent
+    pub fn record_encoded(&self, packet: &VariablePacket, bytes: u64) {
+        let traffic = self.traffic(packet.control_type());
+        traffic.packets_out.fetch_add(1, Ordering::Relaxed);
+        traffic.bytes_out.fetch_add(bytes, Ordering::Relaxed);
+        self.largest_out.fetch_max(bytes, Ordering::Relaxed);
+    }
+
+    /// Records a packet that was received and decoded from `bytes` bytes
+    pub fn record_decoded(&self, packet: &VariablePacket, bytes: u64) {
+        let traffic = self.traffic(packet.control_type());
+        traffic.packets_in.fetch_add(1, Ordering::Relaxed);
+        traffic.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+        self.largest_in.fetch_max(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts the error by its category, read timeouts are not errors and not counted
+    pub fn record_decode_error(&self, err: &VariablePacketError) {
+        let counter = match err {
+            &VariablePacketError::IoError(ref err) |
+            &VariablePacketError::FixedHeaderError(FixedHeaderError::IoError(ref err)) => match err.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted => return,
+                _ => &self.io_errors,
+            },
+            &VariablePacketError::FixedHeaderError(..) |
+            &VariablePacketError::UnrecognizedFixedHeader(..) => &self.fixed_header_errors,
+            &VariablePacketError::PacketTooLarge(..) => &self.packets_too_large,
+            &VariablePacketError::NullCharacter => &self.null_characters,
+            _ => &self.malformed_packets,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> PacketStatsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        PacketStatsSnapshot {
+            traffic: CONTROL_TYPES.iter()
+                .map(|&control_type| {
+                    let traffic = self.traffic(control_type);
+                    (control_type, Traffic {
+                        packets_in: load(&traffic.packets_in),
+                        bytes_in: load(&traffic.bytes_in),
+                        packets_out: load(&traffic.packets_out),
+                        bytes_out: load(&traffic.bytes_out),
+                    })
+                })
+                .collect(),
+            largest_in: load(&self.largest_in),
+            largest_out: load(&self.largest_out),
+            decode_errors: DecodeErrors {
+                io: load(&self.io_errors),
+                fixed_header: load(&self.fixed_header_errors),
+                packet_too_large: load(&self.packets_too_large),
+                null_character: load(&self.null_characters),
+                malformed: load(&self.malformed_packets),
+            },
+        }
+    }
+
+    fn traffic(&self, control_type: ControlType) -> &AtomicTraffic {
+        &self.traffic[control_type as usize - 1]
+    }
+}
+
+/// Records an encoded packet in optional stats, for types taking an `Option<Arc<PacketStats>>`
+pub fn record_encoded(stats: Option<&PacketStats>, packet: &VariablePacket) {
+    if let Some(stats) = stats {
+        stats.record_encoded(packet, packet.encoded_length() as u64);
+    }
+}
+
+/// Records the result of decoding a packet in optional stats
+pub fn record_decoded(stats: Option<&PacketStats>, result: &Result<VariablePacket, VariablePacketError>) {
+    if let Some(stats) = stats {
+        match result {
+            &Ok(ref packet) => stats.record_decoded(packet, packet.encoded_length() as u64),
+            &Err(ref err) => stats.record_decode_error(err),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct Traffic {
+    pub packets_in: u64,
+    pub bytes_in: u64,
+    pub packets_out: u64,
+    pub bytes_out: u64,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct DecodeErrors {
+    pub io: u64,
+    pub fixed_header: u64,
+    pub packet_too_large: u64,
+    pub null_character: u64,
+    /// Errors in the variable header or payload of a packet
+    pub malformed: u64,
+}
+
+impl DecodeErrors {
+    pub fn total(&self) -> u64 {
+        self.io + self.fixed_header + self.packet_too_large + self.null_character + self.malformed
+    }
+}
+
+/// Values of `PacketStats` at one point in time
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct PacketStatsSnapshot {
+    /// Counters of every control type, ordered by control type value
+    pub traffic: Vec<(ControlType, Traffic)>,
+    /// Size of the largest packet received, in bytes
+    pub largest_in: u64,
+    /// Size of the largest packet sent, in bytes
+    pub largest_out: u64,
+    pub decode_errors: DecodeErrors,
+}
+
+impl PacketStatsSnapshot {
+    pub fn get(&self, control_type: ControlType) -> Traffic {
+        self.traffic.iter()
+            .find(|&&(t, _)| t == control_type)
+            .map(|&(_, traffic)| traffic)
+            .unwrap_or_default()
+    }
+
+    /// Sum over all control types
+    pub fn total(&self) -> Traffic {
+        self.traffic.iter().fold(Traffic::default(), |total, &(_, traffic)| Traffic {
+            packets_in: total.packets_in + traffic.packets_in,
+            bytes_in: total.bytes_in + traffic.bytes_in,
+            packets_out: total.packets_out + traffic.packets_out,
+            bytes_out: total.bytes_out + traffic.bytes_out,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::{self, Cursor};
+
+    use control::ControlType;
+    use control::fixed_header::FixedHeaderError;
+    use packet::*;
+    use Encodable;
+
+    #[test]
+    fn test_packet_stats() {
+        let stats = PacketStats::new();
+
+        let publish = VariablePacket::new(PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level0, b"hello".to_vec()));
+        let ping = VariablePacket::new(PingreqPacket::new());
+        for packet in [&publish, &publish, &ping].iter() {
+            record_encoded(Some(&stats), packet);
+        }
+        assert_eq!(12, publish.encoded_length());
+
+        let inputs: [&[u8]; 6] = [
+            b"\xd0\x00",
+            b"\x40\x02\x00\x01",
+            b"\x40\x01\x00",
+            b"\xf0\x00",
+            b"\x30\x05\x00\x03a\x00b",
+            b"",
+        ];
+        for input in inputs.iter() {
+            let result = VariablePacket::decode_with_options(&mut Cursor::new(*input), &DecodeOptions::new());
+            record_decoded(Some(&stats), &result);
+        }
+
+        let snapshot = stats.snapshot();
+        assert_eq!(Traffic { packets_in: 0, bytes_in: 0, packets_out: 2, bytes_out: 24 },
+                   snapshot.get(ControlType::Publish));
+        assert_eq!(Traffic { packets_in: 0, bytes_in: 0, packets_out: 1, bytes_out: 2 },
+                   snapshot.get(ControlType::PingRequest));
+        assert_eq!(Traffic { packets_in: 1, bytes_in: 2, packets_out: 0, bytes_out: 0 },
+                   snapshot.get(ControlType::PingResponse));
+        assert_eq!(Traffic { packets_in: 1, bytes_in: 4, packets_out: 0, bytes_out: 0 },
+                   snapshot.get(ControlType::PublishAcknowledgement));
+        assert_eq!(Traffic { packets_in: 2, bytes_in: 6, packets_out: 3, bytes_out: 26 }, snapshot.total());
+        assert_eq!(4, snapshot.largest_in);
+        assert_eq!(12, snapshot.largest_out);
+        assert_eq!(DecodeErrors { io: 1, fixed_header: 1, packet_too_large: 0, null_character: 1, malformed: 1 },
+                   snapshot.decode_errors);
+        assert_eq!(4, snapshot.decode_errors.total());
+        assert_eq!(15, snapshot.traffic.len());
+
+        record_encoded(None, &publish);
+        assert_eq!(snapshot, stats.snapshot());
+    }
+
+    #[test]
+    fn test_packet_stats_ignores_timeouts() {
+        let stats = PacketStats::new();
+        let err = VariablePacketError::IoError(io::Error::new(io::ErrorKind::WouldBlock, "timeout"));
+        stats.record_decode_error(&err);
+        let err = FixedHeaderError::IoError(io::Error::new(io::ErrorKind::TimedOut, "timeout"));
+        stats.record_decode_error(&VariablePacketError::FixedHeaderError(err));
+        assert_eq!(0, stats.snapshot().decode_errors.total());
+    }
+}
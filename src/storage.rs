@@ -0,0 +1,399 @@
+//! Crash safe storage of packets
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+use byteorder::{self, BigEndian, ReadBytesExt, WriteBytesExt};
+
+use control::variable_header::ProtocolVersion;
+use packet::{DecodeOptions, VariablePacket, VariablePacketError};
+use Encodable;
+
+const ENTRY_HEADER_LENGTH: u64 = 8;
+
+/// Storage that can be shortened, for `PacketLog::truncate_after`
+pub trait Truncate {
+    fn truncate(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl Truncate for File {
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.set_len(len)
+    }
+}
+
+impl Truncate for Cursor<Vec<u8>> {
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().truncate(len as usize);
+        Ok(())
+    }
+}
+
+/// Append-only log of packets
+///
+/// Every entry is the length of the rest of the entry (4 bytes), the CRC-32 of the rest of the
+/// entry (4 bytes), the protocol level the packet is encoded for (1 byte) and the encoded packet.
+/// A write torn by a crash leaves a short or mismatching entry at the
+/// end, where `recover` stops.
+///
+/// After a restart, replay the log with `recover` and cut off what could not be recovered with
+/// `truncate_after(recover.valid_len())` before appending again.
+#[derive(Debug)]
+pub struct PacketLog<S> {
+    inner: S,
+    len: u64,
+}
+
+impl<S: Seek> PacketLog<S> {
+    /// Appends after the existing content of `inner`
+    pub fn new(mut inner: S) -> io::Result<PacketLog<S>> {
+        let len = try!(inner.seek(SeekFrom::End(0)));
+        Ok(PacketLog {
+            inner: inner,
+            len: len,
+        })
+    }
+
+    /// Length of the log in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Write + Seek> PacketLog<S> {
+    /// Appends an MQTT 3.1.1 packet and returns the offset of its entry
+    pub fn append(&mut self, packet: &VariablePacket) -> io::Result<u64> {
+        self.append_with_version(packet, ProtocolVersion::V3_1_1)
+    }
+
+    /// Appends a packet encoded for `version`, it is decoded for the same version by `recover`
+    pub fn append_with_version(&mut self, packet: &VariablePacket, version: ProtocolVersion) -> io::Result<u64> {
+        let mut buf = Vec::with_capacity(1 + packet.encoded_length() as usize);
+        buf.push(version.level());
+        try!(packet.encode(&mut buf).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string())));
+
+        let mut entry = Vec::with_capacity(ENTRY_HEADER_LENGTH as usize + buf.len());
+        try!(entry.write_u32::<BigEndian>(buf.len() as u32).map_err(byteorder_error));
+        try!(entry.write_u32::<BigEndian>(crc32(&buf)).map_err(byteorder_error));
+        entry.extend_from_slice(&buf);
+
+        let offset = self.len;
+        try!(self.inner.seek(SeekFrom::Start(offset)));
+        try!(self.inner.write_all(&entry));
+        try!(self.inner.flush());
+        self.len += entry.len() as u64;
+        Ok(offset)
+    }
+}
+
+impl<S: Write + Seek + Truncate> PacketLog<S> {
+    /// Drops everything from byte `offset` on, e.g. the entry appended at `offset` and those after it
+    pub fn truncate_after(&mut self, offset: u64) -> io::Result<()> {
+        if offset >= self.len {
+            return Ok(());
+        }
+        try!(self.inner.truncate(offset));
+        self.len = offset;
+        Ok(())
+    }
+}
+
+fn byteorder_error(err: byteorder::Error) -> io::Error {
+    From::from(err)
+}
+
+/// Replays the entries of a `PacketLog` from the start of `reader`
+pub fn recover<'a, R: Read + Seek>(mut reader: R) -> io::Result<Recover<'a, R>> {
+    let len = try!(reader.seek(SeekFrom::End(0)));
+    try!(reader.seek(SeekFrom::Start(0)));
+    Ok(Recover {
+        reader: reader,
+        len: len,
+        offset: 0,
+        done: false,
+        _marker: ::std::marker::PhantomData,
+    })
+}
+
+/// Iterator returned by `recover`
+///
+/// It ends after the first error, everything before `valid_len` was recovered.
+#[derive(Debug)]
+pub struct Recover<'a, R> {
+    reader: R,
+    len: u64,
+    offset: u64,
+    done: bool,
+    _marker: ::std::marker::PhantomData<&'a ()>,
+}
+
+impl<'a, R: Read + Seek> Recover<'a, R> {
+    /// Length of the entries recovered so far
+    pub fn valid_len(&self) -> u64 {
+        self.offset
+    }
+
+    fn read_entry(&mut self) -> Result<VariablePacket, RecoveryError<'a>> {
+        let offset = self.offset;
+        if self.len - offset < ENTRY_HEADER_LENGTH {
+            return Err(RecoveryError::Truncated(offset));
+        }
+
+        let length = try!(self.reader.read_u32::<BigEndian>().map_err(byteorder_error)) as u64;
+        let checksum = try!(self.reader.read_u32::<BigEndian>().map_err(byteorder_error));
+        if self.len - offset - ENTRY_HEADER_LENGTH < length {
+            return Err(RecoveryError::Truncated(offset));
+        }
+
+        let mut buf = Vec::with_capacity(length as usize);
+        try!((&mut self.reader).take(length).read_to_end(&mut buf));
+        if buf.len() as u64 != length {
+            return Err(RecoveryError::Truncated(offset));
+        }
+        if crc32(&buf) != checksum {
+            return Err(RecoveryError::ChecksumMismatch(offset));
+        }
+
+        let version = match buf.first().and_then(|&level| ProtocolVersion::from_level(level)) {
+            Some(version) => version,
+            None => return Err(RecoveryError::UnsupportedProtocolLevel(offset, buf.first().cloned())),
+        };
+        let options = DecodeOptions::new().protocol_version(version);
+        let mut cursor = Cursor::new(&buf[1..]);
+        let packet = try!(VariablePacket::decode_with_options(&mut cursor, &options)
+                              .map_err(|err| RecoveryError::VariablePacketError(offset, err)));
+        if cursor.position() + 1 != length {
+            return Err(RecoveryError::TrailingBytes(offset));
+        }
+
+        self.offset += ENTRY_HEADER_LENGTH + length;
+        Ok(packet)
+    }
+}
+
+impl<'a, R: Read + Seek> Iterator for Recover<'a, R> {
+    type Item = Result<VariablePacket, RecoveryError<'a>>;
+
+    fn next(&mut self) -> Option<Result<VariablePacket, RecoveryError<'a>>> {
+        if self.done || self.offset == self.len {
+            return None;
+        }
+
+        let result = self.read_entry();
+        self.done = result.is_err();
+        Some(result)
+    }
+}
+
+/// Why `recover` stopped early, the offsets are those of the entry that failed
+#[derive(Debug)]
+pub enum RecoveryError<'a> {
+    IoError(io::Error),
+    Truncated(u64),
+    ChecksumMismatch(u64),
+    /// The protocol level byte is missing or unknown
+    UnsupportedProtocolLevel(u64, Option<u8>),
+    VariablePacketError(u64, VariablePacketError<'a>),
+    TrailingBytes(u64),
+}
+
+impl<'a> fmt::Display for RecoveryError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &RecoveryError::IoError(ref err) => err.fmt(f),
+            &RecoveryError::Truncated(offset) => write!(f, "Truncated entry at offset {}", offset),
+            &RecoveryError::ChecksumMismatch(offset) => write!(f, "Checksum mismatch in entry at offset {}", offset),
+            &RecoveryError::UnsupportedProtocolLevel(offset, Some(level)) =>
+                write!(f, "Unsupported protocol level {} in entry at offset {}", level, offset),
+            &RecoveryError::UnsupportedProtocolLevel(offset, None) =>
+                write!(f, "Missing protocol level in entry at offset {}", offset),
+            &RecoveryError::VariablePacketError(offset, ref err) =>
+                write!(f, "Invalid packet in entry at offset {}: {}", offset, err),
+            &RecoveryError::TrailingBytes(offset) => write!(f, "Trailing bytes in entry at offset {}", offset),
+        }
+    }
+}
+
+impl<'a> Error for RecoveryError<'a> {
+    fn description(&self) -> &str {
+        match self {
+            &RecoveryError::IoError(ref err) => err.description(),
+            &RecoveryError::Truncated(..) => "Truncated entry",
+            &RecoveryError::ChecksumMismatch(..) => "Checksum mismatch",
+            &RecoveryError::UnsupportedProtocolLevel(..) => "Unsupported protocol level",
+            &RecoveryError::VariablePacketError(_, ref err) => err.description(),
+            &RecoveryError::TrailingBytes(..) => "Trailing bytes after the packet",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match self {
+            &RecoveryError::IoError(ref err) => Some(err),
+            &RecoveryError::VariablePacketError(_, ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> From<io::Error> for RecoveryError<'a> {
+    fn from(err: io::Error) -> RecoveryError<'a> {
+        RecoveryError::IoError(err)
+    }
+}
+
+/// CRC-32 (IEEE 802.3, as used by zlib)
+fn crc32(buf: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in buf {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xedb88320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use control::variable_header::DisconnectReasonCode;
+    use packet::*;
+
+    fn packets() -> Vec<VariablePacket> {
+        vec![
+            VariablePacket::new(PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level0, b"hello".to_vec())),
+            VariablePacket::new(PubrelPacket::new(10)),
+            VariablePacket::new(PingreqPacket::new()),
+        ]
+    }
+
+    fn write_log() -> (Vec<u8>, Vec<u64>) {
+        let mut log = PacketLog::new(Cursor::new(Vec::new())).unwrap();
+        let offsets = packets().iter().map(|packet| log.append(packet).unwrap()).collect();
+        (log.into_inner().into_inner(), offsets)
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(0, crc32(b""));
+        assert_eq!(0xcbf43926, crc32(b"123456789"));
+    }
+
+    #[test]
+    fn test_packet_log_round_trip() {
+        let (buf, offsets) = write_log();
+        assert_eq!(vec![0, 21, 34], offsets);
+        assert_eq!(45, buf.len());
+
+        let mut entries = recover(Cursor::new(&buf[..])).unwrap();
+        let recovered = (&mut entries).map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(packets(), recovered);
+        assert_eq!(45, entries.valid_len());
+
+        // Appending continues after the existing entries
+        let mut log = PacketLog::new(Cursor::new(buf)).unwrap();
+        assert_eq!(45, log.append(&VariablePacket::new(PingrespPacket::new())).unwrap());
+        assert_eq!(4, recover(Cursor::new(log.into_inner().into_inner())).unwrap().count());
+    }
+
+    #[test]
+    fn test_packet_log_torn_write() {
+        let (buf, offsets) = write_log();
+        for len in offsets[2] + 1..buf.len() as u64 {
+            let torn = &buf[..len as usize];
+            let mut entries = recover(Cursor::new(torn)).unwrap();
+            assert_eq!(packets()[..2].to_vec(), (&mut entries).take(2).map(Result::unwrap).collect::<Vec<_>>());
+            match entries.next() {
+                Some(Err(RecoveryError::Truncated(34))) => {},
+                res => panic!("Unexpected result {:?}", res),
+            }
+            assert!(entries.next().is_none());
+            assert_eq!(34, entries.valid_len());
+        }
+    }
+
+    #[test]
+    fn test_packet_log_corruption() {
+        let (mut buf, offsets) = write_log();
+        buf[offsets[1] as usize + 9] ^= 0x01;
+
+        let mut entries = recover(Cursor::new(buf.clone())).unwrap();
+        assert!(entries.next().unwrap().is_ok());
+        match entries.next() {
+            Some(Err(RecoveryError::ChecksumMismatch(21))) => {},
+            res => panic!("Unexpected result {:?}", res),
+        }
+        assert!(entries.next().is_none());
+
+        // Cut off the corrupt tail and continue
+        let valid_len = entries.valid_len();
+        let mut log = PacketLog::new(Cursor::new(buf)).unwrap();
+        log.truncate_after(valid_len).unwrap();
+        assert_eq!(21, log.append(&VariablePacket::new(PubrelPacket::new(11))).unwrap());
+
+        let recovered = recover(Cursor::new(log.into_inner().into_inner())).unwrap()
+            .map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(vec![packets()[0].clone(), VariablePacket::new(PubrelPacket::new(11))], recovered);
+    }
+
+    #[test]
+    fn test_packet_log_invalid_packet() {
+        // Valid checksum over bytes that are not a packet
+        fn entry(body: &[u8]) -> Vec<u8> {
+            let mut buf = Vec::new();
+            buf.write_u32::<BigEndian>(body.len() as u32).unwrap();
+            buf.write_u32::<BigEndian>(crc32(body)).unwrap();
+            buf.extend_from_slice(body);
+            buf
+        }
+
+        match recover(Cursor::new(entry(b"\x04\xf0\x00"))).unwrap().next() {
+            Some(Err(RecoveryError::VariablePacketError(0, _))) => {},
+            res => panic!("Unexpected result {:?}", res),
+        }
+        match recover(Cursor::new(entry(b"\x06\xc0\x00"))).unwrap().next() {
+            Some(Err(RecoveryError::UnsupportedProtocolLevel(0, Some(6)))) => {},
+            res => panic!("Unexpected result {:?}", res),
+        }
+        match recover(Cursor::new(entry(b""))).unwrap().next() {
+            Some(Err(RecoveryError::UnsupportedProtocolLevel(0, None))) => {},
+            res => panic!("Unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_packet_log_mqtt_5() {
+        let mut publish = PublishPacket::with_version("a/b".to_owned(), QoSWithPacketIdentifier::Level0,
+                                                      b"hello".to_vec(), ProtocolVersion::V5);
+        publish.set_content_type("text/plain");
+        let packets = vec![
+            VariablePacket::new(publish),
+            VariablePacket::new(DisconnectPacket::new_with_reason(DisconnectReasonCode::SessionTakenOver)),
+        ];
+
+        let mut log = PacketLog::new(Cursor::new(Vec::new())).unwrap();
+        for packet in packets.iter() {
+            log.append_with_version(packet, ProtocolVersion::V5).unwrap();
+        }
+        log.append(&VariablePacket::new(PingreqPacket::new())).unwrap();
+
+        let recovered = recover(Cursor::new(log.into_inner().into_inner())).unwrap()
+            .map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(&packets[..], &recovered[..2]);
+        assert_eq!(VariablePacket::new(PingreqPacket::new()), recovered[2]);
+    }
+}
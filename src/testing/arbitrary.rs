@@ -0,0 +1,536 @@
+//! Random spec-valid packets for property tests and fuzzing
+//!
+//! Generation is deterministic for a seed, a failing case is reproduced with `Gen::new(seed)`.
+
+use control::variable_header::{AuthReasonCode, ConnectReasonCode, ConnectReturnCode, DisconnectReasonCode};
+use control::variable_header::{PacketIdentifier, Properties, Property, ProtocolVersion, PubackReasonCode};
+use control::variable_header::{PubcompReasonCode, PubrecReasonCode, PubrelReasonCode, SubackReasonCode};
+use control::variable_header::{TopicName, UnsubackReasonCode};
+use packet::*;
+use packet::suback::SubscribeReturnCode;
+use {QualityOfService, TopicFilter};
+
+/// Characters of generated strings, including multi-byte ones but never U+0000
+const CHARS: &'static [char] = &['a', 'b', 'c', 'x', 'y', 'z', '0', '9', '-', '_', '.', ' ', '$', 'é', '€', '水', '🙂'];
+
+/// Source of random values, a xorshift generator
+#[derive(Debug, Clone)]
+pub struct Gen {
+    state: u64,
+    max_len: usize,
+    version: ProtocolVersion,
+}
+
+impl Gen {
+    pub fn new(seed: u64) -> Gen {
+        // The state must not be zero
+        let state = seed ^ 0x9e37_79b9_7f4a_7c15;
+        Gen {
+            state: if state == 0 { 1 } else { state },
+            max_len: 32,
+            version: ProtocolVersion::V3_1_1,
+        }
+    }
+
+    /// Upper bound of the length of generated strings, payloads and lists, 32 by default
+    pub fn max_len(mut self, max_len: usize) -> Gen {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Protocol version of generated packets, MQTT 3.1.1 by default
+    ///
+    /// MQTT 5 packets come with Reason Codes and properties, and include AUTH.
+    pub fn protocol_version(mut self, version: ProtocolVersion) -> Gen {
+        self.version = version;
+        self
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// A value in `low..high`
+    pub fn range(&mut self, low: usize, high: usize) -> usize {
+        assert!(low < high, "Empty range");
+        low + (self.next_u64() % (high - low) as u64) as usize
+    }
+
+    pub fn bool(&mut self) -> bool {
+        self.next_u64() & 1 == 1
+    }
+
+    pub fn choose<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.range(0, items.len())]
+    }
+
+    pub fn generate<T: Arbitrary>(&mut self) -> T {
+        T::arbitrary(self)
+    }
+
+    fn mqtt5(&self) -> bool {
+        self.version == ProtocolVersion::V5
+    }
+
+    fn len(&mut self) -> usize {
+        self.range(0, self.max_len + 1)
+    }
+
+    fn string(&mut self) -> String {
+        (0..self.len()).map(|_| *self.choose(CHARS)).collect()
+    }
+
+    fn bytes(&mut self) -> Vec<u8> {
+        (0..self.len()).map(|_| self.next_u64() as u8).collect()
+    }
+
+    fn option<T, F: FnOnce(&mut Gen) -> T>(&mut self, f: F) -> Option<T> {
+        if self.bool() { Some(f(self)) } else { None }
+    }
+
+    fn list<T: Arbitrary>(&mut self) -> Vec<T> {
+        let len = self.range(1, self.max_len.min(4).max(1) + 1);
+        (0..len).map(|_| self.generate()).collect()
+    }
+
+    // Levels of a topic, none of them containing a wildcard or a separator
+    fn levels(&mut self) -> Vec<String> {
+        let count = self.range(1, 5);
+        let max_level = (self.max_len / count).max(1);
+        (0..count).map(|_| {
+            let len = self.range(0, max_level + 1);
+            (0..len).map(|_| *self.choose(CHARS)).collect()
+        }).collect()
+    }
+}
+
+/// Types that can be generated from a `Gen`
+pub trait Arbitrary: Sized {
+    fn arbitrary(g: &mut Gen) -> Self;
+}
+
+impl Arbitrary for bool {
+    fn arbitrary(g: &mut Gen) -> bool {
+        g.bool()
+    }
+}
+
+impl Arbitrary for QualityOfService {
+    fn arbitrary(g: &mut Gen) -> QualityOfService {
+        *g.choose(&[QualityOfService::Level0, QualityOfService::Level1, QualityOfService::Level2])
+    }
+}
+
+impl Arbitrary for PacketIdentifier {
+    fn arbitrary(g: &mut Gen) -> PacketIdentifier {
+        PacketIdentifier::new(g.range(1, 0x10000) as u16).unwrap()
+    }
+}
+
+impl Arbitrary for QoSWithPacketIdentifier {
+    fn arbitrary(g: &mut Gen) -> QoSWithPacketIdentifier {
+        match g.generate() {
+            QualityOfService::Level0 => QoSWithPacketIdentifier::Level0,
+            QualityOfService::Level1 => QoSWithPacketIdentifier::Level1(g.generate()),
+            QualityOfService::Level2 => QoSWithPacketIdentifier::Level2(g.generate()),
+        }
+    }
+}
+
+/// Non-empty topic names of up to four levels
+impl Arbitrary for TopicName {
+    fn arbitrary(g: &mut Gen) -> TopicName {
+        let mut name = g.levels().join("/");
+        if name.is_empty() {
+            name.push('t');
+        }
+        TopicName(name)
+    }
+}
+
+/// Valid topic filters, with single-level wildcards and a trailing multi-level wildcard
+impl Arbitrary for TopicFilter {
+    fn arbitrary(g: &mut Gen) -> TopicFilter {
+        let mut levels = g.levels();
+        for level in levels.iter_mut() {
+            if g.range(0, 4) == 0 {
+                *level = "+".to_owned();
+            }
+        }
+        if g.range(0, 4) == 0 {
+            levels.push("#".to_owned());
+        }
+
+        let mut filter = levels.join("/");
+        if filter.is_empty() {
+            filter.push('#');
+        }
+        TopicFilter(filter)
+    }
+}
+
+impl Arbitrary for SubscribeReturnCode {
+    fn arbitrary(g: &mut Gen) -> SubscribeReturnCode {
+        *g.choose(&[SubscribeReturnCode::MaximumQoSLevel0,
+                    SubscribeReturnCode::MaximumQoSLevel1,
+                    SubscribeReturnCode::MaximumQoSLevel2,
+                    SubscribeReturnCode::Failure])
+    }
+}
+
+macro_rules! impl_arbitrary_for_reason_codes {
+    ($($name:ident,)+) => {
+        $(
+            /// Reason Codes defined by the specification
+            impl Arbitrary for $name {
+                fn arbitrary(g: &mut Gen) -> $name {
+                    loop {
+                        let code = $name::from_u8(g.next_u64() as u8);
+                        if code.is_defined() {
+                            return code;
+                        }
+                    }
+                }
+            }
+        )+
+    }
+}
+
+impl_arbitrary_for_reason_codes! {
+    ConnectReasonCode,
+    PubackReasonCode,
+    PubrecReasonCode,
+    PubrelReasonCode,
+    PubcompReasonCode,
+    SubackReasonCode,
+    UnsubackReasonCode,
+    DisconnectReasonCode,
+    AuthReasonCode,
+}
+
+impl Arbitrary for ConnectPacket {
+    fn arbitrary(g: &mut Gen) -> ConnectPacket {
+        let mut builder = ConnectPacket::builder(&g.string())
+            .protocol_version(g.version)
+            .keep_alive(g.next_u64() as u16)
+            .clean_session(g.bool());
+        if g.bool() {
+            let topic: TopicName = g.generate();
+            let message = g.bytes();
+            builder = builder.will(&topic.0, &message, g.generate(), g.bool());
+        }
+        if let Some(user_name) = g.option(Gen::string) {
+            builder = builder.user_name(&user_name);
+            if let Some(password) = g.option(Gen::bytes) {
+                builder = builder.password(&password);
+            }
+        }
+        let mut connect = builder.build().expect("Generated an invalid CONNECT");
+        if g.mqtt5() {
+            connect.set_session_expiry_interval(g.option(|g| g.next_u64() as u32));
+            connect.set_receive_maximum(g.option(|g| g.range(1, 0x10000) as u16)).unwrap();
+            connect.set_maximum_packet_size(g.option(|g| g.range(1, 0x1_0000_0000) as u32)).unwrap();
+            connect.set_topic_alias_maximum(g.option(|g| g.next_u64() as u16));
+        }
+        connect
+    }
+}
+
+impl Arbitrary for ConnackPacket {
+    fn arbitrary(g: &mut Gen) -> ConnackPacket {
+        if g.mqtt5() {
+            // MQTT-3.2.2-6
+            let code: ConnectReasonCode = g.generate();
+            let mut connack = ConnackPacket::with_reason_code(code.is_success() && g.bool(), code);
+            let mut properties = Properties::new();
+            if g.bool() {
+                properties.insert(Property::ReceiveMaximum(g.range(1, 0x10000) as u16));
+            }
+            if g.bool() {
+                properties.insert(Property::TopicAliasMaximum(g.next_u64() as u16));
+            }
+            if let Some(identifier) = g.option(Gen::string) {
+                properties.insert(Property::AssignedClientIdentifier(identifier));
+            }
+            connack.set_properties(properties);
+            connack.set_reason_string(g.option(Gen::string));
+            return connack;
+        }
+
+        match g.range(0, 6) {
+            0 => ConnackPacket::accepted(g.bool()),
+            code => ConnackPacket::rejected(ConnectReturnCode::from_u8(code as u8)),
+        }
+    }
+}
+
+/// PUBLISH packets with DUP only set on QoS 1 and QoS 2 messages
+impl Arbitrary for PublishPacket {
+    fn arbitrary(g: &mut Gen) -> PublishPacket {
+        let topic: TopicName = g.generate();
+        let qos = g.generate();
+        let mut publish = PublishPacket::with_version(topic.0, qos, g.bytes(), g.version);
+        publish.set_retain(g.bool());
+        if qos != QoSWithPacketIdentifier::Level0 && g.bool() {
+            publish.set_dup(true);
+        }
+        if g.mqtt5() {
+            if let Some(content_type) = g.option(Gen::string) {
+                publish.set_content_type(&content_type);
+            }
+            publish.set_topic_alias(g.option(|g| g.range(1, 0x10000) as u16));
+            if let Some(data) = g.option(Gen::bytes) {
+                publish.set_correlation_data(data);
+            }
+        }
+        publish
+    }
+}
+
+macro_rules! impl_arbitrary_for_acknowledgements {
+    ($($name:ident,)+) => {
+        $(
+            impl Arbitrary for $name {
+                fn arbitrary(g: &mut Gen) -> $name {
+                    let pkid = g.generate::<PacketIdentifier>().get();
+                    if !g.mqtt5() {
+                        return $name::new(pkid);
+                    }
+                    let mut pk = $name::new_with_reason(pkid, g.generate());
+                    pk.set_reason_string(g.option(Gen::string));
+                    pk
+                }
+            }
+        )+
+    }
+}
+
+impl_arbitrary_for_acknowledgements! {
+    PubackPacket,
+    PubrecPacket,
+    PubrelPacket,
+    PubcompPacket,
+}
+
+impl Arbitrary for SubscribePacket {
+    fn arbitrary(g: &mut Gen) -> SubscribePacket {
+        let pkid = g.generate();
+        if !g.mqtt5() {
+            let subscriptions = (0..g.range(1, 5)).map(|_| (g.generate(), g.generate())).collect();
+            return SubscribePacket::new(pkid, subscriptions);
+        }
+
+        let subscriptions = (0..g.range(1, 5)).map(|_| {
+            let options = SubscriptionOptions {
+                qos: g.generate(),
+                no_local: g.bool(),
+                retain_as_published: g.bool(),
+                retain_handling: *g.choose(&[RetainHandling::SendAtSubscribe,
+                                             RetainHandling::SendAtSubscribeIfNew,
+                                             RetainHandling::DoNotSend]),
+            };
+            (g.generate(), options)
+        }).collect();
+        let mut subscribe = SubscribePacket::with_options(pkid, subscriptions);
+        subscribe.set_subscription_identifier(g.option(|g| g.range(1, 0x1000_0000) as u32)).unwrap();
+        subscribe
+    }
+}
+
+impl Arbitrary for SubackPacket {
+    fn arbitrary(g: &mut Gen) -> SubackPacket {
+        let pkid = g.generate::<PacketIdentifier>();
+        if !g.mqtt5() {
+            return SubackPacket::new(pkid.get(), g.list());
+        }
+        let mut suback = SubackPacket::with_reason_codes(pkid.get(), g.list());
+        suback.set_reason_string(g.option(Gen::string));
+        suback
+    }
+}
+
+impl Arbitrary for UnsubscribePacket {
+    fn arbitrary(g: &mut Gen) -> UnsubscribePacket {
+        let pkid = g.generate();
+        let mut unsubscribe = UnsubscribePacket::new(pkid, g.list()).expect("Generated an empty UNSUBSCRIBE");
+        if g.mqtt5() {
+            unsubscribe.set_protocol_version(ProtocolVersion::V5);
+            let mut properties = Properties::new();
+            if let Some(value) = g.option(Gen::string) {
+                properties.insert(Property::UserProperty(g.string(), value));
+            }
+            unsubscribe.set_properties(properties);
+        }
+        unsubscribe
+    }
+}
+
+impl Arbitrary for UnsubackPacket {
+    fn arbitrary(g: &mut Gen) -> UnsubackPacket {
+        let pkid = g.generate::<PacketIdentifier>();
+        if !g.mqtt5() {
+            return UnsubackPacket::new(pkid.get());
+        }
+        let mut unsuback = UnsubackPacket::with_reasons(pkid.get(), g.list());
+        unsuback.set_reason_string(g.option(Gen::string));
+        unsuback
+    }
+}
+
+impl Arbitrary for PingreqPacket {
+    fn arbitrary(_: &mut Gen) -> PingreqPacket {
+        PingreqPacket::new()
+    }
+}
+
+impl Arbitrary for PingrespPacket {
+    fn arbitrary(_: &mut Gen) -> PingrespPacket {
+        PingrespPacket::new()
+    }
+}
+
+impl Arbitrary for DisconnectPacket {
+    fn arbitrary(g: &mut Gen) -> DisconnectPacket {
+        if !g.mqtt5() {
+            return DisconnectPacket::new();
+        }
+        let mut disconnect = DisconnectPacket::new_with_reason(g.generate());
+        disconnect.set_reason_string(g.option(Gen::string));
+        disconnect
+    }
+}
+
+/// AUTH packets, only valid in MQTT 5
+impl Arbitrary for AuthPacket {
+    fn arbitrary(g: &mut Gen) -> AuthPacket {
+        let reason_code: AuthReasonCode = g.generate();
+        let mut auth = if reason_code != AuthReasonCode::Success || g.bool() {
+            AuthPacket::with_data(reason_code, g.string(), g.bytes())
+        } else {
+            AuthPacket::new(reason_code)
+        };
+        auth.set_reason_string(g.option(Gen::string));
+        auth
+    }
+}
+
+/// Any packet of the protocol version, every control type being equally likely
+impl Arbitrary for VariablePacket {
+    fn arbitrary(g: &mut Gen) -> VariablePacket {
+        let control_types = if g.mqtt5() { 15 } else { 14 };
+        match g.range(0, control_types) {
+            0 => VariablePacket::new(g.generate::<ConnectPacket>()),
+            1 => VariablePacket::new(g.generate::<ConnackPacket>()),
+            2 => VariablePacket::new(g.generate::<PublishPacket>()),
+            3 => VariablePacket::new(g.generate::<PubackPacket>()),
+            4 => VariablePacket::new(g.generate::<PubrecPacket>()),
+            5 => VariablePacket::new(g.generate::<PubrelPacket>()),
+            6 => VariablePacket::new(g.generate::<PubcompPacket>()),
+            7 => VariablePacket::new(g.generate::<SubscribePacket>()),
+            8 => VariablePacket::new(g.generate::<SubackPacket>()),
+            9 => VariablePacket::new(g.generate::<UnsubscribePacket>()),
+            10 => VariablePacket::new(g.generate::<UnsubackPacket>()),
+            11 => VariablePacket::new(g.generate::<PingreqPacket>()),
+            12 => VariablePacket::new(g.generate::<PingrespPacket>()),
+            13 => VariablePacket::new(g.generate::<DisconnectPacket>()),
+            _ => VariablePacket::new(g.generate::<AuthPacket>()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use testing::DiffMode;
+    use Encodable;
+
+    const CASES: u64 = 2000;
+
+    fn check_round_trip(version: ProtocolVersion) {
+        let options = DecodeOptions::new().minimal_remaining_length(true).protocol_version(version);
+        for seed in 0..CASES {
+            let packet: VariablePacket = Gen::new(seed).protocol_version(version).generate();
+
+            let mut encoded = Vec::new();
+            packet.encode(&mut encoded).unwrap_or_else(|err| panic!("seed {}: {}", seed, err));
+
+            let mut reader = Cursor::new(&encoded[..]);
+            let decoded = VariablePacket::decode_with_options(&mut reader, &options)
+                .unwrap_or_else(|err| panic!("seed {}: {} for {:?}", seed, err, packet));
+            assert_eq!(encoded.len() as u64, reader.position(), "seed {}", seed);
+            assert_packets_eq!(packet, decoded, DiffMode::Exact);
+        }
+    }
+
+    #[test]
+    fn test_arbitrary_packets_round_trip() {
+        check_round_trip(ProtocolVersion::V3_1_1);
+    }
+
+    #[test]
+    fn test_arbitrary_mqtt_5_packets_round_trip() {
+        check_round_trip(ProtocolVersion::V5);
+    }
+
+    #[test]
+    fn test_arbitrary_packets_encoded_length() {
+        for version in [ProtocolVersion::V3_1_1, ProtocolVersion::V5].iter() {
+            for seed in 0..CASES {
+                let packet: VariablePacket = Gen::new(seed).max_len(300).protocol_version(*version).generate();
+
+                let mut encoded = Vec::new();
+                packet.encode(&mut encoded).unwrap();
+                assert_eq!(encoded.len() as u32, packet.encoded_length(), "seed {}: {:?}", seed, packet);
+            }
+        }
+    }
+
+    #[test]
+    fn test_arbitrary_mqtt_5_packets() {
+        let mut g = Gen::new(2).protocol_version(ProtocolVersion::V5);
+        let packets: Vec<VariablePacket> = (0..CASES).map(|_| g.generate()).collect();
+        assert!(packets.iter().any(|packet| match packet {
+            &VariablePacket::AuthPacket(..) => true,
+            _ => false,
+        }));
+        assert!(packets.iter().all(|packet| match packet {
+            &VariablePacket::ConnectPacket(ref pk) => pk.protocol_version() == Some(ProtocolVersion::V5),
+            &VariablePacket::PublishPacket(ref pk) => pk.is_mqtt5(),
+            &VariablePacket::SubscribePacket(ref pk) => pk.is_mqtt5(),
+            &VariablePacket::SubackPacket(ref pk) => pk.is_mqtt5(),
+            &VariablePacket::UnsubscribePacket(ref pk) => pk.is_mqtt5(),
+            _ => true,
+        }));
+    }
+
+    #[test]
+    fn test_arbitrary_values_are_valid() {
+        let mut g = Gen::new(1);
+        for _ in 0..CASES {
+            let filter: TopicFilter = g.generate();
+            assert_eq!(Ok(()), filter.validate(), "{:?}", filter);
+
+            let topic: TopicName = g.generate();
+            assert!(!topic.0.is_empty() && !topic.contains_wildcard() && !topic.0.contains('\0'), "{:?}", topic);
+
+            let publish: PublishPacket = g.generate();
+            assert!(!publish.dup() || publish.qos() != QoSWithPacketIdentifier::Level0);
+        }
+    }
+
+    #[test]
+    fn test_gen_is_deterministic() {
+        let first: Vec<VariablePacket> = (0..20).map(|_| Gen::new(7).generate()).collect();
+        assert!(first.iter().all(|packet| *packet == first[0]));
+
+        let mut g = Gen::new(7);
+        let packets: Vec<VariablePacket> = (0..50).map(|_| g.generate()).collect();
+        assert!(packets.iter().any(|packet| *packet != packets[0]));
+    }
+}
@@ -0,0 +1,582 @@
+//! One-line text representation of packets
+//!
+//! ```text
+//! CONNECT client=dev1 keepalive=30 clean user=bob pass=hex:001122
+//! PUBLISH topic=a/b qos=1 pkid=10 retain payload="hello"
+//! SUBSCRIBE pkid=1 filter=a/+:1 filter=c/#:2
+//! SUBACK pkid=1 codes=1,128
+//! ```
+//!
+//! A line is the control type followed by `key=value` arguments and bare flags. Values are
+//! bare words, `"quoted"` strings with `\"`, `\\`, `\n`, `\r`, `\t` and `\0` escapes, or
+//! `hex:` bytes for payloads and passwords. `to_dsl` gives the line `parse` reads back.
+
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::str::{self, FromStr};
+
+use control::variable_header::{ConnectReturnCode, PacketIdentifier, ProtocolVersion, TopicName};
+use packet::*;
+use packet::suback::SubscribeReturnCode;
+use {QualityOfService, TopicFilter};
+
+/// Failure of `parse`, pointing at the offending token
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct DslError {
+    /// Column of the token, starting at 1
+    pub column: usize,
+    pub token: String,
+    pub message: String,
+}
+
+impl fmt::Display for DslError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "column {}: {} (`{}`)", self.column, self.message, self.token)
+    }
+}
+
+impl Error for DslError {
+    fn description(&self) -> &str {
+        "Invalid packet description"
+    }
+}
+
+struct Token<'a> {
+    column: usize,
+    text: &'a str,
+}
+
+impl<'a> Token<'a> {
+    fn error<T>(&self, message: &str) -> Result<T, DslError> {
+        Err(DslError {
+            column: self.column,
+            token: self.text.to_owned(),
+            message: message.to_owned(),
+        })
+    }
+
+    fn key(&self) -> &'a str {
+        self.text.splitn(2, '=').next().unwrap()
+    }
+
+    fn raw_value(&self) -> Result<&'a str, DslError> {
+        match self.text.find('=') {
+            Some(index) => Ok(&self.text[index + 1..]),
+            None => self.error("Expected a value"),
+        }
+    }
+
+    fn string(&self) -> Result<String, DslError> {
+        let value = try!(self.raw_value());
+        if !value.starts_with('"') {
+            return Ok(value.to_owned());
+        }
+
+        let mut unquoted = String::new();
+        let mut chars = value[1..].chars();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => unquoted.push(match chars.next() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('0') => '\0',
+                    _ => return self.error("Invalid escape"),
+                }),
+                Some(c) => unquoted.push(c),
+                None => return self.error("Unterminated string"),
+            }
+        }
+        if chars.next().is_some() {
+            return self.error("Unexpected characters after the string");
+        }
+        Ok(unquoted)
+    }
+
+    fn bytes(&self) -> Result<Vec<u8>, DslError> {
+        let value = try!(self.raw_value());
+        if !value.starts_with("hex:") {
+            return self.string().map(String::into_bytes);
+        }
+
+        let hex = &value[4..];
+        if hex.len() % 2 != 0 {
+            return self.error("Odd number of hex digits");
+        }
+        (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).or_else(|_| self.error("Invalid hex digits")))
+            .collect()
+    }
+
+    fn number<T: FromStr>(&self) -> Result<T, DslError> {
+        let value = try!(self.raw_value());
+        value.parse().or_else(|_| self.error("Invalid number"))
+    }
+
+    fn qos(&self) -> Result<QualityOfService, DslError> {
+        match try!(self.number::<u8>()) {
+            0 => Ok(QualityOfService::Level0),
+            1 => Ok(QualityOfService::Level1),
+            2 => Ok(QualityOfService::Level2),
+            _ => self.error("Invalid QoS"),
+        }
+    }
+
+    fn packet_identifier(&self) -> Result<PacketIdentifier, DslError> {
+        PacketIdentifier::new(try!(self.number())).map_or_else(|| self.error("Packet identifier must be non-zero"), Ok)
+    }
+}
+
+/// Arguments of a line, each taken at most once
+struct Args<'a> {
+    command: Token<'a>,
+    args: Vec<Option<Token<'a>>>,
+}
+
+impl<'a> Args<'a> {
+    fn take(&mut self, key: &str) -> Result<Option<Token<'a>>, DslError> {
+        let mut found = self.take_all(key).into_iter();
+        let first = found.next();
+        match found.next() {
+            Some(repeated) => repeated.error("Repeated argument"),
+            None => Ok(first),
+        }
+    }
+
+    fn take_all(&mut self, key: &str) -> Vec<Token<'a>> {
+        self.args.iter_mut()
+            .filter(|arg| arg.as_ref().map_or(false, |token| token.key() == key))
+            .map(|arg| arg.take().unwrap())
+            .collect()
+    }
+
+    fn required(&mut self, key: &str) -> Result<Token<'a>, DslError> {
+        match try!(self.take(key)) {
+            Some(token) => Ok(token),
+            None => self.command.error(&format!("Missing `{}`", key)),
+        }
+    }
+
+    fn flag(&mut self, key: &str) -> Result<bool, DslError> {
+        match try!(self.take(key)) {
+            Some(ref token) if token.text != key => token.error("Flags take no value"),
+            Some(..) => Ok(true),
+            None => Ok(false),
+        }
+    }
+
+    fn finish(self) -> Result<(), DslError> {
+        match self.args.into_iter().filter_map(|arg| arg).next() {
+            Some(token) => token.error("Unknown argument"),
+            None => Ok(()),
+        }
+    }
+}
+
+fn tokenize<'a>(line: &'a str) -> Result<Vec<Token<'a>>, DslError> {
+    let mut tokens = Vec::new();
+    let mut chars = line.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let mut end = line.len();
+        let mut quoted = false;
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => quoted = !quoted,
+                '\\' if quoted => { chars.next(); },
+                c if c.is_whitespace() && !quoted => {
+                    end = index;
+                    break;
+                },
+                _ => {},
+            }
+        }
+
+        let token = Token {
+            column: line[..start].chars().count() + 1,
+            text: &line[start..end],
+        };
+        if quoted {
+            return token.error("Unterminated string");
+        }
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Parses one packet, e.g. `PUBLISH topic=a/b qos=1 pkid=10 payload="hello"`
+pub fn parse(line: &str) -> Result<VariablePacket, DslError> {
+    let mut tokens = try!(tokenize(line)).into_iter();
+    let command = match tokens.next() {
+        Some(token) => token,
+        None => return Err(DslError { column: 1, token: String::new(), message: "Empty line".to_owned() }),
+    };
+    let mut args = Args {
+        command: Token { column: command.column, text: command.text },
+        args: tokens.map(Some).collect(),
+    };
+
+    let packet = match command.text {
+        "CONNECT" => try!(parse_connect(&mut args)),
+        "CONNACK" => {
+            let session_present = try!(args.flag("session_present"));
+            let code = match try!(args.take("code")) {
+                Some(token) => ConnectReturnCode::from_u8(try!(token.number())),
+                None => ConnectReturnCode::ConnectionAccepted,
+            };
+            VariablePacket::new(ConnackPacket::new(session_present, code))
+        },
+        "PUBLISH" => try!(parse_publish(&mut args)),
+        "PUBACK" => VariablePacket::new(PubackPacket::new(try!(try!(args.required("pkid")).packet_identifier()).get())),
+        "PUBREC" => VariablePacket::new(PubrecPacket::new(try!(try!(args.required("pkid")).packet_identifier()).get())),
+        "PUBREL" => VariablePacket::new(PubrelPacket::new(try!(try!(args.required("pkid")).packet_identifier()).get())),
+        "PUBCOMP" => VariablePacket::new(PubcompPacket::new(try!(try!(args.required("pkid")).packet_identifier()).get())),
+        "SUBSCRIBE" => {
+            let pkid = try!(try!(args.required("pkid")).packet_identifier());
+            let mut subscriptions = Vec::new();
+            for token in args.take_all("filter") {
+                let value = try!(token.string());
+                let (filter, qos) = match value.rfind(':') {
+                    Some(index) => (&value[..index], &value[index + 1..]),
+                    None => return token.error("Expected `filter:qos`"),
+                };
+                let qos = match qos {
+                    "0" => QualityOfService::Level0,
+                    "1" => QualityOfService::Level1,
+                    "2" => QualityOfService::Level2,
+                    _ => return token.error("Invalid QoS"),
+                };
+                subscriptions.push((TopicFilter::new(filter), qos));
+            }
+            if subscriptions.is_empty() {
+                return args.command.error("Missing `filter`");
+            }
+            VariablePacket::new(SubscribePacket::new(pkid, subscriptions))
+        },
+        "SUBACK" => {
+            let pkid = try!(try!(args.required("pkid")).packet_identifier());
+            let token = try!(args.required("codes"));
+            let mut codes = Vec::new();
+            for code in try!(token.raw_value()).split(',') {
+                match code.parse().ok().and_then(SubscribeReturnCode::from_u8) {
+                    Some(code) => codes.push(code),
+                    None => return token.error("Invalid return code"),
+                }
+            }
+            VariablePacket::new(SubackPacket::new(pkid.get(), codes))
+        },
+        "UNSUBSCRIBE" => {
+            let pkid = try!(try!(args.required("pkid")).packet_identifier());
+            let mut filters = Vec::new();
+            for token in args.take_all("filter") {
+                filters.push(TopicFilter::new(try!(token.string())));
+            }
+            match UnsubscribePacket::new(pkid, filters) {
+                Ok(packet) => VariablePacket::new(packet),
+                Err(..) => return args.command.error("Missing `filter`"),
+            }
+        },
+        "UNSUBACK" => VariablePacket::new(UnsubackPacket::new(try!(try!(args.required("pkid")).packet_identifier()).get())),
+        "PINGREQ" => VariablePacket::new(PingreqPacket::new()),
+        "PINGRESP" => VariablePacket::new(PingrespPacket::new()),
+        "DISCONNECT" => VariablePacket::new(DisconnectPacket::new()),
+        _ => return command.error("Unknown packet type"),
+    };
+
+    try!(args.finish());
+    Ok(packet)
+}
+
+fn parse_connect(args: &mut Args) -> Result<VariablePacket, DslError> {
+    let client = try!(try!(args.required("client")).string());
+    let mut builder = ConnectPacket::builder(&client).clean_session(try!(args.flag("clean")));
+
+    if let Some(token) = try!(args.take("level")) {
+        builder = builder.protocol_version(match try!(token.number::<u8>()) {
+            3 => ProtocolVersion::V3_1,
+            4 => ProtocolVersion::V3_1_1,
+            _ => return token.error("Unsupported protocol level"),
+        });
+    }
+    if let Some(token) = try!(args.take("keepalive")) {
+        builder = builder.keep_alive(try!(token.number()));
+    }
+    if let Some(token) = try!(args.take("will_topic")) {
+        let topic = try!(token.string());
+        let message = match try!(args.take("will_payload")) {
+            Some(token) => try!(token.bytes()),
+            None => Vec::new(),
+        };
+        let qos = match try!(args.take("will_qos")) {
+            Some(token) => try!(token.qos()),
+            None => QualityOfService::Level0,
+        };
+        builder = builder.will(&topic, &message, qos, try!(args.flag("will_retain")));
+    }
+    if let Some(token) = try!(args.take("user")) {
+        builder = builder.user_name(&try!(token.string()));
+    }
+    if let Some(token) = try!(args.take("pass")) {
+        builder = builder.password(&try!(token.bytes()));
+    }
+
+    match builder.build() {
+        Ok(connect) => Ok(VariablePacket::new(connect)),
+        Err(err) => args.command.error(&err.to_string()),
+    }
+}
+
+fn parse_publish(args: &mut Args) -> Result<VariablePacket, DslError> {
+    let topic_token = try!(args.required("topic"));
+    let topic = match TopicName::new(try!(topic_token.string())) {
+        Some(topic) => topic,
+        None => return topic_token.error("Topic name contains wildcards"),
+    };
+
+    let qos = match try!(args.take("qos")) {
+        Some(token) => try!(token.qos()),
+        None => QualityOfService::Level0,
+    };
+    let qos = match (qos, try!(args.take("pkid"))) {
+        (QualityOfService::Level0, None) => QoSWithPacketIdentifier::Level0,
+        (QualityOfService::Level1, Some(token)) => QoSWithPacketIdentifier::Level1(try!(token.packet_identifier())),
+        (QualityOfService::Level2, Some(token)) => QoSWithPacketIdentifier::Level2(try!(token.packet_identifier())),
+        (QualityOfService::Level0, Some(token)) => return token.error("QoS 0 messages have no packet identifier"),
+        (_, None) => return args.command.error("Missing `pkid`"),
+    };
+    let payload = match try!(args.take("payload")) {
+        Some(token) => try!(token.bytes()),
+        None => Vec::new(),
+    };
+
+    let mut publish = PublishPacket::new(topic.0, qos, payload);
+    publish.set_retain(try!(args.flag("retain")));
+    if let Some(token) = try!(args.take("dup")) {
+        if qos == QoSWithPacketIdentifier::Level0 {
+            return token.error("QoS 0 messages cannot be DUP");
+        }
+        publish.set_dup(true);
+    }
+    Ok(VariablePacket::new(publish))
+}
+
+/// The line `parse` reads back into `packet`
+pub fn to_dsl(packet: &VariablePacket) -> String {
+    let mut line = packet.control_type().to_string();
+    {
+        let mut arg = |key: &str, value: Option<String>| {
+            match value {
+                Some(value) => write!(line, " {}={}", key, value),
+                None => write!(line, " {}", key),
+            }.unwrap();
+        };
+
+        match packet {
+            &VariablePacket::ConnectPacket(ref pk) => {
+                arg("client", Some(string(pk.client_identifier())));
+                if pk.protocol_version() == Some(ProtocolVersion::V3_1) {
+                    arg("level", Some("3".to_owned()));
+                }
+                if pk.keep_alive() != 0 {
+                    arg("keepalive", Some(pk.keep_alive().to_string()));
+                }
+                if pk.clean_session() {
+                    arg("clean", None);
+                }
+                if let Some(will) = pk.will() {
+                    arg("will_topic", Some(string(&will.topic.0)));
+                    arg("will_payload", Some(bytes(&will.message)));
+                    arg("will_qos", Some(will.qos.to_string()));
+                    if will.retain {
+                        arg("will_retain", None);
+                    }
+                }
+                if let Some(user_name) = pk.user_name() {
+                    arg("user", Some(string(user_name)));
+                }
+                if let Some(password) = pk.password() {
+                    arg("pass", Some(bytes(password)));
+                }
+            },
+            &VariablePacket::ConnackPacket(ref pk) => {
+                if pk.session_present() {
+                    arg("session_present", None);
+                }
+                arg("code", Some(pk.connect_return_code().to_u8().to_string()));
+            },
+            &VariablePacket::PublishPacket(ref pk) => {
+                arg("topic", Some(string(pk.topic_name())));
+                arg("qos", Some(pk.qos().qos().to_string()));
+                if let Some(pkid) = packet.packet_identifier() {
+                    arg("pkid", Some(pkid.to_string()));
+                }
+                if pk.retain() {
+                    arg("retain", None);
+                }
+                if pk.dup() {
+                    arg("dup", None);
+                }
+                arg("payload", Some(bytes(pk.payload())));
+            },
+            &VariablePacket::SubscribePacket(ref pk) => {
+                arg("pkid", Some(pk.packet_identifier().to_string()));
+                for (filter, qos) in pk.subscriptions() {
+                    arg("filter", Some(string(&format!("{}:{}", filter.as_str(), qos))));
+                }
+            },
+            &VariablePacket::SubackPacket(ref pk) => {
+                arg("pkid", Some(pk.packet_identifier().to_string()));
+                let codes: Vec<String> = pk.return_codes().iter().map(|code| code.to_u8().to_string()).collect();
+                arg("codes", Some(codes.join(",")));
+            },
+            &VariablePacket::UnsubscribePacket(ref pk) => {
+                arg("pkid", Some(pk.packet_identifier().to_string()));
+                for filter in pk.topic_filters() {
+                    arg("filter", Some(string(filter.as_str())));
+                }
+            },
+            _ => {
+                if let Some(pkid) = packet.packet_identifier() {
+                    arg("pkid", Some(pkid.to_string()));
+                }
+            },
+        }
+    }
+    line
+}
+
+// Bare unless the string needs quotes to be read back
+fn string(s: &str) -> String {
+    let bare = !s.is_empty() && !s.starts_with('"') && !s.starts_with("hex:")
+        && !s.chars().any(|c| c.is_whitespace() || c.is_control() || c == '\\');
+    if bare {
+        return s.to_owned();
+    }
+
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\0' => quoted.push_str("\\0"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Quoted if it is text, hex otherwise
+fn bytes(bytes: &[u8]) -> String {
+    match str::from_utf8(bytes) {
+        Ok(s) if !s.chars().any(|c| c.is_control() && !"\n\r\t".contains(c)) => {
+            let quoted = string(s);
+            if quoted.starts_with('"') { quoted } else { format!("\"{}\"", quoted) }
+        },
+        _ => {
+            let mut hex = String::from("hex:");
+            for b in bytes {
+                write!(hex, "{:02x}", b).unwrap();
+            }
+            hex
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dsl_round_trip() {
+        let lines = [
+            "CONNECT client=dev1 keepalive=30 clean user=bob pass=hex:001122",
+            "CONNECT client=\"two words\" level=3 will_topic=w will_payload=\"bye\" will_qos=1 will_retain",
+            "CONNACK session_present code=0",
+            "CONNACK code=5",
+            "PUBLISH topic=a/b qos=1 pkid=10 retain payload=\"hello\"",
+            "PUBLISH topic=a/b qos=2 pkid=65535 dup payload=hex:ff00",
+            "PUBLISH topic=\"a b\" qos=0 payload=\"say \\\"hi\\\"\\n\"",
+            "PUBLISH topic=a qos=0 payload=\"\"",
+            "PUBACK pkid=1",
+            "PUBREC pkid=2",
+            "PUBREL pkid=3",
+            "PUBCOMP pkid=4",
+            "SUBSCRIBE pkid=1 filter=a/+:1 filter=c/#:2",
+            "SUBACK pkid=1 codes=1,128",
+            "UNSUBSCRIBE pkid=7 filter=a/+ filter=\"x y\"",
+            "UNSUBACK pkid=7",
+            "PINGREQ",
+            "PINGRESP",
+            "DISCONNECT",
+        ];
+
+        for line in lines.iter() {
+            let packet = parse(line).unwrap_or_else(|err| panic!("{}: {}", line, err));
+            assert_eq!(*line, to_dsl(&packet));
+            assert_eq!(packet, parse(&to_dsl(&packet)).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_dsl_parse_values() {
+        let packet = parse("  PUBLISH  payload=\"a b\"   topic=a/b qos=1 pkid=10 retain").unwrap();
+        let mut expected = PublishPacket::new("a/b".to_owned(),
+                                              QoSWithPacketIdentifier::Level1(PacketIdentifier::new(10).unwrap()),
+                                              b"a b".to_vec());
+        expected.set_retain(true);
+        assert_eq!(VariablePacket::new(expected), packet);
+
+        let packet = parse("CONNECT client=dev1 user=bob pass=hex:001122").unwrap();
+        match packet {
+            VariablePacket::ConnectPacket(ref connect) => {
+                assert_eq!(Some(&b"\x00\x11\x22"[..]), connect.password());
+                assert!(!connect.clean_session());
+            },
+            _ => panic!("Unexpected packet {:?}", packet),
+        }
+    }
+
+    fn error(line: &str) -> (usize, String, String) {
+        let err = parse(line).unwrap_err();
+        (err.column, err.token, err.message)
+    }
+
+    #[test]
+    fn test_dsl_parse_errors() {
+        let check = |line: &str, column: usize, token: &str, message: &str| {
+            assert_eq!((column, token.to_owned(), message.to_owned()), error(line), "{}", line);
+        };
+
+        check("", 1, "", "Empty line");
+        check("PUBLISHED topic=a", 1, "PUBLISHED", "Unknown packet type");
+        check("PUBLISH topic=a/+", 9, "topic=a/+", "Topic name contains wildcards");
+        check("PUBLISH topic=a qos=3", 17, "qos=3", "Invalid QoS");
+        check("PUBLISH topic=a qos=1", 1, "PUBLISH", "Missing `pkid`");
+        check("PUBLISH topic=a pkid=1", 17, "pkid=1", "QoS 0 messages have no packet identifier");
+        check("PUBLISH topic=a payload=hex:abc", 17, "payload=hex:abc", "Odd number of hex digits");
+        check("PUBLISH topic=a payload=\"abc", 17, "payload=\"abc", "Unterminated string");
+        check("PUBLISH topic=a retain=yes", 17, "retain=yes", "Flags take no value");
+        check("PUBLISH topic=a topic=b", 17, "topic=b", "Repeated argument");
+        check("PUBLISH topic=a colour=red", 17, "colour=red", "Unknown argument");
+        check("PUBACK pkid=0", 8, "pkid=0", "Packet identifier must be non-zero");
+        check("PUBACK pkid=x", 8, "pkid=x", "Invalid number");
+        check("SUBSCRIBE pkid=1 filter=a/b", 18, "filter=a/b", "Expected `filter:qos`");
+        check("SUBACK pkid=1 codes=1,3", 15, "codes=1,3", "Invalid return code");
+        check("CONNECT client=a pass=x", 1, "CONNECT", "Password is set without a user name");
+
+        let err = parse("PUBACK pkid=x").unwrap_err();
+        assert_eq!("column 8: Invalid number (`pkid=x`)", err.to_string());
+    }
+}
@@ -0,0 +1,140 @@
+//! Fuzz target bodies, run by the `fuzz/` crate and by the tests below
+//!
+//! Every function takes the raw fuzzer input and panics if the decoder misbehaves, decode
+//! errors are expected and ignored.
+
+use std::cmp;
+use std::io::{self, Cursor, Read};
+
+use packet::*;
+use testing::arbitrary::Gen;
+use {Encodable, Decodable};
+
+/// Decodes packets from `data` until the first error
+pub fn decode_bytes(data: &[u8]) {
+    let mut reader = Cursor::new(data);
+    decode_all(&mut reader);
+}
+
+/// Decodes the bytes after the first one with the reads cut into chunks of 1 to 8 bytes, the
+/// first byte seeds the chunk sizes
+///
+/// The packets must be the same as when decoding from a single buffer.
+pub fn decode_chunked(data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+
+    let expected = decode_all(&mut Cursor::new(&data[1..]));
+    let mut reader = ChunkedReader {
+        data: &data[1..],
+        gen: Gen::new(data[0] as u64),
+    };
+    assert_eq!(expected, decode_all(&mut reader));
+}
+
+/// Generates a packet from a seed in the first 8 bytes, encodes it, flips the bytes selected
+/// by the rest of the input and decodes the result
+pub fn decode_mutated(data: &[u8]) {
+    let mut seed = [0; 8];
+    let split = cmp::min(8, data.len());
+    seed[..split].copy_from_slice(&data[..split]);
+
+    let packet: VariablePacket = Gen::new(u64::from_le_bytes(seed)).generate();
+    let mut encoded = Vec::new();
+    packet.encode(&mut encoded).expect("Failed to encode a generated packet");
+
+    for mutation in data[split..].chunks(3) {
+        if mutation.len() < 3 || encoded.is_empty() {
+            break;
+        }
+        let pos = (mutation[0] as usize | (mutation[1] as usize) << 8) % encoded.len();
+        encoded[pos] ^= mutation[2];
+    }
+
+    decode_bytes(&encoded);
+}
+
+/// Decodes packets until the first error, checking that each one re-encodes to bytes that
+/// decode to the same packet
+fn decode_all<R: Read>(reader: &mut R) -> Vec<VariablePacket> {
+    let mut packets = Vec::new();
+
+    while let Ok(packet) = VariablePacket::decode(reader) {
+        let mut encoded = Vec::new();
+        packet.encode(&mut encoded).expect("Failed to encode a decoded packet");
+        assert_eq!(encoded.len() as u32, packet.encoded_length());
+
+        match VariablePacket::decode(&mut Cursor::new(&encoded[..])) {
+            Ok(ref decoded) if *decoded == packet => {},
+            result => panic!("{:?} re-encoded to {:?}, decoded as {:?}", packet, encoded, result),
+        }
+        packets.push(packet);
+    }
+    packets
+}
+
+struct ChunkedReader<'a> {
+    data: &'a [u8],
+    gen: Gen,
+}
+
+impl<'a> Read for ChunkedReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = cmp::min(cmp::min(buf.len(), self.data.len()), self.gen.range(1, 9));
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use testing::vectors::vectors;
+
+    const CASES: u64 = 2000;
+
+    // Inputs built from the vectors and from generated packets, with bytes flipped
+    fn inputs() -> Vec<Vec<u8>> {
+        let mut inputs: Vec<Vec<u8>> = vectors().into_iter().map(|v| v.bytes).collect();
+
+        let mut g = Gen::new(0);
+        for _ in 0..CASES {
+            let packet: VariablePacket = g.generate();
+            let mut encoded = Vec::new();
+            packet.encode(&mut encoded).unwrap();
+            for _ in 0..g.range(0, 4) {
+                let pos = g.range(0, encoded.len());
+                encoded[pos] ^= g.next_u64() as u8;
+            }
+            inputs.push(encoded);
+        }
+        inputs
+    }
+
+    #[test]
+    fn test_fuzz_decode_bytes() {
+        for input in inputs() {
+            decode_bytes(&input);
+        }
+    }
+
+    #[test]
+    fn test_fuzz_decode_chunked() {
+        for (i, mut input) in inputs().into_iter().enumerate() {
+            input.insert(0, i as u8);
+            decode_chunked(&input);
+        }
+    }
+
+    #[test]
+    fn test_fuzz_decode_mutated() {
+        let mut g = Gen::new(1);
+        for _ in 0..CASES {
+            let input: Vec<u8> = (0..g.range(0, 40)).map(|_| g.next_u64() as u8).collect();
+            decode_mutated(&input);
+        }
+    }
+}
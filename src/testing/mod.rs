@@ -0,0 +1,626 @@
+//! Scriptable broker and packet assertions for testing clients without a real server
+//!
+//! Only built for the crate's own tests and with the `test-util` feature.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use control::ControlType;
+use control::variable_header::{PacketIdentifier, ProtocolVersion};
+use packet::*;
+use packet::suback::SubscribeReturnCode;
+use server::{MemoryRetainedStore, RetainedStore, SubscriptionTable};
+use {Encodable, Decodable, QualityOfService, TopicFilter};
+
+/// Asserts that two `VariablePacket`s are equal, listing the fields that differ otherwise
+///
+/// An optional third argument selects the `DiffMode`, `DiffMode::Exact` by default.
+#[macro_export]
+macro_rules! assert_packets_eq {
+    ($expected:expr, $actual:expr) => {
+        assert_packets_eq!($expected, $actual, $crate::testing::DiffMode::Exact)
+    };
+    ($expected:expr, $actual:expr, $mode:expr) => {{
+        let diffs = $crate::testing::diff_packets_with(&$expected, &$actual, $mode);
+        if !diffs.is_empty() {
+            let lines: Vec<String> = diffs.iter().map(|diff| format!("  {}", diff)).collect();
+            panic!("packets differ:\n{}", lines.join("\n"));
+        }
+    }};
+}
+
+pub mod arbitrary;
+pub mod dsl;
+pub mod fuzz;
+pub mod vectors;
+
+/// Broker side of a single connection
+///
+/// By default it accepts the CONNECT, grants every subscription at the requested QoS, echoes
+/// PUBLISH packets matching the client's own subscriptions, completes the QoS 1 and QoS 2
+/// handshakes and answers PINGREQ. Every packet read is recorded for `received`.
+///
+/// ```rust,no_run
+/// use mqtt::client::MqttClient;
+/// use mqtt::packet::ConnectPacket;
+/// use mqtt::testing::MockBroker;
+///
+/// let (stream, broker) = MockBroker::spawn(|broker| broker).unwrap();
+/// let mut client = MqttClient::new(stream);
+/// client.connect(ConnectPacket::new("client".to_owned())).unwrap();
+/// client.disconnect().unwrap();
+/// assert_eq!(2, broker.join().unwrap().len());
+/// ```
+pub struct MockBroker<S: Read + Write> {
+    stream: S,
+    connack: ConnackPacket,
+    grant: Box<FnMut(&TopicFilter, QualityOfService) -> SubscribeReturnCode + Send>,
+    echo: bool,
+    ack_delay: Duration,
+    duplicate_deliveries: bool,
+    overrides: Vec<(ControlType, Option<VariablePacket>)>,
+    subscriptions: SubscriptionTable<()>,
+    retained: MemoryRetainedStore,
+    next_pkid: u16,
+    received: Vec<VariablePacket>,
+}
+
+impl<S: Read + Write> MockBroker<S> {
+    pub fn new(stream: S) -> MockBroker<S> {
+        MockBroker {
+            stream: stream,
+            connack: ConnackPacket::accepted(false),
+            grant: Box::new(|_, qos| qos.into()),
+            echo: true,
+            ack_delay: Duration::from_secs(0),
+            duplicate_deliveries: false,
+            overrides: Vec::new(),
+            subscriptions: SubscriptionTable::new(),
+            retained: MemoryRetainedStore::new(),
+            next_pkid: 0,
+            received: Vec::new(),
+        }
+    }
+
+    /// Answer to the CONNECT, accepted without a session by default
+    pub fn connack(mut self, connack: ConnackPacket) -> MockBroker<S> {
+        self.connack = connack;
+        self
+    }
+
+    /// Decides the return code of every requested subscription
+    pub fn grant<F>(mut self, grant: F) -> MockBroker<S>
+        where F: FnMut(&TopicFilter, QualityOfService) -> SubscribeReturnCode + Send + 'static
+    {
+        self.grant = Box::new(grant);
+        self
+    }
+
+    /// Sends the client's PUBLISH packets back to it if they match its subscriptions
+    pub fn echo(mut self, echo: bool) -> MockBroker<S> {
+        self.echo = echo;
+        self
+    }
+
+    /// Waits before sending every PUBACK, PUBREC and PUBCOMP
+    pub fn ack_delay(mut self, ack_delay: Duration) -> MockBroker<S> {
+        self.ack_delay = ack_delay;
+        self
+    }
+
+    /// Sends every QoS 1 and QoS 2 message a second time with the DUP flag set
+    pub fn duplicate_deliveries(mut self, duplicate_deliveries: bool) -> MockBroker<S> {
+        self.duplicate_deliveries = duplicate_deliveries;
+        self
+    }
+
+    /// Answers packets of `control_type` with `response` instead, `None` leaves them unanswered
+    pub fn respond_to(mut self, control_type: ControlType, response: Option<VariablePacket>) -> MockBroker<S> {
+        self.overrides.retain(|&(t, _)| t != control_type);
+        self.overrides.push((control_type, response));
+        self
+    }
+
+    /// Retained message sent to every matching subscription
+    pub fn retain(mut self, topic: &str, qos: QualityOfService, payload: &[u8]) -> MockBroker<S> {
+        self.retained.store(message(topic, qos, payload));
+        self
+    }
+
+    /// Packets read from the client so far
+    pub fn received(&self) -> &[VariablePacket] {
+        &self.received
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Sends a message to the client right away
+    pub fn publish(&mut self, topic: &str, qos: QualityOfService, payload: &[u8]) -> io::Result<()> {
+        self.deliver(&message(topic, qos, payload), qos, false)
+    }
+
+    /// Sends any packet to the client right away, e.g. one it does not expect
+    pub fn inject<P>(&mut self, packet: P) -> io::Result<()>
+        where VariablePacket: From<P>
+    {
+        self.send(VariablePacket::new(packet))
+    }
+
+    /// Reads and answers the next packet, returns its control type
+    pub fn handle_next<'a>(&mut self) -> Result<ControlType, VariablePacketError<'a>> {
+        let packet = try!(VariablePacket::decode(&mut self.stream));
+        let control_type = packet.control_type();
+        self.received.push(packet.clone());
+
+        if let Some(index) = self.overrides.iter().position(|&(t, _)| t == control_type) {
+            if let Some(response) = self.overrides[index].1.clone() {
+                try!(self.send(response).map_err(VariablePacketError::IoError));
+            }
+            return Ok(control_type);
+        }
+
+        try!(self.answer(packet).map_err(VariablePacketError::IoError));
+        Ok(control_type)
+    }
+
+    /// Handles packets until the client sends a DISCONNECT or closes the connection
+    pub fn run(mut self) -> Vec<VariablePacket> {
+        while let Ok(control_type) = self.handle_next() {
+            if control_type == ControlType::Disconnect {
+                break;
+            }
+        }
+        self.received
+    }
+
+    fn answer(&mut self, packet: VariablePacket) -> io::Result<()> {
+        match packet {
+            VariablePacket::ConnectPacket(..) => {
+                let connack = self.connack.clone();
+                self.send(VariablePacket::new(connack))
+            },
+            VariablePacket::SubscribePacket(ref subscribe) => {
+                let suback = SubackPacket::for_request(subscribe, &mut *self.grant);
+                let grants: Vec<_> = subscribe.subscriptions()
+                    .zip(suback.return_codes().iter())
+                    .filter_map(|((filter, _), code)| code.granted_qos().map(|qos| (filter.clone(), qos)))
+                    .collect();
+                try!(self.send(VariablePacket::new(suback)));
+
+                for (filter, qos) in grants {
+                    self.subscriptions.subscribe((), filter.clone(), qos);
+                    let retained: Vec<_> = self.retained.matching(&filter).into_iter().cloned().collect();
+                    for packet in retained {
+                        try!(self.deliver(&packet, qos, true));
+                    }
+                }
+                Ok(())
+            },
+            VariablePacket::UnsubscribePacket(ref unsubscribe) => {
+                for filter in unsubscribe.topic_filters() {
+                    self.subscriptions.unsubscribe(&(), filter);
+                }
+                self.send(VariablePacket::new(UnsubackPacket::new(unsubscribe.packet_identifier().get())))
+            },
+            VariablePacket::PublishPacket(ref publish) => {
+                match publish.qos() {
+                    QoSWithPacketIdentifier::Level0 => {},
+                    QoSWithPacketIdentifier::Level1(pkid) => try!(self.send_ack(PubackPacket::new(pkid.get()))),
+                    QoSWithPacketIdentifier::Level2(pkid) => try!(self.send_ack(PubrecPacket::new(pkid.get()))),
+                }
+
+                if self.echo {
+                    let granted = self.subscriptions.dispatch(publish).next().map(|(_, qos)| qos);
+                    if let Some(qos) = granted {
+                        try!(self.deliver(publish, qos, false));
+                    }
+                }
+                Ok(())
+            },
+            VariablePacket::PubrecPacket(ref pubrec) =>
+                self.send(VariablePacket::new(PubrelPacket::new(pubrec.packet_identifier()))),
+            VariablePacket::PubrelPacket(ref pubrel) =>
+                self.send_ack(PubcompPacket::new(pubrel.packet_identifier())),
+            VariablePacket::PingreqPacket(..) => self.send(VariablePacket::new(PingrespPacket::new())),
+            _ => Ok(()),
+        }
+    }
+
+    fn deliver(&mut self, packet: &PublishPacket, qos: QualityOfService, retain_as_stored: bool) -> io::Result<()> {
+        let mut next_pkid = self.next_pkid;
+        let packet = packet.for_delivery(qos, || {
+            next_pkid = next_pkid.wrapping_add(1).max(1);
+            PacketIdentifier::new(next_pkid).unwrap()
+        }, retain_as_stored, ProtocolVersion::V3_1_1);
+        self.next_pkid = next_pkid;
+
+        try!(self.send(VariablePacket::new(packet.clone())));
+        if self.duplicate_deliveries && packet.qos().qos() != QualityOfService::Level0 {
+            try!(self.send(VariablePacket::new(packet.for_retransmission())));
+        }
+        Ok(())
+    }
+
+    fn send_ack<P>(&mut self, packet: P) -> io::Result<()>
+        where VariablePacket: From<P>
+    {
+        if self.ack_delay > Duration::from_secs(0) {
+            thread::sleep(self.ack_delay);
+        }
+        self.send(VariablePacket::new(packet))
+    }
+
+    fn send(&mut self, packet: VariablePacket) -> io::Result<()> {
+        match packet.encode(&mut self.stream) {
+            Ok(..) => {},
+            Err(VariablePacketError::IoError(err)) => return Err(err),
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidInput, err.to_string())),
+        }
+        self.stream.flush()
+    }
+}
+
+// Message to deliver at up to `qos`, the packet identifier is replaced on delivery
+fn message(topic: &str, qos: QualityOfService, payload: &[u8]) -> PublishPacket {
+    let pkid = PacketIdentifier::new(1).unwrap();
+    let qos = match qos {
+        QualityOfService::Level0 => QoSWithPacketIdentifier::Level0,
+        QualityOfService::Level1 => QoSWithPacketIdentifier::Level1(pkid),
+        QualityOfService::Level2 => QoSWithPacketIdentifier::Level2(pkid),
+    };
+    PublishPacket::new(topic.to_owned(), qos, payload.to_vec())
+}
+
+impl MockBroker<TcpStream> {
+    /// Runs a broker configured by `configure` on a loopback connection in a new thread
+    ///
+    /// Returns the client end of the connection, joining the thread gives the packets received.
+    pub fn spawn<F>(configure: F) -> io::Result<(TcpStream, thread::JoinHandle<Vec<VariablePacket>>)>
+        where F: FnOnce(MockBroker<TcpStream>) -> MockBroker<TcpStream> + Send + 'static
+    {
+        let listener = try!(TcpListener::bind("127.0.0.1:0"));
+        let client = try!(TcpStream::connect(try!(listener.local_addr())));
+
+        let broker = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            configure(MockBroker::new(stream)).run()
+        });
+
+        Ok((client, broker))
+    }
+}
+
+/// Fields compared by `diff_packets_with`
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum DiffMode {
+    Exact,
+    /// Ignores the DUP flag and the packet identifiers, which a retransmission or a
+    /// forwarded message may legitimately change
+    Semantic,
+}
+
+/// A field that differs between two packets
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct FieldDiff {
+    /// Control type of the expected packet
+    pub packet_type: ControlType,
+    /// Path of the field, e.g. `publish.topic_name` or `connect.flags.clean_session`
+    pub field: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for FieldDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: expected {}, got {}", self.field, self.expected, self.actual)
+    }
+}
+
+/// Fields that differ between `expected` and `actual`, empty if the packets are equal
+pub fn diff_packets(expected: &VariablePacket, actual: &VariablePacket) -> Vec<FieldDiff> {
+    diff_packets_with(expected, actual, DiffMode::Exact)
+}
+
+pub fn diff_packets_with(expected: &VariablePacket, actual: &VariablePacket, mode: DiffMode) -> Vec<FieldDiff> {
+    let packet_type = expected.control_type();
+    if packet_type != actual.control_type() {
+        return vec![FieldDiff {
+            packet_type: packet_type,
+            field: "control_type".to_owned(),
+            expected: packet_type.to_string(),
+            actual: actual.control_type().to_string(),
+        }];
+    }
+
+    let prefix = packet_type.to_string().to_lowercase();
+    let ignored = |field: &str| {
+        mode == DiffMode::Semantic && (field == "dup" || field == "packet_identifier")
+    };
+
+    let expected_fields = fields(expected);
+    let mut actual_fields = fields(actual);
+    let mut diffs = Vec::new();
+    for (field, expected_value) in expected_fields {
+        let actual_value = match actual_fields.iter().position(|&(ref f, _)| *f == field) {
+            Some(index) => actual_fields.remove(index).1,
+            None => "<missing>".to_owned(),
+        };
+        if expected_value != actual_value && !ignored(&field) {
+            diffs.push(FieldDiff {
+                packet_type: packet_type,
+                field: format!("{}.{}", prefix, field),
+                expected: expected_value,
+                actual: actual_value,
+            });
+        }
+    }
+    for (field, actual_value) in actual_fields {
+        if !ignored(&field) {
+            diffs.push(FieldDiff {
+                packet_type: packet_type,
+                field: format!("{}.{}", prefix, field),
+                expected: "<missing>".to_owned(),
+                actual: actual_value,
+            });
+        }
+    }
+    diffs
+}
+
+// Field paths and values of a packet, lists are enumerated element by element
+fn fields(packet: &VariablePacket) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    {
+        let mut field = |name: &str, value: String| fields.push((name.to_owned(), value));
+
+        match packet {
+            &VariablePacket::ConnectPacket(ref pk) => {
+                field("protocol_name", format!("{:?}", pk.protocol_name()));
+                field("protocol_level", pk.protocol_level().to_string());
+                field("flags.clean_session", pk.clean_session().to_string());
+                field("keep_alive", pk.keep_alive().to_string());
+                field("client_identifier", format!("{:?}", pk.client_identifier()));
+                match pk.will() {
+                    Some(will) => {
+                        field("will.topic", format!("{:?}", will.topic.0));
+                        field("will.message", bytes(&will.message));
+                        field("flags.will_qos", will.qos.to_string());
+                        field("flags.will_retain", will.retain.to_string());
+                    },
+                    None => field("will", "None".to_owned()),
+                }
+                field("user_name", format!("{:?}", pk.user_name()));
+                field("password", pk.password().map_or("None".to_owned(), bytes));
+            },
+            &VariablePacket::ConnackPacket(ref pk) => {
+                field("flags.session_present", pk.session_present().to_string());
+                field("return_code", format!("{:?}", pk.connect_return_code()));
+            },
+            &VariablePacket::PublishPacket(ref pk) => {
+                field("dup", pk.dup().to_string());
+                field("qos", pk.qos().qos().to_string());
+                field("retain", pk.retain().to_string());
+                field("topic_name", format!("{:?}", pk.topic_name()));
+                field("packet_identifier", format!("{:?}", packet.packet_identifier()));
+                field("payload", bytes(pk.payload()));
+            },
+            &VariablePacket::SubscribePacket(ref pk) => {
+                field("packet_identifier", pk.packet_identifier().get().to_string());
+                for (i, (filter, qos)) in pk.subscriptions().enumerate() {
+                    field(&format!("subscriptions[{}].topic_filter", i), format!("{:?}", filter.as_str()));
+                    field(&format!("subscriptions[{}].qos", i), qos.to_string());
+                }
+            },
+            &VariablePacket::SubackPacket(ref pk) => {
+                field("packet_identifier", pk.packet_identifier().to_string());
+                for (i, code) in pk.return_codes().iter().enumerate() {
+                    field(&format!("return_codes[{}]", i), format!("{:?}", code));
+                }
+            },
+            &VariablePacket::UnsubscribePacket(ref pk) => {
+                field("packet_identifier", pk.packet_identifier().get().to_string());
+                for (i, filter) in pk.topic_filters().enumerate() {
+                    field(&format!("topic_filters[{}]", i), format!("{:?}", filter.as_str()));
+                }
+            },
+            _ => {
+                if let Some(pkid) = packet.packet_identifier() {
+                    field("packet_identifier", pkid.to_string());
+                }
+            },
+        }
+    }
+    fields
+}
+
+fn bytes(bytes: &[u8]) -> String {
+    match ::std::str::from_utf8(bytes) {
+        Ok(s) => format!("{:?}", s),
+        Err(..) => format!("{:?}", bytes),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use control::variable_header::ConnectReturnCode;
+
+    /// Stream replaying `input` and capturing what the broker writes
+    struct Script {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn script(packets: Vec<VariablePacket>) -> Script {
+        let mut input = Vec::new();
+        for packet in packets {
+            packet.encode(&mut input).unwrap();
+        }
+        Script { input: Cursor::new(input), output: Vec::new() }
+    }
+
+    fn written(broker: &MockBroker<Script>) -> Vec<String> {
+        let mut output = Cursor::new(&broker.get_ref().output[..]);
+        let mut packets = Vec::new();
+        while (output.position() as usize) < output.get_ref().len() {
+            packets.push(format!("{}", VariablePacket::decode(&mut output).unwrap()));
+        }
+        packets
+    }
+
+    fn pkid(pkid: u16) -> PacketIdentifier {
+        PacketIdentifier::new(pkid).unwrap()
+    }
+
+    #[test]
+    fn test_mock_broker_default_policy() {
+        let mut broker = MockBroker::new(script(vec![
+            VariablePacket::new(ConnectPacket::new("client".to_owned())),
+            VariablePacket::new(SubscribePacket::new(pkid(1), vec![(TopicFilter::new("a/#"), QualityOfService::Level1)])),
+            VariablePacket::new(PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level2(pkid(2)), b"x".to_vec())),
+            VariablePacket::new(PubrelPacket::new(2)),
+            VariablePacket::new(PingreqPacket::new()),
+        ])).retain("a/c", QualityOfService::Level2, b"retained");
+
+        for _ in 0..5 {
+            broker.handle_next().unwrap();
+        }
+        assert!(broker.handle_next().is_err());
+
+        assert_eq!(vec!["CONNACK(accepted)",
+                        "SUBACK(pkid=1, 1)",
+                        "PUBLISH(topic=\"a/c\", qos=1, pkid=1, payload=8B, retain)",
+                        "PUBREC(pkid=2)",
+                        "PUBLISH(topic=\"a/b\", qos=1, pkid=2, payload=1B)",
+                        "PUBCOMP(pkid=2)",
+                        "PINGRESP"],
+                   written(&broker));
+        assert_eq!(5, broker.received().len());
+    }
+
+    #[test]
+    fn test_mock_broker_misbehavior() {
+        let mut broker = MockBroker::new(script(vec![
+            VariablePacket::new(ConnectPacket::new("client".to_owned())),
+            VariablePacket::new(PingreqPacket::new()),
+            VariablePacket::new(PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level1(pkid(3)), b"x".to_vec())),
+        ])).connack(ConnackPacket::rejected(ConnectReturnCode::NotAuthorized))
+           .duplicate_deliveries(true)
+           .respond_to(ControlType::PingRequest, Some(VariablePacket::new(UnsubackPacket::new(7))))
+           .respond_to(ControlType::Publish, None);
+
+        assert_eq!(ControlType::Connect, broker.handle_next().unwrap());
+        assert_eq!(ControlType::PingRequest, broker.handle_next().unwrap());
+        assert_eq!(ControlType::Publish, broker.handle_next().unwrap());
+        broker.publish("c/d", QualityOfService::Level1, b"hi").unwrap();
+        broker.inject(PubcompPacket::new(9)).unwrap();
+
+        assert_eq!(vec!["CONNACK(rejected=5)",
+                        "UNSUBACK(pkid=7)",
+                        "PUBLISH(topic=\"c/d\", qos=1, pkid=1, payload=2B)",
+                        "PUBLISH(topic=\"c/d\", qos=1, pkid=1, payload=2B, dup)",
+                        "PUBCOMP(pkid=9)"],
+                   written(&broker));
+    }
+
+    fn publish(qos: QoSWithPacketIdentifier, topic: &str, payload: &[u8]) -> VariablePacket {
+        VariablePacket::new(PublishPacket::new(topic.to_owned(), qos, payload.to_vec()))
+    }
+
+    fn field_values(diffs: &[FieldDiff]) -> Vec<(&str, &str, &str)> {
+        diffs.iter().map(|d| (&d.field[..], &d.expected[..], &d.actual[..])).collect()
+    }
+
+    #[test]
+    fn test_diff_packets_publish() {
+        let expected = publish(QoSWithPacketIdentifier::Level1(pkid(1)), "a/b", b"hello");
+        assert!(diff_packets(&expected, &expected.clone()).is_empty());
+
+        let actual = publish(QoSWithPacketIdentifier::Level1(pkid(2)), "a/c", b"hello");
+        assert_eq!(vec![("publish.topic_name", "\"a/b\"", "\"a/c\""),
+                        ("publish.packet_identifier", "Some(1)", "Some(2)")],
+                   field_values(&diff_packets(&expected, &actual)));
+        assert_eq!(ControlType::Publish, diff_packets(&expected, &actual)[0].packet_type);
+
+        let actual = publish(QoSWithPacketIdentifier::Level0, "a/b", b"\xff");
+        assert_eq!(vec![("publish.qos", "1", "0"),
+                        ("publish.packet_identifier", "Some(1)", "None"),
+                        ("publish.payload", "\"hello\"", "[255]")],
+                   field_values(&diff_packets(&expected, &actual)));
+
+        let actual = publish(QoSWithPacketIdentifier::Level0, "a/b", b"hello");
+        assert_eq!(vec![("control_type", "PUBLISH", "PINGREQ")],
+                   field_values(&diff_packets(&actual, &VariablePacket::new(PingreqPacket::new()))));
+    }
+
+    #[test]
+    fn test_diff_packets_semantic() {
+        let expected = PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level2(pkid(1)), b"x".to_vec());
+        let mut actual = expected.for_retransmission();
+        actual.set_qos(QoSWithPacketIdentifier::Level2(pkid(7)));
+        let (expected, actual) = (VariablePacket::new(expected), VariablePacket::new(actual));
+
+        assert_eq!(vec![("publish.dup", "false", "true"), ("publish.packet_identifier", "Some(1)", "Some(7)")],
+                   field_values(&diff_packets(&expected, &actual)));
+        assert!(diff_packets_with(&expected, &actual, DiffMode::Semantic).is_empty());
+        assert_packets_eq!(expected, actual, DiffMode::Semantic);
+    }
+
+    #[test]
+    fn test_diff_packets_connect_and_lists() {
+        let expected = VariablePacket::new(ConnectPacket::builder("client").clean_session(true).build().unwrap());
+        let actual = VariablePacket::new(ConnectPacket::builder("client")
+                                             .will("w", b"gone", QualityOfService::Level1, false)
+                                             .build().unwrap());
+        assert_eq!(vec![("connect.flags.clean_session", "true", "false"),
+                        ("connect.will", "None", "<missing>"),
+                        ("connect.will.topic", "<missing>", "\"w\""),
+                        ("connect.will.message", "<missing>", "\"gone\""),
+                        ("connect.flags.will_qos", "<missing>", "1"),
+                        ("connect.flags.will_retain", "<missing>", "false")],
+                   field_values(&diff_packets(&expected, &actual)));
+
+        let expected = VariablePacket::new(SubscribePacket::new(pkid(1), vec![(TopicFilter::new("a/#"), QualityOfService::Level1)]));
+        let actual = VariablePacket::new(SubscribePacket::new(pkid(1), vec![(TopicFilter::new("a/#"), QualityOfService::Level2),
+                                                                            (TopicFilter::new("b"), QualityOfService::Level0)]));
+        assert_eq!(vec![("subscribe.subscriptions[0].qos", "1", "2"),
+                        ("subscribe.subscriptions[1].topic_filter", "<missing>", "\"b\""),
+                        ("subscribe.subscriptions[1].qos", "<missing>", "0")],
+                   field_values(&diff_packets(&expected, &actual)));
+    }
+
+    #[test]
+    #[should_panic(expected = "publish.topic_name: expected \"a/b\", got \"a/c\"")]
+    fn test_assert_packets_eq_lists_diffs() {
+        assert_packets_eq!(publish(QoSWithPacketIdentifier::Level0, "a/b", b""),
+                           publish(QoSWithPacketIdentifier::Level0, "a/c", b""));
+    }
+}
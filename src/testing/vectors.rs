@@ -0,0 +1,294 @@
+//! Known-good wire encodings of every packet type
+//!
+//! The CONNECT, PUBLISH, SUBSCRIBE, SUBACK and UNSUBSCRIBE vectors follow the examples in the
+//! figures of the MQTT 3.1.1 specification. The MQTT 5 specification has no such figures, its
+//! vectors are synthetic, laid out by hand after the field descriptions. Changes to the wire
+//! format, or new packet features, come with new vectors here so that `check_vector` covers them.
+
+use std::io::{Cursor, Read};
+
+use control::variable_header::{AuthReasonCode, ConnectReasonCode, ConnectReturnCode, DisconnectReasonCode};
+use control::variable_header::{PacketIdentifier, Properties, Property, ProtocolVersion, PubackReasonCode};
+use control::variable_header::{PubcompReasonCode, PubrelReasonCode, SubackReasonCode, UnsubackReasonCode};
+use packet::*;
+use packet::suback::SubscribeReturnCode;
+use testing::{diff_packets, FieldDiff};
+use {Encodable, QualityOfService, TopicFilter};
+
+/// An encoded packet and the packet it decodes to
+#[derive(Debug, Clone)]
+pub struct TestVector {
+    pub description: &'static str,
+    pub bytes: Vec<u8>,
+    pub packet: VariablePacket,
+    /// Protocol version the bytes are decoded for
+    pub version: ProtocolVersion,
+}
+
+impl TestVector {
+    pub fn new<P>(description: &'static str, bytes: &[u8], packet: P) -> TestVector
+        where VariablePacket: From<P>
+    {
+        TestVector::with_version(description, bytes, packet, ProtocolVersion::V3_1_1)
+    }
+
+    pub fn with_version<P>(description: &'static str, bytes: &[u8], packet: P, version: ProtocolVersion) -> TestVector
+        where VariablePacket: From<P>
+    {
+        TestVector {
+            description: description,
+            bytes: bytes.to_vec(),
+            packet: VariablePacket::new(packet),
+            version: version,
+        }
+    }
+}
+
+/// Failure of `check_vector`
+#[derive(Debug)]
+pub enum VectorError<'a> {
+    /// The bytes could not be decoded, the reads were split at `split` if it is set
+    DecodeError(Option<usize>, VariablePacketError<'a>),
+    /// The bytes decoded to another packet
+    PacketMismatch(Option<usize>, Vec<FieldDiff>),
+    /// Bytes left after decoding the packet
+    TrailingBytes(usize),
+    EncodeError(VariablePacketError<'a>),
+    /// The packet encoded to other bytes
+    BytesMismatch(Vec<u8>),
+}
+
+fn pkid(pkid: u16) -> PacketIdentifier {
+    PacketIdentifier::new(pkid).unwrap()
+}
+
+/// Every vector of the corpus
+pub fn vectors() -> Vec<TestVector> {
+    let mut long_publish = b"\x30\x80\x01\x00\x01a".to_vec();
+    long_publish.extend(vec![b'x'; 125]);
+
+    let mut vectors = vec![
+        TestVector::new("CONNECT with will, user name and password (figure 3.6)",
+                        b"\x10\x26\x00\x04MQTT\x04\xce\x00\x0a\
+                          \x00\x06client\x00\x01w\x00\x03bye\x00\x04user\x00\x04pass",
+                        ConnectPacket::builder("client")
+                            .keep_alive(10)
+                            .clean_session(true)
+                            .will("w", b"bye", QualityOfService::Level1, false)
+                            .user_name("user")
+                            .password(b"pass")
+                            .build().unwrap()),
+        TestVector::new("CONNECT without flags",
+                        b"\x10\x0e\x00\x04MQTT\x04\x00\x00\x00\x00\x02id",
+                        ConnectPacket::builder("id").build().unwrap()),
+        TestVector::new("CONNACK accepted with a session",
+                        b"\x20\x02\x01\x00",
+                        ConnackPacket::accepted(true)),
+        TestVector::new("CONNACK not authorized",
+                        b"\x20\x02\x00\x05",
+                        ConnackPacket::rejected(ConnectReturnCode::NotAuthorized)),
+        TestVector::new("PUBLISH QoS 1 (figure 3.11)",
+                        b"\x32\x0c\x00\x03a/b\x00\x0ahello",
+                        PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level1(pkid(10)), b"hello".to_vec())),
+        TestVector::new("PUBLISH QoS 0 retained without payload",
+                        b"\x31\x05\x00\x03a/b",
+                        {
+                            let mut publish = PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level0, Vec::new());
+                            publish.set_retain(true);
+                            publish
+                        }),
+        TestVector::new("PUBLISH QoS 2 retransmission",
+                        b"\x3c\x07\x00\x03a/b\xff\xff",
+                        PublishPacket::new("a/b".to_owned(), QoSWithPacketIdentifier::Level2(pkid(0xffff)), Vec::new())
+                            .for_retransmission()),
+        TestVector::new("PUBLISH with a two byte remaining length", &long_publish,
+                        PublishPacket::new("a".to_owned(), QoSWithPacketIdentifier::Level0, vec![b'x'; 125])),
+        TestVector::new("PUBACK", b"\x40\x02\x00\x0a", PubackPacket::new(10)),
+        TestVector::new("PUBREC", b"\x50\x02\x00\x0a", PubrecPacket::new(10)),
+        TestVector::new("PUBREL", b"\x62\x02\x00\x0a", PubrelPacket::new(10)),
+        TestVector::new("PUBCOMP", b"\x70\x02\x00\x0a", PubcompPacket::new(10)),
+        TestVector::new("SUBSCRIBE (figure 3.22)",
+                        b"\x82\x0e\x00\x0a\x00\x03a/b\x01\x00\x03c/d\x02",
+                        SubscribePacket::new(pkid(10), vec![(TopicFilter::new("a/b"), QualityOfService::Level1),
+                                                            (TopicFilter::new("c/d"), QualityOfService::Level2)])),
+        TestVector::new("SUBACK (figure 3.26)",
+                        b"\x90\x06\x00\x0a\x00\x01\x02\x80",
+                        SubackPacket::new(10, vec![SubscribeReturnCode::MaximumQoSLevel0,
+                                                   SubscribeReturnCode::MaximumQoSLevel1,
+                                                   SubscribeReturnCode::MaximumQoSLevel2,
+                                                   SubscribeReturnCode::Failure])),
+        TestVector::new("UNSUBSCRIBE (figure 3.30)",
+                        b"\xa2\x0c\x00\x0a\x00\x03a/b\x00\x03c/d",
+                        UnsubscribePacket::new(pkid(10), vec![TopicFilter::new("a/b"), TopicFilter::new("c/d")]).unwrap()),
+        TestVector::new("UNSUBACK", b"\xb0\x02\x00\x0a", UnsubackPacket::new(10)),
+        TestVector::new("PINGREQ", b"\xc0\x00", PingreqPacket::new()),
+        TestVector::new("PINGRESP", b"\xd0\x00", PingrespPacket::new()),
+        TestVector::new("DISCONNECT", b"\xe0\x00", DisconnectPacket::new()),
+    ];
+    vectors.extend(mqtt_5_vectors());
+    vectors
+}
+
+fn mqtt_5_vectors() -> Vec<TestVector> {
+    use self::ProtocolVersion::V5;
+
+    vec![
+        TestVector::with_version("MQTT 5 CONNECT with a Session Expiry Interval",
+                                 b"\x10\x18\x00\x04MQTT\x05\x02\x00\x3c\x05\x11\x00\x00\x0e\x10\x00\x06client",
+                                 {
+                                     let mut connect = ConnectPacket::builder("client")
+                                         .protocol_version(V5)
+                                         .keep_alive(60)
+                                         .clean_session(true)
+                                         .build().unwrap();
+                                     connect.set_session_expiry_interval(Some(3600));
+                                     connect
+                                 }, V5),
+        TestVector::with_version("MQTT 5 CONNACK with Receive Maximum and Topic Alias Maximum",
+                                 b"\x20\x09\x00\x00\x06\x21\x00\x14\x22\x00\x0a",
+                                 {
+                                     let mut connack = ConnackPacket::with_reason_code(false, ConnectReasonCode::Success);
+                                     let mut properties = Properties::new();
+                                     properties.insert(Property::ReceiveMaximum(20));
+                                     properties.insert(Property::TopicAliasMaximum(10));
+                                     connack.set_properties(properties);
+                                     connack
+                                 }, V5),
+        TestVector::with_version("MQTT 5 CONNACK bad user name or password",
+                                 b"\x20\x03\x00\x86\x00",
+                                 ConnackPacket::with_reason_code(false, ConnectReasonCode::BadUserNameOrPassword), V5),
+        TestVector::with_version("MQTT 5 PUBLISH QoS 1 with a Content Type",
+                                 b"\x32\x1a\x00\x03a/b\x00\x0a\x0d\x03\x00\x0atext/plainhello",
+                                 {
+                                     let mut publish = PublishPacket::with_version(
+                                         "a/b".to_owned(), QoSWithPacketIdentifier::Level1(pkid(10)), b"hello".to_vec(), V5);
+                                     publish.set_content_type("text/plain");
+                                     publish
+                                 }, V5),
+        TestVector::with_version("MQTT 5 PUBACK no matching subscribers", b"\x40\x03\x00\x0a\x10",
+                                 PubackPacket::new_with_reason(10, PubackReasonCode::NoMatchingSubscribers), V5),
+        TestVector::with_version("MQTT 5 PUBREC with a Reason String",
+                                 b"\x50\x09\x00\x0a\x00\x05\x1f\x00\x02ok",
+                                 {
+                                     let mut pubrec = PubrecPacket::new(10);
+                                     pubrec.set_reason_string(Some("ok".to_owned()));
+                                     pubrec
+                                 }, V5),
+        TestVector::with_version("MQTT 5 PUBREL packet identifier not found", b"\x62\x03\x00\x0a\x92",
+                                 PubrelPacket::new_with_reason(10, PubrelReasonCode::PacketIdentifierNotFound), V5),
+        TestVector::with_version("MQTT 5 PUBCOMP packet identifier not found", b"\x70\x03\x00\x0a\x92",
+                                 PubcompPacket::new_with_reason(10, PubcompReasonCode::PacketIdentifierNotFound), V5),
+        TestVector::with_version("MQTT 5 SUBSCRIBE with No Local, Retain As Published and Retain Handling",
+                                 b"\x82\x09\x00\x0a\x00\x00\x03a/b\x2d",
+                                 SubscribePacket::with_options(pkid(10), vec![(TopicFilter::new("a/b"), SubscriptionOptions {
+                                     qos: QualityOfService::Level1,
+                                     no_local: true,
+                                     retain_as_published: true,
+                                     retain_handling: RetainHandling::DoNotSend,
+                                 })]), V5),
+        TestVector::with_version("MQTT 5 SUBACK", b"\x90\x05\x00\x0a\x00\x01\x87",
+                                 SubackPacket::with_reason_codes(10, vec![SubackReasonCode::GrantedQoS1,
+                                                                          SubackReasonCode::NotAuthorized]), V5),
+        TestVector::with_version("MQTT 5 UNSUBSCRIBE", b"\xa2\x06\x00\x0a\x00\x00\x01a",
+                                 UnsubscribePacket::with_version(pkid(10), vec![TopicFilter::new("a")], V5).unwrap(), V5),
+        TestVector::with_version("MQTT 5 UNSUBACK", b"\xb0\x05\x00\x0a\x00\x00\x11",
+                                 UnsubackPacket::with_reasons(10, vec![UnsubackReasonCode::Success,
+                                                                       UnsubackReasonCode::NoSubscriptionExisted]), V5),
+        TestVector::with_version("MQTT 5 DISCONNECT session taken over", b"\xe0\x01\x8e",
+                                 DisconnectPacket::new_with_reason(DisconnectReasonCode::SessionTakenOver), V5),
+        TestVector::with_version("MQTT 5 AUTH continue authentication",
+                                 b"\xf0\x1f\x18\x1d\x15\x00\x0bSCRAM-SHA-1\x16\x00\x0cserver-first",
+                                 AuthPacket::with_data(AuthReasonCode::ContinueAuthentication,
+                                                       "SCRAM-SHA-1".to_owned(), b"server-first".to_vec()), V5),
+    ]
+}
+
+/// Decodes the vector, in one read and with the reads split at every byte boundary, then
+/// re-encodes the packet and compares the bytes
+pub fn check_vector<'a>(vector: &TestVector) -> Result<(), VectorError<'a>> {
+    try!(check_decode(vector, None, &mut Cursor::new(&vector.bytes[..])));
+
+    for split in 1..vector.bytes.len() {
+        let (head, tail) = vector.bytes.split_at(split);
+        try!(check_decode(vector, Some(split), &mut head.chain(tail)));
+    }
+
+    let mut encoded = Vec::new();
+    try!(vector.packet.encode(&mut encoded).map_err(VectorError::EncodeError));
+    if encoded != vector.bytes {
+        return Err(VectorError::BytesMismatch(encoded));
+    }
+    Ok(())
+}
+
+fn check_decode<'a, R: Read>(vector: &TestVector, split: Option<usize>, reader: &mut R) -> Result<(), VectorError<'a>> {
+    let options = DecodeOptions::new().protocol_version(vector.version);
+    let packet = try!(VariablePacket::decode_with_options(reader, &options)
+                          .map_err(|err| VectorError::DecodeError(split, err)));
+
+    let diffs = diff_packets(&vector.packet, &packet);
+    if !diffs.is_empty() {
+        return Err(VectorError::PacketMismatch(split, diffs));
+    }
+
+    let trailing = reader.bytes().count();
+    if trailing > 0 {
+        return Err(VectorError::TrailingBytes(trailing));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use control::ControlType;
+
+    #[test]
+    fn test_vectors_round_trip() {
+        for vector in vectors() {
+            if let Err(err) = check_vector(&vector) {
+                panic!("{}: {:?}", vector.description, err);
+            }
+        }
+    }
+
+    #[test]
+    fn test_vectors_cover_every_control_type() {
+        let control_types = [
+            ControlType::Connect, ControlType::ConnectAcknowledgement,
+            ControlType::Publish, ControlType::PublishAcknowledgement, ControlType::PublishReceived,
+            ControlType::PublishRelease, ControlType::PublishComplete,
+            ControlType::Subscribe, ControlType::SubscribeAcknowledgement,
+            ControlType::Unsubscribe, ControlType::UnsubscribeAcknowledgement,
+            ControlType::PingRequest, ControlType::PingResponse, ControlType::Disconnect, ControlType::Auth,
+        ];
+
+        let vectors = vectors();
+        for &control_type in control_types.iter() {
+            assert!(vectors.iter().any(|v| v.packet.control_type() == control_type), "No vector of {}", control_type);
+        }
+    }
+
+    #[test]
+    fn test_check_vector_reports_mismatches() {
+        let mut vector = TestVector::new("PUBACK", b"\x40\x02\x00\x0a", PubackPacket::new(11));
+        match check_vector(&vector) {
+            Err(VectorError::PacketMismatch(None, ref diffs)) if diffs.len() == 1 => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        vector.packet = VariablePacket::new(PubackPacket::new(10));
+        vector.bytes.push(0);
+        match check_vector(&vector) {
+            Err(VectorError::TrailingBytes(1)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+
+        vector.bytes = b"\x40\x01\x00".to_vec();
+        match check_vector(&vector) {
+            Err(VectorError::DecodeError(None, _)) => {},
+            err => panic!("Unexpected result {:?}", err),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::prelude::v1::*;
+use std::io::{Read, Write};
+use std::error::Error;
+use std::fmt;
+
+use {Encodable, Decodable};
+use encodable::StringEncodeError;
+
+/// Topic filter in SUBSCRIBE and UNSUBSCRIBE packets, may contain the `+` and `#` wildcards
+#[derive(Debug, Eq, PartialEq, Clone, Hash, Ord, PartialOrd)]
+pub struct TopicFilter(pub String);
+
+impl TopicFilter {
+    pub fn new<S: Into<String>>(filter: S) -> TopicFilter {
+        TopicFilter(filter.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0[..]
+    }
+
+    /// MQTT 5 shared subscription, `$share/{ShareName}/{filter}`
+    pub fn is_shared(&self) -> bool {
+        self.0.starts_with("$share/")
+    }
+
+    /// Checks the filter against the topic filter rules in section 4.7 of the spec
+    pub fn validate(&self) -> Result<(), TopicFilterError> {
+        TopicFilter::validate_str(&self.0)
+    }
+
+    /// `validate` for a filter that is not held in a `TopicFilter`
+    pub fn validate_str(filter: &str) -> Result<(), TopicFilterError> {
+        if filter.is_empty() {
+            return Err(TopicFilterError::Empty);
+        }
+
+        if filter.contains('\0') {
+            return Err(TopicFilterError::NullCharacter);
+        }
+
+        let mut levels = filter.split('/').peekable();
+        while let Some(level) = levels.next() {
+            // The multi-level wildcard must occupy an entire level and be the last one
+            if level.contains('#') && (level != "#" || levels.peek().is_some()) {
+                return Err(TopicFilterError::InvalidMultiLevelWildcard);
+            }
+
+            // The single-level wildcard must occupy an entire level
+            if level.contains('+') && level != "+" {
+                return Err(TopicFilterError::InvalidSingleLevelWildcard);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> From<&'a str> for TopicFilter {
+    fn from(filter: &'a str) -> TopicFilter {
+        TopicFilter(filter.to_owned())
+    }
+}
+
+impl From<String> for TopicFilter {
+    fn from(filter: String) -> TopicFilter {
+        TopicFilter(filter)
+    }
+}
+
+impl fmt::Display for TopicFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum TopicFilterError {
+    Empty,
+    NullCharacter,
+    InvalidMultiLevelWildcard,
+    InvalidSingleLevelWildcard,
+}
+
+impl fmt::Display for TopicFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl Error for TopicFilterError {
+    fn description(&self) -> &str {
+        match self {
+            &TopicFilterError::Empty => "Topic filter is empty",
+            &TopicFilterError::NullCharacter => "Topic filter contains a null character",
+            &TopicFilterError::InvalidMultiLevelWildcard => "Invalid use of the multi-level wildcard",
+            &TopicFilterError::InvalidSingleLevelWildcard => "Invalid use of the single-level wildcard",
+        }
+    }
+}
+
+impl<'a> Encodable<'a> for TopicFilter {
+    type Err = StringEncodeError;
+
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), StringEncodeError> {
+        (&self.0[..]).encode(writer)
+    }
+
+    fn encoded_length(&self) -> u32 {
+        (&self.0[..]).encoded_length()
+    }
+}
+
+impl<'a> Decodable<'a> for TopicFilter {
+    type Err = StringEncodeError;
+    type Cond = ();
+
+    fn decode_with<R: Read>(reader: &mut R, _rest: Option<()>) -> Result<TopicFilter, StringEncodeError> {
+        Ok(TopicFilter(try!(Decodable::decode(reader))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_topic_filter_validate() {
+        for filter in &["sport/tennis/player1/#", "sport/#", "#", "+", "+/tennis/#", "sport/+/player1", "/+", "$SYS/#"] {
+            assert_eq!(Ok(()), TopicFilter::new(*filter).validate(), "{}", filter);
+        }
+
+        assert_eq!(Err(TopicFilterError::Empty), TopicFilter::new("").validate());
+        assert_eq!(Err(TopicFilterError::NullCharacter), TopicFilter::new("a/\0").validate());
+        assert_eq!(Err(TopicFilterError::InvalidMultiLevelWildcard), TopicFilter::new("sport/tennis#").validate());
+        assert_eq!(Err(TopicFilterError::InvalidMultiLevelWildcard), TopicFilter::new("sport/#/ranking").validate());
+        assert_eq!(Err(TopicFilterError::InvalidSingleLevelWildcard), TopicFilter::new("sport+").validate());
+    }
+}
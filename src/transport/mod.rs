@@ -0,0 +1,5 @@
+//! Carrying packets over transports other than a plain byte stream
+
+pub use self::ws::{pack, WsPacketAssembler};
+
+pub mod ws;
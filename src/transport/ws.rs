@@ -0,0 +1,242 @@
+//! MQTT over WebSockets
+//!
+//! Packets travel in binary WebSocket frames, but frame boundaries do not have to line up with
+//! packet boundaries (MQTT 3.1.1 section 6): a frame may hold several packets or a fragment of
+//! one. This module only deals with the frame payloads, the WebSocket connection itself is left
+//! to whatever library the application uses.
+
+use std::cmp;
+use std::io::Cursor;
+use std::mem;
+
+use control::FixedHeader;
+use packet::{DecodeOptions, VariablePacket, VariablePacketError};
+use Encodable;
+
+/// Reassembles packets from the payloads of received binary frames
+///
+/// ```
+/// use mqtt::packet::{PingreqPacket, VariablePacket};
+/// use mqtt::transport::WsPacketAssembler;
+///
+/// let mut assembler = WsPacketAssembler::new();
+/// assembler.push_frame(b"\xc0\x00\xc0");
+/// assert_eq!(Some(VariablePacket::new(PingreqPacket::new())), assembler.next_packet().unwrap());
+/// assert_eq!(None, assembler.next_packet().unwrap());
+/// ```
+#[derive(Debug, Clone)]
+pub struct WsPacketAssembler {
+    buffer: Vec<u8>,
+    options: DecodeOptions,
+}
+
+impl WsPacketAssembler {
+    pub fn new() -> WsPacketAssembler {
+        WsPacketAssembler::with_options(DecodeOptions::new())
+    }
+
+    pub fn with_options(options: DecodeOptions) -> WsPacketAssembler {
+        WsPacketAssembler {
+            buffer: Vec::new(),
+            options: options,
+        }
+    }
+
+    pub fn push_frame(&mut self, frame_payload: &[u8]) {
+        self.buffer.extend_from_slice(frame_payload);
+    }
+
+    /// Number of bytes received but not decoded yet
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Decodes the next complete packet, `None` if more frames are needed
+    ///
+    /// The stream cannot be resynchronized after an error, so the buffered bytes are dropped
+    /// and the connection should be closed.
+    pub fn next_packet<'a>(&mut self) -> Result<Option<VariablePacket>, VariablePacketError<'a>> {
+        loop {
+            let len = match FixedHeader::packet_length(&self.buffer) {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+
+            // Fail without waiting for the rest of a packet that is going to be rejected
+            let too_large = self.options.max_packet_size.map_or(false, |max| len > max as usize);
+            if self.buffer.len() < len && !too_large {
+                return Ok(None);
+            }
+
+            let control_type = self.buffer[0] >> 4;
+            if self.options.allow_unknown_packets && (control_type == 0 || control_type == 15) && !too_large {
+                self.buffer.drain(..len);
+                continue;
+            }
+
+            let end = cmp::min(len, self.buffer.len());
+            let result = VariablePacket::decode_with_options(&mut Cursor::new(&self.buffer[..end]), &self.options);
+            match result {
+                Ok(packet) => {
+                    self.buffer.drain(..len);
+                    return Ok(Some(packet));
+                },
+                Err(err) => {
+                    self.buffer.clear();
+                    return Err(err);
+                },
+            }
+        }
+    }
+}
+
+/// Encodes `packets` into frame payloads of at most `max_frame_size` bytes
+///
+/// Consecutive packets share a frame while they fit. A packet that does not fit in the rest of
+/// the current frame starts a new one, unless it is larger than `max_frame_size` and has to be
+/// split anyway.
+pub fn pack<'a>(packets: &[VariablePacket], max_frame_size: usize) -> Result<Vec<Vec<u8>>, VariablePacketError<'a>> {
+    assert!(max_frame_size > 0, "Frames must hold at least one byte");
+
+    let mut frames = Vec::new();
+    let mut frame = Vec::new();
+    for packet in packets {
+        let mut encoded = Vec::with_capacity(packet.encoded_length() as usize);
+        try!(packet.encode(&mut encoded));
+
+        if !frame.is_empty() && frame.len() + encoded.len() > max_frame_size && encoded.len() <= max_frame_size {
+            frames.push(mem::replace(&mut frame, Vec::new()));
+        }
+
+        let mut rest = &encoded[..];
+        while !rest.is_empty() {
+            let len = cmp::min(max_frame_size - frame.len(), rest.len());
+            frame.extend_from_slice(&rest[..len]);
+            rest = &rest[len..];
+
+            if frame.len() == max_frame_size {
+                frames.push(mem::replace(&mut frame, Vec::new()));
+            }
+        }
+    }
+
+    if !frame.is_empty() {
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use control::variable_header::PacketIdentifier;
+    use packet::*;
+    use testing::arbitrary::Gen;
+
+    fn publish(payload_len: usize) -> VariablePacket {
+        VariablePacket::new(PublishPacket::new("a/b".to_owned(),
+                                               QoSWithPacketIdentifier::Level1(PacketIdentifier::new(10).unwrap()),
+                                               vec![b'x'; payload_len]))
+    }
+
+    #[test]
+    fn test_ws_publish_split_across_three_frames() {
+        let packet = publish(100);
+        let mut encoded = Vec::new();
+        packet.encode(&mut encoded).unwrap();
+
+        let mut assembler = WsPacketAssembler::new();
+        // The first frame ends inside the fixed header
+        assembler.push_frame(&encoded[..1]);
+        assert!(assembler.next_packet().unwrap().is_none());
+        assembler.push_frame(&encoded[1..60]);
+        assert!(assembler.next_packet().unwrap().is_none());
+        assembler.push_frame(&encoded[60..]);
+        assert_eq!(Some(packet), assembler.next_packet().unwrap());
+        assert!(assembler.next_packet().unwrap().is_none());
+        assert_eq!(0, assembler.buffered());
+    }
+
+    #[test]
+    fn test_ws_three_pingreqs_in_one_frame() {
+        let pingreq = VariablePacket::new(PingreqPacket::new());
+        let frames = pack(&[pingreq.clone(), pingreq.clone(), pingreq.clone()], 4096).unwrap();
+        assert_eq!(vec![b"\xc0\x00\xc0\x00\xc0\x00".to_vec()], frames);
+
+        let mut assembler = WsPacketAssembler::new();
+        assembler.push_frame(&frames[0]);
+        for _ in 0..3 {
+            assert_eq!(Some(pingreq.clone()), assembler.next_packet().unwrap());
+        }
+        assert!(assembler.next_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_ws_pack_frame_boundaries() {
+        // 9 bytes each, the third one does not fit in the first frame and starts a new one
+        let small = VariablePacket::new(PublishPacket::new("a".to_owned(), QoSWithPacketIdentifier::Level0, b"abcd".to_vec()));
+        let frames = pack(&[small.clone(), small.clone(), small.clone()], 20).unwrap();
+        assert_eq!(vec![18, 9], frames.iter().map(Vec::len).collect::<Vec<_>>());
+
+        // Oversized packets fill up the current frame
+        let frames = pack(&[small.clone(), publish(300)], 128).unwrap();
+        assert_eq!(vec![128, 128, 63], frames.iter().map(Vec::len).collect::<Vec<_>>());
+
+        let mut assembler = WsPacketAssembler::new();
+        for frame in frames.iter() {
+            assembler.push_frame(frame);
+        }
+        assert_eq!(Some(small), assembler.next_packet().unwrap());
+        assert_eq!(Some(publish(300)), assembler.next_packet().unwrap());
+        assert!(pack(&[], 128).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ws_round_trip_arbitrary_frame_sizes() {
+        let mut g = Gen::new(0);
+        for _ in 0..200 {
+            let packets: Vec<VariablePacket> = (0..g.range(1, 6)).map(|_| g.generate()).collect();
+            let frames = pack(&packets, g.range(1, 64)).unwrap();
+
+            let mut assembler = WsPacketAssembler::new();
+            let mut decoded = Vec::new();
+            for frame in frames {
+                assembler.push_frame(&frame);
+                while let Some(packet) = assembler.next_packet().unwrap() {
+                    decoded.push(packet);
+                }
+            }
+            assert_eq!(packets, decoded);
+            assert_eq!(0, assembler.buffered());
+        }
+    }
+
+    #[test]
+    fn test_ws_assembler_errors() {
+        let mut assembler = WsPacketAssembler::new();
+        assembler.push_frame(b"\x30\xff\xff\xff\xff\x7f");
+        match assembler.next_packet() {
+            Err(VariablePacketError::FixedHeaderError(..)) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert_eq!(0, assembler.buffered());
+
+        // Rejected as soon as the fixed header is known
+        let mut assembler = WsPacketAssembler::with_options(DecodeOptions::new().max_packet_size(Some(64)));
+        assembler.push_frame(b"\x30\x80\x01\x00\x01a");
+        match assembler.next_packet() {
+            Err(VariablePacketError::PacketTooLarge(131)) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_ws_assembler_skips_unknown_packets() {
+        let mut assembler = WsPacketAssembler::with_options(DecodeOptions::lenient());
+        assembler.push_frame(b"\xf0\x02\x00");
+        assert!(assembler.next_packet().unwrap().is_none());
+        assembler.push_frame(b"\x00\xc0\x00");
+        assert_eq!(Some(VariablePacket::new(PingreqPacket::new())), assembler.next_packet().unwrap());
+    }
+}